@@ -0,0 +1,106 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bench::numbered_rrset;
+use dns_resolver::cache::SharedCache;
+use dns_types::protocol::types::{QueryType, RecordType, ResourceRecord};
+
+/// How many RRsets are in the cache, and how many operations each thread
+/// does per iteration.
+const RECORDS: usize = 10_000;
+
+/// How many records are in each RRset.  Answers from upstream are cached an
+/// RRset at a time, each replacing whatever was cached for its name and type.
+const RRSET_SIZE: usize = 4;
+
+/// The RRsets to cache, and a cache with all of them in.
+fn fixtures() -> (Vec<Vec<ResourceRecord>>, SharedCache) {
+    let rrsets: Vec<_> = (0..RECORDS)
+        .map(|i| numbered_rrset(i, RRSET_SIZE))
+        .collect();
+    let cache = SharedCache::with_desired_size(RECORDS * RRSET_SIZE * 2);
+    cache.insert_all(&rrsets.concat());
+    (rrsets, cache)
+}
+
+fn bench_single_thread(c: &mut Criterion) {
+    let (rrsets, cache) = fixtures();
+
+    let mut group = c.benchmark_group("cache");
+    group.bench_function("insert", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            cache.insert_all(&rrsets[i % RECORDS]);
+            i += 1;
+        });
+    });
+    group.bench_function("get/hit", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            let rrset = &rrsets[i % RECORDS];
+            i += 1;
+            cache.get(&rrset[0].name, QueryType::Record(RecordType::A))
+        });
+    });
+    group.bench_function("get/miss", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            let rrset = &rrsets[i % RECORDS];
+            i += 1;
+            cache.get(&rrset[0].name, QueryType::Record(RecordType::AAAA))
+        });
+    });
+    group.finish();
+}
+
+/// Each thread does `RECORDS` operations, where every `write_every`th is an
+/// insert and the rest are gets, so the time per iteration shows how much the
+/// threads get in each other's way.
+fn bench_contention(c: &mut Criterion) {
+    let (rrsets, cache) = fixtures();
+
+    let mut group = c.benchmark_group("cache/contention");
+    group.sample_size(20);
+    for threads in [1, 2, 4, 8] {
+        for write_every in [2, 10] {
+            group.bench_with_input(
+                BenchmarkId::new(format!("1 in {write_every} writes"), threads),
+                &threads,
+                |b, &threads| {
+                    b.iter_custom(|iters| {
+                        let mut elapsed = Duration::ZERO;
+                        for _ in 0..iters {
+                            let start = Instant::now();
+                            thread::scope(|s| {
+                                for t in 0..threads {
+                                    let cache = &cache;
+                                    let rrsets = &rrsets;
+                                    s.spawn(move || {
+                                        for i in 0..RECORDS {
+                                            let rrset = &rrsets[(i + t * 997) % RECORDS];
+                                            if i % write_every == 0 {
+                                                cache.insert_all(rrset);
+                                            } else {
+                                                cache.get(
+                                                    &rrset[0].name,
+                                                    QueryType::Record(RecordType::A),
+                                                );
+                                            }
+                                        }
+                                    });
+                                }
+                            });
+                            elapsed += start.elapsed();
+                        }
+                        elapsed
+                    });
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_single_thread, bench_contention);
+criterion_main!(benches);
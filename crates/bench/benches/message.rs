@@ -0,0 +1,28 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use bench::response;
+use dns_types::protocol::types::Message;
+
+fn bench_message(c: &mut Criterion) {
+    let mut group = c.benchmark_group("message");
+    for answers in [1, 10, 100] {
+        let message = response(answers);
+        let octets = message.to_octets().unwrap().freeze();
+
+        group.throughput(Throughput::Bytes(octets.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("serialise", answers),
+            &message,
+            |b, message| b.iter(|| message.to_octets().unwrap()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("deserialise", answers),
+            &octets,
+            |b, octets| b.iter(|| Message::from_octets(octets).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_message);
+criterion_main!(benches);
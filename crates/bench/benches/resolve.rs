@@ -0,0 +1,60 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use bench::{large_zones, numbered_name, numbered_record, question, APEX};
+use dns_resolver::cache::SharedCache;
+use dns_resolver::resolver::Resolver;
+use dns_types::protocol::types::test_util::domain;
+use dns_types::protocol::types::RecordType;
+
+/// Resolve questions with a non-recursive resolver, so that everything is
+/// answered from the zones or the cache, covering everything `resolved` does
+/// for a query apart from the network I/O.
+fn bench_local_resolution(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    let cache = SharedCache::new();
+    let mut cached = numbered_record(1);
+    cached.name = domain("cached.example.net.");
+    cache.insert(&cached);
+
+    let resolver = Resolver::builder()
+        .recursive(false)
+        .zones(large_zones(100_000, false))
+        .cache(cache)
+        .build();
+
+    let cases = [
+        ("zone hit", question(&numbered_name(54_321), RecordType::A)),
+        (
+            "zone wildcard",
+            question(&format!("a.wild.{APEX}"), RecordType::A),
+        ),
+        (
+            "zone cname chain",
+            question(&format!("cname0.{APEX}"), RecordType::A),
+        ),
+        (
+            "zone nxdomain",
+            question(&format!("missing.{APEX}"), RecordType::A),
+        ),
+        ("cache hit", question("cached.example.net.", RecordType::A)),
+    ];
+
+    let mut group = c.benchmark_group("resolve");
+    for (case, question) in &cases {
+        // make sure this is measuring a successful resolution, and not
+        // something going wrong
+        let answer = runtime.block_on(resolver.lookup(question));
+        assert!(answer.is_ok(), "{case}: {answer:?}");
+
+        group.bench_function(*case, |b| {
+            b.iter(|| runtime.block_on(resolver.lookup(question)));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_local_resolution);
+criterion_main!(benches);
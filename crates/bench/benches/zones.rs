@@ -0,0 +1,44 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use bench::{large_zones, numbered_name, APEX};
+use dns_types::protocol::types::test_util::domain;
+use dns_types::protocol::types::{QueryType, RecordType};
+
+fn bench_resolve(c: &mut Criterion) {
+    let cases = [
+        ("hit", numbered_name(54_321)),
+        ("wildcard", format!("a.b.wild.{APEX}")),
+        ("cname", format!("cname0.{APEX}")),
+        ("nxdomain", format!("missing.sub1.{APEX}")),
+    ];
+
+    let mut group = c.benchmark_group("zones/resolve");
+    for compact in [false, true] {
+        let zones = large_zones(100_000, compact);
+        let layout = if compact { "compact" } else { "tree" };
+        for (case, name) in &cases {
+            let name = domain(name);
+            group.bench_with_input(
+                BenchmarkId::new(*case, format!("100k names, {layout}")),
+                &name,
+                |b, name| b.iter(|| zones.resolve(name, QueryType::Record(RecordType::A))),
+            );
+        }
+    }
+    group.finish();
+}
+
+fn bench_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("zones/build");
+    group.sample_size(10);
+    for compact in [false, true] {
+        let layout = if compact { "compact" } else { "tree" };
+        group.bench_function(format!("100k names, {layout}"), |b| {
+            b.iter(|| large_zones(100_000, compact));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_resolve, bench_build);
+criterion_main!(benches);
@@ -0,0 +1,134 @@
+//! Fixtures for the benchmarks in `benches/`, which cover the parts of
+//! `dns-types` and `dns-resolver` that every query goes through.  Run them
+//! with:
+//!
+//! ```bash
+//! cargo bench -p bench
+//! ```
+//!
+//! Pass `-- --save-baseline <name>` before a change and `-- --baseline <name>`
+//! after it to see what difference it made.
+
+#![warn(clippy::pedantic)]
+// Don't care enough to fix
+#![allow(clippy::missing_panics_doc)]
+#![allow(clippy::must_use_candidate)]
+#![allow(clippy::wildcard_imports)]
+
+use std::net::Ipv4Addr;
+
+use dns_types::protocol::types::test_util::*;
+use dns_types::protocol::types::*;
+use dns_types::zones::types::{Zone, Zones, SOA};
+
+/// The apex of the zone made by `large_zones`.
+pub const APEX: &str = "example.com.";
+
+/// A question for a name and type, in the `IN` class.
+pub fn question(name: &str, rtype: RecordType) -> Question {
+    Question {
+        name: domain(name),
+        qtype: QueryType::Record(rtype),
+        qclass: QueryClass::Record(RecordClass::IN),
+    }
+}
+
+/// The `A` record for the `i`th name made by `large_zones`.
+pub fn numbered_record(i: usize) -> ResourceRecord {
+    let octets = u32::try_from(i).unwrap().to_be_bytes();
+    a_record(
+        &numbered_name(i),
+        Ipv4Addr::new(10, octets[1], octets[2], octets[3]),
+    )
+}
+
+/// A record set of `size` `A` records for the `i`th name made by
+/// `large_zones`, as it would be in an answer from upstream.
+pub fn numbered_rrset(i: usize, size: usize) -> Vec<ResourceRecord> {
+    let name = numbered_name(i);
+    let octets = u32::try_from(i).unwrap().to_be_bytes();
+    (0..size)
+        .map(|j| {
+            a_record(
+                &name,
+                Ipv4Addr::new(
+                    10 + u8::try_from(j).unwrap(),
+                    octets[1],
+                    octets[2],
+                    octets[3],
+                ),
+            )
+        })
+        .collect()
+}
+
+/// The `i`th name made by `large_zones`: names are spread over 100
+/// subdomains, so that there's some depth to the tree.
+pub fn numbered_name(i: usize) -> String {
+    format!("host{i}.sub{}.{APEX}", i % 100)
+}
+
+/// A response with `answers` `A` records, all under the same domain so that
+/// name compression has something to do, and an EDNS `OPT` record.
+pub fn response(answers: usize) -> Message {
+    let mut message = Message::from_question(1234, question(APEX, RecordType::A)).make_response();
+    message.answers = (0..answers).map(numbered_record).collect();
+    message.additional.push(ResourceRecord::edns_opt(1232, &[]));
+    message
+}
+
+/// An authoritative zone for `APEX` with `names` numbered names, each with an
+/// `A` record, plus a wildcard and a chain of `CNAME`s:
+///
+/// - `*.wild.example.com.` has an `A` record
+/// - `cname0.example.com.` to `cname4.example.com.` each point to the next,
+///   and the last points to the first numbered name
+pub fn large_zone(names: usize, compact: bool) -> Zone {
+    let apex = domain(APEX);
+    let soa = SOA {
+        mname: domain(&format!("ns.{APEX}")),
+        rname: domain(&format!("hostmaster.{APEX}")),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: 300,
+    };
+    let mut zone = if compact {
+        Zone::new_compact(apex, Some(soa))
+    } else {
+        Zone::new(apex, Some(soa))
+    };
+
+    for i in 0..names {
+        let rr = numbered_record(i);
+        zone.insert(&rr.name, rr.rtype_with_data, rr.ttl);
+    }
+
+    zone.insert_wildcard(
+        &domain(&format!("wild.{APEX}")),
+        RecordTypeWithData::A {
+            address: Ipv4Addr::new(192, 0, 2, 1),
+        },
+        300,
+    );
+
+    for i in 0..5 {
+        let target = if i == 4 {
+            numbered_name(0)
+        } else {
+            format!("cname{}.{APEX}", i + 1)
+        };
+        let rr = cname_record(&format!("cname{i}.{APEX}"), &target);
+        zone.insert(&rr.name, rr.rtype_with_data, rr.ttl);
+    }
+
+    zone
+}
+
+/// `large_zone` on its own in a `Zones`.
+pub fn large_zones(names: usize, compact: bool) -> Zones {
+    let mut zones = Zones::new();
+    zones.insert(large_zone(names, compact));
+    zones
+}
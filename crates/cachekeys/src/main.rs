@@ -0,0 +1,200 @@
+use clap::Parser;
+use std::collections::BTreeMap;
+use std::io::{stdin, BufRead};
+use std::process;
+use std::str::FromStr;
+
+use dns_types::protocol::types::{DomainName, Label, QueryType, RecordType};
+
+// the doc comments for this struct turn into the CLI help text
+#[derive(Parser)]
+/// Read questions (in `domain [qtype]` form) from stdin, one per line, and
+/// report how they map to cache keys: distinct spellings which share a key, and
+/// spellings which get a different key (or none at all) but which probably
+/// should not, because of a missing trailing dot, escapes, or similar.
+///
+/// Part of resolved.
+struct Args {}
+
+/// A cache key: the normalised domain and the query type.
+type Key = (String, QueryType);
+
+/// Everything seen in the input.
+#[derive(Default)]
+struct Audit {
+    /// For each key, the spellings which mapped to it, and how many times.
+    keys: BTreeMap<Key, BTreeMap<String, usize>>,
+
+    /// Spellings which don't map to a key at all, and how many times.
+    unparseable: BTreeMap<(String, QueryType), usize>,
+
+    /// Spellings which map to a different key (or none) than a more lenient
+    /// reading would give, and the key that reading gives.
+    near_misses: BTreeMap<(String, QueryType), (Option<Key>, Key)>,
+}
+
+impl Audit {
+    fn add(&mut self, spelling: &str, qtype: QueryType) {
+        let strict = DomainName::from_dotted_string(spelling)
+            .map(|d| (d.to_canonical().to_dotted_string(), qtype));
+        let lenient =
+            lenient_domain(spelling).map(|d| (d.to_canonical().to_dotted_string(), qtype));
+
+        if let Some(key) = &strict {
+            *self
+                .keys
+                .entry(key.clone())
+                .or_default()
+                .entry(spelling.to_string())
+                .or_default() += 1;
+        } else {
+            *self
+                .unparseable
+                .entry((spelling.to_string(), qtype))
+                .or_default() += 1;
+        }
+
+        if let Some(lenient) = lenient {
+            if strict.as_ref() != Some(&lenient) {
+                self.near_misses
+                    .insert((spelling.to_string(), qtype), (strict, lenient));
+            }
+        }
+    }
+
+    fn print(&self) {
+        let collisions = self
+            .keys
+            .iter()
+            .filter(|(_, spellings)| spellings.len() > 1)
+            .collect::<Vec<_>>();
+
+        println!(";; SUMMARY");
+        println!("; keys: {}", self.keys.len());
+        println!("; keys with more than one spelling: {}", collisions.len());
+        println!("; unparseable spellings: {}", self.unparseable.len());
+        println!("; near misses: {}", self.near_misses.len());
+
+        if !collisions.is_empty() {
+            println!("\n;; COLLISIONS");
+            for ((name, qtype), spellings) in collisions {
+                println!("{name}\t{qtype}");
+                for (spelling, count) in spellings {
+                    println!("\t{spelling}\t{count}");
+                }
+            }
+        }
+
+        if !self.near_misses.is_empty() {
+            println!("\n;; NEAR MISSES");
+            for ((spelling, qtype), (strict, (lenient_name, _))) in &self.near_misses {
+                let seen = if self.keys.contains_key(&(lenient_name.clone(), *qtype)) {
+                    "seen"
+                } else {
+                    "not seen"
+                };
+                match strict {
+                    Some((strict_name, _)) => println!(
+                        "{spelling}\t{qtype}\tkey {strict_name}, expected {lenient_name} ({seen})"
+                    ),
+                    None => {
+                        println!("{spelling}\t{qtype}\tno key, expected {lenient_name} ({seen})")
+                    }
+                }
+            }
+        }
+
+        if !self.unparseable.is_empty() {
+            println!("\n;; UNPARSEABLE");
+            for ((spelling, qtype), count) in &self.unparseable {
+                println!("{spelling}\t{qtype}\t{count}");
+            }
+        }
+    }
+}
+
+/// Parse a domain more leniently than `DomainName::from_dotted_string`: the
+/// trailing dot is optional, and `\DDD` (decimal) and `\X` escapes are
+/// interpreted, as in zone files.
+fn lenient_domain(s: &str) -> Option<DomainName> {
+    let mut labels = Vec::new();
+    let mut label = Vec::new();
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '.' => {
+                if label.is_empty() {
+                    // only the root domain may have an empty label
+                    return if s == "." {
+                        Some(DomainName::root_domain())
+                    } else {
+                        None
+                    };
+                }
+                labels.push(Label::try_from(&label[..]).ok()?);
+                label.clear();
+            }
+            '\\' => {
+                let escaped = chars.next()?;
+                if let Some(d1) = escaped.to_digit(10) {
+                    let d2 = chars.next()?.to_digit(10)?;
+                    let d3 = chars.next()?.to_digit(10)?;
+                    label.push(u8::try_from(d1 * 100 + d2 * 10 + d3).ok()?);
+                } else {
+                    let mut buf = [0; 4];
+                    label.extend_from_slice(escaped.encode_utf8(&mut buf).as_bytes());
+                }
+            }
+            _ => {
+                let mut buf = [0; 4];
+                label.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+
+    if !label.is_empty() {
+        labels.push(Label::try_from(&label[..]).ok()?);
+    }
+    labels.push(Label::new());
+
+    DomainName::from_labels(labels)
+}
+
+fn main() {
+    Args::parse();
+
+    let mut audit = Audit::default();
+    for (i, line) in stdin().lock().lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                eprintln!("error reading questions from stdin: {err:?}");
+                process::exit(1);
+            }
+        };
+
+        let mut words = line.split_whitespace();
+        let Some(spelling) = words.next() else {
+            continue;
+        };
+        if spelling.starts_with('#') || spelling.starts_with(';') {
+            continue;
+        }
+
+        let qtype = match words.next() {
+            Some(qtype) => match QueryType::from_str(qtype) {
+                Ok(qtype) => qtype,
+                Err(err) => {
+                    eprintln!("line {}: {qtype}: {err:?}", i + 1);
+                    process::exit(1);
+                }
+            },
+            None => QueryType::Record(RecordType::A),
+        };
+
+        audit.add(spelling, qtype);
+    }
+
+    audit.print();
+}
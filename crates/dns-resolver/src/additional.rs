@@ -0,0 +1,240 @@
+use dns_types::protocol::types::*;
+use dns_types::zones::types::*;
+
+use crate::cache::SharedCache;
+use crate::metrics::is_blocked;
+use crate::util::types::Allowlist;
+
+/// Address record types to look up for each target.
+const ADDRESS_QTYPES: [QueryType; 2] = [
+    QueryType::Record(RecordType::A),
+    QueryType::Record(RecordType::AAAA),
+];
+
+/// Find the address records which should go in the additional section of a
+/// response, so clients don't need to make another round-trip to use the
+/// answer.
+///
+/// This corresponds to step 6 of the standard nameserver algorithm: for every
+/// MX, NS, and SRV record in the answer, the A and AAAA records of its target
+/// are looked up in the local zones and the cache.  Upstream nameservers are
+/// never queried, CNAMEs are not followed, and blocked addresses are never
+/// included (unless they're allowlisted, in which case the cache is used).
+///
+/// Records which are already in the answer are not returned again.
+///
+/// See section 4.3.2 of RFC 1034.
+pub fn additional_records(
+    answers: &[ResourceRecord],
+    allowlist: &Allowlist,
+    zones: &Zones,
+    cache: &SharedCache,
+) -> Vec<ResourceRecord> {
+    let mut additional = Vec::new();
+
+    for rr in answers {
+        let target = match &rr.rtype_with_data {
+            RecordTypeWithData::MX { exchange, .. } => exchange,
+            RecordTypeWithData::NS { nsdname } => nsdname,
+            RecordTypeWithData::SRV { target, .. } => target,
+            _ => continue,
+        };
+
+        // a target of "." means there is no such service (RFC 2782, RFC 7505)
+        if target.is_root() {
+            continue;
+        }
+
+        for qtype in ADDRESS_QTYPES {
+            let question = Question {
+                name: target.clone(),
+                qtype,
+                qclass: QueryClass::Record(RecordClass::IN),
+            };
+
+            for address_rr in lookup_address(&question, allowlist, zones, cache) {
+                if !answers.contains(&address_rr) && !additional.contains(&address_rr) {
+                    additional.push(address_rr);
+                }
+            }
+        }
+    }
+
+    additional
+}
+
+/// Look up an address record in the zones, falling back to the cache if the
+/// name isn't in an authoritative zone.
+fn lookup_address(
+    question: &Question,
+    allowlist: &Allowlist,
+    zones: &Zones,
+    cache: &SharedCache,
+) -> Vec<ResourceRecord> {
+    if let Some((zone, zone_result)) = zones.resolve(&question.name, question.qtype) {
+        match zone_result {
+            ZoneResult::Answer { rrs, .. } if is_blocked(&rrs, question) => {
+                if !allowlist.contains(&question.name) {
+                    return Vec::new();
+                }
+            }
+            ZoneResult::Answer { rrs, .. } if !rrs.is_empty() => return rrs,
+            _ => {
+                if zone.is_authoritative() {
+                    return Vec::new();
+                }
+            }
+        }
+    }
+
+    cache.get(&question.name, question.qtype)
+}
+
+#[cfg(test)]
+mod tests {
+    use dns_types::protocol::types::test_util::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+    use crate::cache::test_util::*;
+
+    #[test]
+    fn additional_records_finds_mx_and_srv_targets_in_zones() {
+        let answers = [
+            mx_record("example.com.", "mail.example.com."),
+            srv_record("_sip._udp.example.com.", "sip.example.com."),
+            mx_record("example.com.", "mail.example.com."),
+        ];
+
+        assert_eq!(
+            vec![
+                a_record("mail.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+                a_record("sip.example.com.", Ipv4Addr::new(2, 2, 2, 2)),
+            ],
+            additional_records(&answers, &Allowlist::new(), &zones(), &SharedCache::new())
+        );
+    }
+
+    #[test]
+    fn additional_records_finds_ns_targets_in_cache() {
+        let rr = aaaa_record("ns.example.net.", Ipv6Addr::LOCALHOST);
+
+        let cache = SharedCache::new();
+        cache.insert(&rr);
+
+        let answers = [ns_record("example.net.", "ns.example.net.")];
+
+        assert_cache_response(
+            &rr,
+            &additional_records(&answers, &Allowlist::new(), &zones(), &cache),
+        );
+    }
+
+    #[test]
+    fn additional_records_skips_records_in_answer() {
+        let answers = [
+            mx_record("example.com.", "mail.example.com."),
+            a_record("mail.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+        ];
+
+        assert_eq!(
+            Vec::<ResourceRecord>::new(),
+            additional_records(&answers, &Allowlist::new(), &zones(), &SharedCache::new())
+        );
+    }
+
+    #[test]
+    fn additional_records_skips_blocked_targets() {
+        let answers = [mx_record("example.com.", "blocked.example.com.")];
+
+        assert_eq!(
+            Vec::<ResourceRecord>::new(),
+            additional_records(&answers, &Allowlist::new(), &zones(), &SharedCache::new())
+        );
+    }
+
+    #[test]
+    fn additional_records_does_not_use_cache_for_authoritative_zones() {
+        let cache = SharedCache::new();
+        cache.insert(&a_record(
+            "missing.authoritative.example.com.",
+            Ipv4Addr::new(3, 3, 3, 3),
+        ));
+
+        let answers = [mx_record(
+            "authoritative.example.com.",
+            "missing.authoritative.example.com.",
+        )];
+
+        assert_eq!(
+            Vec::<ResourceRecord>::new(),
+            additional_records(&answers, &Allowlist::new(), &zones(), &cache)
+        );
+    }
+
+    #[test]
+    fn additional_records_skips_root_targets() {
+        let answers = [mx_record("example.com.", ".")];
+
+        assert_eq!(
+            Vec::<ResourceRecord>::new(),
+            additional_records(&answers, &Allowlist::new(), &zones(), &SharedCache::new())
+        );
+    }
+
+    fn mx_record(name: &str, exchange: &str) -> ResourceRecord {
+        ResourceRecord {
+            name: domain(name),
+            rtype_with_data: RecordTypeWithData::MX {
+                preference: 10,
+                exchange: domain(exchange),
+            },
+            rclass: RecordClass::IN,
+            ttl: 300,
+        }
+    }
+
+    fn srv_record(name: &str, target: &str) -> ResourceRecord {
+        ResourceRecord {
+            name: domain(name),
+            rtype_with_data: RecordTypeWithData::SRV {
+                priority: 0,
+                weight: 0,
+                port: 5060,
+                target: domain(target),
+            },
+            rclass: RecordClass::IN,
+            ttl: 300,
+        }
+    }
+
+    fn zones() -> Zones {
+        let mut zones = Zones::new();
+
+        zones.insert(
+            Zone::deserialise(
+                r"
+$ORIGIN example.com.
+
+mail    300 IN A 1.1.1.1
+sip     300 IN A 2.2.2.2
+blocked 300 IN A 0.0.0.0
+",
+            )
+            .unwrap(),
+        );
+
+        zones.insert(
+            Zone::deserialise(
+                r"
+$ORIGIN authoritative.example.com.
+
+@ IN SOA mname rname 1 30 30 30 30
+",
+            )
+            .unwrap(),
+        );
+
+        zones
+    }
+}
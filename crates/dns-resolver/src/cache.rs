@@ -8,6 +8,9 @@ use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use dns_types::protocol::types::*;
+use dns_types::zones::types::Zone;
+
+use crate::util::types::{CachePolicy, TtlLimits};
 
 /// A convenience wrapper around a `Cache` which lets it be shared
 /// between threads.
@@ -37,6 +40,13 @@ impl SharedCache {
         }
     }
 
+    /// Create a new cache with the given desired size and eviction policy.
+    pub fn with_policy(desired_size: usize, policy: CachePolicy) -> Self {
+        SharedCache {
+            cache: Arc::new(Mutex::new(Cache::with_policy(desired_size, policy))),
+        }
+    }
+
     /// Get an entry from the cache.
     ///
     /// The TTL in the returned `ResourceRecord` is relative to the
@@ -72,7 +82,48 @@ impl SharedCache {
             .get_without_checking_expiration(name, qtype)
     }
 
-    /// Insert an entry into the cache.
+    /// Get an entry from the cache which was scoped to a client subnet.  See
+    /// `Cache::get_scoped`.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn get_scoped(
+        &self,
+        client_subnet: &ClientSubnet,
+        name: &DomainName,
+        qtype: QueryType,
+    ) -> Vec<ResourceRecord> {
+        self.cache
+            .lock()
+            .expect(MUTEX_POISON_MESSAGE)
+            .get_scoped(client_subnet, name, qtype)
+    }
+
+    /// Get every unexpired RR in the cache, in no particular order.
+    ///
+    /// This does not count towards the hits and misses in the `stats`, or
+    /// towards the eviction policy.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn all_records(&self) -> Vec<ResourceRecord> {
+        self.cache.lock().expect(MUTEX_POISON_MESSAGE).all_records()
+    }
+
+    /// Get every unexpired RR in the cache as a non-authoritative zone.  See
+    /// `Cache::to_zone`.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn to_zone(&self) -> Zone {
+        self.cache.lock().expect(MUTEX_POISON_MESSAGE).to_zone()
+    }
+
+    /// Insert an entry into the cache, replacing any cached entries with the
+    /// same name and type.  See `Cache::insert`.
     ///
     /// It is not inserted if its TTL is zero or negative.
     ///
@@ -88,10 +139,12 @@ impl SharedCache {
         }
     }
 
-    /// Insert multiple entries into the cache.
+    /// Insert multiple entries into the cache, as whole record sets.  See
+    /// `Cache::insert_all`.
     ///
-    /// This is more efficient than calling `insert` multiple times, as it locks
-    /// the cache just once.
+    /// This is not the same as calling `insert` multiple times: entries with
+    /// the same name and type are cached together, rather than each replacing
+    /// the last.
     ///
     /// Records with a TTL of zero or negative are skipped.
     ///
@@ -101,11 +154,26 @@ impl SharedCache {
     ///
     /// If the mutex has been poisoned.
     pub fn insert_all(&self, records: &[ResourceRecord]) {
-        let mut cache = self.cache.lock().expect(MUTEX_POISON_MESSAGE);
-        for record in records {
-            if record.ttl > 0 {
-                cache.insert(record);
-            }
+        let records = unexpired(records);
+        if !records.is_empty() {
+            let mut cache = self.cache.lock().expect(MUTEX_POISON_MESSAGE);
+            cache.insert_all(&records);
+        }
+    }
+
+    /// Insert multiple entries into the cache, scoped to a client subnet.  See
+    /// `Cache::insert_all_scoped`.
+    ///
+    /// Records with a TTL of zero or negative are skipped.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn insert_all_scoped(&self, client_subnet: &ClientSubnet, records: &[ResourceRecord]) {
+        let records = unexpired(records);
+        if !records.is_empty() {
+            let mut cache = self.cache.lock().expect(MUTEX_POISON_MESSAGE);
+            cache.insert_all_scoped(client_subnet, &records);
         }
     }
 
@@ -120,6 +188,94 @@ impl SharedCache {
     pub fn prune(&self) -> (bool, usize, usize, usize) {
         self.cache.lock().expect(MUTEX_POISON_MESSAGE).prune()
     }
+
+    /// Delete all records.
+    ///
+    /// Returns the number of records deleted.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn clear(&self) -> usize {
+        self.cache.lock().expect(MUTEX_POISON_MESSAGE).clear()
+    }
+
+    /// Delete the records for a domain.  A wildcard query deletes the records
+    /// of every type.
+    ///
+    /// Returns the number of records deleted.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn remove(&self, name: &DomainName, qtype: QueryType) -> usize {
+        self.cache
+            .lock()
+            .expect(MUTEX_POISON_MESSAGE)
+            .remove(name, qtype)
+    }
+
+    /// Delete the records for a domain and all of its subdomains.
+    ///
+    /// Returns the number of records deleted.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn remove_subtree(&self, name: &DomainName) -> usize {
+        self.cache
+            .lock()
+            .expect(MUTEX_POISON_MESSAGE)
+            .remove_subtree(name)
+    }
+
+    /// Change the desired size.  The cache is not shrunk until the next
+    /// `prune`.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn set_desired_size(&self, desired_size: usize) {
+        self.cache
+            .lock()
+            .expect(MUTEX_POISON_MESSAGE)
+            .set_desired_size(desired_size);
+    }
+
+    /// Change the eviction policy.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn set_policy(&self, policy: CachePolicy) {
+        self.cache
+            .lock()
+            .expect(MUTEX_POISON_MESSAGE)
+            .set_policy(policy);
+    }
+
+    /// Change the limits on the TTL of records inserted into the cache.
+    /// Records which are already in the cache are left alone.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn set_ttl_limits(&self, ttl_limits: TtlLimits) {
+        self.cache
+            .lock()
+            .expect(MUTEX_POISON_MESSAGE)
+            .set_ttl_limits(ttl_limits);
+    }
+
+    /// Get the statistics for each record type which has ever been cached
+    /// or looked up.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn stats(&self) -> HashMap<RecordType, CacheStats> {
+        self.cache.lock().expect(MUTEX_POISON_MESSAGE).stats()
+    }
 }
 
 impl Default for SharedCache {
@@ -133,9 +289,21 @@ impl Default for SharedCache {
 /// You probably want to use `SharedCache` instead.
 #[derive(Debug, Clone)]
 pub struct Cache {
-    inner: PartitionedCache<DomainName, RecordType, RecordTypeWithData>,
+    inner:
+        PartitionedCache<DomainName, RecordType, RecordTypeWithData, AnyEvictionPolicy<DomainName>>,
+
+    /// Records from answers which an upstream nameserver tailored to the
+    /// client subnet in the query, which must only be used for queries from
+    /// that subnet.  These are always pruned least recently used first, and
+    /// are not included in the `stats`.
+    scoped: PartitionedCache<ScopedKey, RecordType, RecordTypeWithData, Lru<ScopedKey>>,
+
+    ttl_limits: TtlLimits,
 }
 
+/// The partition key of the records scoped to a client subnet.
+type ScopedKey = (ClientSubnet, DomainName);
+
 impl Default for Cache {
     fn default() -> Self {
         Self::new()
@@ -147,6 +315,8 @@ impl Cache {
     pub fn new() -> Self {
         Self {
             inner: PartitionedCache::new(),
+            scoped: PartitionedCache::new(),
+            ttl_limits: TtlLimits::default(),
         }
     }
 
@@ -155,8 +325,18 @@ impl Cache {
     /// The `prune` method will remove expired entries, and also enough entries
     /// (in least-recently-used order) to get down to this size.
     pub fn with_desired_size(desired_size: usize) -> Self {
+        Self::with_policy(desired_size, CachePolicy::default())
+    }
+
+    /// Create a new cache with the given desired size and eviction policy.
+    ///
+    /// The `prune` method will remove expired entries, and also enough entries
+    /// (in the order chosen by the policy) to get down to this size.
+    pub fn with_policy(desired_size: usize, policy: CachePolicy) -> Self {
         Self {
-            inner: PartitionedCache::with_desired_size(desired_size),
+            inner: PartitionedCache::with_policy(desired_size, AnyEvictionPolicy::new(policy)),
+            scoped: PartitionedCache::with_desired_size(desired_size),
+            ttl_limits: TtlLimits::default(),
         }
     }
 
@@ -165,9 +345,30 @@ impl Cache {
     /// The TTL in the returned `ResourceRecord` is relative to the
     /// current time - not when the record was inserted into the
     /// cache.
+    ///
+    /// This counts towards the hits and misses in the `stats`.  A wildcard
+    /// query counts as a hit for each record type it returns, and never
+    /// counts as a miss.
     pub fn get(&mut self, name: &DomainName, qtype: QueryType) -> Vec<ResourceRecord> {
         let mut rrs = self.get_without_checking_expiration(name, qtype);
         rrs.retain(|rr| rr.ttl > 0);
+
+        match qtype {
+            QueryType::Wildcard => {
+                let mut rtypes = rrs
+                    .iter()
+                    .map(|rr| rr.rtype_with_data.rtype())
+                    .collect::<Vec<_>>();
+                rtypes.sort();
+                rtypes.dedup();
+                for rtype in rtypes {
+                    self.inner.record_lookup(rtype, true);
+                }
+            }
+            QueryType::Record(rtype) => self.inner.record_lookup(rtype, !rrs.is_empty()),
+            _ => (),
+        }
+
         rrs
     }
 
@@ -185,77 +386,301 @@ impl Cache {
         match qtype {
             QueryType::Wildcard => {
                 if let Some(records) = self.inner.get_partition_without_checking_expiration(name) {
-                    for tuples in records.values() {
-                        to_rrs(name, now, tuples, &mut rrs);
+                    for rrset in records.values() {
+                        to_rrs(name, now, rrset, &mut rrs);
+                    }
+                }
+            }
+            QueryType::Record(rtype) => {
+                if let Some(rrset) = self.inner.get_without_checking_expiration(name, &rtype) {
+                    to_rrs(name, now, rrset, &mut rrs);
+                }
+            }
+            _ => (),
+        }
+
+        rrs
+    }
+
+    /// Get RRs from the cache which were scoped to a client subnet.  Only RRs
+    /// inserted with exactly the same client subnet are returned.
+    ///
+    /// This does not count towards the hits and misses in the `stats`.
+    pub fn get_scoped(
+        &mut self,
+        client_subnet: &ClientSubnet,
+        name: &DomainName,
+        qtype: QueryType,
+    ) -> Vec<ResourceRecord> {
+        let now = Instant::now();
+        let key = (*client_subnet, name.clone());
+        let mut rrs = Vec::new();
+        match qtype {
+            QueryType::Wildcard => {
+                if let Some(records) = self.scoped.get_partition_without_checking_expiration(&key) {
+                    for rrset in records.values() {
+                        to_rrs(name, now, rrset, &mut rrs);
                     }
                 }
             }
             QueryType::Record(rtype) => {
-                if let Some(tuples) = self.inner.get_without_checking_expiration(name, &rtype) {
-                    to_rrs(name, now, tuples, &mut rrs);
+                if let Some(rrset) = self.scoped.get_without_checking_expiration(&key, &rtype) {
+                    to_rrs(name, now, rrset, &mut rrs);
                 }
             }
             _ => (),
         }
 
+        rrs.retain(|rr| rr.ttl > 0);
+        rrs
+    }
+
+    /// Get every unexpired RR in the cache, in no particular order.  RRs which
+    /// were scoped to a client subnet are not included.
+    ///
+    /// This does not count towards the hits and misses in the `stats`, or
+    /// towards the eviction policy.
+    pub fn all_records(&self) -> Vec<ResourceRecord> {
+        let now = Instant::now();
+        let mut rrs = Vec::with_capacity(self.inner.current_size);
+        for (name, partition) in &self.inner.partitions {
+            for rrset in partition.records.values() {
+                to_rrs(name, now, rrset, &mut rrs);
+            }
+        }
+        rrs.retain(|rr| rr.ttl > 0);
         rrs
     }
 
-    /// Insert an RR into the cache.
+    /// Get every unexpired RR in the cache as a non-authoritative zone, with
+    /// the TTLs they have left.  Serialising the zone gives a zone file which
+    /// can be loaded into another resolver to warm its cache.  RRs which were
+    /// scoped to a client subnet are not included.
+    ///
+    /// This does not count towards the hits and misses in the `stats`, or
+    /// towards the eviction policy.
+    pub fn to_zone(&self) -> Zone {
+        let mut zone = Zone::default();
+        for rr in self.all_records() {
+            zone.insert(&rr.name, rr.rtype_with_data, rr.ttl);
+        }
+        zone
+    }
+
+    /// Insert an RR into the cache, as a record set of its own: this replaces
+    /// any RRs already cached with the same name and type.
     pub fn insert(&mut self, record: &ResourceRecord) {
-        self.inner.upsert(
-            record.name.clone(),
-            record.rtype_with_data.rtype(),
-            record.rtype_with_data.clone(),
-            Duration::from_secs(record.ttl.into()),
-        );
+        self.insert_all(std::slice::from_ref(record));
+    }
+
+    /// Insert RRs into the cache, grouped into sets by name and type.  Each set
+    /// replaces any RRs already cached with the same name and type, and all of
+    /// its RRs expire together.
+    pub fn insert_all(&mut self, records: &[ResourceRecord]) {
+        for ((name, rtype), (rtypes_with_data, ttl)) in rrsets(records) {
+            self.inner.upsert(
+                name,
+                rtype,
+                rtypes_with_data,
+                Duration::from_secs(self.ttl_limits.clamp(ttl).into()),
+            );
+        }
+    }
+
+    /// Insert an RR into the cache, scoped to a client subnet: it is only
+    /// returned by `get_scoped` with the same client subnet.  Like `insert`,
+    /// this replaces any RRs already cached for the subnet with the same name
+    /// and type.
+    pub fn insert_scoped(&mut self, client_subnet: &ClientSubnet, record: &ResourceRecord) {
+        self.insert_all_scoped(client_subnet, std::slice::from_ref(record));
+    }
+
+    /// Insert RRs into the cache, scoped to a client subnet, grouped into
+    /// record sets as in `insert_all`.
+    pub fn insert_all_scoped(&mut self, client_subnet: &ClientSubnet, records: &[ResourceRecord]) {
+        for ((name, rtype), (rtypes_with_data, ttl)) in rrsets(records) {
+            self.scoped.upsert(
+                (*client_subnet, name),
+                rtype,
+                rtypes_with_data,
+                Duration::from_secs(self.ttl_limits.clamp(ttl).into()),
+            );
+        }
     }
 
     /// Clear expired RRs and, if the cache has grown beyond its desired size,
-    /// prunes domains to get down to size.
+    /// prunes domains to get down to size.  The RRs scoped to a client subnet
+    /// are pruned separately, and have a desired size of their own.
     ///
     /// Returns `(has overflowed?, current size, num expired, num pruned)`.
     pub fn prune(&mut self) -> (bool, usize, usize, usize) {
-        self.inner.prune()
+        let (overflowed, size, expired, pruned) = self.inner.prune();
+        let (scoped_overflowed, scoped_size, scoped_expired, scoped_pruned) = self.scoped.prune();
+        (
+            overflowed || scoped_overflowed,
+            size + scoped_size,
+            expired + scoped_expired,
+            pruned + scoped_pruned,
+        )
+    }
+
+    /// Delete all records.
+    ///
+    /// Returns the number of records deleted.
+    pub fn clear(&mut self) -> usize {
+        self.inner.clear() + self.scoped.clear()
+    }
+
+    /// Delete the records for a domain.  A wildcard query deletes the records
+    /// of every type.
+    ///
+    /// Returns the number of records deleted.
+    pub fn remove(&mut self, name: &DomainName, qtype: QueryType) -> usize {
+        match qtype {
+            QueryType::Wildcard => {
+                self.inner.remove_partition(name)
+                    + self
+                        .scoped
+                        .remove_partitions_where(|(_, scoped_name)| scoped_name == name)
+            }
+            QueryType::Record(rtype) => {
+                let scoped_keys = self
+                    .scoped
+                    .partitions
+                    .keys()
+                    .filter(|(_, scoped_name)| scoped_name == name)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let mut removed = self.inner.remove(name, &rtype);
+                for key in scoped_keys {
+                    removed += self.scoped.remove(&key, &rtype);
+                }
+                removed
+            }
+            _ => 0,
+        }
+    }
+
+    /// Delete the records for a domain and all of its subdomains.
+    ///
+    /// Returns the number of records deleted.
+    pub fn remove_subtree(&mut self, name: &DomainName) -> usize {
+        self.inner
+            .remove_partitions_where(|partition_key| partition_key.is_subdomain_of(name))
+            + self
+                .scoped
+                .remove_partitions_where(|(_, scoped_name)| scoped_name.is_subdomain_of(name))
+    }
+
+    /// Change the desired size, of both the RRs scoped to a client subnet and
+    /// the others.  The cache is not shrunk until the next `prune`.
+    pub fn set_desired_size(&mut self, desired_size: usize) {
+        self.inner.set_desired_size(desired_size);
+        self.scoped.set_desired_size(desired_size);
+    }
+
+    /// Change the eviction policy.  The new policy starts off knowing only
+    /// when each domain was last read and when its records next expire.
+    ///
+    /// Does nothing if the policy is unchanged, so (for example) LFU access
+    /// counts are not lost.
+    pub fn set_policy(&mut self, policy: CachePolicy) {
+        if self.inner.policy.kind() != policy {
+            self.inner.set_policy(AnyEvictionPolicy::new(policy));
+        }
     }
+
+    /// Change the limits on the TTL of records inserted into the cache.
+    /// Records which are already in the cache are left alone.
+    pub fn set_ttl_limits(&mut self, ttl_limits: TtlLimits) {
+        self.ttl_limits = ttl_limits;
+    }
+
+    /// Get the statistics for each record type which has ever been cached
+    /// or looked up.
+    pub fn stats(&self) -> HashMap<RecordType, CacheStats> {
+        self.inner.stats().clone()
+    }
+}
+
+/// Helper for `SharedCache::insert_all`: drops the RRs with a TTL of zero or
+/// less, which must not be cached.
+fn unexpired(records: &[ResourceRecord]) -> Vec<ResourceRecord> {
+    records.iter().filter(|rr| rr.ttl > 0).cloned().collect()
+}
+
+/// Helper for `insert_all`: groups RRs into sets by (canonical) name and type,
+/// dropping duplicates.
+///
+/// The TTL of each set is the lowest TTL of its RRs, as RFC 2181 section 5.2
+/// says to do if the RRs in a set have different TTLs.
+fn rrsets(
+    records: &[ResourceRecord],
+) -> HashMap<(DomainName, RecordType), (Vec<RecordTypeWithData>, u32)> {
+    let mut rrsets: HashMap<_, (Vec<RecordTypeWithData>, u32)> = HashMap::new();
+    for record in records {
+        let rtype_with_data = &record.rtype_with_data;
+        let (rtypes_with_data, ttl) = rrsets
+            .entry((record.name.to_canonical(), rtype_with_data.rtype()))
+            .or_insert_with(|| (Vec::new(), record.ttl));
+        if !rtypes_with_data.contains(rtype_with_data) {
+            rtypes_with_data.push(rtype_with_data.clone());
+        }
+        *ttl = std::cmp::min(*ttl, record.ttl);
+    }
+    rrsets
 }
 
-/// Helper for `get_without_checking_expiration`: converts the cached
-/// record tuples into RRs.
+/// Helper for `get_without_checking_expiration`: converts a cached set of
+/// records into RRs, which all have the same TTL.
 fn to_rrs(
     name: &DomainName,
     now: Instant,
-    tuples: &[(RecordTypeWithData, Instant)],
+    rrset: &RecordSet<RecordTypeWithData>,
     rrs: &mut Vec<ResourceRecord>,
 ) {
-    for (rtype, expires) in tuples {
-        let ttl = if let Ok(ttl) = expires.saturating_duration_since(now).as_secs().try_into() {
-            ttl
-        } else {
-            u32::MAX
-        };
-
+    let ttl = rrset
+        .expires
+        .saturating_duration_since(now)
+        .as_secs()
+        .try_into()
+        .unwrap_or(u32::MAX);
+
+    for rtype_with_data in &rrset.values {
         rrs.push(ResourceRecord {
             name: name.clone(),
-            rtype_with_data: rtype.clone(),
+            rtype_with_data: rtype_with_data.clone(),
             rclass: RecordClass::IN,
             ttl,
         });
     }
 }
 
+/// Statistics about the records in the cache with a given record key.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    /// How many records there are.
+    pub entries: usize,
+    /// How many lookups found a record.
+    pub hits: u64,
+    /// How many lookups didn't find a record.
+    pub misses: u64,
+    /// How many records have been removed because they expired.
+    pub expired: u64,
+    /// How many records have been removed to get the cache down to size.
+    pub pruned: u64,
+}
+
 #[derive(Debug, Clone)]
-pub struct PartitionedCache<K1: Eq + Hash, K2: Eq + Hash, V> {
+pub struct PartitionedCache<K1: Eq + Hash, K2: Eq + Hash, V, P> {
     /// Cached entries, indexed by partition key.
     partitions: HashMap<K1, Partition<K2, V>>,
 
-    /// Priority queue of partition keys ordered by access times.
-    ///
-    /// When the cache is full and there are no expired records to prune,
-    /// partitions will instead be pruned in LRU order.
+    /// Chooses which partitions to prune when the cache is full and there are
+    /// no expired records to prune.
     ///
-    /// INVARIANT: the keys in here are exactly the keys in `partitions`.
-    access_priority: PriorityQueue<K1, Reverse<Instant>>,
+    /// INVARIANT: this tracks exactly the keys in `partitions`.
+    policy: P,
 
     /// Priority queue of partition keys ordered by expiry time.
     ///
@@ -271,6 +696,11 @@ pub struct PartitionedCache<K1: Eq + Hash, K2: Eq + Hash, V> {
 
     /// The desired maximum number of records in the cache.
     desired_size: usize,
+
+    /// Statistics for each record key.
+    ///
+    /// INVARIANT: the `entries` fields sum to `current_size`.
+    stats: HashMap<K2, CacheStats>,
 }
 
 /// The cached records for a domain.
@@ -286,41 +716,75 @@ struct Partition<K: Eq + Hash, V> {
 
     /// How many records there are.
     ///
-    /// INVARIANT: this is the sum of the vector lengths in `records`.
+    /// INVARIANT: this is the sum of the lengths of the `records`.
     size: usize,
 
     /// The records, further divided by record key.
-    records: HashMap<K, Vec<(V, Instant)>>,
+    records: HashMap<K, RecordSet<V>>,
+}
+
+/// The cached records for a partition and record key (in the resolver cache,
+/// the RRs with the same name and type), which are inserted, expire, and are
+/// removed together.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RecordSet<V> {
+    /// The records, with no duplicates.
+    ///
+    /// INVARIANT: this is not empty.
+    pub values: Vec<V>,
+
+    /// When the records expire.
+    pub expires: Instant,
 }
 
-impl<K1: Clone + Eq + Hash, K2: Copy + Eq + Hash, V: PartialEq> Default
-    for PartitionedCache<K1, K2, V>
+impl<
+        K1: Clone + Eq + Hash,
+        K2: Copy + Eq + Hash,
+        V: PartialEq,
+        P: EvictionPolicy<K1> + Default,
+    > Default for PartitionedCache<K1, K2, V, P>
 {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<K1: Clone + Eq + Hash, K2: Copy + Eq + Hash, V: PartialEq> PartitionedCache<K1, K2, V> {
+impl<
+        K1: Clone + Eq + Hash,
+        K2: Copy + Eq + Hash,
+        V: PartialEq,
+        P: EvictionPolicy<K1> + Default,
+    > PartitionedCache<K1, K2, V, P>
+{
     /// Create a new cache with a default desired size.
     pub fn new() -> Self {
         Self::with_desired_size(512)
     }
 
     /// Create a new cache with the given desired size.
+    pub fn with_desired_size(desired_size: usize) -> Self {
+        Self::with_policy(desired_size, P::default())
+    }
+}
+
+impl<K1: Clone + Eq + Hash, K2: Copy + Eq + Hash, V: PartialEq, P: EvictionPolicy<K1>>
+    PartitionedCache<K1, K2, V, P>
+{
+    /// Create a new cache with the given desired size and eviction policy.
     ///
     /// The `prune` method will remove expired records, and also enough records
-    /// (in least-recently-used order) to get down to this size.
-    pub fn with_desired_size(desired_size: usize) -> Self {
+    /// (in the order chosen by the policy) to get down to this size.
+    pub fn with_policy(desired_size: usize, policy: P) -> Self {
         Self {
             // `desired_size / 2` is a compromise: most partitions will have
             // more than one record, so `desired_size` would be too big for the
             // `partitions`.
             partitions: HashMap::with_capacity(desired_size / 2),
-            access_priority: PriorityQueue::with_capacity(desired_size),
+            policy,
             expiry_priority: PriorityQueue::with_capacity(desired_size),
             current_size: 0,
             desired_size,
+            stats: HashMap::new(),
         }
     }
 
@@ -331,11 +795,10 @@ impl<K1: Clone + Eq + Hash, K2: Copy + Eq + Hash, V: PartialEq> PartitionedCache
     pub fn get_partition_without_checking_expiration(
         &mut self,
         partition_key: &K1,
-    ) -> Option<&HashMap<K2, Vec<(V, Instant)>>> {
+    ) -> Option<&HashMap<K2, RecordSet<V>>> {
         if let Some(partition) = self.partitions.get_mut(partition_key) {
             partition.last_read = Instant::now();
-            self.access_priority
-                .change_priority(partition_key, Reverse(partition.last_read));
+            self.policy.access(partition_key, partition.last_read);
             return Some(&partition.records);
         }
 
@@ -343,91 +806,89 @@ impl<K1: Clone + Eq + Hash, K2: Copy + Eq + Hash, V: PartialEq> PartitionedCache
     }
 
     /// Get all records for the given partition and record key from the cache,
-    /// along with their expiration times.
+    /// along with their expiration time.
     ///
     /// These records may have expired if `prune` has not been called recently.
     pub fn get_without_checking_expiration(
         &mut self,
         partition_key: &K1,
         record_key: &K2,
-    ) -> Option<&[(V, Instant)]> {
+    ) -> Option<&RecordSet<V>> {
         if let Some(partition) = self.partitions.get_mut(partition_key) {
-            if let Some(tuples) = partition.records.get(record_key) {
+            if let Some(set) = partition.records.get(record_key) {
                 partition.last_read = Instant::now();
-                self.access_priority
-                    .change_priority(partition_key, Reverse(partition.last_read));
-                return Some(tuples);
+                self.policy.access(partition_key, partition.last_read);
+                return Some(set);
             }
         }
 
         None
     }
 
-    /// Insert a record into the cache, or reset the expiry time if already
-    /// present.
-    pub fn upsert(&mut self, partition_key: K1, record_key: K2, value: V, ttl: Duration) {
+    /// Insert a set of records into the cache, replacing any records already
+    /// cached with the same partition and record key.  The records all expire
+    /// at the same time.
+    ///
+    /// Duplicate values are not checked for: the caller must remove them.
+    /// Does nothing if `values` is empty.
+    pub fn upsert(&mut self, partition_key: K1, record_key: K2, values: Vec<V>, ttl: Duration) {
+        if values.is_empty() {
+            return;
+        }
+
         let now = Instant::now();
-        let expiry = now + ttl;
-        let tuple = (value, expiry);
+        let expires = now + ttl;
+        let inserted = values.len();
+        let set = RecordSet { values, expires };
         if let Some(partition) = self.partitions.get_mut(&partition_key) {
-            if let Some(tuples) = partition.records.get_mut(&record_key) {
-                let mut duplicate_expires_at = None;
-                for i in 0..tuples.len() {
-                    let t = &tuples[i];
-                    if t.0 == tuple.0 {
-                        duplicate_expires_at = Some(t.1);
-                        tuples.swap_remove(i);
-                        break;
-                    }
-                }
+            let replaced = partition
+                .records
+                .insert(record_key, set)
+                .map_or(0, |old| old.values.len());
+            partition.size = partition.size + inserted - replaced;
+            self.current_size = self.current_size + inserted - replaced;
+            let stats = self.stats.entry(record_key).or_default();
+            stats.entries = stats.entries + inserted - replaced;
 
-                tuples.push(tuple);
-
-                if let Some(dup_expiry) = duplicate_expires_at {
-                    partition.size -= 1;
-                    self.current_size -= 1;
-
-                    if dup_expiry == partition.next_expiry {
-                        let mut new_next_expiry = expiry;
-                        for (_, e) in tuples {
-                            if *e < new_next_expiry {
-                                new_next_expiry = *e;
-                            }
-                        }
-                        partition.next_expiry = new_next_expiry;
-                        self.expiry_priority
-                            .change_priority(&partition_key, Reverse(partition.next_expiry));
-                    }
-                }
-            } else {
-                partition.records.insert(record_key, vec![tuple]);
-            }
             partition.last_read = now;
-            partition.size += 1;
-            self.access_priority
-                .change_priority(&partition_key, Reverse(partition.last_read));
-            if expiry < partition.next_expiry {
-                partition.next_expiry = expiry;
+            self.policy.access(&partition_key, partition.last_read);
+
+            // replacing the soonest-to-expire set can make the next expiry
+            // later, so it has to be found again
+            let next_expiry = partition
+                .records
+                .values()
+                .map(|set| set.expires)
+                .min()
+                .unwrap_or(expires);
+            if next_expiry != partition.next_expiry {
+                partition.next_expiry = next_expiry;
                 self.expiry_priority
                     .change_priority(&partition_key, Reverse(partition.next_expiry));
+                self.policy
+                    .expiry_changed(&partition_key, partition.next_expiry);
             }
         } else {
             let mut records = HashMap::new();
-            records.insert(record_key, vec![tuple]);
+            records.insert(record_key, set);
             let partition = Partition {
                 last_read: now,
-                next_expiry: expiry,
-                size: 1,
+                next_expiry: expires,
+                size: inserted,
                 records,
             };
-            self.access_priority
-                .push(partition_key.clone(), Reverse(partition.last_read));
+            self.policy.insert(
+                partition_key.clone(),
+                partition.last_read,
+                partition.next_expiry,
+            );
             self.expiry_priority
                 .push(partition_key.clone(), Reverse(partition.next_expiry));
             self.partitions.insert(partition_key, partition);
-        }
 
-        self.current_size += 1;
+            self.current_size += inserted;
+            self.stats.entry(record_key).or_default().entries += inserted;
+        }
     }
 
     /// Delete all expired records.
@@ -447,8 +908,8 @@ impl<K1: Clone + Eq + Hash, K2: Copy + Eq + Hash, V: PartialEq> PartitionedCache
         pruned
     }
 
-    /// Delete all expired records, and then enough
-    /// least-recently-used records to reduce the cache to the desired
+    /// Delete all expired records, and then enough records (in the order
+    /// chosen by the eviction policy) to reduce the cache to the desired
     /// size.
     ///
     /// Returns `(has overflowed?, current size, num expired, num pruned)`.
@@ -458,60 +919,168 @@ impl<K1: Clone + Eq + Hash, K2: Copy + Eq + Hash, V: PartialEq> PartitionedCache
         let mut num_pruned = 0;
 
         while self.current_size > self.desired_size {
-            num_pruned += self.remove_least_recently_used();
+            num_pruned += self.evict();
         }
 
         (has_overflowed, self.current_size, num_expired, num_pruned)
     }
 
-    /// Helper for `remove_expired`: looks at the next-to-expire
-    /// domain and cleans up expired records from it.  This may delete
-    /// more than one record, and may even delete the whole domain.
+    /// Delete all records.  This doesn't count as the records expiring or
+    /// being pruned.
     ///
-    /// Returns the number of records removed.
-    fn remove_expired_step(&mut self) -> usize {
-        if let Some((partition_key, Reverse(expiry))) = self.expiry_priority.pop() {
-            let now = Instant::now();
+    /// Returns the number of records deleted.
+    pub fn clear(&mut self) -> usize {
+        for partition_key in self.partitions.keys() {
+            self.policy.remove(partition_key);
+        }
+        self.partitions.clear();
+        self.expiry_priority.clear();
+        for stats in self.stats.values_mut() {
+            stats.entries = 0;
+        }
 
-            if expiry > now {
-                self.expiry_priority.push(partition_key, Reverse(expiry));
-                return 0;
-            }
+        std::mem::take(&mut self.current_size)
+    }
 
-            if let Some(partition) = self.partitions.get_mut(&partition_key) {
+    /// Delete all records for the given partition and record key.  This
+    /// doesn't count as the records expiring or being pruned.
+    ///
+    /// Returns the number of records deleted.
+    pub fn remove(&mut self, partition_key: &K1, record_key: &K2) -> usize {
+        let Some(partition) = self.partitions.get_mut(partition_key) else {
+            return 0;
+        };
+        let Some(set) = partition.records.remove(record_key) else {
+            return 0;
+        };
+
+        let removed = set.values.len();
+        partition.size -= removed;
+        self.current_size -= removed;
+        self.stats.entry(*record_key).or_default().entries -= removed;
+
+        let next_expiry = partition.records.values().map(|set| set.expires).min();
+        if let Some(ne) = next_expiry {
+            if ne != partition.next_expiry {
+                partition.next_expiry = ne;
+                self.expiry_priority
+                    .change_priority(partition_key, Reverse(ne));
+                self.policy.expiry_changed(partition_key, ne);
+            }
+        } else {
+            self.partitions.remove(partition_key);
+            self.expiry_priority.remove(partition_key);
+            self.policy.remove(partition_key);
+        }
+
+        removed
+    }
+
+    /// Delete all records for the given partition key.  This doesn't count as
+    /// the records expiring or being pruned.
+    ///
+    /// Returns the number of records deleted.
+    pub fn remove_partition(&mut self, partition_key: &K1) -> usize {
+        let Some(partition) = self.partitions.remove(partition_key) else {
+            return 0;
+        };
+
+        self.expiry_priority.remove(partition_key);
+        self.policy.remove(partition_key);
+        for (rkey, set) in &partition.records {
+            self.stats.entry(*rkey).or_default().entries -= set.values.len();
+        }
+        self.current_size -= partition.size;
+
+        partition.size
+    }
+
+    /// Delete all records for every partition key matching the predicate.
+    /// This doesn't count as the records expiring or being pruned.
+    ///
+    /// Returns the number of records deleted.
+    pub fn remove_partitions_where(&mut self, predicate: impl Fn(&K1) -> bool) -> usize {
+        let partition_keys = self
+            .partitions
+            .keys()
+            .filter(|partition_key| predicate(partition_key))
+            .cloned()
+            .collect::<Vec<K1>>();
+
+        partition_keys
+            .iter()
+            .map(|partition_key| self.remove_partition(partition_key))
+            .sum()
+    }
+
+    /// Change the desired size.  Records are not removed until the next
+    /// `prune`.
+    pub fn set_desired_size(&mut self, desired_size: usize) {
+        self.desired_size = desired_size;
+    }
+
+    /// Count a lookup of a record key as a hit or a miss.
+    pub fn record_lookup(&mut self, record_key: K2, hit: bool) {
+        let stats = self.stats.entry(record_key).or_default();
+        if hit {
+            stats.hits += 1;
+        } else {
+            stats.misses += 1;
+        }
+    }
+
+    /// Get the statistics for each record key which has ever been inserted
+    /// or looked up.
+    pub fn stats(&self) -> &HashMap<K2, CacheStats> {
+        &self.stats
+    }
+
+    /// Helper for `remove_expired`: looks at the next-to-expire
+    /// domain and cleans up expired records from it.  This may delete
+    /// more than one record, and may even delete the whole domain.
+    ///
+    /// Returns the number of records removed.
+    fn remove_expired_step(&mut self) -> usize {
+        if let Some((partition_key, Reverse(expiry))) = self.expiry_priority.pop() {
+            let now = Instant::now();
+
+            if expiry > now {
+                self.expiry_priority.push(partition_key, Reverse(expiry));
+                return 0;
+            }
+
+            if let Some(partition) = self.partitions.get_mut(&partition_key) {
                 let mut pruned = 0;
 
-                let record_keys = partition.records.keys().copied().collect::<Vec<K2>>();
-                let mut next_expiry = None;
-                for rkey in record_keys {
-                    if let Some(tuples) = partition.records.get_mut(&rkey) {
-                        let len = tuples.len();
-                        tuples.retain(|(_, expiry)| expiry > &now);
-                        pruned += len - tuples.len();
-                        for (_, expiry) in tuples {
-                            match next_expiry {
-                                None => next_expiry = Some(*expiry),
-                                Some(t) if *expiry < t => next_expiry = Some(*expiry),
-                                _ => (),
-                            }
-                        }
+                let stats = &mut self.stats;
+                partition.records.retain(|rkey, set| {
+                    if set.expires > now {
+                        return true;
                     }
-                }
+                    let expired = set.values.len();
+                    pruned += expired;
+                    let stats = stats.entry(*rkey).or_default();
+                    stats.entries -= expired;
+                    stats.expired += expired as u64;
+                    false
+                });
+                let next_expiry = partition.records.values().map(|set| set.expires).min();
 
                 partition.size -= pruned;
 
                 if let Some(ne) = next_expiry {
                     partition.next_expiry = ne;
+                    self.policy.expiry_changed(&partition_key, ne);
                     self.expiry_priority.push(partition_key, Reverse(ne));
                 } else {
                     self.partitions.remove(&partition_key);
-                    self.access_priority.remove(&partition_key);
+                    self.policy.remove(&partition_key);
                 }
 
                 self.current_size -= pruned;
                 pruned
             } else {
-                self.access_priority.remove(&partition_key);
+                self.policy.remove(&partition_key);
                 0
             }
         } else {
@@ -519,15 +1088,33 @@ impl<K1: Clone + Eq + Hash, K2: Copy + Eq + Hash, V: PartialEq> PartitionedCache
         }
     }
 
+    /// Change the eviction policy.  The new policy starts off knowing only
+    /// when each partition was last read and when it next expires.
+    pub fn set_policy(&mut self, mut policy: P) {
+        for (partition_key, partition) in &self.partitions {
+            policy.insert(
+                partition_key.clone(),
+                partition.last_read,
+                partition.next_expiry,
+            );
+        }
+        self.policy = policy;
+    }
+
     /// Helper for `prune`: deletes all records associated with the
-    /// least recently used domain.
+    /// domain chosen by the eviction policy.
     ///
     /// Returns the number of records removed.
-    fn remove_least_recently_used(&mut self) -> usize {
-        if let Some((partition_key, _)) = self.access_priority.pop() {
+    fn evict(&mut self) -> usize {
+        if let Some(partition_key) = self.policy.evict() {
             self.expiry_priority.remove(&partition_key);
 
             if let Some(partition) = self.partitions.remove(&partition_key) {
+                for (rkey, set) in &partition.records {
+                    let stats = self.stats.entry(*rkey).or_default();
+                    stats.entries -= set.values.len();
+                    stats.pruned += set.values.len() as u64;
+                }
                 let pruned = partition.size;
                 self.current_size -= pruned;
                 pruned
@@ -540,9 +1127,214 @@ impl<K1: Clone + Eq + Hash, K2: Copy + Eq + Hash, V: PartialEq> PartitionedCache
     }
 }
 
+/// Decides which partition to prune when the cache has grown beyond its
+/// desired size and there are no expired records left to prune.
+///
+/// The cache tells the policy about every partition as it is added, read,
+/// and removed.
+pub trait EvictionPolicy<K> {
+    /// Start tracking a new partition.
+    fn insert(&mut self, key: K, now: Instant, next_expiry: Instant);
+
+    /// A partition has been read or written to.
+    fn access(&mut self, key: &K, now: Instant);
+
+    /// The time the next record in a partition expires at has changed.
+    fn expiry_changed(&mut self, key: &K, next_expiry: Instant);
+
+    /// Stop tracking a partition, because all of its records have expired.
+    fn remove(&mut self, key: &K);
+
+    /// Choose a partition to prune, and stop tracking it.
+    fn evict(&mut self) -> Option<K>;
+}
+
+/// Prune the least recently used partition.
+#[derive(Debug, Clone)]
+pub struct Lru<K: Eq + Hash> {
+    queue: PriorityQueue<K, Reverse<Instant>>,
+}
+
+impl<K: Eq + Hash> Default for Lru<K> {
+    fn default() -> Self {
+        Self {
+            queue: PriorityQueue::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash> EvictionPolicy<K> for Lru<K> {
+    fn insert(&mut self, key: K, now: Instant, _: Instant) {
+        self.queue.push(key, Reverse(now));
+    }
+
+    fn access(&mut self, key: &K, now: Instant) {
+        self.queue.change_priority(key, Reverse(now));
+    }
+
+    fn expiry_changed(&mut self, _: &K, _: Instant) {}
+
+    fn remove(&mut self, key: &K) {
+        self.queue.remove(key);
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        self.queue.pop().map(|(key, _)| key)
+    }
+}
+
+/// Prune the least frequently used partition, breaking ties by pruning the
+/// least recently used.
+#[derive(Debug, Clone)]
+pub struct Lfu<K: Eq + Hash> {
+    queue: PriorityQueue<K, (Reverse<u64>, Reverse<Instant>)>,
+}
+
+impl<K: Eq + Hash> Default for Lfu<K> {
+    fn default() -> Self {
+        Self {
+            queue: PriorityQueue::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash> EvictionPolicy<K> for Lfu<K> {
+    fn insert(&mut self, key: K, now: Instant, _: Instant) {
+        self.queue.push(key, (Reverse(1), Reverse(now)));
+    }
+
+    fn access(&mut self, key: &K, now: Instant) {
+        self.queue
+            .change_priority_by(key, |(Reverse(uses), last_used)| {
+                *uses = uses.saturating_add(1);
+                *last_used = Reverse(now);
+            });
+    }
+
+    fn expiry_changed(&mut self, _: &K, _: Instant) {}
+
+    fn remove(&mut self, key: &K) {
+        self.queue.remove(key);
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        self.queue.pop().map(|(key, _)| key)
+    }
+}
+
+/// Prune the partition with the record which will expire soonest.
+#[derive(Debug, Clone)]
+pub struct TtlOrdered<K: Eq + Hash> {
+    queue: PriorityQueue<K, Reverse<Instant>>,
+}
+
+impl<K: Eq + Hash> Default for TtlOrdered<K> {
+    fn default() -> Self {
+        Self {
+            queue: PriorityQueue::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash> EvictionPolicy<K> for TtlOrdered<K> {
+    fn insert(&mut self, key: K, _: Instant, next_expiry: Instant) {
+        self.queue.push(key, Reverse(next_expiry));
+    }
+
+    fn access(&mut self, _: &K, _: Instant) {}
+
+    fn expiry_changed(&mut self, key: &K, next_expiry: Instant) {
+        self.queue.change_priority(key, Reverse(next_expiry));
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.queue.remove(key);
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        self.queue.pop().map(|(key, _)| key)
+    }
+}
+
+/// One of the eviction policies, chosen at runtime.
+#[derive(Debug, Clone)]
+pub enum AnyEvictionPolicy<K: Eq + Hash> {
+    Lru(Lru<K>),
+    Lfu(Lfu<K>),
+    TtlOrdered(TtlOrdered<K>),
+}
+
+impl<K: Eq + Hash> AnyEvictionPolicy<K> {
+    pub fn new(policy: CachePolicy) -> Self {
+        match policy {
+            CachePolicy::Lru => Self::Lru(Lru::default()),
+            CachePolicy::Lfu => Self::Lfu(Lfu::default()),
+            CachePolicy::Ttl => Self::TtlOrdered(TtlOrdered::default()),
+        }
+    }
+
+    /// Which policy this is.
+    pub fn kind(&self) -> CachePolicy {
+        match self {
+            Self::Lru(_) => CachePolicy::Lru,
+            Self::Lfu(_) => CachePolicy::Lfu,
+            Self::TtlOrdered(_) => CachePolicy::Ttl,
+        }
+    }
+}
+
+impl<K: Eq + Hash> Default for AnyEvictionPolicy<K> {
+    fn default() -> Self {
+        Self::new(CachePolicy::default())
+    }
+}
+
+impl<K: Eq + Hash> EvictionPolicy<K> for AnyEvictionPolicy<K> {
+    fn insert(&mut self, key: K, now: Instant, next_expiry: Instant) {
+        match self {
+            Self::Lru(p) => p.insert(key, now, next_expiry),
+            Self::Lfu(p) => p.insert(key, now, next_expiry),
+            Self::TtlOrdered(p) => p.insert(key, now, next_expiry),
+        }
+    }
+
+    fn access(&mut self, key: &K, now: Instant) {
+        match self {
+            Self::Lru(p) => p.access(key, now),
+            Self::Lfu(p) => p.access(key, now),
+            Self::TtlOrdered(p) => p.access(key, now),
+        }
+    }
+
+    fn expiry_changed(&mut self, key: &K, next_expiry: Instant) {
+        match self {
+            Self::Lru(p) => p.expiry_changed(key, next_expiry),
+            Self::Lfu(p) => p.expiry_changed(key, next_expiry),
+            Self::TtlOrdered(p) => p.expiry_changed(key, next_expiry),
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        match self {
+            Self::Lru(p) => p.remove(key),
+            Self::Lfu(p) => p.remove(key),
+            Self::TtlOrdered(p) => p.remove(key),
+        }
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        match self {
+            Self::Lru(p) => p.evict(),
+            Self::Lfu(p) => p.evict(),
+            Self::TtlOrdered(p) => p.evict(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use dns_types::protocol::types::test_util::*;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
     use super::test_util::*;
     use super::*;
@@ -569,6 +1361,98 @@ mod tests {
         }
     }
 
+    #[test]
+    fn cache_put_ignores_case() {
+        let mut cache = Cache::new();
+        cache.insert(&a_record("WWW.Example.com.", Ipv4Addr::new(1, 1, 1, 1)));
+
+        let rrs = cache.get(&domain("www.EXAMPLE.com."), QueryType::Wildcard);
+        assert_eq!(1, rrs.len());
+        assert_eq!("www.EXAMPLE.com.", rrs[0].name.to_dotted_string());
+
+        let rrs = cache.all_records();
+        assert_eq!(1, rrs.len());
+        assert_eq!("www.example.com.", rrs[0].name.to_dotted_string());
+    }
+
+    #[test]
+    fn cache_to_zone() {
+        let mut cache = Cache::new();
+        let mut a_rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        a_rr.ttl = 3600;
+        let mut expired_rr = a_record("old.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        expired_rr.ttl = 0;
+        cache.insert(&a_rr);
+        cache.insert(&expired_rr);
+        cache.insert(&cname_record("example.com.", "www.example.com."));
+
+        let zone = cache.to_zone();
+        assert!(!zone.is_authoritative());
+
+        let all_records = zone.all_records();
+        assert_eq!(2, all_records.len());
+        let www = &all_records[&domain("www.example.com.")];
+        assert_eq!(1, www.len());
+        assert_eq!(a_rr.rtype_with_data, www[0].rtype_with_data);
+        assert!((3599..=3600).contains(&www[0].ttl));
+        let apex = &all_records[&domain("example.com.")];
+        assert_eq!(1, apex.len());
+        assert!((299..=300).contains(&apex[0].ttl));
+    }
+
+    #[test]
+    fn cache_put_clamps_ttl() {
+        let mut cache = Cache::new();
+        cache.set_ttl_limits(TtlLimits {
+            min: Some(60),
+            max: Some(3600),
+        });
+
+        let mut short_rr = a_record("short.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        short_rr.ttl = 5;
+        let mut long_rr = a_record("long.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        long_rr.ttl = 604_800;
+        cache.insert(&short_rr);
+        cache.insert(&long_rr);
+
+        let short_ttl = cache.get(&short_rr.name, QueryType::Wildcard)[0].ttl;
+        let long_ttl = cache.get(&long_rr.name, QueryType::Wildcard)[0].ttl;
+        assert!((59..=60).contains(&short_ttl));
+        assert!((3599..=3600).contains(&long_ttl));
+    }
+
+    #[test]
+    fn cache_get_ttl_counts_down() {
+        let mut cache = Cache::new();
+        let mut rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        rr.ttl = 300;
+        cache.insert(&rr);
+
+        let rrset = cache
+            .inner
+            .get_without_checking_expiration(&rr.name, &RecordType::A)
+            .unwrap()
+            .clone();
+        let mut rrs = Vec::new();
+        to_rrs(
+            &rr.name,
+            Instant::now() + Duration::from_secs(100),
+            &rrset,
+            &mut rrs,
+        );
+        assert_eq!(1, rrs.len());
+        assert!((199..=200).contains(&rrs[0].ttl));
+
+        let mut rrs = Vec::new();
+        to_rrs(
+            &rr.name,
+            Instant::now() + Duration::from_secs(301),
+            &rrset,
+            &mut rrs,
+        );
+        assert_eq!(0, rrs[0].ttl);
+    }
+
     #[test]
     fn cache_put_deduplicates_and_maintains_invariants() {
         let mut cache = Cache::new();
@@ -582,6 +1466,57 @@ mod tests {
         assert_invariants(&cache);
     }
 
+    #[test]
+    fn cache_put_rrset_shares_lowest_ttl() {
+        let mut cache = Cache::new();
+        let mut rr1 = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        rr1.ttl = 300;
+        let mut rr2 = a_record("www.example.com.", Ipv4Addr::new(2, 2, 2, 2));
+        rr2.ttl = 60;
+        let aaaa_rr = aaaa_record("www.example.com.", Ipv6Addr::LOCALHOST);
+        cache.insert_all(&[rr1.clone(), rr2.clone(), aaaa_rr]);
+
+        let rrs = cache.get(&rr1.name, QueryType::Record(RecordType::A));
+        assert_eq!(2, rrs.len());
+        assert_eq!(rr1.rtype_with_data, rrs[0].rtype_with_data);
+        assert_eq!(rr2.rtype_with_data, rrs[1].rtype_with_data);
+        assert_eq!(rrs[0].ttl, rrs[1].ttl);
+        assert!((59..=60).contains(&rrs[0].ttl));
+        assert_eq!(3, cache.inner.current_size);
+        assert_invariants(&cache);
+    }
+
+    #[test]
+    fn cache_put_replaces_whole_rrset() {
+        let mut cache = Cache::new();
+        cache.insert_all(&[
+            a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+            a_record("www.example.com.", Ipv4Addr::new(2, 2, 2, 2)),
+        ]);
+        let rr = a_record("www.example.com.", Ipv4Addr::new(3, 3, 3, 3));
+        cache.insert(&rr);
+
+        assert_cache_response(&rr, &cache.get(&rr.name, QueryType::Record(RecordType::A)));
+        assert_eq!(1, cache.inner.current_size);
+        assert_invariants(&cache);
+    }
+
+    #[test]
+    fn cache_put_later_expiry_maintains_invariants() {
+        let mut cache = Cache::new();
+        let mut short_rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        short_rr.ttl = 60;
+        let mut long_rr = short_rr.clone();
+        long_rr.ttl = 3600;
+        cache.insert(&short_rr);
+        cache.insert(&aaaa_record("www.example.com.", Ipv6Addr::LOCALHOST));
+        cache.insert(&long_rr);
+
+        assert!((3599..=3600)
+            .contains(&cache.get(&long_rr.name, QueryType::Record(RecordType::A))[0].ttl));
+        assert_invariants(&cache);
+    }
+
     #[test]
     fn cache_put_maintains_invariants() {
         let mut cache = Cache::new();
@@ -638,6 +1573,91 @@ mod tests {
         assert_invariants(&cache);
     }
 
+    #[test]
+    fn cache_put_then_prune_maintains_invariants_with_each_policy() {
+        for policy in [CachePolicy::Lru, CachePolicy::Lfu, CachePolicy::Ttl] {
+            let mut cache = Cache::with_policy(25, policy);
+
+            for _ in 0..100 {
+                let mut rr = arbitrary_resourcerecord();
+                rr.rclass = RecordClass::IN;
+                rr.ttl = 300; // this case isn't testing expiration
+                cache.insert(&rr);
+            }
+
+            let (overflow, current_size, _, _) = cache.prune();
+            assert!(overflow);
+            assert!(current_size <= 25);
+            assert_invariants(&cache);
+        }
+    }
+
+    #[test]
+    fn cache_lfu_prunes_least_frequently_used() {
+        let mut cache = Cache::with_policy(2, CachePolicy::Lfu);
+        let rrs = [
+            a_record("a.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+            a_record("b.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+            a_record("c.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+        ];
+        for rr in &rrs {
+            cache.insert(rr);
+        }
+
+        // `a` is the least recently used, but `b` is the least frequently used
+        for (rr, uses) in rrs.iter().zip([3, 1, 2]) {
+            for _ in 0..uses {
+                cache.get(&rr.name, QueryType::Record(RecordType::A));
+            }
+        }
+
+        assert_eq!((true, 2, 0, 1), cache.prune());
+        assert_eq!(1, cache.get(&rrs[0].name, QueryType::Wildcard).len());
+        assert_eq!(0, cache.get(&rrs[1].name, QueryType::Wildcard).len());
+        assert_eq!(1, cache.get(&rrs[2].name, QueryType::Wildcard).len());
+        assert_invariants(&cache);
+    }
+
+    #[test]
+    fn cache_ttl_prunes_soonest_to_expire() {
+        let mut cache = Cache::with_policy(2, CachePolicy::Ttl);
+        let mut rrs = [
+            a_record("a.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+            a_record("b.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+            a_record("c.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+        ];
+        for (rr, ttl) in rrs.iter_mut().zip([200, 100, 300]) {
+            rr.ttl = ttl;
+            cache.insert(rr);
+        }
+
+        assert_eq!((true, 2, 0, 1), cache.prune());
+        assert_eq!(1, cache.get(&rrs[0].name, QueryType::Wildcard).len());
+        assert_eq!(0, cache.get(&rrs[1].name, QueryType::Wildcard).len());
+        assert_eq!(1, cache.get(&rrs[2].name, QueryType::Wildcard).len());
+        assert_invariants(&cache);
+    }
+
+    #[test]
+    fn cache_set_policy_maintains_invariants() {
+        let mut cache = Cache::with_desired_size(25);
+
+        for _ in 0..100 {
+            let mut rr = arbitrary_resourcerecord();
+            rr.rclass = RecordClass::IN;
+            rr.ttl = 300; // this case isn't testing expiration
+            cache.insert(&rr);
+        }
+
+        for policy in [CachePolicy::Lfu, CachePolicy::Ttl, CachePolicy::Lru] {
+            cache.set_policy(policy);
+            assert_invariants(&cache);
+        }
+
+        cache.prune();
+        assert_invariants(&cache);
+    }
+
     #[test]
     fn cache_put_then_expire_maintains_invariants() {
         let mut cache = Cache::new();
@@ -673,6 +1693,202 @@ mod tests {
         assert_invariants(&cache);
     }
 
+    #[test]
+    fn cache_set_desired_size_then_prune() {
+        let mut cache = Cache::with_desired_size(100);
+
+        for _ in 0..100 {
+            let mut rr = arbitrary_resourcerecord();
+            rr.rclass = RecordClass::IN;
+            rr.ttl = 300; // this case isn't testing expiration
+            cache.insert(&rr);
+        }
+
+        cache.set_desired_size(25);
+        let (overflow, current_size, _, _) = cache.prune();
+        assert!(overflow);
+        assert!(current_size <= 25);
+        assert_invariants(&cache);
+    }
+
+    #[test]
+    fn cache_clear_removes_everything() {
+        let mut cache = Cache::new();
+
+        for _ in 0..100 {
+            let mut rr = arbitrary_resourcerecord();
+            rr.rclass = RecordClass::IN;
+            rr.ttl = 300; // this case isn't testing expiration
+            cache.insert(&rr);
+        }
+
+        let size = cache.inner.current_size;
+        assert_eq!(size, cache.clear());
+        assert_eq!(0, cache.inner.current_size);
+        assert_eq!(None, cache.inner.policy.evict());
+        assert_invariants(&cache);
+    }
+
+    #[test]
+    fn cache_remove_deletes_one_type() {
+        let mut cache = Cache::new();
+        let a_rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        let aaaa_rr = aaaa_record("www.example.com.", Ipv6Addr::LOCALHOST);
+        cache.insert(&a_rr);
+        cache.insert(&aaaa_rr);
+
+        assert_eq!(
+            1,
+            cache.remove(&a_rr.name, QueryType::Record(RecordType::A))
+        );
+        assert_eq!(
+            0,
+            cache.remove(&a_rr.name, QueryType::Record(RecordType::A))
+        );
+        assert_invariants(&cache);
+
+        assert_cache_response(
+            &aaaa_rr,
+            &cache.get(&aaaa_rr.name, QueryType::Record(RecordType::AAAA)),
+        );
+    }
+
+    #[test]
+    fn cache_remove_wildcard_deletes_every_type() {
+        let mut cache = Cache::new();
+        let a_rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        let aaaa_rr = aaaa_record("www.example.com.", Ipv6Addr::LOCALHOST);
+        cache.insert(&a_rr);
+        cache.insert(&aaaa_rr);
+
+        assert_eq!(2, cache.remove(&a_rr.name, QueryType::Wildcard));
+        assert_eq!(0, cache.inner.current_size);
+        assert_invariants(&cache);
+    }
+
+    #[test]
+    fn cache_remove_subtree_deletes_subdomains() {
+        let mut cache = Cache::new();
+        cache.insert(&a_record("example.com.", Ipv4Addr::new(1, 1, 1, 1)));
+        cache.insert(&a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1)));
+        cache.insert(&a_record("a.b.example.com.", Ipv4Addr::new(1, 1, 1, 1)));
+        let kept_rr = a_record("www.example.net.", Ipv4Addr::new(1, 1, 1, 1));
+        cache.insert(&kept_rr);
+
+        assert_eq!(3, cache.remove_subtree(&domain("example.com.")));
+        assert_invariants(&cache);
+
+        assert_cache_response(
+            &kept_rr,
+            &cache.get(&kept_rr.name, QueryType::Record(RecordType::A)),
+        );
+    }
+
+    #[test]
+    fn cache_scoped_only_for_same_subnet() {
+        let mut cache = Cache::new();
+        let client_subnet = ClientSubnet::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)), 24);
+        let other_subnet = ClientSubnet::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)), 24);
+        let rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        cache.insert_scoped(&client_subnet, &rr);
+
+        assert_cache_response(
+            &rr,
+            &cache.get_scoped(&client_subnet, &rr.name, QueryType::Wildcard),
+        );
+        assert!(cache
+            .get_scoped(&other_subnet, &rr.name, QueryType::Wildcard)
+            .is_empty());
+        assert!(cache.get(&rr.name, QueryType::Wildcard).is_empty());
+        assert!(cache.all_records().is_empty());
+    }
+
+    #[test]
+    fn cache_remove_deletes_scoped() {
+        let mut cache = Cache::new();
+        let client_subnet = ClientSubnet::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 56);
+        let rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        cache.insert_scoped(&client_subnet, &rr);
+        cache.insert(&rr);
+
+        assert_eq!(2, cache.remove(&rr.name, QueryType::Record(RecordType::A)));
+        assert!(cache
+            .get_scoped(&client_subnet, &rr.name, QueryType::Wildcard)
+            .is_empty());
+    }
+
+    #[test]
+    fn cache_all_records_skips_expired() {
+        let mut cache = Cache::new();
+        let mut expired_rr = a_record("a.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        expired_rr.ttl = 0;
+        let kept_rr = aaaa_record("b.example.com.", Ipv6Addr::LOCALHOST);
+        cache.insert(&expired_rr);
+        cache.insert(&kept_rr);
+
+        assert_cache_response(&kept_rr, &cache.all_records());
+    }
+
+    #[test]
+    fn cache_get_counts_hits_and_misses() {
+        let mut cache = Cache::new();
+        let rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        cache.insert(&rr);
+
+        cache.get(&rr.name, QueryType::Record(RecordType::A));
+        cache.get(&rr.name, QueryType::Record(RecordType::AAAA));
+        cache.get(&rr.name, QueryType::Wildcard);
+
+        let stats = cache.stats();
+        assert_eq!(
+            Some(&CacheStats {
+                entries: 1,
+                hits: 2,
+                ..Default::default()
+            }),
+            stats.get(&RecordType::A)
+        );
+        assert_eq!(
+            Some(&CacheStats {
+                misses: 1,
+                ..Default::default()
+            }),
+            stats.get(&RecordType::AAAA)
+        );
+    }
+
+    #[test]
+    fn cache_stats_count_expired_and_pruned() {
+        let mut cache = Cache::with_desired_size(1);
+        let mut expired_rr = a_record("a.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        expired_rr.ttl = 0;
+        let pruned_rr = a_record("b.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        let kept_rr = aaaa_record("c.example.com.", Ipv6Addr::LOCALHOST);
+        cache.insert(&expired_rr);
+        cache.insert(&pruned_rr);
+        cache.insert(&kept_rr);
+
+        assert_eq!((true, 1, 1, 1), cache.prune());
+
+        let stats = cache.stats();
+        assert_eq!(
+            Some(&CacheStats {
+                expired: 1,
+                pruned: 1,
+                ..Default::default()
+            }),
+            stats.get(&RecordType::A)
+        );
+        assert_eq!(
+            Some(&CacheStats {
+                entries: 1,
+                ..Default::default()
+            }),
+            stats.get(&RecordType::AAAA)
+        );
+        assert_invariants(&cache);
+    }
+
     fn assert_invariants(cache: &Cache) {
         assert_eq!(
             cache.inner.current_size,
@@ -683,11 +1899,11 @@ mod tests {
                 .map(|e| e.size)
                 .sum::<usize>()
         );
-
         assert_eq!(
-            cache.inner.partitions.len(),
-            cache.inner.access_priority.len()
+            cache.inner.current_size,
+            cache.inner.stats.values().map(|s| s.entries).sum::<usize>()
         );
+
         assert_eq!(
             cache.inner.partitions.len(),
             cache.inner.expiry_priority.len()
@@ -699,23 +1915,21 @@ mod tests {
         for (name, partition) in &cache.inner.partitions {
             assert_eq!(
                 partition.size,
-                partition.records.values().map(Vec::len).sum::<usize>()
+                partition
+                    .records
+                    .values()
+                    .map(|set| set.values.len())
+                    .sum::<usize>()
             );
 
-            let mut min_expires = None;
-            for (rtype, tuples) in &partition.records {
-                for (rtype_with_data, expires) in tuples {
+            for (rtype, set) in &partition.records {
+                assert!(!set.values.is_empty());
+                for (i, rtype_with_data) in set.values.iter().enumerate() {
                     assert_eq!(*rtype, rtype_with_data.rtype());
-
-                    if let Some(e) = min_expires {
-                        if *expires < e {
-                            min_expires = Some(*expires);
-                        }
-                    } else {
-                        min_expires = Some(*expires);
-                    }
+                    assert!(!set.values[i + 1..].contains(rtype_with_data));
                 }
             }
+            let min_expires = partition.records.values().map(|set| set.expires).min();
 
             assert_eq!(Some(partition.next_expiry), min_expires);
 
@@ -723,8 +1937,18 @@ mod tests {
             expiry_priority.push(name.clone(), Reverse(partition.next_expiry));
         }
 
-        assert_eq!(cache.inner.access_priority, access_priority);
         assert_eq!(cache.inner.expiry_priority, expiry_priority);
+
+        match &cache.inner.policy {
+            AnyEvictionPolicy::Lru(lru) => assert_eq!(lru.queue, access_priority),
+            AnyEvictionPolicy::Lfu(lfu) => {
+                assert_eq!(cache.inner.partitions.len(), lfu.queue.len());
+                for name in cache.inner.partitions.keys() {
+                    assert!(lfu.queue.get(name).is_some());
+                }
+            }
+            AnyEvictionPolicy::TtlOrdered(ttl) => assert_eq!(ttl.queue, expiry_priority),
+        }
     }
 }
 
@@ -1,30 +1,80 @@
+use std::time::{Duration, Instant};
+
 use dns_types::protocol::types::*;
 use dns_types::zones::types::Zones;
 
 use crate::cache::SharedCache;
+use crate::events::{Event, Observer};
 use crate::metrics::Metrics;
+use crate::util::types::{Allowlist, Timeouts, ZonePrecedence, ZonePrecedenceRules};
 
 pub struct Context<'a, CT> {
     // global context
     pub r: CT,
     pub zones: &'a Zones,
+    pub allowlist: &'a Allowlist,
     pub cache: &'a SharedCache,
+    zone_precedence: Option<&'a ZonePrecedenceRules>,
+    observer: Option<&'a dyn Observer>,
     // request state
     question_stack: Vec<Question>,
+    query_timeout: Duration,
+    deadline: Instant,
     metrics: Metrics,
 }
 
 impl<'a, CT> Context<'a, CT> {
-    pub fn new(r: CT, zones: &'a Zones, cache: &'a SharedCache, recursion_limit: usize) -> Self {
+    pub fn new(
+        r: CT,
+        zones: &'a Zones,
+        allowlist: &'a Allowlist,
+        cache: &'a SharedCache,
+        timeouts: Timeouts,
+        recursion_limit: usize,
+    ) -> Self {
         Self {
             r,
             zones,
+            allowlist,
             cache,
+            zone_precedence: None,
+            observer: None,
             question_stack: Vec::with_capacity(recursion_limit),
+            query_timeout: timeouts.query,
+            deadline: Instant::now() + timeouts.resolution,
             metrics: Metrics::new(),
         }
     }
 
+    /// Send events to an observer.
+    pub fn with_observer(mut self, observer: Option<&'a dyn Observer>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Combine non-authoritative zones with the cache as these rules say,
+    /// rather than always merging them.
+    pub fn with_zone_precedence(mut self, zone_precedence: &'a ZonePrecedenceRules) -> Self {
+        self.zone_precedence = Some(zone_precedence);
+        self
+    }
+
+    /// How to combine the records for this name in a non-authoritative zone
+    /// with the cache, along with the domain of the rule which says so, if
+    /// there is one.
+    pub fn zone_precedence(&self, name: &DomainName) -> (ZonePrecedence, Option<DomainName>) {
+        self.zone_precedence
+            .map_or((ZonePrecedence::default(), None), |rules| rules.get(name))
+    }
+
+    /// Send an event to the observer, if there is one.  The event is only
+    /// constructed if there is.
+    pub fn observe(&self, event: impl FnOnce() -> Event) {
+        if let Some(observer) = self.observer {
+            observer.event(event());
+        }
+    }
+
     pub fn metrics(&mut self) -> &mut Metrics {
         &mut self.metrics
     }
@@ -33,6 +83,17 @@ impl<'a, CT> Context<'a, CT> {
         self.metrics
     }
 
+    /// How long is left to finish resolving the question.
+    pub fn time_remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    /// How long to wait for the response to a query sent to an upstream
+    /// nameserver: the query timeout, or the time remaining if that is less.
+    pub fn query_timeout(&self) -> Duration {
+        std::cmp::min(self.query_timeout, self.time_remaining())
+    }
+
     pub fn at_recursion_limit(&self) -> bool {
         self.question_stack.len() == self.question_stack.capacity()
     }
@@ -0,0 +1,102 @@
+use std::fmt;
+use std::sync::mpsc;
+
+use dns_types::protocol::types::*;
+
+use crate::util::types::{ResolutionError, Upstream, UpstreamError};
+
+/// Something which happened while resolving a question.  These are sent to an
+/// `Observer` as they happen, so library users can follow resolution without
+/// parsing the logs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The cache had records for a name and type.
+    CacheHit { name: DomainName, qtype: QueryType },
+    /// The cache had no records for a name and type.
+    CacheMiss { name: DomainName, qtype: QueryType },
+    /// A local zone was used to answer a question.
+    ZoneMatch {
+        name: DomainName,
+        apex: DomainName,
+        is_authoritative: bool,
+    },
+    /// A query was sent to an upstream nameserver.
+    UpstreamQuery {
+        upstream: Upstream,
+        question: Question,
+    },
+    /// An upstream nameserver answered a query, or failed to.
+    UpstreamResponse {
+        upstream: Upstream,
+        error: Option<UpstreamError>,
+    },
+    /// A delegation to other nameservers was followed.
+    Referral {
+        name: DomainName,
+        nameservers: Vec<DomainName>,
+    },
+    /// A CNAME was followed.
+    CNAME {
+        name: DomainName,
+        target: DomainName,
+    },
+    /// The question could not be answered.
+    Error { error: ResolutionError },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::CacheHit { name, qtype } => write!(f, "cache hit: {name} {qtype}"),
+            Event::CacheMiss { name, qtype } => write!(f, "cache miss: {name} {qtype}"),
+            Event::ZoneMatch {
+                name,
+                apex,
+                is_authoritative,
+            } => write!(
+                f,
+                "zone match: {name} in {}zone {apex}",
+                if *is_authoritative {
+                    "authoritative "
+                } else {
+                    "non-authoritative "
+                }
+            ),
+            Event::UpstreamQuery { upstream, question } => {
+                write!(f, "upstream query: {question} to {upstream}")
+            }
+            Event::UpstreamResponse {
+                upstream,
+                error: None,
+            } => write!(f, "upstream response: from {upstream}"),
+            Event::UpstreamResponse {
+                upstream,
+                error: Some(error),
+            } => write!(f, "upstream response: {upstream} {error}"),
+            Event::Referral { name, nameservers } => {
+                write!(f, "referral: {name} to")?;
+                for nameserver in nameservers {
+                    write!(f, " {nameserver}")?;
+                }
+                Ok(())
+            }
+            Event::CNAME { name, target } => write!(f, "cname: {name} to {target}"),
+            Event::Error { error } => write!(f, "error: {error}"),
+        }
+    }
+}
+
+/// Receives the events from resolving a question.
+///
+/// Events are sent from the task doing the resolution, so this should return
+/// quickly: to process events elsewhere, use an `mpsc::Sender<Event>`.
+pub trait Observer: Send + Sync {
+    fn event(&self, event: Event);
+}
+
+impl Observer for mpsc::Sender<Event> {
+    fn event(&self, event: Event) {
+        // the receiver going away just means nobody is interested any more
+        _ = self.send(event);
+    }
+}
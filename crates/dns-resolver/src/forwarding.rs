@@ -1,18 +1,25 @@
 use async_recursion::async_recursion;
-use std::net::SocketAddr;
-use std::time::Duration;
+use tokio::task::JoinSet;
 use tokio::time::timeout;
 use tracing::Instrument;
 
 use dns_types::protocol::types::*;
 
 use crate::context::Context;
+use crate::events::Event;
 use crate::local::{resolve_local, LocalResolutionResult};
 use crate::util::nameserver::*;
 use crate::util::types::*;
 
 pub struct ForwardingContextInner {
-    pub forward_address: SocketAddr,
+    /// Guaranteed to be non-empty.
+    pub upstreams: Vec<Upstream>,
+    pub strategy: ForwardingStrategy,
+    pub fallback: FallbackPolicy,
+    pub security: UpstreamSecurityRules,
+    pub network: NetworkOptions,
+    /// Sent to the upstream nameservers, if present.
+    pub client_subnet: Option<ClientSubnet>,
 }
 
 pub type ForwardingContext<'a> = Context<'a, ForwardingContextInner>;
@@ -24,7 +31,11 @@ pub type ForwardingContext<'a> = Context<'a, ForwardingContextInner>;
 /// nameserver can spoof any records it wants, very little validation
 /// is done of its responses.
 ///
-/// This has a 60s timeout.
+/// If there is a client subnet, it is sent to the other nameserver.  Answers
+/// which the other nameserver says are only for that subnet are cached for
+/// that subnet alone.
+///
+/// This gives up when the context's resolution timeout is reached.
 ///
 /// # Errors
 ///
@@ -34,7 +45,7 @@ pub async fn resolve_forwarding<'a>(
     question: &Question,
 ) -> Result<ResolvedRecord, ResolutionError> {
     if let Ok(res) = timeout(
-        Duration::from_secs(60),
+        context.time_remaining(),
         resolve_forwarding_notimeout(context, question),
     )
     .await
@@ -79,51 +90,195 @@ async fn resolve_forwarding_notimeout<'a>(
             ..
         }) => {
             context.push_question(question);
-            let answer = match resolve_forwarding_notimeout(context, &cname_question)
+            if context.is_duplicate_question(&cname_question) {
+                tracing::debug!("hit CNAME loop");
+                context.pop_question();
+                return Err(ResolutionError::CnameLoop {
+                    question: cname_question,
+                });
+            }
+            let answer = resolve_forwarding_notimeout(context, &cname_question)
                 .instrument(tracing::error_span!("resolve_forwarding", %cname_question))
                 .await
-            {
-                Ok(resolved) => {
+                .map(|resolved| {
                     let soa_rr = resolved.soa_rr().cloned();
                     let mut r_rrs = resolved.rrs();
                     let mut combined_rrs = Vec::with_capacity(rrs.len() + r_rrs.len());
                     combined_rrs.append(&mut rrs);
                     combined_rrs.append(&mut r_rrs);
-                    Ok(ResolvedRecord::NonAuthoritative {
+                    ResolvedRecord::NonAuthoritative {
                         rrs: combined_rrs,
                         soa_rr,
-                    })
-                }
-                Err(_) => Err(ResolutionError::DeadEnd {
-                    question: cname_question,
-                }),
-            };
+                    }
+                });
             context.pop_question();
             return answer;
         }
+        Err(error @ ResolutionError::CnameLoop { .. }) => return Err(error),
         Err(_) => (),
     }
 
-    if let Some(response) = query_nameserver(context.r.forward_address, question.clone(), true)
-        .instrument(tracing::error_span!("query_nameserver"))
-        .await
+    if let Some(client_subnet) = &context.r.client_subnet {
+        let rrs = context
+            .cache
+            .get_scoped(client_subnet, &question.name, question.qtype);
+        if !rrs.is_empty() {
+            tracing::trace!(%client_subnet, "scoped cache HIT");
+            context.metrics().cache_hit();
+            prioritising_merge(&mut combined_rrs, rrs);
+            return Ok(ResolvedRecord::NonAuthoritative {
+                rrs: combined_rrs,
+                soa_rr: None,
+            });
+        }
+    }
+
+    match query_forwarders(context, question).await {
+        Ok(response) => {
+            context.metrics().nameserver_hit();
+            tracing::trace!("nameserver HIT");
+            // Propagate SOA RR for NXDOMAIN / NODATA responses
+            let soa_rr = get_nxdomain_nodata_soa(question, &response, 0).cloned();
+            let scope = response_scope(context.r.client_subnet.as_ref(), &response);
+            let rrs = response.answers;
+            match scope {
+                Some(client_subnet) => context.cache.insert_all_scoped(client_subnet, &rrs),
+                None => context.cache.insert_all(&rrs),
+            }
+            prioritising_merge(&mut combined_rrs, rrs);
+            Ok(ResolvedRecord::NonAuthoritative {
+                rrs: combined_rrs,
+                soa_rr,
+            })
+        }
+        Err(error) => {
+            context.metrics().nameserver_miss();
+            tracing::trace!("nameserver MISS");
+            Err(ResolutionError::Upstream {
+                question: question.clone(),
+                error,
+            })
+        }
+    }
+}
+
+/// Query the upstream nameservers according to the forwarding strategy,
+/// returning the first usable response, or the reason the last nameserver to
+/// be tried could not be used.
+async fn query_forwarders(
+    context: &ForwardingContext<'_>,
+    question: &Question,
+) -> Result<Message, UpstreamError> {
+    let query_timeout = context.query_timeout();
+    let network = context.r.network;
+    let client_subnet = context.r.client_subnet;
+
+    // always overwritten, as there is at least one upstream
+    let mut last_error = UpstreamError::Unreachable;
+
+    match context.r.strategy {
+        ForwardingStrategy::Failover => {
+            for upstream in &context.r.upstreams {
+                observe_query(context, upstream, question);
+                let result = query_nameserver(
+                    upstream,
+                    question.clone(),
+                    true,
+                    client_subnet,
+                    query_timeout,
+                    network,
+                    &context.r.fallback,
+                    context.r.security.get(upstream),
+                )
+                .instrument(tracing::error_span!("query_nameserver", %upstream))
+                .await;
+                observe_response(context, upstream, &result);
+                match result {
+                    Ok(response) => return Ok(response),
+                    Err(error) => last_error = error,
+                }
+            }
+        }
+        ForwardingStrategy::Race => {
+            let mut set = JoinSet::new();
+            for upstream in &context.r.upstreams {
+                observe_query(context, upstream, question);
+                let upstream = upstream.clone();
+                let question = question.clone();
+                let fallback = context.r.fallback.clone();
+                let security = context.r.security.clone();
+                let span = tracing::error_span!("query_nameserver", %upstream);
+                set.spawn(
+                    async move {
+                        let result = query_nameserver(
+                            &upstream,
+                            question,
+                            true,
+                            client_subnet,
+                            query_timeout,
+                            network,
+                            &fallback,
+                            security.get(&upstream),
+                        )
+                        .await;
+                        (upstream, result)
+                    }
+                    .instrument(span),
+                );
+            }
+
+            // dropping the `JoinSet` aborts the queries which are still
+            // in-flight
+            while let Some(result) = set.join_next().await {
+                if let Ok((upstream, result)) = result {
+                    observe_response(context, &upstream, &result);
+                    match result {
+                        Ok(response) => return Ok(response),
+                        Err(error) => last_error = error,
+                    }
+                }
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// If the response to a query with a client subnet says that the answer is
+/// only for that subnet, returns the subnet.  A response with a client subnet
+/// which doesn't match the query is treated as if it had none.
+fn response_scope<'a>(
+    client_subnet: Option<&'a ClientSubnet>,
+    response: &Message,
+) -> Option<&'a ClientSubnet> {
+    let client_subnet = client_subnet?;
+    let response_subnet = response.client_subnet()?;
+    if response_subnet.address == client_subnet.address
+        && response_subnet.source_prefix_len == client_subnet.source_prefix_len
+        && response_subnet.scope_prefix_len > 0
     {
-        context.metrics().nameserver_hit();
-        tracing::trace!("nameserver HIT");
-        // Propagate SOA RR for NXDOMAIN / NODATA responses
-        let soa_rr = get_nxdomain_nodata_soa(question, &response, 0).cloned();
-        let rrs = response.answers;
-        context.cache.insert_all(&rrs);
-        prioritising_merge(&mut combined_rrs, rrs);
-        Ok(ResolvedRecord::NonAuthoritative {
-            rrs: combined_rrs,
-            soa_rr,
-        })
+        Some(client_subnet)
     } else {
-        context.metrics().nameserver_miss();
-        tracing::trace!("nameserver MISS");
-        Err(ResolutionError::DeadEnd {
-            question: question.clone(),
-        })
+        None
     }
 }
+
+/// Send a query to an upstream nameserver to the observer.
+fn observe_query(context: &ForwardingContext<'_>, upstream: &Upstream, question: &Question) {
+    context.observe(|| Event::UpstreamQuery {
+        upstream: upstream.clone(),
+        question: question.clone(),
+    });
+}
+
+/// Send a response (or failure) from an upstream nameserver to the observer.
+fn observe_response(
+    context: &ForwardingContext<'_>,
+    upstream: &Upstream,
+    result: &Result<Message, UpstreamError>,
+) {
+    context.observe(|| Event::UpstreamResponse {
+        upstream: upstream.clone(),
+        error: result.as_ref().err().copied(),
+    });
+}
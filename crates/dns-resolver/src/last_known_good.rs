@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use dns_types::protocol::types::*;
+
+/// The TTL given to answers returned from the last-known-good store, so that
+/// clients soon retry and get a fresh answer once resolution works again.
+pub const LAST_KNOWN_GOOD_TTL: u32 = 30;
+
+const MUTEX_POISON_MESSAGE: &str =
+    "[INTERNAL ERROR] last-known-good mutex poisoned, cannot recover from this - aborting";
+
+/// The most recent successful answer for each question, to fall back to if
+/// resolution fails (for example, because upstream nameservers are down).
+///
+/// This is separate to the cache: entries are not removed when their TTL
+/// expires, but only when they are older than the maximum age, or when there
+/// are too many.
+///
+/// Invoking `clone` on a `LastKnownGood` gives a new instance which refers to
+/// the same underlying store.
+#[derive(Debug, Clone)]
+pub struct LastKnownGood {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    max_age: Duration,
+    desired_size: usize,
+    answers: HashMap<(DomainName, QueryType), (Vec<ResourceRecord>, Instant)>,
+}
+
+impl LastKnownGood {
+    /// Create a new store.  If the maximum age is zero, nothing is stored.
+    pub fn new(max_age: Duration, desired_size: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                max_age,
+                desired_size,
+                answers: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Record a successful answer, replacing any previous one.  Empty answers
+    /// are not recorded.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn insert(&self, question: &Question, rrs: &[ResourceRecord]) {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        if inner.max_age.is_zero() || rrs.is_empty() {
+            return;
+        }
+
+        inner.answers.insert(
+            (question.name.clone(), question.qtype),
+            (rrs.to_vec(), Instant::now()),
+        );
+    }
+
+    /// Get the last-known-good answer to a question, if there is one which is
+    /// not too old.  The TTLs are capped at `LAST_KNOWN_GOOD_TTL`.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn get(&self, question: &Question) -> Option<Vec<ResourceRecord>> {
+        let inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        let (rrs, inserted) = inner
+            .answers
+            .get(&(question.name.clone(), question.qtype))?;
+
+        if inserted.elapsed() > inner.max_age {
+            return None;
+        }
+
+        let mut rrs = rrs.clone();
+        for rr in &mut rrs {
+            rr.ttl = std::cmp::min(rr.ttl, LAST_KNOWN_GOOD_TTL);
+        }
+        Some(rrs)
+    }
+
+    /// Change the maximum age and desired size.  Entries are not removed
+    /// until the next `prune`.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn set_limits(&self, max_age: Duration, desired_size: usize) {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        inner.max_age = max_age;
+        inner.desired_size = desired_size;
+    }
+
+    /// Remove answers older than the maximum age and then, if there are still
+    /// too many, the oldest answers.
+    ///
+    /// Returns `(current size, num pruned)`.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn prune(&self) -> (usize, usize) {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        let before = inner.answers.len();
+
+        let max_age = inner.max_age;
+        inner
+            .answers
+            .retain(|_, (_, inserted)| inserted.elapsed() <= max_age);
+
+        if inner.answers.len() > inner.desired_size {
+            let mut by_age: Vec<((DomainName, QueryType), Instant)> = inner
+                .answers
+                .iter()
+                .map(|(key, (_, inserted))| (key.clone(), *inserted))
+                .collect();
+            by_age.sort_by_key(|(_, inserted)| *inserted);
+
+            let excess = inner.answers.len() - inner.desired_size;
+            for (key, _) in by_age.into_iter().take(excess) {
+                inner.answers.remove(&key);
+            }
+        }
+
+        (inner.answers.len(), before - inner.answers.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dns_types::protocol::types::test_util::*;
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn get_returns_last_insert_with_capped_ttl() {
+        let lkg = LastKnownGood::new(Duration::from_mins(1), 10);
+        let rr1 = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        let rr2 = a_record("www.example.com.", Ipv4Addr::new(2, 2, 2, 2));
+
+        lkg.insert(&question(&rr1), std::slice::from_ref(&rr1));
+        lkg.insert(&question(&rr2), std::slice::from_ref(&rr2));
+
+        let mut expected = rr2.clone();
+        expected.ttl = LAST_KNOWN_GOOD_TTL;
+        assert_eq!(Some(vec![expected]), lkg.get(&question(&rr2)));
+    }
+
+    #[test]
+    fn get_misses_other_qtype() {
+        let lkg = LastKnownGood::new(Duration::from_mins(1), 10);
+        let rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        lkg.insert(&question(&rr), std::slice::from_ref(&rr));
+
+        let mut other = question(&rr);
+        other.qtype = QueryType::Record(RecordType::AAAA);
+        assert_eq!(None, lkg.get(&other));
+    }
+
+    #[test]
+    fn zero_max_age_disables() {
+        let lkg = LastKnownGood::new(Duration::ZERO, 10);
+        let rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        lkg.insert(&question(&rr), std::slice::from_ref(&rr));
+
+        assert_eq!(None, lkg.get(&question(&rr)));
+        assert_eq!((0, 0), lkg.prune());
+    }
+
+    #[test]
+    fn get_misses_too_old() {
+        let lkg = LastKnownGood::new(Duration::from_mins(1), 10);
+        let rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        lkg.insert(&question(&rr), std::slice::from_ref(&rr));
+        lkg.set_limits(Duration::from_nanos(1), 10);
+        std::thread::sleep(Duration::from_millis(1));
+
+        assert_eq!(None, lkg.get(&question(&rr)));
+        assert_eq!((0, 1), lkg.prune());
+    }
+
+    #[test]
+    fn prune_removes_oldest() {
+        let lkg = LastKnownGood::new(Duration::from_mins(1), 2);
+        let rrs = [
+            a_record("a.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+            a_record("b.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+            a_record("c.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+        ];
+        for rr in &rrs {
+            lkg.insert(&question(rr), std::slice::from_ref(rr));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!((2, 1), lkg.prune());
+        assert_eq!(None, lkg.get(&question(&rrs[0])));
+        assert!(lkg.get(&question(&rrs[1])).is_some());
+        assert!(lkg.get(&question(&rrs[2])).is_some());
+    }
+
+    fn question(rr: &ResourceRecord) -> Question {
+        Question {
+            name: rr.name.clone(),
+            qtype: QueryType::Record(rr.rtype_with_data.rtype()),
+            qclass: QueryClass::Record(RecordClass::IN),
+        }
+    }
+}
@@ -2,7 +2,10 @@ use dns_types::protocol::types::*;
 use dns_types::zones::types::*;
 
 use crate::context::Context;
+use crate::events::Event;
+use crate::metrics::{is_blocked, Metrics};
 use crate::util::types::*;
+use crate::RECURSION_LIMIT;
 
 /// Query type for CNAMEs - used for cache lookups.
 const CNAME_QTYPE: QueryType = QueryType::Record(RecordType::CNAME);
@@ -19,7 +22,8 @@ const CNAME_QTYPE: QueryType = QueryType::Record(RecordType::CNAME);
 ///
 /// - search through it for a match (either an answer, a CNAME, or a delegation)
 ///
-/// - search through the cache if we didn't get an authoritative match
+/// - search through the cache if we didn't get an authoritative match, and
+///   the zone precedence allows it
 ///
 /// This function gives up if the CNAMEs form a cycle.
 ///
@@ -52,8 +56,27 @@ pub fn resolve_local<CT>(
     // what sort of end state is reached.
     if let Some((zone, zone_result)) = context.zones.resolve(&question.name, question.qtype) {
         let _zone_span = tracing::error_span!("zone", apex = %zone.get_apex().to_dotted_string(), is_authoritative = %zone.is_authoritative()).entered();
+        context.observe(|| Event::ZoneMatch {
+            name: question.name.clone(),
+            apex: zone.get_apex().clone(),
+            is_authoritative: zone.is_authoritative(),
+        });
+
+        // Only used for non-authoritative zones: empty answers and name errors
+        // which aren't to be combined with the cache get a synthesised SOA for
+        // the domain of the zone precedence rule, or the apex of the zone.
+        let (precedence, precedence_domain) = context.zone_precedence(&question.name);
+        let soa_domain = precedence_domain.as_ref().unwrap_or(zone.get_apex());
 
         match zone_result {
+            // If we get an answer which would block an allowlisted domain:
+            // ignore it, and proceed to the cache.
+            ZoneResult::Answer { rrs, .. }
+                if is_blocked(&rrs, question) && context.allowlist.contains(&question.name) =>
+            {
+                tracing::trace!("ignoring blocked answer for allowlisted domain");
+                context.metrics().allowlisted();
+            }
             // If we get an answer:
             //
             // - if the zone is authoritative: we're done.
@@ -68,15 +91,26 @@ pub fn resolve_local<CT>(
             //    - if it is a wildcard query, save these results and continue
             //    to the cache (handled below), and use a prioritising merge to
             //    combine the RR sets, preserving the override behaviour.
-            ZoneResult::Answer { rrs } => {
-                context.metrics().zoneresult_answer(&rrs, zone, question);
-
+            //
+            //    - unless the zone precedence is not to merge, in which case
+            //    return these results, or an empty answer if there are none.
+            ZoneResult::Answer { rrs, wildcard } => {
+                context
+                    .metrics()
+                    .zoneresult_answer(&rrs, zone, question, wildcard);
+
+                let merge = precedence == ZonePrecedence::Merge;
                 if let Some(soa_rr) = zone.soa_rr() {
                     tracing::trace!("got authoritative answer");
                     return Ok(LocalResolutionResult::Done {
                         resolved: ResolvedRecord::Authoritative { rrs, soa_rr },
                     });
-                } else if question.qtype != QueryType::Wildcard && !rrs.is_empty() {
+                } else if !merge && rrs.is_empty() {
+                    tracing::trace!("got empty non-authoritative answer - not consulting cache");
+                    return LocalZonePolicy::NoData
+                        .answer(soa_domain, question)
+                        .map(|resolved| LocalResolutionResult::Done { resolved });
+                } else if (!merge || question.qtype != QueryType::Wildcard) && !rrs.is_empty() {
                     tracing::trace!("got non-authoritative answer");
                     return Ok(LocalResolutionResult::Done {
                         resolved: ResolvedRecord::NonAuthoritative { rrs, soa_rr: None },
@@ -98,8 +132,20 @@ pub fn resolve_local<CT>(
             //
             // - if resolving it fails: return the response, which is
             // authoritative if and only if this starting zone is authoritative.
-            ZoneResult::CNAME { cname, rr } => {
-                context.metrics().zoneresult_cname(zone);
+            //
+            // If the zone flattens CNAMEs, and the chain could be followed to
+            // some records using only local data, return those records in
+            // place of the chain.
+            ZoneResult::CNAME {
+                cname,
+                rr,
+                wildcard,
+            } => {
+                context.metrics().zoneresult_cname(zone, wildcard);
+                context.observe(|| Event::CNAME {
+                    name: question.name.clone(),
+                    target: cname.clone(),
+                });
 
                 let mut rrs = vec![rr];
                 let cname_question = Question {
@@ -109,6 +155,13 @@ pub fn resolve_local<CT>(
                 };
 
                 context.push_question(question);
+                if context.is_duplicate_question(&cname_question) {
+                    tracing::debug!("hit CNAME loop");
+                    context.pop_question();
+                    return Err(ResolutionError::CnameLoop {
+                        question: cname_question,
+                    });
+                }
                 let answer = match resolve_local(context, &cname_question) {
                     Ok(LocalResolutionResult::Done { resolved }) => match resolved {
                         ResolvedRecord::Authoritative {
@@ -154,6 +207,10 @@ pub fn resolve_local<CT>(
                             cname_question,
                         }
                     }
+                    Err(error @ ResolutionError::CnameLoop { .. }) => {
+                        context.pop_question();
+                        return Err(error);
+                    }
                     _ => {
                         tracing::trace!("got incomplete cname answer");
                         LocalResolutionResult::CNAME {
@@ -163,6 +220,10 @@ pub fn resolve_local<CT>(
                     }
                 };
                 context.pop_question();
+
+                if zone.flattens_cnames() {
+                    return Ok(flatten_cname_chain(&question.name, answer));
+                }
                 return Ok(answer);
             }
             // If the name is delegated:
@@ -170,6 +231,8 @@ pub fn resolve_local<CT>(
             // - if this zone is authoritative, return the response with the NS
             // RRs in the AUTHORITY section.
             //
+            // - if the zone precedence is exclusive, return an empty answer.
+            //
             // - otherwise ignore and proceed to cache.
             ZoneResult::Delegation { ns_rrs } => {
                 tracing::trace!("got delegation");
@@ -199,6 +262,11 @@ pub fn resolve_local<CT>(
                         rrs: ns_rrs,
                         soa_rr: Some(soa_rr),
                     });
+                } else if precedence == ZonePrecedence::Exclusive {
+                    tracing::trace!("ignoring non-authoritative delegation - not consulting cache");
+                    return LocalZonePolicy::NoData
+                        .answer(soa_domain, question)
+                        .map(|resolved| LocalResolutionResult::Done { resolved });
                 }
             }
             // If the name could not be resolved:
@@ -206,6 +274,8 @@ pub fn resolve_local<CT>(
             // - if this zone is authoritative, a NXDOMAIN response
             // (todo)
             //
+            // - if the zone precedence is exclusive, a NXDOMAIN response.
+            //
             // - otherwise ignore and proceed to cache.
             ZoneResult::NameError => {
                 tracing::trace!("got name error");
@@ -215,6 +285,10 @@ pub fn resolve_local<CT>(
                     return Ok(LocalResolutionResult::Done {
                         resolved: ResolvedRecord::AuthoritativeNameError { soa_rr },
                     });
+                } else if precedence == ZonePrecedence::Exclusive {
+                    return LocalZonePolicy::NxDomain
+                        .answer(soa_domain, question)
+                        .map(|resolved| LocalResolutionResult::Done { resolved });
                 }
             }
         }
@@ -222,9 +296,8 @@ pub fn resolve_local<CT>(
 
     // If we get here, either:
     //
-    // - there is no zone for this question (in practice this will be unlikely,
-    // as the root hints get put into a non-authoritative root zone - and
-    // without root hints, we can't do much)
+    // - there is no zone for this question (for example, there are no hosts
+    // or zone files covering the root domain)
     //
     // - the query was answered by a non-authoritative zone, which means we may
     // have other relevant RRs in the cache
@@ -238,30 +311,22 @@ pub fn resolve_local<CT>(
     // combine with the RRs we already have.
 
     let mut rrs_from_cache = context.cache.get(&question.name, question.qtype);
-    if rrs_from_cache.is_empty() {
-        tracing::trace!(qtype = %question.qtype, "cache MISS");
-        context.metrics().cache_miss();
-    } else {
-        tracing::trace!(qtype = %question.qtype, "cache HIT");
-        context.metrics().cache_hit();
-    }
+    observe_cache(context, &question.name, question.qtype, &rrs_from_cache);
 
     let mut final_cname = None;
     if rrs_from_cache.is_empty() && question.qtype != CNAME_QTYPE {
         let cache_cname_rrs = context.cache.get(&question.name, CNAME_QTYPE);
-        if cache_cname_rrs.is_empty() {
-            tracing::trace!(qtype = %CNAME_QTYPE, "cache MISS");
-            context.metrics().cache_miss();
-        } else {
-            tracing::trace!(qtype = %CNAME_QTYPE, "cache HIT");
-            context.metrics().cache_hit();
-        }
+        observe_cache(context, &question.name, CNAME_QTYPE, &cache_cname_rrs);
 
         if !cache_cname_rrs.is_empty() {
             let cname_rr = cache_cname_rrs[0].clone();
             rrs_from_cache = vec![cname_rr.clone()];
 
             if let RecordTypeWithData::CNAME { cname } = cname_rr.rtype_with_data {
+                context.observe(|| Event::CNAME {
+                    name: question.name.clone(),
+                    target: cname.clone(),
+                });
                 context.push_question(question);
                 let resolved_cname = resolve_local(
                     context,
@@ -296,7 +361,7 @@ pub fn resolve_local<CT>(
                     query: CNAME_QTYPE,
                     result: cname_rr.rtype_with_data.rtype(),
                 });
-            };
+            }
         }
     }
 
@@ -325,6 +390,73 @@ pub fn resolve_local<CT>(
     }
 }
 
+/// Answer a question using only authoritative zones: without the cache,
+/// non-authoritative zones, or upstream nameservers.  This is a fast path for
+/// names which are always served locally, like a LAN zone.
+///
+/// `CNAME`s are followed through authoritative zones, up to `RECURSION_LIMIT`
+/// of them, and the chain is flattened if the first zone flattens CNAMEs.
+///
+/// Returns `None` if the question can't be answered completely like this: the
+/// name (or a `CNAME` target) isn't in an authoritative zone, or is delegated,
+/// or the answer would block it.  Then the question should be resolved as
+/// normal.
+pub fn resolve_authoritative(
+    zones: &Zones,
+    question: &Question,
+) -> Option<(Metrics, ResolvedRecord)> {
+    let mut metrics = Metrics::new();
+    let mut rrs = Vec::new();
+    let mut name = question.name.clone();
+    let mut flatten = None;
+
+    for _ in 0..RECURSION_LIMIT {
+        let (zone, zone_result) = zones.resolve(&name, question.qtype)?;
+        let soa_rr = zone.soa_rr()?;
+        let flatten = *flatten.get_or_insert(zone.flattens_cnames());
+
+        match zone_result {
+            ZoneResult::Answer {
+                rrs: mut answer,
+                wildcard,
+            } => {
+                if is_blocked(&answer, question) {
+                    return None;
+                }
+                metrics.zoneresult_answer(&answer, zone, question, wildcard);
+                rrs.append(&mut answer);
+                if flatten {
+                    rrs = flatten_rrs(&question.name, rrs);
+                }
+                return Some((metrics, ResolvedRecord::Authoritative { rrs, soa_rr }));
+            }
+            ZoneResult::NameError => {
+                metrics.zoneresult_nameerror(zone);
+                return Some((
+                    metrics,
+                    if rrs.is_empty() {
+                        ResolvedRecord::AuthoritativeNameError { soa_rr }
+                    } else {
+                        ResolvedRecord::Authoritative { rrs, soa_rr }
+                    },
+                ));
+            }
+            ZoneResult::CNAME {
+                cname,
+                rr,
+                wildcard,
+            } => {
+                metrics.zoneresult_cname(zone, wildcard);
+                rrs.push(rr);
+                name = cname;
+            }
+            ZoneResult::Delegation { .. } => return None,
+        }
+    }
+
+    None
+}
+
 /// Result of resolving a name using only zones and cache.
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum LocalResolutionResult {
@@ -366,6 +498,76 @@ impl From<LocalResolutionResult> for ResolvedRecord {
     }
 }
 
+/// If the CNAME chain was followed to some records, replace it with those
+/// records, renamed to the name at the start of the chain, and given the lowest
+/// TTL in the chain.
+fn flatten_cname_chain(name: &DomainName, answer: LocalResolutionResult) -> LocalResolutionResult {
+    match answer {
+        LocalResolutionResult::Done {
+            resolved: ResolvedRecord::Authoritative { rrs, soa_rr },
+        } => LocalResolutionResult::Done {
+            resolved: ResolvedRecord::Authoritative {
+                rrs: flatten_rrs(name, rrs),
+                soa_rr,
+            },
+        },
+        LocalResolutionResult::Done {
+            resolved: ResolvedRecord::NonAuthoritative { rrs, soa_rr },
+        } => LocalResolutionResult::Done {
+            resolved: ResolvedRecord::NonAuthoritative {
+                rrs: flatten_rrs(name, rrs),
+                soa_rr,
+            },
+        },
+        _ => answer,
+    }
+}
+
+/// Helper for `flatten_cname_chain`: returns the records unchanged if they're
+/// all CNAMEs.
+fn flatten_rrs(name: &DomainName, rrs: Vec<ResourceRecord>) -> Vec<ResourceRecord> {
+    if rrs
+        .iter()
+        .all(|rr| rr.rtype_with_data.rtype() == RecordType::CNAME)
+    {
+        return rrs;
+    }
+
+    let ttl = rrs.iter().map(|rr| rr.ttl).min().unwrap_or_default();
+    rrs.into_iter()
+        .filter(|rr| rr.rtype_with_data.rtype() != RecordType::CNAME)
+        .map(|rr| ResourceRecord {
+            name: name.clone(),
+            ttl,
+            ..rr
+        })
+        .collect()
+}
+
+/// Record a cache lookup in the metrics, and send it to the observer.
+fn observe_cache<CT>(
+    context: &mut Context<'_, CT>,
+    name: &DomainName,
+    qtype: QueryType,
+    rrs: &[ResourceRecord],
+) {
+    if rrs.is_empty() {
+        tracing::trace!(%qtype, "cache MISS");
+        context.metrics().cache_miss();
+        context.observe(|| Event::CacheMiss {
+            name: name.clone(),
+            qtype,
+        });
+    } else {
+        tracing::trace!(%qtype, "cache HIT");
+        context.metrics().cache_hit();
+        context.observe(|| Event::CacheHit {
+            name: name.clone(),
+            qtype,
+        });
+    }
+}
+
 /// An authoritative name error response, returned by the
 /// non-recursive resolver.
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -377,11 +579,12 @@ pub struct AuthoritativeNameError {
 mod tests {
     use dns_types::protocol::types::test_util::*;
     use dns_types::zones::types::*;
-    use std::net::Ipv4Addr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
     use super::*;
     use crate::cache::test_util::*;
     use crate::cache::SharedCache;
+    use crate::metrics::ZoneHitKind;
 
     #[test]
     fn resolve_local_is_authoritative_for_zones_with_soa() {
@@ -425,6 +628,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn resolve_local_ignores_blocked_answer_for_allowlisted_domain() {
+        let rr = a_record("blocked.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+
+        let cache = SharedCache::new();
+        cache.insert(&rr);
+
+        let mut allowlist = Allowlist::new();
+        allowlist.insert(domain("example.com."));
+
+        let question = Question {
+            name: domain("blocked.example.com."),
+            qclass: QueryClass::Wildcard,
+            qtype: QueryType::Record(RecordType::A),
+        };
+
+        if let Ok(LocalResolutionResult::Done {
+            resolved: ResolvedRecord::NonAuthoritative { rrs, soa_rr: None },
+        }) = resolve_local(
+            &mut Context::new((), &zones(), &allowlist, &cache, Timeouts::default(), 10),
+            &question,
+        ) {
+            assert_cache_response(&rr, &rrs);
+        } else {
+            panic!("expected non-authoritative answer");
+        }
+    }
+
     #[test]
     fn resolve_local_returns_all_record_types() {
         if let Ok(LocalResolutionResult::Done {
@@ -519,6 +750,120 @@ mod tests {
         );
     }
 
+    #[test]
+    fn resolve_local_does_not_combine_override_zones_with_cache() {
+        let zone_rr = a_record("a.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        let cache = SharedCache::new();
+        cache.insert(&cname_record("a.example.com.", "b.example.com."));
+
+        assert_eq!(
+            test_resolve_local_with_precedence(
+                "a.example.com.",
+                &cache,
+                ZonePrecedence::Override,
+                QueryType::Wildcard
+            ),
+            Ok(LocalResolutionResult::Done {
+                resolved: ResolvedRecord::NonAuthoritative {
+                    rrs: vec![zone_rr],
+                    soa_rr: None
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn resolve_local_does_not_use_cache_for_missing_type_in_override_zone() {
+        let question = Question {
+            name: domain("a.example.com."),
+            qtype: QueryType::Record(RecordType::AAAA),
+            qclass: QueryClass::Wildcard,
+        };
+        let cache = SharedCache::new();
+        cache.insert(&aaaa_record("a.example.com.", Ipv6Addr::LOCALHOST));
+
+        for precedence in [ZonePrecedence::Override, ZonePrecedence::Exclusive] {
+            assert_eq!(
+                test_resolve_local_with_precedence(
+                    "a.example.com.",
+                    &cache,
+                    precedence,
+                    question.qtype
+                ),
+                LocalZonePolicy::NoData
+                    .answer(&DomainName::root_domain(), &question)
+                    .map(|resolved| LocalResolutionResult::Done { resolved })
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_local_nameerrors_from_exclusive_zone() {
+        let question = Question {
+            name: domain("no.such.name.example.com."),
+            qtype: QueryType::Wildcard,
+            qclass: QueryClass::Wildcard,
+        };
+        let cache = SharedCache::new();
+        cache.insert(&a_record(
+            "no.such.name.example.com.",
+            Ipv4Addr::new(1, 1, 1, 1),
+        ));
+
+        assert_eq!(
+            test_resolve_local_with_precedence(
+                "no.such.name.example.com.",
+                &cache,
+                ZonePrecedence::Exclusive,
+                question.qtype
+            ),
+            LocalZonePolicy::NxDomain
+                .answer(&DomainName::root_domain(), &question)
+                .map(|resolved| LocalResolutionResult::Done { resolved })
+        );
+        assert!(matches!(
+            test_resolve_local_with_precedence(
+                "no.such.name.example.com.",
+                &cache,
+                ZonePrecedence::Override,
+                question.qtype
+            ),
+            Ok(LocalResolutionResult::Partial { .. })
+        ));
+    }
+
+    #[test]
+    fn resolve_local_uses_most_specific_zone_precedence_rule() {
+        let question = Question {
+            name: domain("no.such.name.example.com."),
+            qtype: QueryType::Wildcard,
+            qclass: QueryClass::Wildcard,
+        };
+        let mut rules = ZonePrecedenceRules::new(ZonePrecedence::Merge);
+        rules.insert(ZonePrecedenceRule {
+            domain: domain("example.com."),
+            precedence: ZonePrecedence::Exclusive,
+        });
+
+        assert_eq!(
+            resolve_local(
+                &mut Context::new(
+                    (),
+                    &zones(),
+                    &Allowlist::new(),
+                    &SharedCache::new(),
+                    Timeouts::default(),
+                    10
+                )
+                .with_zone_precedence(&rules),
+                &question,
+            ),
+            LocalZonePolicy::NxDomain
+                .answer(&domain("example.com."), &question)
+                .map(|resolved| LocalResolutionResult::Done { resolved })
+        );
+    }
+
     #[test]
     fn resolve_local_expands_cnames_from_zone() {
         assert_eq!(
@@ -541,6 +886,163 @@ mod tests {
         );
     }
 
+    #[test]
+    fn resolve_local_flattens_cnames_from_zone() {
+        let mut zone = Zone::deserialise(
+            r"
+$ORIGIN flattened.example.com.
+
+@ IN SOA mname rname 1 30 30 30 30
+
+@       60  IN CNAME www
+www     600 IN A     1.1.1.1
+www     600 IN A     2.2.2.2
+partial 300 IN CNAME somewhere.example.net.
+",
+        )
+        .unwrap();
+        zone.set_flatten_cnames(true);
+        let soa_rr = zone.soa_rr().unwrap();
+
+        let mut zones = zones();
+        zones.insert(zone);
+
+        let test_resolve = |name: &str| {
+            resolve_local(
+                &mut Context::new(
+                    (),
+                    &zones,
+                    &Allowlist::new(),
+                    &SharedCache::new(),
+                    Timeouts::default(),
+                    10,
+                ),
+                &Question {
+                    name: domain(name),
+                    qclass: QueryClass::Wildcard,
+                    qtype: QueryType::Record(RecordType::A),
+                },
+            )
+        };
+
+        let mut flattened_rr1 = a_record("flattened.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        let mut flattened_rr2 = a_record("flattened.example.com.", Ipv4Addr::new(2, 2, 2, 2));
+        flattened_rr1.ttl = 60;
+        flattened_rr2.ttl = 60;
+
+        assert_eq!(
+            test_resolve("flattened.example.com."),
+            Ok(LocalResolutionResult::Done {
+                resolved: ResolvedRecord::Authoritative {
+                    rrs: vec![flattened_rr1, flattened_rr2],
+                    soa_rr,
+                },
+            }),
+        );
+
+        assert_eq!(
+            test_resolve("partial.flattened.example.com."),
+            Ok(LocalResolutionResult::CNAME {
+                rrs: vec![cname_record(
+                    "partial.flattened.example.com.",
+                    "somewhere.example.net."
+                )],
+                cname_question: Question {
+                    name: domain("somewhere.example.net."),
+                    qclass: QueryClass::Wildcard,
+                    qtype: QueryType::Record(RecordType::A),
+                },
+            }),
+        );
+    }
+
+    #[test]
+    fn resolve_local_sends_events_to_observer() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let zones = zones();
+        let allowlist = Allowlist::new();
+        let cache = SharedCache::new();
+
+        _ = resolve_local(
+            &mut Context::new((), &zones, &allowlist, &cache, Timeouts::default(), 10)
+                .with_observer(Some(&sender)),
+            &Question {
+                name: domain("cname-nonauthoritative.authoritative.example.com."),
+                qclass: QueryClass::Wildcard,
+                qtype: QueryType::Record(RecordType::A),
+            },
+        );
+
+        assert_eq!(
+            vec![
+                Event::ZoneMatch {
+                    name: domain("cname-nonauthoritative.authoritative.example.com."),
+                    apex: domain("authoritative.example.com."),
+                    is_authoritative: true,
+                },
+                Event::CNAME {
+                    name: domain("cname-nonauthoritative.authoritative.example.com."),
+                    target: domain("a.example.com."),
+                },
+                Event::ZoneMatch {
+                    name: domain("a.example.com."),
+                    apex: domain("."),
+                    is_authoritative: false,
+                },
+            ],
+            receiver.try_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn resolve_local_records_zone_hits() {
+        let zones = zones();
+        let allowlist = Allowlist::new();
+        let cache = SharedCache::new();
+
+        let mut hits = Vec::new();
+        for (name, qtype) in [
+            (
+                "cname-authoritative.authoritative.example.com.",
+                RecordType::A,
+            ),
+            ("www.authoritative.example.com.", RecordType::MX),
+            ("missing.authoritative.example.com.", RecordType::A),
+            ("foo.delegated.authoritative.example.com.", RecordType::A),
+            ("blocked.example.com.", RecordType::A),
+        ] {
+            let mut context = Context::new((), &zones, &allowlist, &cache, Timeouts::default(), 10);
+            _ = resolve_local(
+                &mut context,
+                &Question {
+                    name: domain(name),
+                    qclass: QueryClass::Wildcard,
+                    qtype: QueryType::Record(qtype),
+                },
+            );
+            hits.extend(
+                context
+                    .done()
+                    .zone_hits
+                    .into_iter()
+                    .map(|hit| (hit.apex.to_dotted_string(), hit.kind)),
+            );
+        }
+
+        let authoritative = "authoritative.example.com.".to_string();
+        assert_eq!(
+            vec![
+                (authoritative.clone(), ZoneHitKind::CNAME),
+                (authoritative.clone(), ZoneHitKind::Answer),
+                (authoritative.clone(), ZoneHitKind::NoData),
+                (authoritative.clone(), ZoneHitKind::NameError),
+                (authoritative, ZoneHitKind::Delegation),
+                (".".to_string(), ZoneHitKind::Blocked),
+            ],
+            hits
+        );
+    }
+
     #[test]
     fn resolve_local_expands_cnames_from_cache() {
         let cname_rr1 = cname_record("cname-1.example.com.", "cname-2.example.com.");
@@ -573,12 +1075,8 @@ mod tests {
 
         assert_eq!(
             test_resolve_local("cname-cycle-a.example.com.", qtype),
-            Ok(LocalResolutionResult::CNAME {
-                rrs: vec![
-                    cname_record("cname-cycle-a.example.com.", "cname-cycle-b.example.com."),
-                    cname_record("cname-cycle-b.example.com.", "cname-cycle-a.example.com."),
-                ],
-                cname_question: Question {
+            Err(ResolutionError::CnameLoop {
+                question: Question {
                     name: domain("cname-cycle-a.example.com."),
                     qclass: QueryClass::Wildcard,
                     qtype,
@@ -682,7 +1180,14 @@ mod tests {
 
         assert_eq!(
             resolve_local(
-                &mut Context::new((), &zones(), &SharedCache::new(), 10),
+                &mut Context::new(
+                    (),
+                    &zones(),
+                    &Allowlist::new(),
+                    &SharedCache::new(),
+                    Timeouts::default(),
+                    10
+                ),
                 &question
             ),
             Err(ResolutionError::DeadEnd {
@@ -714,7 +1219,14 @@ mod tests {
 
         assert_eq!(
             resolve_local(
-                &mut Context::new((), &zones(), &SharedCache::new(), 10),
+                &mut Context::new(
+                    (),
+                    &zones(),
+                    &Allowlist::new(),
+                    &SharedCache::new(),
+                    Timeouts::default(),
+                    10
+                ),
                 &question,
             ),
             Err(ResolutionError::DeadEnd {
@@ -723,6 +1235,70 @@ mod tests {
         );
     }
 
+    #[test]
+    fn resolve_authoritative_answers_from_authoritative_zone() {
+        assert_eq!(
+            test_resolve_authoritative("www.authoritative.example.com."),
+            Some(ResolvedRecord::Authoritative {
+                rrs: vec![a_record(
+                    "www.authoritative.example.com.",
+                    Ipv4Addr::new(1, 1, 1, 1)
+                )],
+                soa_rr: soa_rr(),
+            })
+        );
+    }
+
+    #[test]
+    fn resolve_authoritative_follows_cnames_in_authoritative_zones() {
+        assert_eq!(
+            test_resolve_authoritative("cname-authoritative.authoritative.example.com."),
+            Some(ResolvedRecord::Authoritative {
+                rrs: vec![
+                    cname_record(
+                        "cname-authoritative.authoritative.example.com.",
+                        "www.authoritative.example.com."
+                    ),
+                    a_record("www.authoritative.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+                ],
+                soa_rr: soa_rr(),
+            })
+        );
+    }
+
+    #[test]
+    fn resolve_authoritative_nameerrors_from_authoritative_zone() {
+        assert_eq!(
+            test_resolve_authoritative("no.such.name.authoritative.example.com."),
+            Some(ResolvedRecord::AuthoritativeNameError { soa_rr: soa_rr() })
+        );
+    }
+
+    #[test]
+    fn resolve_authoritative_gives_up_outside_authoritative_zones() {
+        assert_eq!(test_resolve_authoritative("a.example.com."), None);
+        assert_eq!(
+            test_resolve_authoritative("cname-nonauthoritative.authoritative.example.com."),
+            None
+        );
+        assert_eq!(
+            test_resolve_authoritative("delegated.authoritative.example.com."),
+            None
+        );
+    }
+
+    fn test_resolve_authoritative(name: &str) -> Option<ResolvedRecord> {
+        resolve_authoritative(
+            &zones(),
+            &Question {
+                name: domain(name),
+                qclass: QueryClass::Record(RecordClass::IN),
+                qtype: QueryType::Record(RecordType::A),
+            },
+        )
+        .map(|(_, resolved)| resolved)
+    }
+
     fn test_resolve_local(
         name: &str,
         qtype: QueryType,
@@ -730,13 +1306,44 @@ mod tests {
         test_resolve_local_with_cache(name, &SharedCache::new(), qtype)
     }
 
+    fn test_resolve_local_with_precedence(
+        name: &str,
+        cache: &SharedCache,
+        precedence: ZonePrecedence,
+        qtype: QueryType,
+    ) -> Result<LocalResolutionResult, ResolutionError> {
+        resolve_local(
+            &mut Context::new(
+                (),
+                &zones(),
+                &Allowlist::new(),
+                cache,
+                Timeouts::default(),
+                10,
+            )
+            .with_zone_precedence(&ZonePrecedenceRules::new(precedence)),
+            &Question {
+                name: domain(name),
+                qclass: QueryClass::Wildcard,
+                qtype,
+            },
+        )
+    }
+
     fn test_resolve_local_with_cache(
         name: &str,
         cache: &SharedCache,
         qtype: QueryType,
     ) -> Result<LocalResolutionResult, ResolutionError> {
         resolve_local(
-            &mut Context::new((), &zones(), cache, 10),
+            &mut Context::new(
+                (),
+                &zones(),
+                &Allowlist::new(),
+                cache,
+                Timeouts::default(),
+                10,
+            ),
             &Question {
                 name: domain(name),
                 qclass: QueryClass::Wildcard,
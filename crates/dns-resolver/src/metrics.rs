@@ -1,17 +1,19 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
-
 use dns_types::protocol::types::*;
 use dns_types::zones::types::*;
 
-/// The A record for a blocked question
-pub const BLOCKED_A: RecordTypeWithData = RecordTypeWithData::A {
-    address: Ipv4Addr::UNSPECIFIED,
-};
+pub use dns_types::hosts::types::{BLOCKED_A, BLOCKED_AAAA};
 
-/// The AAAA record for a blocked question
-pub const BLOCKED_AAAA: RecordTypeWithData = RecordTypeWithData::AAAA {
-    address: Ipv6Addr::UNSPECIFIED,
-};
+/// Check if an answer to an A or AAAA question (ie, not *) blocks the
+/// domain: it is a single record with the unspecified IP.
+pub fn is_blocked(rrs: &[ResourceRecord], question: &Question) -> bool {
+    if rrs.len() == 1 {
+        let rtype = &rrs[0].rtype_with_data;
+        (question.qtype == QueryType::Record(RecordType::A) && rtype == &BLOCKED_A)
+            || (question.qtype == QueryType::Record(RecordType::AAAA) && rtype == &BLOCKED_AAAA)
+    } else {
+        false
+    }
+}
 
 /// Metrics from a resolution attempt.  The resolvers build this
 /// structure rather than update the Prometheus metrics directly.
@@ -27,6 +29,9 @@ pub struct Metrics {
     /// A or AAAA questions (ie, not *) where the result is from a
     /// zone and has the unspecified IP.
     pub blocked: u64,
+    /// Questions which would have been blocked, but the domain is in
+    /// the allowlist.
+    pub allowlisted: u64,
     /// Cache misses
     pub cache_misses: u64,
     /// Cache hits
@@ -35,6 +40,58 @@ pub struct Metrics {
     pub nameserver_hits: u64,
     /// Questions which an upstream nameserver fails to answer.
     pub nameserver_misses: u64,
+    /// Upstream nameserver addresses which turned out to be lame for the zone
+    /// they were asked about, and so have been held down.
+    pub nameserver_lame: u64,
+    /// Upstream nameserver addresses which were not asked because they are
+    /// held down.
+    pub nameserver_lame_skipped: u64,
+    /// Answers and CNAMEs from zones (including blocked domains), and
+    /// delegations and name errors from authoritative zones, with what
+    /// sort of result each was and whether it came from a wildcard
+    /// record.
+    pub zone_hits: Vec<ZoneHit>,
+}
+
+/// A result from a zone.
+pub struct ZoneHit {
+    /// The apex of the zone.
+    pub apex: DomainName,
+    /// What sort of result it was.
+    pub kind: ZoneHitKind,
+    /// Whether the records came from a wildcard, rather than explicit
+    /// records for the name.
+    pub wildcard: bool,
+}
+
+/// What sort of result a zone gave.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ZoneHitKind {
+    /// Records of the requested type.
+    Answer,
+    /// The name exists, but has no records of the requested type.
+    NoData,
+    /// An answer which blocks the domain.
+    Blocked,
+    /// A CNAME to follow.
+    CNAME,
+    /// A delegation to other nameservers.
+    Delegation,
+    /// The name does not exist.
+    NameError,
+}
+
+impl ZoneHitKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ZoneHitKind::Answer => "answer",
+            ZoneHitKind::NoData => "nodata",
+            ZoneHitKind::Blocked => "blocked",
+            ZoneHitKind::CNAME => "cname",
+            ZoneHitKind::Delegation => "delegation",
+            ZoneHitKind::NameError => "nxdomain",
+        }
+    }
 }
 
 impl Metrics {
@@ -43,22 +100,34 @@ impl Metrics {
             authoritative_hits: 0,
             override_hits: 0,
             blocked: 0,
+            allowlisted: 0,
             cache_misses: 0,
             cache_hits: 0,
             nameserver_hits: 0,
             nameserver_misses: 0,
+            nameserver_lame: 0,
+            nameserver_lame_skipped: 0,
+            zone_hits: Vec::new(),
         }
     }
 
-    pub fn zoneresult_answer(&mut self, rrs: &[ResourceRecord], zone: &Zone, question: &Question) {
-        if rrs.len() == 1 {
-            let rtype = &rrs[0].rtype_with_data;
-            if (question.qtype == QueryType::Record(RecordType::A) && rtype == &BLOCKED_A)
-                || (question.qtype == QueryType::Record(RecordType::AAAA) && rtype == &BLOCKED_AAAA)
-            {
-                self.blocked += 1;
-                return;
-            }
+    pub fn zoneresult_answer(
+        &mut self,
+        rrs: &[ResourceRecord],
+        zone: &Zone,
+        question: &Question,
+        wildcard: bool,
+    ) {
+        if is_blocked(rrs, question) {
+            self.zone_hit(zone, ZoneHitKind::Blocked, wildcard);
+            self.blocked += 1;
+            return;
+        }
+
+        if rrs.is_empty() {
+            self.zone_hit(zone, ZoneHitKind::NoData, wildcard);
+        } else {
+            self.zone_hit(zone, ZoneHitKind::Answer, wildcard);
         }
 
         if zone.is_authoritative() {
@@ -68,7 +137,9 @@ impl Metrics {
         }
     }
 
-    pub fn zoneresult_cname(&mut self, zone: &Zone) {
+    pub fn zoneresult_cname(&mut self, zone: &Zone, wildcard: bool) {
+        self.zone_hit(zone, ZoneHitKind::CNAME, wildcard);
+
         if zone.is_authoritative() {
             self.authoritative_hits += 1;
         } else {
@@ -78,16 +149,30 @@ impl Metrics {
 
     pub fn zoneresult_delegation(&mut self, zone: &Zone) {
         if zone.is_authoritative() {
+            self.zone_hit(zone, ZoneHitKind::Delegation, false);
             self.authoritative_hits += 1;
         }
     }
 
     pub fn zoneresult_nameerror(&mut self, zone: &Zone) {
         if zone.is_authoritative() {
+            self.zone_hit(zone, ZoneHitKind::NameError, false);
             self.authoritative_hits += 1;
         }
     }
 
+    pub fn allowlisted(&mut self) {
+        self.allowlisted += 1;
+    }
+
+    fn zone_hit(&mut self, zone: &Zone, kind: ZoneHitKind, wildcard: bool) {
+        self.zone_hits.push(ZoneHit {
+            apex: zone.get_apex().clone(),
+            kind,
+            wildcard,
+        });
+    }
+
     pub fn cache_hit(&mut self) {
         self.cache_hits += 1;
     }
@@ -103,6 +188,14 @@ impl Metrics {
     pub fn nameserver_miss(&mut self) {
         self.nameserver_misses += 1;
     }
+
+    pub fn nameserver_lame(&mut self) {
+        self.nameserver_lame += 1;
+    }
+
+    pub fn nameserver_lame_skipped(&mut self) {
+        self.nameserver_lame_skipped += 1;
+    }
 }
 
 impl Default for Metrics {
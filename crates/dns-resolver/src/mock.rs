@@ -0,0 +1,519 @@
+//! A mock nameserver, for testing code which talks to upstream nameservers.
+//!
+//! The mock listens on UDP and TCP on the same port, and answers each query
+//! by calling a handler.  It runs on its own threads, so it can be used from
+//! synchronous tests as well as from any async runtime.
+//!
+//! ```no_run
+//! use dns_resolver::mock::{MockNameserver, MockResponse};
+//! use dns_types::protocol::types::Rcode;
+//! use std::time::Duration;
+//!
+//! let nameserver = MockNameserver::builder()
+//!     .respond(|query| MockResponse::rcode(query, Rcode::ServerFailure))
+//!     .delay(Duration::from_millis(100))
+//!     .start()
+//!     .unwrap();
+//!
+//! // point the code under test at `nameserver.address()`
+//! ```
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use dns_types::protocol::types::*;
+
+/// How often the listening threads check whether they should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What the mock nameserver does with a query.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MockResponse {
+    /// Send this message.
+    Message(Message),
+    /// Send these octets as-is: they do not need to be a valid message.
+    Octets(Vec<u8>),
+    /// Do not respond.
+    NoResponse,
+}
+
+impl MockResponse {
+    /// A `NOERROR` response to the query with the given answers.
+    pub fn answer(query: &Message, answers: Vec<ResourceRecord>) -> Self {
+        let mut response = query.make_response();
+        response.answers = answers;
+        Self::Message(response)
+    }
+
+    /// An empty response to the query with the given rcode.
+    pub fn rcode(query: &Message, rcode: Rcode) -> Self {
+        let mut response = query.make_response();
+        response.header.rcode = rcode;
+        Self::Message(response)
+    }
+
+    /// A response to the query which cannot be parsed: it has the right ID,
+    /// but claims to have a question which is not there.
+    pub fn malformed(query: &Message) -> Self {
+        let mut octets = vec![0; 12];
+        octets[0..2].copy_from_slice(&query.header.id.to_be_bytes());
+        octets[2] = 0b1000_0000;
+        octets[5] = 1;
+        Self::Octets(octets)
+    }
+}
+
+type Handler = dyn Fn(&Message) -> MockResponse + Send + Sync;
+
+/// A running mock nameserver, which stops when dropped.
+pub struct MockNameserver {
+    address: SocketAddr,
+    queries: Arc<Mutex<Vec<(Message, bool)>>>,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl MockNameserver {
+    /// Build a new mock nameserver.
+    pub fn builder() -> MockNameserverBuilder {
+        MockNameserverBuilder::default()
+    }
+
+    /// The address the mock is listening on, for both UDP and TCP.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Every query received so far, over either protocol, in order.
+    ///
+    /// # Panics
+    ///
+    /// If a thread handling a query panicked.
+    pub fn queries(&self) -> Vec<Message> {
+        let queries = self.queries.lock().unwrap();
+        queries.iter().map(|(query, _)| query.clone()).collect()
+    }
+
+    /// Every query received so far over TCP, in order.
+    ///
+    /// # Panics
+    ///
+    /// If a thread handling a query panicked.
+    pub fn tcp_queries(&self) -> Vec<Message> {
+        let queries = self.queries.lock().unwrap();
+        queries
+            .iter()
+            .filter(|(_, via_tcp)| *via_tcp)
+            .map(|(query, _)| query.clone())
+            .collect()
+    }
+}
+
+impl Drop for MockNameserver {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Configuration for a `MockNameserver`.
+pub struct MockNameserverBuilder {
+    address: SocketAddr,
+    handler: Arc<Handler>,
+    delay: Duration,
+    truncate_udp: bool,
+    tcp: bool,
+}
+
+impl Default for MockNameserverBuilder {
+    fn default() -> Self {
+        Self {
+            address: SocketAddr::from(([127, 0, 0, 1], 0)),
+            handler: Arc::new(|query| MockResponse::rcode(query, Rcode::Refused)),
+            delay: Duration::ZERO,
+            truncate_udp: false,
+            tcp: true,
+        }
+    }
+}
+
+impl MockNameserverBuilder {
+    /// Address to listen on.  Defaults to a free port on `127.0.0.1`.
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Answer queries by calling this function.  Defaults to refusing every
+    /// query.
+    pub fn respond<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Message) -> MockResponse + Send + Sync + 'static,
+    {
+        self.handler = Arc::new(handler);
+        self
+    }
+
+    /// Answer queries from a fixed set of records: a query gets the records
+    /// which match its name, type, and class, or `NXDOMAIN` if there are no
+    /// records for the name at all.
+    pub fn records(self, records: Vec<ResourceRecord>) -> Self {
+        self.respond(move |query| {
+            let Some(question) = query.questions.first() else {
+                return MockResponse::rcode(query, Rcode::FormatError);
+            };
+            if !records.iter().any(|rr| rr.name == question.name) {
+                return MockResponse::rcode(query, Rcode::NameError);
+            }
+            let answers = records
+                .iter()
+                .filter(|rr| rr.name == question.name && rr.matches(question))
+                .cloned()
+                .collect();
+            MockResponse::answer(query, answers)
+        })
+    }
+
+    /// Wait this long before responding to each query.  Defaults to no delay.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Respond to every UDP query with an empty truncated response, so that
+    /// clients have to retry over TCP.  Defaults to `false`.
+    pub fn truncate_udp(mut self, truncate_udp: bool) -> Self {
+        self.truncate_udp = truncate_udp;
+        self
+    }
+
+    /// Whether to listen on TCP as well as UDP.  Defaults to `true`.
+    pub fn tcp(mut self, tcp: bool) -> Self {
+        self.tcp = tcp;
+        self
+    }
+
+    /// Bind the sockets and start answering queries.
+    ///
+    /// # Errors
+    ///
+    /// If the sockets cannot be bound.
+    pub fn start(self) -> io::Result<MockNameserver> {
+        let (udp, tcp) = bind(self.address, self.tcp)?;
+        let address = udp.local_addr()?;
+
+        let server = Arc::new(Server {
+            handler: self.handler,
+            delay: self.delay,
+            truncate_udp: self.truncate_udp,
+            queries: Arc::new(Mutex::new(Vec::new())),
+            stop: Arc::new(AtomicBool::new(false)),
+        });
+
+        let mut threads = Vec::with_capacity(2);
+        udp.set_read_timeout(Some(POLL_INTERVAL))?;
+        threads.push(thread::spawn({
+            let server = server.clone();
+            move || server.serve_udp(&udp)
+        }));
+        if let Some(tcp) = tcp {
+            tcp.set_nonblocking(true)?;
+            threads.push(thread::spawn({
+                let server = server.clone();
+                move || server.serve_tcp(&tcp)
+            }));
+        }
+
+        Ok(MockNameserver {
+            address,
+            queries: server.queries.clone(),
+            stop: server.stop.clone(),
+            threads,
+        })
+    }
+}
+
+/// Bind a UDP socket, and optionally a TCP listener on the same port.  If the
+/// port is 0, keep trying until a port which is free for both is found.
+fn bind(address: SocketAddr, tcp: bool) -> io::Result<(UdpSocket, Option<TcpListener>)> {
+    loop {
+        let udp = UdpSocket::bind(address)?;
+        if !tcp {
+            return Ok((udp, None));
+        }
+        match TcpListener::bind(udp.local_addr()?) {
+            Ok(listener) => return Ok((udp, Some(listener))),
+            Err(error) if address.port() != 0 => return Err(error),
+            Err(_) => (),
+        }
+    }
+}
+
+/// The state shared between the listening threads.
+struct Server {
+    handler: Arc<Handler>,
+    delay: Duration,
+    truncate_udp: bool,
+    queries: Arc<Mutex<Vec<(Message, bool)>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Server {
+    fn serve_udp(self: Arc<Self>, socket: &UdpSocket) {
+        let mut buf = vec![0; 65535];
+        while !self.stop.load(Ordering::Relaxed) {
+            let Ok((size, peer)) = socket.recv_from(&mut buf) else {
+                continue;
+            };
+            let Ok(query) = Message::from_octets(&buf[..size]) else {
+                continue;
+            };
+            let Ok(socket) = socket.try_clone() else {
+                continue;
+            };
+
+            // respond in a separate thread so a delay doesn't hold up other
+            // queries
+            let server = self.clone();
+            thread::spawn(move || {
+                if let Some(mut octets) = server.respond(query, false) {
+                    if octets.len() > 512 {
+                        octets[2] |= 0b0000_0010;
+                        octets.truncate(512);
+                    }
+                    let _ = socket.send_to(&octets, peer);
+                }
+            });
+        }
+    }
+
+    fn serve_tcp(self: Arc<Self>, listener: &TcpListener) {
+        while !self.stop.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let server = self.clone();
+                    thread::spawn(move || server.serve_tcp_connection(stream));
+                }
+                Err(_) => thread::sleep(POLL_INTERVAL),
+            }
+        }
+    }
+
+    /// Answer queries on a connection until the client closes it or the
+    /// server is stopped.  Clients may send several queries over the same
+    /// connection.
+    fn serve_tcp_connection(self: Arc<Self>, mut stream: TcpStream) {
+        if stream.set_nonblocking(false).is_err()
+            || stream.set_read_timeout(Some(POLL_INTERVAL)).is_err()
+        {
+            return;
+        }
+
+        let mut len = [0; 2];
+        while !self.stop.load(Ordering::Relaxed) {
+            match stream.read_exact(&mut len) {
+                Ok(()) => (),
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(_) => return,
+            }
+
+            let mut buf = vec![0; usize::from(u16::from_be_bytes(len))];
+            if stream.set_read_timeout(None).is_err()
+                || stream.read_exact(&mut buf).is_err()
+                || stream.set_read_timeout(Some(POLL_INTERVAL)).is_err()
+            {
+                return;
+            }
+            let Ok(query) = Message::from_octets(&buf) else {
+                return;
+            };
+
+            if let Some(octets) = self.respond(query, true) {
+                let Ok(len) = u16::try_from(octets.len()) else {
+                    return;
+                };
+                if stream.write_all(&len.to_be_bytes()).is_err()
+                    || stream.write_all(&octets).is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Record a query and work out the serialised response, if there is one.
+    fn respond(&self, query: Message, via_tcp: bool) -> Option<Vec<u8>> {
+        let response = (self.handler)(&query);
+        self.queries.lock().unwrap().push((query, via_tcp));
+        thread::sleep(self.delay);
+
+        match response {
+            MockResponse::Message(mut message) => {
+                if self.truncate_udp && !via_tcp {
+                    message.header.is_truncated = true;
+                    message.answers.clear();
+                    message.authority.clear();
+                    message.additional.clear();
+                }
+                message.to_octets().ok().map(|octets| octets.to_vec())
+            }
+            MockResponse::Octets(octets) => Some(octets),
+            MockResponse::NoResponse => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dns_types::protocol::types::test_util::*;
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::util::nameserver::query_nameserver;
+    use crate::util::types::{FallbackPolicy, NetworkOptions, TransportKind, UpstreamError};
+
+    fn query(nameserver: &MockNameserver, name: &str) -> Result<Message, UpstreamError> {
+        query_with(nameserver, name, &FallbackPolicy::default())
+    }
+
+    fn query_with(
+        nameserver: &MockNameserver,
+        name: &str,
+        fallback: &FallbackPolicy,
+    ) -> Result<Message, UpstreamError> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(query_nameserver(
+            &nameserver.address().into(),
+            Question {
+                name: domain(name),
+                qtype: QueryType::Record(RecordType::A),
+                qclass: QueryClass::Record(RecordClass::IN),
+            },
+            false,
+            None,
+            Duration::from_millis(500),
+            NetworkOptions::default(),
+            fallback,
+            None,
+        ))
+    }
+
+    fn www_record() -> ResourceRecord {
+        a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1))
+    }
+
+    #[test]
+    fn answers_from_records() {
+        let nameserver = MockNameserver::builder()
+            .records(vec![www_record()])
+            .start()
+            .unwrap();
+
+        let response = query(&nameserver, "www.example.com.").unwrap();
+        assert_eq!(vec![www_record()], response.answers);
+
+        let response = query(&nameserver, "missing.example.com.").unwrap();
+        assert_eq!(Rcode::NameError, response.header.rcode);
+
+        assert_eq!(2, nameserver.queries().len());
+        assert!(nameserver.tcp_queries().is_empty());
+    }
+
+    #[test]
+    fn truncated_udp_retries_over_tcp() {
+        let nameserver = MockNameserver::builder()
+            .records(vec![www_record()])
+            .truncate_udp(true)
+            .start()
+            .unwrap();
+
+        let response = query(&nameserver, "www.example.com.").unwrap();
+        assert_eq!(vec![www_record()], response.answers);
+        assert_eq!(2, nameserver.queries().len());
+        assert_eq!(1, nameserver.tcp_queries().len());
+    }
+
+    #[test]
+    fn fallback_policy_sets_transport_order() {
+        let nameserver = MockNameserver::builder()
+            .records(vec![www_record()])
+            .start()
+            .unwrap();
+
+        let fallback = FallbackPolicy::new(vec![TransportKind::Tcp, TransportKind::Udp]);
+        let response = query_with(&nameserver, "www.example.com.", &fallback).unwrap();
+        assert_eq!(vec![www_record()], response.answers);
+        assert_eq!(1, nameserver.queries().len());
+        assert_eq!(1, nameserver.tcp_queries().len());
+    }
+
+    #[test]
+    fn truncated_udp_without_fallback_is_invalid() {
+        let nameserver = MockNameserver::builder()
+            .records(vec![www_record()])
+            .truncate_udp(true)
+            .start()
+            .unwrap();
+
+        let fallback = FallbackPolicy::new(vec![TransportKind::Udp]);
+        assert_eq!(
+            Err(UpstreamError::InvalidResponse),
+            query_with(&nameserver, "www.example.com.", &fallback)
+        );
+        assert!(nameserver.tcp_queries().is_empty());
+    }
+
+    #[test]
+    fn malformed_is_invalid() {
+        let nameserver = MockNameserver::builder()
+            .respond(MockResponse::malformed)
+            .start()
+            .unwrap();
+
+        assert_eq!(
+            Err(UpstreamError::InvalidResponse),
+            query(&nameserver, "www.example.com.")
+        );
+    }
+
+    #[test]
+    fn refused_is_refused() {
+        let nameserver = MockNameserver::builder().start().unwrap();
+
+        assert_eq!(
+            Err(UpstreamError::Refused),
+            query(&nameserver, "www.example.com.")
+        );
+    }
+
+    #[test]
+    fn delay_past_timeout_times_out() {
+        let nameserver = MockNameserver::builder()
+            .records(vec![www_record()])
+            .delay(Duration::from_secs(1))
+            .tcp(false)
+            .start()
+            .unwrap();
+
+        assert_eq!(
+            Err(UpstreamError::Timeout),
+            query(&nameserver, "www.example.com.")
+        );
+    }
+}
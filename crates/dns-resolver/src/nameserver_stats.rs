@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use dns_types::protocol::types::DomainName;
+
+/// The round-trip time assumed for a nameserver which hasn't been queried
+/// yet.  This is optimistic, so that new nameservers get tried, but not so
+/// optimistic that a known-fast nameserver is passed over.
+pub const INITIAL_RTT: Duration = Duration::from_millis(200);
+
+/// How long a failure counts against a nameserver.  After this, it gets
+/// another chance.
+pub const FAILURE_MEMORY: Duration = Duration::from_mins(5);
+
+/// How long to remember a nameserver which isn't being queried.
+pub const MAX_IDLE: Duration = Duration::from_hours(1);
+
+/// How long a lame nameserver is held down for a zone: it isn't asked about
+/// that zone again until this has passed.
+pub const LAME_HOLD_DOWN: Duration = Duration::from_mins(15);
+
+/// The most failures which make a nameserver look slower: each one doubles
+/// its score, up to this many.
+const MAX_FAILURE_PENALTY: u32 = 6;
+
+const MUTEX_POISON_MESSAGE: &str =
+    "[INTERNAL ERROR] nameserver stats mutex poisoned, cannot recover from this - aborting";
+
+/// How quickly, and how reliably, each upstream nameserver has responded, so
+/// that the recursive resolver can prefer the best ones.
+///
+/// The round-trip time is smoothed in the same way as TCP (RFC 6298), so one
+/// slow response doesn't outweigh a history of fast ones.
+///
+/// Nameservers which are lame for a zone - they responded, but with
+/// `REFUSED`, `FORMERR`, or something which shows they aren't authoritative
+/// for it - are held down for `LAME_HOLD_DOWN`, so that the recursive resolver
+/// doesn't keep asking them.
+///
+/// Invoking `clone` on a `NameserverStats` gives a new instance which refers
+/// to the same underlying statistics.
+#[derive(Debug, Clone, Default)]
+pub struct NameserverStats {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    stats: HashMap<IpAddr, Stats>,
+    /// When each hold-down of a nameserver for a zone ends.
+    lame: HashMap<(IpAddr, DomainName), Instant>,
+}
+
+/// The statistics for one nameserver.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Stats {
+    /// The smoothed round-trip time, if the nameserver has ever responded.
+    pub srtt: Option<Duration>,
+    /// How many queries in a row have failed.
+    pub failures: u32,
+    /// When the last query failed.
+    pub last_failure: Option<Instant>,
+    /// When the last query was sent.
+    pub last_used: Instant,
+}
+
+impl Stats {
+    /// The expected time to get a response: lower is better.  Each recent
+    /// failure doubles this.
+    pub fn score(&self, now: Instant) -> Duration {
+        let rtt = self.srtt.unwrap_or(INITIAL_RTT);
+        match self.last_failure {
+            Some(t) if now.saturating_duration_since(t) < FAILURE_MEMORY => {
+                rtt.saturating_mul(1 << self.failures.min(MAX_FAILURE_PENALTY))
+            }
+            _ => rtt,
+        }
+    }
+}
+
+impl NameserverStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a response from a nameserver, and how long it took.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn record_success(&self, address: IpAddr, rtt: Duration) {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        let stats = inner.stats.entry(address).or_insert_with(new_stats);
+        stats.srtt = Some(match stats.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+        stats.failures = 0;
+        stats.last_failure = None;
+        stats.last_used = Instant::now();
+    }
+
+    /// Record that a nameserver couldn't be reached, or didn't respond in
+    /// time.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn record_failure(&self, address: IpAddr) {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        let stats = inner.stats.entry(address).or_insert_with(new_stats);
+        stats.failures = stats.failures.saturating_add(1);
+        stats.last_failure = Some(now);
+        stats.last_used = now;
+    }
+
+    /// Get the statistics for a nameserver, if it has been queried.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn get(&self, address: IpAddr) -> Option<Stats> {
+        self.inner
+            .lock()
+            .expect(MUTEX_POISON_MESSAGE)
+            .stats
+            .get(&address)
+            .copied()
+    }
+
+    /// Record that a nameserver is lame for a zone, holding it down for
+    /// `LAME_HOLD_DOWN`.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn record_lame(&self, address: IpAddr, zone: &DomainName) {
+        self.inner
+            .lock()
+            .expect(MUTEX_POISON_MESSAGE)
+            .lame
+            .insert((address, zone.clone()), Instant::now() + LAME_HOLD_DOWN);
+    }
+
+    /// Check if a nameserver is currently held down for a zone.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn is_lame(&self, address: IpAddr, zone: &DomainName) -> bool {
+        self.inner
+            .lock()
+            .expect(MUTEX_POISON_MESSAGE)
+            .lame
+            .get(&(address, zone.clone()))
+            .is_some_and(|until| *until > Instant::now())
+    }
+
+    /// The expected time to get a response from a nameserver: lower is
+    /// better.  A nameserver which hasn't been queried scores `INITIAL_RTT`.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn score(&self, address: IpAddr) -> Duration {
+        self.get(address)
+            .map_or(INITIAL_RTT, |stats| stats.score(Instant::now()))
+    }
+
+    /// Forget nameservers which haven't been queried for `MAX_IDLE`, and
+    /// hold-downs which have ended.
+    ///
+    /// Returns `(current size, num pruned)`, not counting hold-downs.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn prune(&self) -> (usize, usize) {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        inner.lame.retain(|_, until| *until > now);
+        let before = inner.stats.len();
+        inner
+            .stats
+            .retain(|_, stats| now.saturating_duration_since(stats.last_used) < MAX_IDLE);
+        (inner.stats.len(), before - inner.stats.len())
+    }
+}
+
+fn new_stats() -> Stats {
+    Stats {
+        srtt: None,
+        failures: 0,
+        last_failure: None,
+        last_used: Instant::now(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dns_types::protocol::types::test_util::*;
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    #[test]
+    fn unknown_nameserver_scores_initial_rtt() {
+        assert_eq!(INITIAL_RTT, NameserverStats::new().score(ADDRESS));
+    }
+
+    #[test]
+    fn first_response_sets_rtt() {
+        let stats = NameserverStats::new();
+        stats.record_success(ADDRESS, Duration::from_millis(40));
+
+        assert_eq!(Duration::from_millis(40), stats.score(ADDRESS));
+    }
+
+    #[test]
+    fn later_responses_are_smoothed() {
+        let stats = NameserverStats::new();
+        stats.record_success(ADDRESS, Duration::from_millis(40));
+        stats.record_success(ADDRESS, Duration::from_millis(120));
+
+        assert_eq!(Duration::from_millis(50), stats.score(ADDRESS));
+    }
+
+    #[test]
+    fn failures_double_score() {
+        let stats = NameserverStats::new();
+        stats.record_success(ADDRESS, Duration::from_millis(40));
+        stats.record_failure(ADDRESS);
+        stats.record_failure(ADDRESS);
+
+        assert_eq!(Duration::from_millis(160), stats.score(ADDRESS));
+    }
+
+    #[test]
+    fn old_failures_are_forgiven() {
+        let now = Instant::now();
+        let stats = Stats {
+            srtt: Some(Duration::from_millis(40)),
+            failures: 3,
+            last_failure: Some(now),
+            last_used: now,
+        };
+
+        assert_eq!(Duration::from_millis(40), stats.score(now + FAILURE_MEMORY));
+    }
+
+    #[test]
+    fn response_resets_failures() {
+        let stats = NameserverStats::new();
+        stats.record_failure(ADDRESS);
+        stats.record_success(ADDRESS, Duration::from_millis(40));
+
+        assert_eq!(Duration::from_millis(40), stats.score(ADDRESS));
+    }
+
+    #[test]
+    fn lame_nameserver_is_held_down_for_zone() {
+        let stats = NameserverStats::new();
+        stats.record_lame(ADDRESS, &domain("example.com."));
+
+        assert!(stats.is_lame(ADDRESS, &domain("example.com.")));
+        assert!(!stats.is_lame(ADDRESS, &domain("example.net.")));
+        assert!(!stats.is_lame(
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)),
+            &domain("example.com.")
+        ));
+    }
+
+    #[test]
+    fn lame_nameserver_still_scores_normally() {
+        let stats = NameserverStats::new();
+        stats.record_success(ADDRESS, Duration::from_millis(40));
+        stats.record_lame(ADDRESS, &domain("example.com."));
+
+        assert_eq!(Duration::from_millis(40), stats.score(ADDRESS));
+    }
+
+    #[test]
+    fn prune_forgets_ended_hold_downs() {
+        let stats = NameserverStats::new();
+        stats
+            .inner
+            .lock()
+            .unwrap()
+            .lame
+            .insert((ADDRESS, domain("example.com.")), Instant::now());
+        stats.record_lame(ADDRESS, &domain("example.net."));
+        stats.prune();
+
+        let inner = stats.inner.lock().unwrap();
+        assert_eq!(1, inner.lame.len());
+        assert!(inner.lame.contains_key(&(ADDRESS, domain("example.net."))));
+    }
+}
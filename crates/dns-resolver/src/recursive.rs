@@ -1,33 +1,58 @@
 use async_recursion::async_recursion;
-use std::cmp::Ordering;
+use std::cmp::{Ordering, Reverse};
 use std::collections::{HashMap, HashSet};
-use std::net::IpAddr;
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
 use tokio::time::timeout;
 use tracing::Instrument;
 
 use dns_types::protocol::types::*;
 
 use crate::context::Context;
+use crate::events::Event;
 use crate::local::{resolve_local, LocalResolutionResult};
+use crate::nameserver_stats::NameserverStats;
+use crate::root_hints::RootHints;
 use crate::util::nameserver::*;
 use crate::util::types::*;
 
-pub struct RecursiveContextInner {
+pub struct RecursiveContextInner<'a> {
     pub protocol_mode: ProtocolMode,
     pub upstream_dns_port: u16,
+    pub qname_minimisation: bool,
+    pub root_hints: &'a RootHints,
+    pub nameserver_stats: &'a NameserverStats,
+    pub network: NetworkOptions,
 }
 
-pub type RecursiveContext<'a> = Context<'a, RecursiveContextInner>;
+pub type RecursiveContext<'a> = Context<'a, RecursiveContextInner<'a>>;
+
+/// How long to wait for a nameserver to respond at its address in the
+/// preferred family before also trying its address in the other family, as
+/// recommended by RFC 8305.
+pub const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
 
 /// Recursive DNS resolution.
 ///
 /// This corresponds to the standard resolver algorithm.  If
 /// information is not held locally, it will call out to remote
-/// nameservers, starting with the given root hints.  Since it may
+/// nameservers, starting with the root hints.  Since it may
 /// make network requests, this function is async.
 ///
-/// This has a 60s timeout.
+/// If QNAME minimisation is enabled, nameservers are only sent as much of the
+/// question name as they need to see to give a delegation: see
+/// `query_nameserver_minimised`.
+///
+/// Candidate nameservers are tried fastest first, going by how quickly (and
+/// how reliably) they have responded in the past: see `NameserverStats`.  If a
+/// nameserver has both an IPv4 and an IPv6 address, and the protocol mode
+/// allows both, both are tried: see `query_nameserver_happy_eyeballs`.
+/// Nameservers which are lame for a zone are held down for a while and not
+/// asked about it, and if a nameserver turns out to be lame the next candidate
+/// is tried.
+///
+/// This gives up when the context's resolution timeout is reached.
 ///
 /// See section 5.3.3 of RFC 1034.
 ///
@@ -39,7 +64,7 @@ pub async fn resolve_recursive<'a>(
     question: &Question,
 ) -> Result<ResolvedRecord, ResolutionError> {
     if let Ok(res) = timeout(
-        Duration::from_secs(60),
+        context.time_remaining(),
         resolve_recursive_notimeout(context, question),
     )
     .await
@@ -74,7 +99,10 @@ async fn resolve_recursive_notimeout<'a>(
     match resolve_local(context, question) {
         Ok(LocalResolutionResult::Done { resolved }) => return Ok(resolved),
         Ok(LocalResolutionResult::Partial { rrs }) => combined_rrs = rrs,
-        Ok(LocalResolutionResult::Delegation { delegation, .. }) => candidates = Some(delegation),
+        Ok(LocalResolutionResult::Delegation { delegation, .. }) => {
+            observe_referral(context, &delegation);
+            candidates = Some(delegation);
+        }
         Ok(LocalResolutionResult::CNAME {
             rrs,
             cname_question,
@@ -85,35 +113,45 @@ async fn resolve_recursive_notimeout<'a>(
             context.pop_question();
             return answer;
         }
+        Err(error @ ResolutionError::CnameLoop { .. }) => return Err(error),
         Err(_) => (),
     }
 
     context.push_question(question);
 
-    if candidates.is_none() {
-        candidates = candidate_nameservers(context, &question.name);
-    }
+    let candidates = candidates.unwrap_or_else(|| candidate_nameservers(context, &question.name));
+    let mut zone = candidates.name.clone();
+    let mut candidate_hostnames = candidates.hostnames;
+    sort_candidates(context, &mut candidate_hostnames);
+    let mut next_candidate_hostnames = Vec::with_capacity(candidate_hostnames.len());
+    let mut resolve_candidates_locally = true;
+    let mut lame_error = None;
+
+    loop {
+        let Some(candidate) = candidate_hostnames.pop() else {
+            // try slow candidates if out of fast ones
+            if resolve_candidates_locally && !next_candidate_hostnames.is_empty() {
+                tracing::trace!("restarting with slow candidates");
+                candidate_hostnames = next_candidate_hostnames;
+                next_candidate_hostnames = Vec::new();
+                resolve_candidates_locally = false;
+                continue;
+            }
+            break;
+        };
 
-    if let Some(candidates) = candidates {
-        let mut match_count = candidates.match_count();
-        let mut candidate_hostnames = candidates.hostnames;
-        let mut next_candidate_hostnames = Vec::with_capacity(candidate_hostnames.len());
-        let mut resolve_candidates_locally = true;
+        tracing::trace!(?candidate, "got candidate nameserver");
+        let mut ips =
+            resolve_hostname_to_ips(context, resolve_candidates_locally, candidate.clone()).await;
+        if !ips.is_empty() {
+            drop_lame_ips(context, &mut ips, &zone);
+            if ips.is_empty() {
+                tracing::trace!(?candidate, "skipping lame candidate");
+                continue;
+            }
 
-        while let Some(candidate) = candidate_hostnames.pop() {
-            tracing::trace!(?candidate, "got candidate nameserver");
-            if let Some(ip) =
-                resolve_hostname_to_ip(context, resolve_candidates_locally, candidate.clone()).await
-            {
-                if let Some(nameserver_response) = query_nameserver(
-                    (ip, context.r.upstream_dns_port).into(),
-                    question.clone(),
-                    false,
-                )
-                .instrument(tracing::error_span!("query_nameserver", address = %ip, %match_count))
-                .await
-                .and_then(|res| validate_nameserver_response(question, &res, match_count))
-                {
+            match query_nameserver_happy_eyeballs(context, ips, question, &zone).await {
+                Ok(nameserver_response) => {
                     if resolve_candidates_locally {
                         tracing::trace!(?candidate, "resolved fast candidate");
                     } else {
@@ -125,6 +163,7 @@ async fn resolve_recursive_notimeout<'a>(
                         combined_rrs.clone(),
                         nameserver_response,
                         question,
+                        &zone,
                     )
                     .await
                     {
@@ -133,60 +172,312 @@ async fn resolve_recursive_notimeout<'a>(
                             return result;
                         }
                         Err(delegation) => {
-                            match_count = delegation.match_count();
+                            observe_referral(context, &delegation);
+                            zone = delegation.name.clone();
                             candidate_hostnames = delegation.hostnames;
+                            sort_candidates(context, &mut candidate_hostnames);
                             next_candidate_hostnames =
                                 Vec::with_capacity(candidate_hostnames.len());
                             resolve_candidates_locally = true;
+                            lame_error = None;
                         }
                     }
-                } else {
+                }
+                Err(error) if error.is_lame() => {
+                    tracing::trace!(?candidate, %error, "lame candidate - trying next");
+                    lame_error = Some(error);
+                }
+                Err(error) => {
                     context.metrics().nameserver_miss();
-                    // TODO: should distinguish between timeouts and other
-                    // failures here, and try the next nameserver after a
-                    // timeout.
+                    // TODO: should try the next nameserver after a timeout.
                     context.pop_question();
-                    return Err(ResolutionError::DeadEnd {
+                    return Err(ResolutionError::Upstream {
                         question: question.clone(),
+                        error,
                     });
                 }
-            } else if resolve_candidates_locally {
-                tracing::trace!(?candidate, "skipping slow candidate");
-                next_candidate_hostnames.push(candidate.clone());
-                // try slow candidates if out of fast ones
-                if candidate_hostnames.is_empty() {
-                    tracing::trace!("restarting with slow candidates");
-                    candidate_hostnames = next_candidate_hostnames;
-                    next_candidate_hostnames = Vec::new();
-                    resolve_candidates_locally = false;
-                }
-            } else {
-                // failed to resolve the candidate recursively, just drop it.
-                tracing::trace!(?candidate, "dropping unresolvable candidate");
             }
+        } else if resolve_candidates_locally {
+            tracing::trace!(?candidate, "skipping slow candidate");
+            next_candidate_hostnames.push(candidate.clone());
+        } else {
+            // failed to resolve the candidate recursively, just drop it.
+            tracing::trace!(?candidate, "dropping unresolvable candidate");
         }
     }
 
     tracing::trace!("out of candidates");
     context.pop_question();
-    Err(ResolutionError::DeadEnd {
-        question: question.clone(),
+    if let Some(error) = lame_error {
+        context.metrics().nameserver_miss();
+        Err(ResolutionError::Upstream {
+            question: question.clone(),
+            error,
+        })
+    } else {
+        Err(ResolutionError::DeadEnd {
+            question: question.clone(),
+        })
+    }
+}
+
+/// Drop the addresses of a candidate nameserver which are held down as lame
+/// for the zone: see `NameserverStats`.
+fn drop_lame_ips(context: &mut RecursiveContext<'_>, ips: &mut Vec<IpAddr>, zone: &DomainName) {
+    let stats = context.r.nameserver_stats;
+    ips.retain(|ip| {
+        let lame = stats.is_lame(*ip, zone);
+        if lame {
+            tracing::trace!(%ip, %zone, "skipping lame address");
+            context.metrics().nameserver_lame_skipped();
+        }
+        !lame
+    });
+}
+
+/// Query a nameserver at one of its addresses with `query_nameserver_minimised`.
+///
+/// This is "Happy Eyeballs" (RFC 8305): the address with the best score in the
+/// `NameserverStats` (or the first, if there's a tie) is tried, and if it
+/// hasn't responded after `HAPPY_EYEBALLS_DELAY`, or fails before then, the
+/// next is tried as well, and so on.  The first usable response wins, and the
+/// addresses which were still being tried count as having failed.  So a broken
+/// IPv6 path only delays resolution a little, and only until the resolver
+/// learns to prefer IPv4, rather than by a whole query timeout every time.
+///
+/// Addresses which turn out to be lame for the `zone` are held down in the
+/// `NameserverStats`.
+///
+/// If every address fails, the reason the last one failed is returned.
+async fn query_nameserver_happy_eyeballs(
+    context: &mut RecursiveContext<'_>,
+    mut ips: Vec<IpAddr>,
+    question: &Question,
+    zone: &DomainName,
+) -> Result<NameserverResponse, UpstreamError> {
+    let port = context.r.upstream_dns_port;
+    let minimise = context.r.qname_minimisation;
+    let query_timeout = context.query_timeout();
+    let stats = context.r.nameserver_stats;
+    let network = context.r.network;
+
+    ips.sort_by_cached_key(|ip| stats.score(*ip));
+
+    let mut remaining = ips.into_iter();
+    let mut in_flight = Vec::with_capacity(remaining.len());
+    let mut set = JoinSet::new();
+    let spawn = |context: &RecursiveContext<'_>,
+                 set: &mut JoinSet<_>,
+                 in_flight: &mut Vec<IpAddr>,
+                 ip: IpAddr| {
+        context.observe(|| Event::UpstreamQuery {
+            upstream: Upstream::Address((ip, port).into()),
+            question: question.clone(),
+        });
+        let span = tracing::error_span!("query_nameserver", address = %ip, %zone);
+        let question = question.clone();
+        let zone = zone.clone();
+        let stats = stats.clone();
+        in_flight.push(ip);
+        set.spawn(
+            async move {
+                let result = query_nameserver_minimised(
+                    (ip, port).into(),
+                    &question,
+                    &zone,
+                    minimise,
+                    query_timeout,
+                    &stats,
+                    network,
+                )
+                .await;
+                (ip, result)
+            }
+            .instrument(span),
+        );
+    };
+
+    // always overwritten, as there is at least one address
+    let mut last_error = UpstreamError::Unreachable;
+    loop {
+        if set.is_empty() {
+            match remaining.next() {
+                Some(ip) => spawn(context, &mut set, &mut in_flight, ip),
+                None => return Err(last_error),
+            }
+        }
+
+        let joined = if remaining.len() > 0 {
+            timeout(HAPPY_EYEBALLS_DELAY, set.join_next()).await
+        } else {
+            Ok(set.join_next().await)
+        };
+
+        // dropping the `JoinSet` aborts the queries which are still in-flight
+        if let Ok(Some(Ok((ip, result)))) = &joined {
+            context.observe(|| Event::UpstreamResponse {
+                upstream: Upstream::Address((*ip, port).into()),
+                error: result.as_ref().err().copied(),
+            });
+        }
+
+        match joined {
+            Ok(Some(Ok((ip, Ok(response))))) => {
+                for slow_ip in in_flight.into_iter().filter(|i| *i != ip) {
+                    stats.record_failure(slow_ip);
+                }
+                return Ok(response);
+            }
+            Ok(Some(Ok((ip, Err(error))))) => {
+                if error.is_lame() {
+                    tracing::debug!(%ip, %zone, %error, "holding down lame nameserver");
+                    stats.record_lame(ip, zone);
+                    context.metrics().nameserver_lame();
+                }
+                in_flight.retain(|i| *i != ip);
+                last_error = error;
+            }
+            Ok(Some(Err(_)) | None) => (),
+            Err(_) => {
+                if let Some(ip) = remaining.next() {
+                    tracing::trace!(%ip, "no response yet - also trying next address");
+                    spawn(context, &mut set, &mut in_flight, ip);
+                }
+            }
+        }
+    }
+}
+
+/// Query a nameserver, which is authoritative for `zone`, and validate the
+/// response.
+///
+/// If `minimise` is true this does QNAME minimisation (RFC 9156): rather than
+/// sending the full question, the nameserver is asked for an `A` record at the
+/// name one label below its zone, then two labels below, and so on, until it
+/// gives a delegation (which is returned) or the full name is reached.  So the
+/// root nameservers see `com.` rather than `www.example.com.`, and the `com.`
+/// nameservers see `example.com.`.
+///
+/// If the nameserver gives an NXDOMAIN, or an invalid response, for one of the
+/// shorter names, the full question is sent instead: some nameservers wrongly
+/// answer NXDOMAIN for names which have subdomains but no records of their own.
+///
+/// An unusable response to the full question without the `AA` bit means the
+/// nameserver isn't authoritative for the zone it was delegated.
+///
+/// Each query has a timeout of `query_timeout`, and how long it takes is
+/// recorded in `stats`.
+async fn query_nameserver_minimised(
+    address: SocketAddr,
+    question: &Question,
+    zone: &DomainName,
+    minimise: bool,
+    query_timeout: Duration,
+    stats: &NameserverStats,
+    network: NetworkOptions,
+) -> Result<NameserverResponse, UpstreamError> {
+    let mut labels = zone.labels.len() + 1;
+    while minimise && labels < question.name.labels.len() {
+        let Some(minimised_question) = minimised_question(question, labels) else {
+            break;
+        };
+        tracing::trace!(%minimised_question, "querying with minimised question");
+
+        let response = query_nameserver_timed(
+            address,
+            minimised_question.clone(),
+            query_timeout,
+            stats,
+            network,
+        )
+        .await?;
+        if response.header.rcode == Rcode::NameError {
+            tracing::trace!("got NXDOMAIN for minimised question - using full question");
+            break;
+        }
+
+        match validate_nameserver_response(&minimised_question, &response, zone) {
+            Some(delegation @ NameserverResponse::Delegation { .. }) => return Ok(delegation),
+            Some(_) => labels += 1,
+            None => {
+                tracing::trace!(
+                    "got invalid response for minimised question - using full question"
+                );
+                break;
+            }
+        }
+    }
+
+    let response =
+        query_nameserver_timed(address, question.clone(), query_timeout, stats, network).await?;
+    validate_nameserver_response(question, &response, zone).ok_or(
+        if response.header.is_authoritative {
+            UpstreamError::InvalidResponse
+        } else {
+            UpstreamError::NotAuthoritative
+        },
+    )
+}
+
+/// Query a nameserver, recording how long it took to respond, or that it
+/// didn't.
+///
+/// An error response still counts as a response: only timeouts and network
+/// errors count as failures.
+async fn query_nameserver_timed(
+    address: SocketAddr,
+    question: Question,
+    query_timeout: Duration,
+    stats: &NameserverStats,
+    network: NetworkOptions,
+) -> Result<Message, UpstreamError> {
+    let start = Instant::now();
+    let response = query_nameserver(
+        &Upstream::Address(address),
+        question,
+        false,
+        None,
+        query_timeout,
+        network,
+        &FallbackPolicy::default(),
+        None,
+    )
+    .await;
+    match response {
+        Err(UpstreamError::Timeout | UpstreamError::Unreachable) => {
+            stats.record_failure(address.ip());
+        }
+        _ => stats.record_success(address.ip(), start.elapsed()),
+    }
+    response
+}
+
+/// The question to ask when QNAME minimisation is revealing only the last
+/// `labels` labels (including the root label) of the question name.
+fn minimised_question(question: &Question, labels: usize) -> Option<Question> {
+    let skip = question.name.labels.len().checked_sub(labels)?;
+    Some(Question {
+        name: DomainName::from_labels(question.name.labels[skip..].into())?,
+        qtype: QueryType::Record(RecordType::A),
+        qclass: question.qclass,
     })
 }
 
 /// Helper function for answering a question given a response from an upstream
-/// nameserver: this will only do further querying if the response is a CNAME.
+/// nameserver, which is authoritative for `zone`: this will only do further
+/// querying if the response is a CNAME.
 #[async_recursion]
 async fn resolve_with_nameserver_response<'a>(
     context: &mut RecursiveContext<'a>,
     mut combined_rrs: Vec<ResourceRecord>,
     nameserver_response: NameserverResponse,
     question: &Question,
+    zone: &DomainName,
 ) -> Result<Result<ResolvedRecord, ResolutionError>, Nameservers> {
     match nameserver_response {
         NameserverResponse::Answer { rrs, soa_rr, .. } => {
             tracing::trace!("got recursive answer");
-            context.cache.insert_all(&rrs);
+            cache_in_bailiwick(context, &rrs, zone);
             prioritising_merge(&mut combined_rrs, rrs);
             Ok(Ok(ResolvedRecord::NonAuthoritative {
                 rrs: combined_rrs,
@@ -196,7 +487,7 @@ async fn resolve_with_nameserver_response<'a>(
         NameserverResponse::Delegation {
             rrs, delegation, ..
         } => {
-            context.cache.insert_all(&rrs);
+            cache_in_bailiwick(context, &rrs, zone);
             if question.qtype == QueryType::Record(RecordType::A) {
                 if let Some(rr) = get_record(&rrs, &question.name, RecordType::A) {
                     tracing::trace!("got recursive delegation - using glue A record");
@@ -221,7 +512,11 @@ async fn resolve_with_nameserver_response<'a>(
         }
         NameserverResponse::CNAME { rrs, cname, .. } => {
             tracing::trace!("got recursive CNAME");
-            context.cache.insert_all(&rrs);
+            context.observe(|| Event::CNAME {
+                name: question.name.clone(),
+                target: cname.clone(),
+            });
+            cache_in_bailiwick(context, &rrs, zone);
             prioritising_merge(&mut combined_rrs, rrs);
             let cname_question = Question {
                 name: cname,
@@ -235,6 +530,28 @@ async fn resolve_with_nameserver_response<'a>(
     }
 }
 
+/// Cache records from a nameserver which is authoritative for `zone`.
+///
+/// `validate_nameserver_response` has already dropped any records which are
+/// out of bailiwick, but this checks again, so that a mistake there can't
+/// poison the cache.
+fn cache_in_bailiwick(context: &RecursiveContext<'_>, rrs: &[ResourceRecord], zone: &DomainName) {
+    if rrs.iter().all(|rr| rr.name.is_subdomain_of(zone)) {
+        context.cache.insert_all(rrs);
+    } else {
+        tracing::warn!(%zone, "refusing to cache out of bailiwick records");
+        context.cache.insert_all(&in_bailiwick(rrs, zone));
+    }
+}
+
+/// Send a delegation which is about to be followed to the observer.
+fn observe_referral(context: &RecursiveContext<'_>, delegation: &Nameservers) {
+    context.observe(|| Event::Referral {
+        name: delegation.name.clone(),
+        nameservers: delegation.hostnames.clone(),
+    });
+}
+
 /// Helper function for resolving CNAMEs: resolve, and add some existing RRs to
 /// the ANSWER section of the result.
 async fn resolve_combined_recursive<'a>(
@@ -242,32 +559,77 @@ async fn resolve_combined_recursive<'a>(
     mut rrs: Vec<ResourceRecord>,
     question: Question,
 ) -> Result<ResolvedRecord, ResolutionError> {
-    match resolve_recursive_notimeout(context, &question)
+    if context.is_duplicate_question(&question) {
+        tracing::debug!("hit CNAME loop");
+        return Err(ResolutionError::CnameLoop { question });
+    }
+
+    let resolved = resolve_recursive_notimeout(context, &question)
         .instrument(tracing::error_span!("resolve_combined_recursive", %question))
-        .await
-    {
-        Ok(resolved) => {
-            let soa_rr = resolved.soa_rr().cloned();
-            rrs.append(&mut resolved.rrs());
-            Ok(ResolvedRecord::NonAuthoritative { rrs, soa_rr })
-        }
-        Err(_) => Err(ResolutionError::DeadEnd { question }),
+        .await?;
+    let soa_rr = resolved.soa_rr().cloned();
+    rrs.append(&mut resolved.rrs());
+    Ok(ResolvedRecord::NonAuthoritative { rrs, soa_rr })
+}
+
+/// Sort candidate nameservers so that the best one is at the end, to be
+/// popped first.
+///
+/// A candidate is scored by the best of its addresses in the cache: candidates
+/// whose addresses aren't in the cache come first, to be tried last, as
+/// finding their addresses needs another query.
+fn sort_candidates(context: &RecursiveContext<'_>, hostnames: &mut [DomainName]) {
+    hostnames.sort_by_cached_key(|hostname| {
+        cached_ips(context, hostname)
+            .into_iter()
+            .map(|ip| context.r.nameserver_stats.score(ip))
+            .min()
+            .map(Reverse)
+    });
+}
+
+/// Get the addresses of a hostname from the cache, of the families allowed by
+/// the protocol mode.
+///
+/// This does not count towards the cache hits and misses.
+fn cached_ips(context: &RecursiveContext<'_>, hostname: &DomainName) -> Vec<IpAddr> {
+    address_rtypes(context.r.protocol_mode)
+        .into_iter()
+        .filter_map(|rtype| {
+            let mut rrs = context
+                .cache
+                .get_without_checking_expiration(hostname, QueryType::Record(rtype));
+            rrs.retain(|rr| rr.ttl > 0);
+            get_ip(&rrs, hostname, rtype)
+        })
+        .collect()
+}
+
+/// The address record types to look up for a nameserver, in order of
+/// preference.
+fn address_rtypes(protocol_mode: ProtocolMode) -> Vec<RecordType> {
+    match protocol_mode {
+        ProtocolMode::OnlyV4 => vec![RecordType::A],
+        ProtocolMode::PreferV4 => vec![RecordType::A, RecordType::AAAA],
+        ProtocolMode::PreferV6 => vec![RecordType::AAAA, RecordType::A],
+        ProtocolMode::OnlyV6 => vec![RecordType::AAAA],
     }
 }
 
-/// Resolve a hostname into an IP address, optionally only doing local
+/// Resolve a hostname into IP addresses, optionally only doing local
 /// resolution.
-async fn resolve_hostname_to_ip<'a>(
+///
+/// The addresses are in the order of preference given by the protocol mode,
+/// with at most one of each family.  When resolving locally both families
+/// are looked up, but when resolving recursively this stops after the first
+/// address is found, to avoid making more queries.
+async fn resolve_hostname_to_ips<'a>(
     context: &mut RecursiveContext<'a>,
     resolve_locally: bool,
     hostname: DomainName,
-) -> Option<IpAddr> {
-    let rtypes = match context.r.protocol_mode {
-        ProtocolMode::OnlyV4 => vec![RecordType::A],
-        ProtocolMode::PreferV4 => vec![RecordType::A, RecordType::AAAA],
-        ProtocolMode::PreferV6 => vec![RecordType::AAAA, RecordType::A],
-        ProtocolMode::OnlyV6 => vec![RecordType::AAAA],
-    };
+) -> Vec<IpAddr> {
+    let rtypes = address_rtypes(context.r.protocol_mode);
+    let mut addresses = Vec::with_capacity(rtypes.len());
 
     let mut question = Question {
         name: hostname,
@@ -280,20 +642,19 @@ async fn resolve_hostname_to_ip<'a>(
         if resolve_locally {
             if let Ok(LocalResolutionResult::Done { resolved }) = resolve_local(context, &question)
             {
-                let address = get_ip(&resolved.rrs(), &question.name, rtype);
-                if address.is_some() {
-                    return address;
+                if let Some(address) = get_ip(&resolved.rrs(), &question.name, rtype) {
+                    addresses.push(address);
                 }
             }
         } else if let Ok(result) = resolve_recursive_notimeout(context, &question).await {
-            let address = get_ip(&result.rrs(), &question.name, rtype);
-            if address.is_some() {
-                return address;
+            if let Some(address) = get_ip(&result.rrs(), &question.name, rtype) {
+                addresses.push(address);
+                break;
             }
         }
     }
 
-    None
+    addresses
 }
 
 /// Get the best nameservers by non-recursively looking them up for
@@ -301,10 +662,7 @@ async fn resolve_hostname_to_ip<'a>(
 /// are found, the root hints are returned.
 ///
 /// This corresponds to step 2 of the standard resolver algorithm.
-fn candidate_nameservers(
-    context: &mut RecursiveContext<'_>,
-    question: &DomainName,
-) -> Option<Nameservers> {
+fn candidate_nameservers(context: &mut RecursiveContext<'_>, question: &DomainName) -> Nameservers {
     for i in 0..question.labels.len() {
         let labels = &question.labels[i..];
         if let Some(name) = DomainName::from_labels(labels.into()) {
@@ -325,19 +683,29 @@ fn candidate_nameservers(
             }
 
             if !hostnames.is_empty() {
-                return Some(Nameservers {
+                return Nameservers {
                     hostnames,
                     name: ns_q.name,
-                });
+                };
             }
         }
     }
 
-    None
+    // cache the hints, so that the addresses of the root nameservers can be
+    // found like those of any other nameserver
+    tracing::trace!("using root hints");
+    context.cache.insert_all(context.r.root_hints.rrs());
+    Nameservers {
+        hostnames: context.r.root_hints.nameservers(),
+        name: DomainName::root_domain(),
+    }
 }
 
 /// Validate a nameserver response against the question by only keeping valid
-/// RRs:
+/// RRs.  Records for names outside of the `zone` the nameserver is
+/// authoritative for are out of bailiwick, and are always dropped: the
+/// nameserver has no business saying anything about them, and believing it
+/// would let it poison the cache.  Of the rest, keep:
 ///
 /// - RRs matching the query domain (or the name it ends up being
 ///   after following `CNAME`s) and type (or `CNAME`)
@@ -345,7 +713,7 @@ fn candidate_nameservers(
 /// - `NS` RRs for a superdomain of the query domain (if it matches
 ///   better than our current nameservers).
 ///
-/// - `A` RRs corresponding to a selected `NS` RR
+/// - `A` and `AAAA` RRs corresponding to a selected `NS` RR
 ///
 /// Then, decide whether:
 ///
@@ -365,17 +733,20 @@ fn candidate_nameservers(
 fn validate_nameserver_response(
     question: &Question,
     response: &Message,
-    current_match_count: usize,
+    zone: &DomainName,
 ) -> Option<NameserverResponse> {
-    if let Some((final_name, cname_map)) =
-        follow_cnames(&response.answers, &question.name, question.qtype)
-    {
+    let current_match_count = zone.labels.len();
+    let answers = in_bailiwick(&response.answers, zone);
+    let authority = in_bailiwick(&response.authority, zone);
+    let additional = in_bailiwick(&response.additional, zone);
+
+    if let Some((final_name, cname_map)) = follow_cnames(&answers, &question.name, question.qtype) {
         // get RRs matching the query name or the names it `CNAME`s to
 
-        let mut rrs_for_query = Vec::<ResourceRecord>::with_capacity(response.answers.len());
+        let mut rrs_for_query = Vec::<ResourceRecord>::with_capacity(answers.len());
         let mut seen_final_record = false;
         let mut all_unknown = true;
-        for an in &response.answers {
+        for an in &answers {
             if an.is_unknown() {
                 continue;
             }
@@ -417,9 +788,9 @@ fn validate_nameserver_response(
 
         let (match_name, ns_names) = {
             let ns_from_answers =
-                get_better_ns_names(&response.answers, &question.name, current_match_count);
+                get_better_ns_names(&answers, &question.name, current_match_count);
             let ns_from_authority =
-                get_better_ns_names(&response.authority, &question.name, current_match_count);
+                get_better_ns_names(&authority, &question.name, current_match_count);
             match (ns_from_answers, ns_from_authority) {
                 (Some((mn1, nss1)), Some((mn2, nss2))) => {
                     match mn1.labels.len().cmp(&mn2.labels.len()) {
@@ -446,7 +817,7 @@ fn validate_nameserver_response(
         // you never know, the upstream nameserver may have been kind enough to
         // give an A record along with each NS record, if we're lucky.
         let mut nameserver_rrs = Vec::<ResourceRecord>::with_capacity(ns_names.len() * 2);
-        for rr in &response.answers {
+        for rr in &answers {
             match &rr.rtype_with_data {
                 RecordTypeWithData::NS { nsdname } if ns_names.contains(nsdname) => {
                     nameserver_rrs.push(rr.clone());
@@ -460,7 +831,7 @@ fn validate_nameserver_response(
                 _ => (),
             }
         }
-        for rr in &response.authority {
+        for rr in &authority {
             match &rr.rtype_with_data {
                 RecordTypeWithData::NS { nsdname } if ns_names.contains(nsdname) => {
                     nameserver_rrs.push(rr.clone());
@@ -468,7 +839,7 @@ fn validate_nameserver_response(
                 _ => (),
             }
         }
-        for rr in &response.additional {
+        for rr in &additional {
             match &rr.rtype_with_data {
                 RecordTypeWithData::A { .. } if ns_names.contains(&rr.name) => {
                     nameserver_rrs.push(rr.clone());
@@ -491,6 +862,15 @@ fn validate_nameserver_response(
     }
 }
 
+/// The records which a nameserver authoritative for `zone` can be trusted
+/// about: those for names in the zone.
+fn in_bailiwick(rrs: &[ResourceRecord], zone: &DomainName) -> Vec<ResourceRecord> {
+    rrs.iter()
+        .filter(|rr| rr.name.is_subdomain_of(zone))
+        .cloned()
+        .collect()
+}
+
 /// Given a set of RRs and a domain name we're looking for, follow
 /// `CNAME`s in the response and return the final name (which is the
 /// name that will have the non-`CNAME` records associated with it).
@@ -629,18 +1009,24 @@ mod tests {
     fn candidate_nameservers_gets_all_matches() {
         let qdomain = domain("com.");
         assert_eq!(
-            Some(Nameservers {
+            Nameservers {
                 hostnames: vec![domain("ns1.example.com."), domain("ns2.example.com.")],
                 name: qdomain.clone(),
-            }),
+            },
             candidate_nameservers(
                 &mut Context::new(
                     RecursiveContextInner {
                         protocol_mode: ProtocolMode::PreferV4,
                         upstream_dns_port: 53,
+                        qname_minimisation: true,
+                        root_hints: &RootHints::default(),
+                        nameserver_stats: &NameserverStats::new(),
+                        network: NetworkOptions::default(),
                     },
                     &Zones::new(),
+                    &Allowlist::new(),
                     &cache_with_nameservers(&["com."]),
+                    Timeouts::default(),
                     10,
                 ),
                 &qdomain
@@ -651,18 +1037,24 @@ mod tests {
     #[test]
     fn candidate_nameservers_returns_longest_match() {
         assert_eq!(
-            Some(Nameservers {
+            Nameservers {
                 hostnames: vec![domain("ns1.example.com."), domain("ns2.example.com.")],
                 name: domain("example.com."),
-            }),
+            },
             candidate_nameservers(
                 &mut Context::new(
                     RecursiveContextInner {
                         protocol_mode: ProtocolMode::PreferV4,
                         upstream_dns_port: 53,
+                        qname_minimisation: true,
+                        root_hints: &RootHints::default(),
+                        nameserver_stats: &NameserverStats::new(),
+                        network: NetworkOptions::default(),
                     },
                     &Zones::new(),
+                    &Allowlist::new(),
                     &cache_with_nameservers(&["example.com.", "com."]),
+                    Timeouts::default(),
                     10,
                 ),
                 &domain("www.example.com.")
@@ -671,17 +1063,26 @@ mod tests {
     }
 
     #[test]
-    fn candidate_nameservers_returns_none_on_failure() {
+    fn candidate_nameservers_falls_back_to_root_hints() {
         assert_eq!(
-            None,
+            Nameservers {
+                hostnames: RootHints::default().nameservers(),
+                name: DomainName::root_domain(),
+            },
             candidate_nameservers(
                 &mut Context::new(
                     RecursiveContextInner {
                         protocol_mode: ProtocolMode::PreferV4,
                         upstream_dns_port: 53,
+                        qname_minimisation: true,
+                        root_hints: &RootHints::default(),
+                        nameserver_stats: &NameserverStats::new(),
+                        network: NetworkOptions::default(),
                     },
                     &Zones::new(),
+                    &Allowlist::new(),
                     &cache_with_nameservers(&["com."]),
+                    Timeouts::default(),
                     10,
                 ),
                 &domain("net.")
@@ -693,17 +1094,21 @@ mod tests {
     fn validate_nameserver_response_returns_answer() {
         let (request, response) = nameserver_response(
             "www.example.com.",
-            &[a_record("www.example.com.", Ipv4Addr::new(127, 0, 0, 1))],
+            &[a_record("www.example.com.", Ipv4Addr::LOCALHOST)],
             &[],
             &[],
         );
 
         assert_eq!(
             Some(NameserverResponse::Answer {
-                rrs: vec![a_record("www.example.com.", Ipv4Addr::new(127, 0, 0, 1))],
+                rrs: vec![a_record("www.example.com.", Ipv4Addr::LOCALHOST)],
                 soa_rr: None,
             }),
-            validate_nameserver_response(&request.questions[0], &response, 0)
+            validate_nameserver_response(
+                &request.questions[0],
+                &response,
+                &DomainName::root_domain()
+            )
         );
     }
 
@@ -730,7 +1135,11 @@ mod tests {
                 rrs: vec![a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1))],
                 soa_rr: None,
             }),
-            validate_nameserver_response(&request.questions[0], &response, 0)
+            validate_nameserver_response(
+                &request.questions[0],
+                &response,
+                &DomainName::root_domain()
+            )
         );
     }
 
@@ -754,7 +1163,11 @@ mod tests {
 
         assert_eq!(
             None,
-            validate_nameserver_response(&request.questions[0], &response, 0)
+            validate_nameserver_response(
+                &request.questions[0],
+                &response,
+                &DomainName::root_domain()
+            )
         );
     }
 
@@ -764,7 +1177,7 @@ mod tests {
             "www.example.com.",
             &[
                 cname_record("www.example.com.", "cname-target.example.com."),
-                a_record("cname-target.example.com.", Ipv4Addr::new(127, 0, 0, 1)),
+                a_record("cname-target.example.com.", Ipv4Addr::LOCALHOST),
             ],
             &[],
             &[],
@@ -774,11 +1187,15 @@ mod tests {
             Some(NameserverResponse::Answer {
                 rrs: vec![
                     cname_record("www.example.com.", "cname-target.example.com."),
-                    a_record("cname-target.example.com.", Ipv4Addr::new(127, 0, 0, 1))
+                    a_record("cname-target.example.com.", Ipv4Addr::LOCALHOST)
                 ],
                 soa_rr: None,
             }),
-            validate_nameserver_response(&request.questions[0], &response, 0)
+            validate_nameserver_response(
+                &request.questions[0],
+                &response,
+                &DomainName::root_domain()
+            )
         );
     }
 
@@ -802,7 +1219,11 @@ mod tests {
                 )],
                 cname: domain("cname-target.example.com."),
             }),
-            validate_nameserver_response(&request.questions[0], &response, 0)
+            validate_nameserver_response(
+                &request.questions[0],
+                &response,
+                &DomainName::root_domain()
+            )
         );
     }
 
@@ -815,7 +1236,11 @@ mod tests {
             &[ns_record("example.com.", "ns-ar.example.net.")],
         );
 
-        match validate_nameserver_response(&request.questions[0], &response, 0) {
+        match validate_nameserver_response(
+            &request.questions[0],
+            &response,
+            &DomainName::root_domain(),
+        ) {
             Some(NameserverResponse::Delegation {
                 rrs: mut actual_rrs,
                 delegation: mut actual_delegation,
@@ -858,7 +1283,7 @@ mod tests {
             validate_nameserver_response(
                 &request.questions[0],
                 &response,
-                domain("subdomain.example.com.").labels.len()
+                &domain("subdomain.example.com.")
             )
         );
     }
@@ -895,7 +1320,11 @@ mod tests {
                     name: domain("subdomain.example.com."),
                 },
             }),
-            validate_nameserver_response(&request.questions[0], &response1, 0)
+            validate_nameserver_response(
+                &request.questions[0],
+                &response1,
+                &DomainName::root_domain()
+            )
         );
 
         assert_eq!(
@@ -909,7 +1338,11 @@ mod tests {
                     name: domain("subdomain.example.com."),
                 },
             }),
-            validate_nameserver_response(&request.questions[0], &response2, 0)
+            validate_nameserver_response(
+                &request.questions[0],
+                &response2,
+                &DomainName::root_domain()
+            )
         );
     }
 
@@ -933,7 +1366,11 @@ mod tests {
             ],
         );
 
-        match validate_nameserver_response(&request.questions[0], &response, 0) {
+        match validate_nameserver_response(
+            &request.questions[0],
+            &response,
+            &DomainName::root_domain(),
+        ) {
             Some(NameserverResponse::Delegation {
                 rrs: mut actual_rrs,
                 delegation: _,
@@ -973,11 +1410,19 @@ mod tests {
             ttl: 300,
         };
 
-        let (request, response) =
-            nameserver_response("www.example.com.", &[], &[soa_record.clone()], &[]);
+        let (request, response) = nameserver_response(
+            "www.example.com.",
+            &[],
+            std::slice::from_ref(&soa_record),
+            &[],
+        );
 
         assert_eq!(
-            validate_nameserver_response(&request.questions[0], &response, 0),
+            validate_nameserver_response(
+                &request.questions[0],
+                &response,
+                &DomainName::root_domain()
+            ),
             Some(NameserverResponse::Answer {
                 rrs: Vec::new(),
                 soa_rr: Some(soa_record)
@@ -1005,10 +1450,8 @@ mod tests {
         let (request, response) = nameserver_response("www.example.com.", &[], &[soa_record], &[]);
 
         // pretend we're querying the nameserver for example.com
-        let current_match_count = domain("example.com.").labels.len();
-
         assert_eq!(
-            validate_nameserver_response(&request.questions[0], &response, current_match_count),
+            validate_nameserver_response(&request.questions[0], &response, &domain("example.com.")),
             None,
         );
     }
@@ -1033,11 +1476,92 @@ mod tests {
         let (request, response) = nameserver_response("www.example.com.", &[], &[soa_record], &[]);
 
         assert_eq!(
-            validate_nameserver_response(&request.questions[0], &response, 0),
+            validate_nameserver_response(
+                &request.questions[0],
+                &response,
+                &DomainName::root_domain()
+            ),
             None,
         );
     }
 
+    #[test]
+    fn validate_nameserver_response_does_not_follow_cnames_out_of_bailiwick() {
+        let (request, response) = nameserver_response(
+            "www.example.com.",
+            &[
+                cname_record("www.example.com.", "www.example.net."),
+                a_record("www.example.net.", Ipv4Addr::new(1, 1, 1, 1)),
+            ],
+            &[],
+            &[],
+        );
+
+        assert_eq!(
+            Some(NameserverResponse::CNAME {
+                rrs: vec![cname_record("www.example.com.", "www.example.net.")],
+                cname: domain("www.example.net."),
+            }),
+            validate_nameserver_response(&request.questions[0], &response, &domain("example.com."))
+        );
+    }
+
+    #[test]
+    fn validate_nameserver_response_drops_out_of_bailiwick_glue() {
+        let (request, response) = nameserver_response(
+            "www.example.com.",
+            &[a_record("ns.example.net.", Ipv4Addr::new(1, 1, 1, 1))],
+            &[
+                ns_record("example.com.", "ns.example.com."),
+                ns_record("example.com.", "ns.example.net."),
+            ],
+            &[
+                a_record("ns.example.com.", Ipv4Addr::new(2, 2, 2, 2)),
+                a_record("ns.example.net.", Ipv4Addr::new(3, 3, 3, 3)),
+            ],
+        );
+
+        match validate_nameserver_response(&request.questions[0], &response, &domain("com.")) {
+            Some(NameserverResponse::Delegation {
+                rrs: mut actual_rrs,
+                delegation: _,
+            }) => {
+                let mut expected_rrs = vec![
+                    ns_record("example.com.", "ns.example.com."),
+                    ns_record("example.com.", "ns.example.net."),
+                    a_record("ns.example.com.", Ipv4Addr::new(2, 2, 2, 2)),
+                ];
+
+                expected_rrs.sort();
+                actual_rrs.sort();
+
+                assert_eq!(expected_rrs, actual_rrs);
+            }
+            actual => panic!("Expected delegation, got {actual:?}"),
+        }
+    }
+
+    #[test]
+    fn validate_nameserver_response_drops_out_of_bailiwick_answers() {
+        let (request, response) = nameserver_response(
+            "www.example.com.",
+            &[
+                a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+                a_record("www.example.net.", Ipv4Addr::new(2, 2, 2, 2)),
+            ],
+            &[ns_record("example.net.", "ns.attacker.example.")],
+            &[a_record("ns.attacker.example.", Ipv4Addr::new(3, 3, 3, 3))],
+        );
+
+        assert_eq!(
+            Some(NameserverResponse::Answer {
+                rrs: vec![a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1))],
+                soa_rr: None,
+            }),
+            validate_nameserver_response(&request.questions[0], &response, &domain("example.com."))
+        );
+    }
+
     #[test]
     fn follow_cnames_empty() {
         assert_eq!(
@@ -1072,7 +1596,7 @@ mod tests {
 
     #[test]
     fn follow_cnames_no_cname() {
-        let rr_a = a_record("www.example.com.", Ipv4Addr::new(127, 0, 0, 1));
+        let rr_a = a_record("www.example.com.", Ipv4Addr::LOCALHOST);
         assert_eq!(
             Some((domain("www.example.com."), HashMap::new())),
             follow_cnames(&[rr_a], &domain("www.example.com."), QueryType::Wildcard)
@@ -1083,7 +1607,7 @@ mod tests {
     fn follow_cnames_chain() {
         let rr_cname1 = cname_record("www.example.com.", "www2.example.com.");
         let rr_cname2 = cname_record("www2.example.com.", "www3.example.com.");
-        let rr_a = a_record("www3.example.com.", Ipv4Addr::new(127, 0, 0, 1));
+        let rr_a = a_record("www3.example.com.", Ipv4Addr::LOCALHOST);
 
         let mut expected_map = HashMap::new();
         expected_map.insert(domain("www.example.com."), domain("www2.example.com."));
@@ -1162,7 +1686,7 @@ mod tests {
 
     #[test]
     fn get_ip_domain_mismatch() {
-        let a_rr = a_record("www.example.net.", Ipv4Addr::new(127, 0, 0, 1));
+        let a_rr = a_record("www.example.net.", Ipv4Addr::LOCALHOST);
         assert_eq!(
             None,
             get_ip(&[a_rr], &domain("www.example.com."), RecordType::A)
@@ -1171,7 +1695,7 @@ mod tests {
 
     #[test]
     fn get_ip_type_mismatch() {
-        let aaaa_rr = aaaa_record("www.example.com.", Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1));
+        let aaaa_rr = aaaa_record("www.example.com.", Ipv6Addr::LOCALHOST);
         assert_eq!(
             None,
             get_ip(&[aaaa_rr], &domain("www.example.com."), RecordType::A,)
@@ -1180,15 +1704,15 @@ mod tests {
 
     #[test]
     fn get_ip_domain_and_type_match() {
-        let a_rr = a_record("www.example.com.", Ipv4Addr::new(127, 0, 0, 1));
-        let aaaa_rr = aaaa_record("www.example.com.", Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1));
+        let a_rr = a_record("www.example.com.", Ipv4Addr::LOCALHOST);
+        let aaaa_rr = aaaa_record("www.example.com.", Ipv6Addr::LOCALHOST);
         let rrs = [a_rr, aaaa_rr];
         assert_eq!(
-            Some(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))),
+            Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
             get_ip(&rrs, &domain("www.example.com."), RecordType::A)
         );
         assert_eq!(
-            Some(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))),
+            Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
             get_ip(&rrs, &domain("www.example.com."), RecordType::AAAA)
         );
     }
@@ -1196,9 +1720,9 @@ mod tests {
     #[test]
     fn get_ip_cname_match() {
         let cname_rr = cname_record("www.example.com.", "www.example.net.");
-        let a_rr = a_record("www.example.net.", Ipv4Addr::new(127, 0, 0, 1));
+        let a_rr = a_record("www.example.net.", Ipv4Addr::LOCALHOST);
         assert_eq!(
-            Some(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))),
+            Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
             get_ip(
                 &[cname_rr, a_rr],
                 &domain("www.example.com."),
@@ -1207,12 +1731,104 @@ mod tests {
         );
     }
 
+    #[test]
+    fn minimised_question_reveals_labels() {
+        let question = Question {
+            name: domain("www.example.com."),
+            qtype: QueryType::Record(RecordType::MX),
+            qclass: QueryClass::Record(RecordClass::IN),
+        };
+
+        assert_eq!(
+            Some(Question {
+                name: domain("com."),
+                qtype: QueryType::Record(RecordType::A),
+                qclass: QueryClass::Record(RecordClass::IN),
+            }),
+            minimised_question(&question, 2)
+        );
+        assert_eq!(
+            Some(Question {
+                name: domain("example.com."),
+                qtype: QueryType::Record(RecordType::A),
+                qclass: QueryClass::Record(RecordClass::IN),
+            }),
+            minimised_question(&question, 3)
+        );
+    }
+
+    #[test]
+    fn minimised_question_rejects_too_many_labels() {
+        let question = Question {
+            name: domain("www.example.com."),
+            qtype: QueryType::Record(RecordType::A),
+            qclass: QueryClass::Record(RecordClass::IN),
+        };
+
+        assert_eq!(None, minimised_question(&question, 5));
+    }
+
+    #[test]
+    fn sort_candidates_puts_fastest_last_and_unknown_first() {
+        let cache = SharedCache::new();
+        cache.insert(&a_record("fast.example.com.", Ipv4Addr::new(192, 0, 2, 1)));
+        cache.insert(&a_record("slow.example.com.", Ipv4Addr::new(192, 0, 2, 2)));
+        cache.insert(&a_record("new.example.com.", Ipv4Addr::new(192, 0, 2, 3)));
+
+        let nameserver_stats = NameserverStats::new();
+        nameserver_stats.record_success(
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            Duration::from_millis(10),
+        );
+        nameserver_stats.record_success(
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)),
+            Duration::from_millis(500),
+        );
+
+        let mut hostnames = vec![
+            domain("fast.example.com."),
+            domain("new.example.com."),
+            domain("uncached.example.com."),
+            domain("slow.example.com."),
+        ];
+        sort_candidates(
+            &Context::new(
+                RecursiveContextInner {
+                    protocol_mode: ProtocolMode::PreferV4,
+                    upstream_dns_port: 53,
+                    qname_minimisation: true,
+                    root_hints: &RootHints::default(),
+                    nameserver_stats: &nameserver_stats,
+                    network: NetworkOptions::default(),
+                },
+                &Zones::new(),
+                &Allowlist::new(),
+                &cache,
+                Timeouts::default(),
+                10,
+            ),
+            &mut hostnames,
+        );
+
+        assert_eq!(
+            vec![
+                domain("uncached.example.com."),
+                domain("slow.example.com."),
+                domain("new.example.com."),
+                domain("fast.example.com."),
+            ],
+            hostnames
+        );
+    }
+
     fn cache_with_nameservers(names: &[&str]) -> SharedCache {
         let cache = SharedCache::new();
 
         for name in names {
-            cache.insert(&ns_record(name, "ns1.example.com."));
-            cache.insert(&ns_record(name, "ns2.example.com."));
+            cache.insert_all(&[
+                ns_record(name, "ns1.example.com."),
+                ns_record(name, "ns2.example.com."),
+            ]);
         }
 
         cache
@@ -0,0 +1,630 @@
+use std::collections::HashMap;
+use std::panic;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+use dns_types::protocol::types::{ClientSubnet, Question};
+use dns_types::zones::types::Zones;
+
+use crate::cache::SharedCache;
+use crate::context::Context;
+use crate::events::{Event, Observer};
+use crate::forwarding::{resolve_forwarding, ForwardingContextInner};
+use crate::local::resolve_local;
+use crate::metrics::Metrics;
+use crate::nameserver_stats::NameserverStats;
+use crate::recursive::{resolve_recursive, RecursiveContextInner};
+use crate::root_hints::RootHints;
+use crate::util::types::{
+    Allowlist, FallbackPolicy, ForwardingRule, ForwardingRules, ForwardingStrategy, NetworkOptions,
+    ProtocolMode, RecursionScope, ResolutionError, ResolvedRecord, Timeouts, Upstream,
+    UpstreamSecurityRule, ZonePrecedenceRules,
+};
+use crate::RECURSION_LIMIT;
+
+/// A DNS resolver, holding all of the configuration and state needed to
+/// resolve questions.  This is the easiest way to use the resolver from
+/// another program:
+///
+/// ```no_run
+/// # async fn example() {
+/// use dns_resolver::resolver::Resolver;
+/// use dns_types::protocol::types::*;
+///
+/// let resolver = Resolver::builder()
+///     .forward("1.1.1.1:53".parse::<std::net::SocketAddr>().unwrap())
+///     .build();
+///
+/// let question = Question {
+///     name: DomainName::from_dotted_string("www.example.com.").unwrap(),
+///     qtype: QueryType::Record(RecordType::A),
+///     qclass: QueryClass::Record(RecordClass::IN),
+/// };
+/// let answer = resolver.lookup(&question).await;
+/// # }
+/// ```
+///
+/// Cloning a `Resolver` gives one which shares the cache and nameserver stats,
+/// but has its own copy of everything else.
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    is_recursive: bool,
+    protocol_mode: ProtocolMode,
+    upstream_dns_port: u16,
+    network: NetworkOptions,
+    qname_minimisation: bool,
+    timeouts: Timeouts,
+    root_hints: RootHints,
+    nameserver_stats: NameserverStats,
+    forwarding_rules: ForwardingRules,
+    recursion_scope: RecursionScope,
+    allowlist: Allowlist,
+    zone_precedence: ZonePrecedenceRules,
+    zones: Zones,
+    cache: SharedCache,
+}
+
+impl Resolver {
+    /// Start building a resolver.  See `ResolverBuilder` for the defaults.
+    pub fn builder() -> ResolverBuilder {
+        ResolverBuilder::default()
+    }
+
+    /// Resolve a question using the resolver's own zones: see `resolve`.
+    ///
+    /// # Errors
+    ///
+    /// See `ResolutionError`.
+    pub async fn lookup(&self, question: &Question) -> Result<ResolvedRecord, ResolutionError> {
+        self.lookup_with_metrics(question, None).await.1
+    }
+
+    /// Resolve a question, returning the metrics as well as the answer, and
+    /// sending the events of resolution to an observer if there is one.
+    pub async fn lookup_with_metrics(
+        &self,
+        question: &Question,
+        observer: Option<&dyn Observer>,
+    ) -> (Metrics, Result<ResolvedRecord, ResolutionError>) {
+        self.resolve(&self.zones, true, None, observer, question)
+            .await
+    }
+
+    /// Resolve a question using the standard DNS algorithms, answering from
+    /// the given zones rather than the resolver's own.  This is for callers,
+    /// like a server, which swap in new zones without building a new
+    /// resolver.
+    ///
+    /// If the resolver is recursive, recursion is desired, and the question is
+    /// in the recursion scope, the forwarding rules are consulted to decide
+    /// whether the question should be forwarded to an upstream nameserver or
+    /// resolved recursively.  Otherwise it is answered from local zones and
+    /// the cache only.
+    ///
+    /// Answers from local zones which would block a domain in the allowlist
+    /// are ignored.  Answers from non-authoritative local zones are combined
+    /// with the cache as the zone precedence rules say.
+    ///
+    /// Each query to an upstream nameserver is abandoned after the query
+    /// timeout, and resolution is abandoned after the resolution timeout.
+    /// Queries are sent as the network options say: from the outbound address
+    /// for the nameserver's address family, if there is one, and through the
+    /// proxy, if there is one.  How quickly each upstream nameserver responds
+    /// is recorded in the nameserver stats, so that recursive resolution can
+    /// prefer the fastest.
+    ///
+    /// If there is a client subnet, it is sent to upstream nameservers when
+    /// forwarding, but not when resolving recursively.
+    ///
+    /// If there is an observer, it is sent the events of resolution as they
+    /// happen.
+    pub async fn resolve(
+        &self,
+        zones: &Zones,
+        recursion_desired: bool,
+        client_subnet: Option<ClientSubnet>,
+        observer: Option<&dyn Observer>,
+        question: &Question,
+    ) -> (Metrics, Result<ResolvedRecord, ResolutionError>) {
+        let is_recursive =
+            self.is_recursive && recursion_desired && self.recursion_scope.allows(&question.name);
+
+        match (is_recursive, self.forwarding_rules.get(&question.name)) {
+            (true, Some(upstreams)) => {
+                let mut context = Context::new(
+                    ForwardingContextInner {
+                        upstreams: upstreams.to_vec(),
+                        strategy: self.forwarding_rules.strategy,
+                        fallback: self.forwarding_rules.fallback.clone(),
+                        security: self.forwarding_rules.security.clone(),
+                        network: self.network,
+                        client_subnet,
+                    },
+                    zones,
+                    &self.allowlist,
+                    &self.cache,
+                    self.timeouts,
+                    RECURSION_LIMIT,
+                )
+                .with_zone_precedence(&self.zone_precedence)
+                .with_observer(observer);
+                let result = resolve_forwarding(&mut context, question)
+                    .instrument(tracing::error_span!("resolve_forwarding", ?upstreams, %question))
+                    .await;
+                observe_error(&context, &result);
+                (context.done(), result)
+            }
+            (true, None) => {
+                let mut context = Context::new(
+                    RecursiveContextInner {
+                        protocol_mode: self.protocol_mode,
+                        upstream_dns_port: self.upstream_dns_port,
+                        qname_minimisation: self.qname_minimisation,
+                        root_hints: &self.root_hints,
+                        nameserver_stats: &self.nameserver_stats,
+                        network: self.network,
+                    },
+                    zones,
+                    &self.allowlist,
+                    &self.cache,
+                    self.timeouts,
+                    RECURSION_LIMIT,
+                )
+                .with_zone_precedence(&self.zone_precedence)
+                .with_observer(observer);
+                let result = resolve_recursive(&mut context, question)
+                    .instrument(tracing::error_span!("resolve_recursive", %question))
+                    .await;
+                observe_error(&context, &result);
+                (context.done(), result)
+            }
+            (false, _) => {
+                let mut context = Context::new(
+                    (),
+                    zones,
+                    &self.allowlist,
+                    &self.cache,
+                    self.timeouts,
+                    RECURSION_LIMIT,
+                )
+                .with_zone_precedence(&self.zone_precedence)
+                .with_observer(observer);
+                let result = resolve_local(&mut context, question)
+                    .map(ResolvedRecord::from)
+                    .map_err(|error| match error {
+                        ResolutionError::DeadEnd { question } => {
+                            ResolutionError::RecursionNotAllowed { question }
+                        }
+                        _ => error,
+                    });
+                observe_error(&context, &result);
+                (context.done(), result)
+            }
+        }
+    }
+
+    /// Resolve a batch of questions concurrently, resolving at most
+    /// `parallelism` at once.  The answers are returned in the same order as
+    /// the questions.
+    ///
+    /// A question which appears more than once is only resolved once.  The
+    /// questions share the cache, so lookups which need the same records (like
+    /// the nameservers for a common parent domain) only need to fetch them
+    /// once, though if two questions are being resolved at the same time both
+    /// may need to query upstream nameservers before either gets cached.
+    pub async fn lookup_many(
+        &self,
+        questions: &[Question],
+        parallelism: usize,
+    ) -> Vec<Result<ResolvedRecord, ResolutionError>> {
+        let mut unique = Vec::with_capacity(questions.len());
+        let mut indices = HashMap::with_capacity(questions.len());
+        let positions: Vec<usize> = questions
+            .iter()
+            .map(|question| {
+                *indices.entry(question).or_insert_with(|| {
+                    unique.push(question.clone());
+                    unique.len() - 1
+                })
+            })
+            .collect();
+
+        let resolver = Arc::new(self.clone());
+        let mut results = vec![None; unique.len()];
+        let mut remaining = unique.into_iter().enumerate();
+        let mut set = JoinSet::new();
+        loop {
+            while set.len() < parallelism.max(1) {
+                let Some((i, question)) = remaining.next() else {
+                    break;
+                };
+                let resolver = resolver.clone();
+                set.spawn(async move { (i, resolver.lookup(&question).await) });
+            }
+
+            match set.join_next().await {
+                Some(Ok((i, result))) => results[i] = Some(result),
+                Some(Err(error)) => panic::resume_unwind(error.into_panic()),
+                None => break,
+            }
+        }
+
+        positions
+            .into_iter()
+            .map(|i| results[i].clone().unwrap_or(Err(ResolutionError::Timeout)))
+            .collect()
+    }
+
+    /// The cache, which should be pruned from time to time.
+    pub fn cache(&self) -> &SharedCache {
+        &self.cache
+    }
+
+    /// The nameserver stats.
+    pub fn nameserver_stats(&self) -> &NameserverStats {
+        &self.nameserver_stats
+    }
+
+    /// Whether the resolver performs recursive or forwarding resolution at
+    /// all.
+    pub fn is_recursive(&self) -> bool {
+        self.is_recursive
+    }
+
+    pub fn protocol_mode(&self) -> ProtocolMode {
+        self.protocol_mode
+    }
+
+    pub fn upstream_dns_port(&self) -> u16 {
+        self.upstream_dns_port
+    }
+
+    pub fn network(&self) -> NetworkOptions {
+        self.network
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    pub fn root_hints(&self) -> &RootHints {
+        &self.root_hints
+    }
+
+    pub fn allowlist(&self) -> &Allowlist {
+        &self.allowlist
+    }
+}
+
+/// Send an event to the observer if resolution failed.
+fn observe_error<CT>(context: &Context<'_, CT>, result: &Result<ResolvedRecord, ResolutionError>) {
+    if let Err(error) = result {
+        context.observe(|| Event::Error {
+            error: error.clone(),
+        });
+    }
+}
+
+/// Builds a `Resolver`.  By default the resolver:
+///
+/// - resolves questions recursively, starting from the built-in root hints
+/// - queries upstream nameservers on port 53 over IPv4 only
+/// - uses QNAME minimisation
+/// - uses the default timeouts
+/// - has no zones, and an empty cache with the default size
+/// - merges answers from non-authoritative zones with the cache
+#[derive(Debug, Clone)]
+pub struct ResolverBuilder {
+    resolver: Resolver,
+    forward_upstreams: Vec<Upstream>,
+    forward_strategy: ForwardingStrategy,
+    forward_fallback: FallbackPolicy,
+    forward_rules: Vec<ForwardingRule>,
+    upstream_security: Vec<UpstreamSecurityRule>,
+}
+
+impl Default for ResolverBuilder {
+    fn default() -> Self {
+        Self {
+            resolver: Resolver {
+                is_recursive: true,
+                protocol_mode: ProtocolMode::OnlyV4,
+                upstream_dns_port: 53,
+                network: NetworkOptions::default(),
+                qname_minimisation: true,
+                timeouts: Timeouts::default(),
+                root_hints: RootHints::default(),
+                nameserver_stats: NameserverStats::new(),
+                forwarding_rules: ForwardingRules::default(),
+                recursion_scope: RecursionScope::new(),
+                allowlist: Allowlist::new(),
+                zone_precedence: ZonePrecedenceRules::default(),
+                zones: Zones::new(),
+                cache: SharedCache::new(),
+            },
+            forward_upstreams: Vec::new(),
+            forward_strategy: ForwardingStrategy::default(),
+            forward_fallback: FallbackPolicy::default(),
+            forward_rules: Vec::new(),
+            upstream_security: Vec::new(),
+        }
+    }
+}
+
+impl ResolverBuilder {
+    /// Whether to perform recursive or forwarding resolution at all: if not,
+    /// questions are only answered from the zones and the cache.
+    pub fn recursive(mut self, is_recursive: bool) -> Self {
+        self.resolver.is_recursive = is_recursive;
+        self
+    }
+
+    /// How to choose between IPv4 and IPv6 when resolving recursively.
+    pub fn protocol_mode(mut self, protocol_mode: ProtocolMode) -> Self {
+        self.resolver.protocol_mode = protocol_mode;
+        self
+    }
+
+    /// Which port to query upstream nameservers on when resolving
+    /// recursively.
+    pub fn upstream_dns_port(mut self, upstream_dns_port: u16) -> Self {
+        self.resolver.upstream_dns_port = upstream_dns_port;
+        self
+    }
+
+    /// Where to send queries to upstream nameservers from, and whether to use
+    /// a proxy.
+    pub fn network(mut self, network: NetworkOptions) -> Self {
+        self.resolver.network = network;
+        self
+    }
+
+    /// Whether to use QNAME minimisation when resolving recursively.
+    pub fn qname_minimisation(mut self, qname_minimisation: bool) -> Self {
+        self.resolver.qname_minimisation = qname_minimisation;
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.resolver.timeouts = timeouts;
+        self
+    }
+
+    /// The nameservers to start recursive resolution from.
+    pub fn root_hints(mut self, root_hints: RootHints) -> Self {
+        self.resolver.root_hints = root_hints;
+        self
+    }
+
+    /// Share nameserver stats with another resolver.
+    pub fn nameserver_stats(mut self, nameserver_stats: NameserverStats) -> Self {
+        self.resolver.nameserver_stats = nameserver_stats;
+        self
+    }
+
+    /// Forward questions to this nameserver rather than resolving them
+    /// recursively.  This can be called more than once.
+    pub fn forward(mut self, upstream: impl Into<Upstream>) -> Self {
+        self.forward_upstreams.push(upstream.into());
+        self
+    }
+
+    /// How to use multiple forwarding nameservers.
+    pub fn forward_strategy(mut self, strategy: ForwardingStrategy) -> Self {
+        self.forward_strategy = strategy;
+        self
+    }
+
+    /// Which transports to try, in order, when querying forwarding
+    /// nameservers.
+    pub fn forward_fallback(mut self, fallback: FallbackPolicy) -> Self {
+        self.forward_fallback = fallback;
+        self
+    }
+
+    /// Forward questions for a domain to a specific nameserver, overriding
+    /// `forward`.  This can be called more than once.
+    pub fn forward_rule(mut self, rule: ForwardingRule) -> Self {
+        self.forward_rules.push(rule);
+        self
+    }
+
+    /// Whether to require or only prefer encryption when querying a
+    /// forwarding nameserver, and how to authenticate it.  This can be called
+    /// more than once.
+    pub fn upstream_security(mut self, rule: UpstreamSecurityRule) -> Self {
+        self.upstream_security.push(rule);
+        self
+    }
+
+    /// Which domains recursive or forwarding resolution may be used for.
+    pub fn recursion_scope(mut self, recursion_scope: RecursionScope) -> Self {
+        self.resolver.recursion_scope = recursion_scope;
+        self
+    }
+
+    /// Domains which zones may not block.
+    pub fn allowlist(mut self, allowlist: Allowlist) -> Self {
+        self.resolver.allowlist = allowlist;
+        self
+    }
+
+    /// How records from non-authoritative local zones are combined with
+    /// records from the cache and upstream nameservers.
+    pub fn zone_precedence(mut self, zone_precedence: ZonePrecedenceRules) -> Self {
+        self.resolver.zone_precedence = zone_precedence;
+        self
+    }
+
+    /// Local zones, which are used in preference to upstream nameservers.
+    pub fn zones(mut self, zones: Zones) -> Self {
+        self.resolver.zones = zones;
+        self
+    }
+
+    /// The cache, which may be shared with another resolver.
+    pub fn cache(mut self, cache: SharedCache) -> Self {
+        self.resolver.cache = cache;
+        self
+    }
+
+    pub fn build(self) -> Resolver {
+        let mut resolver = self.resolver;
+        resolver.forwarding_rules =
+            ForwardingRules::new(self.forward_upstreams, self.forward_strategy);
+        resolver.forwarding_rules.fallback = self.forward_fallback;
+        for rule in self.forward_rules {
+            resolver.forwarding_rules.insert(rule);
+        }
+        for rule in self.upstream_security {
+            resolver.forwarding_rules.security.insert(rule);
+        }
+        resolver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dns_types::protocol::types::test_util::*;
+    use dns_types::zones::types::*;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+
+    #[test]
+    fn build_installs_forwarding_rules() {
+        let default_address: SocketAddr = "1.1.1.1:53".parse().unwrap();
+        let rule_address: SocketAddr = "2.2.2.2:53".parse().unwrap();
+
+        let resolver = Resolver::builder()
+            .forward(default_address)
+            .forward_rule(ForwardingRule {
+                domain: domain("example.com."),
+                address: Some(rule_address),
+            })
+            .build();
+
+        assert_eq!(
+            Some(&[Upstream::from(default_address)][..]),
+            resolver.forwarding_rules.get(&domain("www.example.net."))
+        );
+        assert_eq!(
+            Some(&[Upstream::from(rule_address)][..]),
+            resolver.forwarding_rules.get(&domain("www.example.com."))
+        );
+    }
+
+    #[test]
+    fn lookup_answers_from_zones() {
+        let answer = runtime().block_on(zones_resolver().lookup(&question("www.example.com.")));
+
+        assert_eq!(
+            Ok(vec![a_record(
+                "www.example.com.",
+                Ipv4Addr::new(1, 1, 1, 1)
+            )]),
+            answer.map(ResolvedRecord::rrs)
+        );
+    }
+
+    #[test]
+    fn resolve_answers_from_given_zones() {
+        let resolver = Resolver::builder().recursive(false).build();
+        let zones = zones_resolver().zones;
+
+        let (_, answer) = runtime().block_on(resolver.resolve(
+            &zones,
+            true,
+            None,
+            None,
+            &question("mail.example.com."),
+        ));
+
+        assert_eq!(
+            Ok(vec![a_record(
+                "mail.example.com.",
+                Ipv4Addr::new(2, 2, 2, 2)
+            )]),
+            answer.map(ResolvedRecord::rrs)
+        );
+    }
+
+    #[test]
+    fn resolve_without_recursion_desired_does_not_forward() {
+        let resolver = Resolver::builder()
+            .forward("192.0.2.1:53".parse::<SocketAddr>().unwrap())
+            .build();
+
+        let (_, answer) = runtime().block_on(resolver.resolve(
+            &Zones::new(),
+            false,
+            None,
+            None,
+            &question("www.example.com."),
+        ));
+
+        assert!(matches!(
+            answer,
+            Err(ResolutionError::RecursionNotAllowed { .. })
+        ));
+    }
+
+    #[test]
+    fn lookup_many_answers_in_order() {
+        let questions = [
+            question("www.example.com."),
+            question("missing.example.com."),
+            question("mail.example.com."),
+            question("www.example.com."),
+        ];
+
+        let answers = runtime().block_on(zones_resolver().lookup_many(&questions, 2));
+
+        assert_eq!(
+            vec![
+                Ok(vec![a_record(
+                    "www.example.com.",
+                    Ipv4Addr::new(1, 1, 1, 1)
+                )]),
+                Ok(Vec::new()),
+                Ok(vec![a_record(
+                    "mail.example.com.",
+                    Ipv4Addr::new(2, 2, 2, 2)
+                )]),
+                Ok(vec![a_record(
+                    "www.example.com.",
+                    Ipv4Addr::new(1, 1, 1, 1)
+                )]),
+            ],
+            answers
+                .into_iter()
+                .map(|answer| answer.map(ResolvedRecord::rrs))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    fn zones_resolver() -> Resolver {
+        let mut zones = Zones::new();
+        zones.insert(
+            Zone::deserialise(
+                r"
+$ORIGIN example.com.
+@   IN SOA mname rname 1 30 30 30 30
+www  300 IN A 1.1.1.1
+mail 300 IN A 2.2.2.2
+",
+            )
+            .unwrap(),
+        );
+
+        Resolver::builder().recursive(false).zones(zones).build()
+    }
+}
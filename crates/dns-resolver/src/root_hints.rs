@@ -0,0 +1,297 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tracing::Instrument;
+
+use dns_types::protocol::types::*;
+use dns_types::zones::types::Zone;
+
+use crate::cache::SharedCache;
+use crate::util::nameserver::query_nameserver;
+use crate::util::types::{FallbackPolicy, NetworkOptions, ProtocolMode, Upstream};
+
+/// The root hints file from IANA, used if no other root hints are given.
+pub const DEFAULT_ROOT_HINTS: &str = include_str!("../../../config/root.hints");
+
+/// The root hints: the `NS` records for the root domain, and the `A` and `AAAA`
+/// records for those nameservers.  Recursive resolution starts from these if
+/// there are no better nameservers in the cache or the zones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootHints {
+    /// Guaranteed to have at least one `NS` record.
+    rrs: Vec<ResourceRecord>,
+}
+
+/// An error parsing root hints.
+#[derive(Debug)]
+pub enum Error {
+    /// The hints are not a valid zone file.
+    Zone(dns_types::zones::deserialise::Error),
+    /// The hints do not have any `NS` records for the root domain.
+    NoNameservers,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Zone(error) => write!(f, "{error}"),
+            Error::NoNameservers => write!(f, "no NS records for the root domain"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl RootHints {
+    /// Parse root hints from a zone file, which is normally non-authoritative
+    /// (with no SOA record).  Only the `NS` records for the root domain, and
+    /// the `A` and `AAAA` records for those nameservers, are kept.
+    ///
+    /// # Errors
+    ///
+    /// If the zone file cannot be parsed, or it has no `NS` records for the
+    /// root domain.
+    pub fn deserialise(data: &str) -> Result<Self, Error> {
+        let zone = Zone::deserialise(data).map_err(Error::Zone)?;
+        Self::from_zone(&zone).ok_or(Error::NoNameservers)
+    }
+
+    /// Get the root hints from a zone.  Returns `None` if there are no `NS`
+    /// records for the root domain.
+    pub fn from_zone(zone: &Zone) -> Option<Self> {
+        let root = DomainName::root_domain();
+        let records = zone.all_records();
+
+        let mut rrs = Vec::new();
+        let mut nsdnames = HashSet::new();
+        for zr in records.get(&root).into_iter().flatten() {
+            if let RecordTypeWithData::NS { nsdname } = &zr.rtype_with_data {
+                nsdnames.insert(nsdname.clone());
+                rrs.push(zr.to_rr(&root));
+            }
+        }
+
+        if rrs.is_empty() {
+            return None;
+        }
+
+        for nsdname in &nsdnames {
+            for zr in records.get(nsdname).into_iter().flatten() {
+                if matches!(
+                    zr.rtype_with_data,
+                    RecordTypeWithData::A { .. } | RecordTypeWithData::AAAA { .. }
+                ) {
+                    rrs.push(zr.to_rr(nsdname));
+                }
+            }
+        }
+
+        Some(Self { rrs })
+    }
+
+    /// All the records.
+    pub fn rrs(&self) -> &[ResourceRecord] {
+        &self.rrs
+    }
+
+    /// The root nameservers.
+    pub fn nameservers(&self) -> Vec<DomainName> {
+        self.rrs
+            .iter()
+            .filter_map(|rr| match &rr.rtype_with_data {
+                RecordTypeWithData::NS { nsdname } => Some(nsdname.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The addresses of the root nameservers which can be used with the given
+    /// protocol mode, preferred addresses first.
+    pub fn addresses(&self, protocol_mode: ProtocolMode) -> Vec<IpAddr> {
+        let (v4, v6): (Vec<IpAddr>, Vec<IpAddr>) = self
+            .rrs
+            .iter()
+            .filter_map(|rr| match rr.rtype_with_data {
+                RecordTypeWithData::A { address } => Some(IpAddr::V4(address)),
+                RecordTypeWithData::AAAA { address } => Some(IpAddr::V6(address)),
+                _ => None,
+            })
+            .partition(IpAddr::is_ipv4);
+
+        match protocol_mode {
+            ProtocolMode::OnlyV4 => v4,
+            ProtocolMode::PreferV4 => [v4, v6].concat(),
+            ProtocolMode::PreferV6 => [v6, v4].concat(),
+            ProtocolMode::OnlyV6 => v6,
+        }
+    }
+}
+
+impl Default for RootHints {
+    fn default() -> Self {
+        Self::deserialise(DEFAULT_ROOT_HINTS).expect("built-in root hints are invalid")
+    }
+}
+
+/// Ask the root nameservers for the current root `NS` records (and the
+/// addresses of those nameservers), and cache them.  This is called "priming",
+/// and means that resolution doesn't rely on the hints being up to date.
+///
+/// Each root nameserver is tried in turn, waiting up to `query_timeout` for
+/// each, until one gives a usable answer.  Queries are sent as the network
+/// options say (see `query_nameserver`).
+/// Returns the number of root nameservers cached, or `None` if none of them
+/// did.
+///
+/// See RFC 8109.
+pub async fn prime(
+    root_hints: &RootHints,
+    protocol_mode: ProtocolMode,
+    upstream_dns_port: u16,
+    query_timeout: Duration,
+    network: NetworkOptions,
+    cache: &SharedCache,
+) -> Option<usize> {
+    let question = Question {
+        name: DomainName::root_domain(),
+        qtype: QueryType::Record(RecordType::NS),
+        qclass: QueryClass::Record(RecordClass::IN),
+    };
+
+    for ip in root_hints.addresses(protocol_mode) {
+        let address = SocketAddr::new(ip, upstream_dns_port);
+        let response = match query_nameserver(
+            &Upstream::Address(address),
+            question.clone(),
+            false,
+            None,
+            query_timeout,
+            network,
+            &FallbackPolicy::default(),
+            None,
+        )
+        .instrument(tracing::error_span!("query_nameserver", %address))
+        .await
+        {
+            Ok(response) => response,
+            Err(error) => {
+                tracing::debug!(%address, %error, "no response to priming query");
+                continue;
+            }
+        };
+
+        let rrs = priming_rrs(&response);
+        let count = rrs
+            .iter()
+            .filter(|rr| rr.rtype_with_data.rtype() == RecordType::NS)
+            .count();
+        if count > 0 {
+            cache.insert_all(&rrs);
+            return Some(count);
+        }
+
+        tracing::debug!(%address, "no root NS records in response to priming query");
+    }
+
+    None
+}
+
+/// The records to cache from a response to a priming query: the root `NS`
+/// records from the answer section, and the `A` and `AAAA` records for those
+/// nameservers from the additional section.
+fn priming_rrs(response: &Message) -> Vec<ResourceRecord> {
+    let mut rrs = Vec::new();
+    let mut nsdnames = HashSet::new();
+    for rr in &response.answers {
+        if let RecordTypeWithData::NS { nsdname } = &rr.rtype_with_data {
+            if rr.name.is_root() {
+                nsdnames.insert(nsdname.clone());
+                rrs.push(rr.clone());
+            }
+        }
+    }
+
+    for rr in &response.additional {
+        if matches!(
+            rr.rtype_with_data,
+            RecordTypeWithData::A { .. } | RecordTypeWithData::AAAA { .. }
+        ) && nsdnames.contains(&rr.name)
+        {
+            rrs.push(rr.clone());
+        }
+    }
+
+    rrs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use dns_types::protocol::types::test_util::*;
+
+    use super::*;
+
+    #[test]
+    fn default_root_hints_are_valid() {
+        let root_hints = RootHints::default();
+
+        assert_eq!(13, root_hints.nameservers().len());
+        assert_eq!(13, root_hints.addresses(ProtocolMode::OnlyV4).len());
+        assert_eq!(13, root_hints.addresses(ProtocolMode::OnlyV6).len());
+    }
+
+    #[test]
+    fn root_hints_ignore_unrelated_records() {
+        let root_hints = RootHints::deserialise(
+            ".                   300 NS a.root.example.\n\
+             a.root.example.     300 A  192.0.2.1\n\
+             b.root.example.     300 A  192.0.2.2\n\
+             www.example.com.    300 A  192.0.2.3\n",
+        )
+        .unwrap();
+
+        assert_eq!(vec![domain("a.root.example.")], root_hints.nameservers());
+        assert_eq!(
+            vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))],
+            root_hints.addresses(ProtocolMode::PreferV6)
+        );
+    }
+
+    #[test]
+    fn root_hints_require_nameservers() {
+        assert!(matches!(
+            RootHints::deserialise("a.root.example. 300 A 192.0.2.1\n"),
+            Err(Error::NoNameservers)
+        ));
+    }
+
+    #[test]
+    fn priming_rrs_keeps_root_ns_and_glue() {
+        let mut response = Message::from_question(
+            1234,
+            Question {
+                name: DomainName::root_domain(),
+                qtype: QueryType::Record(RecordType::NS),
+                qclass: QueryClass::Record(RecordClass::IN),
+            },
+        );
+        response.answers = vec![
+            ns_record(".", "a.root.example."),
+            ns_record("com.", "a.gtld.example."),
+        ];
+        response.additional = vec![
+            a_record("a.root.example.", Ipv4Addr::new(192, 0, 2, 1)),
+            a_record("a.gtld.example.", Ipv4Addr::new(192, 0, 2, 2)),
+        ];
+
+        assert_eq!(
+            vec![
+                ns_record(".", "a.root.example."),
+                a_record("a.root.example.", Ipv4Addr::new(192, 0, 2, 1)),
+            ],
+            priming_rrs(&response)
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use bytes::{Bytes, BytesMut};
+use http::header::{ACCEPT, CONTENT_TYPE};
+use http::{Request, StatusCode, Uri};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rustls::ClientConfig;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{LazyLock, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tower_service::Service;
+
+use dns_types::protocol::types::Message;
+
+use crate::util::tls::tls_config;
+use crate::util::transport::{connect_tcp_for, pad_query, ExchangeFuture, Transport};
+use crate::util::types::{
+    HttpsUrl, NetworkOptions, SpkiPin, Upstream, UpstreamError, UpstreamSecurity,
+};
+
+/// The media type of a DNS message (see section 6 of RFC 8484).
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// The largest response to accept: the same as the largest DNS message.
+const MAX_RESPONSE_SIZE: usize = 65535;
+
+/// How long to keep an idle connection to a nameserver open for the next
+/// query.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const MUTEX_POISON_MESSAGE: &str =
+    "[INTERNAL ERROR] HTTPS clients mutex poisoned, cannot recover from this - aborting";
+
+type HttpsClient = Client<HttpsConnector<UpstreamConnector>, Full<Bytes>>;
+
+/// HTTPS clients, one for each way of connecting to nameservers and set of
+/// pins, shared by every query.  Each client keeps a pool of connections, so a connection (and
+/// its TLS session) is reused for later queries to the same nameserver, and
+/// several queries can be in flight at once over HTTP/2.
+#[allow(clippy::type_complexity)]
+static HTTPS_CLIENTS: LazyLock<
+    Mutex<HashMap<(NetworkOptions, Option<Vec<SpkiPin>>), HttpsClient>>,
+> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// DNS over HTTPS (RFC 8484), for upstream nameservers given as a URL.
+///
+/// Queries have an ID of 0, as RFC 8484 recommends, so that HTTP caches can
+/// work, and are padded.  The nameserver must have a certificate valid for
+/// the URL's host or, if its `UpstreamSecurity` has any pins, matching one of
+/// them.
+///
+/// Connections are reused for later queries to the same nameserver, and
+/// several queries can be in flight at once over HTTP/2.
+#[derive(Debug, Copy, Clone)]
+pub struct Https;
+
+impl Transport for Https {
+    fn can_send(&self, upstream: &Upstream, _: usize, _: NetworkOptions) -> bool {
+        matches!(upstream, Upstream::Https(_))
+    }
+
+    fn prepare(&self, request: &mut Message) {
+        request.header.id = 0;
+        pad_query(request);
+    }
+
+    fn exchange<'a>(
+        &'a self,
+        upstream: &'a Upstream,
+        serialised_request: BytesMut,
+        network: NetworkOptions,
+        security: Option<&'a UpstreamSecurity>,
+    ) -> ExchangeFuture<'a> {
+        Box::pin(async move {
+            let Upstream::Https(url) = upstream else {
+                return Err(UpstreamError::Unreachable);
+            };
+            query_nameserver_https(url, serialised_request.freeze(), network, security).await
+        })
+    }
+}
+
+/// Send a message to a remote nameserver over HTTPS, returning the response.
+///
+/// # Panics
+///
+/// If the mutex has been poisoned.
+async fn query_nameserver_https(
+    url: &HttpsUrl,
+    serialised_request: Bytes,
+    network: NetworkOptions,
+    security: Option<&UpstreamSecurity>,
+) -> Result<Message, UpstreamError> {
+    let pins = security
+        .map(|security| security.pins.clone())
+        .filter(|pins| !pins.is_empty());
+    let client = HTTPS_CLIENTS
+        .lock()
+        .expect(MUTEX_POISON_MESSAGE)
+        .entry((network, pins))
+        .or_insert_with(|| https_client(network, security))
+        .clone();
+
+    let request = Request::post(url.uri().clone())
+        .header(CONTENT_TYPE, DNS_MESSAGE)
+        .header(ACCEPT, DNS_MESSAGE)
+        .body(Full::new(serialised_request))
+        .map_err(|_| UpstreamError::Unreachable)?;
+    let response = client.request(request).await.map_err(|error| {
+        tracing::debug!(?error, "HTTPS request failed");
+        UpstreamError::Unreachable
+    })?;
+
+    if response.status() != StatusCode::OK {
+        tracing::debug!(status = %response.status(), "unexpected HTTPS status");
+        return Err(UpstreamError::InvalidResponse);
+    }
+    if response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type != DNS_MESSAGE)
+    {
+        return Err(UpstreamError::InvalidResponse);
+    }
+
+    let body = Limited::new(response.into_body(), MAX_RESPONSE_SIZE)
+        .collect()
+        .await
+        .map_err(|_| UpstreamError::Unreachable)?
+        .to_bytes();
+
+    Message::from_octets(&body).map_err(|_| UpstreamError::InvalidResponse)
+}
+
+fn https_client(network: NetworkOptions, security: Option<&UpstreamSecurity>) -> HttpsClient {
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(ClientConfig::clone(&tls_config(security, b"")))
+        .https_only()
+        .enable_all_versions()
+        .wrap_connector(UpstreamConnector { network });
+
+    Client::builder(TokioExecutor::new())
+        .pool_idle_timeout(IDLE_TIMEOUT)
+        .build(connector)
+}
+
+/// Opens TCP connections for the HTTPS client, in the same way as for DNS
+/// over TCP.
+///
+/// If the URL has a hostname rather than an IP address, it is looked up with
+/// the system resolver.
+#[derive(Debug, Clone)]
+struct UpstreamConnector {
+    network: NetworkOptions,
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = TokioIo<TcpStream>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let network = self.network;
+        Box::pin(async move {
+            let address = remote_address(&uri).await?;
+            let stream = connect_tcp_for(address, network).await?;
+            stream.set_nodelay(true)?;
+            Ok(TokioIo::new(stream))
+        })
+    }
+}
+
+/// Find the address to connect to for a URL.
+async fn remote_address(uri: &Uri) -> io::Result<SocketAddr> {
+    let Some(host) = uri.host() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "URL has no host",
+        ));
+    };
+    let port = uri.port_u16().unwrap_or(443);
+
+    // IPv6 addresses in URLs are in square brackets
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+
+    tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "could not resolve host"))
+}
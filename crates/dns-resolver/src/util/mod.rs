@@ -1,3 +1,9 @@
+pub mod https;
 pub mod nameserver;
 pub mod net;
+pub mod pool;
+pub mod proxy;
+pub mod quic;
+pub mod tls;
+pub mod transport;
 pub mod types;
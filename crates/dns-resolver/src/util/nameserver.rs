@@ -2,118 +2,187 @@ use rand::Rng;
 use std::cmp::Ordering;
 use std::net::SocketAddr;
 use std::time::Duration;
-use tokio::net::{TcpStream, UdpSocket};
 use tokio::time::timeout;
 
 use dns_types::protocol::types::*;
 
-use crate::util::net::{read_tcp_bytes, send_tcp_bytes, send_udp_bytes};
+use crate::util::transport::Transport;
+use crate::util::types::{
+    FallbackPolicy, NetworkOptions, TransportKind, Upstream, UpstreamError, UpstreamSecurity,
+};
 
-/// Send a message to a remote nameserver, preferring UDP if the request is
-/// small enough.  If the request is too large, or if the UDP response is
-/// truncated, tries again using TCP.
+/// Send a message to an upstream nameserver, trying each transport the
+/// fallback policy allows in turn until one gives a usable response.
+/// Transports which can't be used for this nameserver or this message (eg, UDP
+/// if the message is too large or there is a proxy) are skipped, and a
+/// truncated response means the next transport is tried.
 ///
 /// If an error occurs while sending the message or receiving the response, or
-/// the response does not match the request, `None` is returned.
+/// the response does not match the request, the reason is returned.  If more
+/// than one transport was tried, this is the reason the first failed.
 ///
-/// This has a 5s timeout for each request, so 10s in total.
+/// If the nameserver has an `UpstreamSecurity`, its encryption policy decides
+/// which transports are tried (see `FallbackPolicy::transports_for`) and how
+/// the nameserver is authenticated.
+///
+/// Each attempt has a timeout of `query_timeout`, so this may take several
+/// times that in total.
+///
+/// The message is sent from the outbound address for the nameserver's address
+/// family, if there is one, and through the proxy, if there is one.
+///
+/// If there is a client subnet, it is sent in an EDNS `OPT` pseudo-record.
+///
+/// # Errors
+///
+/// See `UpstreamError`.
+#[allow(clippy::missing_panics_doc)]
+#[allow(clippy::too_many_arguments)]
 pub async fn query_nameserver(
-    address: SocketAddr,
+    upstream: &Upstream,
     question: Question,
     recursion_desired: bool,
-) -> Option<Message> {
-    let mut request = Message::from_question(rand::thread_rng().gen(), question);
-    request.header.recursion_desired = recursion_desired;
-
-    match request.to_octets() {
-        Ok(mut serialised_request) => {
-            tracing::trace!(message = ?request, ?address, "forwarding query to nameserver");
+    client_subnet: Option<ClientSubnet>,
+    query_timeout: Duration,
+    network: NetworkOptions,
+    fallback: &FallbackPolicy,
+    security: Option<&UpstreamSecurity>,
+) -> Result<Message, UpstreamError> {
+    let mut first_error = None;
+    let mut truncated = false;
+
+    for kind in fallback.transports_for(upstream, security) {
+        let transport = kind.transport();
+
+        let mut request = Message::from_question(rand::thread_rng().gen(), question.clone());
+        request.header.recursion_desired = recursion_desired;
+        if let Some(client_subnet) = client_subnet {
+            request.additional.push(ResourceRecord::edns_opt(
+                512,
+                &[EdnsOption::client_subnet(&client_subnet)],
+            ));
+        }
+        transport.prepare(&mut request);
 
-            if let Some(response) = query_nameserver_udp(address, &mut serialised_request).await {
-                if response_matches_request(&request, &response) {
-                    return Some(response);
-                }
+        // safe because a message with a single question always serialises
+        let serialised_request = request.to_octets().unwrap();
+        if !transport.can_send(upstream, serialised_request.len(), network) {
+            continue;
+        }
+        tracing::trace!(message = ?request, %upstream, %kind, "forwarding query to nameserver");
+
+        match exchange(
+            transport,
+            upstream,
+            serialised_request,
+            query_timeout,
+            network,
+            security,
+        )
+        .await
+        {
+            Ok(response) if response.header.is_truncated => {
+                tracing::trace!(%kind, "got truncated response - trying next transport");
+                truncated = true;
             }
-
-            if let Some(response) = query_nameserver_tcp(address, &mut serialised_request).await {
-                if response_matches_request(&request, &response) {
-                    return Some(response);
+            Ok(response) => match check_response(&request, response) {
+                Ok(response) => return Ok(response),
+                Err(error) => {
+                    first_error.get_or_insert(error);
                 }
+            },
+            Err(error) => {
+                first_error.get_or_insert(error);
             }
-
-            None
-        }
-        Err(error) => {
-            tracing::warn!(message = ?request, ?error, "could not serialise message");
-            None
         }
     }
+
+    Err(first_error.unwrap_or(if truncated {
+        UpstreamError::InvalidResponse
+    } else {
+        UpstreamError::Unreachable
+    }))
 }
 
-/// Send a message to a remote nameserver over UDP, returning the
-/// response.  If the message would be truncated, or an error occurs
-/// while sending it, `None` is returned.  Otherwise the deserialised
-/// response message is: but this response is NOT validated -
-/// consumers MUST validate the response before using it!
+/// Send a message to a remote nameserver over UDP or TCP, and return the
+/// response whatever it is: unlike `query_nameserver`, the response is not
+/// checked against the request, and a truncated UDP response is not retried
+/// over TCP.  This is for debugging tools, which want to show the response.
+///
+/// If there is a proxy, TCP is always used.
+///
+/// This has a timeout of `query_timeout`.
+///
+/// # Errors
 ///
-/// This has a 5s timeout.
-async fn query_nameserver_udp(
+/// If the nameserver can't be reached, doesn't respond in time, or the
+/// response can't be parsed.
+#[allow(clippy::missing_panics_doc)]
+pub async fn query_nameserver_unchecked(
     address: SocketAddr,
-    serialised_request: &mut [u8],
-) -> Option<Message> {
-    timeout(
-        Duration::from_secs(5),
-        query_nameserver_udp_notimeout(address, serialised_request),
+    question: Question,
+    recursion_desired: bool,
+    use_tcp: bool,
+    query_timeout: Duration,
+    network: NetworkOptions,
+) -> Result<Message, UpstreamError> {
+    let mut request = Message::from_question(rand::thread_rng().gen(), question);
+    request.header.recursion_desired = recursion_desired;
+
+    // safe because a message with a single question always serialises
+    let serialised_request = request.to_octets().unwrap();
+
+    let kind = if use_tcp || network.proxy.is_some() {
+        TransportKind::Tcp
+    } else {
+        TransportKind::Udp
+    };
+    exchange(
+        kind.transport(),
+        &Upstream::Address(address),
+        serialised_request,
+        query_timeout,
+        network,
+        None,
     )
     .await
-    .unwrap_or_default()
-}
-
-/// Timeout-less version of `query_nameserver_udp`.
-async fn query_nameserver_udp_notimeout(
-    address: SocketAddr,
-    serialised_request: &mut [u8],
-) -> Option<Message> {
-    if serialised_request.len() > 512 {
-        return None;
-    }
-
-    let mut buf = vec![0u8; 512];
-    let sock = UdpSocket::bind("0.0.0.0:0").await.ok()?;
-    sock.connect(address).await.ok()?;
-    send_udp_bytes(&sock, serialised_request).await.ok()?;
-    sock.recv(&mut buf).await.ok()?;
-
-    Message::from_octets(&buf).ok()
 }
 
-/// Send a message to a remote nameserver over TCP, returning the
-/// response.  This has the same return value caveats as
-/// `query_nameserver_udp`.
-///
-/// This has a 5s timeout.
-async fn query_nameserver_tcp(
-    address: SocketAddr,
-    serialised_request: &mut [u8],
-) -> Option<Message> {
+/// Send a message over a transport with a timeout of `query_timeout`,
+/// returning the response: but this response is NOT validated - consumers MUST
+/// validate the response before using it!
+async fn exchange(
+    transport: &dyn Transport,
+    upstream: &Upstream,
+    serialised_request: bytes::BytesMut,
+    query_timeout: Duration,
+    network: NetworkOptions,
+    security: Option<&UpstreamSecurity>,
+) -> Result<Message, UpstreamError> {
     timeout(
-        Duration::from_secs(5),
-        query_nameserver_tcp_notimeout(address, serialised_request),
+        query_timeout,
+        transport.exchange(upstream, serialised_request, network, security),
     )
     .await
-    .unwrap_or_default()
+    .unwrap_or(Err(UpstreamError::Timeout))
 }
 
-/// Timeout-less version of `query_nameserver_tcp`.
-async fn query_nameserver_tcp_notimeout(
-    address: SocketAddr,
-    serialised_request: &mut [u8],
-) -> Option<Message> {
-    let mut stream = TcpStream::connect(address).await.ok()?;
-    send_tcp_bytes(&mut stream, serialised_request).await.ok()?;
-    let bytes = read_tcp_bytes(&mut stream).await.ok()?;
-
-    Message::from_octets(bytes.as_ref()).ok()
+/// Check that a response matches the request, or work out why it can't be
+/// used: a response to the request with an error rcode is a refusal or a
+/// failure, anything else is invalid.
+fn check_response(request: &Message, response: Message) -> Result<Message, UpstreamError> {
+    if response_matches_request(request, &response) {
+        Ok(response)
+    } else if request.header.id == response.header.id && response.header.is_response {
+        match response.header.rcode {
+            Rcode::Refused => Err(UpstreamError::Refused),
+            Rcode::FormatError => Err(UpstreamError::FormatError),
+            Rcode::NoError | Rcode::NameError => Err(UpstreamError::InvalidResponse),
+            _ => Err(UpstreamError::ServerFailure),
+        }
+    } else {
+        Err(UpstreamError::InvalidResponse)
+    }
 }
 
 /// Very basic validation that a nameserver response matches a
@@ -268,6 +337,40 @@ mod tests {
 
         assert!(!response_matches_request(&request, &response));
     }
+
+    #[test]
+    fn check_response_accepts() {
+        let (request, response) = matching_nameserver_response();
+
+        assert_eq!(Ok(response.clone()), check_response(&request, response));
+    }
+
+    #[test]
+    fn check_response_gives_rcode_errors() {
+        for (rcode, error) in [
+            (Rcode::Refused, UpstreamError::Refused),
+            (Rcode::FormatError, UpstreamError::FormatError),
+            (Rcode::ServerFailure, UpstreamError::ServerFailure),
+            (Rcode::NotImplemented, UpstreamError::ServerFailure),
+        ] {
+            let (request, mut response) = matching_nameserver_response();
+            response.header.rcode = rcode;
+
+            assert_eq!(Err(error), check_response(&request, response));
+        }
+    }
+
+    #[test]
+    fn check_response_rejects_mismatched_response() {
+        let (request, mut response) = matching_nameserver_response();
+        response.header.id += 1;
+        response.header.rcode = Rcode::Refused;
+
+        assert_eq!(
+            Err(UpstreamError::InvalidResponse),
+            check_response(&request, response)
+        );
+    }
 }
 
 #[cfg(test)]
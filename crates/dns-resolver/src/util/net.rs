@@ -1,8 +1,10 @@
 use bytes::BytesMut;
 use std::io;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpStream, UdpSocket};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+use crate::util::types::OutboundAddresses;
 
 /// Read a DNS message from a TCP stream.
 ///
@@ -16,7 +18,7 @@ use tokio::net::{TcpStream, UdpSocket};
 /// # Errors
 ///
 /// If reading from the stream fails or returns an incomplete message.
-pub async fn read_tcp_bytes(stream: &mut TcpStream) -> Result<BytesMut, TcpError> {
+pub async fn read_tcp_bytes<R: AsyncRead + Unpin>(stream: &mut R) -> Result<BytesMut, TcpError> {
     match stream.read_u16().await {
         Ok(size) => {
             let expected = size as usize;
@@ -69,6 +71,40 @@ pub enum TcpError {
     },
 }
 
+/// Create a UDP socket to send queries to an upstream nameserver, bound to
+/// the outbound address for its family, and connected to the nameserver.
+///
+/// # Errors
+///
+/// If binding or connecting the socket fails.
+pub async fn connect_udp(
+    remote: SocketAddr,
+    outbound: OutboundAddresses,
+) -> Result<UdpSocket, io::Error> {
+    let sock = UdpSocket::bind(outbound.local_address_for(remote)).await?;
+    sock.connect(remote).await?;
+    Ok(sock)
+}
+
+/// Open a TCP connection to an upstream nameserver, from the outbound address
+/// for its family.
+///
+/// # Errors
+///
+/// If binding the socket or connecting fails.
+pub async fn connect_tcp(
+    remote: SocketAddr,
+    outbound: OutboundAddresses,
+) -> Result<TcpStream, io::Error> {
+    let socket = if remote.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.bind(outbound.local_address_for(remote))?;
+    socket.connect(remote).await
+}
+
 /// Write a serialised message to a UDP channel.  This sets or clears
 /// the TC flag as appropriate.
 ///
@@ -96,7 +132,8 @@ pub async fn send_udp_bytes(sock: &UdpSocket, bytes: &mut [u8]) -> Result<(), io
     Ok(())
 }
 
-/// Like `send_udp_bytes` but sends to the given address
+/// Like `send_udp_bytes` but sends to the given address, and does not clear
+/// the TC flag if it is already set: responses may be deliberately truncated.
 ///
 /// # Errors
 ///
@@ -121,7 +158,6 @@ pub async fn send_udp_bytes_to(
         bytes[2] |= 0b0000_0010;
         sock.send_to(&bytes[..512], target).await?;
     } else {
-        bytes[2] &= 0b1111_1101;
         sock.send_to(bytes, target).await?;
     }
 
@@ -139,7 +175,10 @@ pub async fn send_udp_bytes_to(
 /// # Panics
 ///
 /// If given an incomplete (< 12 byte) message.
-pub async fn send_tcp_bytes(stream: &mut TcpStream, bytes: &mut [u8]) -> Result<(), io::Error> {
+pub async fn send_tcp_bytes<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    bytes: &mut [u8],
+) -> Result<(), io::Error> {
     if bytes.len() < 12 {
         tracing::error!(length = %bytes.len(), "message too short");
         panic!("expected complete message");
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long an idle connection to an upstream nameserver is kept open for.
+/// Nameservers close idle connections themselves after a short while (RFC
+/// 7766 suggests a few seconds), so there's no point keeping them longer.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most idle connections to keep open to each upstream nameserver.
+pub const MAX_IDLE_PER_ADDRESS: usize = 4;
+
+const MUTEX_POISON_MESSAGE: &str =
+    "[INTERNAL ERROR] connection pool mutex poisoned, cannot recover from this - aborting";
+
+/// Idle connections to upstream nameservers, so that a connection can be
+/// reused for the next query to the same nameserver rather than opening a new
+/// one.
+///
+/// Connections are keyed by `K`, which identifies the nameserver and anything
+/// else which makes one connection unsuitable for another's queries.
+///
+/// A connection is taken out of the pool while it's being used, so each one
+/// only has one query in flight at a time.
+#[derive(Debug)]
+pub struct ConnectionPool<K, T> {
+    idle: Mutex<HashMap<K, Vec<(T, Instant)>>>,
+}
+
+impl<K, T> Default for ConnectionPool<K, T> {
+    fn default() -> Self {
+        Self {
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Eq + Hash, T> ConnectionPool<K, T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take the most recently used idle connection to a nameserver, if there
+    /// is one which hasn't been idle for longer than `IDLE_TIMEOUT`.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn take(&self, key: &K) -> Option<T> {
+        let mut idle = self.idle.lock().expect(MUTEX_POISON_MESSAGE);
+        let connections = idle.get_mut(key)?;
+        let found = loop {
+            match connections.pop() {
+                Some((connection, since)) if since.elapsed() < IDLE_TIMEOUT => {
+                    break Some(connection)
+                }
+                Some(_) => (),
+                None => break None,
+            }
+        };
+        if connections.is_empty() {
+            idle.remove(key);
+        }
+        found
+    }
+
+    /// Put a connection back into the pool after use.  Connections which have
+    /// been idle for longer than `IDLE_TIMEOUT` are closed, and if there are
+    /// already `MAX_IDLE_PER_ADDRESS` idle connections to the nameserver this
+    /// one is closed instead.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn put(&self, key: K, connection: T) {
+        let mut idle = self.idle.lock().expect(MUTEX_POISON_MESSAGE);
+        idle.retain(|_, connections| {
+            connections.retain(|(_, since)| since.elapsed() < IDLE_TIMEOUT);
+            !connections.is_empty()
+        });
+
+        let connections = idle.entry(key).or_default();
+        if connections.len() < MAX_IDLE_PER_ADDRESS {
+            connections.push((connection, Instant::now()));
+        }
+    }
+
+    /// The number of idle connections.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn len(&self) -> usize {
+        self.idle
+            .lock()
+            .expect(MUTEX_POISON_MESSAGE)
+            .values()
+            .map(Vec::len)
+            .sum()
+    }
+
+    /// Whether there are no idle connections.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use super::*;
+
+    const ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 53);
+    const OTHER_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)), 53);
+
+    #[test]
+    fn take_empty() {
+        let pool = ConnectionPool::<SocketAddr, usize>::new();
+
+        assert_eq!(None, pool.take(&ADDRESS));
+    }
+
+    #[test]
+    fn take_returns_put_connection() {
+        let pool = ConnectionPool::new();
+        pool.put(ADDRESS, 1);
+
+        assert_eq!(Some(1), pool.take(&ADDRESS));
+        assert_eq!(None, pool.take(&ADDRESS));
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn take_is_per_address() {
+        let pool = ConnectionPool::new();
+        pool.put(ADDRESS, 1);
+
+        assert_eq!(None, pool.take(&OTHER_ADDRESS));
+        assert_eq!(Some(1), pool.take(&ADDRESS));
+    }
+
+    #[test]
+    fn take_prefers_most_recent() {
+        let pool = ConnectionPool::new();
+        pool.put(ADDRESS, 1);
+        pool.put(ADDRESS, 2);
+
+        assert_eq!(Some(2), pool.take(&ADDRESS));
+        assert_eq!(Some(1), pool.take(&ADDRESS));
+    }
+
+    #[test]
+    fn take_skips_expired() {
+        let pool = ConnectionPool::new();
+        pool.idle.lock().unwrap().insert(
+            ADDRESS,
+            vec![(1, Instant::now().checked_sub(IDLE_TIMEOUT).unwrap())],
+        );
+
+        assert_eq!(None, pool.take(&ADDRESS));
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn put_limits_idle_connections() {
+        let pool = ConnectionPool::new();
+        for i in 0..MAX_IDLE_PER_ADDRESS * 2 {
+            pool.put(ADDRESS, i);
+        }
+
+        assert_eq!(MAX_IDLE_PER_ADDRESS, pool.len());
+    }
+}
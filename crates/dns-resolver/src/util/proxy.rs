@@ -0,0 +1,186 @@
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::util::net::connect_tcp;
+use crate::util::types::{OutboundAddresses, UpstreamProxy};
+
+/// The longest HTTP response header to a `CONNECT` request which will be
+/// read, so that a misbehaving proxy can't make this read forever.
+pub const MAX_HTTP_RESPONSE_HEADER_LEN: usize = 8192;
+
+/// Open a TCP connection to an upstream nameserver through a proxy.  The
+/// connection to the proxy is made from the outbound address for its family.
+///
+/// Once this returns, the stream is connected to the nameserver, and can be
+/// used in the same way as a direct connection.
+///
+/// # Errors
+///
+/// If connecting to the proxy fails, or the proxy refuses or fails to connect
+/// to the nameserver.
+pub async fn connect_tcp_via(
+    proxy: UpstreamProxy,
+    remote: SocketAddr,
+    outbound: OutboundAddresses,
+) -> Result<TcpStream, io::Error> {
+    match proxy {
+        UpstreamProxy::Socks5(address) => {
+            let mut stream = connect_tcp(address, outbound).await?;
+            socks5_handshake(&mut stream, remote).await?;
+            Ok(stream)
+        }
+        UpstreamProxy::Http(address) => {
+            let mut stream = connect_tcp(address, outbound).await?;
+            http_handshake(&mut stream, remote).await?;
+            Ok(stream)
+        }
+    }
+}
+
+/// Ask a SOCKS5 proxy to connect to the nameserver (RFC 1928).
+async fn socks5_handshake(stream: &mut TcpStream, remote: SocketAddr) -> Result<(), io::Error> {
+    // version 5, one authentication method: none
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut method = [0; 2];
+    stream.read_exact(&mut method).await?;
+    if method != [5, 0] {
+        return Err(proxy_error("SOCKS5 proxy requires authentication"));
+    }
+
+    stream.write_all(&socks5_connect_request(remote)).await?;
+
+    // version, reply, reserved, address type
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 {
+        return Err(proxy_error("SOCKS5 proxy sent an invalid reply"));
+    }
+    if reply[1] != 0 {
+        return Err(proxy_error("SOCKS5 proxy could not connect"));
+    }
+
+    // the address the proxy bound to isn't needed, but it has to be read
+    // before the stream can be used.
+    let bound_address_len = match reply[3] {
+        1 => 4,
+        3 => stream.read_u8().await? as usize,
+        4 => 16,
+        _ => return Err(proxy_error("SOCKS5 proxy sent an invalid reply")),
+    };
+    let mut bound_address_and_port = vec![0; bound_address_len + 2];
+    stream.read_exact(&mut bound_address_and_port).await?;
+
+    Ok(())
+}
+
+/// Ask an HTTP proxy to connect to the nameserver, with the `CONNECT` method.
+async fn http_handshake(stream: &mut TcpStream, remote: SocketAddr) -> Result<(), io::Error> {
+    stream
+        .write_all(http_connect_request(remote).as_bytes())
+        .await?;
+
+    // read the response a byte at a time, so nothing after the header (which
+    // would be the start of the nameserver's response) is consumed.
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_HTTP_RESPONSE_HEADER_LEN {
+            return Err(proxy_error("HTTP proxy response header too long"));
+        }
+        header.push(stream.read_u8().await?);
+    }
+
+    if http_response_is_success(&header) {
+        Ok(())
+    } else {
+        Err(proxy_error("HTTP proxy could not connect"))
+    }
+}
+
+/// The SOCKS5 `CONNECT` request for an IP address and port.
+fn socks5_connect_request(remote: SocketAddr) -> Vec<u8> {
+    // version 5, command CONNECT, reserved
+    let mut request = vec![5, 1, 0];
+    match remote {
+        SocketAddr::V4(address) => {
+            request.push(1);
+            request.extend_from_slice(&address.ip().octets());
+        }
+        SocketAddr::V6(address) => {
+            request.push(4);
+            request.extend_from_slice(&address.ip().octets());
+        }
+    }
+    request.extend_from_slice(&remote.port().to_be_bytes());
+    request
+}
+
+/// The HTTP `CONNECT` request for an IP address and port.
+fn http_connect_request(remote: SocketAddr) -> String {
+    format!("CONNECT {remote} HTTP/1.1\r\nHost: {remote}\r\n\r\n")
+}
+
+/// Check if the status line of an HTTP response header has a 2xx status.
+fn http_response_is_success(header: &[u8]) -> bool {
+    let Some(status_line) = header.split(|b| *b == b'\n').next() else {
+        return false;
+    };
+    let mut parts = status_line.split(|b| *b == b' ');
+    matches!(
+        (parts.next(), parts.next()),
+        (Some(version), Some([b'2', _, _])) if version.starts_with(b"HTTP/1.")
+    )
+}
+
+fn proxy_error(message: &'static str) -> io::Error {
+    io::Error::other(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socks5_connect_request_v4() {
+        assert_eq!(
+            vec![5, 1, 0, 1, 192, 0, 2, 1, 0, 53],
+            socks5_connect_request("192.0.2.1:53".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn socks5_connect_request_v6() {
+        assert_eq!(
+            vec![
+                5, 1, 0, 4, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x14, 0xe9
+            ],
+            socks5_connect_request("[2001:db8::1]:5353".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn http_connect_request_v6() {
+        assert_eq!(
+            "CONNECT [2001:db8::1]:53 HTTP/1.1\r\nHost: [2001:db8::1]:53\r\n\r\n",
+            http_connect_request("[2001:db8::1]:53".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn http_response_is_success_accepts_2xx() {
+        assert!(http_response_is_success(
+            b"HTTP/1.1 200 Connection established\r\n\r\n"
+        ));
+        assert!(http_response_is_success(b"HTTP/1.0 200 OK\r\n\r\n"));
+    }
+
+    #[test]
+    fn http_response_is_success_rejects_others() {
+        assert!(!http_response_is_success(
+            b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n"
+        ));
+        assert!(!http_response_is_success(b"SSH-2.0-OpenSSH\r\n\r\n"));
+        assert!(!http_response_is_success(b"\r\n\r\n"));
+    }
+}
@@ -0,0 +1,177 @@
+use bytes::BytesMut;
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{ClientConfig, Connection, Endpoint};
+use rustls::pki_types::ServerName;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, LazyLock, Mutex};
+
+use dns_types::protocol::types::Message;
+
+use crate::util::net::send_tcp_bytes;
+use crate::util::tls::{
+    authentication, server_name, tls_config, Authentication, DNS_OVER_TLS_PORT,
+};
+use crate::util::transport::{pad_query, read_response, ExchangeFuture, Transport};
+use crate::util::types::{
+    NetworkOptions, OutboundAddresses, Upstream, UpstreamError, UpstreamSecurity,
+};
+
+/// The ALPN token for DNS over QUIC (see section 4.1.1 of RFC 9250).
+const DOQ_ALPN: &[u8] = b"doq";
+
+const MUTEX_POISON_MESSAGE: &str =
+    "[INTERNAL ERROR] QUIC connections mutex poisoned, cannot recover from this - aborting";
+
+/// Local QUIC endpoints, one for each local address queries are sent from.
+static QUIC_ENDPOINTS: LazyLock<Mutex<HashMap<SocketAddr, Endpoint>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Open QUIC connections to upstream nameservers, shared by every query.
+/// Connections are only reused for queries with the same `Authentication`.
+#[allow(clippy::type_complexity)]
+static QUIC_CONNECTIONS: LazyLock<
+    Mutex<HashMap<(SocketAddr, OutboundAddresses, Authentication), Connection>>,
+> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// DNS over QUIC (RFC 9250), to port 853 of the nameserver's address.  This
+/// can't be used through a proxy.
+///
+/// The nameserver is authenticated as its `UpstreamSecurity` says, or by a
+/// certificate valid for its IP address if it has none.
+///
+/// Queries have an ID of 0, as RFC 9250 requires, and are padded.  Each is
+/// sent on its own stream of a connection which is kept open for later
+/// queries to the same nameserver, so several can be in flight at once.
+#[derive(Debug, Copy, Clone)]
+pub struct Quic;
+
+impl Transport for Quic {
+    fn can_send(&self, upstream: &Upstream, _: usize, network: NetworkOptions) -> bool {
+        matches!(upstream, Upstream::Address(_)) && network.proxy.is_none()
+    }
+
+    fn prepare(&self, request: &mut Message) {
+        request.header.id = 0;
+        pad_query(request);
+    }
+
+    fn exchange<'a>(
+        &'a self,
+        upstream: &'a Upstream,
+        serialised_request: BytesMut,
+        network: NetworkOptions,
+        security: Option<&'a UpstreamSecurity>,
+    ) -> ExchangeFuture<'a> {
+        Box::pin(async move {
+            let Upstream::Address(address) = upstream else {
+                return Err(UpstreamError::Unreachable);
+            };
+            let address = SocketAddr::new(address.ip(), DNS_OVER_TLS_PORT);
+            query_nameserver_quic(address, serialised_request, network.outbound, security).await
+        })
+    }
+}
+
+/// Send a message to a remote nameserver over QUIC, reusing the open
+/// connection to it if there is one.
+///
+/// # Panics
+///
+/// If the mutex has been poisoned.
+async fn query_nameserver_quic(
+    address: SocketAddr,
+    mut serialised_request: BytesMut,
+    outbound: OutboundAddresses,
+    security: Option<&UpstreamSecurity>,
+) -> Result<Message, UpstreamError> {
+    let key = (address, outbound, authentication(security));
+    let existing = QUIC_CONNECTIONS
+        .lock()
+        .expect(MUTEX_POISON_MESSAGE)
+        .get(&key)
+        .filter(|connection| connection.close_reason().is_none())
+        .cloned();
+
+    if let Some(connection) = existing {
+        // the nameserver may have closed the connection since it was last
+        // used, so if this fails try again with a new one.
+        if let Ok(response) = exchange_quic(&connection, &mut serialised_request).await {
+            tracing::trace!("reused QUIC connection");
+            return Ok(response);
+        }
+        tracing::trace!("reused QUIC connection failed - reconnecting");
+    }
+
+    let connection = connect_quic(address, outbound, security).await?;
+    {
+        let mut connections = QUIC_CONNECTIONS.lock().expect(MUTEX_POISON_MESSAGE);
+        // close any connection made with a previous policy
+        connections.retain(|(other_address, other_outbound, _), _| {
+            (*other_address, *other_outbound) != (address, outbound)
+        });
+        connections.insert(key, connection.clone());
+    }
+    exchange_quic(&connection, &mut serialised_request).await
+}
+
+/// Open a QUIC connection to a nameserver, from the outbound address for its
+/// family.
+///
+/// # Panics
+///
+/// If the mutex has been poisoned.
+async fn connect_quic(
+    address: SocketAddr,
+    outbound: OutboundAddresses,
+    security: Option<&UpstreamSecurity>,
+) -> Result<Connection, UpstreamError> {
+    let Ok(config) = QuicClientConfig::try_from(tls_config(security, DOQ_ALPN)) else {
+        tracing::warn!("QUIC client configuration unavailable");
+        return Err(UpstreamError::Unreachable);
+    };
+    let config = ClientConfig::new(Arc::new(config));
+    let server_name = match server_name(address.ip(), security)? {
+        ServerName::DnsName(name) => name.as_ref().to_string(),
+        _ => address.ip().to_string(),
+    };
+
+    let local = outbound.local_address_for(address);
+    let endpoint = {
+        let mut endpoints = QUIC_ENDPOINTS.lock().expect(MUTEX_POISON_MESSAGE);
+        if let Some(endpoint) = endpoints.get(&local) {
+            endpoint.clone()
+        } else {
+            let endpoint = Endpoint::client(local).map_err(|_| UpstreamError::Unreachable)?;
+            endpoints.insert(local, endpoint.clone());
+            endpoint
+        }
+    };
+
+    endpoint
+        .connect_with(config, address, &server_name)
+        .map_err(|_| UpstreamError::Unreachable)?
+        .await
+        .map_err(|error| {
+            tracing::debug!(?error, "QUIC handshake failed");
+            UpstreamError::Unreachable
+        })
+}
+
+/// Send a message on a new stream of a QUIC connection, and read the
+/// response.  Messages are framed as over TCP, and the stream is closed after
+/// the message is sent (see section 4.2 of RFC 9250).
+async fn exchange_quic(
+    connection: &Connection,
+    serialised_request: &mut [u8],
+) -> Result<Message, UpstreamError> {
+    let (mut send, mut recv) = connection
+        .open_bi()
+        .await
+        .map_err(|_| UpstreamError::Unreachable)?;
+    send_tcp_bytes(&mut send, serialised_request)
+        .await
+        .map_err(|_| UpstreamError::Unreachable)?;
+    send.finish().map_err(|_| UpstreamError::Unreachable)?;
+    read_response(&mut recv).await
+}
@@ -0,0 +1,323 @@
+use bytes::BytesMut;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::server::ParsedCertificate;
+use rustls::{ClientConfig, DigitallySignedStruct, Error, RootCertStore, SignatureScheme};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+use dns_types::protocol::types::{DomainName, Message};
+
+use crate::util::pool::ConnectionPool;
+use crate::util::transport::{
+    connect_tcp_for, exchange_stream, pad_query, ExchangeFuture, Transport,
+};
+use crate::util::types::{NetworkOptions, SpkiPin, Upstream, UpstreamError, UpstreamSecurity};
+
+/// The port for DNS over TLS and DNS over QUIC (see RFC 7858 and RFC 9250).
+pub const DNS_OVER_TLS_PORT: u16 = 853;
+
+const MUTEX_POISON_MESSAGE: &str =
+    "[INTERNAL ERROR] TLS configurations mutex poisoned, cannot recover from this - aborting";
+
+/// What a connection to a nameserver was authenticated with: the name and
+/// pins from its `UpstreamSecurity`.  Connections are only reused for queries
+/// with the same, so that a changed policy takes effect on the next query
+/// rather than when the old connections happen to close.
+pub type Authentication = (Option<DomainName>, Vec<SpkiPin>);
+
+/// Idle TLS connections to upstream nameservers, shared by every query.
+#[allow(clippy::type_complexity)]
+static TLS_POOL: LazyLock<
+    ConnectionPool<(SocketAddr, NetworkOptions, Authentication), TlsStream<TcpStream>>,
+> = LazyLock::new(ConnectionPool::new);
+
+/// TLS client configurations, one for each set of pins and ALPN protocol, so
+/// that they (and their session caches) are shared by every query.
+#[allow(clippy::type_complexity)]
+static TLS_CONFIGS: LazyLock<
+    Mutex<HashMap<(Option<Vec<SpkiPin>>, &'static [u8]), Arc<ClientConfig>>>,
+> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// DNS over TLS (RFC 7858), to port 853 of the nameserver's address.
+///
+/// The nameserver is authenticated as its `UpstreamSecurity` says, or by a
+/// certificate valid for its IP address if it has none.
+///
+/// An idle connection to the nameserver is reused if there is one, and the
+/// connection is kept open for the next query afterwards, as with TCP.
+#[derive(Debug, Copy, Clone)]
+pub struct Tls;
+
+impl Transport for Tls {
+    fn can_send(&self, upstream: &Upstream, _: usize, _: NetworkOptions) -> bool {
+        matches!(upstream, Upstream::Address(_))
+    }
+
+    fn prepare(&self, request: &mut Message) {
+        pad_query(request);
+    }
+
+    fn exchange<'a>(
+        &'a self,
+        upstream: &'a Upstream,
+        serialised_request: BytesMut,
+        network: NetworkOptions,
+        security: Option<&'a UpstreamSecurity>,
+    ) -> ExchangeFuture<'a> {
+        Box::pin(async move {
+            let Upstream::Address(address) = upstream else {
+                return Err(UpstreamError::Unreachable);
+            };
+            let address = SocketAddr::new(address.ip(), DNS_OVER_TLS_PORT);
+            query_nameserver_tls(address, serialised_request, network, security).await
+        })
+    }
+}
+
+async fn query_nameserver_tls(
+    address: SocketAddr,
+    mut serialised_request: BytesMut,
+    network: NetworkOptions,
+    security: Option<&UpstreamSecurity>,
+) -> Result<Message, UpstreamError> {
+    let key = (address, network, authentication(security));
+    if let Some(mut stream) = TLS_POOL.take(&key) {
+        // the nameserver may have closed the connection since it was last
+        // used, so if this fails try again with a new one.
+        if let Ok(response) = exchange_stream(&mut stream, &mut serialised_request).await {
+            tracing::trace!("reused TLS connection");
+            TLS_POOL.put(key, stream);
+            return Ok(response);
+        }
+        tracing::trace!("reused TLS connection failed - reconnecting");
+    }
+
+    let server_name = server_name(address.ip(), security)?;
+    let stream = connect_tcp_for(address, network)
+        .await
+        .map_err(|_| UpstreamError::Unreachable)?;
+    let mut stream = TlsConnector::from(tls_config(security, b""))
+        .connect(server_name, stream)
+        .await
+        .map_err(|error| {
+            tracing::debug!(?error, "TLS handshake failed");
+            UpstreamError::Unreachable
+        })?;
+    let response = exchange_stream(&mut stream, &mut serialised_request).await?;
+    TLS_POOL.put(key, stream);
+    Ok(response)
+}
+
+/// The `Authentication` a connection made with an `UpstreamSecurity` has.
+pub fn authentication(security: Option<&UpstreamSecurity>) -> Authentication {
+    security.map_or_else(Authentication::default, |security| {
+        (security.name.clone(), security.pins.clone())
+    })
+}
+
+/// The name to authenticate a nameserver as: the name in its
+/// `UpstreamSecurity`, if there is one, or its IP address.
+///
+/// # Errors
+///
+/// If the name is not a valid DNS name for TLS.
+pub fn server_name(
+    ip: IpAddr,
+    security: Option<&UpstreamSecurity>,
+) -> Result<ServerName<'static>, UpstreamError> {
+    match security.and_then(|security| security.name.as_ref()) {
+        Some(name) => {
+            let name = name.to_dotted_string();
+            ServerName::try_from(name.trim_end_matches('.').to_string())
+                .map_err(|_| UpstreamError::Unreachable)
+        }
+        None => Ok(ServerName::IpAddress(ip.into())),
+    }
+}
+
+/// TLS client configuration for upstream nameservers, offering the given
+/// ALPN protocol (if it's not empty).
+///
+/// If the `UpstreamSecurity` has any pins, the nameserver's certificate must
+/// match one of them.  Otherwise it must be issued by one of the Mozilla root
+/// certificate authorities.
+///
+/// # Panics
+///
+/// If the mutex has been poisoned, or if the TLS library doesn't support the
+/// default protocol versions, which would be a bug.
+pub fn tls_config(security: Option<&UpstreamSecurity>, alpn: &'static [u8]) -> Arc<ClientConfig> {
+    let pins = security
+        .map(|security| security.pins.clone())
+        .filter(|pins| !pins.is_empty());
+
+    TLS_CONFIGS
+        .lock()
+        .expect(MUTEX_POISON_MESSAGE)
+        .entry((pins.clone(), alpn))
+        .or_insert_with(|| {
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let builder = ClientConfig::builder_with_provider(provider.clone())
+                .with_safe_default_protocol_versions()
+                .expect("[INTERNAL ERROR] default TLS protocol versions unsupported");
+            let mut config = if let Some(pins) = pins {
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(PinnedVerifier { pins, provider }))
+                    .with_no_client_auth()
+            } else {
+                let roots = RootCertStore {
+                    roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+                };
+                builder.with_root_certificates(roots).with_no_client_auth()
+            };
+            if !alpn.is_empty() {
+                config.alpn_protocols = vec![alpn.to_vec()];
+            }
+            Arc::new(config)
+        })
+        .clone()
+}
+
+/// Accepts a certificate if the SHA-256 hash of its public key matches one of
+/// the pins, whoever issued it and whatever names it is for (see section 4.2
+/// of RFC 7858).  Handshake signatures are still checked, so the nameserver
+/// must have the private key.
+#[derive(Debug)]
+struct PinnedVerifier {
+    pins: Vec<SpkiPin>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let cert = ParsedCertificate::try_from(end_entity)?;
+        let spki = cert.subject_public_key_info();
+        let hash = ring::digest::digest(&ring::digest::SHA256, spki.as_ref());
+        if self.pins.iter().any(|pin| pin.0 == hash.as_ref()) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(Error::General("certificate does not match any pin".into()))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::types::EncryptionPolicy;
+
+    #[test]
+    fn authentication_without_security_is_empty() {
+        assert_eq!((None, Vec::new()), authentication(None));
+    }
+
+    #[test]
+    fn authentication_ignores_encryption_policy() {
+        let strict = security(EncryptionPolicy::Strict, None, vec![]);
+        let opportunistic = security(EncryptionPolicy::Opportunistic, None, vec![]);
+
+        assert_eq!(
+            authentication(Some(&strict)),
+            authentication(Some(&opportunistic))
+        );
+    }
+
+    #[test]
+    fn pooled_connection_not_reused_after_pins_change() {
+        let address = SocketAddr::from(([192, 0, 2, 1], DNS_OVER_TLS_PORT));
+        let network = NetworkOptions::default();
+        let unpinned = security(EncryptionPolicy::Strict, None, vec![]);
+        let pinned = security(EncryptionPolicy::Strict, None, vec![SpkiPin([7; 32])]);
+
+        let pool = ConnectionPool::new();
+        pool.put((address, network, authentication(Some(&unpinned))), "old");
+
+        assert_eq!(
+            None,
+            pool.take(&(address, network, authentication(Some(&pinned))))
+        );
+        assert_eq!(
+            Some("old"),
+            pool.take(&(address, network, authentication(Some(&unpinned))))
+        );
+    }
+
+    #[test]
+    fn pooled_connection_not_reused_after_name_changes() {
+        let address = SocketAddr::from(([192, 0, 2, 1], DNS_OVER_TLS_PORT));
+        let network = NetworkOptions::default();
+        let named = security(
+            EncryptionPolicy::Strict,
+            Some(DomainName::from_dotted_string("dns.example.com.").unwrap()),
+            vec![],
+        );
+
+        let pool = ConnectionPool::new();
+        pool.put((address, network, authentication(None)), "old");
+
+        assert_eq!(
+            None,
+            pool.take(&(address, network, authentication(Some(&named))))
+        );
+    }
+
+    fn security(
+        encryption: EncryptionPolicy,
+        name: Option<DomainName>,
+        pins: Vec<SpkiPin>,
+    ) -> UpstreamSecurity {
+        UpstreamSecurity {
+            encryption,
+            name,
+            pins,
+        }
+    }
+}
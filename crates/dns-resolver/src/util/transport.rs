@@ -0,0 +1,236 @@
+use bytes::BytesMut;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::LazyLock;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use dns_types::protocol::types::*;
+
+use crate::util::https::Https;
+use crate::util::net::{
+    connect_tcp, connect_udp, read_tcp_bytes, send_tcp_bytes, send_udp_bytes, TcpError,
+};
+use crate::util::pool::ConnectionPool;
+use crate::util::proxy::connect_tcp_via;
+use crate::util::quic::Quic;
+use crate::util::tls::Tls;
+use crate::util::types::{
+    NetworkOptions, TransportKind, Upstream, UpstreamError, UpstreamSecurity,
+};
+
+/// Pad queries sent over encrypted transports to a multiple of this many
+/// octets, as recommended by RFC 8467.
+pub const QUERY_PADDING_BLOCK_SIZE: usize = 128;
+
+/// The largest query which can be sent over UDP, without EDNS.
+const UDP_MAX_SIZE: usize = 512;
+
+/// Idle TCP connections to upstream nameservers, shared by every query.
+static TCP_POOL: LazyLock<ConnectionPool<(SocketAddr, NetworkOptions), TcpStream>> =
+    LazyLock::new(ConnectionPool::new);
+
+/// The response to a query sent over some transport.
+pub type ExchangeFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Message, UpstreamError>> + Send + 'a>>;
+
+/// A way of sending a query to an upstream nameserver and getting the response
+/// back.
+///
+/// Timeouts, checking the response against the query, and falling back to
+/// another transport are all left to the caller: see
+/// `nameserver::query_nameserver`.
+pub trait Transport: Sync {
+    /// Whether this transport can send a query of `len` octets to the
+    /// upstream nameserver.
+    fn can_send(&self, upstream: &Upstream, len: usize, network: NetworkOptions) -> bool;
+
+    /// Make any changes to a query which this transport needs before it is
+    /// serialised, such as padding it.
+    fn prepare(&self, _request: &mut Message) {}
+
+    /// Send a serialised query to the upstream nameserver, returning the
+    /// response: but this response is NOT validated - consumers MUST validate
+    /// the response before using it!
+    ///
+    /// The connection is made from the outbound address for the nameserver's
+    /// address family, if there is one, and through the proxy, if there is
+    /// one and the transport supports it.  Encrypted transports authenticate
+    /// the nameserver as its `UpstreamSecurity` says.
+    ///
+    /// This has no timeout.
+    fn exchange<'a>(
+        &'a self,
+        upstream: &'a Upstream,
+        serialised_request: BytesMut,
+        network: NetworkOptions,
+        security: Option<&'a UpstreamSecurity>,
+    ) -> ExchangeFuture<'a>;
+}
+
+impl TransportKind {
+    /// The implementation of this transport.
+    pub fn transport(self) -> &'static dyn Transport {
+        match self {
+            TransportKind::Udp => &Udp,
+            TransportKind::Tcp => &Tcp,
+            TransportKind::Tls => &Tls,
+            TransportKind::Https => &Https,
+            TransportKind::Quic => &Quic,
+        }
+    }
+}
+
+/// Plain DNS over UDP.  This can't be used through a proxy, or for queries
+/// over 512 octets.
+#[derive(Debug, Copy, Clone)]
+pub struct Udp;
+
+impl Transport for Udp {
+    fn can_send(&self, upstream: &Upstream, len: usize, network: NetworkOptions) -> bool {
+        matches!(upstream, Upstream::Address(_)) && network.proxy.is_none() && len <= UDP_MAX_SIZE
+    }
+
+    fn exchange<'a>(
+        &'a self,
+        upstream: &'a Upstream,
+        serialised_request: BytesMut,
+        network: NetworkOptions,
+        _: Option<&'a UpstreamSecurity>,
+    ) -> ExchangeFuture<'a> {
+        Box::pin(async move {
+            let Upstream::Address(address) = upstream else {
+                return Err(UpstreamError::Unreachable);
+            };
+            query_nameserver_udp(*address, serialised_request, network).await
+        })
+    }
+}
+
+async fn query_nameserver_udp(
+    address: SocketAddr,
+    mut serialised_request: BytesMut,
+    network: NetworkOptions,
+) -> Result<Message, UpstreamError> {
+    let unreachable = |_| UpstreamError::Unreachable;
+
+    let mut buf = vec![0u8; UDP_MAX_SIZE];
+    let sock = connect_udp(address, network.outbound)
+        .await
+        .map_err(unreachable)?;
+    send_udp_bytes(&sock, &mut serialised_request)
+        .await
+        .map_err(unreachable)?;
+    sock.recv(&mut buf).await.map_err(unreachable)?;
+
+    Message::from_octets(&buf).map_err(|_| UpstreamError::InvalidResponse)
+}
+
+/// Plain DNS over TCP.
+///
+/// An idle connection to the nameserver is reused if there is one, and the
+/// connection is kept open for the next query afterwards: see
+/// `ConnectionPool`.
+#[derive(Debug, Copy, Clone)]
+pub struct Tcp;
+
+impl Transport for Tcp {
+    fn can_send(&self, upstream: &Upstream, _: usize, _: NetworkOptions) -> bool {
+        matches!(upstream, Upstream::Address(_))
+    }
+
+    fn exchange<'a>(
+        &'a self,
+        upstream: &'a Upstream,
+        serialised_request: BytesMut,
+        network: NetworkOptions,
+        _: Option<&'a UpstreamSecurity>,
+    ) -> ExchangeFuture<'a> {
+        Box::pin(async move {
+            let Upstream::Address(address) = upstream else {
+                return Err(UpstreamError::Unreachable);
+            };
+            query_nameserver_tcp(*address, serialised_request, network).await
+        })
+    }
+}
+
+async fn query_nameserver_tcp(
+    address: SocketAddr,
+    mut serialised_request: BytesMut,
+    network: NetworkOptions,
+) -> Result<Message, UpstreamError> {
+    let key = (address, network);
+    if let Some(mut stream) = TCP_POOL.take(&key) {
+        // the nameserver may have closed the connection since it was last
+        // used, so if this fails try again with a new one.
+        if let Ok(response) = exchange_stream(&mut stream, &mut serialised_request).await {
+            tracing::trace!("reused TCP connection");
+            TCP_POOL.put(key, stream);
+            return Ok(response);
+        }
+        tracing::trace!("reused TCP connection failed - reconnecting");
+    }
+
+    let mut stream = connect_tcp_for(address, network)
+        .await
+        .map_err(|_| UpstreamError::Unreachable)?;
+    let response = exchange_stream(&mut stream, &mut serialised_request).await?;
+    TCP_POOL.put(key, stream);
+    Ok(response)
+}
+
+/// Open a TCP connection to a nameserver, through the proxy if there is one.
+///
+/// # Errors
+///
+/// If connecting to the nameserver or the proxy fails.
+pub async fn connect_tcp_for(
+    address: SocketAddr,
+    network: NetworkOptions,
+) -> std::io::Result<TcpStream> {
+    match network.proxy {
+        Some(proxy) => connect_tcp_via(proxy, address, network.outbound).await,
+        None => connect_tcp(address, network.outbound).await,
+    }
+}
+
+/// Send a message over a stream, with the two-octet length prefix used by
+/// TCP, and read the response.
+///
+/// # Errors
+///
+/// See `UpstreamError`.
+pub async fn exchange_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    serialised_request: &mut [u8],
+) -> Result<Message, UpstreamError> {
+    send_tcp_bytes(stream, serialised_request)
+        .await
+        .map_err(|_| UpstreamError::Unreachable)?;
+    read_response(stream).await
+}
+
+/// Read a response with a two-octet length prefix from a stream.
+///
+/// # Errors
+///
+/// See `UpstreamError`.
+pub async fn read_response<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Message, UpstreamError> {
+    let bytes = read_tcp_bytes(stream).await.map_err(|error| match error {
+        TcpError::TooShort { .. } => UpstreamError::InvalidResponse,
+        TcpError::IO { .. } => UpstreamError::Unreachable,
+    })?;
+
+    Message::from_octets(bytes.as_ref()).map_err(|_| UpstreamError::InvalidResponse)
+}
+
+/// Pad a query to a multiple of `QUERY_PADDING_BLOCK_SIZE`, adding an EDNS
+/// `OPT` pseudo-record to put the padding in if there isn't one already.
+pub fn pad_query(request: &mut Message) {
+    if !request.additional.iter().any(ResourceRecord::is_edns_opt) {
+        request.additional.push(ResourceRecord::edns_opt(512, &[]));
+    }
+    request.pad(QUERY_PADDING_BLOCK_SIZE);
+}
@@ -1,8 +1,15 @@
-use std::collections::HashSet;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use dns_types::protocol::types::*;
+use dns_types::zones::types::SOA;
 
 pub const CANNOT_PARSE_PROTOCOL_MODE: &str =
     "expected one of 'only-v4', 'prefer-v4', 'prefer-v6', 'only'v6'";
@@ -50,6 +57,1288 @@ impl FromStr for ProtocolMode {
     }
 }
 
+pub const CANNOT_PARSE_FORWARDING_RULE: &str =
+    "expected a rule of the form 'domain=ip:port' or 'domain=recurse', eg 'corp.example.com.=10.0.0.1:53'";
+
+/// A rule to forward queries for a domain, and all of its subdomains, to a
+/// specific upstream nameserver, or to resolve them recursively even if there
+/// are default nameservers.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ForwardingRule {
+    pub domain: DomainName,
+    /// If `None`, queries are resolved recursively.
+    pub address: Option<SocketAddr>,
+}
+
+impl fmt::Display for ForwardingRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.address {
+            Some(address) => write!(f, "{}={address}", self.domain),
+            None => write!(f, "{}=recurse", self.domain),
+        }
+    }
+}
+
+impl FromStr for ForwardingRule {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((domain_str, address_str)) = s.split_once('=') else {
+            return Err(CANNOT_PARSE_FORWARDING_RULE);
+        };
+
+        let Ok(domain) = DomainName::from_str(domain_str) else {
+            return Err(CANNOT_PARSE_FORWARDING_RULE);
+        };
+
+        if address_str == "recurse" {
+            return Ok(ForwardingRule {
+                domain,
+                address: None,
+            });
+        }
+
+        match SocketAddr::from_str(address_str) {
+            Ok(address) => Ok(ForwardingRule {
+                domain,
+                address: Some(address),
+            }),
+            Err(_) => Err(CANNOT_PARSE_FORWARDING_RULE),
+        }
+    }
+}
+
+pub const CANNOT_PARSE_FORWARDING_STRATEGY: &str = "expected one of 'failover', 'race'";
+
+/// How the forwarding resolver should use multiple upstream nameservers.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum ForwardingStrategy {
+    /// Query each nameserver in turn, stopping at the first which gives a
+    /// usable answer.
+    #[default]
+    Failover,
+    /// Query all of the nameservers at once, and use the first usable answer
+    /// to come back.  This cuts latency when one nameserver is slow, at the
+    /// cost of sending more queries.
+    Race,
+}
+
+impl fmt::Display for ForwardingStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ForwardingStrategy::Failover => write!(f, "failover"),
+            ForwardingStrategy::Race => write!(f, "race"),
+        }
+    }
+}
+
+impl FromStr for ForwardingStrategy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "failover" => Ok(ForwardingStrategy::Failover),
+            "race" => Ok(ForwardingStrategy::Race),
+            _ => Err(CANNOT_PARSE_FORWARDING_STRATEGY),
+        }
+    }
+}
+
+pub const CANNOT_PARSE_TRANSPORT_KIND: &str =
+    "expected one of 'udp', 'tcp', 'tls', 'https', 'quic'";
+
+/// A protocol for sending queries to upstream nameservers.  See
+/// `transport::Transport` for how each is used.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum TransportKind {
+    /// Plain DNS over UDP.
+    Udp,
+    /// Plain DNS over TCP (RFC 7766).
+    Tcp,
+    /// DNS over TLS (RFC 7858).
+    Tls,
+    /// DNS over HTTPS (RFC 8484).
+    Https,
+    /// DNS over QUIC (RFC 9250).
+    Quic,
+}
+
+impl TransportKind {
+    /// Whether this transport encrypts queries.
+    pub fn is_encrypted(self) -> bool {
+        matches!(
+            self,
+            TransportKind::Tls | TransportKind::Https | TransportKind::Quic
+        )
+    }
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportKind::Udp => write!(f, "udp"),
+            TransportKind::Tcp => write!(f, "tcp"),
+            TransportKind::Tls => write!(f, "tls"),
+            TransportKind::Https => write!(f, "https"),
+            TransportKind::Quic => write!(f, "quic"),
+        }
+    }
+}
+
+impl FromStr for TransportKind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "udp" => Ok(TransportKind::Udp),
+            "tcp" => Ok(TransportKind::Tcp),
+            "tls" => Ok(TransportKind::Tls),
+            "https" => Ok(TransportKind::Https),
+            "quic" => Ok(TransportKind::Quic),
+            _ => Err(CANNOT_PARSE_TRANSPORT_KIND),
+        }
+    }
+}
+
+/// Which transports to try, in order, when querying an upstream nameserver
+/// which is given as an address.  Each is tried in turn until one gives a
+/// usable response, skipping any which can't be used (eg, UDP when the query
+/// is too big, or when there is a proxy).
+///
+/// Upstream nameservers given as an HTTPS URL are always queried over HTTPS.
+///
+/// An upstream nameserver with an encryption policy only uses the encrypted
+/// transports in the list (or TLS, if there are none), and, if the policy is
+/// opportunistic, then the unencrypted ones.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct FallbackPolicy {
+    transports: Vec<TransportKind>,
+}
+
+impl FallbackPolicy {
+    /// Try these transports in order.  If there are none, the default (UDP
+    /// then TCP) is used.
+    pub fn new(transports: Vec<TransportKind>) -> Self {
+        if transports.is_empty() {
+            Self::default()
+        } else {
+            Self { transports }
+        }
+    }
+
+    /// The transports to try for an upstream nameserver, in order.
+    pub fn transports_for(
+        &self,
+        upstream: &Upstream,
+        security: Option<&UpstreamSecurity>,
+    ) -> Vec<TransportKind> {
+        if let Upstream::Https(_) = upstream {
+            return vec![TransportKind::Https];
+        }
+        let Some(security) = security else {
+            return self.transports.clone();
+        };
+
+        let (mut transports, plaintext): (Vec<_>, Vec<_>) = self
+            .transports
+            .iter()
+            .partition(|transport| transport.is_encrypted());
+        if transports.is_empty() {
+            transports.push(TransportKind::Tls);
+        }
+        if security.encryption == EncryptionPolicy::Opportunistic {
+            transports.extend(plaintext);
+        }
+        transports
+    }
+}
+
+impl Default for FallbackPolicy {
+    fn default() -> Self {
+        Self {
+            transports: vec![TransportKind::Udp, TransportKind::Tcp],
+        }
+    }
+}
+
+impl fmt::Display for FallbackPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let transports = self
+            .transports
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        write!(f, "{}", transports.join(","))
+    }
+}
+
+pub const CANNOT_PARSE_ENCRYPTION_POLICY: &str = "expected one of 'strict', 'opportunistic'";
+
+/// Whether queries to an upstream nameserver must be encrypted.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum EncryptionPolicy {
+    /// Only use encrypted transports, and fail if none of them can be used or
+    /// the nameserver can't be authenticated.
+    Strict,
+    /// Use encrypted transports if possible, but fall back to unencrypted ones
+    /// if none of them can be used or the nameserver can't be authenticated.
+    Opportunistic,
+}
+
+impl fmt::Display for EncryptionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncryptionPolicy::Strict => write!(f, "strict"),
+            EncryptionPolicy::Opportunistic => write!(f, "opportunistic"),
+        }
+    }
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(EncryptionPolicy::Strict),
+            "opportunistic" => Ok(EncryptionPolicy::Opportunistic),
+            _ => Err(CANNOT_PARSE_ENCRYPTION_POLICY),
+        }
+    }
+}
+
+pub const CANNOT_PARSE_SPKI_PIN: &str =
+    "expected a base64-encoded SHA-256 hash, eg 'V5L96iSKnlMUuZG4kyHbt5wbgFiBTrgqbbE01twjW84='";
+
+/// The SHA-256 hash of a certificate's public key (its `SubjectPublicKeyInfo`),
+/// for pinning (see section 4.2 of RFC 7858).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct SpkiPin(pub [u8; 32]);
+
+impl fmt::Display for SpkiPin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", BASE64.encode(self.0))
+    }
+}
+
+impl FromStr for SpkiPin {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        BASE64
+            .decode(s)
+            .ok()
+            .and_then(|hash| hash.try_into().ok())
+            .map(SpkiPin)
+            .ok_or(CANNOT_PARSE_SPKI_PIN)
+    }
+}
+
+/// How to secure queries to an upstream nameserver.
+///
+/// The nameserver is authenticated by its certificate: if there are any pins,
+/// the certificate's public key must match one of them; otherwise the
+/// certificate must be issued by a trusted authority for `name` (or the
+/// nameserver's IP address, if there is no name).
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct UpstreamSecurity {
+    pub encryption: EncryptionPolicy,
+    pub name: Option<DomainName>,
+    pub pins: Vec<SpkiPin>,
+}
+
+pub const CANNOT_PARSE_UPSTREAM_SECURITY_RULE: &str =
+    "expected a rule of the form 'host=policy[,name=domain][,pin=hash]', eg '1.1.1.1=strict,name=cloudflare-dns.com.'";
+
+/// A rule giving the `UpstreamSecurity` for the upstream nameservers with an IP
+/// address or URL host.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct UpstreamSecurityRule {
+    pub host: String,
+    pub security: UpstreamSecurity,
+}
+
+impl fmt::Display for UpstreamSecurityRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.host, self.security.encryption)?;
+        if let Some(name) = &self.security.name {
+            write!(f, ",name={name}")?;
+        }
+        for pin in &self.security.pins {
+            write!(f, ",pin={pin}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The `UpstreamSecurity` rules for upstream nameservers, by IP address or URL
+/// host.
+///
+/// Invoking `clone` on an `UpstreamSecurityRules` gives a new instance which
+/// shares the rules until one of them is changed.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct UpstreamSecurityRules {
+    rules: Arc<HashMap<String, UpstreamSecurity>>,
+}
+
+impl UpstreamSecurityRules {
+    /// Add a rule, replacing any existing rule for the same host.
+    pub fn insert(&mut self, rule: UpstreamSecurityRule) {
+        Arc::make_mut(&mut self.rules).insert(rule.host, rule.security);
+    }
+
+    /// Find the rule for an upstream nameserver, if there is one.
+    pub fn get(&self, upstream: &Upstream) -> Option<&UpstreamSecurity> {
+        self.rules.get(&upstream.host())
+    }
+}
+
+impl FromStr for UpstreamSecurityRule {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((host, options)) = s.split_once('=') else {
+            return Err(CANNOT_PARSE_UPSTREAM_SECURITY_RULE);
+        };
+        if host.is_empty() {
+            return Err(CANNOT_PARSE_UPSTREAM_SECURITY_RULE);
+        }
+        let host = if let Ok(ip) = IpAddr::from_str(host) {
+            ip.to_string()
+        } else if let Some(name) =
+            DomainName::from_relative_dotted_string(&DomainName::root_domain(), host)
+        {
+            name.to_dotted_string()
+                .trim_end_matches('.')
+                .to_ascii_lowercase()
+        } else {
+            return Err(CANNOT_PARSE_UPSTREAM_SECURITY_RULE);
+        };
+
+        let mut options = options.split(',');
+        let encryption = options
+            .next()
+            .and_then(|policy| EncryptionPolicy::from_str(policy).ok())
+            .ok_or(CANNOT_PARSE_UPSTREAM_SECURITY_RULE)?;
+        let mut security = UpstreamSecurity {
+            encryption,
+            name: None,
+            pins: Vec::new(),
+        };
+        for option in options {
+            match option.split_once('=') {
+                Some(("name", name)) => {
+                    security.name = Some(
+                        DomainName::from_relative_dotted_string(&DomainName::root_domain(), name)
+                            .ok_or(CANNOT_PARSE_UPSTREAM_SECURITY_RULE)?,
+                    );
+                }
+                Some(("pin", pin)) => security.pins.push(SpkiPin::from_str(pin)?),
+                _ => return Err(CANNOT_PARSE_UPSTREAM_SECURITY_RULE),
+            }
+        }
+
+        Ok(UpstreamSecurityRule { host, security })
+    }
+}
+
+pub const CANNOT_PARSE_ANSWER_ROTATION: &str = "expected one of 'none', 'round-robin', 'random'";
+
+/// How to order multiple records with the same name and type in an answer, so
+/// that clients which use the first record are spread across all of them.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum AnswerRotation {
+    /// Keep the records in the order they were resolved in.
+    #[default]
+    None,
+    /// Rotate the records by one more place each time they are answered.
+    RoundRobin,
+    /// Shuffle the records.
+    Random,
+}
+
+impl AnswerRotation {
+    /// Reorder the records with the same name and type in an answer.  Records
+    /// only move between the positions taken up by records with their name and
+    /// type, so the order of the names and types themselves (eg, a CNAME and
+    /// then its target) is kept.
+    ///
+    /// For `RoundRobin`, `counts` says how many places to rotate each set of
+    /// records by, and is incremented for each set of records rotated.
+    pub fn apply(self, rrs: &mut [ResourceRecord], counts: &RotationCounts) {
+        if self == AnswerRotation::None {
+            return;
+        }
+
+        let mut rrsets: Vec<Vec<usize>> = Vec::new();
+        let mut rrset_indices = HashMap::new();
+        for (i, rr) in rrs.iter().enumerate() {
+            let key = (&rr.name, rr.rtype_with_data.rtype());
+            let rrset_index = *rrset_indices.entry(key).or_insert_with(|| {
+                rrsets.push(Vec::new());
+                rrsets.len() - 1
+            });
+            rrsets[rrset_index].push(i);
+        }
+
+        for positions in rrsets {
+            if positions.len() < 2 {
+                continue;
+            }
+
+            let mut rrset: Vec<ResourceRecord> =
+                positions.iter().map(|i| rrs[*i].clone()).collect();
+            match self {
+                AnswerRotation::None => (),
+                AnswerRotation::RoundRobin => {
+                    let count = counts.next(&rrset[0].name, rrset[0].rtype_with_data.rtype());
+                    rrset.rotate_left(count % positions.len());
+                }
+                AnswerRotation::Random => rrset.shuffle(&mut rand::thread_rng()),
+            }
+            for (i, rr) in positions.into_iter().zip(rrset) {
+                rrs[i] = rr;
+            }
+        }
+    }
+}
+
+/// The maximum number of sets of records `RotationCounts` keeps a count for.
+pub const MAX_ROTATION_COUNTS: usize = 4096;
+
+const MUTEX_POISON_MESSAGE: &str =
+    "[INTERNAL ERROR] rotation counts mutex poisoned, cannot recover from this - aborting";
+
+/// How many times each set of records with the same name and type has been
+/// rotated by `AnswerRotation::RoundRobin`, so that each one moves on by one
+/// place every time it is answered, no matter what else is answered in between.
+///
+/// If there are more than `MAX_ROTATION_COUNTS` sets of records, an arbitrary
+/// one is forgotten to make room, so it starts again from the order it was
+/// resolved in.
+#[derive(Debug, Clone, Default)]
+pub struct RotationCounts {
+    counts: Arc<Mutex<HashMap<(DomainName, RecordType), usize>>>,
+}
+
+impl RotationCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the count for a set of records, and then increment it.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn next(&self, name: &DomainName, rtype: RecordType) -> usize {
+        let mut counts = self.counts.lock().expect(MUTEX_POISON_MESSAGE);
+        let key = (name.clone(), rtype);
+        if counts.len() >= MAX_ROTATION_COUNTS && !counts.contains_key(&key) {
+            if let Some(evicted) = counts.keys().next().cloned() {
+                counts.remove(&evicted);
+            }
+        }
+        let count = counts.entry(key).or_insert(0);
+        let current = *count;
+        *count = count.wrapping_add(1);
+        current
+    }
+}
+
+impl fmt::Display for AnswerRotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AnswerRotation::None => write!(f, "none"),
+            AnswerRotation::RoundRobin => write!(f, "round-robin"),
+            AnswerRotation::Random => write!(f, "random"),
+        }
+    }
+}
+
+impl FromStr for AnswerRotation {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(AnswerRotation::None),
+            "round-robin" => Ok(AnswerRotation::RoundRobin),
+            "random" => Ok(AnswerRotation::Random),
+            _ => Err(CANNOT_PARSE_ANSWER_ROTATION),
+        }
+    }
+}
+
+pub const CANNOT_PARSE_CACHE_POLICY: &str = "expected one of 'lru', 'lfu', 'ttl'";
+
+/// How the cache chooses which records to prune when it has grown too big.
+/// Expired records are always pruned first.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum CachePolicy {
+    /// Prune the least recently used domains.
+    #[default]
+    Lru,
+    /// Prune the least frequently used domains.  This keeps popular records
+    /// cached even if lots of domains are only looked up once.
+    Lfu,
+    /// Prune the domains with the records which will expire soonest.
+    Ttl,
+}
+
+impl fmt::Display for CachePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CachePolicy::Lru => write!(f, "lru"),
+            CachePolicy::Lfu => write!(f, "lfu"),
+            CachePolicy::Ttl => write!(f, "ttl"),
+        }
+    }
+}
+
+impl FromStr for CachePolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(CachePolicy::Lru),
+            "lfu" => Ok(CachePolicy::Lfu),
+            "ttl" => Ok(CachePolicy::Ttl),
+            _ => Err(CANNOT_PARSE_CACHE_POLICY),
+        }
+    }
+}
+
+/// Bounds on the TTL of records.  If the minimum is above the maximum, the
+/// minimum wins.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct TtlLimits {
+    /// Records with a lower TTL are given this TTL instead.
+    pub min: Option<u32>,
+    /// Records with a higher TTL are given this TTL instead.
+    pub max: Option<u32>,
+}
+
+impl TtlLimits {
+    /// Bring a TTL within the limits.
+    pub fn clamp(self, ttl: u32) -> u32 {
+        let ttl = self.max.map_or(ttl, |max| ttl.min(max));
+        self.min.map_or(ttl, |min| ttl.max(min))
+    }
+
+    /// Bring the TTLs of some records within the limits.
+    pub fn clamp_all(self, rrs: &mut [ResourceRecord]) {
+        for rr in rrs {
+            rr.ttl = self.clamp(rr.ttl);
+        }
+    }
+}
+
+/// How long to wait for upstream nameservers.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Timeouts {
+    /// How long to wait for the response to each query sent to an upstream
+    /// nameserver.  A query which is retried over TCP gets this long again.
+    pub query: Duration,
+    /// How long to spend answering a question in total, including following
+    /// CNAMEs and delegations.  Upstream queries which would end after this
+    /// get less time.
+    pub resolution: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            query: Duration::from_secs(5),
+            resolution: Duration::from_mins(1),
+        }
+    }
+}
+
+/// Which local addresses to send queries to upstream nameservers from, for
+/// each address family.  If there isn't one for a family, the operating system
+/// picks, based on its routing table.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct OutboundAddresses {
+    pub v4: Option<Ipv4Addr>,
+    pub v6: Option<Ipv6Addr>,
+}
+
+impl OutboundAddresses {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send queries from this address, replacing the previous address of the
+    /// same family (if any).
+    pub fn set(&mut self, address: IpAddr) {
+        match address {
+            IpAddr::V4(address) => self.v4 = Some(address),
+            IpAddr::V6(address) => self.v6 = Some(address),
+        }
+    }
+
+    /// The local address to send queries to `remote` from, with port 0 so that
+    /// the operating system picks the port: this is the unspecified address if
+    /// there isn't one for the family.
+    pub fn local_address_for(&self, remote: SocketAddr) -> SocketAddr {
+        match remote {
+            SocketAddr::V4(_) => (self.v4.unwrap_or(Ipv4Addr::UNSPECIFIED), 0).into(),
+            SocketAddr::V6(_) => (self.v6.unwrap_or(Ipv6Addr::UNSPECIFIED), 0).into(),
+        }
+    }
+}
+
+impl FromIterator<IpAddr> for OutboundAddresses {
+    /// If there is more than one address of a family, the last is used.
+    fn from_iter<I: IntoIterator<Item = IpAddr>>(addresses: I) -> Self {
+        let mut outbound = Self::new();
+        for address in addresses {
+            outbound.set(address);
+        }
+        outbound
+    }
+}
+
+pub const CANNOT_PARSE_UPSTREAM_PROXY: &str =
+    "expected a proxy of the form 'socks5://ip:port' or 'http://ip:port'";
+
+/// A proxy to send queries to upstream nameservers through.
+///
+/// The proxy is given as an IP address rather than a hostname, since looking
+/// up its hostname would need a working resolver.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum UpstreamProxy {
+    /// A SOCKS5 proxy (RFC 1928), without authentication.
+    Socks5(SocketAddr),
+    /// An HTTP proxy which supports the `CONNECT` method.
+    Http(SocketAddr),
+}
+
+impl fmt::Display for UpstreamProxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpstreamProxy::Socks5(address) => write!(f, "socks5://{address}"),
+            UpstreamProxy::Http(address) => write!(f, "http://{address}"),
+        }
+    }
+}
+
+impl FromStr for UpstreamProxy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((scheme, address_str)) = s.split_once("://") else {
+            return Err(CANNOT_PARSE_UPSTREAM_PROXY);
+        };
+        let address = address_str
+            .trim_end_matches('/')
+            .parse()
+            .map_err(|_| CANNOT_PARSE_UPSTREAM_PROXY)?;
+
+        match scheme {
+            "socks5" => Ok(UpstreamProxy::Socks5(address)),
+            "http" => Ok(UpstreamProxy::Http(address)),
+            _ => Err(CANNOT_PARSE_UPSTREAM_PROXY),
+        }
+    }
+}
+
+pub const CANNOT_PARSE_HTTPS_URL: &str =
+    "expected an HTTPS URL, eg 'https://dns.example.com/dns-query'";
+
+/// The URL of a DNS-over-HTTPS nameserver (see RFC 8484).
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct HttpsUrl(http::Uri);
+
+impl HttpsUrl {
+    pub fn uri(&self) -> &http::Uri {
+        &self.0
+    }
+}
+
+impl fmt::Display for HttpsUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for HttpsUrl {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let uri = http::Uri::from_str(s).map_err(|_| CANNOT_PARSE_HTTPS_URL)?;
+        if uri.scheme() == Some(&http::uri::Scheme::HTTPS) && uri.host().is_some() {
+            Ok(Self(uri))
+        } else {
+            Err(CANNOT_PARSE_HTTPS_URL)
+        }
+    }
+}
+
+/// An upstream nameserver to forward queries to.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum Upstream {
+    /// A nameserver which is queried over UDP and TCP.
+    Address(SocketAddr),
+    /// A nameserver which is queried over HTTPS.
+    Https(HttpsUrl),
+}
+
+impl Upstream {
+    /// The IP address or URL host of the nameserver, as used to look up its
+    /// `UpstreamSecurity`.
+    pub fn host(&self) -> String {
+        match self {
+            Upstream::Address(address) => address.ip().to_string(),
+            Upstream::Https(url) => url
+                .uri()
+                .host()
+                .unwrap_or_default()
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_ascii_lowercase(),
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Upstream::Address(address) => write!(f, "{address}"),
+            Upstream::Https(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+impl From<SocketAddr> for Upstream {
+    fn from(address: SocketAddr) -> Self {
+        Upstream::Address(address)
+    }
+}
+
+impl From<HttpsUrl> for Upstream {
+    fn from(url: HttpsUrl) -> Self {
+        Upstream::Https(url)
+    }
+}
+
+/// How to reach upstream nameservers over the network.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct NetworkOptions {
+    /// Which local addresses to send queries from.
+    pub outbound: OutboundAddresses,
+    /// If set, every query is sent over TCP through this proxy, rather than
+    /// directly to the nameserver.  When there is also an outbound address,
+    /// the connection to the proxy is made from it.
+    pub proxy: Option<UpstreamProxy>,
+}
+
+/// Which upstream nameservers (if any) to forward queries to.
+///
+/// A query is forwarded to the nameservers of the most specific rule which
+/// matches its domain, falling back to the default nameservers if there is no
+/// matching rule.  If there are no default nameservers either, or the most
+/// specific matching rule says to recurse, the query is resolved recursively.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ForwardingRules {
+    pub strategy: ForwardingStrategy,
+    pub fallback: FallbackPolicy,
+    pub security: UpstreamSecurityRules,
+    default: Vec<Upstream>,
+    rules: HashMap<DomainName, Vec<Upstream>>,
+}
+
+impl ForwardingRules {
+    pub fn new(default: Vec<Upstream>, strategy: ForwardingStrategy) -> Self {
+        Self {
+            strategy,
+            fallback: FallbackPolicy::default(),
+            security: UpstreamSecurityRules::default(),
+            default,
+            rules: HashMap::new(),
+        }
+    }
+
+    /// Add a rule.  If there is already a rule for the same domain, this
+    /// nameserver is added after the existing ones.  A rule to recurse only
+    /// applies if there are no nameservers for the same domain.
+    pub fn insert(&mut self, rule: ForwardingRule) {
+        let upstreams = self.rules.entry(rule.domain).or_default();
+        if let Some(address) = rule.address {
+            upstreams.push(Upstream::Address(address));
+        }
+    }
+
+    /// Find the nameservers to forward a query for this domain to, if there
+    /// are any.
+    pub fn get(&self, name: &DomainName) -> Option<&[Upstream]> {
+        for i in 0..name.labels.len() {
+            let labels = &name.labels[i..];
+            if let Some(name) = DomainName::from_labels(labels.into()) {
+                if let Some(upstreams) = self.rules.get(&name) {
+                    return if upstreams.is_empty() {
+                        None
+                    } else {
+                        Some(upstreams)
+                    };
+                }
+            }
+        }
+
+        if self.default.is_empty() {
+            None
+        } else {
+            Some(&self.default)
+        }
+    }
+}
+
+/// Which domains recursive or forwarding resolution may be used for.  Any
+/// other domains can only be answered from local zones and the cache.
+///
+/// The most specific matching rule for a domain decides whether it is in
+/// scope.  If no rule matches, the domain is in scope unless there are some
+/// domains which recursion is explicitly allowed for.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RecursionScope {
+    rules: HashMap<DomainName, bool>,
+    has_allow_rules: bool,
+}
+
+impl RecursionScope {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow recursion for a domain and its subdomains.  Once there is at
+    /// least one allowed domain, recursion is disallowed for any domain which
+    /// doesn't match a rule.
+    pub fn allow(&mut self, domain: DomainName) {
+        self.rules.insert(domain, true);
+        self.has_allow_rules = true;
+    }
+
+    /// Disallow recursion for a domain and its subdomains.
+    pub fn deny(&mut self, domain: DomainName) {
+        self.rules.insert(domain, false);
+    }
+
+    /// Check if recursive or forwarding resolution may be used for this
+    /// domain.
+    pub fn allows(&self, name: &DomainName) -> bool {
+        for i in 0..name.labels.len() {
+            let labels = &name.labels[i..];
+            if let Some(name) = DomainName::from_labels(labels.into()) {
+                if let Some(allowed) = self.rules.get(&name) {
+                    return *allowed;
+                }
+            }
+        }
+
+        !self.has_allow_rules
+    }
+}
+
+pub const CANNOT_PARSE_LOCAL_ZONE_RULE: &str = "expected a rule of the form 'domain=policy', where the policy is one of 'nxdomain', 'nodata', 'refuse', or 'static:ip[,ip...]', eg 'ads.example.com.=nxdomain'";
+
+/// The TTL of records in answers given by a local-zone policy.
+pub const LOCAL_ZONE_TTL: u32 = 300;
+
+/// How to answer questions for a domain, and all of its subdomains, without
+/// consulting local zones, the cache, or upstream nameservers.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum LocalZonePolicy {
+    /// Answer with an authoritative name error.
+    NxDomain,
+    /// Answer with an authoritative empty answer.
+    NoData,
+    /// Refuse the question.
+    Refuse,
+    /// Answer `A` and `AAAA` questions with these addresses, and any other
+    /// questions with an authoritative empty answer.
+    Static(Vec<IpAddr>),
+}
+
+impl LocalZonePolicy {
+    /// Answer a question for a domain covered by this policy, where `domain`
+    /// is the domain the policy is for.
+    ///
+    /// The SOA record in the answer is synthesised for `domain`.
+    ///
+    /// # Errors
+    ///
+    /// If the policy is to refuse the question.
+    pub fn answer(
+        &self,
+        domain: &DomainName,
+        question: &Question,
+    ) -> Result<ResolvedRecord, ResolutionError> {
+        let soa_rr = SOA {
+            mname: domain.clone(),
+            rname: domain.clone(),
+            serial: 1,
+            refresh: LOCAL_ZONE_TTL,
+            retry: LOCAL_ZONE_TTL,
+            expire: LOCAL_ZONE_TTL,
+            minimum: LOCAL_ZONE_TTL,
+        }
+        .to_rr(domain);
+
+        match self {
+            LocalZonePolicy::NxDomain => Ok(ResolvedRecord::AuthoritativeNameError { soa_rr }),
+            LocalZonePolicy::NoData => Ok(ResolvedRecord::Authoritative {
+                rrs: Vec::new(),
+                soa_rr,
+            }),
+            LocalZonePolicy::Refuse => Err(ResolutionError::PolicyRefused {
+                question: question.clone(),
+            }),
+            LocalZonePolicy::Static(addresses) => {
+                let rrs = addresses
+                    .iter()
+                    .map(|address| ResourceRecord {
+                        name: question.name.clone(),
+                        rtype_with_data: match address {
+                            IpAddr::V4(address) => RecordTypeWithData::A { address: *address },
+                            IpAddr::V6(address) => RecordTypeWithData::AAAA { address: *address },
+                        },
+                        rclass: RecordClass::IN,
+                        ttl: LOCAL_ZONE_TTL,
+                    })
+                    .filter(|rr| rr.matches(question))
+                    .collect();
+                Ok(ResolvedRecord::Authoritative { rrs, soa_rr })
+            }
+        }
+    }
+}
+
+impl fmt::Display for LocalZonePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LocalZonePolicy::NxDomain => write!(f, "nxdomain"),
+            LocalZonePolicy::NoData => write!(f, "nodata"),
+            LocalZonePolicy::Refuse => write!(f, "refuse"),
+            LocalZonePolicy::Static(addresses) => {
+                write!(f, "static:")?;
+                for (i, address) in addresses.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{address}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FromStr for LocalZonePolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nxdomain" => Ok(LocalZonePolicy::NxDomain),
+            "nodata" => Ok(LocalZonePolicy::NoData),
+            "refuse" => Ok(LocalZonePolicy::Refuse),
+            _ => {
+                let Some(addresses_str) = s.strip_prefix("static:") else {
+                    return Err(CANNOT_PARSE_LOCAL_ZONE_RULE);
+                };
+                let mut addresses = Vec::new();
+                for address_str in addresses_str.split(',') {
+                    match IpAddr::from_str(address_str) {
+                        Ok(address) => addresses.push(address),
+                        Err(_) => return Err(CANNOT_PARSE_LOCAL_ZONE_RULE),
+                    }
+                }
+                Ok(LocalZonePolicy::Static(addresses))
+            }
+        }
+    }
+}
+
+pub const CANNOT_PARSE_BLOCKED_RESPONSE: &str = "expected one of 'address', 'nxdomain', 'nodata'";
+
+/// How to answer `A` and `AAAA` questions for blocked domains: those which a
+/// zone maps to the unspecified address (`0.0.0.0` or `::`).
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum BlockedResponse {
+    /// Answer with the unspecified address, as given in the zone.
+    #[default]
+    Address,
+    /// Answer with an authoritative name error.
+    NxDomain,
+    /// Answer with an authoritative empty answer.
+    NoData,
+}
+
+impl BlockedResponse {
+    /// The local-zone policy to answer blocked domains with, if the answer
+    /// from the zone shouldn't be used as-is.
+    pub fn policy(self) -> Option<LocalZonePolicy> {
+        match self {
+            BlockedResponse::Address => None,
+            BlockedResponse::NxDomain => Some(LocalZonePolicy::NxDomain),
+            BlockedResponse::NoData => Some(LocalZonePolicy::NoData),
+        }
+    }
+}
+
+impl fmt::Display for BlockedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockedResponse::Address => write!(f, "address"),
+            BlockedResponse::NxDomain => write!(f, "nxdomain"),
+            BlockedResponse::NoData => write!(f, "nodata"),
+        }
+    }
+}
+
+impl FromStr for BlockedResponse {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "address" => Ok(BlockedResponse::Address),
+            "nxdomain" => Ok(BlockedResponse::NxDomain),
+            "nodata" => Ok(BlockedResponse::NoData),
+            _ => Err(CANNOT_PARSE_BLOCKED_RESPONSE),
+        }
+    }
+}
+
+/// A local-zone policy for a domain, and all of its subdomains.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct LocalZoneRule {
+    pub domain: DomainName,
+    pub policy: LocalZonePolicy,
+}
+
+impl fmt::Display for LocalZoneRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.domain, self.policy)
+    }
+}
+
+impl FromStr for LocalZoneRule {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((domain_str, policy_str)) = s.split_once('=') else {
+            return Err(CANNOT_PARSE_LOCAL_ZONE_RULE);
+        };
+
+        match (
+            DomainName::from_str(domain_str),
+            LocalZonePolicy::from_str(policy_str),
+        ) {
+            (Ok(domain), Ok(policy)) => Ok(LocalZoneRule { domain, policy }),
+            _ => Err(CANNOT_PARSE_LOCAL_ZONE_RULE),
+        }
+    }
+}
+
+/// Which domains are answered by a local-zone policy, rather than by the
+/// normal resolution process.
+///
+/// The most specific matching rule for a domain decides its policy.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LocalZonePolicies {
+    rules: HashMap<DomainName, LocalZonePolicy>,
+}
+
+impl LocalZonePolicies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule.  If there is already a rule for the same domain, it is
+    /// replaced.
+    pub fn insert(&mut self, rule: LocalZoneRule) {
+        self.rules.insert(rule.domain, rule.policy);
+    }
+
+    /// Find the policy for this domain, if there is one, along with the domain
+    /// the policy is for.
+    pub fn get(&self, name: &DomainName) -> Option<(DomainName, &LocalZonePolicy)> {
+        if self.rules.is_empty() {
+            return None;
+        }
+
+        for i in 0..name.labels.len() {
+            let labels = &name.labels[i..];
+            if let Some(name) = DomainName::from_labels(labels.into()) {
+                if let Some(policy) = self.rules.get(&name) {
+                    return Some((name, policy));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+pub const CANNOT_PARSE_ZONE_PRECEDENCE: &str = "expected one of 'merge', 'override', 'exclusive'";
+
+pub const CANNOT_PARSE_ZONE_PRECEDENCE_RULE: &str = "expected a rule of the form 'domain=precedence', where the precedence is one of 'merge', 'override', or 'exclusive', eg 'lan.=exclusive'";
+
+/// How the records in a non-authoritative zone are combined with records from
+/// the cache and upstream nameservers.  Authoritative zones are always the only
+/// source of answers for the names in them.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum ZonePrecedence {
+    /// Records from the zone override records of the same type from elsewhere,
+    /// but the answer to a wildcard query also includes records of other types
+    /// from the cache, and a type or name which is not in the zone is resolved
+    /// as normal.
+    #[default]
+    Merge,
+    /// A name which is in the zone is answered only from the zone: a wildcard
+    /// query gets just the zone's records, and a type which is not in the zone
+    /// gets an empty answer.  A name which is not in the zone is resolved as
+    /// normal.
+    Override,
+    /// Every name under the zone's apex is answered only from the zone: a name
+    /// which is not in the zone gets a name error, rather than being resolved
+    /// as normal.
+    Exclusive,
+}
+
+impl fmt::Display for ZonePrecedence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZonePrecedence::Merge => write!(f, "merge"),
+            ZonePrecedence::Override => write!(f, "override"),
+            ZonePrecedence::Exclusive => write!(f, "exclusive"),
+        }
+    }
+}
+
+impl FromStr for ZonePrecedence {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "merge" => Ok(ZonePrecedence::Merge),
+            "override" => Ok(ZonePrecedence::Override),
+            "exclusive" => Ok(ZonePrecedence::Exclusive),
+            _ => Err(CANNOT_PARSE_ZONE_PRECEDENCE),
+        }
+    }
+}
+
+/// The zone precedence for a domain, and all of its subdomains.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ZonePrecedenceRule {
+    pub domain: DomainName,
+    pub precedence: ZonePrecedence,
+}
+
+impl fmt::Display for ZonePrecedenceRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.domain, self.precedence)
+    }
+}
+
+impl FromStr for ZonePrecedenceRule {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((domain_str, precedence_str)) = s.split_once('=') else {
+            return Err(CANNOT_PARSE_ZONE_PRECEDENCE_RULE);
+        };
+
+        match (
+            DomainName::from_str(domain_str),
+            ZonePrecedence::from_str(precedence_str),
+        ) {
+            (Ok(domain), Ok(precedence)) => Ok(ZonePrecedenceRule { domain, precedence }),
+            _ => Err(CANNOT_PARSE_ZONE_PRECEDENCE_RULE),
+        }
+    }
+}
+
+/// The zone precedence for the names in non-authoritative zones.
+///
+/// The most specific matching rule for a domain decides its precedence, and
+/// domains which no rule matches use the default.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ZonePrecedenceRules {
+    pub default: ZonePrecedence,
+    rules: HashMap<DomainName, ZonePrecedence>,
+}
+
+impl ZonePrecedenceRules {
+    pub fn new(default: ZonePrecedence) -> Self {
+        Self {
+            default,
+            rules: HashMap::new(),
+        }
+    }
+
+    /// Add a rule.  If there is already a rule for the same domain, it is
+    /// replaced.
+    pub fn insert(&mut self, rule: ZonePrecedenceRule) {
+        self.rules.insert(rule.domain, rule.precedence);
+    }
+
+    /// Find the precedence for this domain, along with the domain of the rule
+    /// which gave it, if it isn't the default.
+    pub fn get(&self, name: &DomainName) -> (ZonePrecedence, Option<DomainName>) {
+        if self.rules.is_empty() {
+            return (self.default, None);
+        }
+
+        for i in 0..name.labels.len() {
+            let labels = &name.labels[i..];
+            if let Some(name) = DomainName::from_labels(labels.into()) {
+                if let Some(precedence) = self.rules.get(&name) {
+                    return (*precedence, Some(name));
+                }
+            }
+        }
+
+        (self.default, None)
+    }
+}
+
+/// Domains which are exempt from being blocked.  An answer from a zone which
+/// would block one of these domains, or one of their subdomains, is ignored,
+/// and the domain is resolved as if it weren't in the zone.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Allowlist {
+    domains: HashSet<DomainName>,
+}
+
+impl Allowlist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Exempt a domain and its subdomains from being blocked.
+    pub fn insert(&mut self, domain: DomainName) {
+        self.domains.insert(domain);
+    }
+
+    /// Check if this domain is exempt from being blocked.
+    pub fn contains(&self, name: &DomainName) -> bool {
+        if self.domains.is_empty() {
+            return false;
+        }
+
+        for i in 0..name.labels.len() {
+            let labels = &name.labels[i..];
+            if let Some(name) = DomainName::from_labels(labels.into()) {
+                if self.domains.contains(&name) {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+}
+
 /// The result of a name resolution attempt.
 ///
 /// If this is a `CNAME`, it should be added to the answer section of
@@ -98,8 +1387,21 @@ pub enum ResolutionError {
     RecursionLimit,
     /// Tried to resolve a question while resolving the same question.
     DuplicateQuestion { question: Question },
+    /// Followed a CNAME back to a question already being resolved: the
+    /// question is where the loop closes.
+    CnameLoop { question: Question },
     /// Was unable to resolve a necessary record.
     DeadEnd { question: Question },
+    /// Could not answer a question from local zones or the cache, and
+    /// recursive or forwarding resolution is not allowed for it.
+    RecursionNotAllowed { question: Question },
+    /// A local-zone policy says to refuse a question.
+    PolicyRefused { question: Question },
+    /// An upstream nameserver could not be used to answer a question.
+    Upstream {
+        question: Question,
+        error: UpstreamError,
+    },
     /// Configuration error: a local zone delegates without defining NS records.
     LocalDelegationMissingNS {
         apex: DomainName,
@@ -119,7 +1421,11 @@ impl std::fmt::Display for ResolutionError {
             ResolutionError::Timeout => write!(f, "timed out"),
             ResolutionError::RecursionLimit => write!(f, "CNAME chain too long"),
             ResolutionError::DuplicateQuestion{question} => write!(f, "loop when answering '{} {} {}'", question.name, question.qclass, question.qtype),
+            ResolutionError::CnameLoop{question} => write!(f, "CNAME loop at '{} {} {}'", question.name, question.qclass, question.qtype),
             ResolutionError::DeadEnd{question} => write!(f, "unable to answer '{} {} {}'", question.name, question.qclass, question.qtype),
+            ResolutionError::RecursionNotAllowed{question} => write!(f, "unable to answer '{} {} {}' without recursion", question.name, question.qclass, question.qtype),
+            ResolutionError::PolicyRefused{question} => write!(f, "refused to answer '{} {} {}' by local-zone policy", question.name, question.qclass, question.qtype),
+            ResolutionError::Upstream{question, error} => write!(f, "upstream nameserver {error} when answering '{} {} {}'", question.name, question.qclass, question.qtype),
             ResolutionError::LocalDelegationMissingNS{apex,domain} => write!(f, "configuration error: got delegation for domain '{domain}' from zone '{apex}', but there are no NS records"),
             ResolutionError::CacheTypeMismatch{query,result} => write!(f, "internal error (bug): tried to fetch '{query}' from cache but got '{result}' instead"),
         }
@@ -132,6 +1438,62 @@ impl std::error::Error for ResolutionError {
     }
 }
 
+/// Why an upstream nameserver could not be used.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum UpstreamError {
+    /// The nameserver did not respond in time.
+    Timeout,
+    /// Could not connect to, send to, or receive from the nameserver.
+    Unreachable,
+    /// The nameserver responded with `REFUSED`.
+    Refused,
+    /// The nameserver responded with `FORMERR`: it couldn't make sense of the
+    /// query.
+    FormatError,
+    /// The nameserver was delegated a zone it isn't authoritative for: it
+    /// gave a response without the `AA` bit which was neither an answer nor a
+    /// referral closer to the question (a lame delegation).
+    NotAuthoritative,
+    /// The nameserver responded with `SERVFAIL`, or some other error.
+    ServerFailure,
+    /// The response could not be parsed, did not match the request, or did
+    /// not make sense.
+    InvalidResponse,
+}
+
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            UpstreamError::Timeout => write!(f, "timed out"),
+            UpstreamError::Unreachable => write!(f, "unreachable"),
+            UpstreamError::Refused => write!(f, "refused the query"),
+            UpstreamError::FormatError => write!(f, "could not parse the query"),
+            UpstreamError::NotAuthoritative => write!(f, "is not authoritative for the zone"),
+            UpstreamError::ServerFailure => write!(f, "failed"),
+            UpstreamError::InvalidResponse => write!(f, "gave an invalid response"),
+        }
+    }
+}
+
+impl UpstreamError {
+    /// Whether this means the nameserver is lame: it responded, but won't or
+    /// can't answer questions about the zone it was delegated.  The recursive
+    /// resolver holds these nameservers down for a while, rather than asking
+    /// them again.
+    pub fn is_lame(self) -> bool {
+        matches!(
+            self,
+            UpstreamError::Refused | UpstreamError::FormatError | UpstreamError::NotAuthoritative
+        )
+    }
+}
+
+impl std::error::Error for UpstreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
 /// A set of nameservers for a domain
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct Nameservers {
@@ -192,14 +1554,47 @@ pub fn prioritising_merge(priority: &mut Vec<ResourceRecord>, new: Vec<ResourceR
     }
 }
 
+/// Reduce the answer to an `ANY` question to a single set of records, as
+/// described in RFC 8482: the records with the same type as the first record
+/// for the question name.  Records for other names are dropped.  If there are
+/// no records for the question name, the answer is left as it is.
+pub fn minimise_any_answer(name: &DomainName, rrs: &mut Vec<ResourceRecord>) {
+    if let Some(rtype) = rrs
+        .iter()
+        .find(|rr| rr.name == *name)
+        .map(|rr| rr.rtype_with_data.rtype())
+    {
+        rrs.retain(|rr| rr.name == *name && rr.rtype_with_data.rtype() == rtype);
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::net::Ipv4Addr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
     use dns_types::protocol::types::test_util::*;
 
     use super::*;
 
+    #[test]
+    fn ttl_limits_clamp() {
+        let limits = TtlLimits {
+            min: Some(60),
+            max: Some(3600),
+        };
+        assert_eq!(60, limits.clamp(5));
+        assert_eq!(300, limits.clamp(300));
+        assert_eq!(3600, limits.clamp(604_800));
+
+        assert_eq!(5, TtlLimits::default().clamp(5));
+
+        let inverted = TtlLimits {
+            min: Some(3600),
+            max: Some(60),
+        };
+        assert_eq!(3600, inverted.clamp(300));
+    }
+
     #[test]
     fn prioritised_merge_prioritises_by_name_and_type() {
         let mut priority = vec![
@@ -230,4 +1625,655 @@ mod tests {
 
         assert_eq!(expected, priority);
     }
+
+    #[test]
+    fn minimise_any_answer_keeps_first_rrset() {
+        let mut rrs = vec![
+            ns_record("example.com.", "ns1.example.com."),
+            a_record("example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+            ns_record("example.com.", "ns2.example.com."),
+            a_record("ns1.example.com.", Ipv4Addr::new(2, 2, 2, 2)),
+        ];
+        minimise_any_answer(&domain("example.com."), &mut rrs);
+
+        assert_eq!(
+            vec![
+                ns_record("example.com.", "ns1.example.com."),
+                ns_record("example.com.", "ns2.example.com."),
+            ],
+            rrs
+        );
+    }
+
+    #[test]
+    fn minimise_any_answer_ignores_other_names() {
+        let mut rrs = vec![
+            a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+            cname_record("example.com.", "www.example.com."),
+        ];
+        minimise_any_answer(&domain("example.com."), &mut rrs);
+
+        assert_eq!(vec![cname_record("example.com.", "www.example.com.")], rrs);
+    }
+
+    #[test]
+    fn minimise_any_answer_leaves_unrelated_answer() {
+        let mut rrs = vec![a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1))];
+        minimise_any_answer(&domain("example.com."), &mut rrs);
+
+        assert_eq!(
+            vec![a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1))],
+            rrs
+        );
+    }
+
+    #[test]
+    fn answer_rotation_round_robin_rotates_each_rrset() {
+        let rrs = vec![
+            cname_record("www.example.com.", "target.example.com."),
+            a_record("target.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+            a_record("target.example.com.", Ipv4Addr::new(2, 2, 2, 2)),
+            a_record("target.example.com.", Ipv4Addr::new(3, 3, 3, 3)),
+        ];
+        let counts = RotationCounts::new();
+
+        let mut rotated = rrs.clone();
+        AnswerRotation::RoundRobin.apply(&mut rotated, &counts);
+        assert_eq!(rrs, rotated);
+
+        let mut rotated = rrs.clone();
+        AnswerRotation::RoundRobin.apply(&mut rotated, &counts);
+        assert_eq!(
+            vec![
+                cname_record("www.example.com.", "target.example.com."),
+                a_record("target.example.com.", Ipv4Addr::new(2, 2, 2, 2)),
+                a_record("target.example.com.", Ipv4Addr::new(3, 3, 3, 3)),
+                a_record("target.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+            ],
+            rotated
+        );
+
+        let mut rotated = rrs.clone();
+        AnswerRotation::RoundRobin.apply(&mut rotated, &counts);
+        let mut rotated = rrs.clone();
+        AnswerRotation::RoundRobin.apply(&mut rotated, &counts);
+        assert_eq!(rrs, rotated);
+    }
+
+    #[test]
+    fn answer_rotation_round_robin_counts_each_rrset_separately() {
+        let www = vec![
+            a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+            a_record("www.example.com.", Ipv4Addr::new(2, 2, 2, 2)),
+        ];
+        let mail = vec![
+            a_record("mail.example.com.", Ipv4Addr::new(3, 3, 3, 3)),
+            a_record("mail.example.com.", Ipv4Addr::new(4, 4, 4, 4)),
+        ];
+        let counts = RotationCounts::new();
+
+        let mut rotated = www.clone();
+        AnswerRotation::RoundRobin.apply(&mut rotated, &counts);
+        assert_eq!(www, rotated);
+
+        let mut rotated = mail.clone();
+        AnswerRotation::RoundRobin.apply(&mut rotated, &counts);
+        assert_eq!(mail, rotated);
+
+        let mut rotated = www.clone();
+        AnswerRotation::RoundRobin.apply(&mut rotated, &counts);
+        assert_eq!(vec![www[1].clone(), www[0].clone()], rotated);
+
+        let mut rotated = mail.clone();
+        AnswerRotation::RoundRobin.apply(&mut rotated, &counts);
+        assert_eq!(vec![mail[1].clone(), mail[0].clone()], rotated);
+    }
+
+    #[test]
+    fn rotation_counts_evicts_one_when_full() {
+        let counts = RotationCounts::new();
+        for i in 0..MAX_ROTATION_COUNTS {
+            let name = domain(&format!("host-{i}.example.com."));
+            assert_eq!(0, counts.next(&name, RecordType::A));
+            assert_eq!(1, counts.next(&name, RecordType::A));
+        }
+
+        assert_eq!(
+            0,
+            counts.next(&domain("another.example.com."), RecordType::A)
+        );
+
+        let counts = counts.counts.lock().unwrap();
+        assert_eq!(MAX_ROTATION_COUNTS, counts.len());
+        assert_eq!(
+            MAX_ROTATION_COUNTS - 1,
+            counts.values().filter(|count| **count == 2).count()
+        );
+    }
+
+    #[test]
+    fn answer_rotation_keeps_rrset_positions() {
+        let rrs = vec![
+            a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+            aaaa_record("www.example.com.", Ipv6Addr::LOCALHOST),
+            a_record("www.example.com.", Ipv4Addr::new(2, 2, 2, 2)),
+            aaaa_record("www.example.com.", Ipv6Addr::UNSPECIFIED),
+        ];
+        let counts = RotationCounts::new();
+        counts.next(&domain("www.example.com."), RecordType::A);
+        counts.next(&domain("www.example.com."), RecordType::AAAA);
+
+        let mut rotated = rrs.clone();
+        AnswerRotation::RoundRobin.apply(&mut rotated, &counts);
+        assert_eq!(
+            vec![
+                a_record("www.example.com.", Ipv4Addr::new(2, 2, 2, 2)),
+                aaaa_record("www.example.com.", Ipv6Addr::UNSPECIFIED),
+                a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1)),
+                aaaa_record("www.example.com.", Ipv6Addr::LOCALHOST),
+            ],
+            rotated
+        );
+
+        for _ in 0..100 {
+            let mut shuffled = rrs.clone();
+            AnswerRotation::Random.apply(&mut shuffled, &counts);
+            for (rr, shuffled_rr) in rrs.iter().zip(&shuffled) {
+                assert_eq!(
+                    rr.rtype_with_data.rtype(),
+                    shuffled_rr.rtype_with_data.rtype()
+                );
+            }
+        }
+
+        let mut unchanged = rrs.clone();
+        AnswerRotation::None.apply(&mut unchanged, &counts);
+        assert_eq!(rrs, unchanged);
+    }
+
+    #[test]
+    fn forwarding_rule_from_str() {
+        assert_eq!(
+            Ok(ForwardingRule {
+                domain: domain("corp.example.com."),
+                address: Some("10.0.0.1:53".parse().unwrap()),
+            }),
+            "corp.example.com.=10.0.0.1:53".parse()
+        );
+        assert_eq!(
+            Ok(ForwardingRule {
+                domain: domain("corp.example.com."),
+                address: Some("[::1]:5353".parse().unwrap()),
+            }),
+            "corp.example.com.=[::1]:5353".parse()
+        );
+        assert_eq!(
+            Ok(ForwardingRule {
+                domain: domain("corp.example.com."),
+                address: None,
+            }),
+            "corp.example.com.=recurse".parse()
+        );
+
+        assert!(ForwardingRule::from_str("corp.example.com.").is_err());
+        assert!(ForwardingRule::from_str("corp.example.com.=10.0.0.1").is_err());
+    }
+
+    #[test]
+    fn upstream_proxy_parse() {
+        assert_eq!(
+            Ok(UpstreamProxy::Socks5("127.0.0.1:9050".parse().unwrap())),
+            "socks5://127.0.0.1:9050".parse()
+        );
+        assert_eq!(
+            Ok(UpstreamProxy::Http("[::1]:3128".parse().unwrap())),
+            "http://[::1]:3128/".parse()
+        );
+
+        assert!(UpstreamProxy::from_str("127.0.0.1:9050").is_err());
+        assert!(UpstreamProxy::from_str("socks4://127.0.0.1:9050").is_err());
+        assert!(UpstreamProxy::from_str("socks5://localhost:9050").is_err());
+    }
+
+    #[test]
+    fn outbound_addresses_local_address_for_matches_family() {
+        let outbound = [
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)),
+        ]
+        .into_iter()
+        .collect::<OutboundAddresses>();
+
+        assert_eq!(
+            "192.0.2.2:0".parse::<SocketAddr>().unwrap(),
+            outbound.local_address_for("198.51.100.1:53".parse().unwrap())
+        );
+        assert_eq!(
+            "[::]:0".parse::<SocketAddr>().unwrap(),
+            outbound.local_address_for("[2001:db8::1]:53".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn forwarding_rules_get_prefers_most_specific() {
+        let default: SocketAddr = "1.1.1.1:53".parse().unwrap();
+        let corp = "10.0.0.1:53".parse().unwrap();
+        let lab = "10.0.0.2:53".parse().unwrap();
+
+        let mut rules =
+            ForwardingRules::new(vec![Upstream::from(default)], ForwardingStrategy::Failover);
+        rules.insert(ForwardingRule {
+            domain: domain("corp.example.com."),
+            address: Some(corp),
+        });
+        rules.insert(ForwardingRule {
+            domain: domain("lab.corp.example.com."),
+            address: Some(lab),
+        });
+
+        assert_eq!(
+            Some(&[Upstream::from(default)][..]),
+            rules.get(&domain("example.com."))
+        );
+        assert_eq!(
+            Some(&[Upstream::from(corp)][..]),
+            rules.get(&domain("corp.example.com."))
+        );
+        assert_eq!(
+            Some(&[Upstream::from(corp)][..]),
+            rules.get(&domain("www.corp.example.com."))
+        );
+        assert_eq!(
+            Some(&[Upstream::from(lab)][..]),
+            rules.get(&domain("lab.corp.example.com."))
+        );
+        assert_eq!(
+            Some(&[Upstream::from(lab)][..]),
+            rules.get(&domain("www.lab.corp.example.com."))
+        );
+    }
+
+    #[test]
+    fn forwarding_rules_get_falls_back_to_recursive() {
+        let corp = "10.0.0.1:53".parse().unwrap();
+
+        let mut rules = ForwardingRules::new(Vec::new(), ForwardingStrategy::Failover);
+        rules.insert(ForwardingRule {
+            domain: domain("corp.example.com."),
+            address: Some(corp),
+        });
+
+        assert_eq!(None, rules.get(&domain("example.com.")));
+        assert_eq!(
+            Some(&[Upstream::from(corp)][..]),
+            rules.get(&domain("www.corp.example.com."))
+        );
+    }
+
+    #[test]
+    fn forwarding_rules_insert_keeps_order() {
+        let corp1 = "10.0.0.1:53".parse().unwrap();
+        let corp2 = "10.0.0.2:53".parse().unwrap();
+
+        let mut rules = ForwardingRules::new(Vec::new(), ForwardingStrategy::Failover);
+        rules.insert(ForwardingRule {
+            domain: domain("corp.example.com."),
+            address: Some(corp1),
+        });
+        rules.insert(ForwardingRule {
+            domain: domain("corp.example.com."),
+            address: Some(corp2),
+        });
+
+        assert_eq!(
+            Some(&[Upstream::from(corp1), Upstream::from(corp2)][..]),
+            rules.get(&domain("www.corp.example.com."))
+        );
+    }
+
+    #[test]
+    fn forwarding_rules_get_recurses_for_rule() {
+        let default: SocketAddr = "1.1.1.1:53".parse().unwrap();
+        let corp = "10.0.0.1:53".parse().unwrap();
+
+        let mut rules =
+            ForwardingRules::new(vec![Upstream::from(default)], ForwardingStrategy::Failover);
+        rules.insert(ForwardingRule {
+            domain: domain("internal.example.com."),
+            address: None,
+        });
+        rules.insert(ForwardingRule {
+            domain: domain("corp.internal.example.com."),
+            address: Some(corp),
+        });
+
+        assert_eq!(
+            Some(&[Upstream::from(default)][..]),
+            rules.get(&domain("www.example.com."))
+        );
+        assert_eq!(None, rules.get(&domain("www.internal.example.com.")));
+        assert_eq!(
+            Some(&[Upstream::from(corp)][..]),
+            rules.get(&domain("www.corp.internal.example.com."))
+        );
+    }
+
+    #[test]
+    fn https_url_from_str() {
+        let url = HttpsUrl::from_str("https://dns.example.com/dns-query").unwrap();
+        assert_eq!(Some("dns.example.com"), url.uri().host());
+        assert_eq!("https://dns.example.com/dns-query", url.to_string());
+
+        assert!(HttpsUrl::from_str("http://dns.example.com/dns-query").is_err());
+        assert!(HttpsUrl::from_str("dns.example.com").is_err());
+        assert!(HttpsUrl::from_str("1.1.1.1:53").is_err());
+    }
+
+    #[test]
+    fn fallback_policy_transports_for() {
+        let policy = FallbackPolicy::new(vec![TransportKind::Tls, TransportKind::Tcp]);
+        let address = Upstream::from(SocketAddr::from((Ipv4Addr::new(1, 1, 1, 1), 53)));
+        let url = Upstream::from(HttpsUrl::from_str("https://1.1.1.1/dns-query").unwrap());
+
+        assert_eq!(
+            vec![TransportKind::Tls, TransportKind::Tcp],
+            policy.transports_for(&address, None)
+        );
+        assert_eq!(
+            vec![TransportKind::Https],
+            policy.transports_for(&url, None)
+        );
+        assert_eq!(
+            vec![TransportKind::Udp, TransportKind::Tcp],
+            FallbackPolicy::new(Vec::new()).transports_for(&address, None)
+        );
+    }
+
+    #[test]
+    fn fallback_policy_transports_for_strict() {
+        let address = Upstream::from(SocketAddr::from((Ipv4Addr::new(1, 1, 1, 1), 53)));
+        let security = UpstreamSecurity {
+            encryption: EncryptionPolicy::Strict,
+            name: None,
+            pins: Vec::new(),
+        };
+
+        assert_eq!(
+            vec![TransportKind::Quic, TransportKind::Tls],
+            FallbackPolicy::new(vec![
+                TransportKind::Udp,
+                TransportKind::Quic,
+                TransportKind::Tcp,
+                TransportKind::Tls
+            ])
+            .transports_for(&address, Some(&security))
+        );
+        assert_eq!(
+            vec![TransportKind::Tls],
+            FallbackPolicy::default().transports_for(&address, Some(&security))
+        );
+    }
+
+    #[test]
+    fn fallback_policy_transports_for_opportunistic() {
+        let address = Upstream::from(SocketAddr::from((Ipv4Addr::new(1, 1, 1, 1), 53)));
+        let security = UpstreamSecurity {
+            encryption: EncryptionPolicy::Opportunistic,
+            name: None,
+            pins: Vec::new(),
+        };
+
+        assert_eq!(
+            vec![TransportKind::Quic, TransportKind::Udp, TransportKind::Tcp],
+            FallbackPolicy::new(vec![
+                TransportKind::Udp,
+                TransportKind::Quic,
+                TransportKind::Tcp
+            ])
+            .transports_for(&address, Some(&security))
+        );
+        assert_eq!(
+            vec![TransportKind::Tls, TransportKind::Udp, TransportKind::Tcp],
+            FallbackPolicy::default().transports_for(&address, Some(&security))
+        );
+    }
+
+    #[test]
+    fn upstream_security_rule_roundtrip() {
+        for s in [
+            "1.1.1.1=strict",
+            "2606:4700:4700::1111=opportunistic,name=cloudflare-dns.com.",
+            "dns.example.com=strict,pin=V5L96iSKnlMUuZG4kyHbt5wbgFiBTrgqbbE01twjW84=",
+        ] {
+            let rule = UpstreamSecurityRule::from_str(s).unwrap();
+            assert_eq!(s, rule.to_string());
+        }
+    }
+
+    #[test]
+    fn upstream_security_rule_rejects_bad_options() {
+        for s in [
+            "1.1.1.1",
+            "=strict",
+            "1.1.1.1=secure",
+            "1.1.1.1=strict,port=853",
+            "1.1.1.1=strict,pin=abc",
+        ] {
+            assert!(UpstreamSecurityRule::from_str(s).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn upstream_security_rules_match_url_host() {
+        let mut rules = UpstreamSecurityRules::default();
+        rules.insert(UpstreamSecurityRule::from_str("DNS.Example.com=strict").unwrap());
+        rules.insert(UpstreamSecurityRule::from_str("::1=opportunistic").unwrap());
+
+        let url = Upstream::from(HttpsUrl::from_str("https://dns.example.com/dns-query").unwrap());
+        let address = Upstream::from(SocketAddr::from((Ipv6Addr::LOCALHOST, 53)));
+        let other = Upstream::from(SocketAddr::from((Ipv4Addr::LOCALHOST, 53)));
+
+        assert_eq!(
+            Some(EncryptionPolicy::Strict),
+            rules.get(&url).map(|security| security.encryption)
+        );
+        assert_eq!(
+            Some(EncryptionPolicy::Opportunistic),
+            rules.get(&address).map(|security| security.encryption)
+        );
+        assert_eq!(None, rules.get(&other));
+    }
+
+    #[test]
+    fn spki_pin_roundtrip() {
+        let pin = SpkiPin([7; 32]);
+        assert_eq!(Ok(pin), SpkiPin::from_str(&pin.to_string()));
+        assert!(SpkiPin::from_str("AAAA").is_err());
+    }
+
+    #[test]
+    fn allowlist_contains_subdomains() {
+        let mut allowlist = Allowlist::new();
+        allowlist.insert(domain("example.com."));
+
+        assert!(allowlist.contains(&domain("example.com.")));
+        assert!(allowlist.contains(&domain("www.example.com.")));
+        assert!(!allowlist.contains(&domain("com.")));
+        assert!(!allowlist.contains(&domain("example.net.")));
+    }
+
+    #[test]
+    fn recursion_scope_allows_everything_by_default() {
+        let scope = RecursionScope::new();
+
+        assert!(scope.allows(&domain(".")));
+        assert!(scope.allows(&domain("www.example.com.")));
+    }
+
+    #[test]
+    fn recursion_scope_deny() {
+        let mut scope = RecursionScope::new();
+        scope.deny(domain("lan."));
+
+        assert!(scope.allows(&domain("www.example.com.")));
+        assert!(!scope.allows(&domain("lan.")));
+        assert!(!scope.allows(&domain("nas.lan.")));
+    }
+
+    #[test]
+    fn recursion_scope_allow() {
+        let mut scope = RecursionScope::new();
+        scope.allow(domain("example.com."));
+
+        assert!(!scope.allows(&domain("example.net.")));
+        assert!(scope.allows(&domain("example.com.")));
+        assert!(scope.allows(&domain("www.example.com.")));
+    }
+
+    #[test]
+    fn recursion_scope_prefers_most_specific() {
+        let mut scope = RecursionScope::new();
+        scope.allow(domain("example.com."));
+        scope.deny(domain("internal.example.com."));
+        scope.allow(domain("public.internal.example.com."));
+
+        assert!(scope.allows(&domain("www.example.com.")));
+        assert!(!scope.allows(&domain("www.internal.example.com.")));
+        assert!(scope.allows(&domain("www.public.internal.example.com.")));
+    }
+
+    #[test]
+    fn local_zone_rule_from_str() {
+        assert_eq!(
+            Ok(LocalZoneRule {
+                domain: domain("ads.example.com."),
+                policy: LocalZonePolicy::NxDomain,
+            }),
+            "ads.example.com.=nxdomain".parse()
+        );
+        assert_eq!(
+            Ok(LocalZoneRule {
+                domain: domain("example.com."),
+                policy: LocalZonePolicy::Static(vec![
+                    "10.0.0.1".parse().unwrap(),
+                    "::1".parse().unwrap()
+                ]),
+            }),
+            "example.com.=static:10.0.0.1,::1".parse()
+        );
+
+        assert!(LocalZoneRule::from_str("example.com.").is_err());
+        assert!(LocalZoneRule::from_str("example.com.=nonsense").is_err());
+        assert!(LocalZoneRule::from_str("example.com.=static:").is_err());
+    }
+
+    #[test]
+    fn zone_precedence_rule_from_str() {
+        assert_eq!(
+            Ok(ZonePrecedenceRule {
+                domain: domain("lan."),
+                precedence: ZonePrecedence::Exclusive,
+            }),
+            "lan.=exclusive".parse()
+        );
+
+        assert!(ZonePrecedenceRule::from_str("lan.").is_err());
+        assert!(ZonePrecedenceRule::from_str("lan.=nonsense").is_err());
+    }
+
+    #[test]
+    fn zone_precedence_rules_get_prefers_most_specific() {
+        let mut rules = ZonePrecedenceRules::new(ZonePrecedence::Override);
+        rules.insert(ZonePrecedenceRule {
+            domain: domain("lan."),
+            precedence: ZonePrecedence::Exclusive,
+        });
+        rules.insert(ZonePrecedenceRule {
+            domain: domain("guest.lan."),
+            precedence: ZonePrecedence::Merge,
+        });
+
+        assert_eq!(
+            (ZonePrecedence::Exclusive, Some(domain("lan."))),
+            rules.get(&domain("lan."))
+        );
+        assert_eq!(
+            (ZonePrecedence::Exclusive, Some(domain("lan."))),
+            rules.get(&domain("nas.lan."))
+        );
+        assert_eq!(
+            (ZonePrecedence::Merge, Some(domain("guest.lan."))),
+            rules.get(&domain("pc.guest.lan."))
+        );
+        assert_eq!(
+            (ZonePrecedence::Override, None),
+            rules.get(&domain("example.com."))
+        );
+    }
+
+    #[test]
+    fn local_zone_policies_get_prefers_most_specific() {
+        let mut policies = LocalZonePolicies::new();
+        policies.insert(LocalZoneRule {
+            domain: domain("example.com."),
+            policy: LocalZonePolicy::NxDomain,
+        });
+        policies.insert(LocalZoneRule {
+            domain: domain("www.example.com."),
+            policy: LocalZonePolicy::Refuse,
+        });
+
+        assert_eq!(None, policies.get(&domain("example.net.")));
+        assert_eq!(
+            Some((domain("example.com."), &LocalZonePolicy::NxDomain)),
+            policies.get(&domain("foo.example.com."))
+        );
+        assert_eq!(
+            Some((domain("www.example.com."), &LocalZonePolicy::Refuse)),
+            policies.get(&domain("a.www.example.com."))
+        );
+    }
+
+    #[test]
+    fn local_zone_policy_answer() {
+        let apex = domain("example.com.");
+        let question = |qtype| Question {
+            name: domain("www.example.com."),
+            qtype,
+            qclass: QueryClass::Record(RecordClass::IN),
+        };
+
+        assert!(matches!(
+            LocalZonePolicy::NxDomain.answer(&apex, &question(QueryType::Record(RecordType::A))),
+            Ok(ResolvedRecord::AuthoritativeNameError { soa_rr }) if soa_rr.name == apex
+        ));
+        assert!(matches!(
+            LocalZonePolicy::Refuse.answer(&apex, &question(QueryType::Record(RecordType::A))),
+            Err(ResolutionError::PolicyRefused { .. })
+        ));
+
+        let policy =
+            LocalZonePolicy::Static(vec!["10.0.0.1".parse().unwrap(), "::1".parse().unwrap()]);
+        assert_eq!(
+            Ok(vec![a_record(
+                "www.example.com.",
+                Ipv4Addr::new(10, 0, 0, 1)
+            )]),
+            policy
+                .answer(&apex, &question(QueryType::Record(RecordType::A)))
+                .map(ResolvedRecord::rrs)
+        );
+        assert_eq!(
+            Ok(vec![aaaa_record("www.example.com.", Ipv6Addr::LOCALHOST)]),
+            policy
+                .answer(&apex, &question(QueryType::Record(RecordType::AAAA)))
+                .map(ResolvedRecord::rrs)
+        );
+        assert_eq!(
+            Ok(Vec::new()),
+            policy
+                .answer(&apex, &question(QueryType::Record(RecordType::MX)))
+                .map(ResolvedRecord::rrs)
+        );
+    }
 }
@@ -0,0 +1,41 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use std::fmt::Write;
+
+use dns_types::hosts::types::Hosts;
+
+/// A blocklist-like hosts file with a million names, many sharing labels.
+fn hosts_data(lines: usize) -> String {
+    let mut data = String::with_capacity(lines * 40);
+    data.push_str("# a large blocklist\n");
+    for i in 0..lines {
+        writeln!(
+            data,
+            "0.0.0.0 ads{i}.tracker{}.example{}.com",
+            i % 1000,
+            i % 10
+        )
+        .unwrap();
+    }
+    data
+}
+
+fn bench_deserialise(c: &mut Criterion) {
+    let data = hosts_data(1_000_000);
+
+    let mut group = c.benchmark_group("hosts");
+    group.sample_size(10);
+    group.bench_function("deserialise/1M lines", |b| {
+        b.iter(|| Hosts::deserialise(&data).unwrap());
+    });
+    group.bench_function("deserialise_reader/1M lines", |b| {
+        b.iter_batched(
+            || data.as_bytes(),
+            |reader| Hosts::deserialise_reader(reader).unwrap().unwrap(),
+            BatchSize::SmallInput,
+        );
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_deserialise);
+criterion_main!(benches);
@@ -1,7 +1,7 @@
-use std::collections::HashSet;
-
-use std::net::{IpAddr, Ipv4Addr};
-use std::str::FromStr;
+use bytes::{Bytes, BytesMut};
+use std::io::{self, BufRead};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
 
 use crate::hosts::types::*;
 use crate::protocol::types::*;
@@ -13,111 +13,347 @@ impl Hosts {
     ///
     /// If the string cannot be parsed.
     pub fn deserialise(data: &str) -> Result<Self, Error> {
-        let mut hosts = Self::new();
-        for line in data.lines() {
-            if let Some((address, new_names)) = parse_line(line)? {
-                for name in new_names {
-                    match address {
-                        IpAddr::V4(ip) => {
-                            hosts.v4.insert(name, ip);
-                        }
-                        IpAddr::V6(ip) => {
-                            hosts.v6.insert(name, ip);
-                        }
-                    }
-                }
+        let mut parser = Parser::default();
+        for line in data.as_bytes().split(|octet| *octet == b'\n') {
+            parser.insert_line(line)?;
+        }
+        Ok(parser.finish())
+    }
+
+    /// Parse hosts data from a reader a line at a time, without holding all
+    /// of it in memory.
+    ///
+    /// # Errors
+    ///
+    /// If the data cannot be read (the outer `Result`) or cannot be parsed
+    /// (the inner `Result`).
+    pub fn deserialise_reader<R: BufRead>(mut reader: R) -> io::Result<Result<Self, Error>> {
+        let mut parser = Parser::default();
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                return Ok(Ok(parser.finish()));
+            }
+            if let Err(error) = parser.insert_line(&line) {
+                return Ok(Err(error));
             }
         }
-        Ok(hosts)
     }
 }
 
-/// Parse a single line.
-///
-/// # Errors
+/// A byte-oriented hosts file parser.
 ///
-/// If the string cannot be parsed.
-fn parse_line(line: &str) -> Result<Option<(IpAddr, HashSet<DomainName>)>, Error> {
-    let mut state = State::SkipToAddress;
-    let mut address = IpAddr::V4(Ipv4Addr::LOCALHOST);
-    let mut new_names = HashSet::new();
-
-    for (i, octet) in line.chars().enumerate() {
-        if !octet.is_ascii() {
-            return Err(Error::ExpectedAscii { octet });
-        }
-
-        state = match (&state, octet) {
-            (_, '#') => State::CommentToEndOfLine,
-            (State::CommentToEndOfLine, _) => break,
-
-            (State::SkipToAddress, c) if c.is_whitespace() => state,
-            (State::SkipToAddress, _) => State::ReadingAddress { start: i },
-
-            (State::ReadingAddress { .. }, '%') => break,
-            (State::ReadingAddress { start }, c) if c.is_whitespace() => {
-                let addr_str = &line[*start..i];
-                match IpAddr::from_str(addr_str) {
-                    Ok(addr) => address = addr,
-                    Err(_) => {
-                        return Err(Error::CouldNotParseAddress {
-                            address: addr_str.into(),
-                        })
-                    }
-                }
-                State::SkipToName
-            }
-            (State::ReadingAddress { .. }, _) => state,
+/// Blocklists can have hundreds of thousands of lines, so this avoids
+/// allocating where it can: all the labels on a line share one buffer, and the
+/// entries are collected before building the `Hosts`, so that its maps are
+/// allocated once at the right size rather than growing (and rehashing every
+/// name) as lines are parsed.
+#[derive(Debug, Default)]
+struct Parser {
+    v4: Vec<(DomainName, Ipv4Addr)>,
+    v6: Vec<(DomainName, Ipv6Addr)>,
+    /// IPv6 addresses which had a zone index, like `fe80::1%lo0`.
+    v6_scoped: Vec<(DomainName, Ipv6Addr)>,
+    blocked_subtrees: Vec<DomainName>,
+    /// The number of lines parsed so far.
+    lines: usize,
+}
 
-            (State::SkipToName, c) if c.is_whitespace() => state,
-            (State::SkipToName, _) => State::ReadingName { start: i },
+impl Parser {
+    /// Parse a single line and add its names to the hosts.
+    ///
+    /// # Errors
+    ///
+    /// If the line cannot be parsed.  The error has the line number, and the
+    /// column of the field which could not be parsed.
+    fn insert_line(&mut self, line: &[u8]) -> Result<(), Error> {
+        self.lines += 1;
+        self.try_insert_line(line).map_err(|error| Error::At {
+            line: self.lines,
+            column: error_column(line, &error),
+            error: Box::new(error),
+        })
+    }
 
-            (State::ReadingName { start }, c) if c.is_whitespace() => {
-                let name_str = &line[*start..i];
-                match DomainName::from_relative_dotted_string(&DomainName::root_domain(), name_str)
-                {
-                    Some(name) => {
-                        new_names.insert(name);
-                    }
-                    None => {
-                        return Err(Error::CouldNotParseName {
-                            name: name_str.into(),
-                        })
-                    }
+    /// Parse a single line and add its names to the hosts.
+    ///
+    /// # Errors
+    ///
+    /// If the line cannot be parsed.
+    fn try_insert_line(&mut self, line: &[u8]) -> Result<(), Error> {
+        #[cfg(feature = "idna")]
+        let converted = unicode_names_to_ascii(line);
+        #[cfg(feature = "idna")]
+        let line = converted.as_deref().unwrap_or(line);
+
+        if let Some(name) = parse_blocked_subtree_line(line)? {
+            self.blocked_subtrees.push(name);
+        } else if let Some((address, is_scoped, new_names)) = parse_line(line)? {
+            let entries = new_names.into_iter();
+            match address {
+                IpAddr::V4(ip) => self.v4.extend(entries.map(|name| (name, ip))),
+                IpAddr::V6(ip) if is_scoped => {
+                    self.v6_scoped.extend(entries.map(|name| (name, ip)));
                 }
-                State::SkipToName
+                IpAddr::V6(ip) => self.v6.extend(entries.map(|name| (name, ip))),
             }
-            (State::ReadingName { .. }, _) => state,
         }
+        Ok(())
+    }
+
+    /// Build the `Hosts`.  If a name appears more than once, the last one
+    /// wins, except that an IPv6 address which had a zone index only gets
+    /// used if the name has no other IPv6 address.
+    fn finish(self) -> Hosts {
+        let mut hosts = Hosts::new();
+        hosts.v4.reserve(self.v4.len());
+        hosts.v4.extend(self.v4);
+        hosts.v6.reserve(self.v6.len() + self.v6_scoped.len());
+        hosts.v6.extend(self.v6_scoped);
+        hosts.v6.extend(self.v6);
+        hosts.blocked_subtrees.extend(self.blocked_subtrees);
+        hosts
     }
+}
+
+/// Parse a line which blocks a name and all of its subdomains, written either
+/// as `*.example.com` or, as in Adblock-style lists, as `||example.com^`.
+/// Returns `None` if the line is not in either form.
+///
+/// # Errors
+///
+/// If the line is in one of the forms but the name cannot be parsed.
+fn parse_blocked_subtree_line(line: &[u8]) -> Result<Option<DomainName>, Error> {
+    let line = strip_comment(line).trim_ascii();
 
-    if let State::ReadingName { start } = state {
-        let name_str = &line[start..];
-        match DomainName::from_relative_dotted_string(&DomainName::root_domain(), name_str) {
-            Some(name) => {
-                new_names.insert(name);
+    let name_octets = if let Some(rest) = line.strip_prefix(b"||") {
+        match rest.strip_suffix(b"^") {
+            Some(name_octets) => name_octets,
+            None => {
+                return Err(Error::CouldNotParseName {
+                    name: String::from_utf8_lossy(line).into(),
+                })
             }
+        }
+    } else if let Some(name_octets) = line.strip_prefix(b"*.") {
+        name_octets
+    } else {
+        return Ok(None);
+    };
+
+    if let Some(i) = line.iter().position(|octet| !octet.is_ascii()) {
+        return Err(Error::ExpectedAscii {
+            octet: first_char(&line[i..]),
+        });
+    }
+
+    let mut buf = BytesMut::from(name_octets);
+    buf.make_ascii_lowercase();
+    let buf = buf.freeze();
+    match parse_name(&buf, 0..buf.len()) {
+        Some(name) if !name_octets.iter().any(u8::is_ascii_whitespace) => Ok(Some(name)),
+        _ => Err(Error::CouldNotParseName {
+            name: String::from_utf8_lossy(line).into(),
+        }),
+    }
+}
+
+/// Parse a single line.  Everything after a `#` is a comment, as is a line
+/// starting with a `!` (as in Adblock-style lists).
+///
+/// An IPv6 address can have a zone index, like `fe80::1%lo0`, which is
+/// dropped, since a DNS record can't say which interface to use.  Whether the
+/// address had one is returned along with it.
+///
+/// # Errors
+///
+/// If the line cannot be parsed.
+fn parse_line(line: &[u8]) -> Result<Option<(IpAddr, bool, Vec<DomainName>)>, Error> {
+    let line = strip_comment(line);
+    if line.trim_ascii_start().starts_with(b"!") {
+        return Ok(None);
+    }
+
+    if let Some(i) = line.iter().position(|octet| !octet.is_ascii()) {
+        return Err(Error::ExpectedAscii {
+            octet: first_char(&line[i..]),
+        });
+    }
+
+    let mut fields = line
+        .split(|octet| (*octet as char).is_whitespace())
+        .filter(|field| !field.is_empty());
+
+    let Some(address_octets) = fields.next() else {
+        return Ok(None);
+    };
+
+    // copy all the names into one lowercase buffer, which the labels are
+    // slices of
+    let names_octets: Vec<&[u8]> = fields.collect();
+    if names_octets.is_empty() {
+        return Ok(None);
+    }
+    let mut buf = BytesMut::with_capacity(names_octets.iter().map(|name| name.len()).sum());
+    let mut ranges = Vec::with_capacity(names_octets.len());
+    for name_octets in &names_octets {
+        let start = buf.len();
+        buf.extend_from_slice(name_octets);
+        ranges.push(start..buf.len());
+    }
+    buf.make_ascii_lowercase();
+    let buf = buf.freeze();
+
+    let mut new_names = Vec::with_capacity(ranges.len());
+    for (name_octets, range) in names_octets.iter().zip(ranges) {
+        match parse_name(&buf, range) {
+            Some(name) => new_names.push(name),
             None => {
                 return Err(Error::CouldNotParseName {
-                    name: name_str.into(),
+                    name: String::from_utf8_lossy(name_octets).into(),
                 })
             }
         }
     }
 
-    if new_names.is_empty() {
-        Ok(None)
-    } else {
-        Ok(Some((address, new_names)))
+    // safe because the line is ASCII
+    let address_str = std::str::from_utf8(address_octets).unwrap();
+    let parsed = match address_str.split_once('%') {
+        Some((unscoped, zone_index)) if !zone_index.is_empty() => unscoped
+            .parse::<Ipv6Addr>()
+            .map(|address| (IpAddr::V6(address), true)),
+        _ => address_str.parse().map(|address| (address, false)),
+    };
+    match parsed {
+        Ok((address, is_scoped)) => Ok(Some((address, is_scoped, new_names))),
+        Err(_) => Err(Error::CouldNotParseAddress {
+            address: address_str.into(),
+        }),
     }
 }
 
+/// Convert the Unicode names on a line to A-labels, dropping any comment.
+/// Returns `None` if the line is ASCII, isn't UTF-8, or has a name which is
+/// not valid IDNA, in which case the line should be parsed as it is (and
+/// rejected if it is not ASCII).
+#[cfg(feature = "idna")]
+fn unicode_names_to_ascii(line: &[u8]) -> Option<Vec<u8>> {
+    let line = strip_comment(line);
+    if line.is_ascii() {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(line.len());
+    for field in std::str::from_utf8(line).ok()?.split_ascii_whitespace() {
+        if !out.is_empty() {
+            out.push(b' ');
+        }
+        // keep the Adblock-style `||` and `^` out of the name
+        let (prefix, name, suffix) = match field.strip_prefix("||") {
+            Some(rest) => match rest.strip_suffix('^') {
+                Some(name) => ("||", name, "^"),
+                None => ("||", rest, ""),
+            },
+            None => ("", field, ""),
+        };
+        out.extend_from_slice(prefix.as_bytes());
+        out.extend_from_slice(unicode_to_ascii(name)?.as_bytes());
+        out.extend_from_slice(suffix.as_bytes());
+    }
+
+    Some(out)
+}
+
+/// Find the column (starting from 1) of the part of a line which an error is
+/// about: the non-ASCII octet, the address, or the name.
+fn error_column(line: &[u8], error: &Error) -> usize {
+    let offset = match error {
+        Error::ExpectedAscii { .. } => line.iter().position(|octet| !octet.is_ascii()),
+        Error::CouldNotParseName { name } => fields(line)
+            .skip(1)
+            .find(|(_, field)| *field == name.as_bytes())
+            .map(|(offset, _)| offset),
+        _ => None,
+    };
+    // otherwise the error is about the whole line, or its first field
+    offset
+        .or_else(|| fields(line).next().map(|(offset, _)| offset))
+        .unwrap_or(0)
+        + 1
+}
+
+/// Split a line into whitespace-separated fields, with their offsets.
+fn fields(line: &[u8]) -> impl Iterator<Item = (usize, &[u8])> {
+    let mut offset = 0;
+    line.split(|octet| (*octet as char).is_whitespace())
+        .map(move |field| {
+            let start = offset;
+            offset += field.len() + 1;
+            (start, field)
+        })
+        .filter(|(_, field)| !field.is_empty())
+}
+
+/// Remove everything after a `#`.
+fn strip_comment(line: &[u8]) -> &[u8] {
+    match line.iter().position(|octet| *octet == b'#') {
+        Some(i) => &line[..i],
+        None => line,
+    }
+}
+
+/// Parse a name from part of a lowercase buffer.  Names are always relative to
+/// the root domain.
+fn parse_name(buf: &Bytes, range: Range<usize>) -> Option<DomainName> {
+    let mut end = range.end;
+    if buf[range.clone()].ends_with(b".") {
+        end -= 1;
+    }
+
+    let mut labels = Vec::new();
+    if range.start < end {
+        let mut start = range.start;
+        for label_octets in buf[range.start..end].split(|octet| *octet == b'.') {
+            if label_octets.is_empty() {
+                return None;
+            }
+            let label_end = start + label_octets.len();
+            labels.push(Label::from_octets(buf.slice(start..label_end))?);
+            start = label_end + 1;
+        }
+    }
+    labels.push(Label::new());
+
+    DomainName::from_labels(labels)
+}
+
+/// Decode the first character of some (possibly invalid) UTF-8, for error
+/// messages.
+fn first_char(octets: &[u8]) -> char {
+    String::from_utf8_lossy(&octets[..octets.len().min(4)])
+        .chars()
+        .next()
+        .unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
 /// An error that can occur reading a hosts file.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
-    ExpectedAscii { octet: char },
-    CouldNotParseAddress { address: String },
-    CouldNotParseName { name: String },
+    ExpectedAscii {
+        octet: char,
+    },
+    CouldNotParseAddress {
+        address: String,
+    },
+    CouldNotParseName {
+        name: String,
+    },
+    At {
+        line: usize,
+        column: usize,
+        error: Box<Error>,
+    },
 }
 
 impl std::fmt::Display for Error {
@@ -130,6 +366,11 @@ impl std::fmt::Display for Error {
             Error::CouldNotParseName { name } => {
                 write!(f, "could not parse domain name '{name:?}'")
             }
+            Error::At {
+                line,
+                column,
+                error,
+            } => write!(f, "line {line}, column {column}: {error}"),
         }
     }
 }
@@ -140,18 +381,9 @@ impl std::error::Error for Error {
     }
 }
 
-/// States for the line parser
-enum State {
-    SkipToAddress,
-    ReadingAddress { start: usize },
-    SkipToName,
-    ReadingName { start: usize },
-    CommentToEndOfLine,
-}
-
 #[cfg(test)]
 mod tests {
-    use std::net::Ipv6Addr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
     use super::*;
 
@@ -174,17 +406,20 @@ mod tests {
             ("two.", Ipv4Addr::new(1, 2, 3, 4)),
             ("three.", Ipv4Addr::new(1, 2, 3, 4)),
             ("four.", Ipv4Addr::new(1, 2, 3, 4)),
-            ("blocked.", Ipv4Addr::new(0, 0, 0, 0)),
-            ("localhost.", Ipv4Addr::new(127, 0, 0, 1)),
+            ("blocked.", Ipv4Addr::UNSPECIFIED),
+            ("localhost.", Ipv4Addr::LOCALHOST),
         ];
 
-        let expected_aaaa_records = &[("localhost.", Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))];
+        let expected_aaaa_records = &[("localhost.", Ipv6Addr::LOCALHOST)];
 
         for (name, addr) in expected_a_records {
             let mut rr = a_record(name, *addr);
             rr.ttl = TTL;
             assert_eq!(
-                Some(ZoneResult::Answer { rrs: vec![rr] }),
+                Some(ZoneResult::Answer {
+                    rrs: vec![rr],
+                    wildcard: false
+                }),
                 Zone::from(hosts.clone()).resolve(&domain(name), QueryType::Record(RecordType::A))
             );
         }
@@ -193,7 +428,10 @@ mod tests {
             let mut rr = aaaa_record(name, *addr);
             rr.ttl = TTL;
             assert_eq!(
-                Some(ZoneResult::Answer { rrs: vec![rr] }),
+                Some(ZoneResult::Answer {
+                    rrs: vec![rr],
+                    wildcard: false
+                }),
                 Zone::from(hosts.clone())
                     .resolve(&domain(name), QueryType::Record(RecordType::AAAA))
             );
@@ -201,17 +439,55 @@ mod tests {
     }
 
     #[test]
-    fn parse_line_ignores_iface_address() {
-        assert_eq!(Ok(None), parse_line("fe80::1%lo0 localhost"));
+    fn parse_line_drops_zone_index() {
+        assert_eq!(
+            Ok(Some((
+                IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1)),
+                true,
+                vec![domain("localhost.")]
+            ))),
+            parse_line(b"fe80::1%lo0 localhost")
+        );
+    }
+
+    #[test]
+    fn parse_line_rejects_zone_index_on_ipv4() {
+        assert_eq!(
+            Err(Error::CouldNotParseAddress {
+                address: "1.2.3.4%eth0".into()
+            }),
+            parse_line(b"1.2.3.4%eth0 foo")
+        );
+    }
+
+    #[test]
+    fn scoped_address_does_not_replace_unscoped() {
+        for hosts_data in [
+            "::1 localhost\nfe80::1%lo0 localhost",
+            "fe80::1%lo0 localhost\n::1 localhost",
+        ] {
+            let hosts = Hosts::deserialise(hosts_data).unwrap();
+            assert_eq!(
+                Some(&Ipv6Addr::LOCALHOST),
+                hosts.v6.get(&domain("localhost."))
+            );
+        }
+
+        let hosts = Hosts::deserialise("fe80::1%lo0 router").unwrap();
+        assert_eq!(
+            Some(&Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1)),
+            hosts.v6.get(&domain("router."))
+        );
     }
 
     #[test]
     fn parse_line_parses_ipv4_with_names() {
-        if let Ok(parsed) = parse_line("1.2.3.4 foo bar") {
+        if let Ok(parsed) = parse_line(b"1.2.3.4 foo bar") {
             assert_eq!(
                 Some((
                     IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
-                    [domain("foo."), domain("bar.")].into_iter().collect()
+                    false,
+                    vec![domain("foo."), domain("bar.")]
                 )),
                 parsed
             );
@@ -222,7 +498,7 @@ mod tests {
 
     #[test]
     fn parse_line_parses_ipv4_without_names() {
-        if let Ok(parsed) = parse_line("1.2.3.4") {
+        if let Ok(parsed) = parse_line(b"1.2.3.4") {
             assert_eq!(None, parsed);
         } else {
             panic!("unexpected parse failure");
@@ -231,11 +507,12 @@ mod tests {
 
     #[test]
     fn parse_line_parses_ipv6_with_names() {
-        if let Ok(parsed) = parse_line("::1:2:3 foo bar") {
+        if let Ok(parsed) = parse_line(b"::1:2:3 foo bar") {
             assert_eq!(
                 Some((
                     IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 1, 2, 3)),
-                    [domain("foo."), domain("bar.")].into_iter().collect()
+                    false,
+                    vec![domain("foo."), domain("bar.")]
                 )),
                 parsed
             );
@@ -246,10 +523,208 @@ mod tests {
 
     #[test]
     fn parse_line_parses_ipv6_without_names() {
-        if let Ok(parsed) = parse_line("::1") {
+        if let Ok(parsed) = parse_line(b"::1") {
             assert_eq!(None, parsed);
         } else {
             panic!("unexpected parse failure");
         }
     }
+
+    #[test]
+    fn parse_line_ignores_comments() {
+        assert_eq!(Ok(None), parse_line(b"# 1.2.3.4 foo \xc3\xa9"));
+        assert_eq!(
+            Ok(Some((
+                IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+                false,
+                vec![domain("foo.")]
+            ))),
+            parse_line(b"1.2.3.4 foo# bar")
+        );
+    }
+
+    #[test]
+    fn parse_line_rejects_non_ascii() {
+        assert_eq!(
+            Err(Error::ExpectedAscii { octet: '\u{e9}' }),
+            parse_line("1.2.3.4 caf\u{e9}".as_bytes())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "idna")]
+    fn deserialise_converts_unicode_names() {
+        let hosts = Hosts::deserialise(
+            "1.2.3.4 B\u{fc}cher.example # caf\u{e9}\n||b\u{fc}cher.test^\n*.b\u{fc}cher.invalid",
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(&Ipv4Addr::new(1, 2, 3, 4)),
+            hosts.v4.get(&domain("xn--bcher-kva.example."))
+        );
+        assert!(hosts
+            .blocked_subtrees
+            .contains(&domain("xn--bcher-kva.test.")));
+        assert!(hosts
+            .blocked_subtrees
+            .contains(&domain("xn--bcher-kva.invalid.")));
+    }
+
+    #[test]
+    fn parse_line_rejects_bad_names() {
+        for name in ["foo..bar", "..", ".foo", &"x".repeat(64)] {
+            assert_eq!(
+                Err(Error::CouldNotParseName { name: name.into() }),
+                parse_line(format!("1.2.3.4 {name}").as_bytes())
+            );
+        }
+    }
+
+    #[test]
+    fn parse_line_rejects_bad_address() {
+        assert_eq!(
+            Err(Error::CouldNotParseAddress {
+                address: "1.2.3".into()
+            }),
+            parse_line(b"1.2.3 foo")
+        );
+    }
+
+    #[test]
+    fn parse_line_is_case_insensitive() {
+        assert_eq!(
+            Ok(Some((
+                IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+                false,
+                vec![domain("www.example.com."), domain("www.example.com.")]
+            ))),
+            parse_line(b"1.2.3.4 www.example.com WWW.Example.COM.")
+        );
+    }
+
+    #[test]
+    fn serialise_roundtrip() {
+        for _ in 0..100 {
+            let expected = crate::hosts::types::test_util::arbitrary_hosts();
+            assert_eq!(
+                Ok(expected.clone()),
+                Hosts::deserialise(&expected.serialise())
+            );
+        }
+    }
+
+    #[test]
+    fn parse_blocked_subtree_line_parses_both_forms() {
+        assert_eq!(
+            Ok(Some(domain("ads.example.com."))),
+            parse_blocked_subtree_line(b"||ads.example.com^")
+        );
+        assert_eq!(
+            Ok(Some(domain("tracker.net."))),
+            parse_blocked_subtree_line(b"  *.Tracker.NET.  # comment")
+        );
+    }
+
+    #[test]
+    fn parse_blocked_subtree_line_ignores_other_lines() {
+        assert_eq!(Ok(None), parse_blocked_subtree_line(b"1.2.3.4 foo"));
+        assert_eq!(Ok(None), parse_blocked_subtree_line(b"# *.foo"));
+        assert_eq!(Ok(None), parse_blocked_subtree_line(b""));
+    }
+
+    #[test]
+    fn parse_blocked_subtree_line_rejects_bad_names() {
+        for line in ["||foo", "||foo^$third-party", "*.foo bar", "*.foo..bar"] {
+            assert_eq!(
+                Err(Error::CouldNotParseName { name: line.into() }),
+                parse_blocked_subtree_line(line.as_bytes())
+            );
+        }
+    }
+
+    #[test]
+    fn parse_line_ignores_adblock_comments() {
+        assert_eq!(Ok(None), parse_line(b"! Title: some list"));
+    }
+
+    #[test]
+    fn deserialise_blocks_subtrees() {
+        let hosts = Hosts::deserialise("||ads.example.com^\n*.tracker.net").unwrap();
+
+        for zone in [Zone::from(hosts.clone()), hosts.into_compact_zone()] {
+            for name in [
+                "ads.example.com.",
+                "foo.bar.ads.example.com.",
+                "tracker.net.",
+            ] {
+                if let Some(ZoneResult::Answer { rrs, .. }) =
+                    zone.resolve(&domain(name), QueryType::Record(RecordType::A))
+                {
+                    assert_eq!(
+                        vec![BLOCKED_A],
+                        rrs.into_iter()
+                            .map(|rr| rr.rtype_with_data)
+                            .collect::<Vec<_>>()
+                    );
+                } else {
+                    panic!("expected {name} to be blocked");
+                }
+            }
+
+            // the parent domain is an empty non-terminal, it isn't blocked
+            assert_eq!(
+                Some(ZoneResult::Answer {
+                    rrs: Vec::new(),
+                    wildcard: false
+                }),
+                zone.resolve(&domain("example.com."), QueryType::Record(RecordType::A))
+            );
+        }
+    }
+
+    #[test]
+    fn deserialise_reader_matches_deserialise() {
+        let hosts_data = "1.2.3.4 one two\r\n::1 localhost\n0.0.0.0 blocked";
+
+        assert_eq!(
+            Hosts::deserialise(hosts_data).unwrap(),
+            Hosts::deserialise_reader(hosts_data.as_bytes())
+                .unwrap()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn deserialise_error_position() {
+        let hosts_data = "# comment\n1.2.3.4 one two\n\n  1.2.3.4 three fo..ur five\n";
+
+        let expected = Err(Error::At {
+            line: 4,
+            column: 17,
+            error: Box::new(Error::CouldNotParseName {
+                name: "fo..ur".into(),
+            }),
+        });
+
+        assert_eq!(expected, Hosts::deserialise(hosts_data));
+        assert_eq!(
+            expected,
+            Hosts::deserialise_reader(hosts_data.as_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn deserialise_error_position_address() {
+        assert_eq!(
+            Err(Error::At {
+                line: 2,
+                column: 2,
+                error: Box::new(Error::CouldNotParseAddress {
+                    address: "1.2.3".into(),
+                }),
+            }),
+            Hosts::deserialise("::1 localhost\n 1.2.3 foo")
+        );
+    }
 }
@@ -39,6 +39,14 @@ impl Hosts {
             out.push('\n');
         }
 
+        let mut sorted_blocked_subtrees = self.blocked_subtrees.iter().collect::<Vec<_>>();
+        sorted_blocked_subtrees.sort();
+        for domain in sorted_blocked_subtrees {
+            let mut name_without_dot = domain.to_dotted_string();
+            name_without_dot.pop();
+            _ = writeln!(&mut out, "*.{name_without_dot}");
+        }
+
         out
     }
 }
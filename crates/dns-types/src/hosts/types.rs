@@ -1,18 +1,36 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::{Ipv4Addr, Ipv6Addr};
 
 use crate::protocol::types::*;
 use crate::zones::types::*;
 
-/// TTL used when converting into A / AAAA records.
+/// Default TTL used when converting into A / AAAA records.
 pub const TTL: u32 = 5;
 
+/// The A record for a blocked name.
+pub const BLOCKED_A: RecordTypeWithData = RecordTypeWithData::A {
+    address: Ipv4Addr::UNSPECIFIED,
+};
+
+/// The AAAA record for a blocked name.
+pub const BLOCKED_AAAA: RecordTypeWithData = RecordTypeWithData::AAAA {
+    address: Ipv6Addr::UNSPECIFIED,
+};
+
 /// A collection of A records.
 #[derive(Debug, Clone, Eq, PartialEq)]
 #[cfg_attr(any(feature = "test-util", test), derive(arbitrary::Arbitrary))]
 pub struct Hosts {
     pub v4: HashMap<DomainName, Ipv4Addr>,
     pub v6: HashMap<DomainName, Ipv6Addr>,
+    /// Names which are blocked along with all of their subdomains, by
+    /// resolving to the unspecified address.
+    #[cfg_attr(any(feature = "test-util", test), arbitrary(default))]
+    pub blocked_subtrees: HashSet<DomainName>,
+    /// The TTL of the A and AAAA records.  Hosts files don't have TTLs, so
+    /// this is `TTL` unless it's been changed.
+    #[cfg_attr(any(feature = "test-util", test), arbitrary(value = TTL))]
+    pub ttl: u32,
 }
 
 impl Hosts {
@@ -20,11 +38,14 @@ impl Hosts {
         Self {
             v4: HashMap::new(),
             v6: HashMap::new(),
+            blocked_subtrees: HashSet::new(),
+            ttl: TTL,
         }
     }
 
     /// Merge another hosts file into this one.  If the same name has
-    /// records in both files, the new file will win.
+    /// records in both files, the new file will win.  The TTL of this one is
+    /// kept.
     pub fn merge(&mut self, other: Hosts) {
         for (name, address) in other.v4 {
             self.v4.insert(name, address);
@@ -32,16 +53,19 @@ impl Hosts {
         for (name, address) in other.v6 {
             self.v6.insert(name, address);
         }
+        self.blocked_subtrees.extend(other.blocked_subtrees);
     }
 
     /// Convert a zone into a hosts file, discarding any non-A and
-    /// non-AAAA records.
+    /// non-AAAA records.  The TTL is the lowest of the records which are
+    /// kept.
     pub fn from_zone_lossy(zone: &Zone) -> Self {
         let mut v4 = HashMap::new();
         let mut v6 = HashMap::new();
+        let mut ttl = None;
         for (name, zrs) in zone.all_records() {
             for zr in zrs {
-                let rr = zr.to_rr(name);
+                let rr = zr.to_rr(&name);
                 match rr.rtype_with_data {
                     RecordTypeWithData::A { address } => {
                         v4.insert(rr.name, address);
@@ -49,12 +73,18 @@ impl Hosts {
                     RecordTypeWithData::AAAA { address } => {
                         v6.insert(rr.name, address);
                     }
-                    _ => (),
+                    _ => continue,
                 }
+                ttl = Some(ttl.map_or(rr.ttl, |ttl: u32| ttl.min(rr.ttl)));
             }
         }
 
-        Self { v4, v6 }
+        Self {
+            v4,
+            v6,
+            blocked_subtrees: HashSet::new(),
+            ttl: ttl.unwrap_or(TTL),
+        }
     }
 }
 
@@ -64,15 +94,37 @@ impl Default for Hosts {
     }
 }
 
+impl Hosts {
+    /// Convert into a compact non-authoritative zone, which uses much less
+    /// memory than `Zone::from` for large hosts files, like blocklists.  See
+    /// `Zone::new_compact`.
+    pub fn into_compact_zone(self) -> Zone {
+        let mut zone = Zone::new_compact(DomainName::root_domain(), None);
+        self.insert_into(&mut zone);
+        zone
+    }
+
+    fn insert_into(self, zone: &mut Zone) {
+        let ttl = self.ttl;
+        for (name, address) in self.v4 {
+            zone.insert(&name, RecordTypeWithData::A { address }, ttl);
+        }
+        for (name, address) in self.v6 {
+            zone.insert(&name, RecordTypeWithData::AAAA { address }, ttl);
+        }
+        for name in self.blocked_subtrees {
+            for rtype_with_data in [BLOCKED_A, BLOCKED_AAAA] {
+                zone.insert(&name, rtype_with_data.clone(), ttl);
+                zone.insert_wildcard(&name, rtype_with_data, ttl);
+            }
+        }
+    }
+}
+
 impl From<Hosts> for Zone {
     fn from(hosts: Hosts) -> Zone {
         let mut zone = Self::default();
-        for (name, address) in hosts.v4 {
-            zone.insert(&name, RecordTypeWithData::A { address }, TTL);
-        }
-        for (name, address) in hosts.v6 {
-            zone.insert(&name, RecordTypeWithData::AAAA { address }, TTL);
-        }
+        hosts.insert_into(&mut zone);
         zone
     }
 }
@@ -80,20 +132,37 @@ impl From<Hosts> for Zone {
 impl TryFrom<Zone> for Hosts {
     type Error = TryFromZoneError;
 
+    /// Wildcard records are allowed if they block a subtree: that is, a
+    /// wildcard has exactly one `A` record and one `AAAA` record, both of
+    /// which have the unspecified address.
+    ///
+    /// The TTL is the lowest of the records.
+    ///
     /// # Errors
     ///
-    /// If the zone has wildcard domain names or non-A / non-AAAA
+    /// If the zone has other wildcard domain names or non-A / non-AAAA
     /// record types.
     fn try_from(zone: Zone) -> Result<Self, Self::Error> {
-        if !zone.all_wildcard_records().is_empty() {
-            return Err(TryFromZoneError::HasWildcardRecords);
+        let mut ttl = None;
+        let mut blocked_subtrees = HashSet::new();
+        for (name, zrs) in zone.all_wildcard_records() {
+            let mut rtypes_with_data: Vec<RecordTypeWithData> = Vec::with_capacity(zrs.len());
+            for zr in zrs {
+                ttl = Some(ttl.map_or(zr.ttl, |ttl: u32| ttl.min(zr.ttl)));
+                rtypes_with_data.push(zr.rtype_with_data);
+            }
+            rtypes_with_data.sort();
+            if rtypes_with_data != [BLOCKED_A, BLOCKED_AAAA] {
+                return Err(TryFromZoneError::HasWildcardRecords);
+            }
+            blocked_subtrees.insert(name);
         }
 
         let mut v4 = HashMap::new();
         let mut v6 = HashMap::new();
         for (name, zrs) in zone.all_records() {
             for zr in zrs {
-                let rr = zr.to_rr(name);
+                let rr = zr.to_rr(&name);
                 match rr.rtype_with_data {
                     RecordTypeWithData::A { address } => {
                         v4.insert(rr.name, address);
@@ -103,10 +172,26 @@ impl TryFrom<Zone> for Hosts {
                     }
                     _ => return Err(TryFromZoneError::HasRecordTypesOtherThanA),
                 }
+                ttl = Some(ttl.map_or(rr.ttl, |ttl: u32| ttl.min(rr.ttl)));
+            }
+        }
+
+        // the names themselves are blocked by the subtree
+        for name in &blocked_subtrees {
+            if v4.get(name) == Some(&Ipv4Addr::UNSPECIFIED)
+                && v6.get(name) == Some(&Ipv6Addr::UNSPECIFIED)
+            {
+                v4.remove(name);
+                v6.remove(name);
             }
         }
 
-        Ok(Self { v4, v6 })
+        Ok(Self {
+            v4,
+            v6,
+            blocked_subtrees,
+            ttl: ttl.unwrap_or(TTL),
+        })
     }
 }
 
@@ -136,6 +221,45 @@ mod tests {
         }
     }
 
+    #[test]
+    fn hosts_zone_file_roundtrip() {
+        for _ in 0..100 {
+            let expected = arbitrary_hosts();
+            let serialised = Zone::from(expected.clone()).serialise();
+            let zone = Zone::deserialise(&serialised).unwrap();
+            assert_eq!(Ok(expected), Hosts::try_from(zone), "{serialised}");
+        }
+    }
+
+    #[test]
+    fn hosts_zone_roundtrip_ttl() {
+        let mut expected = arbitrary_hosts();
+        expected.v4.insert(domain("www.lan."), Ipv4Addr::LOCALHOST);
+        expected.ttl = 3600;
+
+        assert_eq!(Ok(expected.clone()), Hosts::try_from(Zone::from(expected)));
+    }
+
+    #[test]
+    fn hosts_zone_roundtrip_blocked_subtrees() {
+        let mut expected = arbitrary_hosts_with_apex(&domain("hosts."));
+        expected.blocked_subtrees.insert(domain("blocked."));
+        expected.blocked_subtrees.insert(domain("sub.blocked."));
+
+        assert_eq!(Ok(expected.clone()), Hosts::try_from(Zone::from(expected)));
+    }
+
+    #[test]
+    fn hosts_try_from_zone_rejects_other_wildcards() {
+        let mut zone = Zone::default();
+        zone.insert_wildcard(&domain("example.com."), BLOCKED_A, TTL);
+
+        assert_eq!(
+            Err(TryFromZoneError::HasWildcardRecords),
+            Hosts::try_from(zone)
+        );
+    }
+
     #[test]
     fn hosts_merge_zone_merge_equiv_when_disjoint() {
         for _ in 0..100 {
@@ -154,6 +278,57 @@ mod tests {
         }
     }
 
+    #[test]
+    fn hosts_compact_zone_roundtrip() {
+        for _ in 0..100 {
+            let expected = arbitrary_hosts();
+            assert_eq!(
+                Ok(expected.clone()),
+                Hosts::try_from(expected.into_compact_zone())
+            );
+        }
+    }
+
+    #[test]
+    fn hosts_compact_zone_resolves_like_zone() {
+        for _ in 0..100 {
+            let hosts = arbitrary_hosts();
+            let zone = Zone::from(hosts.clone());
+            let compact_zone = hosts.clone().into_compact_zone();
+
+            for name in hosts.v4.keys().chain(hosts.v6.keys()) {
+                // the name, a subdomain, and every ancestor
+                let mut names = vec![domain("foo.").make_subdomain_of(name).unwrap()];
+                for i in 0..name.labels.len() {
+                    names.push(DomainName::from_labels(name.labels[i..].to_vec()).unwrap());
+                }
+
+                for name in names {
+                    for qtype in [
+                        QueryType::Record(RecordType::A),
+                        QueryType::Record(RecordType::AAAA),
+                        QueryType::Record(RecordType::MX),
+                        QueryType::Wildcard,
+                    ] {
+                        assert_eq!(
+                            sorted(zone.resolve(&name, qtype)),
+                            sorted(compact_zone.resolve(&name, qtype))
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn sorted(result: Option<ZoneResult>) -> Option<ZoneResult> {
+        if let Some(ZoneResult::Answer { mut rrs, wildcard }) = result {
+            rrs.sort();
+            Some(ZoneResult::Answer { rrs, wildcard })
+        } else {
+            result
+        }
+    }
+
     fn arbitrary_hosts_with_apex(apex: &DomainName) -> Hosts {
         let arbitrary = arbitrary_hosts();
 
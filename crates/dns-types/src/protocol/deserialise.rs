@@ -7,6 +7,24 @@ use std::net::{Ipv4Addr, Ipv6Addr};
 
 use crate::protocol::types::*;
 
+/// Maximum number of compression pointers which will be followed
+/// when deserialising a single domain name.
+///
+/// Pointers must point to an earlier part of the message, so a chain
+/// of pointers always terminates, but without a limit a message could
+/// contain a chain thousands of pointers long.  Legitimate messages
+/// need very few: a name is usually compressed by pointing to a
+/// single earlier name, which may itself be compressed.
+pub const DOMAINNAME_MAX_POINTERS: usize = 16;
+
+/// Maximum number of labels, across all domain names, in a message.
+///
+/// Compression pointers let a two-octet pointer expand into a domain
+/// name of up to 128 labels, so a small message can decode into a
+/// very large `Message` value.  This is far more than a legitimate
+/// message would need.
+pub const MESSAGE_MAX_LABELS: usize = 16384;
+
 impl Message {
     /// # Errors
     ///
@@ -105,6 +123,13 @@ impl ResourceRecord {
 
         let rdata_start = buffer.position;
 
+        // checking this up front means that an RR which claims to have more
+        // RDATA than there is left in the message is rejected as such, rather
+        // than as whichever field parsing happens to fail at.
+        if rdata_start + (rdlength as usize) > buffer.octets.len() {
+            return Err(Error::ResourceRecordRdataOutOfBounds(id));
+        }
+
         let mut raw_rdata = || {
             if let Some(octets) = buffer.take(rdlength as usize) {
                 Ok(Bytes::copy_from_slice(octets))
@@ -214,6 +239,10 @@ impl ResourceRecord {
 }
 
 impl DomainName {
+    /// Domain names are deserialised iteratively, following at most
+    /// `DOMAINNAME_MAX_POINTERS` compression pointers, so a malicious
+    /// message cannot cause unbounded work.
+    ///
     /// # Errors
     ///
     /// If the domain cannot be parsed.
@@ -221,20 +250,23 @@ impl DomainName {
     fn deserialise(id: u16, buffer: &mut ConsumableBuffer) -> Result<Self, Error> {
         let mut len = 0;
         let mut labels = Vec::<Label>::with_capacity(5);
-        let start = buffer.position;
+        let mut start = buffer.position;
+        let mut cursor = buffer.at_offset(start);
+        let mut pointers_followed = 0;
+        let mut resume_at = None;
 
-        'outer: loop {
-            let size = buffer.next_u8().ok_or(Error::DomainTooShort(id))?;
+        loop {
+            let size = cursor.next_u8().ok_or(Error::DomainTooShort(id))?;
 
             if usize::from(size) <= LABEL_MAX_LEN {
                 len += 1;
 
                 if size == 0 {
                     labels.push(Label::new());
-                    break 'outer;
+                    break;
                 }
 
-                if let Some(os) = buffer.take(size as usize) {
+                if let Some(os) = cursor.take(size as usize) {
                     // safe because of the bounds check above
                     let label = Label::try_from(os).unwrap();
                     len += label.len() as usize;
@@ -244,13 +276,11 @@ impl DomainName {
                 }
 
                 if len > DOMAINNAME_MAX_LEN {
-                    break 'outer;
+                    break;
                 }
             } else if size >= 192 {
-                // this requires re-parsing the pointed-to domain -
-                // not great but works for now.
                 let hi = size & 0b0011_1111;
-                let lo = buffer.next_u8().ok_or(Error::DomainTooShort(id))?;
+                let lo = cursor.next_u8().ok_or(Error::DomainTooShort(id))?;
                 let ptr = u16::from_be_bytes([hi, lo]).into();
 
                 // pointer must be to an earlier record (not merely a
@@ -260,20 +290,35 @@ impl DomainName {
                     return Err(Error::DomainPointerInvalid(id));
                 }
 
-                let mut other = DomainName::deserialise(id, &mut buffer.at_offset(ptr))?;
-                len += other.len;
-                labels.append(&mut other.labels);
-                break 'outer;
+                pointers_followed += 1;
+                if pointers_followed > DOMAINNAME_MAX_POINTERS {
+                    return Err(Error::DomainPointerChainTooLong(id));
+                }
+
+                // the name in the original buffer ends at the first
+                // pointer, everything else is elsewhere in the message
+                if resume_at.is_none() {
+                    resume_at = Some(cursor.position);
+                }
+
+                start = ptr;
+                cursor = buffer.at_offset(ptr);
             } else {
                 return Err(Error::DomainLabelInvalid(id));
             }
         }
 
-        if len <= DOMAINNAME_MAX_LEN {
-            Ok(DomainName { labels, len })
-        } else {
-            Err(Error::DomainTooLong(id))
+        if len > DOMAINNAME_MAX_LEN {
+            return Err(Error::DomainTooLong(id));
         }
+
+        buffer.position = resume_at.unwrap_or(cursor.position);
+        buffer.labels_seen += labels.len();
+        if buffer.labels_seen > MESSAGE_MAX_LABELS {
+            return Err(Error::TooManyLabels(id));
+        }
+
+        Ok(DomainName { labels, len })
     }
 }
 
@@ -340,6 +385,9 @@ pub enum Error {
     /// A resource record is the wrong format.
     ResourceRecordInvalid(u16),
 
+    /// A resource record's RDLENGTH goes past the end of the message.
+    ResourceRecordRdataOutOfBounds(u16),
+
     /// A domain is incomplete.
     DomainTooShort(u16),
 
@@ -351,6 +399,13 @@ pub enum Error {
 
     /// A domain label is longer than 63 octets, but not a pointer.
     DomainLabelInvalid(u16),
+
+    /// A domain follows more than `DOMAINNAME_MAX_POINTERS`
+    /// compression pointers.
+    DomainPointerChainTooLong(u16),
+
+    /// The message has more than `MESSAGE_MAX_LABELS` labels in total.
+    TooManyLabels(u16),
 }
 
 impl std::fmt::Display for Error {
@@ -363,10 +418,18 @@ impl std::fmt::Display for Error {
                 f,
                 "resource record RDLENGTH field does not match parsed RDATA length"
             ),
+            Error::ResourceRecordRdataOutOfBounds(_) => write!(
+                f,
+                "resource record RDLENGTH field goes past the end of the message"
+            ),
             Error::DomainTooShort(_) => write!(f, "domain name too short"),
             Error::DomainTooLong(_) => write!(f, "domain name too long"),
             Error::DomainPointerInvalid(_) => write!(f, "domain name compression pointer invalid"),
             Error::DomainLabelInvalid(_) => write!(f, "domain label invalid"),
+            Error::DomainPointerChainTooLong(_) => {
+                write!(f, "domain name compression pointer chain too long")
+            }
+            Error::TooManyLabels(_) => write!(f, "too many labels in message"),
         }
     }
 }
@@ -385,10 +448,13 @@ impl Error {
             Error::QuestionTooShort(id) => Some(id),
             Error::ResourceRecordTooShort(id) => Some(id),
             Error::ResourceRecordInvalid(id) => Some(id),
+            Error::ResourceRecordRdataOutOfBounds(id) => Some(id),
             Error::DomainTooShort(id) => Some(id),
             Error::DomainTooLong(id) => Some(id),
             Error::DomainPointerInvalid(id) => Some(id),
             Error::DomainLabelInvalid(id) => Some(id),
+            Error::DomainPointerChainTooLong(id) => Some(id),
+            Error::TooManyLabels(id) => Some(id),
         }
     }
 }
@@ -397,6 +463,8 @@ impl Error {
 struct ConsumableBuffer<'a> {
     octets: &'a [u8],
     position: usize,
+    /// Number of labels in all domain names deserialised so far.
+    labels_seen: usize,
 }
 
 impl<'a> ConsumableBuffer<'a> {
@@ -404,6 +472,7 @@ impl<'a> ConsumableBuffer<'a> {
         Self {
             octets,
             position: 0,
+            labels_seen: 0,
         }
     }
 
@@ -455,6 +524,227 @@ impl<'a> ConsumableBuffer<'a> {
         Self {
             octets: self.octets,
             position,
+            labels_seen: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::protocol::types::test_util::*;
+
+    #[test]
+    fn names_keep_their_case() {
+        let mut query = Message::from_question(
+            1234,
+            Question {
+                name: domain("WwW.ExAmple.COM."),
+                qtype: QueryType::Record(RecordType::A),
+                qclass: QueryClass::Record(RecordClass::IN),
+            },
+        );
+        query.answers = vec![a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1))];
+
+        let message = Message::from_octets(&query.to_octets().unwrap()).unwrap();
+
+        assert_eq!(
+            "WwW.ExAmple.COM.",
+            message.questions[0].name.to_dotted_string()
+        );
+        assert_eq!(
+            "www.example.com.",
+            message.answers[0].name.to_dotted_string()
+        );
+    }
+
+    #[test]
+    fn pointer_chain_at_limit_is_accepted() {
+        let octets = message_with_pointer_chain(DOMAINNAME_MAX_POINTERS);
+        let message = Message::from_octets(&octets).unwrap();
+
+        for question in message.questions {
+            assert_eq!(domain("www."), question.name);
         }
     }
+
+    #[test]
+    fn pointer_chain_over_limit_is_rejected() {
+        let octets = message_with_pointer_chain(DOMAINNAME_MAX_POINTERS + 1);
+
+        assert_eq!(
+            Err(Error::DomainPointerChainTooLong(ID)),
+            Message::from_octets(&octets)
+        );
+    }
+
+    #[test]
+    fn pointer_to_self_is_rejected() {
+        let mut octets = header(1, 0);
+        octets.extend_from_slice(&[0b1100_0000, 12, 0, 1, 0, 1]);
+
+        assert_eq!(
+            Err(Error::DomainPointerInvalid(ID)),
+            Message::from_octets(&octets)
+        );
+    }
+
+    #[test]
+    fn label_count_at_limit_is_accepted() {
+        let octets = message_with_repeated_long_name(MESSAGE_MAX_LABELS / 128);
+
+        assert!(Message::from_octets(&octets).is_ok());
+    }
+
+    #[test]
+    fn label_count_over_limit_is_rejected() {
+        let octets = message_with_repeated_long_name(MESSAGE_MAX_LABELS / 128 + 1);
+
+        assert_eq!(Err(Error::TooManyLabels(ID)), Message::from_octets(&octets));
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn rdata_past_end_of_message_is_rejected() {
+        let mut octets = header(0, 1);
+        octets.extend_from_slice(&[
+            // NAME
+            0,
+            // TYPE
+            0, 1,
+            // CLASS
+            0, 1,
+            // TTL
+            0, 0, 1, 44,
+            // RDLENGTH
+            0, 100,
+            // RDATA
+            1, 2, 3, 4,
+        ]);
+
+        assert_eq!(
+            Err(Error::ResourceRecordRdataOutOfBounds(ID)),
+            Message::from_octets(&octets)
+        );
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn rdata_name_past_rdlength_is_rejected() {
+        let mut octets = header(0, 1);
+        octets.extend_from_slice(&[
+            // NAME
+            0,
+            // TYPE
+            0, 5,
+            // CLASS
+            0, 1,
+            // TTL
+            0, 0, 1, 44,
+            // RDLENGTH
+            0, 1,
+            // RDATA
+            3, 119, 119, 119, 0,
+        ]);
+
+        assert_eq!(
+            Err(Error::ResourceRecordInvalid(ID)),
+            Message::from_octets(&octets)
+        );
+    }
+
+    #[test]
+    fn every_truncation_is_rejected() {
+        let octets = valid_message().to_octets().unwrap();
+
+        for len in 0..octets.len() {
+            assert!(
+                Message::from_octets(&octets[..len]).is_err(),
+                "truncation to {len} octets parsed"
+            );
+        }
+    }
+
+    #[test]
+    fn every_single_octet_corruption_terminates() {
+        let original = valid_message().to_octets().unwrap();
+
+        for i in 0..original.len() {
+            let mut octets = original.clone();
+            for octet in 0..=u8::MAX {
+                octets[i] = octet;
+                if let Ok(message) = Message::from_octets(&octets) {
+                    let serialised = message.to_octets().unwrap();
+                    assert_eq!(Ok(message), Message::from_octets(&serialised));
+                }
+            }
+        }
+    }
+
+    const ID: u16 = 1234;
+
+    fn header(qdcount: u16, ancount: u16) -> Vec<u8> {
+        let mut octets = Vec::with_capacity(12);
+        octets.extend_from_slice(&ID.to_be_bytes());
+        octets.extend_from_slice(&[0, 0]);
+        octets.extend_from_slice(&qdcount.to_be_bytes());
+        octets.extend_from_slice(&ancount.to_be_bytes());
+        octets.extend_from_slice(&[0, 0, 0, 0]);
+        octets
+    }
+
+    /// A message where the first question is "www." and each
+    /// subsequent question is a pointer to the name of the previous
+    /// one, so the last question follows `chain_len` pointers.
+    fn message_with_pointer_chain(chain_len: usize) -> Vec<u8> {
+        let mut octets = header((chain_len + 1).try_into().unwrap(), 0);
+
+        let mut previous_name = octets.len();
+        octets.extend_from_slice(&[3, b'w', b'w', b'w', 0, 0, 1, 0, 1]);
+        for _ in 0..chain_len {
+            let this_name = octets.len();
+            let [hi, lo] = u16::try_from(previous_name).unwrap().to_be_bytes();
+            octets.extend_from_slice(&[0b1100_0000 | hi, lo, 0, 1, 0, 1]);
+            previous_name = this_name;
+        }
+
+        octets
+    }
+
+    /// A message with `count` questions, all of which are the same
+    /// 128-label name (the first in full and the rest as pointers).
+    fn message_with_repeated_long_name(count: usize) -> Vec<u8> {
+        let mut octets = header(count.try_into().unwrap(), 0);
+
+        let name = octets.len();
+        for _ in 0..127 {
+            octets.extend_from_slice(&[1, b'a']);
+        }
+        octets.extend_from_slice(&[0, 0, 1, 0, 1]);
+        let [hi, lo] = u16::try_from(name).unwrap().to_be_bytes();
+        for _ in 1..count {
+            octets.extend_from_slice(&[0b1100_0000 | hi, lo, 0, 1, 0, 1]);
+        }
+
+        octets
+    }
+
+    fn valid_message() -> Message {
+        let mut message = Message::from_question(
+            ID,
+            Question {
+                name: domain("www.example.com."),
+                qtype: QueryType::Record(RecordType::A),
+                qclass: QueryClass::Record(RecordClass::IN),
+            },
+        );
+        message.answers = vec![
+            cname_record("www.example.com.", "example.com."),
+            a_record("example.com.", Ipv4Addr::new(1, 2, 3, 4)),
+        ];
+        message.authority = vec![ns_record("example.com.", "ns.example.com.")];
+        message
+    }
 }
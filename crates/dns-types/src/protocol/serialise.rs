@@ -1,7 +1,7 @@
 //! Serialisation of DNS messages to the wire format.  See the `types`
 //! module for details of the format.
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use std::collections::HashMap;
 
 use crate::protocol::types::*;
@@ -102,12 +102,15 @@ impl ResourceRecord {
         let rdlength_index = buffer.index();
         buffer.write_u16(0);
 
+        // names in the RDATA of the record types defined in RFC 1035 can be
+        // compressed, but not in any others, as a server which doesn't know
+        // the type couldn't expand them (RFC 3597 section 4)
         match &self.rtype_with_data {
             RecordTypeWithData::A { address } => buffer.write_octets(&address.octets()),
-            RecordTypeWithData::NS { nsdname } => nsdname.serialise(buffer, false),
-            RecordTypeWithData::MD { madname } => madname.serialise(buffer, false),
-            RecordTypeWithData::MF { madname } => madname.serialise(buffer, false),
-            RecordTypeWithData::CNAME { cname } => cname.serialise(buffer, false),
+            RecordTypeWithData::NS { nsdname } => nsdname.serialise(buffer, true),
+            RecordTypeWithData::MD { madname } => madname.serialise(buffer, true),
+            RecordTypeWithData::MF { madname } => madname.serialise(buffer, true),
+            RecordTypeWithData::CNAME { cname } => cname.serialise(buffer, true),
             RecordTypeWithData::SOA {
                 mname,
                 rname,
@@ -117,31 +120,31 @@ impl ResourceRecord {
                 expire,
                 minimum,
             } => {
-                mname.serialise(buffer, false);
-                rname.serialise(buffer, false);
+                mname.serialise(buffer, true);
+                rname.serialise(buffer, true);
                 buffer.write_u32(*serial);
                 buffer.write_u32(*refresh);
                 buffer.write_u32(*retry);
                 buffer.write_u32(*expire);
                 buffer.write_u32(*minimum);
             }
-            RecordTypeWithData::MB { madname } => madname.serialise(buffer, false),
-            RecordTypeWithData::MG { mdmname } => mdmname.serialise(buffer, false),
-            RecordTypeWithData::MR { newname } => newname.serialise(buffer, false),
+            RecordTypeWithData::MB { madname } => madname.serialise(buffer, true),
+            RecordTypeWithData::MG { mdmname } => mdmname.serialise(buffer, true),
+            RecordTypeWithData::MR { newname } => newname.serialise(buffer, true),
             RecordTypeWithData::NULL { octets } => buffer.write_octets(octets),
             RecordTypeWithData::WKS { octets } => buffer.write_octets(octets),
-            RecordTypeWithData::PTR { ptrdname } => ptrdname.serialise(buffer, false),
+            RecordTypeWithData::PTR { ptrdname } => ptrdname.serialise(buffer, true),
             RecordTypeWithData::HINFO { octets } => buffer.write_octets(octets),
             RecordTypeWithData::MINFO { rmailbx, emailbx } => {
-                rmailbx.serialise(buffer, false);
-                emailbx.serialise(buffer, false);
+                rmailbx.serialise(buffer, true);
+                emailbx.serialise(buffer, true);
             }
             RecordTypeWithData::MX {
                 preference,
                 exchange,
             } => {
                 buffer.write_u16(*preference);
-                exchange.serialise(buffer, false);
+                exchange.serialise(buffer, true);
             }
             RecordTypeWithData::TXT { octets } => buffer.write_octets(octets),
             RecordTypeWithData::AAAA { address } => buffer.write_octets(&address.octets()),
@@ -154,10 +157,11 @@ impl ResourceRecord {
                 buffer.write_u16(*priority);
                 buffer.write_u16(*weight);
                 buffer.write_u16(*port);
+                // RFC 2782 forbids compressing the target
                 target.serialise(buffer, false);
             }
             RecordTypeWithData::Unknown { octets, .. } => buffer.write_octets(octets),
-        };
+        }
 
         // -2 so we don't also include the 2 octets for the rdlength
         let rdlength = usize_to_u16(buffer.index() - rdlength_index - 2)?;
@@ -170,16 +174,23 @@ impl ResourceRecord {
 }
 
 impl DomainName {
+    /// Write a name.  If `compress` is true, the longest suffix of the name
+    /// which has already been written is replaced with a pointer to it (RFC
+    /// 1035 section 4.1.4).  Whether or not the name is compressed, later
+    /// names can point to its suffixes.
     fn serialise(&self, buffer: &mut WritableBuffer, compress: bool) {
-        if compress {
-            if let Some(ptr) = buffer.name_pointer(self) {
-                buffer.write_u16(ptr);
-                return;
+        for (i, label) in self.labels.iter().enumerate() {
+            if !label.is_empty() {
+                let suffix = &self.labels[i..];
+                if compress {
+                    if let Some(ptr) = buffer.name_pointer(suffix) {
+                        buffer.write_u16(ptr);
+                        return;
+                    }
+                }
+                buffer.memoise_name(suffix);
             }
-        }
 
-        buffer.memoise_name(self);
-        for label in &self.labels {
             buffer.write_u8(label.len());
             buffer.write_octets(label.octets());
         }
@@ -236,7 +247,10 @@ impl std::error::Error for Error {
 /// A buffer which can be written to, for serialisation purposes.
 struct WritableBuffer {
     octets: BytesMut,
-    name_pointers: HashMap<DomainName, u16>,
+    /// Pointers to the names (and suffixes of names) which have been written,
+    /// keyed by the octets of their labels.  These are compared with case, so
+    /// that compression never changes how a name is spelled.
+    name_pointers: HashMap<Vec<Bytes>, u16>,
 }
 
 impl Default for WritableBuffer {
@@ -253,18 +267,18 @@ impl WritableBuffer {
         self.octets.len()
     }
 
-    fn memoise_name(&mut self, name: &DomainName) {
-        if !name.is_root() && !self.name_pointers.contains_key(name) {
-            if let Ok(index) = u16::try_from(self.index()) {
-                let [hi, lo] = index.to_be_bytes();
+    fn memoise_name(&mut self, labels: &[Label]) {
+        if let Ok(index) = u16::try_from(self.index()) {
+            if index <= POINTER_MAX_OFFSET {
                 self.name_pointers
-                    .insert(name.clone(), u16::from_be_bytes([hi | 0b1100_0000, lo]));
+                    .entry(name_pointer_key(labels))
+                    .or_insert(index | POINTER_TAG);
             }
         }
     }
 
-    fn name_pointer(&self, name: &DomainName) -> Option<u16> {
-        self.name_pointers.get(name).copied()
+    fn name_pointer(&self, labels: &[Label]) -> Option<u16> {
+        self.name_pointers.get(&name_pointer_key(labels)).copied()
     }
 
     fn write_u8(&mut self, octet: u8) {
@@ -284,6 +298,18 @@ impl WritableBuffer {
     }
 }
 
+/// The `name_pointers` key for a name.
+fn name_pointer_key(labels: &[Label]) -> Vec<Bytes> {
+    labels.iter().map(|label| label.octets().clone()).collect()
+}
+
+/// The high bits which mark a compression pointer.
+const POINTER_TAG: u16 = 0b1100_0000_0000_0000;
+
+/// The largest offset a compression pointer can hold: names written after
+/// this can't be pointed to.
+const POINTER_MAX_OFFSET: u16 = !POINTER_TAG;
+
 /// Helper function to convert a `usize` into a `u16` (or return an error).
 ///
 /// # Errors
@@ -357,6 +383,140 @@ mod tests {
         );
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn test_name_compression_suffix() {
+        let mut buf = WritableBuffer::default();
+        domain("www.example.com.").serialise(&mut buf, true);
+        domain("mail.example.com.").serialise(&mut buf, true);
+        domain("com.").serialise(&mut buf, true);
+        domain(".").serialise(&mut buf, true);
+
+        assert_eq!(
+            vec![
+                // domain 1
+                3, 119, 119, 119, // "www"
+                7, 101, 120, 97, 109, 112, 108, 101, // "example"
+                3, 99, 111, 109, 0, // "com"
+                // domain 2
+                4, 109, 97, 105, 108, // "mail"
+                0b1100_0000, 0b0000_0100, // pointer to "example.com"
+                // domain 3
+                0b1100_0000, 0b0000_1100, // pointer to "com"
+                // domain 4
+                0,
+            ],
+            buf.octets,
+        );
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_name_compression_srv_target() {
+        let mut buf = WritableBuffer::default();
+
+        let _ = ResourceRecord {
+            name: domain("_dns._udp.example.com."),
+            rtype_with_data: RecordTypeWithData::SRV {
+                priority: 1,
+                weight: 2,
+                port: 53,
+                target: domain("example.com."),
+            },
+            rclass: RecordClass::IN,
+            ttl: 300,
+        }.serialise(&mut buf);
+
+        assert_eq!(
+            vec![
+                // NAME
+                4, 95, 100, 110, 115, // "_dns"
+                4, 95, 117, 100, 112, // "_udp"
+                7, 101, 120, 97, 109, 112, 108, 101, // "example"
+                3, 99, 111, 109, 0, // "com"
+                // TYPE
+                0b0000_0000, 0b0010_0001, // SRV
+                // CLASS
+                0b0000_0000, 0b0000_0001, // IN
+                // TTL
+                0b0000_0000, 0b0000_0000, 0b0000_0001, 0b0010_1100, // 300
+                // RDLENGTH
+                0b0000_0000, 0b0001_0011, // 19 octets
+                // RDATA
+                0, 1, // priority
+                0, 2, // weight
+                0, 53, // port
+                7, 101, 120, 97, 109, 112, 108, 101, // "example"
+                3, 99, 111, 109, 0, // "com"
+            ],
+            buf.octets,
+        );
+    }
+
+    #[test]
+    fn test_name_compression_pointer_range() {
+        let mut buf = WritableBuffer::default();
+        buf.write_octets(&[0; POINTER_MAX_OFFSET as usize + 1]);
+        domain("www.example.com.").serialise(&mut buf, true);
+        let len = buf.octets.len();
+        domain("www.example.com.").serialise(&mut buf, true);
+
+        assert_eq!(
+            buf.octets[POINTER_MAX_OFFSET as usize + 1..len],
+            buf.octets[len..]
+        );
+    }
+
+    #[test]
+    fn test_arbitrary_message_roundtrip() {
+        for _ in 0..100 {
+            let message = arbitrary_message();
+            let octets = message.to_octets().unwrap();
+            assert_eq!(Ok(message), Message::from_octets(&octets));
+        }
+    }
+
+    #[test]
+    fn test_name_compression_roundtrip() {
+        let mut message = Message::from_question(
+            1234,
+            Question {
+                name: domain("www.example.com."),
+                qtype: QueryType::Record(RecordType::MX),
+                qclass: QueryClass::Record(RecordClass::IN),
+            },
+        )
+        .make_response();
+        for name in ["mx1.example.com.", "mx2.example.com.", "mx.example.net."] {
+            message.answers.push(ResourceRecord {
+                name: domain("www.example.com."),
+                rtype_with_data: RecordTypeWithData::MX {
+                    preference: 10,
+                    exchange: domain(name),
+                },
+                rclass: RecordClass::IN,
+                ttl: 300,
+            });
+        }
+        message.authority.push(ResourceRecord {
+            name: domain("example.com."),
+            rtype_with_data: RecordTypeWithData::SOA {
+                mname: domain("ns.example.com."),
+                rname: domain("hostmaster.example.com."),
+                serial: 1,
+                refresh: 2,
+                retry: 3,
+                expire: 4,
+                minimum: 5,
+            },
+            rclass: RecordClass::IN,
+            ttl: 300,
+        });
+
+        let octets = message.to_octets().unwrap();
+        assert_eq!(Ok(message), Message::from_octets(&octets));
+    }
+
     #[test]
     #[rustfmt::skip]
     fn test_name_compression_records() {
@@ -411,12 +571,11 @@ mod tests {
                 // TTL
                 0b0000_0000, 0b0000_0000, 0b0000_0001, 0b0010_1100, // 300
                 // RDLENGTH
-                0b0000_0000, 0b0001_0010, // 18 octets
+                0b0000_0000, 0b0000_0111, // 7 octets
                 // RDATA
                 0, 32, // preference
                 2, 109, 120, // "mx"
-                7, 101, 120, 97, 109, 112, 108, 101, // "example"
-                3, 99, 111, 109, 0, // "com"
+                0b1100_0000, 0b0000_1000, // pointer to "example.com"
                 // NAME
                 0b1100_0000, 0b0010_0111, // pointer to "mx.example.com"
                 // TYPE
@@ -426,11 +585,9 @@ mod tests {
                 // TTL
                 0b0000_0000, 0b0000_0000, 0b0000_0001, 0b0010_1100, // 300
                 // RDLENGTH
-                0b0000_0000, 0b0001_0001, // 17 octets
+                0b0000_0000, 0b0000_0010, // 2 octets
                 // RDATA
-                3, 119, 119, 119, // "www"
-                7, 101, 120, 97, 109, 112, 108, 101, // "example"
-                3, 99, 111, 109, 0, // "com"
+                0b1100_0000, 0b0000_0100, // pointer to "www.example.com"
             ],
             buf.octets,
         );
@@ -470,12 +627,11 @@ mod tests {
                 // TTL
                 0b0000_0000, 0b0000_0000, 0b0000_0001, 0b0010_1100, // 300
                 // RDLENGTH
-                0b0000_0000, 0b0001_0010, // 18 octets
+                0b0000_0000, 0b0000_0111, // 7 octets
                 // RDATA
                 0, 32, // preference
                 2, 109, 120, // "mx"
-                7, 101, 120, 97, 109, 112, 108, 101, // "example"
-                3, 99, 111, 109, 0, // "com"
+                0b1100_0000, 0b0000_1000, // pointer to "example.com"
             ],
             buf.octets,
         );
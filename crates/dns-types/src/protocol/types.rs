@@ -1,6 +1,8 @@
 use bytes::{BufMut, Bytes, BytesMut};
+use std::cmp::Ordering;
 use std::fmt;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 
 /// Maximum encoded length of a domain name.  The number of labels
@@ -121,6 +123,110 @@ impl Message {
             additional: Vec::new(),
         }
     }
+
+    /// Get the EDNS `OPT` pseudo-record from the additional section, if there
+    /// is one.
+    pub fn edns_opt(&self) -> Option<&ResourceRecord> {
+        self.additional.iter().find(|rr| rr.is_edns_opt())
+    }
+
+    /// Get the client subnet option from the EDNS `OPT` pseudo-record, if
+    /// there is one and it is valid.
+    pub fn client_subnet(&self) -> Option<ClientSubnet> {
+        self.edns_opt()?
+            .edns_options()?
+            .iter()
+            .find_map(ClientSubnet::from_option)
+    }
+
+    /// Get the cookie option from the EDNS `OPT` pseudo-record, if there is
+    /// one and it is valid.
+    pub fn cookie(&self) -> Option<Cookie> {
+        self.edns_opt()?
+            .edns_options()?
+            .iter()
+            .find_map(Cookie::from_option)
+    }
+
+    /// Check if the EDNS `OPT` pseudo-record has a TCP keepalive option,
+    /// asking the server how long it will keep the connection open.
+    pub fn has_tcp_keepalive(&self) -> bool {
+        self.edns_opt()
+            .and_then(ResourceRecord::edns_options)
+            .is_some_and(|options| {
+                options
+                    .iter()
+                    .any(|option| option.code == EDNS_OPTION_TCP_KEEPALIVE)
+            })
+    }
+
+    /// Get the full 12-bit RCODE, combining the 4 bits in the header with the
+    /// 8 bits in the EDNS `OPT` pseudo-record, if there is one (see section
+    /// 6.1.3 of RFC 6891).
+    pub fn extended_rcode(&self) -> u16 {
+        let upper = self.edns_opt().map_or(0, |rr| rr.ttl >> 24);
+        // `upper` is at most 255, so this cannot truncate
+        #[allow(clippy::cast_possible_truncation)]
+        let upper = upper as u16;
+        (upper << 4) | u16::from(u8::from(self.header.rcode))
+    }
+
+    /// Set the full 12-bit RCODE, splitting it between the header and the EDNS
+    /// `OPT` pseudo-record.  An RCODE which does not fit in the header needs
+    /// an `OPT` record, so one is added if there isn't one already.
+    pub fn set_extended_rcode(&mut self, rcode: u16) {
+        // the lower half is masked, so this cannot truncate
+        #[allow(clippy::cast_possible_truncation)]
+        let lower = (rcode & 0b1111) as u8;
+        let upper = u32::from((rcode >> 4) & 0xff);
+        self.header.rcode = Rcode::from(lower);
+
+        if let Some(rr) = self.additional.iter_mut().find(|rr| rr.is_edns_opt()) {
+            rr.ttl = (rr.ttl & 0x00ff_ffff) | (upper << 24);
+        } else if upper > 0 {
+            let mut rr = ResourceRecord::edns_opt(512, &[]);
+            rr.ttl = upper << 24;
+            self.additional.push(rr);
+        }
+    }
+
+    /// Pad the message with an EDNS padding option so that its serialised
+    /// length is a multiple of `block_size` octets, as recommended for
+    /// encrypted transports by RFC 8467 (which suggests 128 for queries and
+    /// 468 for responses).  Any existing padding is replaced.
+    ///
+    /// Returns `false`, and does nothing, if the message has no EDNS `OPT`
+    /// pseudo-record (clients which don't use EDNS can't have asked for
+    /// padding) or if it can't be serialised.
+    pub fn pad(&mut self, block_size: usize) -> bool {
+        let Some(i) = self.additional.iter().position(ResourceRecord::is_edns_opt) else {
+            return false;
+        };
+        let opt = self.additional[i].clone();
+        let Some(mut options) = opt.edns_options() else {
+            return false;
+        };
+        options.retain(|option| option.code != EDNS_OPTION_PADDING);
+
+        let with_padding = |options: &mut Vec<EdnsOption>, len| {
+            options.push(EdnsOption::padding(len));
+            let mut rr = ResourceRecord::edns_opt(u16::from(opt.rclass), options);
+            options.pop();
+            rr.ttl = opt.ttl;
+            rr
+        };
+
+        self.additional[i] = with_padding(&mut options, 0);
+        let Ok(serialised) = self.to_octets() else {
+            self.additional[i] = opt;
+            return false;
+        };
+        let block_size = block_size.max(1);
+        let len = (block_size - serialised.len() % block_size) % block_size;
+        self.additional[i] = with_padding(&mut options, len);
+
+        true
+    }
 }
 
 /// Common header type for all messages.
@@ -206,24 +312,24 @@ pub struct Header {
     /// - `0` No error condition
     ///
     /// - `1` Format error - The name server was unable to interpret
-    ///       the query.
+    ///   the query.
     ///
     /// - `2` Server failure - The name server was unable to process
-    ///       this query due to a problem with the name server.
+    ///   this query due to a problem with the name server.
     ///
     /// - `3` Name Error - Meaningful only for responses from an
-    ///       authoritative name server, this code signifies that the
-    ///       domain name referenced in the query does not exist.
+    ///   authoritative name server, this code signifies that the
+    ///   domain name referenced in the query does not exist.
     ///
     /// - `4` Not Implemented - The name server does not support the
-    ///       requested kind of query.
+    ///   requested kind of query.
     ///
     /// - `5` Refused - The name server refuses to perform the
-    ///       specified operation for policy reasons.  For example, a
-    ///       name server may not wish to provide the information to
-    ///       the particular requester, or a name server may not wish
-    ///       to perform a particular operation (e.g., zone transfer)
-    ///       for particular data.
+    ///   specified operation for policy reasons.  For example, a
+    ///   name server may not wish to provide the information to
+    ///   the particular requester, or a name server may not wish
+    ///   to perform a particular operation (e.g., zone transfer)
+    ///   for particular data.
     ///
     /// - `6-15` Reserved for future use.
     pub rcode: Rcode,
@@ -344,6 +450,342 @@ impl ResourceRecord {
     pub fn matches(&self, question: &Question) -> bool {
         self.rtype_with_data.matches(question.qtype) && self.rclass.matches(question.qclass)
     }
+
+    /// Construct an EDNS `OPT` pseudo-record (see section 6 of RFC 6891) with
+    /// no extended RCODE or flags set, and the given options.
+    pub fn edns_opt(udp_payload_size: u16, options: &[EdnsOption]) -> Self {
+        let mut octets = BytesMut::new();
+        for option in options {
+            octets.put_u16(option.code);
+            octets.put_u16(option.data.len().try_into().unwrap_or(u16::MAX));
+            octets.put_slice(&option.data);
+        }
+
+        let RecordType::Unknown(tag) = RecordType::from(RECORD_TYPE_OPT) else {
+            unreachable!("OPT is not a known record type");
+        };
+
+        Self {
+            name: DomainName::root_domain(),
+            rtype_with_data: RecordTypeWithData::Unknown {
+                tag,
+                octets: octets.freeze(),
+            },
+            rclass: RecordClass::from(udp_payload_size),
+            ttl: 0,
+        }
+    }
+
+    /// Returns true if this is an EDNS `OPT` pseudo-record.
+    pub fn is_edns_opt(&self) -> bool {
+        u16::from(self.rtype_with_data.rtype()) == RECORD_TYPE_OPT
+    }
+
+    /// Get the options of an EDNS `OPT` pseudo-record.  Returns `None` if this
+    /// is not an `OPT` record, or if the options are malformed.
+    pub fn edns_options(&self) -> Option<Vec<EdnsOption>> {
+        if !self.is_edns_opt() {
+            return None;
+        }
+        let RecordTypeWithData::Unknown { octets, .. } = &self.rtype_with_data else {
+            return None;
+        };
+
+        let mut options = Vec::new();
+        let mut i = 0;
+        while i < octets.len() {
+            let header = octets.get(i..i + 4)?;
+            let code = u16::from_be_bytes([header[0], header[1]]);
+            let len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+            if octets.len() < i + 4 + len {
+                return None;
+            }
+            options.push(EdnsOption {
+                code,
+                data: octets.slice(i + 4..i + 4 + len),
+            });
+            i += 4 + len;
+        }
+
+        Some(options)
+    }
+}
+
+/// The type code of the EDNS `OPT` pseudo-record.  This is not a `RecordType`,
+/// as it can't appear in zones or be queried for, so `OPT` records are
+/// represented as unknown records.
+pub const RECORD_TYPE_OPT: u16 = 41;
+
+/// The EDNS option code for a client subnet (see RFC 7871).
+pub const EDNS_OPTION_CLIENT_SUBNET: u16 = 8;
+
+/// The EDNS option code for a cookie (see RFC 7873).
+pub const EDNS_OPTION_COOKIE: u16 = 10;
+
+/// The EDNS option code for a TCP keepalive (see RFC 7828).
+pub const EDNS_OPTION_TCP_KEEPALIVE: u16 = 11;
+
+/// The EDNS option code for padding (see RFC 7830).
+pub const EDNS_OPTION_PADDING: u16 = 12;
+
+/// The EDNS option code for an extended DNS error (see RFC 8914).
+pub const EDNS_OPTION_EXTENDED_ERROR: u16 = 15;
+
+/// The extended RCODE for a missing or invalid server cookie (see section 8
+/// of RFC 7873).
+pub const RCODE_BADCOOKIE: u16 = 23;
+
+/// An option in an EDNS `OPT` pseudo-record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Bytes,
+}
+
+impl EdnsOption {
+    /// Construct an extended DNS error option (see section 2 of RFC 8914).
+    pub fn extended_error(info_code: ExtendedErrorCode, extra_text: &str) -> Self {
+        let mut data = BytesMut::with_capacity(2 + extra_text.len());
+        data.put_u16(info_code as u16);
+        data.put_slice(extra_text.as_bytes());
+
+        Self {
+            code: EDNS_OPTION_EXTENDED_ERROR,
+            data: data.freeze(),
+        }
+    }
+
+    /// Construct a client subnet option (see section 6 of RFC 7871).
+    pub fn client_subnet(client_subnet: &ClientSubnet) -> Self {
+        let address_len = client_subnet.address_len();
+        let mut data = BytesMut::with_capacity(4 + address_len);
+        match client_subnet.address {
+            IpAddr::V4(address) => {
+                data.put_u16(1);
+                data.put_u8(client_subnet.source_prefix_len);
+                data.put_u8(client_subnet.scope_prefix_len);
+                data.put_slice(&address.octets()[..address_len]);
+            }
+            IpAddr::V6(address) => {
+                data.put_u16(2);
+                data.put_u8(client_subnet.source_prefix_len);
+                data.put_u8(client_subnet.scope_prefix_len);
+                data.put_slice(&address.octets()[..address_len]);
+            }
+        }
+
+        Self {
+            code: EDNS_OPTION_CLIENT_SUBNET,
+            data: data.freeze(),
+        }
+    }
+
+    /// Construct a cookie option (see section 4 of RFC 7873).
+    pub fn cookie(cookie: &Cookie) -> Self {
+        let server_len = cookie.server.as_ref().map_or(0, Bytes::len);
+        let mut data = BytesMut::with_capacity(8 + server_len);
+        data.put_slice(&cookie.client);
+        if let Some(server) = &cookie.server {
+            data.put_slice(server);
+        }
+
+        Self {
+            code: EDNS_OPTION_COOKIE,
+            data: data.freeze(),
+        }
+    }
+
+    /// Construct a TCP keepalive option, saying how long the server will keep
+    /// an idle connection open, in units of 100 milliseconds (see section 3.1
+    /// of RFC 7828).
+    pub fn tcp_keepalive(timeout: u16) -> Self {
+        Self {
+            code: EDNS_OPTION_TCP_KEEPALIVE,
+            data: Bytes::copy_from_slice(&timeout.to_be_bytes()),
+        }
+    }
+
+    /// Construct a padding option of `len` zero octets (see section 3 of RFC
+    /// 7830).  The option itself takes up another 4 octets.
+    pub fn padding(len: usize) -> Self {
+        Self {
+            code: EDNS_OPTION_PADDING,
+            data: Bytes::from(vec![0; len]),
+        }
+    }
+}
+
+/// A DNS cookie (see RFC 7873), which lets a server check that a query came
+/// from a client it has talked to before, and a client check that a response
+/// came from the server it asked, making off-path spoofing much harder.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Cookie {
+    /// The client cookie, which the client picks for each server.
+    pub client: [u8; 8],
+
+    /// The server cookie, which the server picks for each client.  This is
+    /// between 8 and 32 octets long, and is `None` if the client doesn't know
+    /// it yet.
+    pub server: Option<Bytes>,
+}
+
+impl Cookie {
+    /// The minimum length of a server cookie.
+    pub const SERVER_MIN_LEN: usize = 8;
+
+    /// The maximum length of a server cookie.
+    pub const SERVER_MAX_LEN: usize = 32;
+
+    /// Parse a cookie option.  Returns `None` if this is some other option, or
+    /// if it is not valid: for example, if the server cookie is too short.
+    pub fn from_option(option: &EdnsOption) -> Option<Self> {
+        if option.code != EDNS_OPTION_COOKIE {
+            return None;
+        }
+
+        let client = option.data.get(..8)?.try_into().ok()?;
+        let server = match option.data.len() - 8 {
+            0 => None,
+            len if (Self::SERVER_MIN_LEN..=Self::SERVER_MAX_LEN).contains(&len) => {
+                Some(option.data.slice(8..))
+            }
+            _ => return None,
+        };
+
+        Some(Self { client, server })
+    }
+}
+
+/// The subnet of the client which a query was made on behalf of, from the
+/// client subnet EDNS option (see RFC 7871).  Upstream nameservers can use
+/// this to tailor their answers to where the client is.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ClientSubnet {
+    /// The address of the subnet.  Bits past `source_prefix_len` are zero.
+    pub address: IpAddr,
+
+    /// How many bits of the address are given.
+    pub source_prefix_len: u8,
+
+    /// How many bits of the address the answer applies to.  This is zero in
+    /// queries, and in answers which are the same for every client.
+    pub scope_prefix_len: u8,
+}
+
+impl ClientSubnet {
+    /// Construct a client subnet from an address and a prefix length, with a
+    /// scope prefix length of zero.  The prefix length is capped at the length
+    /// of the address, and the bits of the address past it are cleared.
+    pub fn new(address: IpAddr, source_prefix_len: u8) -> Self {
+        let source_prefix_len = source_prefix_len.min(max_prefix_len(address));
+        Self {
+            address: mask_address(address, source_prefix_len),
+            source_prefix_len,
+            scope_prefix_len: 0,
+        }
+    }
+
+    /// Shorten the prefix to at most `ipv4_prefix_len` or `ipv6_prefix_len`
+    /// bits, depending on the address family, so that less is revealed about
+    /// the client.  The scope prefix length is reset to zero.
+    pub fn truncate(&self, ipv4_prefix_len: u8, ipv6_prefix_len: u8) -> Self {
+        let max_len = match self.address {
+            IpAddr::V4(_) => ipv4_prefix_len,
+            IpAddr::V6(_) => ipv6_prefix_len,
+        };
+        Self::new(self.address, self.source_prefix_len.min(max_len))
+    }
+
+    /// Parse a client subnet option.  Returns `None` if this is some other
+    /// option, or if it is not valid: for example, if the address has bits set
+    /// past the prefix length.
+    pub fn from_option(option: &EdnsOption) -> Option<Self> {
+        if option.code != EDNS_OPTION_CLIENT_SUBNET || option.data.len() < 4 {
+            return None;
+        }
+
+        let family = u16::from_be_bytes([option.data[0], option.data[1]]);
+        let source_prefix_len = option.data[2];
+        let scope_prefix_len = option.data[3];
+        let octets = &option.data[4..];
+        let address = match family {
+            1 if octets.len() <= 4 => {
+                let mut padded = [0; 4];
+                padded[..octets.len()].copy_from_slice(octets);
+                IpAddr::V4(Ipv4Addr::from(padded))
+            }
+            2 if octets.len() <= 16 => {
+                let mut padded = [0; 16];
+                padded[..octets.len()].copy_from_slice(octets);
+                IpAddr::V6(Ipv6Addr::from(padded))
+            }
+            _ => return None,
+        };
+
+        let client_subnet = Self {
+            address,
+            source_prefix_len,
+            scope_prefix_len,
+        };
+        let max_len = max_prefix_len(address);
+        if source_prefix_len > max_len
+            || scope_prefix_len > max_len
+            || octets.len() != client_subnet.address_len()
+            || mask_address(address, source_prefix_len) != address
+        {
+            return None;
+        }
+
+        Some(client_subnet)
+    }
+
+    /// How many octets of the address are sent in the option.
+    fn address_len(&self) -> usize {
+        usize::from(self.source_prefix_len).div_ceil(8)
+    }
+}
+
+impl fmt::Display for ClientSubnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.source_prefix_len)
+    }
+}
+
+/// The number of bits in an address.
+fn max_prefix_len(address: IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Clear the bits of an address past the prefix length.
+fn mask_address(address: IpAddr, prefix_len: u8) -> IpAddr {
+    match address {
+        IpAddr::V4(address) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_len))
+                .unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(address.to_bits() & mask))
+        }
+        IpAddr::V6(address) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_len))
+                .unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(address.to_bits() & mask))
+        }
+    }
+}
+
+/// Extended DNS error info codes (see section 4 of RFC 8914).  Only the ones
+/// which `resolved` uses are included.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ExtendedErrorCode {
+    /// An error which no other code covers: the extra text says what it is.
+    Other = 0,
+    /// The answer was resolved from expired data.
+    StaleAnswer = 3,
 }
 
 /// A record type with its associated, deserialised, data.
@@ -753,6 +1195,17 @@ impl Opcode {
     }
 }
 
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Opcode::Standard => write!(f, "standard"),
+            Opcode::Inverse => write!(f, "inverse"),
+            Opcode::Status => write!(f, "status"),
+            Opcode::Reserved(_) => write!(f, "reserved"),
+        }
+    }
+}
+
 impl From<u8> for Opcode {
     fn from(octet: u8) -> Self {
         match octet & 0b0000_1111 {
@@ -882,6 +1335,26 @@ impl DomainName {
         self.labels.ends_with(&other.labels)
     }
 
+    /// A copy of this name with every label lowercased.  Names are always
+    /// compared case-insensitively, so this is only needed where a name is
+    /// stored and later given out again, like the keys of a cache.
+    pub fn to_canonical(&self) -> Self {
+        Self {
+            labels: self.labels.iter().map(Label::to_canonical).collect(),
+            len: self.len,
+        }
+    }
+
+    /// Compare two names, not ignoring case.
+    pub fn eq_case_sensitive(&self, other: &Self) -> bool {
+        self.labels.len() == other.labels.len()
+            && self
+                .labels
+                .iter()
+                .zip(&other.labels)
+                .all(|(a, b)| a.eq_case_sensitive(b))
+    }
+
     pub fn make_subdomain_of(&self, origin: &Self) -> Option<Self> {
         let mut labels = self.labels.clone();
         labels.pop();
@@ -947,6 +1420,35 @@ impl DomainName {
         Self::from_labels(labels)
     }
 
+    /// The name used for reverse lookups of an address: under `in-addr.arpa.`
+    /// for IPv4 and `ip6.arpa.` for IPv6.
+    ///
+    /// See section 3.5 of RFC 1035 and section 2.5 of RFC 3596.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn reverse_pointer(address: IpAddr) -> Self {
+        let name = match address {
+            IpAddr::V4(ip) => {
+                let [a, b, c, d] = ip.octets();
+                format!("{d}.{c}.{b}.{a}.in-addr.arpa.")
+            }
+            IpAddr::V6(ip) => {
+                let mut name = String::with_capacity(73);
+                for octet in ip.octets().iter().rev() {
+                    for nibble in [octet & 0xf, octet >> 4] {
+                        // safe because a nibble is always a hex digit
+                        name.push(char::from_digit(nibble.into(), 16).unwrap());
+                        name.push('.');
+                    }
+                }
+                name.push_str("ip6.arpa.");
+                name
+            }
+        };
+
+        // safe because these are always valid domain names
+        Self::from_dotted_string(&name).unwrap()
+    }
+
     pub fn from_labels(labels: Vec<Label>) -> Option<Self> {
         if labels.is_empty() {
             return None;
@@ -972,6 +1474,76 @@ impl DomainName {
     }
 }
 
+#[cfg(feature = "idna")]
+impl DomainName {
+    /// Like `from_dotted_string`, but labels may also be Unicode (U-labels),
+    /// which are converted to their ASCII form (A-labels, like
+    /// `xn--bcher-kva`) following IDNA.  ASCII labels are left as they are.
+    pub fn from_unicode_dotted_string(s: &str) -> Option<Self> {
+        Self::from_dotted_string(&unicode_to_ascii(s)?)
+    }
+
+    /// Like `to_dotted_string`, but A-labels are shown in their Unicode form
+    /// (U-labels).  A label which looks like an A-label but isn't valid IDNA
+    /// is shown as it is.
+    pub fn to_unicode_dotted_string(&self) -> String {
+        if self.is_root() {
+            return ".".to_string();
+        }
+
+        let mut out = String::with_capacity(self.len);
+        let mut first = true;
+        for label in &self.labels {
+            if first {
+                first = false;
+            } else {
+                out.push('.');
+            }
+            let ascii = label
+                .octets
+                .iter()
+                .map(|octet| *octet as char)
+                .collect::<String>();
+            if ascii
+                .get(..4)
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case("xn--"))
+            {
+                let (unicode, result) = idna::domain_to_unicode(&ascii);
+                if result.is_ok() {
+                    out.push_str(&unicode);
+                    continue;
+                }
+            }
+            out.push_str(&ascii);
+        }
+
+        out
+    }
+}
+
+/// Convert the Unicode labels of a dotted string to A-labels, leaving the
+/// ASCII labels alone.  Returns `None` if a label is not valid IDNA.
+#[cfg(feature = "idna")]
+pub(crate) fn unicode_to_ascii(s: &str) -> Option<String> {
+    if s.is_ascii() {
+        return Some(s.to_string());
+    }
+
+    let mut out = String::with_capacity(s.len());
+    for (i, label) in s.split('.').enumerate() {
+        if i > 0 {
+            out.push('.');
+        }
+        if label.is_ascii() {
+            out.push_str(label);
+        } else {
+            out.push_str(&idna::domain_to_ascii(label).ok()?);
+        }
+    }
+
+    Some(out)
+}
+
 impl fmt::Debug for DomainName {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("DomainName")
@@ -982,7 +1554,7 @@ impl fmt::Debug for DomainName {
 
 impl fmt::Display for DomainName {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", &self.to_dotted_string())
+        write!(f, "{}", self.to_dotted_string())
     }
 }
 
@@ -1031,7 +1603,11 @@ impl<'a> arbitrary::Arbitrary<'a> for DomainName {
 
 /// A label is just a sequence of octets, which are compared as
 /// case-insensitive ASCII.  A label can be no longer than 63 octets.
-#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+///
+/// The original case of the octets is kept, so that a name can be echoed back
+/// exactly as it was given (which matters for 0x20 randomisation), but it is
+/// ignored by the `Eq`, `Ord`, and `Hash` impls.
+#[derive(Debug, Clone)]
 pub struct Label {
     /// Private to this module so constructing an invalid `Label` is
     /// impossible.
@@ -1056,9 +1632,71 @@ impl Label {
         self.octets.is_empty()
     }
 
+    /// The octets of the label, in their original case.
     pub fn octets(&self) -> &Bytes {
         &self.octets
     }
+
+    /// A copy of this label with its octets lowercased.
+    pub fn to_canonical(&self) -> Self {
+        if self.octets.iter().any(u8::is_ascii_uppercase) {
+            Self {
+                octets: Bytes::from(self.octets.to_ascii_lowercase()),
+            }
+        } else {
+            self.clone()
+        }
+    }
+
+    /// Compare two labels, not ignoring case.
+    pub fn eq_case_sensitive(&self, other: &Self) -> bool {
+        self.octets == other.octets
+    }
+}
+
+impl Label {
+    /// Create a label from octets without copying them.  Returns `None` if
+    /// the label is too long.
+    pub(crate) fn from_octets(octets: Bytes) -> Option<Self> {
+        if octets.len() > LABEL_MAX_LEN {
+            None
+        } else {
+            Some(Self { octets })
+        }
+    }
+
+    fn canonical_octets(&self) -> impl Iterator<Item = u8> + '_ {
+        self.octets.iter().map(u8::to_ascii_lowercase)
+    }
+}
+
+impl PartialEq for Label {
+    fn eq(&self, other: &Self) -> bool {
+        self.octets.eq_ignore_ascii_case(&other.octets)
+    }
+}
+
+impl Eq for Label {}
+
+impl Ord for Label {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.canonical_octets().cmp(other.canonical_octets())
+    }
+}
+
+impl PartialOrd for Label {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Hash for Label {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.octets.len());
+        for octet in self.canonical_octets() {
+            state.write_u8(octet);
+        }
+    }
 }
 
 impl Default for Label {
@@ -1070,13 +1708,13 @@ impl Default for Label {
 impl TryFrom<&[u8]> for Label {
     type Error = LabelTryFromOctetsError;
 
-    fn try_from(mixed_case_octets: &[u8]) -> Result<Self, Self::Error> {
-        if mixed_case_octets.len() > LABEL_MAX_LEN {
+    fn try_from(octets: &[u8]) -> Result<Self, Self::Error> {
+        if octets.len() > LABEL_MAX_LEN {
             return Err(LabelTryFromOctetsError::TooLong);
         }
 
         Ok(Self {
-            octets: Bytes::copy_from_slice(&mixed_case_octets.to_ascii_lowercase()),
+            octets: Bytes::copy_from_slice(octets),
         })
     }
 }
@@ -1099,7 +1737,7 @@ impl<'a> arbitrary::Arbitrary<'a> for Label {
                 {
                     b'x'
                 } else {
-                    ascii_byte.to_ascii_lowercase()
+                    ascii_byte
                 },
             );
         }
@@ -1593,6 +2231,251 @@ mod tests {
         }
     }
 
+    #[test]
+    fn edns_opt_with_extended_error() {
+        let rr = ResourceRecord::edns_opt(
+            1232,
+            &[EdnsOption::extended_error(
+                ExtendedErrorCode::StaleAnswer,
+                "hi",
+            )],
+        );
+
+        assert!(rr.is_edns_opt());
+        assert!(rr.name.is_root());
+        assert_eq!(u16::from(rr.rclass), 1232);
+        match rr.rtype_with_data {
+            RecordTypeWithData::Unknown { octets, .. } => {
+                assert_eq!(&octets[..], &[0, 15, 0, 4, 0, 3, b'h', b'i']);
+            }
+            _ => panic!("expected unknown record"),
+        }
+    }
+
+    #[test]
+    fn edns_options_roundtrip() {
+        let options = vec![
+            EdnsOption::extended_error(ExtendedErrorCode::StaleAnswer, "hi"),
+            EdnsOption {
+                code: 1234,
+                data: Bytes::new(),
+            },
+        ];
+        let rr = ResourceRecord::edns_opt(1232, &options);
+
+        assert_eq!(Some(options), rr.edns_options());
+    }
+
+    #[test]
+    fn edns_options_rejects_truncated() {
+        let rr = ResourceRecord::edns_opt(
+            1232,
+            &[EdnsOption::extended_error(
+                ExtendedErrorCode::StaleAnswer,
+                "hi",
+            )],
+        );
+        let RecordTypeWithData::Unknown { tag, octets } = rr.rtype_with_data else {
+            panic!("expected unknown record");
+        };
+        let rr = ResourceRecord {
+            rtype_with_data: RecordTypeWithData::Unknown {
+                tag,
+                octets: octets.slice(..octets.len() - 1),
+            },
+            ..rr
+        };
+
+        assert_eq!(None, rr.edns_options());
+    }
+
+    #[test]
+    fn client_subnet_roundtrip() {
+        for client_subnet in [
+            ClientSubnet::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 24),
+            ClientSubnet::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 0),
+            ClientSubnet::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 128),
+            ClientSubnet {
+                scope_prefix_len: 20,
+                ..ClientSubnet::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 57)
+            },
+        ] {
+            assert_eq!(
+                Some(client_subnet),
+                ClientSubnet::from_option(&EdnsOption::client_subnet(&client_subnet))
+            );
+        }
+    }
+
+    #[test]
+    fn client_subnet_option() {
+        let client_subnet = ClientSubnet::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 22);
+
+        assert_eq!(Ipv4Addr::new(192, 0, 0, 0), client_subnet.address);
+        assert_eq!(
+            &[0, 1, 22, 0, 192, 0, 0][..],
+            &EdnsOption::client_subnet(&client_subnet).data[..]
+        );
+    }
+
+    #[test]
+    fn client_subnet_rejects_bits_past_prefix() {
+        let option = EdnsOption {
+            code: EDNS_OPTION_CLIENT_SUBNET,
+            data: Bytes::from_static(&[0, 1, 16, 0, 192, 0, 2]),
+        };
+
+        assert_eq!(None, ClientSubnet::from_option(&option));
+    }
+
+    #[test]
+    fn client_subnet_truncate() {
+        let client_subnet = ClientSubnet::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 32);
+
+        assert_eq!(
+            ClientSubnet::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)), 24),
+            client_subnet.truncate(24, 56)
+        );
+        assert_eq!(client_subnet, client_subnet.truncate(64, 56));
+    }
+
+    #[test]
+    fn message_client_subnet() {
+        let client_subnet = ClientSubnet::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 56);
+        let mut message = Message::from_question(
+            0,
+            Question {
+                name: DomainName::root_domain(),
+                qtype: QueryType::Record(RecordType::A),
+                qclass: QueryClass::Record(RecordClass::IN),
+            },
+        );
+        assert_eq!(None, message.client_subnet());
+
+        message.additional.push(ResourceRecord::edns_opt(
+            1232,
+            &[
+                EdnsOption::extended_error(ExtendedErrorCode::StaleAnswer, ""),
+                EdnsOption::client_subnet(&client_subnet),
+            ],
+        ));
+        assert_eq!(Some(client_subnet), message.client_subnet());
+    }
+
+    #[test]
+    fn cookie_roundtrip() {
+        for cookie in [
+            Cookie {
+                client: [1, 2, 3, 4, 5, 6, 7, 8],
+                server: None,
+            },
+            Cookie {
+                client: [1, 2, 3, 4, 5, 6, 7, 8],
+                server: Some(Bytes::from_static(&[9; 8])),
+            },
+            Cookie {
+                client: [1, 2, 3, 4, 5, 6, 7, 8],
+                server: Some(Bytes::from_static(&[9; 32])),
+            },
+        ] {
+            assert_eq!(
+                Some(cookie.clone()),
+                Cookie::from_option(&EdnsOption::cookie(&cookie))
+            );
+        }
+    }
+
+    #[test]
+    fn cookie_rejects_bad_lengths() {
+        for len in [0, 7, 9, 15, 41] {
+            let option = EdnsOption {
+                code: EDNS_OPTION_COOKIE,
+                data: Bytes::from(vec![0; len]),
+            };
+            assert_eq!(None, Cookie::from_option(&option), "length {len}");
+        }
+    }
+
+    #[test]
+    fn message_cookie() {
+        let cookie = Cookie {
+            client: [1, 2, 3, 4, 5, 6, 7, 8],
+            server: None,
+        };
+        let mut message = Message::from_question(0, example_question());
+        assert_eq!(None, message.cookie());
+
+        message.additional.push(ResourceRecord::edns_opt(
+            1232,
+            &[EdnsOption::cookie(&cookie)],
+        ));
+        assert_eq!(Some(cookie), message.cookie());
+    }
+
+    #[test]
+    fn message_tcp_keepalive() {
+        let mut message = Message::from_question(0, example_question());
+        assert!(!message.has_tcp_keepalive());
+
+        message.additional.push(ResourceRecord::edns_opt(
+            1232,
+            &[EdnsOption {
+                code: EDNS_OPTION_TCP_KEEPALIVE,
+                data: Bytes::new(),
+            }],
+        ));
+        assert!(message.has_tcp_keepalive());
+
+        assert_eq!(
+            Bytes::from_static(&[0, 100]),
+            EdnsOption::tcp_keepalive(100).data
+        );
+    }
+
+    #[test]
+    fn extended_rcode_roundtrip() {
+        let mut message = Message::from_question(0, example_question()).make_response();
+        assert_eq!(0, message.extended_rcode());
+
+        message.set_extended_rcode(RCODE_BADCOOKIE);
+        assert_eq!(RCODE_BADCOOKIE, message.extended_rcode());
+        assert!(message.edns_opt().is_some());
+
+        let deserialised = Message::from_octets(&message.to_octets().unwrap()).unwrap();
+        assert_eq!(RCODE_BADCOOKIE, deserialised.extended_rcode());
+
+        message.set_extended_rcode(u16::from(u8::from(Rcode::Refused)));
+        assert_eq!(Rcode::Refused, message.header.rcode);
+        assert_eq!(
+            u16::from(u8::from(Rcode::Refused)),
+            message.extended_rcode()
+        );
+    }
+
+    #[test]
+    fn pad_to_block_size() {
+        let mut message = Message::from_question(0, example_question());
+        assert!(!message.pad(128));
+
+        message.additional.push(ResourceRecord::edns_opt(1232, &[]));
+        for block_size in [128, 468, 13] {
+            assert!(message.pad(block_size));
+            assert_eq!(0, message.to_octets().unwrap().len() % block_size);
+        }
+
+        let options = message.edns_opt().unwrap().edns_options().unwrap();
+        assert_eq!(1, options.len());
+        assert_eq!(EDNS_OPTION_PADDING, options[0].code);
+    }
+
+    fn example_question() -> Question {
+        Question {
+            name: DomainName::root_domain(),
+            qtype: QueryType::Record(RecordType::A),
+            qclass: QueryClass::Record(RecordClass::IN),
+        }
+    }
+
     #[test]
     fn u16_recordclass_roundtrip() {
         for i in 0..100 {
@@ -1661,6 +2544,67 @@ mod tests {
         assert!(combined.unwrap().is_subdomain_of(&apex));
     }
 
+    #[test]
+    fn domainname_reverse_pointer() {
+        assert_eq!(
+            domain("4.3.2.1.in-addr.arpa."),
+            DomainName::reverse_pointer(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)))
+        );
+        assert_eq!(
+            domain("b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.0.0.0.0.1.2.3.4.ip6.arpa."),
+            DomainName::reverse_pointer(IpAddr::V6(Ipv6Addr::new(
+                0x4321, 0, 1, 2, 3, 4, 0x0567, 0x89ab
+            )))
+        );
+    }
+
+    #[test]
+    fn domainname_preserves_case() {
+        let name = domain("WwW.ExAmple.COM.");
+
+        assert_eq!("WwW.ExAmple.COM.", name.to_dotted_string());
+        assert_eq!("www.example.com.", name.to_canonical().to_dotted_string());
+    }
+
+    #[test]
+    fn domainname_compares_case_insensitively() {
+        let mixed = domain("WwW.ExAmple.COM.");
+        let lower = domain("www.example.com.");
+
+        assert_eq!(lower, mixed);
+        assert_eq!(Ordering::Equal, lower.cmp(&mixed));
+        assert!(!lower.eq_case_sensitive(&mixed));
+        assert!(lower.eq_case_sensitive(&mixed.to_canonical()));
+        assert!(mixed.is_subdomain_of(&domain("example.com.")));
+
+        let mut set = std::collections::HashSet::new();
+        set.insert(lower);
+        assert!(set.contains(&mixed));
+    }
+
+    #[test]
+    #[cfg(feature = "idna")]
+    fn domainname_unicode_roundtrip() {
+        let name = DomainName::from_unicode_dotted_string("www.Bücher.example.").unwrap();
+
+        assert_eq!(domain("www.xn--bcher-kva.example."), name);
+        assert_eq!("www.xn--bcher-kva.example.", name.to_dotted_string());
+        assert_eq!("www.bücher.example.", name.to_unicode_dotted_string());
+    }
+
+    #[test]
+    #[cfg(feature = "idna")]
+    fn domainname_unicode_keeps_ascii_labels() {
+        let name = DomainName::from_unicode_dotted_string("_Sip._tcp.example.").unwrap();
+
+        assert_eq!("_Sip._tcp.example.", name.to_dotted_string());
+        assert_eq!("_Sip._tcp.example.", name.to_unicode_dotted_string());
+        assert_eq!(
+            "xn--a.example.",
+            domain("xn--a.example.").to_unicode_dotted_string()
+        );
+    }
+
     #[test]
     fn domainname_conversions() {
         let mut rng = rand::thread_rng();
@@ -1694,7 +2638,7 @@ mod tests {
 
                     octets.put_u8(chr);
                     dotted_string_input.push(chr as char);
-                    output.push(chr.to_ascii_lowercase() as char);
+                    output.push(chr as char);
                 }
                 labels_input.push(Label::try_from(&octets.freeze()[..]).unwrap());
             }
@@ -1745,10 +2689,42 @@ pub mod test_util {
         panic!("could not generate arbitrary value!");
     }
 
+    pub fn arbitrary_message() -> Message {
+        let mut rng = rand::thread_rng();
+        for size in [128, 256, 512, 1024, 2048, 4096] {
+            let mut buf = BytesMut::with_capacity(size);
+            for _ in 0..size {
+                buf.put_u8(rng.gen());
+            }
+
+            if let Ok(message) = Message::arbitrary(&mut Unstructured::new(&buf.freeze())) {
+                return message;
+            }
+        }
+
+        panic!("could not generate arbitrary value!");
+    }
+
     pub fn domain(name: &str) -> DomainName {
         DomainName::from_dotted_string(name).unwrap()
     }
 
+    pub fn question(name: &str) -> Question {
+        Question {
+            name: domain(name),
+            qtype: QueryType::Record(RecordType::A),
+            qclass: QueryClass::Record(RecordClass::IN),
+        }
+    }
+
+    pub fn a_response(name: &str) -> Message {
+        let mut response = Message::from_question(0, question(name)).make_response();
+        response
+            .answers
+            .push(a_record(name, Ipv4Addr::new(192, 0, 2, 1)));
+        response
+    }
+
     pub fn a_record(name: &str, address: Ipv4Addr) -> ResourceRecord {
         ResourceRecord {
             name: domain(name),
@@ -1,44 +1,218 @@
 use bytes::{BufMut, Bytes, BytesMut};
+use std::io;
 use std::iter::Peekable;
 use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use crate::protocol::types::*;
 use crate::zones::types::*;
 
+/// The most deeply `$INCLUDE` entries can be nested.  This stops a cycle of
+/// includes which can't be spotted from the paths alone (for example, through
+/// symlinks) from recursing forever.
+pub const MAX_INCLUDE_DEPTH: usize = 16;
+
 impl Zone {
     /// Parse a string of zone data
     ///
-    /// This implementation does not support `$INCLUDE` entries or
-    /// non-`IN` record classes.  These will raise an error.
+    /// This implementation does not support `$INCLUDE` entries (see
+    /// `Zone::deserialise_with_includes`) or non-`IN` record classes.  These
+    /// will raise an error.
     ///
     /// # Errors
     ///
     /// If the string cannot be parsed.
     pub fn deserialise(data: &str) -> Result<Self, Error> {
-        let mut rrs = Vec::new();
-        let mut wildcard_rrs = Vec::new();
-        let mut apex_and_soa = None;
-        let mut origin = None;
-        let mut previous_domain = None;
-        let mut previous_ttl = None;
-        let mut stream = data.chars().peekable();
-        while let Some(entry) = parse_entry(
+        Self::deserialise_with_origin(data, None)
+    }
+
+    /// Parse a string of zone data, with an initial origin: relative names
+    /// before the first `$ORIGIN` entry are relative to it.
+    ///
+    /// # Errors
+    ///
+    /// If the string cannot be parsed.
+    pub fn deserialise_with_origin(data: &str, origin: Option<DomainName>) -> Result<Self, Error> {
+        ZoneFile::deserialise_with_origin(data, origin)?.into_zone()
+    }
+
+    /// Parse the zone data read from the file at `path`, with an initial
+    /// origin (see `Zone::deserialise_with_origin`), reading any files it
+    /// `$INCLUDE`s with `read_file`.
+    ///
+    /// A relative path in an `$INCLUDE` entry is relative to the directory of
+    /// the file the entry is in.  An included file starts with the origin
+    /// given in the `$INCLUDE` entry, or the current origin if there isn't one,
+    /// and any `$ORIGIN` entries in it do not change the origin of the file
+    /// which included it.
+    ///
+    /// # Errors
+    ///
+    /// If the string or any included file cannot be parsed, a file cannot be
+    /// read, or the includes form a cycle.
+    pub fn deserialise_with_includes<F>(
+        data: &str,
+        path: &Path,
+        origin: Option<DomainName>,
+        read_file: F,
+    ) -> Result<Self, Error>
+    where
+        F: FnMut(&Path) -> io::Result<String>,
+    {
+        ZoneFile::deserialise_with_includes(data, path, origin, read_file)?.into_zone()
+    }
+}
+
+/// The records of a zone file, as they are written and in the order they
+/// appear, before they are checked and built into a `Zone`.
+///
+/// Building a `Zone` loses some information, such as TTLs below the `SOA`
+/// minimum and duplicate records, so this is useful for checking zone files.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ZoneFile {
+    /// The apex and the `SOA` record, if there is one.
+    pub apex_and_soa: Option<(DomainName, SOA)>,
+    /// All the records other than the `SOA` and wildcards.
+    pub rrs: Vec<ResourceRecord>,
+    /// Wildcard records, named after the domain the wildcard is under: so a
+    /// record for `*.example.com.` has the name `example.com.`
+    pub wildcard_rrs: Vec<ResourceRecord>,
+}
+
+impl ZoneFile {
+    /// Parse a string of zone data, with an initial origin, like
+    /// `Zone::deserialise_with_origin`.
+    ///
+    /// # Errors
+    ///
+    /// If the string cannot be parsed.
+    pub fn deserialise_with_origin(data: &str, origin: Option<DomainName>) -> Result<Self, Error> {
+        let mut parser = Parser::default();
+        parser.parse(data, origin, None)?;
+        Ok(parser.records)
+    }
+
+    /// Parse the zone data read from the file at `path`, with an initial
+    /// origin, reading any files it `$INCLUDE`s with `read_file`, like
+    /// `Zone::deserialise_with_includes`.
+    ///
+    /// # Errors
+    ///
+    /// If the string or any included file cannot be parsed, a file cannot be
+    /// read, or the includes form a cycle.
+    pub fn deserialise_with_includes<F>(
+        data: &str,
+        path: &Path,
+        origin: Option<DomainName>,
+        mut read_file: F,
+    ) -> Result<Self, Error>
+    where
+        F: FnMut(&Path) -> io::Result<String>,
+    {
+        let mut includes = Includes {
+            stack: vec![path.to_path_buf()],
+            read_file: &mut read_file,
+        };
+        let mut parser = Parser::default();
+        parser.parse(data, origin, Some(&mut includes))?;
+        Ok(parser.records)
+    }
+
+    /// Build the zone from the records.
+    ///
+    /// # Errors
+    ///
+    /// If any record is not a subdomain of the apex.
+    pub fn into_zone(self) -> Result<Zone, Error> {
+        let mut zone = if let Some((apex, soa)) = self.apex_and_soa {
+            Zone::new(apex, Some(soa))
+        } else {
+            Zone::default()
+        };
+
+        for rr in self.rrs {
+            if !rr.name.is_subdomain_of(zone.get_apex()) {
+                return Err(Error::NotSubdomainOfApex {
+                    apex: zone.get_apex().clone(),
+                    name: rr.name,
+                });
+            }
+            zone.insert(&rr.name, rr.rtype_with_data, rr.ttl);
+        }
+
+        for rr in self.wildcard_rrs {
+            if !rr.name.is_subdomain_of(zone.get_apex()) {
+                return Err(Error::NotSubdomainOfApex {
+                    apex: zone.get_apex().clone(),
+                    name: rr.name,
+                });
+            }
+            zone.insert_wildcard(&rr.name, rr.rtype_with_data, rr.ttl);
+        }
+
+        Ok(zone)
+    }
+}
+
+/// The state of parsing a zone file, and any files it includes.
+#[derive(Debug, Default)]
+struct Parser {
+    records: ZoneFile,
+    previous_domain: Option<MaybeWildcard>,
+    previous_ttl: Option<u32>,
+}
+
+/// The files being parsed, for `$INCLUDE` entries.
+struct Includes<'a> {
+    /// The including files, with the file currently being parsed last.
+    stack: Vec<PathBuf>,
+    read_file: &'a mut dyn FnMut(&Path) -> io::Result<String>,
+}
+
+impl Parser {
+    /// Parse some zone data.  If there is no `Includes`, `$INCLUDE` entries
+    /// are an error.
+    ///
+    /// # Errors
+    ///
+    /// If the string cannot be parsed.
+    fn parse(
+        &mut self,
+        data: &str,
+        mut origin: Option<DomainName>,
+        mut includes: Option<&mut Includes>,
+    ) -> Result<(), Error> {
+        let mut stream = Stream::new(data.chars());
+        while let Some((position, entry)) = parse_entry(
             origin.as_ref(),
-            previous_domain.as_ref(),
-            previous_ttl,
+            self.previous_domain.as_ref(),
+            self.previous_ttl,
             &mut stream,
         )? {
             match entry {
                 Entry::Origin { name } => origin = Some(name),
-                Entry::Include { path, origin } => {
-                    return Err(Error::IncludeNotSupported { path, origin })
-                }
+                Entry::Include {
+                    path,
+                    origin: include_origin,
+                } => match includes.as_deref_mut() {
+                    Some(includes) => {
+                        self.include(&path, include_origin.or(origin.clone()), includes)
+                            .map_err(|error| error.at(position))?;
+                    }
+                    None => {
+                        return Err(Error::IncludeNotSupported {
+                            path,
+                            origin: include_origin,
+                        }
+                        .at(position))
+                    }
+                },
                 Entry::RR { rr } => {
-                    previous_domain = Some(MaybeWildcard::Normal {
+                    self.previous_domain = Some(MaybeWildcard::Normal {
                         name: rr.name.clone(),
                     });
-                    previous_ttl = Some(rr.ttl);
+                    self.previous_ttl = Some(rr.ttl);
 
                     if let RecordTypeWithData::SOA {
                         mname,
@@ -50,10 +224,10 @@ impl Zone {
                         minimum,
                     } = rr.rtype_with_data
                     {
-                        if apex_and_soa.is_some() {
-                            return Err(Error::MultipleSOA);
+                        if self.records.apex_and_soa.is_some() {
+                            return Err(Error::MultipleSOA.at(position));
                         }
-                        apex_and_soa = Some((
+                        self.records.apex_and_soa = Some((
                             rr.name,
                             SOA {
                                 mname,
@@ -66,50 +240,83 @@ impl Zone {
                             },
                         ));
                     } else {
-                        rrs.push(rr);
+                        self.records.rrs.push(rr);
                     }
                 }
                 Entry::WildcardRR { rr } => {
-                    previous_domain = Some(MaybeWildcard::Wildcard {
+                    self.previous_domain = Some(MaybeWildcard::Wildcard {
                         name: rr.name.clone(),
                     });
-                    previous_ttl = Some(rr.ttl);
+                    self.previous_ttl = Some(rr.ttl);
 
                     if rr.rtype_with_data.rtype() == RecordType::SOA {
-                        return Err(Error::WildcardSOA);
+                        return Err(Error::WildcardSOA.at(position));
                     }
-                    wildcard_rrs.push(rr);
+                    self.records.wildcard_rrs.push(rr);
                 }
             }
         }
 
-        let mut zone = if let Some((apex, soa)) = apex_and_soa {
-            Zone::new(apex, Some(soa))
-        } else {
-            Zone::default()
+        Ok(())
+    }
+
+    /// Read and parse an included file.
+    ///
+    /// # Errors
+    ///
+    /// If the file cannot be read or parsed, or is already being parsed.
+    fn include(
+        &mut self,
+        path: &str,
+        origin: Option<DomainName>,
+        includes: &mut Includes,
+    ) -> Result<(), Error> {
+        // safe because the stack starts with the top-level file, and every
+        // push is matched by a pop
+        let including_path = includes.stack.last().unwrap();
+        // collecting the components drops any `.`s
+        let full_path: PathBuf = match including_path.parent() {
+            Some(dir) => dir.join(path).components().collect(),
+            None => Path::new(path).components().collect(),
         };
 
-        for rr in rrs {
-            if !rr.name.is_subdomain_of(zone.get_apex()) {
-                return Err(Error::NotSubdomainOfApex {
-                    apex: zone.get_apex().clone(),
-                    name: rr.name,
-                });
-            }
-            zone.insert(&rr.name, rr.rtype_with_data, rr.ttl);
+        if includes.stack.contains(&full_path) {
+            return Err(Error::IncludeCycle {
+                path: full_path.display().to_string(),
+            });
         }
-
-        for rr in wildcard_rrs {
-            if !rr.name.is_subdomain_of(zone.get_apex()) {
-                return Err(Error::NotSubdomainOfApex {
-                    apex: zone.get_apex().clone(),
-                    name: rr.name,
-                });
-            }
-            zone.insert_wildcard(&rr.name, rr.rtype_with_data, rr.ttl);
+        if includes.stack.len() > MAX_INCLUDE_DEPTH {
+            return Err(Error::IncludeTooDeep {
+                path: full_path.display().to_string(),
+            });
         }
 
-        Ok(zone)
+        let data = (includes.read_file)(&full_path).map_err(|error| Error::IncludeRead {
+            path: full_path.display().to_string(),
+            error: error.to_string(),
+        })?;
+
+        // RFC 1035 section 5.1: the included file doesn't change the
+        // current domain (or TTL) of the including file
+        let previous_domain = self.previous_domain.clone();
+        let previous_ttl = self.previous_ttl;
+
+        includes.stack.push(full_path);
+        let result = self.parse(&data, origin, Some(includes));
+        // safe because of the push just above
+        let full_path = includes.stack.pop().unwrap();
+
+        self.previous_domain = previous_domain;
+        self.previous_ttl = previous_ttl;
+
+        result.map_err(|error| match error {
+            // keep the innermost file, which is the one with the error
+            Error::InInclude { .. } => error,
+            _ => Error::InInclude {
+                path: full_path.display().to_string(),
+                error: Box::new(error),
+            },
+        })
     }
 }
 
@@ -161,35 +368,40 @@ impl Zone {
 /// - `\X` - quotes a character, where `X` is a non-digit
 /// - `\DDD` - an octet, given as a decimal number
 ///
-/// Returns `None` if the stream is empty.
+/// Returns the entry and the position it starts at, or `None` if the stream is
+/// empty.
 ///
 /// # Errors
 ///
-/// If the string cannot be parsed.
+/// If the string cannot be parsed.  The error has the position of the
+/// unexpected character if the entry cannot be tokenised, or else the position
+/// the entry starts at.
 fn parse_entry<I: Iterator<Item = char>>(
     origin: Option<&DomainName>,
     previous_domain: Option<&MaybeWildcard>,
     previous_ttl: Option<u32>,
-    stream: &mut Peekable<I>,
-) -> Result<Option<Entry>, Error> {
+    stream: &mut Stream<I>,
+) -> Result<Option<(Position, Entry)>, Error> {
     loop {
-        let tokens = tokenise_entry(stream)?;
-        if tokens.is_empty() {
+        stream.skip_whitespace();
+        let position = stream.position;
+        let tokens = tokenise_entry(stream).map_err(|error| error.at(stream.previous))?;
+        let entry = if tokens.is_empty() {
             if stream.peek().is_none() {
                 return Ok(None);
             }
+            continue;
         } else if tokens[0].0 == "$ORIGIN" {
-            return Ok(Some(parse_origin(origin, tokens)?));
+            parse_origin(origin, tokens)
         } else if tokens[0].0 == "$INCLUDE" {
-            return Ok(Some(parse_include(origin, tokens)?));
+            parse_include(origin, tokens)
         } else {
-            return Ok(Some(parse_rr(
-                origin,
-                previous_domain,
-                previous_ttl,
-                tokens,
-            )?));
-        }
+            parse_rr(origin, previous_domain, previous_ttl, tokens)
+        };
+        return match entry {
+            Ok(entry) => Ok(Some((position, entry))),
+            Err(error) => Err(error.at(position)),
+        };
     }
 }
 
@@ -556,6 +768,11 @@ fn parse_domain_or_wildcard(
 ///
 /// If the string cannot be parsed.
 fn parse_domain(origin: Option<&DomainName>, dotted_string: &str) -> Result<DomainName, Error> {
+    #[cfg(feature = "idna")]
+    let converted = unicode_to_ascii(dotted_string);
+    #[cfg(feature = "idna")]
+    let dotted_string = converted.as_deref().unwrap_or(dotted_string);
+
     let dotted_string_vec = dotted_string.chars().collect::<Vec<char>>();
 
     if dotted_string_vec.is_empty() {
@@ -647,9 +864,7 @@ fn to_rr(wname: MaybeWildcard, rtype_with_data: RecordTypeWithData, ttl: u32) ->
 /// # Errors
 ///
 /// If the string cannot be parsed.
-fn tokenise_entry<I: Iterator<Item = char>>(
-    stream: &mut Peekable<I>,
-) -> Result<Vec<(String, Bytes)>, Error> {
+fn tokenise_entry<I: Iterator<Item = char>>(stream: &mut I) -> Result<Vec<(String, Bytes)>, Error> {
     let mut tokens = Vec::new();
     let mut token_string = String::new();
     let mut token_octets = BytesMut::new();
@@ -695,6 +910,11 @@ fn tokenise_entry<I: Iterator<Item = char>>(
                     token_string.push(c);
                     token_octets.put_u8(c as u8);
                     State::UnquotedString
+                } else if cfg!(feature = "idna") {
+                    // an unquoted string may be a Unicode domain name
+                    token_string.push(c);
+                    token_octets.put_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                    State::UnquotedString
                 } else {
                     return Err(Error::TokeniserUnexpected { unexpected: c });
                 }
@@ -738,6 +958,11 @@ fn tokenise_entry<I: Iterator<Item = char>>(
                     token_string.push(c);
                     token_octets.put_u8(c as u8);
                     State::UnquotedString
+                } else if cfg!(feature = "idna") {
+                    // an unquoted string may be a Unicode domain name
+                    token_string.push(c);
+                    token_octets.put_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                    State::UnquotedString
                 } else {
                     return Err(Error::TokeniserUnexpected { unexpected: c });
                 }
@@ -838,6 +1063,57 @@ fn tokenise_escape<I: Iterator<Item = char>>(stream: &mut I) -> Result<u8, Error
     }
 }
 
+/// A line and column in some zone data, both starting from 1.  Columns count
+/// characters, not octets.
+type Position = (usize, usize);
+
+/// The characters of some zone data, keeping track of where they are for error
+/// messages.
+struct Stream<I: Iterator<Item = char>> {
+    chars: Peekable<I>,
+    /// The position of the next character.
+    position: Position,
+    /// The position of the character most recently returned.
+    previous: Position,
+}
+
+impl<I: Iterator<Item = char>> Stream<I> {
+    fn new(chars: I) -> Self {
+        Self {
+            chars: chars.peekable(),
+            position: (1, 1),
+            previous: (1, 1),
+        }
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+
+    /// Skip any whitespace, including newlines.  This is only done between
+    /// entries, since whitespace inside an entry separates its tokens.
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c.is_whitespace()) {
+            self.next();
+        }
+    }
+}
+
+impl<I: Iterator<Item = char>> Iterator for Stream<I> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        self.previous = self.position;
+        if c == '\n' {
+            self.position = (self.position.0 + 1, 1);
+        } else {
+            self.position.1 += 1;
+        }
+        Some(c)
+    }
+}
+
 /// States the tokeniser can be in
 enum State {
     Initial,
@@ -884,6 +1160,25 @@ pub enum Error {
         path: String,
         origin: Option<DomainName>,
     },
+    IncludeCycle {
+        path: String,
+    },
+    IncludeTooDeep {
+        path: String,
+    },
+    IncludeRead {
+        path: String,
+        error: String,
+    },
+    InInclude {
+        path: String,
+        error: Box<Error>,
+    },
+    At {
+        line: usize,
+        column: usize,
+        error: Box<Error>,
+    },
     MultipleSOA,
     WildcardSOA,
     NotSubdomainOfApex {
@@ -915,6 +1210,22 @@ pub enum Error {
     },
 }
 
+impl Error {
+    /// Give the error a position in the file being parsed, unless it already
+    /// has one or is from an included file (where the position is in that
+    /// file).
+    fn at(self, (line, column): Position) -> Self {
+        match self {
+            Error::At { .. } | Error::InInclude { .. } => self,
+            _ => Error::At {
+                line,
+                column,
+                error: Box::new(self),
+            },
+        }
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -923,6 +1234,20 @@ impl std::fmt::Display for Error {
                 write!(f, "unexpected escape '{unexpected:?}'")
             }
             Error::IncludeNotSupported { .. } => write!(f, "'$INCLUDE' directive not supported"),
+            Error::IncludeCycle { path } => write!(f, "'$INCLUDE' of '{path}' forms a cycle"),
+            Error::IncludeTooDeep { path } => write!(
+                f,
+                "'$INCLUDE' of '{path}' nested more than {MAX_INCLUDE_DEPTH} deep"
+            ),
+            Error::IncludeRead { path, error } => {
+                write!(f, "could not read included file '{path}': {error}")
+            }
+            Error::InInclude { path, error } => write!(f, "in included file '{path}': {error}"),
+            Error::At {
+                line,
+                column,
+                error,
+            } => write!(f, "line {line}, column {column}: {error}"),
             Error::MultipleSOA => write!(f, "multiple SOA records, expected one or zero"),
             Error::WildcardSOA => write!(f, "wildcard SOA record not allowed"),
             Error::NotSubdomainOfApex { apex, name } => {
@@ -955,6 +1280,8 @@ impl std::error::Error for Error {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use crate::protocol::types::test_util::*;
 
     use super::*;
@@ -1014,6 +1341,261 @@ mod tests {
         assert_eq!(expected_all_wildcard_records, actual_all_wildcard_records);
     }
 
+    #[test]
+    fn parse_zone_with_origin() {
+        let zone_data = "@    IN    SOA    nyarlathotep barrucadu.nyarlathotep 1 30 30 30 30\n\
+                         nyarlathotep      300    IN    A        10.0.0.3";
+
+        assert!(Zone::deserialise(zone_data).is_err());
+
+        let zone = Zone::deserialise_with_origin(zone_data, Some(domain("lan."))).unwrap();
+        assert_eq!(&domain("lan."), zone.get_apex());
+        assert_eq!(
+            Some(ZoneResult::Answer {
+                rrs: vec![a_record("nyarlathotep.lan.", Ipv4Addr::new(10, 0, 0, 3))],
+                wildcard: false
+            }),
+            zone.resolve(
+                &domain("nyarlathotep.lan."),
+                QueryType::Record(RecordType::A)
+            )
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "idna")]
+    fn parse_zone_converts_unicode_names() {
+        let zone_data = "$ORIGIN b\u{fc}cher.example.\n\
+                         www    300    IN    CNAME    stra\u{df}e.example.\n\
+                         *.caf\u{e9}    300    IN    A    10.0.0.3";
+        let records = ZoneFile::deserialise_with_origin(zone_data, None).unwrap();
+
+        assert_eq!(
+            vec![cname_record(
+                "www.xn--bcher-kva.example.",
+                "xn--strae-oqa.example."
+            )],
+            records.rrs
+        );
+        assert_eq!(
+            vec![a_record(
+                "xn--caf-dma.xn--bcher-kva.example.",
+                Ipv4Addr::new(10, 0, 0, 3)
+            )],
+            records.wildcard_rrs
+        );
+    }
+
+    #[test]
+    fn parse_zone_file_records_as_written() {
+        let zone_data = "$ORIGIN lan.\n\
+                         @    IN    SOA    nyarlathotep barrucadu.nyarlathotep 1 30 30 30 300\n\
+                         nyarlathotep      60     IN    A        10.0.0.3\n\
+                         nyarlathotep      60     IN    A        10.0.0.3\n\
+                         *.nyarlathotep    300    IN    A        10.0.0.4\n\
+                         example.com.      300    IN    A        10.0.0.5";
+
+        let records = ZoneFile::deserialise_with_origin(zone_data, None).unwrap();
+        assert_eq!(
+            Some(domain("lan.")),
+            records.apex_and_soa.as_ref().map(|(apex, _)| apex.clone())
+        );
+        let mut low_ttl = a_record("nyarlathotep.lan.", Ipv4Addr::new(10, 0, 0, 3));
+        low_ttl.ttl = 60;
+        assert_eq!(
+            vec![
+                low_ttl.clone(),
+                low_ttl,
+                a_record("example.com.", Ipv4Addr::new(10, 0, 0, 5))
+            ],
+            records.rrs
+        );
+        assert_eq!(
+            vec![a_record("nyarlathotep.lan.", Ipv4Addr::new(10, 0, 0, 4))],
+            records.wildcard_rrs
+        );
+
+        assert!(matches!(
+            records.into_zone(),
+            Err(Error::NotSubdomainOfApex { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_zone_with_includes() {
+        let files = HashMap::from([
+            (
+                PathBuf::from("/zones/hosts.inc"),
+                "nyarlathotep 300 IN A 10.0.0.3\n$ORIGIN sub.lan.\nwww 300 IN A 10.0.0.4",
+            ),
+            (
+                PathBuf::from("/zones/../shared/mail.inc"),
+                "@ 300 IN MX 10 mail.example.net.",
+            ),
+        ]);
+        let zone_data = "$ORIGIN lan.\n\
+                         @ IN SOA nyarlathotep barrucadu.nyarlathotep 1 30 30 30 30\n\
+                         $INCLUDE hosts.inc\n\
+                         $INCLUDE ../shared/mail.inc mail\n\
+                         azathoth 300 IN A 10.0.0.5\n\
+                         $INCLUDE hosts.inc\n\
+                         \x20   IN TXT \"after include\"";
+
+        let zone = Zone::deserialise_with_includes(
+            zone_data,
+            Path::new("/zones/lan.zone"),
+            None,
+            read_from(&files),
+        )
+        .unwrap();
+
+        // the `$ORIGIN` in the included file doesn't change the origin of
+        // `azathoth`, and the blank owner after the second include of
+        // `hosts.inc` is `azathoth` again, not `www.sub.lan.`
+        for (name, rtype) in [
+            ("nyarlathotep.lan.", RecordType::A),
+            ("www.sub.lan.", RecordType::A),
+            ("mail.lan.", RecordType::MX),
+            ("azathoth.lan.", RecordType::A),
+            ("azathoth.lan.", RecordType::TXT),
+        ] {
+            assert!(matches!(
+                zone.resolve(&domain(name), QueryType::Record(rtype)),
+                Some(ZoneResult::Answer { rrs, .. }) if rrs.len() == 1
+            ));
+        }
+    }
+
+    #[test]
+    fn parse_zone_include_not_supported_without_reader() {
+        assert_eq!(
+            Err(Error::At {
+                line: 1,
+                column: 1,
+                error: Box::new(Error::IncludeNotSupported {
+                    path: "hosts.inc".to_string(),
+                    origin: None
+                })
+            }),
+            Zone::deserialise("$INCLUDE hosts.inc")
+        );
+    }
+
+    #[test]
+    fn parse_zone_include_cycle() {
+        let files = HashMap::from([
+            (PathBuf::from("/zones/a.inc"), "$INCLUDE ./b.inc"),
+            (PathBuf::from("/zones/b.inc"), "$INCLUDE a.inc"),
+        ]);
+
+        assert_eq!(
+            Err(Error::InInclude {
+                path: "/zones/b.inc".to_string(),
+                error: Box::new(Error::At {
+                    line: 1,
+                    column: 1,
+                    error: Box::new(Error::IncludeCycle {
+                        path: "/zones/a.inc".to_string()
+                    })
+                })
+            }),
+            Zone::deserialise_with_includes(
+                "$INCLUDE a.inc",
+                Path::new("/zones/lan.zone"),
+                None,
+                read_from(&files)
+            )
+        );
+    }
+
+    #[test]
+    fn parse_zone_include_missing_file() {
+        assert!(matches!(
+            Zone::deserialise_with_includes(
+                "$INCLUDE missing.inc",
+                Path::new("/zones/lan.zone"),
+                None,
+                read_from(&HashMap::new())
+            ),
+            Err(Error::At { error, .. }) if matches!(&*error, Error::IncludeRead { path, .. } if path == "/zones/missing.inc")
+        ));
+    }
+
+    #[test]
+    fn parse_zone_error_position() {
+        let zone_data = "$ORIGIN lan.\n\
+                         ; a comment\n\
+                         \n\
+                         nyarlathotep 300 IN A 10.0.0.3\n  \
+                           azathoth   abc IN A 10.0.0.4";
+
+        assert_eq!(
+            Err(Error::At {
+                line: 5,
+                column: 3,
+                error: Box::new(Error::ExpectedU32 {
+                    digits: "abc".to_string()
+                })
+            }),
+            Zone::deserialise(zone_data)
+        );
+    }
+
+    #[test]
+    fn parse_zone_error_position_multiline_entry() {
+        let zone_data = "$ORIGIN lan.\n\
+                         @ IN SOA nyarlathotep barrucadu.nyarlathotep (\n\
+                         1 30 30\n\
+                         30 30 ) )";
+
+        assert_eq!(
+            Err(Error::At {
+                line: 4,
+                column: 9,
+                error: Box::new(Error::TokeniserUnexpected { unexpected: ')' })
+            }),
+            Zone::deserialise(zone_data)
+        );
+    }
+
+    #[test]
+    fn parse_zone_error_position_in_include() {
+        let files = HashMap::from([(
+            PathBuf::from("/zones/hosts.inc"),
+            "nyarlathotep 300 IN A 10.0.0.3\nazathoth 300 IN A 10.0.0",
+        )]);
+
+        assert_eq!(
+            Err(Error::InInclude {
+                path: "/zones/hosts.inc".to_string(),
+                error: Box::new(Error::At {
+                    line: 2,
+                    column: 1,
+                    error: Box::new(Error::MissingType {
+                        tokens: tokenise_str("azathoth 300 IN A 10.0.0")
+                    })
+                })
+            }),
+            Zone::deserialise_with_includes(
+                "$ORIGIN lan.\n$INCLUDE hosts.inc",
+                Path::new("/zones/lan.zone"),
+                None,
+                read_from(&files)
+            )
+        );
+    }
+
+    fn read_from<'a>(
+        files: &'a HashMap<PathBuf, &'static str>,
+    ) -> impl FnMut(&Path) -> io::Result<String> + 'a {
+        |path| {
+            files
+                .get(path)
+                .map(|data| (*data).to_string())
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        }
+    }
+
     #[test]
     fn parse_rr_origin() {
         let tokens = tokenise_str("* IN 300 A 10.0.0.2");
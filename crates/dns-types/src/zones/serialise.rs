@@ -6,6 +6,14 @@ use crate::protocol::types::*;
 use crate::zones::types::*;
 
 impl Zone {
+    /// Increase the SOA serial (if the zone is authoritative), and then
+    /// serialise the zone.  Use this rather than `serialise` when writing a
+    /// changed zone back to a file, so that secondaries notice the change.
+    pub fn serialise_with_bumped_serial(&mut self, bump: SerialBump) -> String {
+        self.bump_serial(bump);
+        self.serialise()
+    }
+
     pub fn serialise(&self) -> String {
         let mut out = String::new();
 
@@ -40,10 +48,10 @@ impl Zone {
         let sorted_domains = {
             let mut set = HashSet::new();
             for name in all_records.keys() {
-                set.insert(*name);
+                set.insert(name);
             }
             for name in all_wildcard_records.keys() {
-                set.insert(*name);
+                set.insert(name);
             }
             let mut vec = set.into_iter().collect::<Vec<&DomainName>>();
             vec.sort();
@@ -53,7 +61,7 @@ impl Zone {
         for domain in sorted_domains {
             if let Some(zrs) = all_records.get(domain) {
                 let has_wildcards = all_wildcard_records.contains_key(domain);
-                for zr in zrs {
+                for zr in sorted(zrs) {
                     if zr.rtype_with_data.rtype() == RecordType::SOA {
                         // already handled above, and it's invalid for
                         // a zone to have multiple SOA records
@@ -72,7 +80,7 @@ impl Zone {
                 }
             }
             if let Some(zrs) = all_wildcard_records.get(domain) {
-                for zr in zrs {
+                for zr in sorted(zrs) {
                     _ = writeln!(
                         &mut out,
                         "*.{} {} IN {} {}",
@@ -167,6 +175,15 @@ impl Zone {
     }
 }
 
+/// The records for a name, ordered by type, so that serialising the same
+/// records always gives the same output.  Records of the same type keep their
+/// order.
+fn sorted(zrs: &[ZoneRecord]) -> Vec<&ZoneRecord> {
+    let mut sorted = zrs.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|zr| zr.rtype_with_data.rtype());
+    sorted
+}
+
 /// Serialise a string of octets to a quoted or unquoted string with
 /// the appropriate escaping.
 fn serialise_octets(octets: &[u8], quoted: bool) -> String {
@@ -202,8 +219,36 @@ fn serialise_octets(octets: &[u8], quoted: bool) -> String {
 
 #[cfg(test)]
 mod tests {
+    use crate::zones::types::test_util::*;
+
     use super::*;
 
+    #[test]
+    fn serialise_roundtrip() {
+        for _ in 0..100 {
+            let zone = arbitrary_zone();
+            let serialised = zone.clone().serialise();
+            assert_eq!(Ok(zone), Zone::deserialise(&serialised), "{serialised}");
+        }
+    }
+
+    #[test]
+    fn serialise_with_bumped_serial() {
+        let mut zone = Zone::deserialise(
+            r"
+$ORIGIN example.com.
+
+@   300 IN SOA mname rname 41 30 30 30 30
+www 300 IN A   1.1.1.1
+",
+        )
+        .unwrap();
+
+        let serialised = zone.serialise_with_bumped_serial(SerialBump::Increment);
+        assert_eq!(Some(42), zone.get_soa().map(|soa| soa.serial));
+        assert_eq!(Ok(zone), Zone::deserialise(&serialised));
+    }
+
     #[test]
     fn serialise_octets_special() {
         assert_eq!("\\012", serialise_octets(&[12], false));
@@ -1,11 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::protocol::types::*;
 
 /// A collection of zones.
+///
+/// Cloning is cheap, so a clone can be used as a snapshot: the zones are
+/// shared between clones, and a zone is only copied when it is changed
+/// through one of them.
 #[derive(Debug, Clone)]
 pub struct Zones {
-    zones: HashMap<DomainName, Zone>,
+    zones: HashMap<DomainName, Arc<Zone>>,
 }
 
 impl Default for Zones {
@@ -23,11 +31,26 @@ impl Zones {
 
     /// Find the zone for a domain, if there is one.
     pub fn get(&self, name: &DomainName) -> Option<&Zone> {
+        self.get_apex(name)
+            .and_then(|apex| self.zones.get(&apex))
+            .map(AsRef::as_ref)
+    }
+
+    /// Find the zone for a domain, if there is one, to change it.  If the
+    /// zone is shared with a clone of this `Zones`, it is copied first.
+    pub fn get_mut(&mut self, name: &DomainName) -> Option<&mut Zone> {
+        self.get_apex(name)
+            .and_then(|apex| self.zones.get_mut(&apex))
+            .map(Arc::make_mut)
+    }
+
+    /// Find the apex of the zone for a domain, if there is one.
+    fn get_apex(&self, name: &DomainName) -> Option<DomainName> {
         for i in 0..name.labels.len() {
             let labels = &name.labels[i..];
             if let Some(name) = DomainName::from_labels(labels.into()) {
-                if let Some(zone) = self.zones.get(&name) {
-                    return Some(zone);
+                if self.zones.contains_key(&name) {
+                    return Some(name);
                 }
             }
         }
@@ -51,9 +74,61 @@ impl Zones {
         }
     }
 
+    /// All of the zones, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &Zone> {
+        self.zones.values().map(AsRef::as_ref)
+    }
+
+    /// The number of zones.
+    pub fn len(&self) -> usize {
+        self.zones.len()
+    }
+
+    /// Returns true if there are no zones.
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    /// The number of records in all of the zones, including wildcard records.
+    pub fn record_count(&self) -> usize {
+        self.zones
+            .values()
+            .map(|zone| zone.record_count() + zone.wildcard_record_count())
+            .sum()
+    }
+
     /// Create or replace a zone.
     pub fn insert(&mut self, zone: Zone) {
-        self.zones.insert(zone.apex.clone(), zone);
+        self.zones.insert(zone.apex.clone(), Arc::new(zone));
+    }
+
+    /// Remove a zone.
+    pub fn remove(&mut self, apex: &DomainName) -> Option<Zone> {
+        self.zones.remove(apex).map(Arc::unwrap_or_clone)
+    }
+
+    /// Remove all the records for a domain and its subdomains, from every
+    /// zone.  Zones with an apex below the domain are removed entirely, but
+    /// the `SOA` record of the zone containing the domain is kept.  See
+    /// `Zone::remove_subtree`.
+    ///
+    /// Returns the number of records removed.
+    pub fn remove_subtree(&mut self, name: &DomainName) -> usize {
+        let mut count = 0;
+        self.zones.retain(|apex, zone| {
+            if apex != name && apex.is_subdomain_of(name) {
+                count += zone.record_count() + zone.wildcard_record_count();
+                false
+            } else {
+                true
+            }
+        });
+
+        if let Some(zone) = self.get_mut(name) {
+            count += zone.remove_subtree(name);
+        }
+
+        count
     }
 
     /// Create a new zone or merge with an existing one.  See
@@ -62,7 +137,7 @@ impl Zones {
     pub fn insert_merge(&mut self, other_zone: Zone) {
         if let Some(my_zone) = self.zones.get_mut(&other_zone.apex) {
             // safe because of the apex check
-            my_zone.merge(other_zone).unwrap();
+            Arc::make_mut(my_zone).merge(other_zone).unwrap();
         } else {
             self.insert(other_zone);
         }
@@ -74,11 +149,108 @@ impl Zones {
         for (apex, other_zone) in other.zones {
             if let Some(my_zone) = self.zones.get_mut(&apex) {
                 // safe because of the apex check
-                my_zone.merge(other_zone).unwrap();
+                Arc::make_mut(my_zone)
+                    .merge(Arc::unwrap_or_clone(other_zone))
+                    .unwrap();
             } else {
-                self.insert(other_zone);
+                self.zones.insert(apex, other_zone);
+            }
+        }
+    }
+
+    /// Add a `PTR` record for the address of every `A` and `AAAA` record in
+    /// every zone, pointing back at the name of the `A` or `AAAA` record.
+    /// Unspecified and loopback addresses, which are used by blocklists, are
+    /// skipped.
+    ///
+    /// Each `PTR` record goes into the most specific zone containing its name
+    /// (so an authoritative `in-addr.arpa` or `ip6.arpa` zone is filled in),
+    /// or into a non-authoritative root zone if there is none.  Names which
+    /// already have a `PTR` record are left alone.
+    ///
+    /// Returns the number of records added.
+    pub fn synthesise_reverse_records(&mut self) -> usize {
+        let mut existing = HashSet::new();
+        let mut synthesised = BTreeMap::new();
+        for zone in self.zones.values() {
+            for (name, zrs) in zone.all_records() {
+                for zr in zrs {
+                    let address = match zr.rtype_with_data {
+                        RecordTypeWithData::A { address } => IpAddr::V4(address),
+                        RecordTypeWithData::AAAA { address } => IpAddr::V6(address),
+                        RecordTypeWithData::PTR { .. } => {
+                            existing.insert(name.clone());
+                            continue;
+                        }
+                        _ => continue,
+                    };
+                    if address.is_unspecified() || address.is_loopback() {
+                        continue;
+                    }
+                    synthesised
+                        .entry(DomainName::reverse_pointer(address))
+                        .or_insert_with(Vec::new)
+                        .push((name.clone(), zr.ttl));
+                }
+            }
+        }
+
+        let mut count = 0;
+        for (ptr_name, targets) in synthesised {
+            if existing.contains(&ptr_name) {
+                continue;
+            }
+
+            let apex = self
+                .get(&ptr_name)
+                .map_or_else(DomainName::root_domain, |zone| zone.apex.clone());
+            let zone = Arc::make_mut(
+                self.zones
+                    .entry(apex.clone())
+                    .or_insert_with(|| Arc::new(Zone::new(apex, None))),
+            );
+            for (ptrdname, ttl) in targets {
+                zone.insert(&ptr_name, RecordTypeWithData::PTR { ptrdname }, ttl);
+                count += 1;
+            }
+        }
+
+        count
+    }
+
+    /// Find the names with a `CNAME` record whose chain, followed through
+    /// these zones, leads back to the name itself: queries for these names
+    /// can never be answered.
+    ///
+    /// Wildcard `CNAME` records are followed, but chains are only started
+    /// from names which have their own `CNAME` record.  A chain which joins a
+    /// loop without being part of it is not reported.
+    pub fn cname_loops(&self) -> Vec<DomainName> {
+        let mut owners = Vec::new();
+        for zone in self.zones.values() {
+            zone.records.cname_owners(&mut owners);
+        }
+
+        let mut loops = Vec::new();
+        for owner in owners {
+            let mut seen = HashSet::new();
+            let mut name = owner.clone();
+            while let Some((_, ZoneResult::CNAME { cname, .. })) =
+                self.resolve(&name, QueryType::Record(RecordType::A))
+            {
+                if cname == owner {
+                    loops.push(owner);
+                    break;
+                }
+                if !seen.insert(cname.clone()) {
+                    break;
+                }
+                name = cname;
             }
         }
+
+        loops.sort();
+        loops
     }
 }
 
@@ -97,6 +269,18 @@ pub struct Zone {
     /// then records for "www.barrucadu.co.uk" would be indexed under
     /// "www".
     records: ZoneRecords,
+
+    /// Whether `A` and `AAAA` records go into `compact` rather than
+    /// `records`.
+    is_compact: bool,
+
+    /// `A` and `AAAA` records stored compactly.  Only used if the zone is
+    /// compact, or has been merged with a compact zone.
+    compact: CompactRecords,
+
+    /// Whether CNAME chains starting in this zone are replaced with the
+    /// records they lead to.
+    flatten_cnames: bool,
 }
 
 impl Default for Zone {
@@ -165,9 +349,48 @@ impl Zone {
         if let Some(soa) = &soa {
             let rr = soa.to_rr(&apex);
             records.insert(&[], rr.rtype_with_data, rr.ttl);
-        };
+        }
+
+        Self {
+            apex,
+            soa,
+            records,
+            is_compact: false,
+            compact: CompactRecords::default(),
+            flatten_cnames: false,
+        }
+    }
+
+    /// Construct a new compact zone, which stores `A` and `AAAA` records
+    /// in a table rather than in a tree of labels.  This uses much less
+    /// memory for zones with a huge number of names and little else, like
+    /// blocklists, at the cost of slightly slower lookups.
+    ///
+    /// Each name in the table can have one `A` and one `AAAA` record: any
+    /// others, and all other record types, are stored as normal.
+    pub fn new_compact(apex: DomainName, soa: Option<SOA>) -> Self {
+        let mut zone = Self::new(apex, soa);
+        zone.is_compact = true;
+        zone
+    }
+
+    /// Returns true if the zone is compact.  See `Zone::new_compact`.
+    pub fn is_compact(&self) -> bool {
+        self.is_compact
+    }
+
+    /// Set whether CNAME chains starting in this zone are flattened: if a
+    /// chain can be followed to the end using only local data, the records at
+    /// the end are returned as if they belonged to the name which was asked
+    /// about, and the CNAMEs are left out.
+    pub fn set_flatten_cnames(&mut self, flatten_cnames: bool) {
+        self.flatten_cnames = flatten_cnames;
+    }
 
-        Self { apex, soa, records }
+    /// Returns true if CNAME chains starting in this zone are flattened.  See
+    /// `Zone::set_flatten_cnames`.
+    pub fn flattens_cnames(&self) -> bool {
+        self.flatten_cnames
     }
 
     /// Returns the apex domain.
@@ -190,14 +413,41 @@ impl Zone {
         self.soa.as_ref().map(|soa| soa.to_rr(&self.apex))
     }
 
+    /// Increase the SOA serial, so that secondaries notice that the zone has
+    /// changed.  Returns the new serial, or `None` if the zone is not
+    /// authoritative.
+    pub fn bump_serial(&mut self, bump: SerialBump) -> Option<u32> {
+        let soa = self.soa.as_mut()?;
+        soa.serial = bump.next_serial(soa.serial, SystemTime::now());
+
+        let rr = soa.to_rr(&self.apex);
+        self.records.this.insert(
+            RecordType::SOA,
+            vec![ZoneRecord {
+                rtype_with_data: rr.rtype_with_data,
+                ttl: rr.ttl,
+            }],
+        );
+
+        Some(soa.serial)
+    }
+
     /// Resolve a query.  Returns `None` if the domain is not a
     /// subdomain of the apex.
     ///
     /// This corresponds to step 3 of the standard nameserver
     /// algorithm (see section 4.3.2 of RFC 1034).
     pub fn resolve(&self, name: &DomainName, qtype: QueryType) -> Option<ZoneResult> {
-        self.relative_domain(name)
-            .map(|relative| self.records.resolve(name, qtype, relative))
+        self.relative_domain(name).map(|relative| {
+            let result = self.records.resolve(name, qtype, relative);
+            if self.compact.is_empty() {
+                result
+            } else {
+                let encloser_len = self.records.closest_encloser_len(relative);
+                self.compact
+                    .resolve(name, qtype, relative, encloser_len, result)
+            }
+        })
     }
 
     /// Insert a record for a domain.  This domain MUST be a subdomain
@@ -208,8 +458,16 @@ impl Zone {
     /// TTL is lower, it will be raised.
     pub fn insert(&mut self, name: &DomainName, rtype_with_data: RecordTypeWithData, ttl: u32) {
         if let Some(relative_domain) = self.relative_domain(name) {
-            self.records
-                .insert(relative_domain, rtype_with_data, self.actual_ttl(ttl));
+            let ttl = self.actual_ttl(ttl);
+            let rtype_with_data = if self.is_compact {
+                match self.compact.insert(relative_domain, rtype_with_data, ttl) {
+                    Ok(()) => return,
+                    Err(rtype_with_data) => rtype_with_data,
+                }
+            } else {
+                rtype_with_data
+            };
+            self.records.insert(relative_domain, rtype_with_data, ttl);
         }
     }
 
@@ -231,6 +489,94 @@ impl Zone {
         }
     }
 
+    /// Remove a record for a domain.  The `SOA` record can't be removed.
+    ///
+    /// Returns true if there was such a record.
+    pub fn remove(&mut self, name: &DomainName, rtype_with_data: &RecordTypeWithData) -> bool {
+        if rtype_with_data.rtype() == RecordType::SOA {
+            return false;
+        }
+
+        if let Some(relative_domain) = self.relative_domain(name) {
+            let in_compact = self
+                .compact
+                .remove(relative_domain, |other| other == rtype_with_data);
+            let in_records = self
+                .records
+                .remove(relative_domain, |zr| &zr.rtype_with_data == rtype_with_data);
+            !in_compact.is_empty() || !in_records.is_empty()
+        } else {
+            false
+        }
+    }
+
+    /// Remove all the records of one type for a domain.  The `SOA` record
+    /// can't be removed.
+    ///
+    /// Returns the removed records.
+    pub fn remove_rrset(&mut self, name: &DomainName, rtype: RecordType) -> Vec<ZoneRecord> {
+        if rtype == RecordType::SOA {
+            return Vec::new();
+        }
+
+        if let Some(relative_domain) = self.relative_domain(name) {
+            let mut removed = self
+                .compact
+                .remove(relative_domain, |other| other.rtype() == rtype);
+            removed.append(
+                &mut self
+                    .records
+                    .remove(relative_domain, |zr| zr.rtype_with_data.rtype() == rtype),
+            );
+            removed
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Replace all the records of one type for a domain, as if by
+    /// `Zone::remove_rrset` followed by `Zone::insert` for each new record.
+    /// New records of a different type are ignored, and the `SOA` record
+    /// can't be replaced.
+    ///
+    /// Returns the removed records.
+    pub fn replace_rrset(
+        &mut self,
+        name: &DomainName,
+        rtype: RecordType,
+        zrs: Vec<ZoneRecord>,
+    ) -> Vec<ZoneRecord> {
+        if rtype == RecordType::SOA {
+            return Vec::new();
+        }
+
+        let removed = self.remove_rrset(name, rtype);
+        for zr in zrs {
+            if zr.rtype_with_data.rtype() == rtype {
+                self.insert(name, zr.rtype_with_data, zr.ttl);
+            }
+        }
+        removed
+    }
+
+    /// Remove all the records for a domain and its subdomains, including
+    /// wildcard records.  If the domain is the apex, the `SOA` record is kept.
+    ///
+    /// Returns the number of records removed.
+    pub fn remove_subtree(&mut self, name: &DomainName) -> usize {
+        if let Some(relative_domain) = self.relative_domain(name) {
+            let mut count = self.compact.remove_subtree(relative_domain)
+                + self.records.remove_subtree(relative_domain);
+            if let Some(rr) = self.soa_rr().filter(|_| relative_domain.is_empty()) {
+                self.records.insert(&[], rr.rtype_with_data, rr.ttl);
+                count -= 1;
+            }
+            count
+        } else {
+            0
+        }
+    }
+
     /// Take a domain and chop off the suffix corresponding to the
     /// apex of this zone.
     ///
@@ -271,22 +617,80 @@ impl Zone {
 
         self.records.merge(other.records);
 
+        self.is_compact |= other.is_compact;
+        self.flatten_cnames |= other.flatten_cnames;
+        for (relative_domain, rtype_with_data, ttl) in self.compact.merge(other.compact) {
+            self.records.insert(&relative_domain, rtype_with_data, ttl);
+        }
+
         Ok(())
     }
 
-    /// Return all the records in the zone.
-    pub fn all_records(&self) -> HashMap<&DomainName, Vec<&ZoneRecord>> {
+    /// Return all the records in the zone, grouped by name.  This includes
+    /// the `SOA` record, if the zone is authoritative, but not wildcard
+    /// records: see `Zone::all_wildcard_records`.
+    pub fn all_records(&self) -> HashMap<DomainName, Vec<ZoneRecord>> {
         let mut map = HashMap::new();
         self.records.all_records(&mut map);
+        self.compact.all_records(&self.apex, &mut map);
         map
     }
 
-    /// Return all the wildcard records in the zone.
-    pub fn all_wildcard_records(&self) -> HashMap<&DomainName, Vec<&ZoneRecord>> {
+    /// Return all the wildcard records in the zone, grouped by the name which
+    /// the wildcard is beneath: so the records for `*.example.com` are under
+    /// `example.com`.
+    pub fn all_wildcard_records(&self) -> HashMap<DomainName, Vec<ZoneRecord>> {
         let mut map = HashMap::new();
         self.records.all_wildcard_records(&mut map);
         map
     }
+
+    /// Every record in the zone, including the `SOA` record and wildcard
+    /// records, in no particular order.  This is `Zone::all_records` and
+    /// `Zone::all_wildcard_records` combined, without the grouping.
+    pub fn iter_records(&self) -> impl Iterator<Item = NamedZoneRecord> {
+        let records = self
+            .all_records()
+            .into_iter()
+            .map(|(name, zrs)| (name, false, zrs));
+        let wildcards = self
+            .all_wildcard_records()
+            .into_iter()
+            .map(|(name, zrs)| (name, true, zrs));
+        records.chain(wildcards).flat_map(|(name, wildcard, zrs)| {
+            zrs.into_iter().map(move |record| NamedZoneRecord {
+                name: name.clone(),
+                wildcard,
+                record,
+            })
+        })
+    }
+
+    /// The number of records in the zone, including the `SOA` record but not
+    /// wildcard records.
+    pub fn record_count(&self) -> usize {
+        self.records.record_count() + self.compact.record_count()
+    }
+
+    /// The number of wildcard records in the zone.
+    pub fn wildcard_record_count(&self) -> usize {
+        self.records.wildcard_record_count()
+    }
+}
+
+/// A record in a zone, along with the name it belongs to.  See
+/// `Zone::iter_records`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamedZoneRecord {
+    /// The fully-qualified name of the record or, if this is a wildcard
+    /// record, of the name which the wildcard is beneath.
+    pub name: DomainName,
+
+    /// Whether this is a wildcard record, which belongs to `*.name` rather
+    /// than to `name`.
+    pub wildcard: bool,
+
+    pub record: ZoneRecord,
 }
 
 /// The result of looking up a name in a zone.
@@ -294,10 +698,16 @@ impl Zone {
 pub enum ZoneResult {
     Answer {
         rrs: Vec<ResourceRecord>,
+        /// Whether the records came from a wildcard, rather than from the
+        /// name itself.
+        wildcard: bool,
     },
     CNAME {
         cname: DomainName,
         rr: ResourceRecord,
+        /// Whether the record came from a wildcard, rather than from the
+        /// name itself.
+        wildcard: bool,
     },
     Delegation {
         ns_rrs: Vec<ResourceRecord>,
@@ -333,54 +743,91 @@ impl ZoneRecords {
         }
     }
 
-    /// Resolve a query
+    /// Resolve a query, where these are the records of the whole zone and so
+    /// this node is the apex.
+    ///
+    /// This walks down the tree to the closest encloser of the name: the
+    /// deepest node which exists, including nodes which only exist because
+    /// they have descendants (empty non-terminals).  Then:
+    ///
+    /// - if a node on the way, other than the apex, has `NS` records, it is a
+    ///   zone cut and the answer is a delegation (case 3.b of the standard
+    ///   nameserver algorithm).
+    ///
+    /// - if the closest encloser is the name, the answer is its records, or
+    ///   NODATA if it has none of the type (case 3.a).
+    ///
+    /// - otherwise the name does not exist, and the answer is synthesised
+    ///   from the closest encloser's wildcard (RFC 4592 section 3.3.1), or is
+    ///   NXDOMAIN if it has none.  A wildcard is never used for a name which
+    ///   exists, and a wildcard further up the tree is never used.
+    ///
+    /// A query for the wildcard's own name (`*.` followed by the closest
+    /// encloser) gets the wildcard records.
     pub fn resolve(
         &self,
         name: &DomainName,
         qtype: QueryType,
         relative_domain: &[Label],
     ) -> ZoneResult {
-        if relative_domain.is_empty() {
-            // Name matched entirely - this is either case 3.b (if
-            // this name is delegated elsewhere) or 3.a (if not) of
-            // the standard nameserver algorithm
-            zone_result_helper(name, qtype, &self.this, &self.nsdname)
-        } else {
-            let pos = relative_domain.len() - 1;
-            if let Some(child) = self.children.get(&relative_domain[pos]) {
-                child.resolve(name, qtype, &relative_domain[0..pos])
-            } else if let Some(wildcards) = &self.wildcards {
-                // Name cannot be matched further, but there are
-                // wildcards.  This is part of case 3.c of the standard
-                // nameserver algorithm.
-                //
-                // Semantics of wildcard NS records are "undefined"
-                // and the practice is "discouraged, but not barred"
-                // (RFC 4592).  So I've chosen to implement them as
-                // prepending the next label to the current name.
-                let mut labels = self.nsdname.labels.clone();
-                labels.insert(0, relative_domain[pos].clone());
-                let nsdname = DomainName::from_labels(labels).unwrap();
-                zone_result_helper(name, qtype, wildcards, &nsdname)
-            } else {
-                // Name cannot be matched further, and there are no
-                // wildcards.  Check if there are NS records here: if
-                // so, we can delegate (part 3.b of the standard
-                // nameserver algorithm), otherwise this is the other
-                // part of case 3.c.
-                match self.this.get(&RecordType::NS) {
-                    Some(ns_zrs) => {
-                        if ns_zrs.is_empty() {
-                            ZoneResult::NameError
-                        } else {
-                            ZoneResult::Delegation {
-                                ns_rrs: ns_zrs.iter().map(|zr| zr.to_rr(&self.nsdname)).collect(),
-                            }
-                        }
-                    }
-                    None => ZoneResult::NameError,
+        let mut node = self;
+        let mut remaining = relative_domain;
+        while let Some((label, rest)) = remaining.split_last() {
+            if !std::ptr::eq(node, self) {
+                if let Some(delegation) = node.delegation() {
+                    return delegation;
                 }
             }
+
+            let is_wildcard_name = rest.is_empty() && label.octets().as_ref() == b"*";
+            match (node.children.get(label), &node.wildcards) {
+                (_, Some(wildcards)) if is_wildcard_name => {
+                    return zone_result_helper(name, qtype, wildcards, true);
+                }
+                (Some(child), _) => {
+                    node = child;
+                    remaining = rest;
+                }
+                (None, Some(wildcards)) => {
+                    return zone_result_helper(name, qtype, wildcards, true);
+                }
+                (None, None) => return ZoneResult::NameError,
+            }
+        }
+
+        if QueryType::Record(RecordType::NS) != qtype && !std::ptr::eq(node, self) {
+            if let Some(delegation) = node.delegation() {
+                return delegation;
+            }
+        }
+        zone_result_helper(name, qtype, &node.this, false)
+    }
+
+    /// How many labels of the relative domain, from the apex down, lead to a
+    /// node in the tree.
+    fn closest_encloser_len(&self, relative_domain: &[Label]) -> usize {
+        let mut node = self;
+        let mut len = 0;
+        for label in relative_domain.iter().rev() {
+            let Some(child) = node.children.get(label) else {
+                break;
+            };
+            node = child;
+            len += 1;
+        }
+        len
+    }
+
+    /// The delegation to make if this node is a zone cut: that is, if it has
+    /// `NS` records.  Only meaningful for nodes other than the apex.
+    fn delegation(&self) -> Option<ZoneResult> {
+        let ns_zrs = self.this.get(&RecordType::NS)?;
+        if ns_zrs.is_empty() {
+            None
+        } else {
+            Some(ZoneResult::Delegation {
+                ns_rrs: ns_zrs.iter().map(|zr| zr.to_rr(&self.nsdname)).collect(),
+            })
         }
     }
 
@@ -473,6 +920,8 @@ impl ZoneRecords {
         if let Some(other_wildcards) = other.wildcards {
             if let Some(my_wildcards) = self.wildcards.as_mut() {
                 merge_zrs_helper(my_wildcards, other_wildcards);
+            } else {
+                self.wildcards = Some(other_wildcards);
             }
         }
 
@@ -486,10 +935,10 @@ impl ZoneRecords {
     }
 
     /// Return all the records in the zone.
-    pub fn all_records<'a>(&'a self, map: &mut HashMap<&'a DomainName, Vec<&'a ZoneRecord>>) {
-        let zrs: Vec<&ZoneRecord> = self.this.values().flatten().collect();
+    pub fn all_records(&self, map: &mut HashMap<DomainName, Vec<ZoneRecord>>) {
+        let zrs: Vec<ZoneRecord> = self.this.values().flatten().cloned().collect();
         if !zrs.is_empty() {
-            map.insert(&self.nsdname, zrs);
+            map.insert(self.nsdname.clone(), zrs);
         }
 
         for child in self.children.values() {
@@ -497,15 +946,100 @@ impl ZoneRecords {
         }
     }
 
+    /// Remove the records for a domain which match the predicate, and any
+    /// parts of the tree left empty.  Returns the removed records.
+    pub fn remove(
+        &mut self,
+        relative_domain: &[Label],
+        mut predicate: impl FnMut(&ZoneRecord) -> bool,
+    ) -> Vec<ZoneRecord> {
+        if let Some((label, remainder)) = relative_domain.split_last() {
+            let Some(child) = self.children.get_mut(label) else {
+                return Vec::new();
+            };
+            let removed = child.remove(remainder, predicate);
+            if child.is_empty() {
+                self.children.remove(label);
+            }
+            removed
+        } else {
+            let mut removed = Vec::new();
+            for zrs in self.this.values_mut() {
+                removed.extend(zrs.extract_if(.., |zr| predicate(zr)));
+            }
+            self.this.retain(|_, zrs| !zrs.is_empty());
+            removed
+        }
+    }
+
+    /// Remove all the records for a domain and its subdomains, and any parts
+    /// of the tree left empty.  Returns the number of records removed.
+    pub fn remove_subtree(&mut self, relative_domain: &[Label]) -> usize {
+        if let Some((label, remainder)) = relative_domain.split_last() {
+            let Some(child) = self.children.get_mut(label) else {
+                return 0;
+            };
+            let count = child.remove_subtree(remainder);
+            if child.is_empty() {
+                self.children.remove(label);
+            }
+            count
+        } else {
+            let count = self.record_count() + self.wildcard_record_count();
+            self.this.clear();
+            self.wildcards = None;
+            self.children.clear();
+            count
+        }
+    }
+
+    /// Whether there are no records here or below.
+    fn is_empty(&self) -> bool {
+        self.this.is_empty() && self.wildcards.is_none() && self.children.is_empty()
+    }
+
+    /// Collect the names in the zone which have a `CNAME` record, not
+    /// including wildcards.
+    fn cname_owners(&self, names: &mut Vec<DomainName>) {
+        if self.this.contains_key(&RecordType::CNAME) {
+            names.push(self.nsdname.clone());
+        }
+
+        for child in self.children.values() {
+            child.cname_owners(names);
+        }
+    }
+
+    /// Count the records in the zone.
+    pub fn record_count(&self) -> usize {
+        self.this.values().map(Vec::len).sum::<usize>()
+            + self
+                .children
+                .values()
+                .map(ZoneRecords::record_count)
+                .sum::<usize>()
+    }
+
+    /// Count the wildcard records in the zone.
+    pub fn wildcard_record_count(&self) -> usize {
+        self.wildcards
+            .iter()
+            .flat_map(HashMap::values)
+            .map(Vec::len)
+            .sum::<usize>()
+            + self
+                .children
+                .values()
+                .map(ZoneRecords::wildcard_record_count)
+                .sum::<usize>()
+    }
+
     /// Return all the wildcard records in the zone.
-    pub fn all_wildcard_records<'a>(
-        &'a self,
-        map: &mut HashMap<&'a DomainName, Vec<&'a ZoneRecord>>,
-    ) {
+    pub fn all_wildcard_records(&self, map: &mut HashMap<DomainName, Vec<ZoneRecord>>) {
         if let Some(ws) = &self.wildcards {
-            let zrs: Vec<&ZoneRecord> = ws.values().flatten().collect();
+            let zrs: Vec<ZoneRecord> = ws.values().flatten().cloned().collect();
             if !zrs.is_empty() {
-                map.insert(&self.nsdname, zrs);
+                map.insert(self.nsdname.clone(), zrs);
             }
         }
 
@@ -515,94 +1049,428 @@ impl ZoneRecords {
     }
 }
 
-/// A SOA record.
-#[derive(Debug, Clone, PartialEq, Eq)]
-#[cfg_attr(any(feature = "test-util", test), derive(arbitrary::Arbitrary))]
-pub struct SOA {
-    pub mname: DomainName,
-    pub rname: DomainName,
-    pub serial: u32,
-    pub refresh: u32,
-    pub retry: u32,
-    pub expire: u32,
-    pub minimum: u32,
+/// A table of `A` and `AAAA` records, used by compact zones.
+///
+/// A `ZoneRecords` node has several maps and a full `DomainName`, which adds
+/// up to hundreds of bytes per name: a blocklist with a million names can take
+/// up most of a gigabyte.  This has one small entry per name instead.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct CompactRecords {
+    /// Keyed by the labels relative to the apex, in reverse order, each
+    /// prefixed by its length.  So the subdomains of a name sort directly
+    /// after it.
+    entries: BTreeMap<Box<[u8]>, CompactEntry>,
 }
 
-impl SOA {
-    /// Convert it into a SOA RR
-    pub fn to_rr(&self, name: &DomainName) -> ResourceRecord {
-        ResourceRecord {
-            name: name.clone(),
-            rtype_with_data: self.to_rdata(),
-            rclass: RecordClass::IN,
-            ttl: self.minimum,
-        }
+/// The records for one name in a `CompactRecords`, with their TTLs.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+struct CompactEntry {
+    v4: Option<(Ipv4Addr, u32)>,
+    v6: Option<(Ipv6Addr, u32)>,
+}
+
+impl CompactRecords {
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
     }
 
-    /// Convert it into a SOA RDATA
-    pub fn to_rdata(&self) -> RecordTypeWithData {
-        RecordTypeWithData::SOA {
-            mname: self.mname.clone(),
-            rname: self.rname.clone(),
-            serial: self.serial,
-            refresh: self.refresh,
-            retry: self.retry,
-            expire: self.expire,
-            minimum: self.minimum,
+    /// Add a record.  Gives the record back if it can't be stored here,
+    /// because it is not an `A` or `AAAA` record, or because the name
+    /// already has a different record of that type.
+    fn insert(
+        &mut self,
+        relative_domain: &[Label],
+        rtype_with_data: RecordTypeWithData,
+        ttl: u32,
+    ) -> Result<(), RecordTypeWithData> {
+        match rtype_with_data {
+            RecordTypeWithData::A { address } => {
+                let entry = self.entries.entry(key(relative_domain)).or_default();
+                match entry.v4 {
+                    None => entry.v4 = Some((address, ttl)),
+                    Some(existing) if existing == (address, ttl) => (),
+                    Some(_) => return Err(rtype_with_data),
+                }
+                Ok(())
+            }
+            RecordTypeWithData::AAAA { address } => {
+                let entry = self.entries.entry(key(relative_domain)).or_default();
+                match entry.v6 {
+                    None => entry.v6 = Some((address, ttl)),
+                    Some(existing) if existing == (address, ttl) => (),
+                    Some(_) => return Err(rtype_with_data),
+                }
+                Ok(())
+            }
+            _ => Err(rtype_with_data),
         }
     }
-}
-
-/// A single record
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct ZoneRecord {
-    pub rtype_with_data: RecordTypeWithData,
-    pub ttl: u32,
-}
 
-impl ZoneRecord {
-    /// Convert it into an RR
-    pub fn to_rr(&self, name: &DomainName) -> ResourceRecord {
-        ResourceRecord {
-            name: name.clone(),
-            rtype_with_data: self.rtype_with_data.clone(),
-            rclass: RecordClass::IN,
-            ttl: self.ttl,
+    /// Merge another table into this one.  Returns the records which can't
+    /// be stored here.
+    fn merge(&mut self, other: CompactRecords) -> Vec<(Vec<Label>, RecordTypeWithData, u32)> {
+        let mut rejected = Vec::new();
+        for (key, other_entry) in other.entries {
+            let labels = labels_from_key(&key);
+            let records = other_entry
+                .v4
+                .map(|(address, ttl)| (RecordTypeWithData::A { address }, ttl))
+                .into_iter()
+                .chain(
+                    other_entry
+                        .v6
+                        .map(|(address, ttl)| (RecordTypeWithData::AAAA { address }, ttl)),
+                );
+            for (rtype_with_data, ttl) in records {
+                if let Err(rtype_with_data) = self.insert(&labels, rtype_with_data, ttl) {
+                    rejected.push((labels.clone(), rtype_with_data, ttl));
+                }
+            }
         }
+        rejected
     }
-}
 
-/// Handles the terminal cases of step 3 of the standard nameserver
-/// algorithm.  If we're here, we've got a domain and records which
-/// are associated with it.  The possible cases are:
-///
-/// - There are `NS` record(s) delegating this name elsewhere, and the
-///   qtype is not *exactly equal to* `NS`.  In which case we should
-///   return those.  If this is a top-level non-recursive query they
-///   will be returned in the AUTHORITY section.
-///
-/// - There's a `CNAME` record on this name, and the qtype does *not*
-///   match `CNAME`.  In which case we return a `CNAME` response, and
-///   the upstream resolver will then try go resolve that.
-///
-/// - Otherwise, return all RRs which match the query: this answers
-///   the question.
-fn zone_result_helper(
-    name: &DomainName,
-    qtype: QueryType,
-    records: &HashMap<RecordType, Vec<ZoneRecord>>,
-    nsdname: &DomainName,
-) -> ZoneResult {
-    if QueryType::Record(RecordType::NS) != qtype {
-        if let Some(ns_zrs) = records.get(&RecordType::NS) {
-            if !ns_zrs.is_empty() {
-                return ZoneResult::Delegation {
-                    ns_rrs: ns_zrs.iter().map(|zr| zr.to_rr(nsdname)).collect(),
-                };
+    /// Combine the result of resolving a name in the `ZoneRecords` with the
+    /// records in the table.
+    ///
+    /// A name in the table hides wildcards, like a name in the tree does.
+    /// But a delegation or `CNAME` in the tree takes priority.
+    ///
+    /// `encloser_len` is how many labels of the relative domain are in the
+    /// tree.  If a name in the table is between that and the name, it's the
+    /// real closest encloser: the table has no wildcards, so a wildcard answer
+    /// from the tree becomes NXDOMAIN.
+    fn resolve(
+        &self,
+        name: &DomainName,
+        qtype: QueryType,
+        relative_domain: &[Label],
+        encloser_len: usize,
+        result: ZoneResult,
+    ) -> ZoneResult {
+        let key = key(relative_domain);
+        let entry = self.entries.get(&key);
+        let exists = entry.is_some() || self.has_descendants(&key);
+
+        match result {
+            ZoneResult::Answer {
+                mut rrs,
+                wildcard: false,
+            } => {
+                if let Some(entry) = entry {
+                    for rr in entry.rrs(name, qtype) {
+                        if !rrs.contains(&rr) {
+                            rrs.push(rr);
+                        }
+                    }
+                }
+                ZoneResult::Answer {
+                    rrs,
+                    wildcard: false,
+                }
+            }
+            ZoneResult::Answer { wildcard: true, .. }
+            | ZoneResult::CNAME { wildcard: true, .. }
+            | ZoneResult::NameError
+                if exists =>
+            {
+                ZoneResult::Answer {
+                    rrs: entry
+                        .map(|entry| entry.rrs(name, qtype))
+                        .unwrap_or_default(),
+                    wildcard: false,
+                }
+            }
+            ZoneResult::Answer { wildcard: true, .. }
+            | ZoneResult::CNAME { wildcard: true, .. }
+                if encloser_len + 1 < relative_domain.len() =>
+            {
+                let ancestor =
+                    self::key(&relative_domain[relative_domain.len() - encloser_len - 1..]);
+                if self.entries.contains_key(&ancestor) || self.has_descendants(&ancestor) {
+                    ZoneResult::NameError
+                } else {
+                    result
+                }
             }
+            result => result,
         }
     }
 
+    /// Whether there are any names in the table below this one.
+    fn has_descendants(&self, key: &[u8]) -> bool {
+        self.entries
+            .range::<[u8], _>((Bound::Excluded(key), Bound::Unbounded))
+            .next()
+            .is_some_and(|(other, _)| other.starts_with(key))
+    }
+
+    /// Remove the records for a name which match the predicate.  Returns the
+    /// removed records.
+    fn remove(
+        &mut self,
+        relative_domain: &[Label],
+        mut predicate: impl FnMut(&RecordTypeWithData) -> bool,
+    ) -> Vec<ZoneRecord> {
+        let key = key(relative_domain);
+        let Some(entry) = self.entries.get_mut(&key) else {
+            return Vec::new();
+        };
+
+        let mut removed = Vec::new();
+        if let Some((address, ttl)) = entry.v4 {
+            let rtype_with_data = RecordTypeWithData::A { address };
+            if predicate(&rtype_with_data) {
+                entry.v4 = None;
+                removed.push(ZoneRecord {
+                    rtype_with_data,
+                    ttl,
+                });
+            }
+        }
+        if let Some((address, ttl)) = entry.v6 {
+            let rtype_with_data = RecordTypeWithData::AAAA { address };
+            if predicate(&rtype_with_data) {
+                entry.v6 = None;
+                removed.push(ZoneRecord {
+                    rtype_with_data,
+                    ttl,
+                });
+            }
+        }
+        if entry.v4.is_none() && entry.v6.is_none() {
+            self.entries.remove(&key);
+        }
+        removed
+    }
+
+    /// Remove the records for a name and the names below it.  Returns the
+    /// number of records removed.
+    fn remove_subtree(&mut self, relative_domain: &[Label]) -> usize {
+        let key = key(relative_domain);
+        let keys = self
+            .entries
+            .range::<[u8], _>((Bound::Included(&key[..]), Bound::Unbounded))
+            .map(|(other, _)| other)
+            .take_while(|other| other.starts_with(&key))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut count = 0;
+        for other in keys {
+            if let Some(entry) = self.entries.remove(&other) {
+                count += entry.records().count();
+            }
+        }
+        count
+    }
+
+    /// Count the records in the table.
+    fn record_count(&self) -> usize {
+        self.entries
+            .values()
+            .map(|entry| entry.records().count())
+            .sum()
+    }
+
+    /// Return all the records in the table.
+    fn all_records(&self, apex: &DomainName, map: &mut HashMap<DomainName, Vec<ZoneRecord>>) {
+        for (key, entry) in &self.entries {
+            let mut labels = labels_from_key(key);
+            labels.extend_from_slice(&apex.labels);
+            // safe because the labels came from a valid name in this zone
+            let name = DomainName::from_labels(labels).unwrap();
+            let zrs = map.entry(name).or_default();
+            for (rtype_with_data, ttl) in entry.records() {
+                zrs.push(ZoneRecord {
+                    rtype_with_data,
+                    ttl,
+                });
+            }
+        }
+    }
+}
+
+impl CompactEntry {
+    fn records(&self) -> impl Iterator<Item = (RecordTypeWithData, u32)> {
+        let v4 = self
+            .v4
+            .map(|(address, ttl)| (RecordTypeWithData::A { address }, ttl));
+        let v6 = self
+            .v6
+            .map(|(address, ttl)| (RecordTypeWithData::AAAA { address }, ttl));
+        v4.into_iter().chain(v6)
+    }
+
+    fn rrs(&self, name: &DomainName, qtype: QueryType) -> Vec<ResourceRecord> {
+        self.records()
+            .filter(|(rtype_with_data, _)| rtype_with_data.matches(qtype))
+            .map(|(rtype_with_data, ttl)| ResourceRecord {
+                name: name.clone(),
+                rtype_with_data,
+                rclass: RecordClass::IN,
+                ttl,
+            })
+            .collect()
+    }
+}
+
+/// The `CompactRecords` key for a relative domain.  Keys are lowercase, as
+/// names are compared case-insensitively.
+#[allow(clippy::cast_possible_truncation)]
+fn key(relative_domain: &[Label]) -> Box<[u8]> {
+    let mut key = Vec::with_capacity(
+        relative_domain
+            .iter()
+            .map(|label| label.len() as usize + 1)
+            .sum(),
+    );
+    for label in relative_domain.iter().rev() {
+        key.push(label.len());
+        key.extend(label.octets().iter().map(u8::to_ascii_lowercase));
+    }
+    key.into_boxed_slice()
+}
+
+/// The relative domain for a `CompactRecords` key.
+#[allow(clippy::missing_panics_doc)]
+fn labels_from_key(key: &[u8]) -> Vec<Label> {
+    let mut labels = Vec::new();
+    let mut i = 0;
+    while i < key.len() {
+        let len = usize::from(key[i]);
+        // safe because the key was built from valid labels
+        labels.push(Label::try_from(&key[i + 1..i + 1 + len]).unwrap());
+        i += 1 + len;
+    }
+    labels.reverse();
+    labels
+}
+
+/// A SOA record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(any(feature = "test-util", test), derive(arbitrary::Arbitrary))]
+pub struct SOA {
+    pub mname: DomainName,
+    pub rname: DomainName,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
+impl SOA {
+    /// Convert it into a SOA RR
+    pub fn to_rr(&self, name: &DomainName) -> ResourceRecord {
+        ResourceRecord {
+            name: name.clone(),
+            rtype_with_data: self.to_rdata(),
+            rclass: RecordClass::IN,
+            ttl: self.minimum,
+        }
+    }
+
+    /// Convert it into a SOA RDATA
+    pub fn to_rdata(&self) -> RecordTypeWithData {
+        RecordTypeWithData::SOA {
+            mname: self.mname.clone(),
+            rname: self.rname.clone(),
+            serial: self.serial,
+            refresh: self.refresh,
+            retry: self.retry,
+            expire: self.expire,
+            minimum: self.minimum,
+        }
+    }
+}
+
+/// How to increase the SOA serial of a zone which has changed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SerialBump {
+    /// Add one to the serial.
+    Increment,
+
+    /// Use the current date (in UTC) followed by a two-digit revision, like
+    /// `2024030500`.  If the serial is already at or past the first revision
+    /// for today, add one to it instead.
+    Date,
+}
+
+impl SerialBump {
+    /// Work out the serial which comes after this one, at the given time.
+    pub fn next_serial(self, serial: u32, now: SystemTime) -> u32 {
+        let date_serial = match self {
+            SerialBump::Increment => None,
+            SerialBump::Date => date_serial(now),
+        };
+
+        match date_serial {
+            Some(date_serial) if date_serial > serial => date_serial,
+            _ => serial.wrapping_add(1),
+        }
+    }
+}
+
+/// The first `YYYYMMDDnn` serial of the day, or `None` if it doesn't fit in a
+/// `u32`.
+fn date_serial(now: SystemTime) -> Option<u32> {
+    let days = now.duration_since(UNIX_EPOCH).ok()?.as_secs() / 86400;
+
+    // from days since the epoch to a (year, month, day) date in the proleptic
+    // Gregorian calendar: see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    u32::try_from(year * 1_000_000 + month * 10_000 + day * 100).ok()
+}
+
+/// A single record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneRecord {
+    pub rtype_with_data: RecordTypeWithData,
+    pub ttl: u32,
+}
+
+impl ZoneRecord {
+    /// Convert it into an RR
+    pub fn to_rr(&self, name: &DomainName) -> ResourceRecord {
+        ResourceRecord {
+            name: name.clone(),
+            rtype_with_data: self.rtype_with_data.clone(),
+            rclass: RecordClass::IN,
+            ttl: self.ttl,
+        }
+    }
+}
+
+/// Handles the terminal cases of step 3 of the standard nameserver
+/// algorithm.  If we're here, we've got a domain and records which
+/// are associated with it, and any delegation has already been
+/// handled.  The possible cases are:
+///
+/// - There's a `CNAME` record on this name, and the qtype does *not*
+///   match `CNAME`.  In which case we return a `CNAME` response, and
+///   the upstream resolver will then try go resolve that.
+///
+/// - Otherwise, return all RRs which match the query: this answers
+///   the question.
+///
+/// The `wildcard` flag says whether `records` are wildcard records,
+/// and is passed through to the result.
+fn zone_result_helper(
+    name: &DomainName,
+    qtype: QueryType,
+    records: &HashMap<RecordType, Vec<ZoneRecord>>,
+    wildcard: bool,
+) -> ZoneResult {
     if !RecordType::CNAME.matches(qtype) {
         if let Some(cname_zrs) = records.get(&RecordType::CNAME) {
             if !cname_zrs.is_empty() {
@@ -611,6 +1479,7 @@ fn zone_result_helper(
                     return ZoneResult::CNAME {
                         cname: cname.clone(),
                         rr,
+                        wildcard,
                     };
                 }
                 panic!("got non-CNAME record for CNAME query: {rr:?}");
@@ -624,7 +1493,7 @@ fn zone_result_helper(
             for zrs in records.values() {
                 rrs.append(&mut zrs.iter().map(|zr| zr.to_rr(name)).collect());
             }
-            ZoneResult::Answer { rrs }
+            ZoneResult::Answer { rrs, wildcard }
         }
         QueryType::Record(rtype) => ZoneResult::Answer {
             rrs: if let Some(zrs) = records.get(&rtype) {
@@ -632,8 +1501,12 @@ fn zone_result_helper(
             } else {
                 Vec::new()
             },
+            wildcard,
+        },
+        _ => ZoneResult::Answer {
+            rrs: Vec::new(),
+            wildcard,
         },
-        _ => ZoneResult::Answer { rrs: Vec::new() },
     }
 }
 
@@ -657,9 +1530,36 @@ fn merge_zrs_helper(
     }
 }
 
+#[cfg(any(feature = "test-util", test))]
+#[allow(clippy::missing_panics_doc)]
+pub mod test_util {
+    use super::*;
+
+    use arbitrary::{Arbitrary, Unstructured};
+    use rand::Rng;
+
+    pub fn arbitrary_zone() -> Zone {
+        let mut rng = rand::thread_rng();
+        // zones have up to 128 records, so can need a lot of data
+        for size in [1024, 4096, 16384, 65536] {
+            let mut buf = Vec::new();
+            for _ in 0..size {
+                buf.push(rng.gen());
+            }
+
+            if let Ok(zone) = Zone::arbitrary(&mut Unstructured::new(&buf)) {
+                return zone;
+            }
+        }
+
+        panic!("could not generate arbitrary value!");
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::net::Ipv4Addr;
+    use bytes::Bytes;
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
     use super::*;
     use crate::protocol::types::test_util::*;
@@ -684,6 +1584,148 @@ mod tests {
         assert_eq!(Some(&zone), zones.get(&domain("www.example.com.")));
     }
 
+    #[test]
+    fn zones_synthesise_reverse_records() {
+        let mut forward = Zone::new(domain("example.com."), None);
+        forward.insert(
+            &domain("www.example.com."),
+            RecordTypeWithData::A {
+                address: Ipv4Addr::new(192, 168, 1, 10),
+            },
+            300,
+        );
+        forward.insert(
+            &domain("www.example.com."),
+            RecordTypeWithData::AAAA {
+                address: "2001:db8::1".parse().unwrap(),
+            },
+            300,
+        );
+        forward.insert(
+            &domain("blocked.example.com."),
+            RecordTypeWithData::A {
+                address: Ipv4Addr::UNSPECIFIED,
+            },
+            300,
+        );
+
+        let mut zones = Zones::new();
+        zones.insert(forward);
+        zones.insert(Zone::new(
+            domain("168.192.in-addr.arpa."),
+            Some(SOA {
+                mname: domain("ns.example.com."),
+                rname: domain("hostmaster.example.com."),
+                serial: 1,
+                refresh: 300,
+                retry: 300,
+                expire: 300,
+                minimum: 300,
+            }),
+        ));
+
+        assert_eq!(2, zones.synthesise_reverse_records());
+
+        let (zone, result) = zones
+            .resolve(
+                &domain("10.1.168.192.in-addr.arpa."),
+                QueryType::Record(RecordType::PTR),
+            )
+            .unwrap();
+        assert_eq!(&domain("168.192.in-addr.arpa."), zone.get_apex());
+        assert_eq!(
+            ZoneResult::Answer {
+                rrs: vec![ResourceRecord {
+                    name: domain("10.1.168.192.in-addr.arpa."),
+                    rtype_with_data: RecordTypeWithData::PTR {
+                        ptrdname: domain("www.example.com."),
+                    },
+                    rclass: RecordClass::IN,
+                    ttl: 300,
+                }],
+                wildcard: false,
+            },
+            result
+        );
+
+        let ipv6_name =
+            domain("1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa.");
+        let (zone, _) = zones
+            .resolve(&ipv6_name, QueryType::Record(RecordType::PTR))
+            .unwrap();
+        assert_eq!(&DomainName::root_domain(), zone.get_apex());
+
+        assert_eq!(
+            Some(ZoneResult::NameError),
+            zones
+                .resolve(
+                    &domain("0.0.0.0.in-addr.arpa."),
+                    QueryType::Record(RecordType::PTR)
+                )
+                .map(|(_, result)| result)
+        );
+    }
+
+    #[test]
+    fn zones_synthesise_reverse_records_keeps_existing() {
+        let mut zone = Zone::new(DomainName::root_domain(), None);
+        zone.insert(
+            &domain("www.example.com."),
+            RecordTypeWithData::A {
+                address: Ipv4Addr::new(192, 0, 2, 1),
+            },
+            300,
+        );
+        zone.insert(
+            &domain("1.2.0.192.in-addr.arpa."),
+            RecordTypeWithData::PTR {
+                ptrdname: domain("example.com."),
+            },
+            300,
+        );
+
+        let mut zones = Zones::new();
+        zones.insert(zone);
+
+        assert_eq!(0, zones.synthesise_reverse_records());
+    }
+
+    #[test]
+    fn zones_cname_loops() {
+        let mut zone = Zone::new(domain("example.com."), None);
+        for (name, target) in [
+            ("a.example.com.", "b.example.com."),
+            ("b.example.com.", "a.example.com."),
+            ("c.example.com.", "a.example.com."),
+            ("d.example.com.", "d.example.com."),
+            ("e.example.com.", "f.example.com."),
+            ("y.example.com.", "x.w.example.com."),
+        ] {
+            let rr = cname_record(name, target);
+            zone.insert(&rr.name, rr.rtype_with_data, rr.ttl);
+        }
+        zone.insert_wildcard(
+            &domain("w.example.com."),
+            RecordTypeWithData::CNAME {
+                cname: domain("y.example.com."),
+            },
+            300,
+        );
+
+        let mut zones = Zones::new();
+        zones.insert(zone);
+
+        assert_eq!(
+            vec![
+                domain("a.example.com."),
+                domain("b.example.com."),
+                domain("d.example.com."),
+                domain("y.example.com."),
+            ],
+            zones.cname_loops()
+        );
+    }
+
     #[test]
     fn zone_merge_prefers_leftmost_some_authority() {
         let name = domain("example.com.");
@@ -721,6 +1763,31 @@ mod tests {
         assert_eq!(Some(soa2), zone3.soa);
     }
 
+    #[test]
+    fn zone_merge_keeps_wildcards() {
+        let mut zone = Zone::new(domain("example.com."), None);
+        let mut other = Zone::new(domain("example.com."), None);
+        other.insert_wildcard(
+            &domain("example.com."),
+            RecordTypeWithData::A {
+                address: Ipv4Addr::new(1, 1, 1, 1),
+            },
+            300,
+        );
+        zone.merge(other).unwrap();
+
+        assert_eq!(
+            Some(ZoneResult::Answer {
+                rrs: vec![a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1))],
+                wildcard: true,
+            }),
+            zone.resolve(
+                &domain("www.example.com."),
+                QueryType::Record(RecordType::A)
+            )
+        );
+    }
+
     #[test]
     fn zone_merge_checks_apex_consistency() {
         Zone::new(domain("example.com."), None)
@@ -745,7 +1812,7 @@ mod tests {
 
         zone1.merge(zone2).unwrap();
 
-        if let Some(ZoneResult::Answer { mut rrs }) =
+        if let Some(ZoneResult::Answer { mut rrs, .. }) =
             zone1.resolve(&domain("www.example.com."), QueryType::Wildcard)
         {
             let mut expected = vec![a_rr1, a_rr2];
@@ -802,11 +1869,73 @@ mod tests {
         let zone = Zone::new(apex.clone(), Some(soa));
 
         assert_eq!(
-            Some(ZoneResult::Answer { rrs: vec![soa_rr] }),
+            Some(ZoneResult::Answer {
+                rrs: vec![soa_rr],
+                wildcard: false
+            }),
             zone.resolve(&apex, QueryType::Record(RecordType::SOA))
         );
     }
 
+    #[test]
+    fn zone_bump_serial() {
+        let apex = domain("example.com.");
+        let mut soa = SOA {
+            mname: domain("mname."),
+            rname: domain("rname."),
+            serial: 1,
+            refresh: 2,
+            retry: 3,
+            expire: 4,
+            minimum: 5,
+        };
+
+        let mut zone = Zone::new(apex.clone(), Some(soa.clone()));
+        assert_eq!(Some(2), zone.bump_serial(SerialBump::Increment));
+
+        soa.serial = 2;
+        assert_eq!(Some(&soa), zone.get_soa());
+        assert_eq!(
+            Some(ZoneResult::Answer {
+                rrs: vec![soa.to_rr(&apex)],
+                wildcard: false
+            }),
+            zone.resolve(&apex, QueryType::Record(RecordType::SOA))
+        );
+    }
+
+    #[test]
+    fn zone_bump_serial_nonauthoritative() {
+        let mut zone = Zone::default();
+        assert_eq!(None, zone.bump_serial(SerialBump::Increment));
+    }
+
+    #[test]
+    fn serial_bump_increment() {
+        let now = SystemTime::now();
+        assert_eq!(2, SerialBump::Increment.next_serial(1, now));
+        assert_eq!(0, SerialBump::Increment.next_serial(u32::MAX, now));
+    }
+
+    #[test]
+    fn serial_bump_date() {
+        // 2024-03-05T12:00:00Z
+        let now = UNIX_EPOCH + std::time::Duration::from_hours(474_900);
+        assert_eq!(2_024_030_500, SerialBump::Date.next_serial(1, now));
+        assert_eq!(
+            2_024_030_501,
+            SerialBump::Date.next_serial(2_024_030_500, now)
+        );
+        assert_eq!(
+            2_025_000_001,
+            SerialBump::Date.next_serial(2_025_000_000, now)
+        );
+
+        // 2000-02-29T23:59:59Z
+        let now = UNIX_EPOCH + std::time::Duration::from_secs(951_868_799);
+        assert_eq!(2_000_022_900, SerialBump::Date.next_serial(1, now));
+    }
+
     #[test]
     fn zone_insert_resolve() {
         for _ in 0..100 {
@@ -819,6 +1948,7 @@ mod tests {
 
             let expected = Some(ZoneResult::Answer {
                 rrs: vec![rr.clone()],
+                wildcard: false,
             });
 
             assert_eq!(
@@ -843,6 +1973,7 @@ mod tests {
 
             let expected = Some(ZoneResult::Answer {
                 rrs: vec![rr.clone()],
+                wildcard: true,
             });
 
             assert_eq!(
@@ -853,6 +1984,126 @@ mod tests {
         }
     }
 
+    #[test]
+    fn compact_zone_combines_records() {
+        let mut zone = Zone::new_compact(domain("example.com."), None);
+        let a_rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        let mx_rr = ResourceRecord {
+            name: domain("www.example.com."),
+            rtype_with_data: RecordTypeWithData::MX {
+                preference: 10,
+                exchange: domain("mail.example.com."),
+            },
+            rclass: RecordClass::IN,
+            ttl: 300,
+        };
+        zone.insert(&a_rr.name, a_rr.rtype_with_data.clone(), a_rr.ttl);
+        zone.insert(&mx_rr.name, mx_rr.rtype_with_data.clone(), mx_rr.ttl);
+
+        assert_eq!(1, zone.compact.entries.len());
+        assert_eq!(
+            Some(ZoneResult::Answer {
+                rrs: vec![a_rr.clone()],
+                wildcard: false
+            }),
+            zone.resolve(&a_rr.name, QueryType::Record(RecordType::A))
+        );
+        assert_eq!(
+            Some(ZoneResult::Answer {
+                rrs: vec![mx_rr.clone(), a_rr.clone()],
+                wildcard: false
+            }),
+            zone.resolve(&a_rr.name, QueryType::Wildcard)
+        );
+        assert_eq!(
+            Some(ZoneResult::Answer {
+                rrs: Vec::new(),
+                wildcard: false
+            }),
+            zone.resolve(&domain("example.com."), QueryType::Record(RecordType::A))
+        );
+        assert_eq!(
+            Some(ZoneResult::NameError),
+            zone.resolve(
+                &domain("foo.example.com."),
+                QueryType::Record(RecordType::A)
+            )
+        );
+    }
+
+    #[test]
+    fn compact_zone_stores_extra_addresses_normally() {
+        let mut zone = Zone::new_compact(domain("example.com."), None);
+        let a_rr1 = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        let a_rr2 = a_record("www.example.com.", Ipv4Addr::new(2, 2, 2, 2));
+        zone.insert(&a_rr1.name, a_rr1.rtype_with_data.clone(), a_rr1.ttl);
+        zone.insert(&a_rr2.name, a_rr2.rtype_with_data.clone(), a_rr2.ttl);
+
+        let mut other = Zone::new_compact(domain("example.com."), None);
+        let a_rr3 = a_record("www.example.com.", Ipv4Addr::new(3, 3, 3, 3));
+        other.insert(&a_rr3.name, a_rr3.rtype_with_data.clone(), a_rr3.ttl);
+        zone.merge(other).unwrap();
+
+        assert_eq!(
+            Some(ZoneResult::Answer {
+                rrs: vec![a_rr2, a_rr3, a_rr1],
+                wildcard: false
+            }),
+            zone.resolve(
+                &domain("www.example.com."),
+                QueryType::Record(RecordType::A)
+            )
+        );
+    }
+
+    #[test]
+    fn compact_zone_names_hide_wildcards() {
+        let mut zone = Zone::new_compact(domain("example.com."), None);
+        let a_rr = a_record("blocked.example.com.", Ipv4Addr::UNSPECIFIED);
+        zone.insert(&a_rr.name, a_rr.rtype_with_data.clone(), a_rr.ttl);
+        zone.insert_wildcard(
+            &domain("example.com."),
+            RecordTypeWithData::CNAME {
+                cname: domain("target.example.net."),
+            },
+            300,
+        );
+
+        assert_eq!(
+            Some(ZoneResult::Answer {
+                rrs: vec![a_rr.clone()],
+                wildcard: false
+            }),
+            zone.resolve(&a_rr.name, QueryType::Record(RecordType::A))
+        );
+        assert!(matches!(
+            zone.resolve(
+                &domain("other.example.com."),
+                QueryType::Record(RecordType::A)
+            ),
+            Some(ZoneResult::CNAME { wildcard: true, .. })
+        ));
+    }
+
+    #[test]
+    fn compact_zone_all_records() {
+        let mut zone = Zone::new_compact(domain("example.com."), None);
+        let a_rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        let aaaa_rr = aaaa_record("www.example.com.", Ipv6Addr::LOCALHOST);
+        zone.insert(&a_rr.name, a_rr.rtype_with_data.clone(), a_rr.ttl);
+        zone.insert(&aaaa_rr.name, aaaa_rr.rtype_with_data.clone(), aaaa_rr.ttl);
+
+        let all_records = zone.all_records();
+        assert_eq!(1, all_records.len());
+        assert_eq!(
+            vec![a_rr.clone(), aaaa_rr],
+            all_records[&a_rr.name]
+                .iter()
+                .map(|zr| zr.to_rr(&a_rr.name))
+                .collect::<Vec<_>>()
+        );
+    }
+
     #[test]
     fn zone_insert_all_records() {
         let mut zone = Zone::new(domain("example.com."), None);
@@ -903,6 +2154,221 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn zone_iter_records() {
+        let mut zone = Zone::new_compact(domain("example.com."), Some(example_soa()));
+        let a_rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        let cname_rr = cname_record("foo.example.com.", "www.example.com.");
+        let wildcard_rr = a_record("bar.example.com.", Ipv4Addr::new(2, 2, 2, 2));
+        zone.insert(&a_rr.name, a_rr.rtype_with_data.clone(), a_rr.ttl);
+        zone.insert(
+            &cname_rr.name,
+            cname_rr.rtype_with_data.clone(),
+            cname_rr.ttl,
+        );
+        zone.insert_wildcard(
+            &wildcard_rr.name,
+            wildcard_rr.rtype_with_data.clone(),
+            wildcard_rr.ttl,
+        );
+
+        let mut expected = vec![
+            (zone.soa_rr().unwrap(), false),
+            (a_rr, false),
+            (cname_rr, false),
+            (wildcard_rr, true),
+        ];
+        for (rr, _) in &mut expected {
+            rr.ttl = zone.actual_ttl(rr.ttl);
+        }
+        expected.sort();
+
+        let mut actual = zone
+            .iter_records()
+            .map(|nzr| (nzr.record.to_rr(&nzr.name), nzr.wildcard))
+            .collect::<Vec<_>>();
+        actual.sort();
+
+        assert_eq!(expected, actual);
+        assert_eq!(3, zone.record_count());
+        assert_eq!(1, zone.wildcard_record_count());
+    }
+
+    #[test]
+    fn zones_record_count() {
+        let mut zones = Zones::new();
+        assert!(zones.is_empty());
+        assert_eq!(0, zones.record_count());
+
+        let mut zone = Zone::new(domain("example.com."), Some(example_soa()));
+        let rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        zone.insert(&rr.name, rr.rtype_with_data.clone(), rr.ttl);
+        zone.insert_wildcard(&rr.name, rr.rtype_with_data, rr.ttl);
+        zones.insert(zone);
+        zones.insert(Zone::new(domain("example.net."), None));
+
+        assert_eq!(2, zones.len());
+        assert_eq!(3, zones.record_count());
+    }
+
+    #[test]
+    fn zones_clone_is_snapshot() {
+        let mut zones = Zones::new();
+        let a_rr = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        let mut zone = Zone::new(domain("example.com."), None);
+        zone.insert(&a_rr.name, a_rr.rtype_with_data.clone(), a_rr.ttl);
+        zones.insert(zone);
+
+        let snapshot = zones.clone();
+        zones
+            .get_mut(&a_rr.name)
+            .unwrap()
+            .remove(&a_rr.name, &a_rr.rtype_with_data);
+
+        assert_eq!(0, zones.record_count());
+        assert_eq!(1, snapshot.record_count());
+    }
+
+    #[test]
+    fn zones_remove_subtree() {
+        let mut zones = Zones::new();
+        let mut parent = Zone::new(domain("example.com."), Some(example_soa()));
+        let mut child = Zone::new(domain("sub.www.example.com."), Some(example_soa()));
+        for name in ["www.example.com.", "foo.www.example.com.", "example.com."] {
+            let rr = a_record(name, Ipv4Addr::new(1, 1, 1, 1));
+            parent.insert(&rr.name, rr.rtype_with_data, rr.ttl);
+        }
+        let rr = a_record("sub.www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        child.insert(&rr.name, rr.rtype_with_data, rr.ttl);
+        zones.insert(parent);
+        zones.insert(child);
+
+        assert_eq!(4, zones.remove_subtree(&domain("www.example.com.")));
+        assert_eq!(1, zones.len());
+        assert_eq!(2, zones.record_count());
+    }
+
+    #[test]
+    fn zone_remove() {
+        let mut zone = Zone::new(domain("example.com."), None);
+        let a_rr1 = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        let a_rr2 = a_record("www.example.com.", Ipv4Addr::new(2, 2, 2, 2));
+        zone.insert(&a_rr1.name, a_rr1.rtype_with_data.clone(), a_rr1.ttl);
+        zone.insert(&a_rr2.name, a_rr2.rtype_with_data.clone(), a_rr2.ttl);
+
+        assert!(zone.remove(&a_rr1.name, &a_rr1.rtype_with_data));
+        assert!(!zone.remove(&a_rr1.name, &a_rr1.rtype_with_data));
+        assert_eq!(
+            Some(ZoneResult::Answer {
+                rrs: vec![a_rr2.clone()],
+                wildcard: false
+            }),
+            zone.resolve(&a_rr1.name, QueryType::Wildcard)
+        );
+
+        assert!(zone.remove(&a_rr2.name, &a_rr2.rtype_with_data));
+        assert_eq!(
+            Some(ZoneResult::NameError),
+            zone.resolve(&a_rr1.name, QueryType::Wildcard)
+        );
+    }
+
+    #[test]
+    fn zone_remove_keeps_soa() {
+        let soa = example_soa();
+        let mut zone = Zone::new(domain("example.com."), Some(soa.clone()));
+
+        assert!(!zone.remove(&domain("example.com."), &soa.to_rdata()));
+        assert!(zone
+            .remove_rrset(&domain("example.com."), RecordType::SOA)
+            .is_empty());
+        assert_eq!(0, zone.remove_subtree(&domain("example.com.")));
+        assert_eq!(
+            Some(ZoneResult::Answer {
+                rrs: vec![zone.soa_rr().unwrap()],
+                wildcard: false
+            }),
+            zone.resolve(&domain("example.com."), QueryType::Record(RecordType::SOA))
+        );
+    }
+
+    #[test]
+    fn zone_replace_rrset() {
+        let mut zone = Zone::new_compact(domain("example.com."), None);
+        let a_rr1 = a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1));
+        let a_rr2 = a_record("www.example.com.", Ipv4Addr::new(2, 2, 2, 2));
+        let aaaa_rr = aaaa_record("www.example.com.", Ipv6Addr::LOCALHOST);
+        zone.insert(&a_rr1.name, a_rr1.rtype_with_data.clone(), a_rr1.ttl);
+        zone.insert(&aaaa_rr.name, aaaa_rr.rtype_with_data.clone(), aaaa_rr.ttl);
+
+        let removed = zone.replace_rrset(
+            &a_rr1.name,
+            RecordType::A,
+            vec![
+                ZoneRecord {
+                    rtype_with_data: a_rr2.rtype_with_data.clone(),
+                    ttl: a_rr2.ttl,
+                },
+                ZoneRecord {
+                    rtype_with_data: aaaa_rr.rtype_with_data.clone(),
+                    ttl: aaaa_rr.ttl,
+                },
+            ],
+        );
+
+        assert_eq!(
+            vec![ZoneRecord {
+                rtype_with_data: a_rr1.rtype_with_data,
+                ttl: a_rr1.ttl,
+            }],
+            removed
+        );
+        if let Some(ZoneResult::Answer { mut rrs, .. }) =
+            zone.resolve(&a_rr1.name, QueryType::Wildcard)
+        {
+            let mut expected = vec![a_rr2, aaaa_rr];
+            expected.sort();
+            rrs.sort();
+
+            assert_eq!(expected, rrs);
+        } else {
+            panic!("expected answer");
+        }
+    }
+
+    #[test]
+    fn zone_remove_subtree() {
+        for mut zone in [
+            Zone::new(domain("example.com."), None),
+            Zone::new_compact(domain("example.com."), None),
+        ] {
+            for name in [
+                "example.com.",
+                "www.example.com.",
+                "a.www.example.com.",
+                "b.a.www.example.com.",
+                "wwwx.example.com.",
+            ] {
+                let rr = a_record(name, Ipv4Addr::new(1, 1, 1, 1));
+                zone.insert(&rr.name, rr.rtype_with_data, rr.ttl);
+            }
+            let rr = cname_record("www.example.com.", "example.com.");
+            zone.insert_wildcard(&rr.name, rr.rtype_with_data, rr.ttl);
+
+            assert_eq!(4, zone.remove_subtree(&domain("www.example.com.")));
+            assert_eq!(2, zone.record_count());
+            assert_eq!(0, zone.wildcard_record_count());
+            assert_eq!(
+                Some(ZoneResult::NameError),
+                zone.resolve(&domain("www.example.com."), QueryType::Wildcard)
+            );
+            assert_eq!(
+                Some(ZoneResult::NameError),
+                zone.resolve(&domain("c.www.example.com."), QueryType::Wildcard)
+            );
+        }
+    }
+
     #[test]
     fn zone_resolve_cname() {
         let mut zone = Zone::new(domain("example.com."), None);
@@ -912,19 +2378,22 @@ mod tests {
         assert_eq!(
             Some(ZoneResult::CNAME {
                 cname: domain("example.com."),
-                rr: rr.clone()
+                rr: rr.clone(),
+                wildcard: false,
             }),
             zone.resolve(&rr.name, QueryType::Record(RecordType::A))
         );
         assert_eq!(
             Some(ZoneResult::Answer {
-                rrs: vec![rr.clone()]
+                rrs: vec![rr.clone()],
+                wildcard: false,
             }),
             zone.resolve(&rr.name, QueryType::Record(RecordType::CNAME))
         );
         assert_eq!(
             Some(ZoneResult::Answer {
-                rrs: vec![rr.clone()]
+                rrs: vec![rr.clone()],
+                wildcard: false,
             }),
             zone.resolve(&rr.name, QueryType::Wildcard)
         );
@@ -944,19 +2413,22 @@ mod tests {
         assert_eq!(
             Some(ZoneResult::CNAME {
                 cname: domain("example.com."),
-                rr: rr.clone()
+                rr: rr.clone(),
+                wildcard: true,
             }),
             zone.resolve(&rr.name, QueryType::Record(RecordType::A))
         );
         assert_eq!(
             Some(ZoneResult::Answer {
-                rrs: vec![rr.clone()]
+                rrs: vec![rr.clone()],
+                wildcard: true,
             }),
             zone.resolve(&rr.name, QueryType::Record(RecordType::CNAME))
         );
         assert_eq!(
             Some(ZoneResult::Answer {
-                rrs: vec![rr.clone()]
+                rrs: vec![rr.clone()],
+                wildcard: true,
             }),
             zone.resolve(&rr.name, QueryType::Wildcard)
         );
@@ -976,7 +2448,8 @@ mod tests {
         );
         assert_eq!(
             Some(ZoneResult::Answer {
-                rrs: vec![rr.clone()]
+                rrs: vec![rr.clone()],
+                wildcard: false,
             }),
             zone.resolve(&rr.name, QueryType::Record(RecordType::NS))
         );
@@ -989,55 +2462,221 @@ mod tests {
     }
 
     #[test]
-    fn zone_resolve_delegation_wildcard() {
+    fn zone_resolve_wildcard_ns_is_not_a_delegation() {
         let mut zone = Zone::new(domain("example.com."), None);
         let wildcard_rr = ns_record("example.com.", "ns.example.com.");
-        let rr = ns_record("www.example.com.", "ns.example.com.");
         zone.insert_wildcard(
             &wildcard_rr.name,
             wildcard_rr.rtype_with_data.clone(),
             wildcard_rr.ttl,
         );
 
-        assert_eq!(
-            Some(ZoneResult::Delegation {
-                ns_rrs: vec![rr.clone()]
+        for name in [
+            "www.example.com.",
+            "some.long.subdomain.of.www.example.com.",
+        ] {
+            let rr = ns_record(name, "ns.example.com.");
+            assert_eq!(
+                Some(ZoneResult::Answer {
+                    rrs: Vec::new(),
+                    wildcard: true,
+                }),
+                zone.resolve(&rr.name, QueryType::Record(RecordType::A))
+            );
+            assert_eq!(
+                Some(ZoneResult::Answer {
+                    rrs: vec![rr.clone()],
+                    wildcard: true,
+                }),
+                zone.resolve(&rr.name, QueryType::Record(RecordType::NS))
+            );
+        }
+    }
+
+    fn txt_record(name: &str, text: &str) -> ResourceRecord {
+        ResourceRecord {
+            name: domain(name),
+            rtype_with_data: RecordTypeWithData::TXT {
+                octets: Bytes::copy_from_slice(text.as_bytes()),
+            },
+            rclass: RecordClass::IN,
+            ttl: 300,
+        }
+    }
+
+    fn mx_record(name: &str, preference: u16, exchange: &str) -> ResourceRecord {
+        ResourceRecord {
+            name: domain(name),
+            rtype_with_data: RecordTypeWithData::MX {
+                preference,
+                exchange: domain(exchange),
+            },
+            rclass: RecordClass::IN,
+            ttl: 300,
+        }
+    }
+
+    /// The example zone from section 2.2.1 of RFC 4592, with `SRV`
+    /// records replaced by `TXT` records.
+    fn rfc4592_zone() -> Zone {
+        let mut zone = Zone::new(
+            domain("example."),
+            Some(SOA {
+                mname: domain("ns.example.com."),
+                rname: domain("hostmaster.example."),
+                serial: 1,
+                refresh: 300,
+                retry: 300,
+                expire: 300,
+                minimum: 300,
             }),
-            zone.resolve(&rr.name, QueryType::Record(RecordType::A))
         );
-        assert_eq!(
+        for rr in [
+            ns_record("example.", "ns.example.com."),
+            ns_record("example.", "ns.example.net."),
+            txt_record("sub.*.example.", "this is not a wildcard"),
+            a_record("host1.example.", Ipv4Addr::new(192, 0, 2, 1)),
+            txt_record("_ssh._tcp.host1.example.", "ssh"),
+            txt_record("_ssh._tcp.host2.example.", "ssh"),
+            ns_record("subdel.example.", "ns.example.com."),
+            ns_record("subdel.example.", "ns.example.net."),
+            a_record("glue.subdel.example.", Ipv4Addr::new(192, 0, 2, 2)),
+        ] {
+            zone.insert(&rr.name, rr.rtype_with_data, rr.ttl);
+        }
+        for rr in [
+            txt_record("example.", "this is a wildcard"),
+            mx_record("example.", 10, "host1.example."),
+        ] {
+            zone.insert_wildcard(&rr.name, rr.rtype_with_data, rr.ttl);
+        }
+        zone
+    }
+
+    #[test]
+    fn zone_resolve_rfc4592_matrix() {
+        let zone = rfc4592_zone();
+        let nodata = |wildcard| {
             Some(ZoneResult::Answer {
-                rrs: vec![rr.clone()]
-            }),
-            zone.resolve(&rr.name, QueryType::Record(RecordType::NS))
-        );
+                rrs: Vec::new(),
+                wildcard,
+            })
+        };
+        let wildcard_mx = |name| {
+            Some(ZoneResult::Answer {
+                rrs: vec![mx_record(name, 10, "host1.example.")],
+                wildcard: true,
+            })
+        };
+        let subdel = Some(ZoneResult::Delegation {
+            ns_rrs: vec![
+                ns_record("subdel.example.", "ns.example.com."),
+                ns_record("subdel.example.", "ns.example.net."),
+            ],
+        });
+
+        let cases = [
+            // no such name: synthesised from the wildcard, or NODATA
+            (
+                "host3.example.",
+                RecordType::MX,
+                wildcard_mx("host3.example."),
+            ),
+            ("host3.example.", RecordType::A, nodata(true)),
+            (
+                "foo.bar.example.",
+                RecordType::MX,
+                wildcard_mx("foo.bar.example."),
+            ),
+            // the wildcard's own name
+            ("*.example.", RecordType::MX, wildcard_mx("*.example.")),
+            // existing names, including empty non-terminals, hide the wildcard
+            ("host1.example.", RecordType::MX, nodata(false)),
+            ("sub.*.example.", RecordType::MX, nodata(false)),
+            ("_tcp.host1.example.", RecordType::MX, nodata(false)),
+            // the closest encloser has no wildcard, so the one above it
+            // isn't used
+            (
+                "_telnet._tcp.host1.example.",
+                RecordType::MX,
+                Some(ZoneResult::NameError),
+            ),
+            (
+                "host.sub.*.example.",
+                RecordType::MX,
+                Some(ZoneResult::NameError),
+            ),
+            // the apex is not a delegation
+            ("example.", RecordType::A, nodata(false)),
+            // a zone cut, and anything below it, is a delegation
+            ("subdel.example.", RecordType::A, subdel.clone()),
+            ("host.subdel.example.", RecordType::MX, subdel.clone()),
+            ("glue.subdel.example.", RecordType::A, subdel.clone()),
+        ];
+
+        for (name, rtype, expected) in cases {
+            assert_eq!(
+                expected,
+                zone.resolve(&domain(name), QueryType::Record(rtype)),
+                "{name} {rtype}"
+            );
+        }
+    }
+
+    #[test]
+    fn zone_resolve_apex_nameerror_with_ns() {
+        let zone = rfc4592_zone();
+        let mut zone_without_wildcards = Zone::new(domain("example."), zone.get_soa().cloned());
+        for (name, zrs) in zone.all_records() {
+            for zr in zrs {
+                zone_without_wildcards.insert(&name, zr.rtype_with_data, zr.ttl);
+            }
+        }
+
         assert_eq!(
-            Some(ZoneResult::Delegation {
-                ns_rrs: vec![rr.clone()]
-            }),
-            zone.resolve(&rr.name, QueryType::Wildcard)
+            Some(ZoneResult::NameError),
+            zone_without_wildcards.resolve(
+                &domain("missing.example."),
+                QueryType::Record(RecordType::A)
+            )
         );
     }
 
     #[test]
-    fn zone_resolve_delegation_wildcard_multilabel() {
-        let mut zone = Zone::new(domain("example.com."), None);
-        let wildcard_rr = ns_record("example.com.", "ns.example.com.");
+    fn zone_resolve_compact_name_hides_wildcard() {
+        let mut zone = Zone::new_compact(domain("example."), None);
         zone.insert_wildcard(
-            &wildcard_rr.name,
-            wildcard_rr.rtype_with_data.clone(),
-            wildcard_rr.ttl,
+            &domain("example."),
+            RecordTypeWithData::A {
+                address: Ipv4Addr::new(192, 0, 2, 1),
+            },
+            300,
         );
+        let rr = a_record("host.example.", Ipv4Addr::new(192, 0, 2, 2));
+        zone.insert(&rr.name, rr.rtype_with_data.clone(), rr.ttl);
+        assert!(zone.is_compact());
 
         assert_eq!(
-            Some(ZoneResult::Delegation {
-                ns_rrs: vec![ns_record("www.example.com.", "ns.example.com.")]
+            Some(ZoneResult::Answer {
+                rrs: vec![rr],
+                wildcard: false,
             }),
+            zone.resolve(&domain("host.example."), QueryType::Record(RecordType::A))
+        );
+        assert_eq!(
+            Some(ZoneResult::NameError),
             zone.resolve(
-                &domain("some.long.subdomain.of.www.example.com."),
-                QueryType::Record(RecordType::A),
+                &domain("sub.host.example."),
+                QueryType::Record(RecordType::A)
             )
         );
+        assert_eq!(
+            Some(ZoneResult::Answer {
+                rrs: vec![a_record("other.example.", Ipv4Addr::new(192, 0, 2, 1))],
+                wildcard: true,
+            }),
+            zone.resolve(&domain("other.example."), QueryType::Record(RecordType::A))
+        );
     }
 
     #[test]
@@ -1066,23 +2705,47 @@ mod tests {
         zone.insert(&rr.name, rr.rtype_with_data, rr.ttl);
 
         assert_eq!(
-            Some(ZoneResult::Answer { rrs: Vec::new() }),
+            Some(ZoneResult::Answer {
+                rrs: Vec::new(),
+                wildcard: false
+            }),
             zone.resolve(
                 &domain("chain.of.subdomains.example.com."),
                 QueryType::Wildcard,
             )
         );
         assert_eq!(
-            Some(ZoneResult::Answer { rrs: Vec::new() }),
+            Some(ZoneResult::Answer {
+                rrs: Vec::new(),
+                wildcard: false
+            }),
             zone.resolve(&domain("of.subdomains.example.com."), QueryType::Wildcard)
         );
         assert_eq!(
-            Some(ZoneResult::Answer { rrs: Vec::new() }),
+            Some(ZoneResult::Answer {
+                rrs: Vec::new(),
+                wildcard: false
+            }),
             zone.resolve(&domain("subdomains.example.com."), QueryType::Wildcard)
         );
         assert_eq!(
-            Some(ZoneResult::Answer { rrs: Vec::new() }),
+            Some(ZoneResult::Answer {
+                rrs: Vec::new(),
+                wildcard: false
+            }),
             zone.resolve(&domain("example.com."), QueryType::Wildcard)
         );
     }
+
+    fn example_soa() -> SOA {
+        SOA {
+            mname: domain("mname."),
+            rname: domain("rname."),
+            serial: 1,
+            refresh: 2,
+            retry: 3,
+            expire: 4,
+            minimum: 5,
+        }
+    }
 }
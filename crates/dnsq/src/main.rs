@@ -1,18 +1,41 @@
 use clap::Parser;
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use dns_resolver::cache::SharedCache;
-use dns_resolver::resolve;
-use dns_resolver::util::types::{ProtocolMode, ResolvedRecord};
+use dns_resolver::events::{Event, Observer};
+use dns_resolver::metrics::Metrics;
+use dns_resolver::resolver::Resolver;
+use dns_resolver::util::nameserver::query_nameserver_unchecked;
+use dns_resolver::util::types::{
+    CachePolicy, FallbackPolicy, ForwardingRule, ForwardingStrategy, HttpsUrl, NetworkOptions,
+    ProtocolMode, RecursionScope, ResolutionError, ResolvedRecord, Timeouts, TransportKind,
+    UpstreamProxy, UpstreamSecurityRule,
+};
+use dns_types::hosts::types::TTL as HOSTS_TTL;
 use dns_types::protocol::types::{
-    DomainName, QueryClass, QueryType, Question, RecordClass, RecordType, ResourceRecord,
+    DomainName, Message, QueryClass, QueryType, Question, RecordClass, RecordType, ResourceRecord,
 };
 use dns_types::zones::types::Zone;
-use resolved::fs::load_zone_configuration;
+use resolved::fs::{load_root_hints, load_zone_configuration};
+
+/// Show a name, with its A-labels as Unicode if `unicode` is set.
+fn show_name(name: &DomainName, unicode: bool) -> String {
+    if unicode {
+        name.to_unicode_dotted_string()
+    } else {
+        name.to_dotted_string()
+    }
+}
 
-fn print_section(heading: &str, rrs: &[ResourceRecord]) {
+fn print_section(heading: &str, rrs: &[ResourceRecord], unicode: bool) {
     if rrs.is_empty() {
         return;
     }
@@ -22,7 +45,7 @@ fn print_section(heading: &str, rrs: &[ResourceRecord]) {
         let rdata = Zone::default().serialise_rdata(&rr.rtype_with_data);
         println!(
             "{}\t{}\t{}\t{}\t{}",
-            rr.name,
+            show_name(&rr.name, unicode),
             rr.ttl,
             rr.rclass,
             rr.rtype_with_data.rtype(),
@@ -35,19 +58,91 @@ fn print_section(heading: &str, rrs: &[ResourceRecord]) {
 #[derive(Parser)]
 /// DNS recursive lookup utility
 struct Args {
-    /// Domain name to resolve
-    #[clap(value_parser)]
-    domain: DomainName,
+    /// Domain name to resolve, which may have Unicode labels
+    #[clap(value_parser = parse_domain, required_unless_present_any = ["interactive", "file", "reverse"])]
+    domain: Option<DomainName>,
 
     /// Query type to resolve
     #[clap(default_value_t = QueryType::Record(RecordType::A), value_parser)]
     qtype: QueryType,
 
+    /// Read questions (in `domain [qtype]` form) from stdin, one per line,
+    /// answering them all with the same cache and showing the cache hits and
+    /// misses and time taken for each
+    #[clap(
+        short,
+        long,
+        action(clap::ArgAction::SetTrue),
+        conflicts_with = "domain"
+    )]
+    interactive: bool,
+
+    /// Read questions (in `domain [qtype]` form) from this file, one per line,
+    /// and resolve them all concurrently with the same cache
+    #[clap(long, value_parser, conflicts_with_all = ["domain", "interactive"])]
+    file: Option<PathBuf>,
+
+    /// How many questions from `--file` to resolve at once
+    #[clap(long, value_parser, default_value_t = 8, requires = "file")]
+    parallelism: usize,
+
+    /// Look up the PTR record for this IPv4 or IPv6 address, rather than
+    /// giving a domain and query type
+    #[clap(
+        short = 'x',
+        long,
+        value_parser,
+        conflicts_with_all = ["domain", "interactive", "file"]
+    )]
+    reverse: Option<IpAddr>,
+
+    /// Send the question directly to this nameserver (in `ip` or `ip:port`
+    /// form), rather than resolving it, and show the full response: can also
+    /// be given as `@ip` or `@ip:port`
+    #[clap(long, value_parser = parse_server, conflicts_with_all = ["interactive", "file"])]
+    server: Option<SocketAddr>,
+
+    /// Send the question to the `--server` nameserver over TCP, rather than
+    /// UDP
+    #[clap(long, action(clap::ArgAction::SetTrue), requires = "server")]
+    tcp: bool,
+
+    /// Show what happens while resolving each question: cache hits and
+    /// misses, zone matches, queries sent to upstream nameservers, referrals,
+    /// and CNAMEs
+    #[clap(long, action(clap::ArgAction::SetTrue), conflicts_with = "file")]
+    trace: bool,
+
+    /// Show the names of questions and records with Unicode labels, rather
+    /// than in their ASCII `xn--` form
+    #[clap(long, action(clap::ArgAction::SetTrue))]
+    unicode: bool,
+
+    /// How many records to hold in the cache in interactive or file mode
+    #[clap(short = 's', long, value_parser, default_value_t = 512)]
+    cache_size: usize,
+
+    /// How to choose which records to prune when the cache is too big in
+    /// interactive or file mode: one of 'lru', 'lfu', or 'ttl'
+    #[clap(long, default_value_t = CachePolicy::Lru, value_parser)]
+    cache_policy: CachePolicy,
+
     /// Only answer queries for which this configuration is authoritative: do
     /// not perform recursive or forwarding resolution
     #[clap(long, action(clap::ArgAction::SetTrue))]
     authoritative_only: bool,
 
+    /// Only perform recursive or forwarding resolution for this domain (and
+    /// its subdomains), can be specified more than once
+    #[clap(long, value_parser)]
+    recursion_domain: Vec<DomainName>,
+
+    /// Never perform recursive or forwarding resolution for this domain (and
+    /// its subdomains), even if `--recursion-domain` would allow it, can be
+    /// specified more than once
+    #[clap(long, value_parser)]
+    no_recursion_domain: Vec<DomainName>,
+
     /// How to choose between connecting to upstream nameservers over IPv4 or
     /// IPv6 when acting as a recursive resolver: one of 'only-v4', 'prefer-v4',
     /// 'prefer-v6', 'only-v6'
@@ -59,11 +154,71 @@ struct Args {
     #[clap(long, default_value_t = 53, value_parser)]
     upstream_dns_port: u16,
 
+    /// Send queries to upstream nameservers from this local address, rather
+    /// than letting the operating system choose, can be specified once for
+    /// IPv4 and once for IPv6
+    #[clap(long, value_parser)]
+    outbound_bind: Vec<IpAddr>,
+
+    /// Send queries to upstream nameservers over TCP through this proxy, given
+    /// as 'socks5://ip:port' or 'http://ip:port'
+    #[clap(long, value_parser)]
+    upstream_proxy: Option<UpstreamProxy>,
+
+    /// Send the full question name to every nameserver when acting as a
+    /// recursive resolver, rather than only as much of it as each nameserver
+    /// needs to see (QNAME minimisation)
+    #[clap(long, action(clap::ArgAction::SetTrue))]
+    no_qname_minimisation: bool,
+
+    /// How many seconds to wait for the response to each query sent to an
+    /// upstream nameserver
+    #[clap(long, value_parser, default_value_t = 5)]
+    query_timeout: u64,
+
+    /// How many seconds to spend answering a question in total, including
+    /// following CNAMEs and delegations
+    #[clap(long, value_parser, default_value_t = 60)]
+    resolution_timeout: u64,
+
     /// Act as a forwarding resolver, not a recursive resolver: forward queries
     /// which can't be answered from local state to this nameserver (in
-    /// `ip:port` form)
+    /// `ip:port` form), can be specified more than once
     #[clap(short, long, value_parser)]
-    forward_address: Option<SocketAddr>,
+    forward_address: Vec<SocketAddr>,
+
+    /// Like `--forward-address`, but forward queries to this DNS-over-HTTPS
+    /// (RFC 8484) nameserver (in `https://host[:port]/path` form), can be
+    /// specified more than once
+    #[clap(long, value_parser)]
+    forward_url: Vec<HttpsUrl>,
+
+    /// How to use multiple forwarding nameservers: one of 'failover' (try
+    /// each in turn) or 'race' (query all at once and use the first answer)
+    #[clap(long, default_value_t = ForwardingStrategy::Failover, value_parser)]
+    forward_strategy: ForwardingStrategy,
+
+    /// Which transport to use for forwarding nameservers given with
+    /// `--forward-address`: one of 'udp', 'tcp', 'tls' (DNS over TLS), or
+    /// 'quic' (DNS over QUIC), can be specified more than once to try each in
+    /// turn until one works [default: udp, then tcp]
+    #[clap(long, value_parser)]
+    forward_transport: Vec<TransportKind>,
+
+    /// Forward queries for a domain (and its subdomains) to a specific
+    /// nameserver, overriding `--forward-address` (in `domain=ip:port` form),
+    /// or resolve them recursively (in `domain=recurse` form), can be
+    /// specified more than once
+    #[clap(short = 'F', long, value_parser)]
+    forward_rule: Vec<ForwardingRule>,
+
+    /// Whether queries to a forwarding nameserver (by IP address, or by host
+    /// for `--forward-url`) must be encrypted ('strict') or only should be
+    /// ('opportunistic'), and how to authenticate it (in
+    /// `host=policy[,name=domain][,pin=hash]` form), can be specified more
+    /// than once
+    #[clap(long, value_parser)]
+    upstream_security: Vec<UpstreamSecurityRule>,
 
     /// Path to a hosts file, can be specified more than once
     #[clap(short = 'a', long, value_parser)]
@@ -82,70 +237,447 @@ struct Args {
     /// once
     #[clap(short = 'Z', long, value_parser)]
     zones_dir: Vec<PathBuf>,
+
+    /// Path to a directory of authoritative zone files named after their apex
+    /// (like 'example.com.zone'), can be specified more than once
+    #[clap(long, value_parser)]
+    zones_dir_auto: Vec<PathBuf>,
+
+    /// Add a PTR record for the address of every A and AAAA record in the hosts
+    /// and zone files, unless there already is one
+    #[clap(long, action(clap::ArgAction::SetTrue))]
+    synthesise_ptr: bool,
+
+    /// TTL (in seconds) of the records from the hosts files
+    #[clap(long, value_parser, default_value_t = HOSTS_TTL)]
+    hosts_ttl: u32,
+
+    /// Flatten CNAME chains in the zone with this apex, answering with the
+    /// records at the end of the chain renamed to the name which was asked
+    /// about, if the chain can be followed using only the hosts and zone files
+    /// and the cache, can be specified more than once
+    #[clap(long, value_parser)]
+    flatten_cnames: Vec<DomainName>,
+
+    /// Path to a root hints file, giving the root nameservers to start
+    /// recursive resolution from, if not given the built-in root hints are used
+    #[clap(long, value_parser)]
+    root_hints: Option<PathBuf>,
 }
 
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
+/// Parse a domain name, converting any Unicode labels to their ASCII form.
+fn parse_domain(s: &str) -> Result<DomainName, String> {
+    DomainName::from_unicode_dotted_string(s).ok_or_else(|| "expected a domain name".to_string())
+}
 
-    let question = Question {
-        name: args.domain,
-        qtype: args.qtype,
-        qclass: QueryClass::Record(RecordClass::IN),
-    };
+/// Parse a nameserver address, in `ip` or `ip:port` form.
+fn parse_server(s: &str) -> Result<SocketAddr, String> {
+    if let Ok(address) = SocketAddr::from_str(s) {
+        return Ok(address);
+    }
+    IpAddr::from_str(s)
+        .map(|ip| SocketAddr::new(ip, 53))
+        .map_err(|_| "expected 'ip' or 'ip:port'".to_string())
+}
 
-    let zones = match load_zone_configuration(
-        &args.hosts_file,
-        &args.hosts_dir,
-        &args.zone_file,
-        &args.zones_dir,
-    )
-    .await
-    {
-        Some(zs) => zs,
-        None => {
-            eprintln!("could not load configuration");
-            process::exit(1);
-        }
-    };
+/// Turn dig-style `@server` arguments into `--server=server`.
+fn rewrite_server_args(args: impl Iterator<Item = OsString>) -> Vec<OsString> {
+    args.map(|arg| match arg.to_str() {
+        Some(server) if server.starts_with('@') => format!("--server={}", &server[1..]).into(),
+        _ => arg,
+    })
+    .collect()
+}
+
+/// Prints events as they happen, for `--trace`.
+struct PrintObserver;
+
+impl Observer for PrintObserver {
+    fn event(&self, event: Event) {
+        println!(";; {event}");
+    }
+}
 
+/// Resolve a question, printing the events of resolution first if tracing.
+async fn lookup(
+    resolver: &Resolver,
+    trace: bool,
+    question: &Question,
+) -> (Metrics, Result<ResolvedRecord, ResolutionError>) {
+    let result = resolver
+        .lookup_with_metrics(question, trace.then_some(&PrintObserver as &dyn Observer))
+        .await;
+    if trace {
+        println!();
+    }
+    result
+}
+
+/// Print the question and its answer.  Returns `false` if there was an error.
+fn print_answer(
+    question: &Question,
+    response: Result<ResolvedRecord, ResolutionError>,
+    unicode: bool,
+) -> bool {
     println!(";; QUESTION");
-    println!("{}\t{}\t{}", question.name, question.qclass, question.qtype);
-
-    // TODO: log upstream queries as they happen
-    let (_, response) = resolve(
-        !args.authoritative_only,
-        args.protocol_mode,
-        args.upstream_dns_port,
-        args.forward_address,
-        &zones,
-        &SharedCache::new(),
-        &question,
-    )
-    .await;
+    println!(
+        "{}\t{}\t{}",
+        show_name(&question.name, unicode),
+        question.qclass,
+        question.qtype
+    );
 
     match response {
         Ok(response) => match response {
             ResolvedRecord::Authoritative { rrs, soa_rr } => {
-                print_section("ANSWER", &rrs);
-                print_section("AUTHORITY", &[soa_rr]);
+                print_section("ANSWER", &rrs, unicode);
+                print_section("AUTHORITY", &[soa_rr], unicode);
             }
             ResolvedRecord::AuthoritativeNameError { soa_rr } => {
                 println!("\n;; ANSWER");
                 println!("; name does not exist");
-                print_section("AUTHORITY", &[soa_rr]);
+                print_section("AUTHORITY", &[soa_rr], unicode);
             }
             ResolvedRecord::NonAuthoritative { rrs, soa_rr } => {
-                print_section("ANSWER", &rrs);
+                print_section("ANSWER", &rrs, unicode);
                 if let Some(soa_rr) = soa_rr {
-                    print_section("AUTHORITY", &[soa_rr]);
+                    print_section("AUTHORITY", &[soa_rr], unicode);
                 }
             }
         },
         Err(err) => {
             println!("\n;; ANSWER");
             println!("; {err}");
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Send a question directly to a nameserver and print the full response.  A
+/// truncated UDP response is retried over TCP.  Returns `false` if there was
+/// an error.
+async fn query_server(
+    address: SocketAddr,
+    question: Question,
+    use_tcp: bool,
+    query_timeout: Duration,
+    network: NetworkOptions,
+    unicode: bool,
+) -> bool {
+    let start = Instant::now();
+    let mut use_tcp = use_tcp || network.proxy.is_some();
+    let mut response = query_nameserver_unchecked(
+        address,
+        question.clone(),
+        true,
+        use_tcp,
+        query_timeout,
+        network,
+    )
+    .await;
+    if matches!(&response, Ok(message) if message.header.is_truncated && !use_tcp) {
+        println!(";; truncated, retrying over TCP\n");
+        use_tcp = true;
+        response =
+            query_nameserver_unchecked(address, question, true, use_tcp, query_timeout, network)
+                .await;
+    }
+    let duration = start.elapsed();
+
+    let protocol = if use_tcp { "tcp" } else { "udp" };
+    match response {
+        Ok(message) => {
+            print_message(&message, unicode);
+            println!("\n;; server: {address} ({protocol})");
+            println!(";; time: {:.3}ms", duration.as_secs_f64() * 1000.0);
+            true
+        }
+        Err(err) => {
+            println!(";; server: {address} ({protocol})");
+            println!("; {err}");
+            false
+        }
+    }
+}
+
+/// Print a whole message: the header and every section.
+fn print_message(message: &Message, unicode: bool) {
+    let header = &message.header;
+    let flags: Vec<&str> = [
+        (header.is_response, "qr"),
+        (header.is_authoritative, "aa"),
+        (header.is_truncated, "tc"),
+        (header.recursion_desired, "rd"),
+        (header.recursion_available, "ra"),
+    ]
+    .into_iter()
+    .filter_map(|(set, flag)| set.then_some(flag))
+    .collect();
+
+    println!(";; HEADER");
+    println!(
+        "; id: {}, opcode: {}, rcode: {}",
+        header.id, header.opcode, header.rcode
+    );
+    println!("; flags: {}", flags.join(" "));
+
+    println!("\n;; QUESTION");
+    for question in &message.questions {
+        println!(
+            "{}\t{}\t{}",
+            show_name(&question.name, unicode),
+            question.qclass,
+            question.qtype
+        );
+    }
+
+    print_section("ANSWER", &message.answers, unicode);
+    print_section("AUTHORITY", &message.authority, unicode);
+    print_section("ADDITIONAL", &message.additional, unicode);
+}
+
+/// Parse a line of interactive or file input, in `domain [qtype]` form.
+fn parse_question(line: &str) -> Result<Question, String> {
+    let mut words = line.split_whitespace();
+    let Some(domain) = words.next() else {
+        return Err("expected a domain".to_string());
+    };
+    let name = parse_domain(domain).map_err(|err| format!("{domain}: {err}"))?;
+    let qtype = match words.next() {
+        Some(qtype) => QueryType::from_str(qtype).map_err(|err| format!("{qtype}: {err}"))?,
+        None => QueryType::Record(RecordType::A),
+    };
+    if let Some(extra) = words.next() {
+        return Err(format!("unexpected {extra}"));
+    }
+
+    Ok(Question {
+        name,
+        qtype,
+        qclass: QueryClass::Record(RecordClass::IN),
+    })
+}
+
+/// Answer questions from stdin until it is closed, sharing a cache between all
+/// of them.
+async fn interactive(resolver: &Resolver, trace: bool, unicode: bool) {
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        print!("> ");
+        _ = io::stdout().flush();
+
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(err)) => {
+                eprintln!("could not read input: {err}");
+                process::exit(1);
+            }
+            None => {
+                println!();
+                return;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let question = match parse_question(&line) {
+            Ok(question) => question,
+            Err(err) => {
+                println!("; {err}");
+                continue;
+            }
+        };
+
+        let start = Instant::now();
+        let (metrics, response) = lookup(resolver, trace, &question).await;
+        let duration = start.elapsed();
+
+        // prune as the server does after every request, so that the cache
+        // behaves the same
+        resolver.cache().prune();
+
+        print_answer(&question, response, unicode);
+        println!(
+            "\n;; cache hits: {}, cache misses: {}, nameserver hits: {}, nameserver misses: {}",
+            metrics.cache_hits,
+            metrics.cache_misses,
+            metrics.nameserver_hits,
+            metrics.nameserver_misses
+        );
+        println!(";; time: {:.3}ms\n", duration.as_secs_f64() * 1000.0);
+    }
+}
+
+/// Answer all the questions in a file concurrently, and print the answers in
+/// the order of the questions.  Returns `false` if there was an error.
+async fn file(resolver: &Resolver, path: &Path, parallelism: usize, unicode: bool) -> bool {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("could not read {}: {err}", path.display());
+            process::exit(1);
+        }
+    };
+
+    let mut questions = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_question(line) {
+            Ok(question) => questions.push(question),
+            Err(err) => {
+                eprintln!("{}:{}: {err}", path.display(), i + 1);
+                process::exit(1);
+            }
+        }
+    }
+
+    let responses = resolver.lookup_many(&questions, parallelism).await;
+
+    let mut ok = true;
+    for (i, (question, response)) in questions.iter().zip(responses).enumerate() {
+        if i > 0 {
+            println!();
+        }
+        ok &= print_answer(question, response, unicode);
+    }
+    ok
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse_from(rewrite_server_args(env::args_os()));
+
+    // safe because clap requires a domain or an address when not in
+    // interactive or file mode
+    let question = (!args.interactive && args.file.is_none()).then(|| match args.reverse {
+        Some(address) => Question {
+            name: DomainName::reverse_pointer(address),
+            qtype: QueryType::Record(RecordType::PTR),
+            qclass: QueryClass::Record(RecordClass::IN),
+        },
+        None => Question {
+            name: args.domain.clone().unwrap(),
+            qtype: args.qtype,
+            qclass: QueryClass::Record(RecordClass::IN),
+        },
+    });
+
+    let network = NetworkOptions {
+        outbound: args.outbound_bind.into_iter().collect(),
+        proxy: args.upstream_proxy,
+    };
+
+    if let Some(server) = args.server {
+        // safe because `--server` conflicts with `--interactive` and `--file`
+        let question = question.unwrap();
+        let query_timeout = Duration::from_secs(args.query_timeout);
+        if !query_server(
+            server,
+            question,
+            args.tcp,
+            query_timeout,
+            network,
+            args.unicode,
+        )
+        .await
+        {
+            process::exit(1);
+        }
+        return;
+    }
+
+    let zones = match load_zone_configuration(
+        &args.hosts_file,
+        &args.hosts_dir,
+        &args.zone_file,
+        &args.zones_dir,
+        &args.zones_dir_auto,
+        args.synthesise_ptr,
+        false,
+        args.hosts_ttl,
+        &args.flatten_cnames,
+        false,
+    )
+    .await
+    {
+        Ok(zs) => zs,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{error}");
+            }
+            eprintln!("could not load configuration");
             process::exit(1);
         }
+    };
+
+    let Some(root_hints) = load_root_hints(args.root_hints.as_deref()).await else {
+        eprintln!("could not load configuration");
+        process::exit(1);
+    };
+
+    let mut recursion_scope = RecursionScope::new();
+    for domain in args.recursion_domain {
+        recursion_scope.allow(domain);
+    }
+    for domain in args.no_recursion_domain {
+        recursion_scope.deny(domain);
+    }
+
+    let mut builder = Resolver::builder()
+        .recursive(!args.authoritative_only)
+        .protocol_mode(args.protocol_mode)
+        .upstream_dns_port(args.upstream_dns_port)
+        .network(network)
+        .qname_minimisation(!args.no_qname_minimisation)
+        .timeouts(Timeouts {
+            query: Duration::from_secs(args.query_timeout),
+            resolution: Duration::from_secs(args.resolution_timeout),
+        })
+        .root_hints(root_hints)
+        .forward_strategy(args.forward_strategy)
+        .forward_fallback(FallbackPolicy::new(args.forward_transport))
+        .recursion_scope(recursion_scope)
+        .zones(zones)
+        .cache(SharedCache::with_policy(
+            std::cmp::max(1, args.cache_size),
+            args.cache_policy,
+        ));
+    for address in args.forward_address {
+        builder = builder.forward(address);
+    }
+    for url in args.forward_url {
+        builder = builder.forward(url);
+    }
+    for rule in args.forward_rule {
+        builder = builder.forward_rule(rule);
+    }
+    for rule in args.upstream_security {
+        builder = builder.upstream_security(rule);
+    }
+    let resolver = builder.build();
+
+    if args.interactive {
+        interactive(&resolver, args.trace, args.unicode).await;
+        return;
+    }
+
+    if let Some(path) = args.file {
+        if !file(&resolver, &path, args.parallelism, args.unicode).await {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // safe because of the interactive and file mode checks above
+    let question = question.unwrap();
+
+    let (_, response) = lookup(&resolver, args.trace, &question).await;
+    if !print_answer(&question, response, args.unicode) {
+        process::exit(1);
     }
 }
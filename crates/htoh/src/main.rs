@@ -1,5 +1,5 @@
 use clap::Parser;
-use std::io::{stdin, Read};
+use std::io::stdin;
 use std::process;
 
 use dns_types::hosts::types::Hosts;
@@ -15,16 +15,14 @@ struct Args {}
 fn main() {
     Args::parse();
 
-    let mut buf = String::new();
-    if let Err(err) = stdin().read_to_string(&mut buf) {
-        eprintln!("error reading hosts file from stdin: {err:?}");
-        process::exit(1);
-    }
-
-    match Hosts::deserialise(&buf) {
-        Ok(hosts) => print!("{}", hosts.serialise()),
+    match Hosts::deserialise_reader(stdin().lock()) {
+        Ok(Ok(hosts)) => print!("{}", hosts.serialise()),
+        Ok(Err(err)) => {
+            eprintln!("error parsing hosts file from stdin: {err}");
+            process::exit(1);
+        }
         Err(err) => {
-            eprintln!("error parsing hosts file from stdin: {err:?}");
+            eprintln!("error reading hosts file from stdin: {err:?}");
             process::exit(1);
         }
     }
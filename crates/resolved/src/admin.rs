@@ -0,0 +1,1006 @@
+use axum::body::Body;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{routing, Json, Router};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::{Notify, RwLock};
+
+use dns_resolver::cache::SharedCache;
+use dns_types::hosts::types::Hosts;
+use dns_types::protocol::types::{
+    DomainName, QueryClass, QueryType, Question, RecordClass, RecordType, RecordTypeWithData,
+};
+use dns_types::zones::types::Zone;
+
+use crate::clients::{ClientStats, ClientStatsSummary};
+use crate::config::parse_list;
+use crate::handle::ResolverHandle;
+use crate::logging::{LogFilter, LogFormat};
+use crate::overrides::ServedZones;
+use crate::recent::{QueryFilter, RecentQueries, RecentQuery};
+use crate::top::{TopQueries, TopSummary, DEFAULT_TOP_COUNT};
+
+/// Target for audit log messages, so they can be filtered separately with
+/// `RUST_LOG`.
+pub const AUDIT_LOG_TARGET: &str = "resolved::audit";
+
+/// An API token, which may only change override records for some domains, and
+/// may only change the log filter if explicitly allowed.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AdminToken {
+    /// Identifies the token in the audit log.
+    pub name: String,
+
+    /// The secret, given in an `Authorization: Bearer` header.
+    pub token: String,
+
+    /// The token may change records for these domains and their subdomains.
+    #[serde(deserialize_with = "parse_list")]
+    pub domains: Vec<DomainName>,
+
+    /// The token may view and change the log filter.
+    #[serde(default)]
+    pub logging: bool,
+}
+
+impl AdminToken {
+    /// Check if the token may change override records for a domain.
+    pub fn may_modify(&self, name: &DomainName) -> bool {
+        self.domains
+            .iter()
+            .any(|domain| name.is_subdomain_of(domain))
+    }
+}
+
+// don't leak the secret into logs
+impl fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdminToken")
+            .field("name", &self.name)
+            .field("domains", &self.domains)
+            .field("logging", &self.logging)
+            .finish_non_exhaustive()
+    }
+}
+
+/// State for the admin API handlers.
+#[derive(Debug, Clone)]
+pub struct AdminState {
+    pub tokens: Arc<RwLock<Vec<AdminToken>>>,
+    pub zones: ServedZones,
+    pub log_filter: LogFilter,
+    pub resolver: ResolverHandle,
+    pub cache: SharedCache,
+    pub recent_queries: RecentQueries,
+    pub top_queries: TopQueries,
+    pub client_stats: ClientStats,
+    /// Notified to reload the configuration, hosts, and zones, as if
+    /// `resolved` had been sent SIGHUP.
+    pub reload: Arc<Notify>,
+    /// Notified to resolve the questions in the prime file again, after the
+    /// whole cache is flushed.
+    pub prime_cache: Arc<Notify>,
+}
+
+/// The admin API routes:
+///
+/// - `GET /api/overrides` - the override records the token may change, in zone
+///   file format
+///
+/// - `PUT /api/overrides/{name}` - replace the override records for a domain
+///   with those in the request body, in zone file format
+///
+/// - `DELETE /api/overrides/{name}` - remove the override records for a domain
+///
+/// - `GET /api/log-filter` - the current log filter, in `RUST_LOG` format
+///
+/// - `PUT /api/log-filter` - replace the log filter with the request body, in
+///   `RUST_LOG` format
+///
+/// - `GET /api/log-format` - the current log format, in `RUST_LOG_FORMAT`
+///   format
+///
+/// - `PUT /api/log-format` - replace the log format with the request body, in
+///   `RUST_LOG_FORMAT` format
+///
+/// - `POST /api/hosts` - resolve the domains in the request body, one per
+///   line, and return a hosts file of their addresses
+///
+/// - `GET /api/cache` - the number of records in the cache, and statistics
+///   for each record type, as JSON
+///
+/// - `DELETE /api/cache` - remove every record from the cache
+///
+/// - `GET /api/cache/dump` - every record in the cache, with the TTL it has
+///   left, in zone file format
+///
+/// - `DELETE /api/cache/{name}` - remove the records for a domain from the
+///   cache: only those of one type if there is a `type` query parameter, and
+///   also those of its subdomains if there is a `subtree=true` query parameter
+///
+/// - `GET /api/zones` - the zones being served, and how many records they
+///   have, as JSON
+///
+/// - `GET /api/queries/recent` - the most recent queries and their responses,
+///   oldest first, as JSON
+///
+/// - `GET /api/queries/tail` - queries as they are answered, one per line,
+///   until the connection is closed: only those for a domain and its
+///   subdomains, from a client, or of a type if there are `name`, `client`, or
+///   `type` query parameters
+///
+/// - `GET /api/queries/top` - the most queried names, the clients making the
+///   most queries, and the names those clients query most, with approximate
+///   counts, as JSON: 10 of each unless there is a `count` query parameter
+///
+/// - `DELETE /api/queries/top` - forget the counts and start again
+///
+/// - `GET /api/clients` - how many queries each client has made, and how many
+///   of them were blocked or answered with NXDOMAIN, most queries first, as
+///   JSON: every client unless there is a `count` query parameter
+///
+/// - `DELETE /api/clients` - forget the per-client counts and start again
+///
+/// - `POST /api/reload` - reload the configuration, hosts, and zones
+///
+/// Every request needs an `Authorization: Bearer {token}` header.
+pub fn router(state: AdminState) -> Router {
+    Router::new()
+        .route("/api/overrides", routing::get(get_overrides))
+        .route(
+            "/api/overrides/{name}",
+            routing::put(put_override).delete(delete_override),
+        )
+        .route(
+            "/api/log-filter",
+            routing::get(get_log_filter).put(put_log_filter),
+        )
+        .route(
+            "/api/log-format",
+            routing::get(get_log_format).put(put_log_format),
+        )
+        .route("/api/hosts", routing::post(post_hosts))
+        .route("/api/cache", routing::get(get_cache).delete(delete_cache))
+        .route("/api/cache/dump", routing::get(get_cache_dump))
+        .route("/api/cache/{name}", routing::delete(delete_cache_name))
+        .route("/api/zones", routing::get(get_zones))
+        .route("/api/queries/recent", routing::get(get_recent_queries))
+        .route("/api/queries/tail", routing::get(get_tail_queries))
+        .route(
+            "/api/queries/top",
+            routing::get(get_top_queries).delete(delete_top_queries),
+        )
+        .route(
+            "/api/clients",
+            routing::get(get_clients).delete(delete_clients),
+        )
+        .route("/api/reload", routing::post(post_reload))
+        .with_state(state)
+}
+
+async fn get_overrides(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> (StatusCode, String) {
+    let token = match authenticate(&state.tokens.read().await, &headers) {
+        Ok(token) => token,
+        Err(response) => return response,
+    };
+
+    let zone = state.zones.get(|name| token.may_modify(name)).await;
+    (StatusCode::OK, zone.serialise())
+}
+
+async fn put_override(
+    State(state): State<AdminState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: String,
+) -> (StatusCode, String) {
+    let token = match authenticate(&state.tokens.read().await, &headers) {
+        Ok(token) => token,
+        Err(response) => return response,
+    };
+    let name = match authorise(&token, &name, "put") {
+        Ok(name) => name,
+        Err(response) => return response,
+    };
+
+    let zone = match Zone::deserialise(&body) {
+        Ok(zone) => zone,
+        Err(error) => return (StatusCode::BAD_REQUEST, format!("{error}\n")),
+    };
+    if let Err(reason) = validate_override(&name, &zone) {
+        return (StatusCode::BAD_REQUEST, format!("{reason}\n"));
+    }
+
+    let records = zone.serialise();
+    state.zones.set(name.clone(), zone).await;
+    tracing::info!(
+        target: AUDIT_LOG_TARGET,
+        token = %token.name,
+        %name,
+        %records,
+        "put override"
+    );
+
+    (StatusCode::NO_CONTENT, String::new())
+}
+
+async fn delete_override(
+    State(state): State<AdminState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> (StatusCode, String) {
+    let token = match authenticate(&state.tokens.read().await, &headers) {
+        Ok(token) => token,
+        Err(response) => return response,
+    };
+    let name = match authorise(&token, &name, "delete") {
+        Ok(name) => name,
+        Err(response) => return response,
+    };
+
+    if let Some(zone) = state.zones.remove(&name).await {
+        tracing::info!(
+            target: AUDIT_LOG_TARGET,
+            token = %token.name,
+            %name,
+            records = %zone.serialise(),
+            "deleted override"
+        );
+        (StatusCode::NO_CONTENT, String::new())
+    } else {
+        (StatusCode::NOT_FOUND, "no override records\n".to_string())
+    }
+}
+
+async fn get_log_filter(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> (StatusCode, String) {
+    let token = match authenticate(&state.tokens.read().await, &headers) {
+        Ok(token) => token,
+        Err(response) => return response,
+    };
+    if let Err(response) = authorise_logging(&token, "get log filter") {
+        return response;
+    }
+
+    (StatusCode::OK, format!("{}\n", state.log_filter.current()))
+}
+
+async fn put_log_filter(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    body: String,
+) -> (StatusCode, String) {
+    let token = match authenticate(&state.tokens.read().await, &headers) {
+        Ok(token) => token,
+        Err(response) => return response,
+    };
+    if let Err(response) = authorise_logging(&token, "put log filter") {
+        return response;
+    }
+
+    let directives = body.trim();
+    if let Err(error) = state.log_filter.set(directives) {
+        return (StatusCode::BAD_REQUEST, format!("{error}\n"));
+    }
+    tracing::info!(
+        target: AUDIT_LOG_TARGET,
+        token = %token.name,
+        filter = %directives,
+        "put log filter"
+    );
+
+    (StatusCode::NO_CONTENT, String::new())
+}
+
+async fn get_log_format(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> (StatusCode, String) {
+    let token = match authenticate(&state.tokens.read().await, &headers) {
+        Ok(token) => token,
+        Err(response) => return response,
+    };
+    if let Err(response) = authorise_logging(&token, "get log format") {
+        return response;
+    }
+
+    (
+        StatusCode::OK,
+        format!("{}\n", state.log_filter.current_format()),
+    )
+}
+
+async fn put_log_format(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    body: String,
+) -> (StatusCode, String) {
+    let token = match authenticate(&state.tokens.read().await, &headers) {
+        Ok(token) => token,
+        Err(response) => return response,
+    };
+    if let Err(response) = authorise_logging(&token, "put log format") {
+        return response;
+    }
+
+    let format = match LogFormat::from_str(&body) {
+        Ok(format) => format,
+        Err(error) => return (StatusCode::BAD_REQUEST, format!("{error}\n")),
+    };
+    if let Err(error) = state.log_filter.set_format(format) {
+        return (StatusCode::BAD_REQUEST, format!("{error}\n"));
+    }
+    tracing::info!(
+        target: AUDIT_LOG_TARGET,
+        token = %token.name,
+        %format,
+        "put log format"
+    );
+
+    (StatusCode::NO_CONTENT, String::new())
+}
+
+async fn post_hosts(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    body: String,
+) -> (StatusCode, String) {
+    // anyone could make these queries over DNS anyway, so any token will do
+    if let Err(response) = authenticate(&state.tokens.read().await, &headers) {
+        return response;
+    }
+
+    let mut names = Vec::new();
+    for line in body.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match DomainName::from_str(line) {
+            Ok(name) => names.push(name),
+            Err(_) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid domain name: {line}\n"),
+                )
+            }
+        }
+    }
+
+    let mut hosts = Hosts::new();
+    for name in names {
+        for rtype in [RecordType::A, RecordType::AAAA] {
+            let question = Question {
+                name: name.clone(),
+                qtype: QueryType::Record(rtype),
+                qclass: QueryClass::Record(RecordClass::IN),
+            };
+
+            // the answer may start with a chain of CNAMEs, so the addresses
+            // aren't necessarily for this name
+            let Some(response) = state.resolver.resolve(question).await else {
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "resolver has stopped\n".to_string(),
+                );
+            };
+            for rr in response.answers {
+                match rr.rtype_with_data {
+                    RecordTypeWithData::A { address } => {
+                        hosts.v4.entry(name.clone()).or_insert(address);
+                    }
+                    RecordTypeWithData::AAAA { address } => {
+                        hosts.v6.entry(name.clone()).or_insert(address);
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    (StatusCode::OK, hosts.serialise())
+}
+
+#[derive(Debug, Serialize)]
+struct CacheSummary {
+    records: usize,
+    types: BTreeMap<String, CacheTypeSummary>,
+}
+
+#[derive(Debug, Serialize)]
+struct CacheTypeSummary {
+    records: usize,
+    hits: u64,
+    misses: u64,
+    expired: u64,
+    pruned: u64,
+}
+
+async fn get_cache(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> Result<Json<CacheSummary>, (StatusCode, String)> {
+    authenticate(&state.tokens.read().await, &headers)?;
+
+    let mut summary = CacheSummary {
+        records: 0,
+        types: BTreeMap::new(),
+    };
+    for (rtype, stats) in state.cache.stats() {
+        summary.records += stats.entries;
+        summary.types.insert(
+            rtype.to_string(),
+            CacheTypeSummary {
+                records: stats.entries,
+                hits: stats.hits,
+                misses: stats.misses,
+                expired: stats.expired,
+                pruned: stats.pruned,
+            },
+        );
+    }
+
+    Ok(Json(summary))
+}
+
+async fn get_cache_dump(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> (StatusCode, String) {
+    if let Err(response) = authenticate(&state.tokens.read().await, &headers) {
+        return response;
+    }
+
+    (StatusCode::OK, state.cache.to_zone().serialise())
+}
+
+async fn delete_cache(State(state): State<AdminState>, headers: HeaderMap) -> (StatusCode, String) {
+    let token = match authenticate(&state.tokens.read().await, &headers) {
+        Ok(token) => token,
+        Err(response) => return response,
+    };
+
+    let removed = state.cache.clear();
+    state.prime_cache.notify_one();
+    tracing::info!(
+        target: AUDIT_LOG_TARGET,
+        token = %token.name,
+        %removed,
+        "cleared cache"
+    );
+
+    (StatusCode::NO_CONTENT, String::new())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeleteCacheParams {
+    #[serde(rename = "type")]
+    qtype: Option<String>,
+    #[serde(default)]
+    subtree: bool,
+}
+
+async fn delete_cache_name(
+    State(state): State<AdminState>,
+    Path(name): Path<String>,
+    Query(params): Query<DeleteCacheParams>,
+    headers: HeaderMap,
+) -> (StatusCode, String) {
+    let token = match authenticate(&state.tokens.read().await, &headers) {
+        Ok(token) => token,
+        Err(response) => return response,
+    };
+
+    let Ok(name) = DomainName::from_str(&name) else {
+        return (StatusCode::BAD_REQUEST, "invalid domain name\n".to_string());
+    };
+    let qtype = match params.qtype.as_deref().map(QueryType::from_str) {
+        None => QueryType::Wildcard,
+        Some(Ok(qtype)) => qtype,
+        Some(Err(_)) => return (StatusCode::BAD_REQUEST, "invalid type\n".to_string()),
+    };
+    if params.subtree && qtype != QueryType::Wildcard {
+        return (
+            StatusCode::BAD_REQUEST,
+            "cannot remove a single type from a subtree\n".to_string(),
+        );
+    }
+
+    let removed = if params.subtree {
+        state.cache.remove_subtree(&name)
+    } else {
+        state.cache.remove(&name, qtype)
+    };
+    tracing::info!(
+        target: AUDIT_LOG_TARGET,
+        token = %token.name,
+        %name,
+        %qtype,
+        subtree = %params.subtree,
+        %removed,
+        "removed records from cache"
+    );
+
+    (StatusCode::NO_CONTENT, String::new())
+}
+
+#[derive(Debug, Serialize)]
+struct ZoneSummary {
+    apex: String,
+    authoritative: bool,
+    records: usize,
+    wildcard_records: usize,
+}
+
+async fn get_zones(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ZoneSummary>>, (StatusCode, String)> {
+    authenticate(&state.tokens.read().await, &headers)?;
+
+    let zones = state.zones.current.load();
+    let mut summaries = zones
+        .iter()
+        .map(|zone| ZoneSummary {
+            apex: zone.get_apex().to_dotted_string(),
+            authoritative: zone.is_authoritative(),
+            records: zone.record_count(),
+            wildcard_records: zone.wildcard_record_count(),
+        })
+        .collect::<Vec<_>>();
+    summaries.sort_by(|a, b| a.apex.cmp(&b.apex));
+
+    Ok(Json(summaries))
+}
+
+async fn get_recent_queries(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<RecentQuery>>, (StatusCode, String)> {
+    authenticate(&state.tokens.read().await, &headers)?;
+
+    Ok(Json(state.recent_queries.get()))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TailQueriesParams {
+    name: Option<String>,
+    client: Option<String>,
+    #[serde(rename = "type")]
+    qtype: Option<String>,
+}
+
+async fn get_tail_queries(
+    State(state): State<AdminState>,
+    Query(params): Query<TailQueriesParams>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let token = authenticate(&state.tokens.read().await, &headers)?;
+
+    let bad_request = |reason: &str| (StatusCode::BAD_REQUEST, format!("{reason}\n"));
+    let filter = QueryFilter {
+        name: match params.name.as_deref().map(DomainName::from_str) {
+            None => None,
+            Some(Ok(name)) => Some(name),
+            Some(Err(_)) => return Err(bad_request("invalid domain name")),
+        },
+        client: match params.client.as_deref().map(IpAddr::from_str) {
+            None => None,
+            Some(Ok(client)) => Some(client),
+            Some(Err(_)) => return Err(bad_request("invalid client address")),
+        },
+        qtype: match params.qtype.as_deref().map(QueryType::from_str) {
+            None => None,
+            Some(Ok(qtype)) => Some(qtype),
+            Some(Err(_)) => return Err(bad_request("invalid type")),
+        },
+    };
+
+    let Some(queries) = state.recent_queries.tail(filter.clone()) else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "recent queries are disabled\n".to_string(),
+        ));
+    };
+    tracing::info!(token = %token.name, %filter, "started tail");
+
+    // the stream ends, and the receiver is dropped, when the client
+    // disconnects
+    let lines = stream::unfold(queries, |mut queries| async move {
+        let query = queries.recv().await?;
+        Some((Ok::<_, Infallible>(format!("{query}\n")), queries))
+    });
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        Body::from_stream(lines),
+    )
+        .into_response())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TopQueriesParams {
+    count: Option<usize>,
+}
+
+async fn get_top_queries(
+    State(state): State<AdminState>,
+    Query(params): Query<TopQueriesParams>,
+    headers: HeaderMap,
+) -> Result<Json<TopSummary>, (StatusCode, String)> {
+    authenticate(&state.tokens.read().await, &headers)?;
+
+    Ok(Json(
+        state
+            .top_queries
+            .get(params.count.unwrap_or(DEFAULT_TOP_COUNT)),
+    ))
+}
+
+async fn delete_top_queries(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> (StatusCode, String) {
+    let token = match authenticate(&state.tokens.read().await, &headers) {
+        Ok(token) => token,
+        Err(response) => return response,
+    };
+
+    state.top_queries.reset();
+    tracing::info!(target: AUDIT_LOG_TARGET, token = %token.name, "reset top queries");
+
+    (StatusCode::NO_CONTENT, String::new())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClientsParams {
+    count: Option<usize>,
+}
+
+async fn get_clients(
+    State(state): State<AdminState>,
+    Query(params): Query<ClientsParams>,
+    headers: HeaderMap,
+) -> Result<Json<ClientStatsSummary>, (StatusCode, String)> {
+    authenticate(&state.tokens.read().await, &headers)?;
+
+    Ok(Json(state.client_stats.get(params.count)))
+}
+
+async fn delete_clients(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> (StatusCode, String) {
+    let token = match authenticate(&state.tokens.read().await, &headers) {
+        Ok(token) => token,
+        Err(response) => return response,
+    };
+
+    state.client_stats.reset();
+    tracing::info!(target: AUDIT_LOG_TARGET, token = %token.name, "reset client stats");
+
+    (StatusCode::NO_CONTENT, String::new())
+}
+
+async fn post_reload(State(state): State<AdminState>, headers: HeaderMap) -> (StatusCode, String) {
+    let token = match authenticate(&state.tokens.read().await, &headers) {
+        Ok(token) => token,
+        Err(response) => return response,
+    };
+
+    state.reload.notify_one();
+    tracing::info!(target: AUDIT_LOG_TARGET, token = %token.name, "requested reload");
+
+    (StatusCode::ACCEPTED, String::new())
+}
+
+/// Find the token given in the `Authorization` header.
+fn authenticate(
+    tokens: &[AdminToken],
+    headers: &HeaderMap,
+) -> Result<AdminToken, (StatusCode, String)> {
+    let unauthorised = || (StatusCode::UNAUTHORIZED, "invalid token\n".to_string());
+
+    let secret = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(unauthorised)?;
+
+    tokens
+        .iter()
+        .find(|token| constant_time_eq(token.token.as_bytes(), secret.as_bytes()))
+        .cloned()
+        .ok_or_else(unauthorised)
+}
+
+/// Check the token may change the override records for a domain, logging
+/// refusals to the audit log.
+fn authorise(
+    token: &AdminToken,
+    name: &str,
+    action: &str,
+) -> Result<DomainName, (StatusCode, String)> {
+    let Ok(name) = DomainName::from_str(name) else {
+        return Err((StatusCode::BAD_REQUEST, "invalid domain name\n".to_string()));
+    };
+
+    if token.may_modify(&name) {
+        Ok(name)
+    } else {
+        tracing::warn!(
+            target: AUDIT_LOG_TARGET,
+            token = %token.name,
+            %name,
+            %action,
+            "refused override change outside of token's domains"
+        );
+        Err((
+            StatusCode::FORBIDDEN,
+            "token may not change this domain\n".to_string(),
+        ))
+    }
+}
+
+/// Check the token may view and change the log filter, logging refusals to
+/// the audit log.
+fn authorise_logging(token: &AdminToken, action: &str) -> Result<(), (StatusCode, String)> {
+    if token.logging {
+        Ok(())
+    } else {
+        tracing::warn!(
+            target: AUDIT_LOG_TARGET,
+            token = %token.name,
+            %action,
+            "refused log filter access"
+        );
+        Err((
+            StatusCode::FORBIDDEN,
+            "token may not access the log filter\n".to_string(),
+        ))
+    }
+}
+
+/// Check that a zone is suitable to use as the override records for a domain:
+/// it must be non-authoritative, and only have non-wildcard records for that
+/// domain.
+fn validate_override(name: &DomainName, zone: &Zone) -> Result<(), &'static str> {
+    if zone.is_authoritative() {
+        return Err("override records cannot have a SOA");
+    }
+    if zone.wildcard_record_count() > 0 {
+        return Err("override records cannot be wildcards");
+    }
+
+    let all_records = zone.all_records();
+    if all_records.is_empty() {
+        return Err("no override records given");
+    }
+    if all_records.keys().any(|rname| rname != name) {
+        return Err("override records must all be for the domain being changed");
+    }
+
+    Ok(())
+}
+
+/// Compare two byte strings in time which depends only on their lengths, so
+/// that response timing doesn't reveal how much of a token was guessed.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use dns_types::protocol::types::test_util::*;
+
+    use super::*;
+
+    #[test]
+    fn authenticate_finds_token() {
+        let tokens = tokens();
+
+        assert_eq!(
+            Ok("lan".to_string()),
+            authenticate(&tokens, &bearer("lan-secret")).map(|token| token.name)
+        );
+        assert_eq!(
+            Ok("all".to_string()),
+            authenticate(&tokens, &bearer("all-secret")).map(|token| token.name)
+        );
+    }
+
+    #[test]
+    fn authenticate_rejects_missing_token() {
+        assert_eq!(
+            Err(StatusCode::UNAUTHORIZED),
+            authenticate(&tokens(), &HeaderMap::new())
+                .map(|token| token.name)
+                .map_err(|(status, _)| status)
+        );
+    }
+
+    #[test]
+    fn authenticate_rejects_wrong_token() {
+        for secret in ["", "lan", "lan-secret-", "LAN-SECRET"] {
+            assert_eq!(
+                Err(StatusCode::UNAUTHORIZED),
+                authenticate(&tokens(), &bearer(secret))
+                    .map(|token| token.name)
+                    .map_err(|(status, _)| status),
+                "{secret}"
+            );
+        }
+    }
+
+    #[test]
+    fn authenticate_rejects_other_schemes() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Basic lan-secret"),
+        );
+
+        assert_eq!(
+            Err(StatusCode::UNAUTHORIZED),
+            authenticate(&tokens(), &headers)
+                .map(|token| token.name)
+                .map_err(|(status, _)| status)
+        );
+    }
+
+    #[test]
+    fn may_modify_allows_domain_and_subdomains() {
+        let token = &tokens()[0];
+
+        assert!(token.may_modify(&domain("lan.")));
+        assert!(token.may_modify(&domain("nas.lan.")));
+        assert!(token.may_modify(&domain("www.nas.lan.")));
+    }
+
+    #[test]
+    fn may_modify_refuses_outside_domains() {
+        let token = &tokens()[0];
+
+        assert!(!token.may_modify(&DomainName::root_domain()));
+        assert!(!token.may_modify(&domain("example.com.")));
+        assert!(!token.may_modify(&domain("notlan.")));
+        assert!(!token.may_modify(&domain("lan.example.com.")));
+    }
+
+    #[test]
+    fn may_modify_refuses_parent_domain() {
+        let token = AdminToken {
+            name: "sub".to_string(),
+            token: "sub-secret".to_string(),
+            domains: vec![domain("home.lan.")],
+            logging: false,
+        };
+
+        assert!(token.may_modify(&domain("nas.home.lan.")));
+        assert!(!token.may_modify(&domain("lan.")));
+    }
+
+    #[test]
+    fn authorise_refuses_outside_domains() {
+        let token = &tokens()[0];
+
+        assert_eq!(Ok(domain("nas.lan.")), authorise(token, "nas.lan.", "put"));
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            authorise(token, "example.com.", "put").map_err(|(status, _)| status)
+        );
+        assert_eq!(
+            Err(StatusCode::BAD_REQUEST),
+            authorise(token, "not a domain..", "put").map_err(|(status, _)| status)
+        );
+    }
+
+    #[test]
+    fn authorise_logging_needs_permission() {
+        let tokens = tokens();
+
+        assert!(authorise_logging(&tokens[0], "get").is_err());
+        assert!(authorise_logging(&tokens[1], "get").is_ok());
+    }
+
+    #[test]
+    fn validate_override_accepts_records_for_domain() {
+        let zone = Zone::deserialise("nas.lan. 300 IN A 10.0.0.2\nnas.lan. 300 IN AAAA fd00::2\n")
+            .unwrap();
+
+        assert_eq!(Ok(()), validate_override(&domain("nas.lan."), &zone));
+    }
+
+    #[test]
+    fn validate_override_rejects_soa() {
+        let zone = Zone::deserialise("nas.lan. IN SOA mname. rname. 1 30 30 30 30\n").unwrap();
+
+        assert_eq!(
+            Err("override records cannot have a SOA"),
+            validate_override(&domain("nas.lan."), &zone)
+        );
+    }
+
+    #[test]
+    fn validate_override_rejects_wildcards() {
+        let zone = Zone::deserialise("*.nas.lan. 300 IN A 10.0.0.2\n").unwrap();
+
+        assert_eq!(
+            Err("override records cannot be wildcards"),
+            validate_override(&domain("nas.lan."), &zone)
+        );
+    }
+
+    #[test]
+    fn validate_override_rejects_empty() {
+        assert_eq!(
+            Err("no override records given"),
+            validate_override(&domain("nas.lan."), &Zone::default())
+        );
+    }
+
+    #[test]
+    fn validate_override_rejects_other_domains() {
+        let zone =
+            Zone::deserialise("nas.lan. 300 IN A 10.0.0.2\nwww.nas.lan. 300 IN A 10.0.0.3\n")
+                .unwrap();
+
+        assert_eq!(
+            Err("override records must all be for the domain being changed"),
+            validate_override(&domain("nas.lan."), &zone)
+        );
+    }
+
+    #[test]
+    fn constant_time_eq_compares() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    fn tokens() -> Vec<AdminToken> {
+        vec![
+            AdminToken {
+                name: "lan".to_string(),
+                token: "lan-secret".to_string(),
+                domains: vec![domain("lan.")],
+                logging: false,
+            },
+            AdminToken {
+                name: "all".to_string(),
+                token: "all-secret".to_string(),
+                domains: vec![DomainName::root_domain()],
+                logging: true,
+            },
+        ]
+    }
+
+    fn bearer(secret: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {secret}")).unwrap(),
+        );
+        headers
+    }
+}
@@ -0,0 +1,449 @@
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use dns_types::protocol::types::{Message, Rcode};
+
+use crate::net::{network, IPV4_PREFIX_LENGTH, IPV6_PREFIX_LENGTH};
+
+pub const CANNOT_PARSE_CLIENT_PRIVACY: &str = "expected one of 'none', 'truncate', 'hash'";
+
+const MUTEX_POISON_MESSAGE: &str =
+    "[INTERNAL ERROR] client stats mutex poisoned, cannot recover from this - aborting";
+
+/// How to identify clients in the per-client statistics.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum ClientPrivacy {
+    /// By their address.
+    #[default]
+    None,
+
+    /// By their network: the first 24 bits of an IPv4 address, or the first
+    /// 56 bits of an IPv6 address.  Clients on the same network are counted
+    /// together.
+    Truncate,
+
+    /// By a hash of their address, with a key chosen at random when
+    /// `resolved` starts, so that clients can be told apart but the hashes
+    /// can't be reversed by hashing every address.
+    Hash,
+}
+
+impl fmt::Display for ClientPrivacy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientPrivacy::None => write!(f, "none"),
+            ClientPrivacy::Truncate => write!(f, "truncate"),
+            ClientPrivacy::Hash => write!(f, "hash"),
+        }
+    }
+}
+
+impl FromStr for ClientPrivacy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ClientPrivacy::None),
+            "truncate" => Ok(ClientPrivacy::Truncate),
+            "hash" => Ok(ClientPrivacy::Hash),
+            _ => Err(CANNOT_PARSE_CLIENT_PRIVACY),
+        }
+    }
+}
+
+/// Query, block, and NXDOMAIN counts for each client, so that the admin API
+/// can show which devices are making the most queries, and how many of those
+/// are for blocked or nonexistent names.
+///
+/// Only a fixed number of clients are tracked: when a new one turns up and
+/// there is no room, the one seen least recently is forgotten.
+///
+/// Invoking `clone` on a `ClientStats` gives a new instance which refers to
+/// the same counts.
+#[derive(Debug, Clone)]
+pub struct ClientStats {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    capacity: usize,
+    privacy: ClientPrivacy,
+    hasher: RandomState,
+    since: SystemTime,
+    clients: HashMap<String, Counts>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Counts {
+    queries: u64,
+    blocked: u64,
+    nxdomain: u64,
+    last_seen: SystemTime,
+}
+
+/// The counts for every tracked client.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientStatsSummary {
+    /// When counting started, in seconds since the UNIX epoch.
+    pub since: u64,
+    pub privacy: String,
+    pub clients: Vec<ClientEntry>,
+}
+
+/// A client, and the queries it has made.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientEntry {
+    /// The client's address, network, or hashed address, depending on the
+    /// privacy mode.
+    pub client: String,
+    pub queries: u64,
+    /// How many queries were for a name blocked by a hosts file or zone.
+    pub blocked: u64,
+    /// How many queries were answered with NXDOMAIN.
+    pub nxdomain: u64,
+    /// The fraction of queries answered with NXDOMAIN.
+    pub nxdomain_rate: f64,
+    /// When the client last made a query, in seconds since the UNIX epoch.
+    pub last_seen: u64,
+}
+
+impl ClientStats {
+    /// Create counters which track up to `capacity` clients, identified as
+    /// `privacy` says.  A capacity of zero means nothing is counted.
+    pub fn new(capacity: usize, privacy: ClientPrivacy) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                privacy,
+                hasher: RandomState::new(),
+                since: SystemTime::now(),
+                clients: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Count a query, by its response, and whether it was blocked.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn record(&self, client: IpAddr, response: &Message, blocked: bool) {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        if inner.capacity == 0 {
+            return;
+        }
+
+        let key = inner.key(client);
+        if !inner.clients.contains_key(&key) && inner.clients.len() >= inner.capacity {
+            if let Some(oldest) = inner
+                .clients
+                .iter()
+                .min_by_key(|(_, counts)| counts.last_seen)
+                .map(|(key, _)| key.clone())
+            {
+                inner.clients.remove(&oldest);
+            }
+        }
+
+        let counts = inner.clients.entry(key).or_insert(Counts {
+            queries: 0,
+            blocked: 0,
+            nxdomain: 0,
+            last_seen: SystemTime::now(),
+        });
+        counts.queries += 1;
+        if blocked {
+            counts.blocked += 1;
+        }
+        if response.header.rcode == Rcode::NameError {
+            counts.nxdomain += 1;
+        }
+        counts.last_seen = SystemTime::now();
+    }
+
+    /// Get the counts for the `count` clients making the most queries, most
+    /// first, or for every client if `count` is `None`.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn get(&self, count: Option<usize>) -> ClientStatsSummary {
+        let inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        let mut clients = inner
+            .clients
+            .iter()
+            .map(|(client, counts)| ClientEntry {
+                client: client.clone(),
+                queries: counts.queries,
+                blocked: counts.blocked,
+                nxdomain: counts.nxdomain,
+                #[allow(clippy::cast_precision_loss)]
+                nxdomain_rate: counts.nxdomain as f64 / counts.queries as f64,
+                last_seen: unix_seconds(counts.last_seen),
+            })
+            .collect::<Vec<_>>();
+        clients.sort_by_key(|entry| Reverse(entry.queries));
+        if let Some(count) = count {
+            clients.truncate(count);
+        }
+
+        ClientStatsSummary {
+            since: unix_seconds(inner.since),
+            privacy: inner.privacy.to_string(),
+            clients,
+        }
+    }
+
+    /// Forget every count and start again.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn reset(&self) {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        inner.since = SystemTime::now();
+        inner.clients.clear();
+    }
+
+    /// Change how many clients are tracked, and how they are identified.  If
+    /// the capacity shrinks, the clients seen least recently are dropped.  If
+    /// the privacy mode changes, every count is forgotten, so that addresses
+    /// recorded before aren't kept after a switch to a more private mode.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn set_limits(&self, capacity: usize, privacy: ClientPrivacy) {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        inner.capacity = capacity;
+        if inner.privacy != privacy {
+            inner.privacy = privacy;
+            inner.since = SystemTime::now();
+            inner.clients.clear();
+        }
+
+        if inner.clients.len() > capacity {
+            let mut entries = inner.clients.drain().collect::<Vec<_>>();
+            entries.sort_by_key(|(_, counts)| Reverse(counts.last_seen));
+            entries.truncate(capacity);
+            inner.clients = entries.into_iter().collect();
+        }
+    }
+}
+
+impl Inner {
+    /// How to identify a client, according to the privacy mode.
+    fn key(&self, client: IpAddr) -> String {
+        match self.privacy {
+            ClientPrivacy::None => client.to_string(),
+            ClientPrivacy::Truncate => {
+                let prefix = if client.is_ipv4() {
+                    IPV4_PREFIX_LENGTH
+                } else {
+                    IPV6_PREFIX_LENGTH
+                };
+                format!("{}/{prefix}", network(client))
+            }
+            ClientPrivacy::Hash => format!("{:016x}", self.hasher.hash_one(client)),
+        }
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use dns_types::protocol::types::test_util::*;
+
+    use super::*;
+
+    #[test]
+    fn client_privacy_from_str_roundtrips() {
+        for privacy in [
+            ClientPrivacy::None,
+            ClientPrivacy::Truncate,
+            ClientPrivacy::Hash,
+        ] {
+            assert_eq!(Ok(privacy), privacy.to_string().parse());
+        }
+        assert_eq!(
+            Err(CANNOT_PARSE_CLIENT_PRIVACY),
+            ClientPrivacy::from_str("anonymise")
+        );
+    }
+
+    #[test]
+    fn counts_queries_blocks_and_nxdomains() {
+        let stats = ClientStats::new(10, ClientPrivacy::None);
+
+        stats.record(ipv4(1), &response(Rcode::NoError), false);
+        stats.record(ipv4(1), &response(Rcode::NoError), true);
+        stats.record(ipv4(1), &response(Rcode::NameError), false);
+        stats.record(ipv4(1), &response(Rcode::NameError), false);
+        stats.record(ipv4(2), &response(Rcode::NoError), false);
+
+        let summary = stats.get(None);
+        assert_eq!("none", summary.privacy);
+        assert_eq!(2, summary.clients.len());
+
+        let entry = &summary.clients[0];
+        assert_eq!("10.0.0.1", entry.client);
+        assert_eq!(4, entry.queries);
+        assert_eq!(1, entry.blocked);
+        assert_eq!(2, entry.nxdomain);
+        assert!((entry.nxdomain_rate - 0.5).abs() < f64::EPSILON);
+
+        assert_eq!("10.0.0.2", summary.clients[1].client);
+        assert_eq!(1, summary.clients[1].queries);
+    }
+
+    #[test]
+    fn get_limits_count() {
+        let stats = ClientStats::new(10, ClientPrivacy::None);
+        for i in 1..=3 {
+            for _ in 0..i {
+                stats.record(ipv4(i), &response(Rcode::NoError), false);
+            }
+        }
+
+        assert_eq!(vec!["10.0.0.3", "10.0.0.2"], clients(&stats.get(Some(2))));
+    }
+
+    #[test]
+    fn truncate_groups_by_network() {
+        let stats = ClientStats::new(10, ClientPrivacy::Truncate);
+
+        stats.record(ipv4(1), &response(Rcode::NoError), false);
+        stats.record(ipv4(200), &response(Rcode::NoError), false);
+        stats.record(
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0x01ab, 0, 0, 0, 1)),
+            &response(Rcode::NoError),
+            false,
+        );
+
+        let summary = stats.get(None);
+        assert_eq!("truncate", summary.privacy);
+        assert_eq!(
+            vec!["10.0.0.0/24", "2001:db8:0:100::/56"],
+            clients(&summary)
+        );
+        assert_eq!(2, summary.clients[0].queries);
+    }
+
+    #[test]
+    fn hash_hides_addresses() {
+        let stats = ClientStats::new(10, ClientPrivacy::Hash);
+
+        stats.record(ipv4(1), &response(Rcode::NoError), false);
+        stats.record(ipv4(1), &response(Rcode::NoError), false);
+        stats.record(ipv4(2), &response(Rcode::NoError), false);
+
+        let summary = stats.get(None);
+        assert_eq!("hash", summary.privacy);
+        assert_eq!(2, summary.clients.len());
+        assert_eq!(2, summary.clients[0].queries);
+        for entry in &summary.clients {
+            assert_eq!(16, entry.client.len());
+            assert!(!entry.client.contains("10.0.0"));
+        }
+        assert_ne!(summary.clients[0].client, summary.clients[1].client);
+    }
+
+    #[test]
+    fn forgets_least_recently_seen_when_full() {
+        let stats = ClientStats::new(2, ClientPrivacy::None);
+
+        stats.record(ipv4(1), &response(Rcode::NoError), false);
+        sleep(Duration::from_millis(2));
+        stats.record(ipv4(2), &response(Rcode::NoError), false);
+        sleep(Duration::from_millis(2));
+        stats.record(ipv4(1), &response(Rcode::NoError), false);
+        sleep(Duration::from_millis(2));
+        stats.record(ipv4(3), &response(Rcode::NoError), false);
+
+        let summary = stats.get(None);
+        let mut clients = clients(&summary);
+        clients.sort_unstable();
+        assert_eq!(vec!["10.0.0.1", "10.0.0.3"], clients);
+    }
+
+    #[test]
+    fn capacity_zero_counts_nothing() {
+        let stats = ClientStats::new(0, ClientPrivacy::None);
+
+        stats.record(ipv4(1), &response(Rcode::NoError), false);
+
+        assert!(stats.get(None).clients.is_empty());
+    }
+
+    #[test]
+    fn set_limits_changing_privacy_forgets_everything() {
+        let stats = ClientStats::new(10, ClientPrivacy::None);
+        stats.record(ipv4(1), &response(Rcode::NoError), false);
+
+        stats.set_limits(10, ClientPrivacy::None);
+        assert_eq!(1, stats.get(None).clients.len());
+
+        stats.set_limits(10, ClientPrivacy::Truncate);
+        let summary = stats.get(None);
+        assert_eq!("truncate", summary.privacy);
+        assert!(summary.clients.is_empty());
+    }
+
+    #[test]
+    fn set_limits_shrinking_keeps_most_recent() {
+        let stats = ClientStats::new(10, ClientPrivacy::None);
+        for i in 1..=3 {
+            stats.record(ipv4(i), &response(Rcode::NoError), false);
+            sleep(Duration::from_millis(2));
+        }
+
+        stats.set_limits(1, ClientPrivacy::None);
+
+        assert_eq!(vec!["10.0.0.3"], clients(&stats.get(None)));
+    }
+
+    #[test]
+    fn reset_forgets_everything() {
+        let stats = ClientStats::new(10, ClientPrivacy::None);
+        stats.record(ipv4(1), &response(Rcode::NoError), false);
+
+        stats.reset();
+
+        assert!(stats.get(None).clients.is_empty());
+    }
+
+    fn clients(summary: &ClientStatsSummary) -> Vec<&str> {
+        summary
+            .clients
+            .iter()
+            .map(|entry| entry.client.as_str())
+            .collect()
+    }
+
+    fn ipv4(i: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, i))
+    }
+
+    fn response(rcode: Rcode) -> Message {
+        let mut response = Message::from_question(0, question("www.example.com.")).make_response();
+        response.header.rcode = rcode;
+        response
+    }
+}
@@ -0,0 +1,248 @@
+use serde::{Deserialize, Deserializer};
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use dns_resolver::util::types::{
+    AnswerRotation, BlockedResponse, CachePolicy, ForwardingRule, ForwardingStrategy, HttpsUrl,
+    LocalZoneRule, ProtocolMode, TransportKind, UpstreamProxy, UpstreamSecurityRule,
+    ZonePrecedence, ZonePrecedenceRule,
+};
+use dns_types::protocol::types::DomainName;
+
+use crate::admin::AdminToken;
+use crate::clients::ClientPrivacy;
+use crate::overload::OverloadAction;
+use crate::ratelimit::RateLimitAction;
+use crate::remote::HostsUrl;
+
+/// The contents of a `resolved` configuration file.
+///
+/// Most settings correspond to a command-line argument: settings which take a
+/// single value are only used if the argument was not given, and settings
+/// which take a list of values are combined with the arguments.
+///
+/// Admin API tokens can only be given in the configuration file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub addresses: Vec<SocketAddr>,
+    pub udp_sockets: Option<usize>,
+    pub metrics_address: Option<SocketAddr>,
+    pub recent_queries: Option<usize>,
+    pub top_queries: Option<usize>,
+    pub top_queries_metrics: Option<usize>,
+    pub client_stats: Option<usize>,
+    #[serde(deserialize_with = "parse_optional")]
+    pub client_stats_privacy: Option<ClientPrivacy>,
+    pub control_socket: Option<PathBuf>,
+    pub authoritative_only: Option<bool>,
+    #[serde(deserialize_with = "parse_list")]
+    pub recursion_domains: Vec<DomainName>,
+    #[serde(deserialize_with = "parse_list")]
+    pub no_recursion_domains: Vec<DomainName>,
+    #[serde(deserialize_with = "parse_list")]
+    pub local_zones: Vec<LocalZoneRule>,
+    #[serde(deserialize_with = "parse_optional")]
+    pub protocol_mode: Option<ProtocolMode>,
+    pub upstream_dns_port: Option<u16>,
+    pub outbound_binds: Vec<IpAddr>,
+    #[serde(deserialize_with = "parse_optional")]
+    pub upstream_proxy: Option<UpstreamProxy>,
+    pub no_qname_minimisation: Option<bool>,
+    pub query_timeout: Option<u64>,
+    pub resolution_timeout: Option<u64>,
+    pub minimal_any: Option<bool>,
+    pub minimal_responses: Option<bool>,
+    #[serde(deserialize_with = "parse_optional")]
+    pub answer_rotation: Option<AnswerRotation>,
+    pub multiple_questions: Option<bool>,
+    pub forward_addresses: Vec<SocketAddr>,
+    #[serde(deserialize_with = "parse_list")]
+    pub forward_urls: Vec<HttpsUrl>,
+    #[serde(deserialize_with = "parse_optional")]
+    pub forward_strategy: Option<ForwardingStrategy>,
+    #[serde(deserialize_with = "parse_list")]
+    pub forward_transports: Vec<TransportKind>,
+    #[serde(deserialize_with = "parse_list")]
+    pub forward_rules: Vec<ForwardingRule>,
+    #[serde(deserialize_with = "parse_list")]
+    pub upstream_security_rules: Vec<UpstreamSecurityRule>,
+    pub forward_client_subnet: Option<bool>,
+    pub client_subnet_ipv4_prefix: Option<u8>,
+    pub client_subnet_ipv6_prefix: Option<u8>,
+    pub cache_size: Option<usize>,
+    #[serde(deserialize_with = "parse_optional")]
+    pub cache_policy: Option<CachePolicy>,
+    pub min_ttl: Option<u32>,
+    pub max_ttl: Option<u32>,
+    pub clamp_authoritative_ttls: Option<bool>,
+    pub last_known_good_max_age: Option<u64>,
+    pub client_rate_limit: Option<u32>,
+    pub global_rate_limit: Option<u32>,
+    #[serde(deserialize_with = "parse_optional")]
+    pub rate_limit_action: Option<RateLimitAction>,
+    pub response_rate_limit: Option<u32>,
+    pub response_rate_limit_slip: Option<u32>,
+    pub require_cookies: Option<bool>,
+    pub max_in_flight: Option<usize>,
+    pub request_timeout: Option<u64>,
+    #[serde(deserialize_with = "parse_optional")]
+    pub overload_action: Option<OverloadAction>,
+    pub max_tcp_connections: Option<usize>,
+    pub tcp_idle_timeout: Option<u64>,
+    pub hosts_files: Vec<PathBuf>,
+    pub hosts_dirs: Vec<PathBuf>,
+    #[serde(deserialize_with = "parse_list")]
+    pub hosts_urls: Vec<HostsUrl>,
+    pub hosts_url_dir: Option<PathBuf>,
+    pub refresh_interval: Option<u64>,
+    pub refresh_local_files: Option<bool>,
+    pub zone_files: Vec<PathBuf>,
+    pub zones_dirs: Vec<PathBuf>,
+    pub zones_dirs_auto: Vec<PathBuf>,
+    pub synthesise_ptr: Option<bool>,
+    pub compact_hosts: Option<bool>,
+    pub hosts_ttl: Option<u32>,
+    #[serde(deserialize_with = "parse_list")]
+    pub flatten_cnames: Vec<DomainName>,
+    pub skip_bad_files: Option<bool>,
+    #[serde(deserialize_with = "parse_list")]
+    pub allow_domains: Vec<DomainName>,
+    pub allowlist_files: Vec<PathBuf>,
+    #[serde(deserialize_with = "parse_optional")]
+    pub blocked_response: Option<BlockedResponse>,
+    #[serde(deserialize_with = "parse_optional")]
+    pub zone_precedence: Option<ZonePrecedence>,
+    #[serde(deserialize_with = "parse_list")]
+    pub zone_precedence_rules: Vec<ZonePrecedenceRule>,
+    #[serde(deserialize_with = "parse_list")]
+    pub fast_path_zones: Vec<DomainName>,
+    pub watch: Option<bool>,
+    pub root_hints: Option<PathBuf>,
+    pub prime_file: Option<PathBuf>,
+    pub admin_tokens: Vec<AdminToken>,
+}
+
+impl Config {
+    /// Parse a configuration file.
+    ///
+    /// # Errors
+    ///
+    /// If the string cannot be parsed.
+    pub fn deserialise(data: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(data)
+    }
+}
+
+/// Deserialise a list of strings using `FromStr`, for types which do not
+/// implement `Deserialize` themselves.
+pub(crate) fn parse_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| T::from_str(s).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Deserialise an optional string using `FromStr`, for types which do not
+/// implement `Deserialize` themselves.
+fn parse_optional<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => T::from_str(&s).map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dns_types::protocol::types::test_util::domain;
+
+    use super::*;
+
+    #[test]
+    fn deserialise_empty() {
+        let config = Config::deserialise("").unwrap();
+
+        assert!(config.addresses.is_empty());
+        assert_eq!(None, config.udp_sockets);
+        assert_eq!(None, config.protocol_mode);
+        assert!(config.forward_rules.is_empty());
+        assert!(config.admin_tokens.is_empty());
+    }
+
+    #[test]
+    fn deserialise_settings() {
+        let config = Config::deserialise(
+            r#"
+            addresses = ["127.0.0.1:53", "[::1]:53"]
+            udp-sockets = 4
+            authoritative-only = true
+            protocol-mode = "only-v6"
+            recursion-domains = ["lan."]
+            forward-addresses = ["1.1.1.1:53"]
+            forward-rules = ["corp.example.com.=10.0.0.1:53"]
+            zone-files = ["/etc/resolved/zones/lan.zone"]
+
+            [[admin-tokens]]
+            name = "dhcp"
+            token = "secret"
+            domains = ["lan."]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                "127.0.0.1:53".parse::<SocketAddr>().unwrap(),
+                "[::1]:53".parse().unwrap()
+            ],
+            config.addresses
+        );
+        assert_eq!(Some(4), config.udp_sockets);
+        assert_eq!(Some(true), config.authoritative_only);
+        assert_eq!(Some(ProtocolMode::OnlyV6), config.protocol_mode);
+        assert_eq!(vec![domain("lan.")], config.recursion_domains);
+        assert_eq!(
+            vec!["1.1.1.1:53".parse::<SocketAddr>().unwrap()],
+            config.forward_addresses
+        );
+        assert_eq!(
+            vec![ForwardingRule {
+                domain: domain("corp.example.com."),
+                address: Some("10.0.0.1:53".parse().unwrap()),
+            }],
+            config.forward_rules
+        );
+        assert_eq!(
+            vec![PathBuf::from("/etc/resolved/zones/lan.zone")],
+            config.zone_files
+        );
+        assert_eq!(1, config.admin_tokens.len());
+        assert_eq!("dhcp", config.admin_tokens[0].name);
+        assert_eq!(vec![domain("lan.")], config.admin_tokens[0].domains);
+        assert!(!config.admin_tokens[0].logging);
+    }
+
+    #[test]
+    fn deserialise_rejects_unknown_keys() {
+        assert!(Config::deserialise("adresses = [\"127.0.0.1:53\"]").is_err());
+    }
+
+    #[test]
+    fn deserialise_rejects_bad_values() {
+        assert!(Config::deserialise("protocol-mode = \"only-v5\"").is_err());
+        assert!(Config::deserialise("forward-rules = [\"lan.\"]").is_err());
+        assert!(Config::deserialise("udp-sockets = \"four\"").is_err());
+    }
+}
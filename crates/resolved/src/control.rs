@@ -0,0 +1,493 @@
+use std::fmt;
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf,
+};
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::Notify;
+
+use dns_resolver::cache::SharedCache;
+use dns_types::protocol::types::{DomainName, QueryType};
+
+use crate::admin::AUDIT_LOG_TARGET;
+use crate::logging::{LogFilter, LogFormat};
+use crate::overrides::ServedZones;
+use crate::recent::{QueryFilter, RecentQueries};
+use crate::top::{TopQueries, DEFAULT_TOP_COUNT};
+
+/// A command sent to the control socket, on a single line.
+///
+/// The response is `ok` on a line by itself followed by any output, or
+/// `error: ` followed by the reason.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Command {
+    /// Reload the configuration, hosts, and zones, as if `resolved` had been
+    /// sent SIGHUP.
+    Reload,
+    /// Remove every record from the cache.
+    FlushCache,
+    /// Remove the records of one type (or every type, for a wildcard query)
+    /// for a domain from the cache.
+    FlushCacheName(DomainName, QueryType),
+    /// Remove the records for a domain and all of its subdomains from the
+    /// cache.
+    FlushCacheSubtree(DomainName),
+    /// Get every record in the cache, in zone file format.
+    DumpCache,
+    /// Get statistics about the cache and the zones.
+    Stats,
+    /// Get this many of the most queried names, the clients making the most
+    /// queries, and the names those clients query most.
+    Top(usize),
+    /// Get the log filter, in `RUST_LOG` format.
+    GetLogFilter,
+    /// Replace the log filter, in `RUST_LOG` format.
+    SetLogFilter(String),
+    /// Get the log format, in `RUST_LOG_FORMAT` format.
+    GetLogFormat,
+    /// Replace the log format.
+    SetLogFormat(LogFormat),
+    /// Follow the queries which match a filter, one per line, until the
+    /// connection is closed.
+    Tail(QueryFilter),
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Reload => write!(f, "reload"),
+            Command::FlushCache => write!(f, "flush-cache"),
+            Command::FlushCacheName(name, qtype) => write!(f, "flush-cache {name} {qtype}"),
+            Command::FlushCacheSubtree(name) => write!(f, "flush-cache-subtree {name}"),
+            Command::DumpCache => write!(f, "dump-cache"),
+            Command::Stats => write!(f, "stats"),
+            Command::Top(count) => write!(f, "top {count}"),
+            Command::GetLogFilter => write!(f, "log-filter"),
+            Command::SetLogFilter(directives) => write!(f, "log-filter {directives}"),
+            Command::GetLogFormat => write!(f, "log-format"),
+            Command::SetLogFormat(format) => write!(f, "log-format {format}"),
+            Command::Tail(filter) if *filter == QueryFilter::default() => write!(f, "tail"),
+            Command::Tail(filter) => write!(f, "tail {filter}"),
+        }
+    }
+}
+
+/// An error parsing a command.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CommandFromStrError {
+    /// There is no such command.
+    UnknownCommand(String),
+    /// The command was given the wrong number of arguments.
+    WrongArguments(String),
+    /// A domain name could not be parsed.
+    InvalidDomainName(String),
+    /// A query type could not be parsed.
+    InvalidQueryType(String),
+    /// A log format could not be parsed.
+    InvalidLogFormat(String),
+    /// A count could not be parsed.
+    InvalidCount(String),
+    /// A query filter could not be parsed.
+    InvalidFilter(String),
+}
+
+impl fmt::Display for CommandFromStrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandFromStrError::UnknownCommand(command) => {
+                write!(f, "unknown command '{command}'")
+            }
+            CommandFromStrError::WrongArguments(command) => {
+                write!(f, "wrong number of arguments for '{command}'")
+            }
+            CommandFromStrError::InvalidDomainName(name) => {
+                write!(f, "invalid domain name '{name}'")
+            }
+            CommandFromStrError::InvalidQueryType(qtype) => write!(f, "invalid type '{qtype}'"),
+            CommandFromStrError::InvalidLogFormat(error) => write!(f, "{error}"),
+            CommandFromStrError::InvalidCount(count) => write!(f, "invalid count '{count}'"),
+            CommandFromStrError::InvalidFilter(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for CommandFromStrError {}
+
+impl FromStr for Command {
+    type Err = CommandFromStrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (command, rest) = s.trim().split_once(' ').unwrap_or((s.trim(), ""));
+        let args = rest.split_whitespace().collect::<Vec<_>>();
+        let wrong_arguments = || Err(CommandFromStrError::WrongArguments(command.to_string()));
+        let domain = |name: &str| {
+            DomainName::from_str(name)
+                .map_err(|_| CommandFromStrError::InvalidDomainName(name.to_string()))
+        };
+
+        match (command, args.as_slice()) {
+            ("reload", []) => Ok(Command::Reload),
+            ("flush-cache", []) => Ok(Command::FlushCache),
+            ("flush-cache", [name]) => {
+                Ok(Command::FlushCacheName(domain(name)?, QueryType::Wildcard))
+            }
+            ("flush-cache", [name, qtype]) => Ok(Command::FlushCacheName(
+                domain(name)?,
+                QueryType::from_str(qtype)
+                    .map_err(|_| CommandFromStrError::InvalidQueryType((*qtype).to_string()))?,
+            )),
+            ("flush-cache-subtree", [name]) => Ok(Command::FlushCacheSubtree(domain(name)?)),
+            ("dump-cache", []) => Ok(Command::DumpCache),
+            ("stats", []) => Ok(Command::Stats),
+            ("top", []) => Ok(Command::Top(DEFAULT_TOP_COUNT)),
+            ("top", [count]) => usize::from_str(count)
+                .map(Command::Top)
+                .map_err(|_| CommandFromStrError::InvalidCount((*count).to_string())),
+            ("log-filter", []) => Ok(Command::GetLogFilter),
+            ("log-filter", _) => Ok(Command::SetLogFilter(rest.trim().to_string())),
+            ("log-format", []) => Ok(Command::GetLogFormat),
+            ("log-format", [format]) => LogFormat::from_str(format)
+                .map(Command::SetLogFormat)
+                .map_err(|error| CommandFromStrError::InvalidLogFormat(error.to_string())),
+            ("tail", _) => QueryFilter::from_str(rest)
+                .map(Command::Tail)
+                .map_err(|error| CommandFromStrError::InvalidFilter(error.to_string())),
+            (
+                "reload"
+                | "flush-cache"
+                | "flush-cache-subtree"
+                | "dump-cache"
+                | "stats"
+                | "top"
+                | "log-format",
+                _,
+            ) => wrong_arguments(),
+            _ => Err(CommandFromStrError::UnknownCommand(command.to_string())),
+        }
+    }
+}
+
+/// State for the control socket.
+#[derive(Debug, Clone)]
+pub struct ControlState {
+    pub zones: ServedZones,
+    pub log_filter: LogFilter,
+    pub cache: SharedCache,
+    /// Notified to reload the configuration, hosts, and zones, as if
+    /// `resolved` had been sent SIGHUP.
+    pub reload: Arc<Notify>,
+    /// Notified to resolve the questions in the prime file again, after the
+    /// whole cache is flushed.
+    pub prime_cache: Arc<Notify>,
+    pub top_queries: TopQueries,
+    pub recent_queries: RecentQueries,
+}
+
+/// The control socket: a unix socket, or a named pipe (like
+/// `\\.\pipe\resolved`) on Windows.
+#[derive(Debug)]
+pub struct ControlListener {
+    #[cfg(unix)]
+    listener: UnixListener,
+    #[cfg(windows)]
+    path: std::path::PathBuf,
+    #[cfg(windows)]
+    server: NamedPipeServer,
+}
+
+impl ControlListener {
+    /// Bind the control socket, replacing any stale socket file left behind by
+    /// a previous run.  Only the current user may connect to it.
+    ///
+    /// # Errors
+    ///
+    /// If the socket cannot be bound.
+    #[cfg(unix)]
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        use std::os::unix::fs::PermissionsExt;
+
+        match std::fs::remove_file(path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => (),
+        }
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(Self { listener })
+    }
+
+    /// Create the control pipe.  Remote clients are rejected, and it is an
+    /// error if the pipe already exists (for example, if another `resolved`
+    /// is using it).
+    ///
+    /// # Errors
+    ///
+    /// If the pipe cannot be created.
+    #[cfg(windows)]
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        let server = ServerOptions::new()
+            .first_pipe_instance(true)
+            .reject_remote_clients(true)
+            .create(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            server,
+        })
+    }
+
+    /// Wait for the next connection.
+    #[cfg(unix)]
+    async fn accept(&mut self) -> io::Result<impl AsyncRead + AsyncWrite + Send + 'static> {
+        let (stream, _) = self.listener.accept().await?;
+        Ok(stream)
+    }
+
+    /// Wait for the next connection.  Each pipe instance only serves one
+    /// client, so a new instance is created for the next.
+    #[cfg(windows)]
+    async fn accept(&mut self) -> io::Result<impl AsyncRead + AsyncWrite + Send + 'static> {
+        self.server.connect().await?;
+        let next = ServerOptions::new()
+            .reject_remote_clients(true)
+            .create(&self.path)?;
+        Ok(std::mem::replace(&mut self.server, next))
+    }
+}
+
+/// Accept connections to the control socket forever, answering one command
+/// per connection.
+pub async fn serve(mut listener: ControlListener, state: ControlState) {
+    loop {
+        match listener.accept().await {
+            Ok(stream) => {
+                tokio::spawn(handle_connection(stream, state.clone()));
+            }
+            Err(error) => tracing::warn!(?error, "could not accept control socket connection"),
+        }
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite>(stream: S, state: ControlState) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    if let Err(error) = reader.read_line(&mut line).await {
+        tracing::debug!(?error, "could not read control socket command");
+        return;
+    }
+
+    let response = match Command::from_str(&line) {
+        Ok(Command::Tail(filter)) => {
+            if let Err(error) = tail(&state, filter, reader, writer).await {
+                tracing::debug!(?error, "could not write control socket response");
+            }
+            return;
+        }
+        Ok(command) => match run(&state, &command).await {
+            Ok(output) => format!("ok\n{output}"),
+            Err(error) => format!("error: {error}\n"),
+        },
+        Err(error) => format!("error: {error}\n"),
+    };
+
+    if let Err(error) = writer.write_all(response.as_bytes()).await {
+        tracing::debug!(?error, "could not write control socket response");
+    }
+}
+
+/// Write the queries which match a filter, one per line, until the client
+/// closes the connection.
+async fn tail<S: AsyncRead + AsyncWrite>(
+    state: &ControlState,
+    filter: QueryFilter,
+    mut reader: BufReader<ReadHalf<S>>,
+    mut writer: WriteHalf<S>,
+) -> io::Result<()> {
+    let Some(mut queries) = state.recent_queries.tail(filter.clone()) else {
+        return writer
+            .write_all(b"error: recent queries are disabled (--recent-queries 0)\n")
+            .await;
+    };
+    tracing::info!(source = "control socket", %filter, "started tail");
+
+    writer.write_all(b"ok\n").await?;
+    writer.flush().await?;
+    let mut ignored = String::new();
+    loop {
+        tokio::select! {
+            query = queries.recv() => {
+                let Some(query) = query else {
+                    break;
+                };
+                writer.write_all(format!("{query}\n").as_bytes()).await?;
+                writer.flush().await?;
+            }
+            // the client doesn't send anything else, so this only finishes
+            // when it closes the connection
+            _ = reader.read_line(&mut ignored) => break,
+        }
+    }
+
+    tracing::info!(source = "control socket", %filter, "stopped tail");
+    Ok(())
+}
+
+/// Run a command, returning its output.
+async fn run(state: &ControlState, command: &Command) -> Result<String, String> {
+    match command {
+        Command::Reload => {
+            state.reload.notify_one();
+            tracing::info!(target: AUDIT_LOG_TARGET, source = "control socket", "reload");
+            Ok(String::new())
+        }
+        Command::FlushCache => {
+            let removed = state.cache.clear();
+            state.prime_cache.notify_one();
+            tracing::info!(
+                target: AUDIT_LOG_TARGET,
+                source = "control socket",
+                %removed,
+                "cleared cache"
+            );
+            Ok(format!("{removed}\n"))
+        }
+        Command::FlushCacheName(name, qtype) => {
+            let removed = state.cache.remove(name, *qtype);
+            tracing::info!(
+                target: AUDIT_LOG_TARGET,
+                source = "control socket",
+                %name,
+                %qtype,
+                subtree = false,
+                %removed,
+                "removed records from cache"
+            );
+            Ok(format!("{removed}\n"))
+        }
+        Command::FlushCacheSubtree(name) => {
+            let removed = state.cache.remove_subtree(name);
+            tracing::info!(
+                target: AUDIT_LOG_TARGET,
+                source = "control socket",
+                %name,
+                subtree = true,
+                %removed,
+                "removed records from cache"
+            );
+            Ok(format!("{removed}\n"))
+        }
+        Command::DumpCache => Ok(state.cache.to_zone().serialise()),
+        Command::Stats => {
+            let mut stats = state.cache.stats().into_iter().collect::<Vec<_>>();
+            stats.sort_by_key(|(rtype, _)| rtype.to_string());
+
+            let zones = state.zones.current.load();
+            let mut output = format!(
+                "zones: {}\ncache records: {}\n\n{:<10} {:>10} {:>10} {:>10} {:>10} {:>10}\n",
+                zones.len(),
+                stats.iter().map(|(_, s)| s.entries).sum::<usize>(),
+                "type",
+                "records",
+                "hits",
+                "misses",
+                "expired",
+                "pruned",
+            );
+            for (rtype, s) in stats {
+                let _ = writeln!(
+                    &mut output,
+                    "{:<10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                    rtype.to_string(),
+                    s.entries,
+                    s.hits,
+                    s.misses,
+                    s.expired,
+                    s.pruned
+                );
+            }
+            Ok(output)
+        }
+        Command::Top(count) => {
+            let top = state.top_queries.get(*count);
+            let mut output = String::new();
+            let _ = writeln!(
+                &mut output,
+                "{:<40} {:>10} {:>10}",
+                "name", "queries", "error"
+            );
+            for entry in top.names {
+                let _ = writeln!(
+                    &mut output,
+                    "{:<40} {:>10} {:>10}",
+                    entry.value, entry.count, entry.error
+                );
+            }
+            let _ = writeln!(
+                &mut output,
+                "\n{:<40} {:>10} {:>10}",
+                "client", "queries", "error"
+            );
+            for entry in top.clients {
+                let _ = writeln!(
+                    &mut output,
+                    "{:<40} {:>10} {:>10}",
+                    entry.value.to_string(),
+                    entry.count,
+                    entry.error
+                );
+            }
+            let _ = writeln!(
+                &mut output,
+                "\n{:<40} {:<40} {:>10} {:>10}",
+                "client", "name", "queries", "error"
+            );
+            for entry in top.client_names {
+                let _ = writeln!(
+                    &mut output,
+                    "{:<40} {:<40} {:>10} {:>10}",
+                    entry.value.client.to_string(),
+                    entry.value.name,
+                    entry.count,
+                    entry.error
+                );
+            }
+            Ok(output)
+        }
+        Command::GetLogFilter => Ok(format!("{}\n", state.log_filter.current())),
+        Command::SetLogFilter(directives) => {
+            state
+                .log_filter
+                .set(directives)
+                .map_err(|error| error.to_string())?;
+            tracing::info!(
+                target: AUDIT_LOG_TARGET,
+                source = "control socket",
+                filter = %directives,
+                "put log filter"
+            );
+            Ok(String::new())
+        }
+        Command::GetLogFormat => Ok(format!("{}\n", state.log_filter.current_format())),
+        Command::SetLogFormat(format) => {
+            state
+                .log_filter
+                .set_format(*format)
+                .map_err(|error| error.to_string())?;
+            tracing::info!(
+                target: AUDIT_LOG_TARGET,
+                source = "control socket",
+                %format,
+                "put log format"
+            );
+            Ok(String::new())
+        }
+        // handled by `handle_connection`, as it streams its output
+        Command::Tail(_) => Err("tail must be the only command on a connection".to_string()),
+    }
+}
@@ -0,0 +1,393 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use std::hash::Hasher;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use siphasher::sip::SipHasher24;
+
+use dns_types::protocol::types::*;
+
+/// The version of the server cookie format (see section 4 of RFC 9018).
+const VERSION: u8 = 1;
+
+/// How long a server cookie is valid for after it was generated.
+const MAX_AGE: u32 = 60 * 60;
+
+/// How far in the future a server cookie's timestamp can be, to allow for
+/// clock skew between servers sharing a secret.
+const MAX_SKEW: u32 = 5 * 60;
+
+/// What a query's cookie option says about the client.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CookieStatus {
+    /// There is no cookie option.
+    Missing,
+
+    /// There is a cookie option, but it is not valid.  The query should be
+    /// answered with a FORMERR.
+    Malformed,
+
+    /// There is only a client cookie, so this is probably the first query the
+    /// client has sent to this server.
+    ClientOnly,
+
+    /// There is a server cookie, but it was not generated by this server for
+    /// this client, or it has expired.
+    Invalid,
+
+    /// There is a server cookie, and it was generated by this server for this
+    /// client: so the query really did come from the client's address.
+    Valid,
+}
+
+impl CookieStatus {
+    /// The name of the status, used as a metric label.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CookieStatus::Missing => "missing",
+            CookieStatus::Malformed => "malformed",
+            CookieStatus::ClientOnly => "client-only",
+            CookieStatus::Invalid => "invalid",
+            CookieStatus::Valid => "valid",
+        }
+    }
+}
+
+/// Generates and checks DNS server cookies (see RFC 7873), using the
+/// interoperable SipHash-2-4 format from RFC 9018.
+///
+/// A server cookie is a hash of the client cookie, the client's address, and
+/// a timestamp, keyed by a secret.  A client which sends back a valid server
+/// cookie must have received an earlier response at its address, which an
+/// off-path attacker spoofing that address cannot do.
+///
+/// The secret is picked at random when `resolved` starts, so server cookies
+/// do not survive a restart: clients with an old cookie get a new one in the
+/// next response.
+#[derive(Debug, Clone)]
+pub struct ServerCookies {
+    secret: [u8; 16],
+}
+
+impl ServerCookies {
+    /// Create a new generator with a random secret.
+    pub fn new() -> Self {
+        Self::with_secret(rand::random())
+    }
+
+    fn with_secret(secret: [u8; 16]) -> Self {
+        Self { secret }
+    }
+
+    /// Make a cookie option to include in the response to a query from
+    /// `peer`, with a fresh server cookie.  Returns `None` if the query did
+    /// not have a valid client cookie.
+    pub fn response_option(&self, query: &Message, peer: IpAddr) -> Option<EdnsOption> {
+        let cookie = query.cookie()?;
+        Some(EdnsOption::cookie(&Cookie {
+            server: Some(self.server_cookie(&cookie.client, peer, now())),
+            ..cookie
+        }))
+    }
+
+    /// Check the cookie option in a query from `peer`.
+    pub fn check(&self, query: &Message, peer: IpAddr) -> CookieStatus {
+        self.check_at(query, peer, now())
+    }
+
+    fn check_at(&self, query: &Message, peer: IpAddr, now: u32) -> CookieStatus {
+        let Some(options) = query.edns_opt().and_then(ResourceRecord::edns_options) else {
+            return CookieStatus::Missing;
+        };
+        let Some(option) = options
+            .iter()
+            .find(|option| option.code == EDNS_OPTION_COOKIE)
+        else {
+            return CookieStatus::Missing;
+        };
+        let Some(cookie) = Cookie::from_option(option) else {
+            return CookieStatus::Malformed;
+        };
+        let Some(server) = cookie.server else {
+            return CookieStatus::ClientOnly;
+        };
+
+        if server.len() != 16 || server[0] != VERSION {
+            return CookieStatus::Invalid;
+        }
+
+        // timestamps are compared with serial number arithmetic (see section
+        // 4.3 of RFC 9018), so that they keep working after 2106
+        let timestamp = u32::from_be_bytes([server[4], server[5], server[6], server[7]]);
+        if now.wrapping_sub(timestamp) > MAX_AGE && timestamp.wrapping_sub(now) > MAX_SKEW {
+            return CookieStatus::Invalid;
+        }
+
+        if self.server_cookie(&cookie.client, peer, timestamp) == server {
+            CookieStatus::Valid
+        } else {
+            CookieStatus::Invalid
+        }
+    }
+
+    /// Generate a server cookie: the version, three reserved octets, the
+    /// timestamp, and then the hash of all of those plus the client cookie
+    /// and address (see section 4 of RFC 9018).
+    fn server_cookie(&self, client: &[u8; 8], peer: IpAddr, timestamp: u32) -> Bytes {
+        let mut cookie = BytesMut::with_capacity(16);
+        cookie.put_u8(VERSION);
+        cookie.put_slice(&[0; 3]);
+        cookie.put_u32(timestamp);
+
+        let mut hasher = SipHasher24::new_with_key(&self.secret);
+        hasher.write(client);
+        hasher.write(&cookie);
+        match peer {
+            IpAddr::V4(address) => hasher.write(&address.octets()),
+            IpAddr::V6(address) => hasher.write(&address.octets()),
+        }
+        // SipHash's output is little-endian, so that servers sharing a secret
+        // agree on the cookie whatever their byte order
+        cookie.put_u64_le(hasher.finish());
+
+        cookie.freeze()
+    }
+}
+
+impl Default for ServerCookies {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The current time as a 32-bit UNIX timestamp, which wraps around in 2106.
+fn now() -> u32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    // deliberately truncated: see `check`
+    #[allow(clippy::cast_possible_truncation)]
+    let secs = secs as u32;
+    secs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    const SECRET: [u8; 16] = [
+        0xe5, 0xe9, 0x73, 0xe5, 0xa6, 0xb2, 0xa4, 0x3f, 0x48, 0xe7, 0xdc, 0x84, 0x9e, 0x37, 0xbf,
+        0xcf,
+    ];
+
+    const CLIENT: [u8; 8] = [0x24, 0x64, 0xc4, 0xab, 0xcf, 0x10, 0xc9, 0x57];
+
+    #[test]
+    fn server_cookie_known_answer() {
+        // RFC 9018 appendix A.1
+        let cookies = ServerCookies::with_secret(SECRET);
+        let peer = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 100));
+
+        assert_eq!(
+            &[
+                0x01, 0x00, 0x00, 0x00, 0x5c, 0xf7, 0x9f, 0x11, 0x1f, 0x81, 0x30, 0xc3, 0xee, 0xe2,
+                0x94, 0x80
+            ][..],
+            &cookies.server_cookie(&CLIENT, peer, 1_559_731_985)[..]
+        );
+    }
+
+    #[test]
+    fn check_without_cookie_is_missing() {
+        let cookies = ServerCookies::with_secret(SECRET);
+
+        assert_eq!(
+            CookieStatus::Missing,
+            cookies.check_at(&query(&[]), peer(), 0)
+        );
+    }
+
+    #[test]
+    fn check_with_bad_option_is_malformed() {
+        let cookies = ServerCookies::with_secret(SECRET);
+        let option = EdnsOption {
+            code: EDNS_OPTION_COOKIE,
+            data: Bytes::from_static(&[1, 2, 3]),
+        };
+
+        assert_eq!(
+            CookieStatus::Malformed,
+            cookies.check_at(&query(&[option]), peer(), 0)
+        );
+    }
+
+    #[test]
+    fn check_with_client_cookie_only() {
+        let cookies = ServerCookies::with_secret(SECRET);
+
+        assert_eq!(
+            CookieStatus::ClientOnly,
+            cookies.check_at(&with_cookie(None), peer(), 0)
+        );
+    }
+
+    #[test]
+    fn check_accepts_own_cookie() {
+        let cookies = ServerCookies::with_secret(SECRET);
+        let now = 1_559_731_985;
+        let server = cookies.server_cookie(&CLIENT, peer(), now);
+
+        assert_eq!(
+            CookieStatus::Valid,
+            cookies.check_at(&with_cookie(Some(server)), peer(), now)
+        );
+    }
+
+    #[test]
+    fn check_rejects_cookie_for_other_client() {
+        let cookies = ServerCookies::with_secret(SECRET);
+        let now = 1_559_731_985;
+        let server = cookies.server_cookie(&CLIENT, peer(), now);
+
+        let other_peer = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert_eq!(
+            CookieStatus::Invalid,
+            cookies.check_at(&with_cookie(Some(server.clone())), other_peer, now)
+        );
+
+        let mut query = with_cookie(None);
+        query.additional = vec![ResourceRecord::edns_opt(
+            4096,
+            &[EdnsOption::cookie(&Cookie {
+                client: [0; 8],
+                server: Some(server),
+            })],
+        )];
+        assert_eq!(CookieStatus::Invalid, cookies.check_at(&query, peer(), now));
+    }
+
+    #[test]
+    fn check_rejects_cookie_from_other_secret() {
+        // a restart picks a new secret, which invalidates the old cookies
+        let old = ServerCookies::with_secret(SECRET);
+        let new = ServerCookies::with_secret([0; 16]);
+        let now = 1_559_731_985;
+        let server = old.server_cookie(&CLIENT, peer(), now);
+
+        assert_eq!(
+            CookieStatus::Invalid,
+            new.check_at(&with_cookie(Some(server)), peer(), now)
+        );
+    }
+
+    #[test]
+    fn check_rejects_other_versions_and_lengths() {
+        let cookies = ServerCookies::with_secret(SECRET);
+        let now = 1_559_731_985;
+        let server = cookies.server_cookie(&CLIENT, peer(), now);
+
+        let mut other_version = BytesMut::from(&server[..]);
+        other_version[0] = 2;
+        assert_eq!(
+            CookieStatus::Invalid,
+            cookies.check_at(&with_cookie(Some(other_version.freeze())), peer(), now)
+        );
+
+        assert_eq!(
+            CookieStatus::Invalid,
+            cookies.check_at(&with_cookie(Some(server.slice(..8))), peer(), now)
+        );
+    }
+
+    #[test]
+    fn check_timestamp_window() {
+        let cookies = ServerCookies::with_secret(SECRET);
+        let timestamp = 1_559_731_985;
+        let query = with_cookie(Some(cookies.server_cookie(&CLIENT, peer(), timestamp)));
+
+        for (now, status) in [
+            (timestamp, CookieStatus::Valid),
+            (timestamp + MAX_AGE, CookieStatus::Valid),
+            (timestamp + MAX_AGE + 1, CookieStatus::Invalid),
+            (timestamp - MAX_SKEW, CookieStatus::Valid),
+            (timestamp - MAX_SKEW - 1, CookieStatus::Invalid),
+        ] {
+            assert_eq!(status, cookies.check_at(&query, peer(), now), "{now}");
+        }
+    }
+
+    #[test]
+    fn check_timestamp_window_wraps() {
+        let cookies = ServerCookies::with_secret(SECRET);
+
+        // generated just before the timestamp wraps, checked just after
+        let timestamp = u32::MAX - 10;
+        let query = with_cookie(Some(cookies.server_cookie(&CLIENT, peer(), timestamp)));
+        assert_eq!(CookieStatus::Valid, cookies.check_at(&query, peer(), 10));
+        assert_eq!(
+            CookieStatus::Invalid,
+            cookies.check_at(&query, peer(), MAX_AGE)
+        );
+
+        // generated by a server with a clock just after the wrap, checked just
+        // before
+        let timestamp = 10;
+        let query = with_cookie(Some(cookies.server_cookie(&CLIENT, peer(), timestamp)));
+        assert_eq!(
+            CookieStatus::Valid,
+            cookies.check_at(&query, peer(), u32::MAX - 10)
+        );
+        assert_eq!(
+            CookieStatus::Invalid,
+            cookies.check_at(&query, peer(), u32::MAX - MAX_SKEW)
+        );
+    }
+
+    #[test]
+    fn response_option_has_fresh_server_cookie() {
+        let cookies = ServerCookies::with_secret(SECRET);
+        let option = cookies.response_option(&with_cookie(None), peer()).unwrap();
+        let cookie = Cookie::from_option(&option).unwrap();
+
+        assert_eq!(CLIENT, cookie.client);
+        assert_eq!(
+            CookieStatus::Valid,
+            cookies.check(&query(&[option]), peer())
+        );
+    }
+
+    #[test]
+    fn response_option_needs_client_cookie() {
+        let cookies = ServerCookies::with_secret(SECRET);
+
+        assert_eq!(None, cookies.response_option(&query(&[]), peer()));
+    }
+
+    fn peer() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(198, 51, 100, 100))
+    }
+
+    fn with_cookie(server: Option<Bytes>) -> Message {
+        query(&[EdnsOption::cookie(&Cookie {
+            client: CLIENT,
+            server,
+        })])
+    }
+
+    fn query(options: &[EdnsOption]) -> Message {
+        let mut query = Message::from_question(
+            0,
+            Question {
+                name: DomainName::root_domain(),
+                qtype: QueryType::Record(RecordType::NS),
+                qclass: QueryClass::Record(RecordClass::IN),
+            },
+        );
+        query
+            .additional
+            .push(ResourceRecord::edns_opt(4096, options));
+        query
+    }
+}
@@ -1,88 +1,905 @@
-use std::io;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{File, Metadata};
+use std::io::{self, BufReader};
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use tokio::fs::{read_dir, read_to_string};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use tokio::fs::{metadata, read_dir, read_to_string};
+use tokio::sync::Semaphore;
+use tokio::task::{spawn_blocking, JoinSet};
 
+use dns_resolver::root_hints::RootHints;
+use dns_resolver::util::types::Allowlist;
 use dns_types::hosts::types::Hosts;
+use dns_types::protocol::types::{
+    DomainName, QueryClass, QueryType, Question, RecordClass, RecordType,
+};
 use dns_types::zones::types::{Zone, Zones};
 
+use crate::config::Config;
+use crate::metrics::ZONE_FILE_LOAD_DURATION_SECONDS;
+
 /// Load the hosts and zones from the configuration, generating the
 /// `Zones` parameter for the resolver.
+///
+/// If `synthesise_ptr` is true, `PTR` records are added for the `A` and
+/// `AAAA` records.  See `Zones::synthesise_reverse_records`.
+///
+/// If `compact_hosts` is true, the records from the hosts files are stored in
+/// a compact form.  See `Hosts::into_compact_zone`.
+///
+/// The records from the hosts files have a TTL of `hosts_ttl`.
+///
+/// The zones with an apex in `flatten_cnames` flatten CNAME chains.  See
+/// `Zone::set_flatten_cnames`.
+///
+/// The zone files in `auto_zone_dirs` are loaded as authoritative zones with
+/// the apex given by their file name.  See `ZoneFiles::load`.
+///
+/// If `skip_bad_files` is true, files which cannot be read or parsed are
+/// skipped with a warning, rather than being an error.
+///
+/// # Errors
+///
+/// Every file which cannot be read or parsed, other than the zone files in
+/// `auto_zone_dirs`, unless `skip_bad_files` is true.
+#[allow(clippy::too_many_arguments)]
 pub async fn load_zone_configuration(
     hosts_files: &[PathBuf],
     hosts_dirs: &[PathBuf],
     zone_files: &[PathBuf],
     zone_dirs: &[PathBuf],
-) -> Option<Zones> {
-    let mut is_error = false;
+    auto_zone_dirs: &[PathBuf],
+    synthesise_ptr: bool,
+    compact_hosts: bool,
+    hosts_ttl: u32,
+    flatten_cnames: &[DomainName],
+    skip_bad_files: bool,
+) -> Result<Zones, Vec<LoadError>> {
+    let (_, zones) = ZoneFiles::load(
+        hosts_files,
+        hosts_dirs,
+        zone_files,
+        zone_dirs,
+        auto_zone_dirs,
+        synthesise_ptr,
+        compact_hosts,
+        hosts_ttl,
+        flatten_cnames,
+        skip_bad_files,
+    )
+    .await?;
+    Ok(zones)
+}
+
+/// An error reading or parsing a hosts or zone file, or listing a directory of
+/// them.
+#[derive(Debug)]
+pub enum LoadError {
+    ReadDir {
+        path: PathBuf,
+        error: io::Error,
+    },
+    ReadMetadata {
+        path: PathBuf,
+        error: io::Error,
+    },
+    ReadHosts {
+        path: PathBuf,
+        error: io::Error,
+    },
+    ParseHosts {
+        path: PathBuf,
+        error: dns_types::hosts::deserialise::Error,
+    },
+    ReadZone {
+        path: PathBuf,
+        error: io::Error,
+    },
+    ParseZone {
+        path: PathBuf,
+        error: dns_types::zones::deserialise::Error,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::ReadDir { path, error } => {
+                write!(f, "could not read directory {}: {error}", path.display())
+            }
+            LoadError::ReadMetadata { path, error } => {
+                write!(f, "could not read metadata of {}: {error}", path.display())
+            }
+            LoadError::ReadHosts { path, error } => {
+                write!(f, "could not read hosts file {}: {error}", path.display())
+            }
+            LoadError::ParseHosts { path, error } => {
+                write!(f, "could not parse hosts file {}: {error}", path.display())
+            }
+            LoadError::ReadZone { path, error } => {
+                write!(f, "could not read zone file {}: {error}", path.display())
+            }
+            LoadError::ParseZone { path, error } => {
+                write!(f, "could not parse zone file {}: {error}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// The errors found while loading files.  If bad files are being skipped, they
+/// are logged and counted instead.
+struct Errors {
+    skip_bad_files: bool,
+    errors: Vec<LoadError>,
+    skipped: usize,
+}
+
+impl Errors {
+    fn new(skip_bad_files: bool) -> Self {
+        Self {
+            skip_bad_files,
+            errors: Vec::new(),
+            skipped: 0,
+        }
+    }
+
+    fn push(&mut self, error: LoadError) {
+        if self.skip_bad_files {
+            tracing::warn!(%error, "skipping bad file");
+            self.skipped += 1;
+        } else {
+            self.errors.push(error);
+        }
+    }
+
+    /// Return the errors which weren't skipped, if there are any.
+    fn check(&mut self) -> Result<(), Vec<LoadError>> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+}
+
+/// The hosts and zone files which the zones being served were loaded from, so
+/// that a reload only has to re-read the files which have changed.
+///
+/// A file has changed if its size or modification time has.  Since every hosts
+/// file, and every zone file for the same apex, is merged into one zone, a
+/// changed file means re-reading all the files for its zone (and for the zone
+/// it used to be for, if its apex has changed): but the files for other zones
+/// are left alone, and those zones are not rebuilt.  A zone file has also
+/// changed if any file it includes with `$INCLUDE` has.
+#[derive(Debug, Clone, Default)]
+pub struct ZoneFiles {
+    files: HashMap<PathBuf, FileState>,
+    synthesise_ptr: bool,
+    compact_hosts: bool,
+    hosts_ttl: u32,
+    flatten_cnames: Vec<DomainName>,
+    /// The number of files and directories which couldn't be loaded, and
+    /// were skipped.
+    skipped: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileState {
+    kind: FileKind,
+    fingerprint: Fingerprint,
+    /// The apex of the zone the file's records went into.  This is always the
+    /// root domain for hosts files.
+    apex: DomainName,
+    /// The files included by a zone file, and their fingerprints when they
+    /// were read.
+    includes: Vec<(PathBuf, Fingerprint)>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum FileKind {
+    Hosts,
+    Zone,
+    /// A zone file named after its apex, like `example.com.zone`, which must
+    /// be an authoritative zone for that apex.
+    AutoZone,
+}
+
+impl FileKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            FileKind::Hosts => "hosts",
+            FileKind::Zone => "zone",
+            FileKind::AutoZone => "auto_zone",
+        }
+    }
+}
+
+/// Used to tell whether a file has changed without reading it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Fingerprint {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl From<&Metadata> for Fingerprint {
+    fn from(meta: &Metadata) -> Self {
+        Self {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        }
+    }
+}
+
+/// A parsed hosts or zone file.  Zone files also have the files they include,
+/// with their fingerprints.
+#[derive(Debug)]
+enum Parsed {
+    Hosts(Hosts),
+    Zone(Box<Zone>, Vec<(PathBuf, Fingerprint)>),
+}
+
+/// How the zones being served need to change after a reload.
+#[derive(Debug)]
+pub enum ZonesUpdate {
+    /// No files have changed.
+    Unchanged,
+
+    /// Replace all of the zones.
+    Replace(Zones),
+
+    /// Replace some of the zones, keyed by apex, removing those which are
+    /// `None`.
+    Partial(Vec<(DomainName, Option<Zone>)>),
+}
+
+impl ZoneFiles {
+    /// Read every hosts and zone file.  Returns every error if any of them
+    /// cannot be read or parsed, other than the zone files in
+    /// `auto_zone_dirs`.
+    ///
+    /// If `skip_bad_files` is true, a file or directory which cannot be read
+    /// or parsed is logged and skipped instead, so that one bad file (such as
+    /// a corrupt blocklist) doesn't stop everything else from being served.
+    ///
+    /// Each file in `auto_zone_dirs` named `<apex>.zone` is loaded as an
+    /// authoritative zone for that apex, with the apex as its initial
+    /// `$ORIGIN`: it must have a `SOA` record for the apex.  If one of these
+    /// files cannot be read or parsed, or has the wrong apex, the error is
+    /// logged and the file is skipped, so one bad zone doesn't stop the
+    /// others from being served.  Other files in the directories are ignored.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn load(
+        hosts_files: &[PathBuf],
+        hosts_dirs: &[PathBuf],
+        zone_files: &[PathBuf],
+        zone_dirs: &[PathBuf],
+        auto_zone_dirs: &[PathBuf],
+        synthesise_ptr: bool,
+        compact_hosts: bool,
+        hosts_ttl: u32,
+        flatten_cnames: &[DomainName],
+        skip_bad_files: bool,
+    ) -> Result<(Self, Zones), Vec<LoadError>> {
+        let mut errors = Errors::new(skip_bad_files);
+        let paths = list_files(
+            hosts_files,
+            hosts_dirs,
+            zone_files,
+            zone_dirs,
+            auto_zone_dirs,
+            &mut errors,
+        )
+        .await;
+        let (paths, fingerprints): (Vec<_>, Vec<_>) = fingerprint_files(paths, &mut errors)
+            .await
+            .into_iter()
+            .unzip();
+        let parsed = parse_files(&paths, &mut errors).await;
+        errors.check()?;
+
+        let files = file_states(&paths, fingerprints, &parsed);
+        let mut zones = merge_parsed(&paths, parsed, compact_hosts, hosts_ttl, flatten_cnames);
+        if synthesise_ptr {
+            let count = zones.synthesise_reverse_records();
+            tracing::info!(%count, "synthesised PTR records");
+        }
+
+        Ok((
+            Self {
+                files,
+                synthesise_ptr,
+                compact_hosts,
+                hosts_ttl,
+                flatten_cnames: flatten_cnames.to_vec(),
+                skipped: errors.skipped,
+            },
+            zones,
+        ))
+    }
+
+    /// The number of files and directories which couldn't be loaded, and were
+    /// skipped.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Re-read the hosts and zone files which have changed, and any others
+    /// needed to rebuild their zones.  Returns the new state and the changes
+    /// to make to the zones being served, or every error if any file cannot be
+    /// read or parsed.
+    ///
+    /// If `PTR` records are being synthesised, any change rebuilds every zone,
+    /// since a new address in one zone can add a record to any other.  So does
+    /// turning `compact_hosts` on or off, or changing `hosts_ttl` or
+    /// `flatten_cnames`.
+    ///
+    /// A file in `auto_zone_dirs` which can no longer be parsed is skipped, as
+    /// in `load`, and so its zone stops being served until it is fixed.  So is
+    /// any other file, if `skip_bad_files` is true.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn reload(
+        &self,
+        hosts_files: &[PathBuf],
+        hosts_dirs: &[PathBuf],
+        zone_files: &[PathBuf],
+        zone_dirs: &[PathBuf],
+        auto_zone_dirs: &[PathBuf],
+        synthesise_ptr: bool,
+        compact_hosts: bool,
+        hosts_ttl: u32,
+        flatten_cnames: &[DomainName],
+        skip_bad_files: bool,
+    ) -> Result<(Self, ZonesUpdate), Vec<LoadError>> {
+        let mut errors = Errors::new(skip_bad_files);
+        let paths = list_files(
+            hosts_files,
+            hosts_dirs,
+            zone_files,
+            zone_dirs,
+            auto_zone_dirs,
+            &mut errors,
+        )
+        .await;
+        let (paths, fingerprints): (Vec<_>, Vec<_>) = fingerprint_files(paths, &mut errors)
+            .await
+            .into_iter()
+            .unzip();
+        errors.check()?;
+
+        let mut dirty = Vec::new();
+        let mut affected = HashSet::new();
+        for ((path, kind), fingerprint) in paths.iter().zip(&fingerprints) {
+            if let Some(state) = self.files.get(path) {
+                if state.kind == *kind
+                    && state.fingerprint == *fingerprint
+                    && !includes_changed(&state.includes).await
+                {
+                    continue;
+                }
+                affected.insert(state.apex.clone());
+            }
+            dirty.push((path.clone(), *kind));
+        }
+        let current: HashSet<&PathBuf> = paths.iter().map(|(path, _)| path).collect();
+        for (path, state) in &self.files {
+            if !current.contains(path) {
+                affected.insert(state.apex.clone());
+            }
+        }
+
+        let same_options = synthesise_ptr == self.synthesise_ptr
+            && compact_hosts == self.compact_hosts
+            && hosts_ttl == self.hosts_ttl
+            && flatten_cnames == self.flatten_cnames;
+        if dirty.is_empty() && affected.is_empty() && same_options {
+            let new = Self {
+                skipped: errors.skipped,
+                ..self.clone()
+            };
+            return Ok((new, ZonesUpdate::Unchanged));
+        }
+
+        if synthesise_ptr || !same_options {
+            let (new, zones) = Self::load(
+                hosts_files,
+                hosts_dirs,
+                zone_files,
+                zone_dirs,
+                auto_zone_dirs,
+                synthesise_ptr,
+                compact_hosts,
+                hosts_ttl,
+                flatten_cnames,
+                skip_bad_files,
+            )
+            .await?;
+            return Ok((new, ZonesUpdate::Replace(zones)));
+        }
+
+        let mut parsed = parse_files(&dirty, &mut errors).await;
+        for (path, _) in &dirty {
+            if let Some(parsed) = parsed.get(path) {
+                affected.insert(parsed.apex());
+            }
+        }
+
+        let clean: Vec<(PathBuf, FileKind)> = paths
+            .iter()
+            .filter(|(path, _)| {
+                !parsed.contains_key(path)
+                    && !dirty.iter().any(|(dirty_path, _)| dirty_path == path)
+                    && self
+                        .files
+                        .get(path)
+                        .is_some_and(|state| affected.contains(&state.apex))
+            })
+            .cloned()
+            .collect();
+        parsed.extend(parse_files(&clean, &mut errors).await);
+        errors.check()?;
+
+        // clean files in affected zones may have changed since they were last
+        // looked at, but if so they will be considered dirty next time.  files
+        // which weren't parsed (auto zone files, or any file if bad files are
+        // being skipped) are forgotten, so they will be tried again next time.
+        let mut files = file_states(&paths, fingerprints, &parsed);
+        for (path, state) in &self.files {
+            if current.contains(path)
+                && !files.contains_key(path)
+                && !dirty.iter().any(|(dirty_path, _)| dirty_path == path)
+                && !clean.iter().any(|(clean_path, _)| clean_path == path)
+            {
+                files.insert(path.clone(), state.clone());
+            }
+        }
+
+        // every parsed file is for an affected zone
+        let mut zones = merge_parsed(&paths, parsed, compact_hosts, hosts_ttl, flatten_cnames);
+        let changes = affected
+            .into_iter()
+            .map(|apex| {
+                let zone = zones.remove(&apex);
+                (apex, zone)
+            })
+            .collect();
+
+        Ok((
+            Self {
+                files,
+                synthesise_ptr,
+                compact_hosts,
+                hosts_ttl,
+                flatten_cnames: flatten_cnames.to_vec(),
+                skipped: errors.skipped,
+            },
+            ZonesUpdate::Partial(changes),
+        ))
+    }
+}
+
+impl Parsed {
+    fn apex(&self) -> DomainName {
+        match self {
+            Parsed::Hosts(_) => DomainName::root_domain(),
+            Parsed::Zone(zone, _) => zone.get_apex().clone(),
+        }
+    }
+
+    /// The number of records (or, for hosts files, names) which were loaded.
+    fn record_count(&self) -> usize {
+        match self {
+            Parsed::Hosts(hosts) => hosts.v4.len() + hosts.v6.len() + hosts.blocked_subtrees.len(),
+            Parsed::Zone(zone, _) => zone.record_count() + zone.wildcard_record_count(),
+        }
+    }
+}
+
+/// Get all the hosts and zone files, in the order they are merged: zone files
+/// first, then auto zone files, then hosts files.
+async fn list_files(
+    hosts_files: &[PathBuf],
+    hosts_dirs: &[PathBuf],
+    zone_files: &[PathBuf],
+    zone_dirs: &[PathBuf],
+    auto_zone_dirs: &[PathBuf],
+    errors: &mut Errors,
+) -> Vec<(PathBuf, FileKind)> {
     let mut hosts_file_paths = Vec::from(hosts_files);
     let mut zone_file_paths = Vec::from(zone_files);
+    let mut auto_zone_file_paths = Vec::new();
 
     for path in zone_dirs {
         match get_files_from_dir(path).await {
             Ok(mut paths) => zone_file_paths.append(&mut paths),
-            Err(error) => {
-                tracing::warn!(?path, ?error, "could not read zone directory");
-                is_error = true;
-            }
+            Err(error) => errors.push(LoadError::ReadDir {
+                path: path.clone(),
+                error,
+            }),
+        }
+    }
+    for path in auto_zone_dirs {
+        match get_files_from_dir(path).await {
+            Ok(paths) => auto_zone_file_paths.extend(
+                paths
+                    .into_iter()
+                    .filter(|path| auto_zone_apex(path).is_some()),
+            ),
+            Err(error) => errors.push(LoadError::ReadDir {
+                path: path.clone(),
+                error,
+            }),
         }
     }
     for path in hosts_dirs {
         match get_files_from_dir(path).await {
             Ok(mut paths) => hosts_file_paths.append(&mut paths),
-            Err(error) => {
-                tracing::warn!(?path, ?error, "could not read hosts directory");
-                is_error = true;
-            }
+            Err(error) => errors.push(LoadError::ReadDir {
+                path: path.clone(),
+                error,
+            }),
         }
     }
 
-    let mut combined_zones = Zones::new();
-    for path in &zone_file_paths {
-        match zone_from_file(Path::new(path)).await {
-            Ok(Ok(zone)) => combined_zones.insert_merge(zone),
-            Ok(Err(error)) => {
-                tracing::warn!(?path, ?error, "could not parse zone file");
-                is_error = true;
+    let zone_file_paths = zone_file_paths
+        .into_iter()
+        .map(|path| (path, FileKind::Zone));
+    let auto_zone_file_paths = auto_zone_file_paths
+        .into_iter()
+        .map(|path| (path, FileKind::AutoZone));
+    let hosts_file_paths = hosts_file_paths
+        .into_iter()
+        .map(|path| (path, FileKind::Hosts));
+    zone_file_paths
+        .chain(auto_zone_file_paths)
+        .chain(hosts_file_paths)
+        .collect()
+}
+
+/// Get the fingerprint of every file, in the same order.  Files whose metadata
+/// cannot be read are left out.
+async fn fingerprint_files(
+    paths: Vec<(PathBuf, FileKind)>,
+    errors: &mut Errors,
+) -> Vec<((PathBuf, FileKind), Fingerprint)> {
+    let mut fingerprints = Vec::with_capacity(paths.len());
+    for (path, kind) in paths {
+        match metadata(&path).await {
+            Ok(meta) => fingerprints.push(((path, kind), Fingerprint::from(&meta))),
+            Err(error) => errors.push(LoadError::ReadMetadata { path, error }),
+        }
+    }
+    fingerprints
+}
+
+/// Check if any of the files included by a zone file have changed since they
+/// were read, or can no longer be read.
+async fn includes_changed(includes: &[(PathBuf, Fingerprint)]) -> bool {
+    for (path, fingerprint) in includes {
+        match metadata(path).await {
+            Ok(meta) if Fingerprint::from(&meta) == *fingerprint => (),
+            _ => return true,
+        }
+    }
+    false
+}
+
+/// Read and parse some files.  Every file is tried, so that all of the errors
+/// are found at once, and those which cannot be read or parsed are left out.
+///
+/// Up to `parse_concurrency()` files are read and parsed at once, and progress
+/// is logged as each one finishes.  The errors are still recorded in the order
+/// of `paths`.
+async fn parse_files(
+    paths: &[(PathBuf, FileKind)],
+    errors: &mut Errors,
+) -> HashMap<PathBuf, Parsed> {
+    let total = paths.len();
+    let start = Instant::now();
+    let semaphore = Arc::new(Semaphore::new(parse_concurrency()));
+    let mut set = JoinSet::new();
+    for (i, (path, kind)) in paths.iter().cloned().enumerate() {
+        let semaphore = semaphore.clone();
+        set.spawn(async move {
+            // the semaphore is never closed
+            let _permit = semaphore.acquire_owned().await;
+            let start = Instant::now();
+            let result = parse_file(&path, kind).await;
+            ZONE_FILE_LOAD_DURATION_SECONDS
+                .with_label_values(&[kind.as_str()])
+                .observe(start.elapsed().as_secs_f64());
+            (i, result)
+        });
+    }
+
+    let mut results: Vec<Option<Result<Option<Parsed>, LoadError>>> =
+        (0..total).map(|_| None).collect();
+    let mut done = 0;
+    let mut records = 0;
+    while let Some(joined) = set.join_next().await {
+        let (i, result) =
+            joined.unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic()));
+        done += 1;
+        if let Ok(Some(parsed)) = &result {
+            records += parsed.record_count();
+            tracing::info!(
+                path = ?paths[i].0,
+                files_done = %done,
+                files_total = %total,
+                records_loaded = %records,
+                "loaded file"
+            );
+        }
+        results[i] = Some(result);
+    }
+
+    let mut parsed = HashMap::with_capacity(total);
+    for ((path, _), result) in paths.iter().zip(results) {
+        match result {
+            Some(Ok(Some(p))) => {
+                parsed.insert(path.clone(), p);
             }
-            Err(error) => {
-                tracing::warn!(?path, ?error, "could not read zone file");
-                is_error = true;
+            Some(Ok(None)) => {
+                // already logged, and always skipped
+                errors.skipped += 1;
             }
+            Some(Err(error)) => errors.push(error),
+            None => (),
         }
     }
 
+    if total > 0 {
+        tracing::info!(
+            files = %parsed.len(),
+            records = %records,
+            duration_seconds = %start.elapsed().as_secs_f64(),
+            "loaded files"
+        );
+    }
+
+    parsed
+}
+
+/// How many files to read and parse at once: one per CPU.
+fn parse_concurrency() -> usize {
+    std::thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// Read and parse one file.  Returns `None` if it is an auto zone file which
+/// cannot be used: these are logged, rather than returned as errors, since they
+/// are always skipped.
+async fn parse_file(path: &Path, kind: FileKind) -> Result<Option<Parsed>, LoadError> {
+    match kind {
+        FileKind::Hosts => match hosts_from_file(path).await {
+            Ok(Ok(hosts)) => Ok(Some(Parsed::Hosts(hosts))),
+            Ok(Err(error)) => Err(LoadError::ParseHosts {
+                path: path.to_path_buf(),
+                error,
+            }),
+            Err(error) => Err(LoadError::ReadHosts {
+                path: path.to_path_buf(),
+                error,
+            }),
+        },
+        FileKind::Zone => match zone_from_file(path, None).await {
+            Ok(Ok((zone, includes))) => Ok(Some(Parsed::Zone(Box::new(zone), includes))),
+            Ok(Err(error)) => Err(LoadError::ParseZone {
+                path: path.to_path_buf(),
+                error,
+            }),
+            Err(error) => Err(LoadError::ReadZone {
+                path: path.to_path_buf(),
+                error,
+            }),
+        },
+        FileKind::AutoZone => Ok(auto_zone_from_file(path)
+            .await
+            .map(|(zone, includes)| Parsed::Zone(Box::new(zone), includes))),
+    }
+}
+
+/// Merge parsed files into zones, in the order of `paths`.  The hosts files
+/// are combined and go into the root zone, which is always present, with a TTL
+/// of `hosts_ttl`.  The zones with an apex in `flatten_cnames` flatten CNAME
+/// chains.
+fn merge_parsed(
+    paths: &[(PathBuf, FileKind)],
+    mut parsed: HashMap<PathBuf, Parsed>,
+    compact_hosts: bool,
+    hosts_ttl: u32,
+    flatten_cnames: &[DomainName],
+) -> Zones {
+    let mut combined_zones = Zones::new();
     let mut combined_hosts = Hosts::default();
-    for path in &hosts_file_paths {
-        match hosts_from_file(Path::new(path)).await {
-            Ok(Ok(hosts)) => combined_hosts.merge(hosts),
-            Ok(Err(error)) => {
-                tracing::warn!(?path, ?error, "could not parse hosts file");
-                is_error = true;
+    for (path, _) in paths {
+        match parsed.remove(path) {
+            Some(Parsed::Zone(zone, _)) => combined_zones.insert_merge(*zone),
+            Some(Parsed::Hosts(hosts)) => combined_hosts.merge(hosts),
+            None => (),
+        }
+    }
+    combined_hosts.ttl = hosts_ttl;
+    if compact_hosts {
+        combined_zones.insert_merge(combined_hosts.into_compact_zone());
+    } else {
+        combined_zones.insert_merge(combined_hosts.into());
+    }
+    for apex in flatten_cnames {
+        if let Some(mut zone) = combined_zones.remove(apex) {
+            zone.set_flatten_cnames(true);
+            combined_zones.insert(zone);
+        }
+    }
+    combined_zones
+}
+
+/// Record the state of the files which have been parsed.
+fn file_states(
+    paths: &[(PathBuf, FileKind)],
+    fingerprints: Vec<Fingerprint>,
+    parsed: &HashMap<PathBuf, Parsed>,
+) -> HashMap<PathBuf, FileState> {
+    paths
+        .iter()
+        .zip(fingerprints)
+        .filter_map(|((path, kind), fingerprint)| {
+            parsed.get(path).map(|parsed| {
+                (
+                    path.clone(),
+                    FileState {
+                        kind: *kind,
+                        fingerprint,
+                        apex: parsed.apex(),
+                        includes: match parsed {
+                            Parsed::Hosts(_) => Vec::new(),
+                            Parsed::Zone(_, includes) => includes.clone(),
+                        },
+                    },
+                )
+            })
+        })
+        .collect()
+}
+
+/// Load the root hints from a file, or use the built-in root hints if there is
+/// no file.
+pub async fn load_root_hints(path: Option<&Path>) -> Option<RootHints> {
+    let Some(path) = path else {
+        return Some(RootHints::default());
+    };
+
+    match read_to_string(path).await {
+        Ok(data) => match RootHints::deserialise(&data) {
+            Ok(root_hints) => Some(root_hints),
+            Err(error) => {
+                tracing::warn!(?path, %error, "could not parse root hints file");
+                None
             }
+        },
+        Err(error) => {
+            tracing::warn!(?path, ?error, "could not read root hints file");
+            None
+        }
+    }
+}
+
+/// Build the allowlist from some domains and the domains in some files.  Each
+/// file has one domain per line: blank lines, and lines starting with `#`, are
+/// ignored.
+///
+/// Returns `None` if any file cannot be read or has an invalid domain.
+pub async fn load_allowlist(domains: &[DomainName], paths: &[PathBuf]) -> Option<Allowlist> {
+    let mut allowlist = Allowlist::new();
+    for domain in domains {
+        allowlist.insert(domain.clone());
+    }
+
+    for path in paths {
+        let data = match read_to_string(path).await {
+            Ok(data) => data,
             Err(error) => {
-                tracing::warn!(?path, ?error, "could not read hosts file");
-                is_error = true;
+                tracing::warn!(?path, ?error, "could not read allowlist file");
+                return None;
+            }
+        };
+
+        for (i, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match DomainName::from_relative_dotted_string(&DomainName::root_domain(), line) {
+                Some(domain) => allowlist.insert(domain),
+                None => {
+                    tracing::warn!(?path, line = i + 1, "could not parse allowlist file");
+                    return None;
+                }
             }
         }
     }
 
-    if is_error {
-        None
-    } else {
-        combined_zones.insert_merge(combined_hosts.into());
-        Some(combined_zones)
+    Some(allowlist)
+}
+
+/// Read the questions to prime the cache with.  The file has one question per
+/// line, in `domain [qtype]` form, where the type defaults to `A`: blank lines,
+/// and lines starting with `#`, are ignored.
+///
+/// Returns `None` if the file cannot be read or has an invalid line.
+pub async fn load_prime_file(path: &Path) -> Option<Vec<Question>> {
+    let data = match read_to_string(path).await {
+        Ok(data) => data,
+        Err(error) => {
+            tracing::warn!(?path, ?error, "could not read prime file");
+            return None;
+        }
+    };
+
+    let mut questions = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match parse_prime_question(line) {
+            Some(question) => questions.push(question),
+            None => {
+                tracing::warn!(?path, line = i + 1, "could not parse prime file");
+                return None;
+            }
+        }
+    }
+
+    Some(questions)
+}
+
+/// Parse a line of a prime file, in `domain [qtype]` form.
+fn parse_prime_question(line: &str) -> Option<Question> {
+    let mut words = line.split_whitespace();
+    let name = DomainName::from_relative_dotted_string(&DomainName::root_domain(), words.next()?)?;
+    let qtype = match words.next() {
+        Some(qtype) => qtype.parse().ok()?,
+        None => QueryType::Record(RecordType::A),
+    };
+    if words.next().is_some() {
+        return None;
     }
+
+    Some(Question {
+        name,
+        qtype,
+        qclass: QueryClass::Record(RecordClass::IN),
+    })
 }
 
-/// Read a hosts file, for example /etc/hosts.
+/// Read a configuration file.
+pub async fn config_from_file<P: AsRef<Path>>(
+    path: P,
+) -> io::Result<Result<Config, toml::de::Error>> {
+    let data = read_to_string(path).await?;
+    Ok(Config::deserialise(&data))
+}
+
+/// Read a hosts file, for example /etc/hosts.  The file is parsed as it is
+/// read, rather than read into memory first, as blocklists can be large.
 async fn hosts_from_file<P: AsRef<Path>>(
     path: P,
 ) -> io::Result<Result<Hosts, dns_types::hosts::deserialise::Error>> {
-    let data = read_to_string(path).await?;
-    Ok(Hosts::deserialise(&data))
+    let path = path.as_ref().to_path_buf();
+    spawn_blocking(move || Hosts::deserialise_reader(BufReader::new(File::open(path)?)))
+        .await
+        .map_err(io::Error::other)?
 }
 
-/// Read a zone file.
+/// Read a zone file, and any files it includes with `$INCLUDE`, which are
+/// fingerprinted as they are read.  Relative names before the first `$ORIGIN`
+/// are relative to `origin`, if given.
 ///
 /// If it has a SOA record, it is an authoritative zone: it may
 /// only have *one* SOA record, and all RRs must be subdomains of
@@ -90,11 +907,65 @@ async fn hosts_from_file<P: AsRef<Path>>(
 ///
 /// If it does not have a SOA record, it is a non-authoritative
 /// zone, and the root domain will be used for its apex.
-async fn zone_from_file<P: AsRef<Path>>(
-    path: P,
-) -> io::Result<Result<Zone, dns_types::zones::deserialise::Error>> {
-    let data = read_to_string(path).await?;
-    Ok(Zone::deserialise(&data))
+async fn zone_from_file(
+    path: &Path,
+    origin: Option<DomainName>,
+) -> io::Result<Result<(Zone, Vec<(PathBuf, Fingerprint)>), dns_types::zones::deserialise::Error>> {
+    let path = path.to_path_buf();
+    spawn_blocking(move || {
+        let data = std::fs::read_to_string(&path)?;
+        let mut includes = Vec::new();
+        let zone = Zone::deserialise_with_includes(&data, &path, origin, |include_path| {
+            let fingerprint = Fingerprint::from(&std::fs::metadata(include_path)?);
+            let data = std::fs::read_to_string(include_path)?;
+            includes.push((include_path.to_path_buf(), fingerprint));
+            Ok(data)
+        });
+        Ok(zone.map(|zone| (zone, includes)))
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Read a zone file named `<apex>.zone`, which must be an authoritative zone
+/// for that apex.  Errors are logged, rather than returned, since they do not
+/// stop other files from being loaded.
+async fn auto_zone_from_file(path: &Path) -> Option<(Zone, Vec<(PathBuf, Fingerprint)>)> {
+    let apex = auto_zone_apex(path)?;
+    match zone_from_file(path, Some(apex.clone())).await {
+        Ok(Ok((zone, includes))) if zone.is_authoritative() && zone.get_apex() == &apex => {
+            Some((zone, includes))
+        }
+        Ok(Ok((zone, _))) => {
+            tracing::warn!(
+                ?path,
+                expected = %apex,
+                actual = %zone.get_apex(),
+                authoritative = %zone.is_authoritative(),
+                "zone file does not have a SOA record for its apex - skipping"
+            );
+            None
+        }
+        Ok(Err(error)) => {
+            tracing::warn!(?path, %error, "could not parse zone file - skipping");
+            None
+        }
+        Err(error) => {
+            tracing::warn!(?path, ?error, "could not read zone file - skipping");
+            None
+        }
+    }
+}
+
+/// Get the apex of a zone from its file name, which is of the form
+/// `<apex>.zone`, for example `example.com.zone`.
+fn auto_zone_apex(path: &Path) -> Option<DomainName> {
+    let stem = path
+        .file_name()?
+        .to_str()?
+        .strip_suffix(".zone")
+        .filter(|stem| !stem.is_empty())?;
+    DomainName::from_relative_dotted_string(&DomainName::root_domain(), stem)
 }
 
 /// Get files from a directory, sorted.
@@ -112,3 +983,253 @@ async fn get_files_from_dir(dir: &Path) -> io::Result<Vec<PathBuf>> {
     out.sort();
     Ok(out)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use dns_types::protocol::types::test_util::*;
+    use dns_types::protocol::types::ResourceRecord;
+    use dns_types::zones::types::ZoneResult;
+
+    use super::*;
+    use crate::test_util::TestDir;
+
+    #[tokio::test]
+    async fn reload_unchanged() {
+        let dir = TestDir::new();
+        let files = Files {
+            zone: vec![dir.write("a.zone", &zone("a.example.", "10.0.0.1"))],
+            hosts: vec![dir.write("hosts", "10.0.1.1 nas.lan\n")],
+            synthesise_ptr: false,
+        };
+        let (zone_files, _) = files.load().await;
+
+        let (_, update) = files.reload(&zone_files).await;
+
+        assert!(matches!(update, ZonesUpdate::Unchanged));
+    }
+
+    #[tokio::test]
+    async fn reload_only_changes_affected_zone() {
+        let dir = TestDir::new();
+        let files = Files {
+            zone: vec![
+                dir.write("a.zone", &zone("a.example.", "10.0.0.1")),
+                dir.write("b.zone", &zone("b.example.", "10.0.0.2")),
+            ],
+            hosts: Vec::new(),
+            synthesise_ptr: false,
+        };
+        let (zone_files, _) = files.load().await;
+
+        dir.write("a.zone", &zone("a.example.", "10.0.0.123"));
+        let changes = partial(files.reload(&zone_files).await.1);
+
+        assert_eq!(1, changes.len());
+        assert_eq!(
+            vec![a_record("www.a.example.", Ipv4Addr::new(10, 0, 0, 123))],
+            answer(
+                changes[&domain("a.example.")].as_ref().unwrap(),
+                "www.a.example."
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn reload_apex_change() {
+        let dir = TestDir::new();
+        let files = Files {
+            zone: vec![
+                dir.write("a.zone", &zone("a.example.", "10.0.0.1")),
+                dir.write("c.zone", &zone("c.example.", "10.0.0.3")),
+            ],
+            hosts: Vec::new(),
+            synthesise_ptr: false,
+        };
+        let (zone_files, _) = files.load().await;
+
+        dir.write("a.zone", &zone("bb.example.", "10.0.0.2"));
+        let changes = partial(files.reload(&zone_files).await.1);
+
+        // the old apex is removed, and the new one added
+        assert_eq!(2, changes.len());
+        assert_eq!(None, changes[&domain("a.example.")]);
+        assert_eq!(
+            vec![a_record("www.bb.example.", Ipv4Addr::new(10, 0, 0, 2))],
+            answer(
+                changes[&domain("bb.example.")].as_ref().unwrap(),
+                "www.bb.example."
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn reload_file_removal() {
+        let dir = TestDir::new();
+        let mut files = Files {
+            zone: vec![
+                dir.write("a.zone", &zone("a.example.", "10.0.0.1")),
+                dir.write("b.zone", &zone("b.example.", "10.0.0.2")),
+            ],
+            hosts: Vec::new(),
+            synthesise_ptr: false,
+        };
+        let (zone_files, _) = files.load().await;
+
+        files.zone.pop();
+        let (zone_files, update) = files.reload(&zone_files).await;
+        let changes = partial(update);
+
+        assert_eq!(1, changes.len());
+        assert_eq!(None, changes[&domain("b.example.")]);
+
+        // and it's forgotten
+        let (_, update) = files.reload(&zone_files).await;
+        assert!(matches!(update, ZonesUpdate::Unchanged));
+    }
+
+    #[tokio::test]
+    async fn reload_merges_hosts_files_into_root_zone() {
+        let dir = TestDir::new();
+        let mut files = Files {
+            zone: vec![dir.write("a.zone", &zone("a.example.", "10.0.0.1"))],
+            hosts: vec![
+                dir.write("hosts1", "10.0.1.1 nas.lan\n"),
+                dir.write("hosts2", "10.0.1.2 printer.lan\n"),
+            ],
+            synthesise_ptr: false,
+        };
+        let (zone_files, _) = files.load().await;
+
+        // changing one hosts file rebuilds the root zone from all of them
+        dir.write("hosts2", "10.0.1.22 printer.lan\n");
+        let (zone_files, update) = files.reload(&zone_files).await;
+        let changes = partial(update);
+
+        assert_eq!(1, changes.len());
+        let root = changes[&DomainName::root_domain()].as_ref().unwrap();
+        assert_eq!(
+            vec![a_record_ttl("nas.lan.", Ipv4Addr::new(10, 0, 1, 1))],
+            answer(root, "nas.lan.")
+        );
+        assert_eq!(
+            vec![a_record_ttl("printer.lan.", Ipv4Addr::new(10, 0, 1, 22))],
+            answer(root, "printer.lan.")
+        );
+
+        // and removing one rebuilds it from the rest
+        files.hosts.remove(0);
+        let changes = partial(files.reload(&zone_files).await.1);
+
+        assert_eq!(1, changes.len());
+        let root = changes[&DomainName::root_domain()].as_ref().unwrap();
+        assert!(matches!(
+            root.resolve(&domain("nas.lan."), QueryType::Record(RecordType::A)),
+            Some(ZoneResult::NameError) | None
+        ));
+        assert_eq!(
+            vec![a_record_ttl("printer.lan.", Ipv4Addr::new(10, 0, 1, 22))],
+            answer(root, "printer.lan.")
+        );
+    }
+
+    #[tokio::test]
+    async fn reload_with_changed_synthesise_ptr_replaces_everything() {
+        let dir = TestDir::new();
+        let mut files = Files {
+            zone: vec![dir.write("a.zone", &zone("a.example.", "10.0.0.1"))],
+            hosts: Vec::new(),
+            synthesise_ptr: false,
+        };
+        let (zone_files, _) = files.load().await;
+
+        files.synthesise_ptr = true;
+        let (zone_files, update) = files.reload(&zone_files).await;
+        let ZonesUpdate::Replace(zones) = update else {
+            panic!("expected replace, got {update:?}");
+        };
+        assert!(matches!(
+            zones.resolve(
+                &domain("1.0.0.10.in-addr.arpa."),
+                QueryType::Record(RecordType::PTR)
+            ),
+            Some((_, ZoneResult::Answer { .. }))
+        ));
+
+        // while synthesising, any change replaces everything
+        dir.write("a.zone", &zone("a.example.", "10.0.0.123"));
+        let (_, update) = files.reload(&zone_files).await;
+        assert!(matches!(update, ZonesUpdate::Replace(_)));
+    }
+
+    struct Files {
+        zone: Vec<PathBuf>,
+        hosts: Vec<PathBuf>,
+        synthesise_ptr: bool,
+    }
+
+    impl Files {
+        async fn load(&self) -> (ZoneFiles, Zones) {
+            ZoneFiles::load(
+                &self.hosts,
+                &[],
+                &self.zone,
+                &[],
+                &[],
+                self.synthesise_ptr,
+                false,
+                HOSTS_TTL,
+                &[],
+                false,
+            )
+            .await
+            .unwrap()
+        }
+
+        async fn reload(&self, zone_files: &ZoneFiles) -> (ZoneFiles, ZonesUpdate) {
+            zone_files
+                .reload(
+                    &self.hosts,
+                    &[],
+                    &self.zone,
+                    &[],
+                    &[],
+                    self.synthesise_ptr,
+                    false,
+                    HOSTS_TTL,
+                    &[],
+                    false,
+                )
+                .await
+                .unwrap()
+        }
+    }
+
+    const HOSTS_TTL: u32 = 5;
+
+    fn zone(apex: &str, address: &str) -> String {
+        format!("$ORIGIN {apex}\n@ IN SOA mname rname 1 30 30 30 30\nwww 300 IN A {address}\n")
+    }
+
+    fn partial(update: ZonesUpdate) -> HashMap<DomainName, Option<Zone>> {
+        match update {
+            ZonesUpdate::Partial(changes) => changes.into_iter().collect(),
+            _ => panic!("expected partial update, got {update:?}"),
+        }
+    }
+
+    fn answer(zone: &Zone, name: &str) -> Vec<ResourceRecord> {
+        match zone.resolve(&domain(name), QueryType::Record(RecordType::A)) {
+            Some(ZoneResult::Answer { rrs, .. }) => rrs,
+            result => panic!("expected answer, got {result:?}"),
+        }
+    }
+
+    fn a_record_ttl(name: &str, address: Ipv4Addr) -> ResourceRecord {
+        ResourceRecord {
+            ttl: HOSTS_TTL,
+            ..a_record(name, address)
+        }
+    }
+}
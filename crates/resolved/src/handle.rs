@@ -0,0 +1,141 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tracing::Instrument;
+
+use dns_types::protocol::types::{Message, Question};
+
+/// How many requests can be waiting for the resolver task before senders
+/// start to block.
+pub const REQUEST_BUFFER_SIZE: usize = 32;
+
+/// Answers a DNS query in the same way as the server does for queries it
+/// receives over the network.
+pub type QueryHandler =
+    Arc<dyn Fn(Message) -> Pin<Box<dyn Future<Output = Message> + Send>> + Send + Sync>;
+
+/// A question, and where to send the response.
+type Request = (Question, oneshot::Sender<Message>);
+
+/// A cheap, cloneable, handle to resolve questions in-process.
+///
+/// Questions are answered by the same `QueryHandler` as queries which come in
+/// over the network - so with the same local-zone policies, zones, cache,
+/// blocking, and last-known-good answers - without the overhead of actually
+/// making a network query to ourselves.
+#[derive(Clone)]
+pub struct ResolverHandle {
+    sender: mpsc::Sender<Request>,
+}
+
+// the query handler is a closure
+impl fmt::Debug for ResolverHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResolverHandle").finish_non_exhaustive()
+    }
+}
+
+impl ResolverHandle {
+    /// Start the resolver task, and return a handle to it.  The task stops
+    /// when every handle has been dropped.
+    pub fn spawn(query_handler: QueryHandler) -> Self {
+        let (sender, receiver) = mpsc::channel(REQUEST_BUFFER_SIZE);
+        tokio::spawn(resolver_task(query_handler, receiver));
+        Self { sender }
+    }
+
+    /// Resolve a question, as a query with recursion desired, and return the
+    /// whole response.  Returns `None` if the resolver task has stopped.
+    pub async fn resolve(&self, question: Question) -> Option<Message> {
+        let (reply, response) = oneshot::channel();
+        self.sender.send((question, reply)).await.ok()?;
+        response.await.ok()
+    }
+}
+
+/// Answer questions sent through a `ResolverHandle`, each in its own task so
+/// that one slow question doesn't hold up the others.
+async fn resolver_task(query_handler: QueryHandler, mut receiver: mpsc::Receiver<Request>) {
+    while let Some((question, reply)) = receiver.recv().await {
+        let query_handler = query_handler.clone();
+        tokio::spawn(
+            async move {
+                let mut query = Message::from_question(0, question.clone());
+                query.header.recursion_desired = true;
+                let response = query_handler(query).await;
+
+                if reply.send(response).is_err() {
+                    tracing::debug!(%question, "requester went away");
+                }
+            }
+            .instrument(tracing::error_span!("resolver_handle")),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use dns_types::protocol::types::*;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn resolve_answers_through_query_handler() {
+        let query_handler: QueryHandler = Arc::new(|query: Message| {
+            Box::pin(async move {
+                assert!(query.header.recursion_desired);
+                let mut response = query.make_response();
+                let question = &query.questions[0];
+                response.answers.push(ResourceRecord {
+                    name: question.name.clone(),
+                    rtype_with_data: RecordTypeWithData::A {
+                        address: Ipv4Addr::new(192, 0, 2, 1),
+                    },
+                    rclass: RecordClass::IN,
+                    ttl: 300,
+                });
+                response
+            })
+        });
+        let handle = ResolverHandle::spawn(query_handler);
+
+        let question = Question {
+            name: DomainName::from_str("www.example.com.").unwrap(),
+            qtype: QueryType::Record(RecordType::A),
+            qclass: QueryClass::Record(RecordClass::IN),
+        };
+        let response = handle.resolve(question.clone()).await.unwrap();
+
+        assert_eq!(vec![question], response.questions);
+        assert_eq!(Rcode::NoError, response.header.rcode);
+        assert_eq!(1, response.answers.len());
+    }
+
+    #[tokio::test]
+    async fn resolve_passes_on_error_responses() {
+        let query_handler: QueryHandler = Arc::new(|query: Message| {
+            Box::pin(async move {
+                let mut response = query.make_response();
+                response.header.rcode = Rcode::Refused;
+                response
+            })
+        });
+        let handle = ResolverHandle::spawn(query_handler);
+
+        let response = handle
+            .resolve(Question {
+                name: DomainName::root_domain(),
+                qtype: QueryType::Record(RecordType::NS),
+                qclass: QueryClass::Record(RecordClass::IN),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(Rcode::Refused, response.header.rcode);
+    }
+}
@@ -1,2 +1,21 @@
+pub mod admin;
+pub mod clients;
+pub mod config;
+pub mod control;
+pub mod cookies;
 pub mod fs;
+pub mod handle;
+pub mod logging;
 pub mod metrics;
+pub mod net;
+pub mod overload;
+pub mod overrides;
+pub mod ratelimit;
+pub mod recent;
+pub mod remote;
+pub mod rrl;
+pub mod signals;
+#[cfg(test)]
+mod test_util;
+pub mod top;
+pub mod watcher;
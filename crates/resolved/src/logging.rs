@@ -0,0 +1,286 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::Layered;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+/// The filter switched to by `LogFilter::toggle_debug`.
+pub const DEBUG_LOG_FILTER: &str = "debug";
+
+/// The environment variable the log format is read from at startup.
+pub const LOG_FORMAT_ENV: &str = "RUST_LOG_FORMAT";
+
+/// The subscriber the format layer is installed in: the registry, with the
+/// filter applied.
+type Filtered = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+
+/// The layer which formats log messages.
+pub type FormatLayer = Box<dyn Layer<Filtered> + Send + Sync>;
+
+const MUTEX_POISON_MESSAGE: &str =
+    "[INTERNAL ERROR] log filter mutex poisoned, cannot recover from this - aborting";
+
+/// A handle to change the log filter (the equivalent of `RUST_LOG`) and the
+/// log format (the equivalent of `RUST_LOG_FORMAT`) while running.
+///
+/// The filter can either be set to something new, or toggled between the
+/// normal filter and `DEBUG_LOG_FILTER`, to temporarily get detailed logs.
+///
+/// Invoking `clone` on a `LogFilter` gives a new instance which refers to the
+/// same filter.
+#[derive(Clone)]
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    format_handle: reload::Handle<FormatLayer, Filtered>,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    normal: String,
+    debug: bool,
+    format: LogFormat,
+}
+
+/// An error changing the log filter.
+#[derive(Debug)]
+pub enum Error {
+    /// The filter could not be parsed.
+    Invalid(String),
+    /// The filter could not be installed.
+    Reload(reload::Error),
+    /// The format could not be parsed.
+    InvalidFormat(String),
+    /// The format could not be installed.
+    ReloadFormat(reload::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Invalid(error) => write!(f, "invalid log filter: {error}"),
+            Error::Reload(error) => write!(f, "could not change log filter: {error}"),
+            Error::InvalidFormat(value) => write!(f, "invalid log format: unknown value '{value}'"),
+            Error::ReloadFormat(error) => write!(f, "could not change log format: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl LogFilter {
+    /// Create the filter and format layers to install in the subscriber,
+    /// along with the handle to change them.  The filter is read from
+    /// `RUST_LOG` and the format from `RUST_LOG_FORMAT`: if the format can't
+    /// be parsed, the default is used.
+    pub fn from_default_env() -> (
+        reload::Layer<EnvFilter, Registry>,
+        reload::Layer<FormatLayer, Filtered>,
+        Self,
+    ) {
+        let normal = std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_default();
+        let format = std::env::var(LOG_FORMAT_ENV)
+            .ok()
+            .and_then(|var| LogFormat::from_str(&var).ok())
+            .unwrap_or_default();
+        let (layer, handle) = reload::Layer::new(EnvFilter::from_default_env());
+        let (format_layer, format_handle) = reload::Layer::new(format.layer());
+        let log_filter = Self {
+            handle,
+            format_handle,
+            state: Arc::new(Mutex::new(State {
+                normal,
+                debug: false,
+                format,
+            })),
+        };
+        (layer, format_layer, log_filter)
+    }
+
+    /// The filter currently in use.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn current(&self) -> String {
+        let state = self.state.lock().expect(MUTEX_POISON_MESSAGE);
+        if state.debug {
+            DEBUG_LOG_FILTER.to_string()
+        } else {
+            state.normal.clone()
+        }
+    }
+
+    /// Replace the normal filter, and switch to it if currently toggled to
+    /// `DEBUG_LOG_FILTER`.
+    ///
+    /// # Errors
+    ///
+    /// If the filter cannot be parsed or installed, in which case it is
+    /// unchanged.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn set(&self, directives: &str) -> Result<(), Error> {
+        let mut state = self.state.lock().expect(MUTEX_POISON_MESSAGE);
+        self.install(directives)?;
+        directives.clone_into(&mut state.normal);
+        state.debug = false;
+        Ok(())
+    }
+
+    /// Switch between the normal filter and `DEBUG_LOG_FILTER`, returning the
+    /// filter now in use.
+    ///
+    /// # Errors
+    ///
+    /// If the filter cannot be installed, in which case it is unchanged.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn toggle_debug(&self) -> Result<String, Error> {
+        let mut state = self.state.lock().expect(MUTEX_POISON_MESSAGE);
+        let directives = if state.debug {
+            state.normal.clone()
+        } else {
+            DEBUG_LOG_FILTER.to_string()
+        };
+        self.install(&directives)?;
+        state.debug = !state.debug;
+        Ok(directives)
+    }
+
+    /// The format currently in use.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn current_format(&self) -> LogFormat {
+        self.state.lock().expect(MUTEX_POISON_MESSAGE).format
+    }
+
+    /// Replace the format.
+    ///
+    /// # Errors
+    ///
+    /// If the format cannot be installed, in which case it is unchanged.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn set_format(&self, format: LogFormat) -> Result<(), Error> {
+        let mut state = self.state.lock().expect(MUTEX_POISON_MESSAGE);
+        self.format_handle
+            .reload(format.layer())
+            .map_err(Error::ReloadFormat)?;
+        state.format = format;
+        Ok(())
+    }
+
+    fn install(&self, directives: &str) -> Result<(), Error> {
+        // same default as `EnvFilter::from_default_env`, for when `RUST_LOG`
+        // is unset or empty
+        let filter = EnvFilter::builder()
+            .with_default_directive(LevelFilter::ERROR.into())
+            .parse(directives)
+            .map_err(|error| Error::Invalid(error.to_string()))?;
+        self.handle.reload(filter).map_err(Error::Reload)
+    }
+}
+
+// the handles don't implement `Debug`
+impl fmt::Debug for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogFilter")
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
+}
+
+/// How log messages are formatted, written as a comma-separated list of
+/// values (the same as `RUST_LOG_FORMAT`):
+///
+/// - One of `full` (default), `compact`, `pretty`, or `json`
+/// - One of `ansi` (default), `no-ansi`
+/// - One of `time` (default), `no-time`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct LogFormat {
+    pub style: LogStyle,
+    pub ansi: bool,
+    pub time: bool,
+}
+
+/// The formatters from the `tracing_subscriber` crate.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LogStyle {
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        Self {
+            style: LogStyle::Full,
+            ansi: true,
+            time: true,
+        }
+    }
+}
+
+impl LogFormat {
+    fn layer(self) -> FormatLayer {
+        let logger = tracing_subscriber::fmt::layer().with_ansi(self.ansi);
+
+        match (self.style, self.time) {
+            (LogStyle::Full, true) => logger.boxed(),
+            (LogStyle::Full, false) => logger.without_time().boxed(),
+            (LogStyle::Compact, true) => logger.compact().boxed(),
+            (LogStyle::Compact, false) => logger.compact().without_time().boxed(),
+            (LogStyle::Pretty, true) => logger.pretty().boxed(),
+            (LogStyle::Pretty, false) => logger.pretty().without_time().boxed(),
+            (LogStyle::Json, true) => logger.json().boxed(),
+            (LogStyle::Json, false) => logger.json().without_time().boxed(),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let style = match self.style {
+            LogStyle::Full => "full",
+            LogStyle::Compact => "compact",
+            LogStyle::Pretty => "pretty",
+            LogStyle::Json => "json",
+        };
+        let ansi = if self.ansi { "ansi" } else { "no-ansi" };
+        let time = if self.time { "time" } else { "no-time" };
+        write!(f, "{style},{ansi},{time}")
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut format = Self::default();
+        for value in s.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            match value {
+                "full" => format.style = LogStyle::Full,
+                "compact" => format.style = LogStyle::Compact,
+                "pretty" => format.style = LogStyle::Pretty,
+                "json" => format.style = LogStyle::Json,
+                "ansi" => format.ansi = true,
+                "no-ansi" => format.ansi = false,
+                "time" => format.time = true,
+                "no-time" => format.time = false,
+                _ => return Err(Error::InvalidFormat(value.to_string())),
+            }
+        }
+        Ok(format)
+    }
+}
@@ -1,28 +1,86 @@
+use arc_swap::ArcSwap;
 use bytes::BytesMut;
-use clap::Parser;
-use std::collections::HashSet;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use prometheus::HistogramTimer;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::env;
-use std::net::{Ipv4Addr, SocketAddr};
-use std::path::PathBuf;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::net::{TcpListener, UdpSocket};
-use tokio::signal::unix::{signal, SignalKind};
-use tokio::sync::mpsc;
-use tokio::sync::RwLock;
-use tokio::time::sleep;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, watch, Notify, RwLock};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout};
 use tracing::Instrument;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::prelude::*;
 
+use dns_resolver::additional::additional_records;
 use dns_resolver::cache::SharedCache;
-use dns_resolver::resolve;
+use dns_resolver::last_known_good::LastKnownGood;
+use dns_resolver::local::resolve_authoritative;
+use dns_resolver::metrics::Metrics;
+use dns_resolver::nameserver_stats::NameserverStats;
+use dns_resolver::resolver::Resolver;
+use dns_resolver::root_hints::{self, RootHints};
 use dns_resolver::util::net::*;
-use dns_resolver::util::types::{ProtocolMode, ResolvedRecord};
+use dns_resolver::util::types::{
+    minimise_any_answer, Allowlist, AnswerRotation, BlockedResponse, CachePolicy, FallbackPolicy,
+    ForwardingRule, ForwardingStrategy, HttpsUrl, LocalZonePolicies, LocalZoneRule, NetworkOptions,
+    ProtocolMode, RecursionScope, ResolutionError, ResolvedRecord, RotationCounts, Timeouts,
+    TransportKind, TtlLimits, UpstreamProxy, UpstreamSecurityRule, ZonePrecedence,
+    ZonePrecedenceRule, ZonePrecedenceRules,
+};
+use dns_types::hosts::types::TTL as HOSTS_TTL;
 use dns_types::protocol::types::*;
 use dns_types::zones::types::*;
-use resolved::fs::load_zone_configuration;
+use resolved::admin::{self, AdminState, AdminToken};
+use resolved::clients::{ClientPrivacy, ClientStats};
+use resolved::config::Config;
+use resolved::control::{self, ControlListener, ControlState};
+use resolved::cookies::{CookieStatus, ServerCookies};
+use resolved::fs::{
+    config_from_file, load_allowlist, load_prime_file, load_root_hints, ZoneFiles, ZonesUpdate,
+};
+use resolved::handle::{QueryHandler, ResolverHandle};
+use resolved::logging::{LogFilter, LogFormat, LOG_FORMAT_ENV};
 use resolved::metrics::*;
+use resolved::overload::{InFlight, Overload, OverloadAction, TcpConnections};
+use resolved::overrides::ServedZones;
+use resolved::ratelimit::{RateLimitAction, RateLimiter};
+use resolved::recent::RecentQueries;
+use resolved::remote::{jitter, jittered, Downloader, HostsUrl, Refreshed};
+use resolved::rrl::{self, ResponseRateLimiter, Verdict};
+use resolved::signals::{DebugLoggingSignals, ReloadSignals, ShutdownSignals};
+use resolved::top::TopQueries;
+use resolved::watcher::FileWatcher;
+
+/// How long to wait for queries which are being processed to finish, when
+/// shutting down.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// The largest UDP datagram: queries with EDNS options can be bigger than the
+/// 512 octets of a plain DNS message.
+const UDP_BUFFER_SIZE: usize = 65535;
+
+/// How many UDP datagrams to receive from a socket, if they're already waiting,
+/// before checking for responses to send.
+const UDP_BATCH_SIZE: usize = 64;
+
+/// How many queries on a single TCP connection to answer at once.  Further
+/// queries on the connection aren't read until a response has been sent.
+const TCP_PIPELINE_LIMIT: usize = 32;
+
+/// How many questions from the prime file to resolve at once.
+const PRIME_PARALLELISM: usize = 8;
+
+/// The shortest `--refresh-interval`, in seconds, so that a configuration file
+/// can't make `resolved` download its hosts URLs over and over.
+const MIN_REFRESH_INTERVAL: u64 = 60;
 
 fn prune_cache_and_update_metrics(cache: &SharedCache) {
     let (overflow, current_size, expired, pruned) = cache.prune();
@@ -40,26 +98,68 @@ fn prune_cache_and_update_metrics(cache: &SharedCache) {
     }
 }
 
-fn triage(query: &Message) -> Result<Option<&'_ Question>, &'static str> {
-    if query.questions.is_empty() {
-        Ok(None)
-    } else if query.questions.len() == 1 {
-        let question = &query.questions[0];
-        if question.is_unknown() {
-            Err(REFUSED_FOR_UNKNOWN_QTYPE_OR_QCLASS)
-        } else {
-            Ok(Some(question))
-        }
-    } else {
+/// Decide which questions to answer.  A message with more than one question is
+/// refused unless `multiple_questions` is set.
+fn triage(query: &Message, multiple_questions: bool) -> Result<&[Question], &'static str> {
+    if query.questions.len() > 1 && !multiple_questions {
         Err(REFUSED_FOR_MULTIPLE_QUESTIONS)
+    } else if query.questions.iter().any(Question::is_unknown) {
+        Err(REFUSED_FOR_UNKNOWN_QTYPE_OR_QCLASS)
+    } else {
+        Ok(&query.questions)
+    }
+}
+
+/// The rcode to respond with when a question can't be answered.  A question
+/// which can only be answered with recursion, when recursion isn't allowed, or
+/// which a local-zone policy says to refuse, is refused: but any other error is
+/// a failure of the server.
+fn resolution_error_rcode(error: &ResolutionError) -> Rcode {
+    match error {
+        ResolutionError::RecursionNotAllowed { .. } | ResolutionError::PolicyRefused { .. } => {
+            Rcode::Refused
+        }
+        ResolutionError::Timeout
+        | ResolutionError::RecursionLimit
+        | ResolutionError::DuplicateQuestion { .. }
+        | ResolutionError::CnameLoop { .. }
+        | ResolutionError::DeadEnd { .. }
+        | ResolutionError::Upstream { .. }
+        | ResolutionError::LocalDelegationMissingNS { .. }
+        | ResolutionError::CacheTypeMismatch { .. } => Rcode::ServerFailure,
+    }
+}
+
+/// The extra text of the extended DNS error to explain why a question can't
+/// be answered, for errors which are down to the data being served rather than
+/// to a failure of the server.
+fn resolution_error_extended_error(error: &ResolutionError) -> Option<&'static str> {
+    match error {
+        ResolutionError::CnameLoop { .. } => Some("CNAME loop"),
+        ResolutionError::RecursionLimit => Some("CNAME chain too long"),
+        _ => None,
     }
 }
 
-async fn resolve_and_build_response(args: ListenArgs, query: Message) -> Message {
+/// Answer a query, returning the response and whether any of its questions
+/// were blocked.
+async fn resolve_and_build_response(args: ListenArgs, query: Message) -> (Message, bool) {
+    // take a snapshot of the settings, so a reload doesn't change them in the
+    // middle of processing this request.
+    let settings = args.settings.read().await.clone();
+
+    // the client subnet is stripped, unless it is configured to be forwarded
+    let query_client_subnet = query.client_subnet();
+    let client_subnet = settings
+        .client_subnet_prefixes
+        .and_then(|(ipv4, ipv6)| Some(query_client_subnet?.truncate(ipv4, ipv6)))
+        .filter(|client_subnet| client_subnet.source_prefix_len > 0);
+
     let mut response = query.make_response();
-    response.header.recursion_available = !args.authoritative_only;
+    response.header.recursion_available = settings.resolver.is_recursive();
+    let mut blocked = false;
 
-    match triage(&query) {
+    match triage(&query, settings.multiple_questions) {
         Err(reason) => {
             DNS_REQUESTS_REFUSED_TOTAL
                 .with_label_values(&[reason])
@@ -67,81 +167,41 @@ async fn resolve_and_build_response(args: ListenArgs, query: Message) -> Message
             tracing::info!(%reason, "refused");
             response.header.rcode = Rcode::Refused;
         }
-        Ok(None) => {}
-        Ok(Some(question)) => {
-            let question_labels: &[&str] = &[
-                &query.header.recursion_desired.to_string(),
-                &question.qtype.to_string(),
-                &question.qclass.to_string(),
-            ];
-            DNS_QUESTIONS_TOTAL.with_label_values(question_labels).inc();
-            let question_timer = DNS_QUESTION_PROCESSING_TIME_SECONDS
-                .with_label_values(question_labels)
-                .start_timer();
+        Ok(questions) => {
+            // take a snapshot of the zones here, rather than where they're
+            // used in the resolver, so that this whole request sees a
+            // consistent version of the zones even if they get updated in the
+            // middle of processing.
+            let zones = args.zones.load_full();
 
-            // lock zones here, rather than where they're used in the resolver,
-            // so that this whole request sees a consistent version of the zones
-            // even if they get updated in the middle of processing.
-            let zones = args.zones_lock.read().await;
-
-            let (metrics, answer) = resolve(
-                query.header.recursion_desired && response.header.recursion_available,
-                args.protocol_mode,
-                args.upstream_dns_port,
-                args.forward_address,
-                &zones,
-                &args.cache,
-                question,
-            )
-            .await;
-
-            DNS_RESOLVER_AUTHORITATIVE_HIT_TOTAL.inc_by(metrics.authoritative_hits);
-            DNS_RESOLVER_OVERRIDE_HIT_TOTAL.inc_by(metrics.override_hits);
-            DNS_RESOLVER_BLOCKED_TOTAL.inc_by(metrics.blocked);
-            DNS_RESOLVER_CACHE_HIT_TOTAL.inc_by(metrics.cache_hits);
-            DNS_RESOLVER_CACHE_MISS_TOTAL.inc_by(metrics.cache_misses);
-            DNS_RESOLVER_NAMESERVER_HIT_TOTAL.inc_by(metrics.nameserver_hits);
-            DNS_RESOLVER_NAMESERVER_MISS_TOTAL.inc_by(metrics.nameserver_misses);
-
-            let message = match answer {
-                Ok(rr) => {
-                    match rr {
-                        ResolvedRecord::Authoritative { mut rrs, soa_rr } => {
-                            response.answers.append(&mut rrs);
-                            response.authority.push(soa_rr);
-                            response.header.is_authoritative = true;
-                        }
-                        ResolvedRecord::AuthoritativeNameError { soa_rr } => {
-                            response.authority.push(soa_rr);
-                            response.header.rcode = Rcode::NameError;
-                            response.header.is_authoritative = true;
-                        }
-                        ResolvedRecord::NonAuthoritative { mut rrs, soa_rr } => {
-                            response.answers.append(&mut rrs);
-                            if let Some(soa_rr) = soa_rr {
-                                response.authority.push(soa_rr);
-                            }
-                            response.header.is_authoritative = false;
-                        }
-                    }
-                    "ok".to_string()
+            for (i, question) in questions.iter().enumerate() {
+                if i == 0 {
+                    blocked |= answer_question(
+                        &args,
+                        &settings,
+                        &zones,
+                        &query,
+                        question,
+                        client_subnet,
+                        &mut response,
+                    )
+                    .await;
+                } else {
+                    let mut partial = query.make_response();
+                    partial.header.recursion_available = response.header.recursion_available;
+                    blocked |= answer_question(
+                        &args,
+                        &settings,
+                        &zones,
+                        &query,
+                        question,
+                        client_subnet,
+                        &mut partial,
+                    )
+                    .await;
+                    merge_response(&mut response, partial);
                 }
-                Err(err) => format!("error: {err}"),
-            };
-
-            let duration_seconds = question_timer.stop_and_record();
-            tracing::info!(
-                %question,
-                authoritative_hits = %metrics.authoritative_hits,
-                override_hits = %metrics.override_hits,
-                blocked = %metrics.blocked,
-                cache_hits = %metrics.cache_hits,
-                cache_misses = %metrics.cache_misses,
-                nameserver_hits = %metrics.nameserver_hits,
-                nameserver_misses = %metrics.nameserver_misses,
-                %duration_seconds,
-                message
-            );
+            }
         }
     }
 
@@ -155,15 +215,253 @@ async fn resolve_and_build_response(args: ListenArgs, query: Message) -> Message
         response.header.is_authoritative = false;
     }
 
-    response
+    // the answer may be specific to the part of the client subnet which was
+    // forwarded, so say so (see section 7.2.1 of RFC 7871)
+    if let Some(query_client_subnet) = query_client_subnet {
+        add_edns_option(
+            &mut response,
+            EdnsOption::client_subnet(&ClientSubnet {
+                scope_prefix_len: client_subnet.map_or(0, |cs| cs.source_prefix_len),
+                ..query_client_subnet
+            }),
+        );
+    }
+
+    (response, blocked)
+}
+
+/// Add an option to the EDNS `OPT` pseudo-record of a response, adding the
+/// record if there isn't one.
+fn add_edns_option(response: &mut Message, option: EdnsOption) {
+    if let Some(i) = response
+        .additional
+        .iter()
+        .position(ResourceRecord::is_edns_opt)
+    {
+        let rr = response.additional.remove(i);
+        let mut options = rr.edns_options().unwrap_or_default();
+        options.push(option);
+        let mut opt = ResourceRecord::edns_opt(u16::from(rr.rclass), &options);
+        opt.ttl = rr.ttl;
+        response.additional.push(opt);
+    } else {
+        response
+            .additional
+            .push(ResourceRecord::edns_opt(512, &[option]));
+    }
+}
+
+/// Answer a single question, adding the answer to the response, and return
+/// whether it was blocked.
+async fn answer_question(
+    args: &ListenArgs,
+    settings: &Settings,
+    zones: &Zones,
+    query: &Message,
+    question: &Question,
+    client_subnet: Option<ClientSubnet>,
+    response: &mut Message,
+) -> bool {
+    let question_labels: &[&str] = &[
+        &query.header.recursion_desired.to_string(),
+        &question.qtype.to_string(),
+        &question.qclass.to_string(),
+    ];
+    DNS_QUESTIONS_TOTAL.with_label_values(question_labels).inc();
+    record_question_shape(question);
+    let question_timer = DNS_QUESTION_PROCESSING_TIME_SECONDS
+        .with_label_values(question_labels)
+        .start_timer();
+
+    let (metrics, mut answer) =
+        if let Some((domain, policy)) = settings.local_zone_policies.get(&question.name) {
+            DNS_LOCAL_ZONE_POLICY_ANSWERS_TOTAL
+                .with_label_values(&[local_zone_policy_label(policy)])
+                .inc();
+            (Metrics::new(), policy.answer(&domain, question))
+        } else if let Some((metrics, rr)) = settings
+            .fast_path_zones
+            .iter()
+            .any(|apex| question.name.is_subdomain_of(apex))
+            .then(|| resolve_authoritative(zones, question))
+            .flatten()
+        {
+            DNS_FAST_PATH_ANSWERS_TOTAL.inc();
+            (metrics, Ok(rr))
+        } else {
+            settings
+                .resolver
+                .resolve(
+                    zones,
+                    query.header.recursion_desired,
+                    client_subnet,
+                    None,
+                    question,
+                )
+                .await
+        };
+
+    if metrics.blocked > 0 {
+        if let Some(policy) = settings.blocked_response.policy() {
+            answer = policy.answer(&question.name, question);
+        }
+    }
+
+    record_resolver_metrics(&metrics);
+    if let Err(err) = &answer {
+        DNS_RESOLUTION_ERRORS_TOTAL
+            .with_label_values(&[resolution_error_reason(err)])
+            .inc();
+    }
+
+    let message = match answer {
+        Ok(rr) => {
+            match rr {
+                ResolvedRecord::Authoritative { mut rrs, soa_rr } => {
+                    if settings.clamp_authoritative_ttls {
+                        settings.ttl_limits.clamp_all(&mut rrs);
+                    }
+                    // the SOA is only needed to cache a negative answer
+                    if rrs.is_empty() || !settings.minimal_responses {
+                        response.authority.push(soa_rr);
+                    }
+                    response.answers.append(&mut rrs);
+                    response.header.is_authoritative = true;
+                }
+                ResolvedRecord::AuthoritativeNameError { soa_rr } => {
+                    response.authority.push(soa_rr);
+                    response.header.rcode = Rcode::NameError;
+                    response.header.is_authoritative = true;
+                }
+                ResolvedRecord::NonAuthoritative { mut rrs, soa_rr } => {
+                    settings.ttl_limits.clamp_all(&mut rrs);
+                    // an answer for one client subnet may not suit others
+                    if client_subnet.is_none() {
+                        args.last_known_good.insert(question, &rrs);
+                    }
+                    if let Some(soa_rr) = soa_rr {
+                        if rrs.is_empty() || !settings.minimal_responses {
+                            response.authority.push(soa_rr);
+                        }
+                    }
+                    response.answers.append(&mut rrs);
+                    response.header.is_authoritative = false;
+                }
+            }
+            "ok".to_string()
+        }
+        Err(
+            err @ (ResolutionError::Timeout
+            | ResolutionError::DeadEnd { .. }
+            | ResolutionError::Upstream { .. }),
+        ) => {
+            if let Some(mut rrs) = args.last_known_good.get(question) {
+                DNS_RESPONSES_LAST_KNOWN_GOOD_TOTAL.inc();
+                response.answers.append(&mut rrs);
+                if query.edns_opt().is_some() {
+                    add_edns_option(
+                        response,
+                        EdnsOption::extended_error(
+                            ExtendedErrorCode::StaleAnswer,
+                            "last known good answer",
+                        ),
+                    );
+                }
+                format!("error: {err} - using last known good answer")
+            } else {
+                response.header.rcode = resolution_error_rcode(&err);
+                format!("error: {err}")
+            }
+        }
+        Err(err) => {
+            response.header.rcode = resolution_error_rcode(&err);
+            if let Some(extra_text) = resolution_error_extended_error(&err) {
+                if query.edns_opt().is_some() {
+                    add_edns_option(
+                        response,
+                        EdnsOption::extended_error(ExtendedErrorCode::Other, extra_text),
+                    );
+                }
+            }
+            format!("error: {err}")
+        }
+    };
+
+    if settings.minimal_any && question.qtype == QueryType::Wildcard {
+        minimise_any_answer(&question.name, &mut response.answers);
+    }
+    if settings.answer_rotation != AnswerRotation::None {
+        settings
+            .answer_rotation
+            .apply(&mut response.answers, &args.rotation_counts);
+    }
+
+    if !settings.minimal_responses {
+        for rr in additional_records(
+            &response.answers,
+            settings.resolver.allowlist(),
+            zones,
+            &args.cache,
+        ) {
+            if !response.additional.contains(&rr) {
+                response.additional.push(rr);
+            }
+        }
+    }
+
+    let duration_seconds = question_timer.stop_and_record();
+    tracing::info!(
+        %question,
+        authoritative_hits = %metrics.authoritative_hits,
+        override_hits = %metrics.override_hits,
+        blocked = %metrics.blocked,
+        cache_hits = %metrics.cache_hits,
+        cache_misses = %metrics.cache_misses,
+        nameserver_hits = %metrics.nameserver_hits,
+        nameserver_misses = %metrics.nameserver_misses,
+        %duration_seconds,
+        message
+    );
+
+    metrics.blocked > 0
+}
+
+/// Add the answer to another question to a response.  The response is only
+/// authoritative if every answer is, and it gets the rcode of the first
+/// question which failed.
+fn merge_response(response: &mut Message, partial: Message) {
+    response.answers.extend(partial.answers);
+    for rr in partial.authority {
+        if !response.authority.contains(&rr) {
+            response.authority.push(rr);
+        }
+    }
+    for rr in partial.additional {
+        if !response.additional.contains(&rr) {
+            response.additional.push(rr);
+        }
+    }
+    response.header.is_authoritative &= partial.header.is_authoritative;
+    if response.header.rcode == Rcode::NoError {
+        response.header.rcode = partial.header.rcode;
+    }
 }
 
-async fn handle_raw_message(args: ListenArgs, buf: &[u8]) -> Option<Message> {
+async fn handle_raw_message(
+    args: ListenArgs,
+    buf: &[u8],
+    peer: IpAddr,
+    via_udp: bool,
+) -> Option<Message> {
     let res = Message::from_octets(buf);
     tracing::debug!(message = ?res, "got message");
 
     match res {
         Ok(msg) => {
+            if !msg.header.is_response {
+                args.top_queries.record(peer, msg.questions.first());
+            }
+
             if msg.header.is_response {
                 // Do not respond to response messages: this is because an
                 // inbound message could spoof its source address / port to
@@ -172,8 +470,59 @@ async fn handle_raw_message(args: ListenArgs, buf: &[u8]) -> Option<Message> {
                 //
                 // See #246
                 None
+            } else if let Err(exceeded) = args.rate_limiter.check(peer) {
+                let action = args.settings.read().await.rate_limit_action;
+                DNS_REQUESTS_RATE_LIMITED_TOTAL
+                    .with_label_values(&[exceeded.as_str(), &action.to_string()])
+                    .inc();
+                tracing::info!(%peer, limit = %exceeded.as_str(), %action, "rate limited");
+                match action {
+                    RateLimitAction::Refused => {
+                        let mut response = msg.make_response();
+                        response.header.rcode = Rcode::Refused;
+                        Some(response)
+                    }
+                    RateLimitAction::Drop => None,
+                }
+            } else if let Some(response) = check_cookie(&args, &msg, peer, via_udp).await {
+                Some(response)
             } else if msg.header.opcode == Opcode::Standard {
-                Some(resolve_and_build_response(args, msg).await)
+                let (max_in_flight, request_timeout, overload_action, tcp_idle_timeout) = {
+                    let settings = args.settings.read().await;
+                    (
+                        settings.max_in_flight,
+                        settings.request_timeout,
+                        settings.overload_action,
+                        settings.tcp_idle_timeout,
+                    )
+                };
+                let Some(_in_flight) = args.in_flight.try_start(max_in_flight) else {
+                    return shed(&msg, Overload::InFlight, overload_action);
+                };
+
+                let start = Instant::now();
+                let recent_queries = args.recent_queries.clone();
+                let client_stats = args.client_stats.clone();
+                let cookie = args.server_cookies.response_option(&msg, peer);
+                let resolution = resolve_and_build_response(args, msg.clone());
+                let (mut response, blocked) = match request_timeout {
+                    Some(deadline) => match timeout(deadline, resolution).await {
+                        Ok(response) => response,
+                        Err(_) => return shed(&msg, Overload::Timeout, overload_action),
+                    },
+                    None => resolution.await,
+                };
+                if let Some(option) = cookie {
+                    add_edns_option(&mut response, option);
+                }
+                // keepalive options over UDP are ignored (see section 3.2.1
+                // of RFC 7828)
+                if !via_udp && msg.has_tcp_keepalive() {
+                    add_edns_option(&mut response, tcp_keepalive_option(tcp_idle_timeout));
+                }
+                recent_queries.record(peer, &response, start.elapsed());
+                client_stats.record(peer, &response, blocked);
+                Some(response)
             } else {
                 let mut response = msg.make_response();
                 response.header.rcode = Rcode::NotImplemented;
@@ -185,62 +534,109 @@ async fn handle_raw_message(args: ListenArgs, buf: &[u8]) -> Option<Message> {
         // / port being resolved's, which would make resolved respond to itself
         // here, but this is fine so long as (1) the response we send is valid
         // and (2) we don't reply to a valid message which is a response.
-        Err(err) => err.id().map(Message::make_format_error_response),
+        Err(err) => {
+            DNS_REQUESTS_MALFORMED_TOTAL
+                .with_label_values(&[malformed_reason(err)])
+                .inc();
+            err.id().map(Message::make_format_error_response)
+        }
+    }
+}
+
+/// Record that a query was shed because the server is overloaded, and make the
+/// response to send (if any).
+fn shed(query: &Message, reason: Overload, action: OverloadAction) -> Option<Message> {
+    DNS_REQUESTS_OVERLOADED_TOTAL
+        .with_label_values(&[reason.as_str(), &action.to_string()])
+        .inc();
+    tracing::info!(reason = %reason.as_str(), %action, "overloaded");
+    match action {
+        OverloadAction::ServFail => {
+            let mut response = query.make_response();
+            response.header.rcode = Rcode::ServerFailure;
+            Some(response)
+        }
+        OverloadAction::Drop => None,
+    }
+}
+
+/// Check the DNS cookie of a query, returning the response to send instead of
+/// answering it if the cookie is malformed or, if cookies are required, if the
+/// query is over UDP and does not have a valid server cookie.
+///
+/// A query with no cookie at all gets an empty truncated response, so that a
+/// client which doesn't support cookies can retry over TCP.  A query with a
+/// missing or invalid server cookie gets a BADCOOKIE response with a fresh
+/// one, so that the client can retry over UDP (see section 5.2.3 of RFC 7873).
+async fn check_cookie(
+    args: &ListenArgs,
+    query: &Message,
+    peer: IpAddr,
+    via_udp: bool,
+) -> Option<Message> {
+    let status = args.server_cookies.check(query, peer);
+    DNS_REQUESTS_COOKIE_TOTAL
+        .with_label_values(&[status.as_str()])
+        .inc();
+
+    let required = via_udp && args.settings.read().await.require_cookies;
+    match status {
+        CookieStatus::Malformed => Some(Message::make_format_error_response(query.header.id)),
+        CookieStatus::Missing if required => {
+            tracing::info!(%peer, "no cookie");
+            let mut response = query.make_response();
+            response.header.is_truncated = true;
+            Some(response)
+        }
+        CookieStatus::ClientOnly | CookieStatus::Invalid if required => {
+            tracing::info!(%peer, status = %status.as_str(), "bad cookie");
+            let mut response = query.make_response();
+            if let Some(option) = args.server_cookies.response_option(query, peer) {
+                add_edns_option(&mut response, option);
+            }
+            response.set_extended_rcode(RCODE_BADCOOKIE);
+            Some(response)
+        }
+        _ => None,
     }
 }
 
-async fn listen_tcp_task(args: ListenArgs, socket: TcpListener) {
+/// Accept TCP connections until shutdown.  Each connection is handled in its
+/// own task, which holds a clone of `in_flight` until it's done.
+async fn listen_tcp_task(
+    args: ListenArgs,
+    socket: TcpListener,
+    mut shutdown: watch::Receiver<bool>,
+    in_flight: mpsc::Sender<()>,
+) {
     loop {
-        match socket.accept().await {
-            Ok((mut stream, peer)) => {
-                tracing::info!(?peer, "TCP request");
-                DNS_REQUESTS_TOTAL.with_label_values(&["tcp"]).inc();
+        let accepted = tokio::select! {
+            accepted = socket.accept() => accepted,
+            _ = shutdown.changed() => return,
+        };
+
+        match accepted {
+            Ok((stream, peer)) => {
+                let max_tcp_connections = args.settings.read().await.max_tcp_connections;
+                let Some(connection) = args.tcp_connections.try_open(max_tcp_connections) else {
+                    tracing::info!(?peer, "too many TCP connections");
+                    DNS_TCP_CONNECTIONS_CLOSED_TOTAL
+                        .with_label_values(&["limit"])
+                        .inc();
+                    continue;
+                };
+
+                tracing::info!(?peer, "TCP connection");
                 let args = args.clone();
+                let shutdown = shutdown.clone();
+                let in_flight = in_flight.clone();
                 tokio::spawn(async move {
-                    let response_timer = DNS_RESPONSE_TIME_SECONDS
-                        .with_label_values(&["tcp"])
-                        .start_timer();
-                    let response = match read_tcp_bytes(&mut stream).await {
-                        Ok(bytes) => handle_raw_message(args, bytes.as_ref()).await,
-                        Err(error) => {
-                            let id = match error {
-                                TcpError::TooShort { id, .. } => id,
-                                TcpError::IO { id, .. } => id,
-                            };
-                            tracing::debug!(?peer, ?error, "TCP read error");
-                            id.map(Message::make_format_error_response)
-                        }
-                    };
-                    if let Some(message) = response {
-                        match message.to_octets() {
-                            Ok(mut serialised) => {
-                                DNS_RESPONSES_TOTAL
-                                    .with_label_values(&[
-                                        &message.header.is_authoritative.to_string(),
-                                        "false",
-                                        &message.header.recursion_desired.to_string(),
-                                        &message.header.recursion_available.to_string(),
-                                        &message.header.rcode.to_string(),
-                                    ])
-                                    .inc();
-
-                                if let Err(error) =
-                                    send_tcp_bytes(&mut stream, &mut serialised).await
-                                {
-                                    tracing::debug!(?peer, ?error, "TCP send error");
-                                }
-                            }
-                            Err(error) => {
-                                tracing::warn!(
-                                    ?peer,
-                                    ?message,
-                                    ?error,
-                                    "could not serialise message"
-                                );
-                            }
-                        };
-                    };
-                    response_timer.observe_duration();
+                    let reason = handle_tcp_connection(args, stream, peer, shutdown).await;
+                    DNS_TCP_CONNECTIONS_CLOSED_TOTAL
+                        .with_label_values(&[reason])
+                        .inc();
+                    drop(connection);
+                    drop(in_flight);
                 });
             }
             Err(error) => tracing::debug!(?error, "TCP accept error"),
@@ -248,175 +644,1312 @@ async fn listen_tcp_task(args: ListenArgs, socket: TcpListener) {
     }
 }
 
-async fn listen_udp_task(args: ListenArgs, socket: UdpSocket) {
+/// Answer queries on a TCP connection until the client closes it, it has been
+/// idle for too long, it stops reading responses, or the server is shutting
+/// down, returning the reason it was closed.
+///
+/// Queries may be pipelined (see section 6.2.1 of RFC 7766): each is answered
+/// in its own task, and responses are sent as soon as they're ready, which
+/// may not be in the order the queries were received.
+async fn handle_tcp_connection(
+    args: ListenArgs,
+    stream: TcpStream,
+    peer: SocketAddr,
+    mut shutdown: watch::Receiver<bool>,
+) -> &'static str {
+    let idle_timeout = args.settings.read().await.tcp_idle_timeout;
+    let (mut reader, mut writer) = stream.into_split();
+    let (tx, mut rx) = mpsc::channel::<(Message, HistogramTimer)>(TCP_PIPELINE_LIMIT);
+
+    // a client which doesn't read its responses would otherwise block the
+    // writer, and so the reader, forever
+    let send_responses = async move {
+        while let Some((message, response_timer)) = rx.recv().await {
+            let sent = timeout(idle_timeout, send_tcp_response(&mut writer, &message, peer)).await;
+            response_timer.observe_duration();
+            match sent {
+                Ok(true) => (),
+                Ok(false) => return Some("error"),
+                Err(_) => {
+                    tracing::debug!(?peer, "TCP write timed out");
+                    return Some("write_timeout");
+                }
+            }
+        }
+        None
+    };
+
+    let read_queries = async move {
+        loop {
+            // wait for there to be room for a response before reading the
+            // next query, so a client can't have an unbounded number of
+            // queries being answered at once
+            let permit = match timeout(idle_timeout, tx.clone().reserve_owned()).await {
+                Ok(Ok(permit)) => permit,
+                Ok(Err(_)) => return "error",
+                Err(_) => return "write_timeout",
+            };
+
+            let read = tokio::select! {
+                read = timeout(idle_timeout, read_tcp_bytes(&mut reader)) => read,
+                _ = shutdown.changed() => return "shutdown",
+                () = tx.closed() => return "error",
+            };
+
+            let bytes = match read {
+                Ok(Ok(bytes)) => bytes,
+                Ok(Err(TcpError::IO { id: None, error }))
+                    if error.kind() == io::ErrorKind::UnexpectedEof =>
+                {
+                    return "closed";
+                }
+                Ok(Err(error)) => {
+                    let id = match error {
+                        TcpError::TooShort { id, .. } => id,
+                        TcpError::IO { id, .. } => id,
+                    };
+                    tracing::debug!(?peer, ?error, "TCP read error");
+                    if let Some(id) = id {
+                        let response_timer = DNS_RESPONSE_TIME_SECONDS
+                            .with_label_values(&["tcp"])
+                            .start_timer();
+                        permit.send((Message::make_format_error_response(id), response_timer));
+                    }
+                    return "error";
+                }
+                Err(_) => return "idle",
+            };
+
+            tracing::info!(?peer, "TCP request");
+            DNS_REQUESTS_TOTAL.with_label_values(&["tcp"]).inc();
+            let response_timer = DNS_RESPONSE_TIME_SECONDS
+                .with_label_values(&["tcp"])
+                .start_timer();
+            let args = args.clone();
+            tokio::spawn(async move {
+                if let Some(response) =
+                    handle_raw_message(args, bytes.as_ref(), peer.ip(), false).await
+                {
+                    permit.send((response, response_timer));
+                }
+            });
+        }
+    };
+
+    // responses to queries which have already been read are still sent after
+    // reading stops, and if sending stops first that is why the connection
+    // was closed
+    let (read_reason, send_reason) = tokio::join!(read_queries, send_responses);
+    send_reason.unwrap_or(read_reason)
+}
+
+/// Send a response over TCP, returning whether it could be sent.
+async fn send_tcp_response(
+    writer: &mut OwnedWriteHalf,
+    message: &Message,
+    peer: SocketAddr,
+) -> bool {
+    match message.to_octets() {
+        Ok(mut serialised) => {
+            DNS_RESPONSES_TOTAL
+                .with_label_values(&[
+                    &message.header.is_authoritative.to_string(),
+                    "false",
+                    &message.header.recursion_desired.to_string(),
+                    &message.header.recursion_available.to_string(),
+                    &message.header.rcode.to_string(),
+                ])
+                .inc();
+
+            if let Err(error) = send_tcp_bytes(writer, &mut serialised).await {
+                tracing::debug!(?peer, ?error, "TCP send error");
+                return false;
+            }
+        }
+        Err(error) => {
+            tracing::warn!(?peer, ?message, ?error, "could not serialise message");
+        }
+    }
+
+    true
+}
+
+/// Build a TCP keepalive option saying how long an idle connection is kept
+/// open, in units of 100 milliseconds.
+fn tcp_keepalive_option(idle_timeout: Duration) -> EdnsOption {
+    EdnsOption::tcp_keepalive(
+        (idle_timeout.as_millis() / 100)
+            .try_into()
+            .unwrap_or(u16::MAX),
+    )
+}
+
+/// Receive UDP messages until shutdown, and then send responses to any
+/// messages which are still being processed.
+async fn listen_udp_task(args: ListenArgs, socket: UdpSocket, mut shutdown: watch::Receiver<bool>) {
     let (tx, mut rx) = mpsc::channel(32);
-    let mut buf = vec![0u8; 512];
+    let mut buf = vec![0u8; UDP_BUFFER_SIZE];
 
     loop {
         tokio::select! {
             Ok((size, peer)) = socket.recv_from(&mut buf) => {
-                tracing::info!(?peer, "UDP request");
-                DNS_REQUESTS_TOTAL.with_label_values(&["udp"]).inc();
-                let bytes = BytesMut::from(&buf[..size]);
-                let reply = tx.clone();
-                let args = args.clone();
-                tokio::spawn(async move {
-                    let response_timer = DNS_RESPONSE_TIME_SECONDS
-                        .with_label_values(&["udp"])
-                        .start_timer();
-                    if let Some(response_message) = handle_raw_message(args, bytes.as_ref()).await {
-                        match reply.send((response_message, peer, response_timer)).await {
-                            Ok(_) => (),
-                            Err(error) => tracing::debug!(?peer, ?error, "UDP send error")
-                        }
+                spawn_udp_request(&args, &tx, &buf[..size], peer);
+
+                // handle any other datagrams which have already arrived without
+                // going back through the `select!`
+                for _ in 1..UDP_BATCH_SIZE {
+                    match socket.try_recv_from(&mut buf) {
+                        Ok((size, peer)) => spawn_udp_request(&args, &tx, &buf[..size], peer),
+                        Err(_) => break,
                     }
-                });
+                }
             }
 
             Some((message, peer, response_timer)) = rx.recv() => {
-                match message.to_octets() {
-                    Ok(mut serialised) => {
-                        DNS_RESPONSES_TOTAL.with_label_values(&[
-                            &message.header.is_authoritative.to_string(),
-                            &(serialised.len() > 512).to_string(),
-                            &message.header.recursion_desired.to_string(),
-                            &message.header.recursion_available.to_string(),
-                            &message.header.rcode.to_string(),
-                        ]).inc();
-                        if let Err(error) = send_udp_bytes_to(&socket, peer, &mut serialised).await
-                        {
-                            tracing::debug!(?peer, ?error, "UDP send error");
-                        }
-                    }
-                    Err(error) => {
-                        tracing::warn!(
-                            ?peer,
-                            ?message,
-                            ?error,
-                            "could not serialise message"
-                        );
-                    }
-                };
-                response_timer.observe_duration();
+                send_udp_response(&socket, &message, peer, response_timer).await;
+            }
+
+            _ = shutdown.changed() => break,
+        }
+    }
+
+    // every task processing a message holds a clone of `tx`, so once they've
+    // all finished the channel closes
+    drop(tx);
+    while let Some((message, peer, response_timer)) = rx.recv().await {
+        send_udp_response(&socket, &message, peer, response_timer).await;
+    }
+}
+
+/// Answer a UDP request in its own task, sending the response to `reply`.
+fn spawn_udp_request(
+    args: &ListenArgs,
+    reply: &mpsc::Sender<(Message, SocketAddr, HistogramTimer)>,
+    buf: &[u8],
+    peer: SocketAddr,
+) {
+    tracing::info!(?peer, "UDP request");
+    DNS_REQUESTS_TOTAL.with_label_values(&["udp"]).inc();
+    let bytes = BytesMut::from(buf);
+    let reply = reply.clone();
+    let args = args.clone();
+    tokio::spawn(async move {
+        let response_timer = DNS_RESPONSE_TIME_SECONDS
+            .with_label_values(&["udp"])
+            .start_timer();
+        let response_rate_limiter = args.response_rate_limiter.clone();
+        let response = handle_raw_message(args, bytes.as_ref(), peer.ip(), true)
+            .await
+            .and_then(|message| apply_response_rate_limit(&response_rate_limiter, peer, message));
+        if let Some(response_message) = response {
+            match reply.send((response_message, peer, response_timer)).await {
+                Ok(()) => (),
+                Err(error) => tracing::debug!(?peer, ?error, "UDP send error"),
             }
         }
+    });
+}
+
+/// Bind `count` UDP sockets to the same address with `SO_REUSEPORT`, so that
+/// the kernel spreads incoming datagrams across them.  More than one socket is
+/// only supported on unix.
+fn bind_udp_sockets(
+    address: SocketAddr,
+    count: usize,
+    only_v6: bool,
+) -> io::Result<Vec<UdpSocket>> {
+    if count > 1 && cfg!(not(unix)) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "multiple UDP sockets need SO_REUSEPORT",
+        ));
+    }
+
+    let mut sockets = Vec::with_capacity(count);
+    for _ in 0..count.max(1) {
+        let socket = Socket::new(
+            Domain::for_address(address),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        #[cfg(unix)]
+        if count > 1 {
+            socket.set_reuse_port(true)?;
+        }
+        if only_v6 {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&address.into())?;
+        sockets.push(UdpSocket::from_std(socket.into())?);
+    }
+    Ok(sockets)
+}
+
+/// Bind a TCP socket, in the same way as `TcpListener::bind`.
+fn bind_tcp_listener(address: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if only_v6 {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Whether an address should only accept IPv6 traffic: an IPv6 address does if
+/// there is also an IPv4 address with the same port, as otherwise (on most
+/// systems) the IPv6 socket would accept IPv4 traffic too, and the two would
+/// conflict.
+fn only_v6(address: SocketAddr, addresses: &[SocketAddr]) -> bool {
+    address.is_ipv6()
+        && addresses
+            .iter()
+            .any(|other| other.is_ipv4() && other.port() == address.port())
+}
+
+/// Check a UDP response against the response rate limit, returning the
+/// response to send (if any).
+fn apply_response_rate_limit(
+    response_rate_limiter: &ResponseRateLimiter,
+    peer: SocketAddr,
+    message: Message,
+) -> Option<Message> {
+    let verdict = response_rate_limiter.check(peer.ip(), &message);
+    if verdict != Verdict::Send {
+        DNS_RESPONSES_RATE_LIMITED_TOTAL
+            .with_label_values(&[verdict.as_str()])
+            .inc();
+        tracing::info!(?peer, action = %verdict.as_str(), "response rate limited");
+    }
+
+    match verdict {
+        Verdict::Send => Some(message),
+        Verdict::Slip => Some(rrl::slipped(&message)),
+        Verdict::Drop => None,
     }
 }
 
+async fn send_udp_response(
+    socket: &UdpSocket,
+    message: &Message,
+    peer: SocketAddr,
+    response_timer: HistogramTimer,
+) {
+    match message.to_octets() {
+        Ok(mut serialised) => {
+            DNS_RESPONSES_TOTAL
+                .with_label_values(&[
+                    &message.header.is_authoritative.to_string(),
+                    &(message.header.is_truncated || serialised.len() > 512).to_string(),
+                    &message.header.recursion_desired.to_string(),
+                    &message.header.recursion_available.to_string(),
+                    &message.header.rcode.to_string(),
+                ])
+                .inc();
+            if let Err(error) = send_udp_bytes_to(socket, peer, &mut serialised).await {
+                tracing::debug!(?peer, ?error, "UDP send error");
+            }
+        }
+        Err(error) => {
+            tracing::warn!(?peer, ?message, ?error, "could not serialise message");
+        }
+    };
+    response_timer.observe_duration();
+}
+
 /// Arguments for `listen_udp` and `listen_tcp` and the resolvers.
 #[derive(Debug, Clone)]
 struct ListenArgs {
-    authoritative_only: bool,
-    protocol_mode: ProtocolMode,
-    upstream_dns_port: u16,
-    forward_address: Option<SocketAddr>,
-    zones_lock: Arc<RwLock<Zones>>,
+    settings: Arc<RwLock<Arc<Settings>>>,
+    zones: Arc<ArcSwap<Zones>>,
+    cache: SharedCache,
+    last_known_good: LastKnownGood,
+    nameserver_stats: NameserverStats,
+    rate_limiter: RateLimiter,
+    response_rate_limiter: ResponseRateLimiter,
+    server_cookies: ServerCookies,
+    in_flight: InFlight,
+    tcp_connections: TcpConnections,
+    /// How many times each RRset has been rotated, for
+    /// `AnswerRotation::RoundRobin`.
+    rotation_counts: RotationCounts,
+    recent_queries: RecentQueries,
+    top_queries: TopQueries,
+    client_stats: ClientStats,
+}
+
+/// Resolver settings which can be changed by reloading the configuration.
+#[derive(Debug)]
+struct Settings {
+    /// Resolves questions which aren't answered by a local-zone policy or a
+    /// fast-path zone.  This shares the cache and nameserver stats with the
+    /// `ListenArgs`, and its own zones are left empty: questions are
+    /// answered from the current zones snapshot instead.
+    resolver: Resolver,
+    minimal_any: bool,
+    minimal_responses: bool,
+    answer_rotation: AnswerRotation,
+    multiple_questions: bool,
+    ttl_limits: TtlLimits,
+    clamp_authoritative_ttls: bool,
+    /// File of questions to resolve at startup and after the cache is
+    /// flushed.
+    prime_file: Option<PathBuf>,
+    hosts_urls: Vec<HostsUrl>,
+    hosts_url_dir: Option<PathBuf>,
+    /// How often to download the hosts URLs again.
+    refresh_interval: Duration,
+    refresh_local_files: bool,
+    /// The IPv4 and IPv6 prefix lengths to truncate client subnets to before
+    /// forwarding them, or `None` if they are stripped.
+    client_subnet_prefixes: Option<(u8, u8)>,
+    local_zone_policies: LocalZonePolicies,
+    /// Domains answered straight from the authoritative zone files.
+    fast_path_zones: Vec<DomainName>,
+    blocked_response: BlockedResponse,
+    rate_limit_action: RateLimitAction,
+    require_cookies: bool,
+    max_in_flight: usize,
+    /// How long to spend answering a query before giving up, or `None` for no
+    /// limit beyond the resolution timeout.
+    request_timeout: Option<Duration>,
+    overload_action: OverloadAction,
+    max_tcp_connections: usize,
+    /// How long to keep a TCP connection open while waiting for the next
+    /// query.
+    tcp_idle_timeout: Duration,
+}
+
+impl Settings {
+    fn from_args(
+        args: &Args,
+        root_hints: RootHints,
+        allowlist: Allowlist,
+        cache: &SharedCache,
+        nameserver_stats: &NameserverStats,
+    ) -> Self {
+        Self {
+            resolver: resolver(args, root_hints, allowlist, cache, nameserver_stats),
+            minimal_any: args.minimal_any,
+            minimal_responses: args.minimal_responses,
+            answer_rotation: args.answer_rotation,
+            multiple_questions: args.multiple_questions,
+            ttl_limits: ttl_limits(args),
+            clamp_authoritative_ttls: args.clamp_authoritative_ttls,
+            prime_file: args.prime_file.clone(),
+            hosts_urls: args.hosts_url.clone(),
+            hosts_url_dir: args.hosts_url_dir.clone(),
+            refresh_interval: Duration::from_secs(std::cmp::max(
+                MIN_REFRESH_INTERVAL,
+                args.refresh_interval,
+            )),
+            refresh_local_files: args.refresh_local_files,
+            client_subnet_prefixes: args.forward_client_subnet.then_some((
+                args.client_subnet_ipv4_prefix,
+                args.client_subnet_ipv6_prefix,
+            )),
+            local_zone_policies: local_zone_policies(args),
+            fast_path_zones: args.fast_path_zone.clone(),
+            blocked_response: args.blocked_response,
+            rate_limit_action: args.rate_limit_action,
+            require_cookies: args.require_cookies,
+            max_in_flight: args.max_in_flight,
+            request_timeout: (args.request_timeout > 0)
+                .then(|| Duration::from_secs(args.request_timeout)),
+            overload_action: args.overload_action,
+            max_tcp_connections: args.max_tcp_connections,
+            tcp_idle_timeout: Duration::from_secs(args.tcp_idle_timeout),
+        }
+    }
+}
+
+/// Everything which `reload_task` changes.
+struct ReloadArgs {
+    cli_args: Args,
+    matches: ArgMatches,
+    address: Vec<SocketAddr>,
+    udp_sockets: usize,
+    metrics_address: SocketAddr,
+    control_socket: Option<PathBuf>,
+    settings: Arc<RwLock<Arc<Settings>>>,
+    served_zones: ServedZones,
     cache: SharedCache,
+    nameserver_stats: NameserverStats,
+    recent_queries: RecentQueries,
+    top_queries: TopQueries,
+    client_stats: ClientStats,
+    reload: Arc<Notify>,
+    last_known_good: LastKnownGood,
+    rate_limiter: RateLimiter,
+    response_rate_limiter: ResponseRateLimiter,
+    admin_tokens: Arc<RwLock<Vec<AdminToken>>>,
+    zone_files: ZoneFiles,
+    watcher: Option<FileWatcher>,
 }
 
-/// Delete expired cache entries every 5 minutes.
+/// Delete expired cache entries, too-old last-known-good answers, and idle
+/// rate limit and response rate limit buckets, every 5 minutes.
 ///
 /// Always removes all expired entries, and then if the cache is still
 /// too big prunes it down to size.
-async fn prune_cache_task(cache: SharedCache) {
+async fn prune_cache_task(
+    cache: SharedCache,
+    last_known_good: LastKnownGood,
+    nameserver_stats: NameserverStats,
+    rate_limiter: RateLimiter,
+    response_rate_limiter: ResponseRateLimiter,
+) {
     loop {
         sleep(Duration::from_secs(60 * 5)).await;
         prune_cache_and_update_metrics(&cache);
+
+        let (current_size, pruned) = last_known_good.prune();
+        LAST_KNOWN_GOOD_SIZE.set(current_size.try_into().unwrap_or(i64::MAX));
+        if pruned > 0 {
+            tracing::info!(%pruned, "pruned last known good answers");
+        }
+
+        let (current_size, pruned) = nameserver_stats.prune();
+        NAMESERVER_STATS_SIZE.set(current_size.try_into().unwrap_or(i64::MAX));
+        if pruned > 0 {
+            tracing::info!(%pruned, "pruned nameserver stats");
+        }
+
+        let clients = rate_limiter.prune();
+        RATE_LIMIT_CLIENTS.set(clients.try_into().unwrap_or(i64::MAX));
+
+        let entries = response_rate_limiter.prune();
+        RESPONSE_RATE_LIMIT_ENTRIES.set(entries.try_into().unwrap_or(i64::MAX));
     }
 }
 
-/// Reload hosts and zones, and replace the value in the `RwLock`.
-async fn reload_task(zones_lock: Arc<RwLock<Zones>>, args: Args) {
-    let mut stream = match signal(SignalKind::user_defined1()) {
-        Ok(s) => s,
-        Err(error) => {
-            tracing::error!(?error, "could not subscribe to SIGUSR1");
-            process::exit(1);
-        }
-    };
+/// Ask the root nameservers for the current root `NS` records and cache them,
+/// so that recursive resolution doesn't rely on the root hints being up to
+/// date.
+async fn prime_root_hints_task(settings: Arc<RwLock<Arc<Settings>>>, cache: SharedCache) {
+    let settings = settings.read().await.clone();
+    let span = tracing::error_span!("prime_root_hints");
 
-    loop {
-        stream.recv().await;
+    match root_hints::prime(
+        settings.resolver.root_hints(),
+        settings.resolver.protocol_mode(),
+        settings.resolver.upstream_dns_port(),
+        settings.resolver.timeouts().query,
+        settings.resolver.network(),
+        &cache,
+    )
+    .instrument(span.clone())
+    .await
+    {
+        Some(count) => span.in_scope(|| tracing::info!(%count, "done - success")),
+        None => span.in_scope(|| tracing::warn!("done - failure, using root hints as-is")),
+    }
+}
 
-        tracing::error_span!("SIGUSR1").in_scope(|| tracing::info!("received"));
-        let start = Instant::now();
-        if let Some(zones) = load_zone_configuration(
-            &args.hosts_file,
-            &args.hosts_dir,
-            &args.zone_file,
-            &args.zones_dir,
-        )
-        .instrument(tracing::error_span!("SIGUSR1"))
-        .await
-        {
-            let mut lock = zones_lock.write().await;
-            *lock = zones;
-            tracing::error_span!("SIGUSR1").in_scope(
-                || tracing::info!(duration_seconds = %start.elapsed().as_secs_f64(), "done - success"),
-            );
-        } else {
-            tracing::error_span!("SIGUSR1").in_scope(
-                || tracing::info!(duration_seconds = %start.elapsed().as_secs_f64(), "done - failure"),
-            );
-        }
+/// Resolve the questions in the prime file, if there is one, at startup and
+/// then every time the whole cache is flushed.
+async fn prime_cache_task(args: ListenArgs, prime: Arc<Notify>) {
+    loop {
+        prime_cache(&args).await;
+        prime.notified().await;
     }
 }
 
-fn begin_logging() {
-    let log_format = if let Ok(var) = env::var("RUST_LOG_FORMAT") {
-        let mut set = HashSet::new();
-        for s in var.split(',') {
-            set.insert(s.to_string());
-        }
-        set
-    } else {
-        HashSet::new()
+/// Resolve the questions in the prime file, so that the answers are cached
+/// before any client asks for them.
+async fn prime_cache(args: &ListenArgs) {
+    let settings = args.settings.read().await.clone();
+    let Some(path) = &settings.prime_file else {
+        return;
     };
 
-    let logger = tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .with_ansi(!log_format.contains("no-ansi"));
+    let span = tracing::error_span!("prime_cache", ?path);
+    let Some(questions) = load_prime_file(path).instrument(span.clone()).await else {
+        return;
+    };
 
-    if log_format.contains("json") {
-        if log_format.contains("no-time") {
-            logger.json().without_time().init();
-        } else {
-            logger.json().init();
-        }
-    } else if log_format.contains("pretty") {
-        if log_format.contains("no-time") {
-            logger.pretty().without_time().init();
-        } else {
-            logger.pretty().init();
+    let zones = args.zones.load_full();
+    let start = Instant::now();
+    let mut succeeded = 0;
+    let mut failed = 0;
+    let mut tasks = JoinSet::new();
+    for question in questions {
+        if tasks.len() >= PRIME_PARALLELISM {
+            if let Some(Ok(true)) = tasks.join_next().await {
+                succeeded += 1;
+            } else {
+                failed += 1;
+            }
         }
-    } else if log_format.contains("compact") {
-        if log_format.contains("no-time") {
-            logger.compact().without_time().init();
+
+        let settings = settings.clone();
+        let zones = zones.clone();
+        tasks.spawn(
+            async move {
+                let (_, answer) = settings
+                    .resolver
+                    .resolve(&zones, true, None, None, &question)
+                    .await;
+                if let Err(error) = &answer {
+                    tracing::debug!(%question.name, %question.qtype, ?error, "could not resolve");
+                }
+                answer.is_ok()
+            }
+            .instrument(span.clone()),
+        );
+    }
+    while let Some(result) = tasks.join_next().await {
+        if let Ok(true) = result {
+            succeeded += 1;
         } else {
-            logger.compact().init();
+            failed += 1;
         }
-    } else if log_format.contains("no-time") {
-        logger.without_time().init();
-    } else {
-        logger.init();
     }
+
+    span.in_scope(|| {
+        tracing::info!(%succeeded, %failed, duration_seconds = %start.elapsed().as_secs_f64(), "done");
+    });
 }
 
-// the doc comments for this struct turn into the CLI help text
-#[derive(Debug, Parser)]
-/// A simple DNS server for home networks.
-///
-/// resolved supports:
+/// Reload the configuration file, hosts, and zones, and replace the settings
+/// and zones being served (keeping any runtime overrides).
 ///
-/// - Three modes of operation: as a recursive or forwarding nameserver (with
+/// This happens on SIGUSR1 or SIGHUP (CTRL_BREAK_EVENT on Windows), or when a hosts or zone file changes if
+/// `--watch` is given.  Requests which are being processed finish with the old
+/// configuration.  If anything fails to load, nothing is changed.
+async fn reload_task(mut reload_args: ReloadArgs) {
+    let mut signals = match ReloadSignals::new() {
+        Ok(s) => s,
+        Err(error) => {
+            tracing::error!(?error, "could not subscribe to reload signals");
+            process::exit(1);
+        }
+    };
+
+    loop {
+        let span = tokio::select! {
+            signal = signals.recv() => tracing::error_span!("signal", %signal),
+            () = file_changed(reload_args.watcher.as_mut()) => tracing::error_span!("file change"),
+            () = reload_args.reload.notified() => tracing::error_span!("reload request"),
+        };
+
+        span.in_scope(|| tracing::info!("received"));
+        let start = Instant::now();
+        let loaded = async {
+            let args = load_args(&reload_args.cli_args, &reload_args.matches).await?;
+            let load_start = Instant::now();
+            let zone_files = reload_args
+                .zone_files
+                .reload(
+                    &hosts_files(&args),
+                    &args.hosts_dir,
+                    &args.zone_file,
+                    &args.zones_dir,
+                    &args.zones_dir_auto,
+                    args.synthesise_ptr,
+                    args.compact_hosts,
+                    args.hosts_ttl,
+                    &args.flatten_cnames,
+                    args.skip_bad_files,
+                )
+                .await;
+            ZONE_LOAD_DURATION_SECONDS
+                .with_label_values(&["reload"])
+                .observe(load_start.elapsed().as_secs_f64());
+            let (zone_files, update) = match zone_files {
+                Ok(loaded) => loaded,
+                Err(errors) => {
+                    for error in errors {
+                        tracing::warn!(%error, "could not load hosts or zone file");
+                    }
+                    return None;
+                }
+            };
+            let root_hints = load_root_hints(args.root_hints.as_deref()).await?;
+            let allowlist = load_allowlist(&args.allow_domain, &args.allowlist_file).await?;
+            Some((args, zone_files, update, root_hints, allowlist))
+        }
+        .instrument(span.clone())
+        .await;
+
+        if let Some((args, zone_files, update, root_hints, allowlist)) = loaded {
+            span.in_scope(|| warn_about_unreloadable_args(&reload_args, &args));
+            reload_args.watcher =
+                span.in_scope(|| update_file_watcher(reload_args.watcher.take(), &args));
+
+            *reload_args.settings.write().await = Arc::new(Settings::from_args(
+                &args,
+                root_hints,
+                allowlist,
+                &reload_args.cache,
+                &reload_args.nameserver_stats,
+            ));
+            reload_args
+                .cache
+                .set_desired_size(std::cmp::max(1, args.cache_size));
+            reload_args.cache.set_policy(args.cache_policy);
+            reload_args.cache.set_ttl_limits(ttl_limits(&args));
+            *reload_args.admin_tokens.write().await = args.admin_tokens;
+            reload_args.recent_queries.set_capacity(args.recent_queries);
+            reload_args
+                .top_queries
+                .set_limits(args.top_queries, args.top_queries_metrics);
+            reload_args
+                .client_stats
+                .set_limits(args.client_stats, args.client_stats_privacy);
+            reload_args.last_known_good.set_limits(
+                Duration::from_secs(args.last_known_good_max_age),
+                std::cmp::max(1, args.cache_size),
+            );
+            reload_args
+                .rate_limiter
+                .set_limits(args.client_rate_limit, args.global_rate_limit);
+            reload_args
+                .response_rate_limiter
+                .set_limits(args.response_rate_limit, args.response_rate_limit_slip);
+            let zones_changed = !matches!(update, ZonesUpdate::Unchanged);
+            match update {
+                ZonesUpdate::Unchanged => span.in_scope(|| tracing::info!("zones unchanged")),
+                ZonesUpdate::Replace(zones) => {
+                    reload_args.served_zones.replace_file_zones(zones).await;
+                }
+                ZonesUpdate::Partial(changes) => {
+                    span.in_scope(|| tracing::info!(count = %changes.len(), "zones changed"));
+                    reload_args.served_zones.update_file_zones(changes).await;
+                }
+            }
+            if zones_changed {
+                let zones = reload_args.served_zones.current.load();
+                span.in_scope(|| warn_about_cname_loops(&zones));
+            }
+            SKIPPED_FILES.set(zone_files.skipped().try_into().unwrap_or(i64::MAX));
+            reload_args.zone_files = zone_files;
+            span.in_scope(
+                || tracing::info!(duration_seconds = %start.elapsed().as_secs_f64(), "done - success"),
+            );
+        } else {
+            span.in_scope(
+                || tracing::info!(duration_seconds = %start.elapsed().as_secs_f64(), "done - failure"),
+            );
+        }
+    }
+}
+
+/// The hosts files to load: those given with `--hosts-file`, and those which
+/// have been downloaded from `--hosts-url`.
+fn hosts_files(args: &Args) -> Vec<PathBuf> {
+    let mut paths = args.hosts_file.clone();
+    if let Some(dir) = &args.hosts_url_dir {
+        paths.extend(
+            args.hosts_url
+                .iter()
+                .map(|url| url.path_in(dir))
+                .filter(|path| path.exists()),
+        );
+    }
+    paths
+}
+
+/// Download the hosts URLs which haven't been downloaded before, so that they
+/// are served from the start.  Those which have are refreshed later, by
+/// `refresh_task`, so that a slow or broken server doesn't hold up startup.
+async fn download_missing_hosts_files(downloader: &Downloader, urls: &[HostsUrl], dir: &Path) {
+    for url in urls {
+        if !url.path_in(dir).exists() {
+            let refreshed = downloader.refresh(url, dir).await;
+            HOSTS_URL_REFRESHES_TOTAL
+                .with_label_values(&[refreshed.as_str()])
+                .inc();
+        }
+    }
+}
+
+/// Download the hosts URLs again if they have changed, and reload if any
+/// have (or always, with `--refresh-local-files`), every
+/// `--refresh-interval`.  The first refresh is after a random delay of up to
+/// a tenth of the interval, and each one after that is after the interval
+/// plus the same again, so that many servers started at the same time don't
+/// all download their lists at once.
+///
+/// A list which fails to download, or which doesn't parse, is left as it
+/// was: so is served from the previous version if there is one.
+async fn refresh_task(settings: Arc<RwLock<Arc<Settings>>>, reload: Arc<Notify>) {
+    let downloader = Downloader::new();
+    let mut delay = jitter(settings.read().await.refresh_interval);
+
+    loop {
+        sleep(delay).await;
+
+        let settings = settings.read().await.clone();
+        delay = jittered(settings.refresh_interval);
+
+        let mut changed = false;
+        if let Some(dir) = &settings.hosts_url_dir {
+            for url in &settings.hosts_urls {
+                let refreshed = downloader
+                    .refresh(url, dir)
+                    .instrument(tracing::error_span!("refresh", %url))
+                    .await;
+                HOSTS_URL_REFRESHES_TOTAL
+                    .with_label_values(&[refreshed.as_str()])
+                    .inc();
+                changed |= refreshed == Refreshed::Updated;
+            }
+        }
+
+        if changed || settings.refresh_local_files {
+            reload.notify_one();
+        }
+    }
+}
+
+/// Warn about names in the zones whose CNAME chain loops back to them, as
+/// questions for them can never be answered.
+fn warn_about_cname_loops(zones: &Zones) {
+    for name in zones.cname_loops() {
+        tracing::warn!(%name, "CNAME loop in zones");
+    }
+}
+
+/// Wait for a watched file to change, or forever if nothing is being watched.
+async fn file_changed(watcher: Option<&mut FileWatcher>) {
+    match watcher {
+        Some(watcher) => watcher.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Start or stop watching files, depending on `--watch`, and watch the
+/// currently configured hosts and zone files and directories.
+fn update_file_watcher(watcher: Option<FileWatcher>, args: &Args) -> Option<FileWatcher> {
+    if !args.watch {
+        return None;
+    }
+
+    let mut watcher = match watcher {
+        Some(watcher) => watcher,
+        None => match FileWatcher::new() {
+            Ok(watcher) => watcher,
+            Err(error) => {
+                tracing::warn!(?error, "could not watch files for changes");
+                return None;
+            }
+        },
+    };
+
+    let files = [
+        args.hosts_file.as_slice(),
+        args.zone_file.as_slice(),
+        args.allowlist_file.as_slice(),
+    ]
+    .concat();
+    let dirs = [
+        args.hosts_dir.as_slice(),
+        args.zones_dir.as_slice(),
+        args.zones_dir_auto.as_slice(),
+    ]
+    .concat();
+    watcher.watch(&files, &dirs);
+    Some(watcher)
+}
+
+/// Switch between the normal log filter and debug logging on SIGUSR2.
+async fn toggle_debug_logging_task(log_filter: LogFilter) {
+    let mut signals = match DebugLoggingSignals::new() {
+        Ok(s) => s,
+        Err(error) => {
+            tracing::error!(?error, "could not subscribe to debug logging signals");
+            process::exit(1);
+        }
+    };
+
+    loop {
+        let signal = signals.recv().await;
+        let span = tracing::error_span!("signal", %signal);
+        let _guard = span.enter();
+        match log_filter.toggle_debug() {
+            Ok(filter) => tracing::warn!(%filter, "changed log filter"),
+            Err(error) => tracing::warn!(%error, "could not change log filter"),
+        }
+    }
+}
+
+/// Log a warning if a setting which can only be changed by restarting has been
+/// changed.
+fn warn_about_unreloadable_args(reload_args: &ReloadArgs, args: &Args) {
+    if reload_args.address != args.address {
+        tracing::warn!(address = ?args.address, "cannot change addresses without restarting");
+    }
+    if reload_args.udp_sockets != args.udp_sockets {
+        tracing::warn!(count = %args.udp_sockets, "cannot change number of UDP sockets without restarting");
+    }
+    if reload_args.metrics_address != args.metrics_address {
+        tracing::warn!(address = %args.metrics_address, "cannot change metrics address without restarting");
+    }
+    if reload_args.control_socket != args.control_socket {
+        tracing::warn!(path = ?args.control_socket, "cannot change control socket without restarting");
+    }
+}
+
+/// Wait for SIGTERM or SIGINT (or a console event on Windows), and return a
+/// span to log the shutdown in.
+async fn wait_for_shutdown_signal() -> tracing::Span {
+    let mut signals = match ShutdownSignals::new() {
+        Ok(s) => s,
+        Err(error) => {
+            tracing::error!(?error, "could not subscribe to shutdown signals");
+            process::exit(1);
+        }
+    };
+
+    let signal = signals.recv().await;
+    tracing::error_span!("signal", %signal)
+}
+
+/// Read the configuration file, if there is one, and combine it with the
+/// command-line arguments.
+async fn load_args(cli_args: &Args, matches: &ArgMatches) -> Option<Args> {
+    let Some(path) = &cli_args.config else {
+        return Some(cli_args.clone());
+    };
+
+    match config_from_file(path).await {
+        Ok(Ok(config)) => Some(merge_config(cli_args, matches, config)),
+        Ok(Err(error)) => {
+            tracing::warn!(?path, %error, "could not parse configuration file");
+            None
+        }
+        Err(error) => {
+            tracing::warn!(?path, ?error, "could not read configuration file");
+            None
+        }
+    }
+}
+
+/// Combine the configuration file with the command-line arguments.
+///
+/// A single-valued setting from the configuration file is only used if the
+/// argument has its default value (ie, it was not given on the command line or
+/// in an environment variable), multi-valued settings from the configuration
+/// file come before those from the arguments.
+fn merge_config(cli_args: &Args, matches: &ArgMatches, config: Config) -> Args {
+    let is_default = |id| matches.value_source(id) == Some(ValueSource::DefaultValue);
+    let mut args = cli_args.clone();
+
+    if !config.addresses.is_empty() {
+        if is_default("address") {
+            args.address = config.addresses;
+        } else {
+            args.address = [config.addresses, args.address].concat();
+        }
+    }
+    if let Some(count) = config.udp_sockets.filter(|_| is_default("udp_sockets")) {
+        args.udp_sockets = count;
+    }
+    if let Some(address) = config
+        .metrics_address
+        .filter(|_| is_default("metrics_address"))
+    {
+        args.metrics_address = address;
+    }
+    if let Some(capacity) = config
+        .recent_queries
+        .filter(|_| is_default("recent_queries"))
+    {
+        args.recent_queries = capacity;
+    }
+    if let Some(capacity) = config.client_stats.filter(|_| is_default("client_stats")) {
+        args.client_stats = capacity;
+    }
+    if let Some(privacy) = config
+        .client_stats_privacy
+        .filter(|_| is_default("client_stats_privacy"))
+    {
+        args.client_stats_privacy = privacy;
+    }
+    if let Some(capacity) = config.top_queries.filter(|_| is_default("top_queries")) {
+        args.top_queries = capacity;
+    }
+    if let Some(count) = config
+        .top_queries_metrics
+        .filter(|_| is_default("top_queries_metrics"))
+    {
+        args.top_queries_metrics = count;
+    }
+    if let Some(flag) = config
+        .authoritative_only
+        .filter(|_| is_default("authoritative_only"))
+    {
+        args.authoritative_only = flag;
+    }
+    if let Some(mode) = config.protocol_mode.filter(|_| is_default("protocol_mode")) {
+        args.protocol_mode = mode;
+    }
+    if let Some(port) = config
+        .upstream_dns_port
+        .filter(|_| is_default("upstream_dns_port"))
+    {
+        args.upstream_dns_port = port;
+    }
+    args.outbound_bind = [config.outbound_binds, args.outbound_bind].concat();
+    if args.upstream_proxy.is_none() {
+        args.upstream_proxy = config.upstream_proxy;
+    }
+    if let Some(flag) = config
+        .no_qname_minimisation
+        .filter(|_| is_default("no_qname_minimisation"))
+    {
+        args.no_qname_minimisation = flag;
+    }
+    if let Some(secs) = config.query_timeout.filter(|_| is_default("query_timeout")) {
+        args.query_timeout = secs;
+    }
+    if let Some(secs) = config
+        .resolution_timeout
+        .filter(|_| is_default("resolution_timeout"))
+    {
+        args.resolution_timeout = secs;
+    }
+    if let Some(flag) = config.minimal_any.filter(|_| is_default("minimal_any")) {
+        args.minimal_any = flag;
+    }
+    if let Some(flag) = config
+        .minimal_responses
+        .filter(|_| is_default("minimal_responses"))
+    {
+        args.minimal_responses = flag;
+    }
+    if let Some(rotation) = config
+        .answer_rotation
+        .filter(|_| is_default("answer_rotation"))
+    {
+        args.answer_rotation = rotation;
+    }
+    if let Some(flag) = config
+        .multiple_questions
+        .filter(|_| is_default("multiple_questions"))
+    {
+        args.multiple_questions = flag;
+    }
+    if let Some(strategy) = config
+        .forward_strategy
+        .filter(|_| is_default("forward_strategy"))
+    {
+        args.forward_strategy = strategy;
+    }
+    // the order matters, so the command line replaces the file rather than
+    // adding to it
+    if args.forward_transport.is_empty() {
+        args.forward_transport = config.forward_transports;
+    }
+    if let Some(flag) = config
+        .forward_client_subnet
+        .filter(|_| is_default("forward_client_subnet"))
+    {
+        args.forward_client_subnet = flag;
+    }
+    if let Some(len) = config
+        .client_subnet_ipv4_prefix
+        .filter(|_| is_default("client_subnet_ipv4_prefix"))
+    {
+        args.client_subnet_ipv4_prefix = len;
+    }
+    if let Some(len) = config
+        .client_subnet_ipv6_prefix
+        .filter(|_| is_default("client_subnet_ipv6_prefix"))
+    {
+        args.client_subnet_ipv6_prefix = len;
+    }
+    if let Some(size) = config.cache_size.filter(|_| is_default("cache_size")) {
+        args.cache_size = size;
+    }
+    if let Some(policy) = config.cache_policy.filter(|_| is_default("cache_policy")) {
+        args.cache_policy = policy;
+    }
+    if args.min_ttl.is_none() {
+        args.min_ttl = config.min_ttl;
+    }
+    if args.max_ttl.is_none() {
+        args.max_ttl = config.max_ttl;
+    }
+    if let Some(flag) = config
+        .clamp_authoritative_ttls
+        .filter(|_| is_default("clamp_authoritative_ttls"))
+    {
+        args.clamp_authoritative_ttls = flag;
+    }
+    if let Some(limit) = config
+        .client_rate_limit
+        .filter(|_| is_default("client_rate_limit"))
+    {
+        args.client_rate_limit = limit;
+    }
+    if let Some(limit) = config
+        .global_rate_limit
+        .filter(|_| is_default("global_rate_limit"))
+    {
+        args.global_rate_limit = limit;
+    }
+    if let Some(action) = config
+        .rate_limit_action
+        .filter(|_| is_default("rate_limit_action"))
+    {
+        args.rate_limit_action = action;
+    }
+    if let Some(limit) = config
+        .response_rate_limit
+        .filter(|_| is_default("response_rate_limit"))
+    {
+        args.response_rate_limit = limit;
+    }
+    if let Some(slip) = config
+        .response_rate_limit_slip
+        .filter(|_| is_default("response_rate_limit_slip"))
+    {
+        args.response_rate_limit_slip = slip;
+    }
+    if let Some(flag) = config
+        .require_cookies
+        .filter(|_| is_default("require_cookies"))
+    {
+        args.require_cookies = flag;
+    }
+    if let Some(limit) = config.max_in_flight.filter(|_| is_default("max_in_flight")) {
+        args.max_in_flight = limit;
+    }
+    if let Some(secs) = config
+        .request_timeout
+        .filter(|_| is_default("request_timeout"))
+    {
+        args.request_timeout = secs;
+    }
+    if let Some(action) = config
+        .overload_action
+        .filter(|_| is_default("overload_action"))
+    {
+        args.overload_action = action;
+    }
+    if let Some(limit) = config
+        .max_tcp_connections
+        .filter(|_| is_default("max_tcp_connections"))
+    {
+        args.max_tcp_connections = limit;
+    }
+    if let Some(secs) = config
+        .tcp_idle_timeout
+        .filter(|_| is_default("tcp_idle_timeout"))
+    {
+        args.tcp_idle_timeout = secs;
+    }
+    if let Some(max_age) = config
+        .last_known_good_max_age
+        .filter(|_| is_default("last_known_good_max_age"))
+    {
+        args.last_known_good_max_age = max_age;
+    }
+
+    args.recursion_domain = [config.recursion_domains, args.recursion_domain].concat();
+    args.no_recursion_domain = [config.no_recursion_domains, args.no_recursion_domain].concat();
+    args.forward_address = [config.forward_addresses, args.forward_address].concat();
+    args.forward_url = [config.forward_urls, args.forward_url].concat();
+    args.forward_rule = [config.forward_rules, args.forward_rule].concat();
+    args.upstream_security = [config.upstream_security_rules, args.upstream_security].concat();
+    args.local_zone = [config.local_zones, args.local_zone].concat();
+    args.hosts_file = [config.hosts_files, args.hosts_file].concat();
+    args.hosts_dir = [config.hosts_dirs, args.hosts_dir].concat();
+    args.hosts_url = [config.hosts_urls, args.hosts_url].concat();
+    if args.hosts_url_dir.is_none() {
+        args.hosts_url_dir = config.hosts_url_dir;
+    }
+    if let Some(interval) = config
+        .refresh_interval
+        .filter(|_| is_default("refresh_interval"))
+    {
+        args.refresh_interval = interval;
+    }
+    if let Some(flag) = config
+        .refresh_local_files
+        .filter(|_| is_default("refresh_local_files"))
+    {
+        args.refresh_local_files = flag;
+    }
+    args.zone_file = [config.zone_files, args.zone_file].concat();
+    args.zones_dir = [config.zones_dirs, args.zones_dir].concat();
+    args.zones_dir_auto = [config.zones_dirs_auto, args.zones_dir_auto].concat();
+    if let Some(flag) = config
+        .synthesise_ptr
+        .filter(|_| is_default("synthesise_ptr"))
+    {
+        args.synthesise_ptr = flag;
+    }
+    if let Some(flag) = config.compact_hosts.filter(|_| is_default("compact_hosts")) {
+        args.compact_hosts = flag;
+    }
+    if let Some(ttl) = config.hosts_ttl.filter(|_| is_default("hosts_ttl")) {
+        args.hosts_ttl = ttl;
+    }
+    args.flatten_cnames = [config.flatten_cnames, args.flatten_cnames].concat();
+    if let Some(flag) = config
+        .skip_bad_files
+        .filter(|_| is_default("skip_bad_files"))
+    {
+        args.skip_bad_files = flag;
+    }
+    if let Some(response) = config
+        .blocked_response
+        .filter(|_| is_default("blocked_response"))
+    {
+        args.blocked_response = response;
+    }
+    if let Some(precedence) = config
+        .zone_precedence
+        .filter(|_| is_default("zone_precedence"))
+    {
+        args.zone_precedence = precedence;
+    }
+    args.zone_precedence_rule = [config.zone_precedence_rules, args.zone_precedence_rule].concat();
+    args.fast_path_zone = [config.fast_path_zones, args.fast_path_zone].concat();
+    args.allow_domain = [config.allow_domains, args.allow_domain].concat();
+    args.allowlist_file = [config.allowlist_files, args.allowlist_file].concat();
+    if let Some(flag) = config.watch.filter(|_| is_default("watch")) {
+        args.watch = flag;
+    }
+    if args.root_hints.is_none() {
+        args.root_hints = config.root_hints;
+    }
+    if args.prime_file.is_none() {
+        args.prime_file = config.prime_file;
+    }
+    if args.control_socket.is_none() {
+        args.control_socket = config.control_socket;
+    }
+    args.admin_tokens = config.admin_tokens;
+
+    args
+}
+
+/// Build the TTL limits from the command-line arguments.
+fn ttl_limits(args: &Args) -> TtlLimits {
+    TtlLimits {
+        min: args.min_ttl,
+        max: args.max_ttl,
+    }
+}
+
+/// Build the resolver from the command-line arguments, sharing the cache and
+/// nameserver stats.
+fn resolver(
+    args: &Args,
+    root_hints: RootHints,
+    allowlist: Allowlist,
+    cache: &SharedCache,
+    nameserver_stats: &NameserverStats,
+) -> Resolver {
+    let mut builder = Resolver::builder()
+        .recursive(!args.authoritative_only)
+        .protocol_mode(args.protocol_mode)
+        .upstream_dns_port(args.upstream_dns_port)
+        .network(NetworkOptions {
+            outbound: args.outbound_bind.iter().copied().collect(),
+            proxy: args.upstream_proxy,
+        })
+        .qname_minimisation(!args.no_qname_minimisation)
+        .timeouts(Timeouts {
+            query: Duration::from_secs(args.query_timeout),
+            resolution: Duration::from_secs(args.resolution_timeout),
+        })
+        .root_hints(root_hints)
+        .nameserver_stats(nameserver_stats.clone())
+        .forward_strategy(args.forward_strategy)
+        .forward_fallback(FallbackPolicy::new(args.forward_transport.clone()))
+        .recursion_scope(recursion_scope(args))
+        .allowlist(allowlist)
+        .zone_precedence(zone_precedence_rules(args))
+        .cache(cache.clone());
+    for address in &args.forward_address {
+        builder = builder.forward(*address);
+    }
+    for url in &args.forward_url {
+        builder = builder.forward(url.clone());
+    }
+    for rule in &args.forward_rule {
+        builder = builder.forward_rule(rule.clone());
+    }
+    for rule in &args.upstream_security {
+        builder = builder.upstream_security(rule.clone());
+    }
+    builder.build()
+}
+
+/// Build the recursion scope from the command-line arguments.
+fn recursion_scope(args: &Args) -> RecursionScope {
+    let mut scope = RecursionScope::new();
+    for domain in &args.recursion_domain {
+        scope.allow(domain.clone());
+    }
+    for domain in &args.no_recursion_domain {
+        scope.deny(domain.clone());
+    }
+    scope
+}
+
+/// Build the local-zone policies from the command-line arguments.
+fn local_zone_policies(args: &Args) -> LocalZonePolicies {
+    let mut policies = LocalZonePolicies::new();
+    for rule in &args.local_zone {
+        policies.insert(rule.clone());
+    }
+    policies
+}
+
+/// Build the zone precedence rules from the command-line arguments.
+fn zone_precedence_rules(args: &Args) -> ZonePrecedenceRules {
+    let mut rules = ZonePrecedenceRules::new(args.zone_precedence);
+    for rule in &args.zone_precedence_rule {
+        rules.insert(rule.clone());
+    }
+    rules
+}
+
+/// Set up logging, returning the handle to change the log filter and format.
+fn begin_logging() -> LogFilter {
+    let (filter_layer, format_layer, log_filter) = LogFilter::from_default_env();
+
+    tracing_subscriber::registry()
+        .with(filter_layer)
+        .with(format_layer)
+        .init();
+
+    if let Ok(Err(error)) = env::var(LOG_FORMAT_ENV).map(|var| var.parse::<LogFormat>()) {
+        tracing::warn!(%error, "ignoring RUST_LOG_FORMAT");
+    }
+
+    log_filter
+}
+
+// the doc comments for this struct turn into the CLI help text
+#[derive(Debug, Parser)]
+/// A simple DNS server for home networks.
+///
+/// resolved supports:
+///
+/// - Three modes of operation: as a recursive or forwarding nameserver (with
 ///   caching) or as an authoritative nameserver for your specified domains
 ///   only.
 ///
 /// - Defining custom records in hosts files (to make existing DNS blacklists
 ///   each to use) and in zone files.
 ///
-/// - Listening on either IPv4 or IPv6, and communicating with upstream
-///   nameservers over both.
+/// - Listening on any number of IPv4 and IPv6 addresses, and communicating
+///   with upstream nameservers over both.
 ///
 /// It is not intended to be a fully-featured internet-facing
 /// nameserver, but just enough to get DNS ad-blocking and nice
@@ -426,14 +1959,86 @@ fn begin_logging() {
 /// "http://{metrics_address}/metrics"
 #[derive(Clone)]
 struct Args {
-    /// Address to listen on (in `ip:port` form)
-    #[clap(short = 'i', long, value_parser, default_value_t = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 53)), env = "RESOLVED_ADDRESS")]
-    address: SocketAddr,
+    /// Path to a TOML configuration file, settings given as arguments or
+    /// environment variables take precedence over those in the file
+    #[clap(short = 'c', long, value_parser, env = "RESOLVED_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on (in `ip:port` form), can be specified more than
+    /// once to listen on several addresses (like separate IPv4 and IPv6
+    /// addresses, or the addresses of specific network interfaces)
+    #[clap(
+        short = 'i',
+        long,
+        value_parser,
+        default_value = "0.0.0.0:53",
+        env = "RESOLVED_ADDRESS"
+    )]
+    address: Vec<SocketAddr>,
 
     /// Address to listen on (in `ip:port` form) to serve Prometheus metrics
     #[clap(long, value_parser, default_value_t = SocketAddr::from((Ipv4Addr::LOCALHOST, 9420)), env = "RESOLVED_METRICS_ADDRESS")]
     metrics_address: SocketAddr,
 
+    /// How many UDP sockets to receive queries on, each with its own task: the
+    /// kernel spreads queries across them, which helps under heavy load.  More
+    /// than one is only supported on unix
+    #[clap(long, value_parser, default_value_t = 1, env = "RESOLVED_UDP_SOCKETS")]
+    udp_sockets: usize,
+
+    /// How many of the most recent queries, and their responses, to keep for
+    /// the admin API.  0 disables this
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 100,
+        env = "RESOLVED_RECENT_QUERIES"
+    )]
+    recent_queries: usize,
+
+    /// How many names, clients, and client and name pairs to count queries
+    /// for, to find the most frequent for the admin API.  The counts are
+    /// approximate, but more accurate the more are counted.  0 disables this
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 1000,
+        env = "RESOLVED_TOP_QUERIES"
+    )]
+    top_queries: usize,
+
+    /// How many of the most queried names, and of the clients making the most
+    /// queries, to export as metrics.  0 disables this
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 0,
+        env = "RESOLVED_TOP_QUERIES_METRICS"
+    )]
+    top_queries_metrics: usize,
+
+    /// How many clients to count queries, blocked queries, and NXDOMAIN
+    /// answers for, for the admin API.  When there are more, the client seen
+    /// least recently is forgotten.  0 disables this
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 1000,
+        env = "RESOLVED_CLIENT_STATS"
+    )]
+    client_stats: usize,
+
+    /// How to identify clients in `--client-stats`: one of 'none' (by
+    /// address), 'truncate' (by /24 or /56 network), or 'hash' (by a hash of
+    /// the address, with a key chosen at startup)
+    #[clap(long, default_value_t = ClientPrivacy::None, value_parser, env = "RESOLVED_CLIENT_STATS_PRIVACY")]
+    client_stats_privacy: ClientPrivacy,
+
+    /// Path to a unix socket (or named pipe, on Windows) to listen on for
+    /// commands from `resolvedctl`, if not given there is no control socket
+    #[clap(long, value_parser, env = "RESOLVED_CONTROL_SOCKET")]
+    control_socket: Option<PathBuf>,
+
     /// Only answer queries for which this server is authoritative: do
     /// not perform recursive or forwarding resolution
     #[clap(
@@ -443,6 +2048,24 @@ struct Args {
     )]
     authoritative_only: bool,
 
+    /// Only perform recursive or forwarding resolution for this domain (and
+    /// its subdomains), can be specified more than once
+    #[clap(long, value_parser, env = "RESOLVED_RECURSION_DOMAINS")]
+    recursion_domain: Vec<DomainName>,
+
+    /// Never perform recursive or forwarding resolution for this domain (and
+    /// its subdomains), even if `--recursion-domain` would allow it, can be
+    /// specified more than once
+    #[clap(long, value_parser, env = "RESOLVED_NO_RECURSION_DOMAINS")]
+    no_recursion_domain: Vec<DomainName>,
+
+    /// Answer questions for a domain (and its subdomains) with a fixed policy,
+    /// rather than from zones, the cache, or upstream nameservers (in
+    /// `domain=policy` form, where the policy is one of 'nxdomain', 'nodata',
+    /// 'refuse', or 'static:ip[,ip...]'), can be specified more than once
+    #[clap(long, value_parser, env = "RESOLVED_LOCAL_ZONES")]
+    local_zone: Vec<LocalZoneRule>,
+
     /// How to choose between connecting to upstream nameservers over IPv4 or
     /// IPv6 when acting as a recursive resolver: one of 'only-v4', 'prefer-v4',
     /// 'prefer-v6', 'only-v6'
@@ -459,11 +2082,148 @@ struct Args {
     )]
     upstream_dns_port: u16,
 
+    /// Send queries to upstream nameservers from this local address, rather
+    /// than letting the operating system choose, can be specified once for
+    /// IPv4 and once for IPv6
+    #[clap(long, value_parser, env = "RESOLVED_OUTBOUND_BIND")]
+    outbound_bind: Vec<IpAddr>,
+
+    /// Send queries to upstream nameservers over TCP through this proxy, given
+    /// as 'socks5://ip:port' or 'http://ip:port'
+    #[clap(long, value_parser, env = "RESOLVED_UPSTREAM_PROXY")]
+    upstream_proxy: Option<UpstreamProxy>,
+
+    /// Send the full question name to every nameserver when acting as a
+    /// recursive resolver, rather than only as much of it as each nameserver
+    /// needs to see (QNAME minimisation)
+    #[clap(
+        long,
+        action(clap::ArgAction::SetTrue),
+        env = "RESOLVED_NO_QNAME_MINIMISATION"
+    )]
+    no_qname_minimisation: bool,
+
+    /// How many seconds to wait for the response to each query sent to an
+    /// upstream nameserver
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 5,
+        env = "RESOLVED_QUERY_TIMEOUT"
+    )]
+    query_timeout: u64,
+
+    /// How many seconds to spend answering a question in total, including
+    /// following CNAMEs and delegations, before giving up with SERVFAIL
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 60,
+        env = "RESOLVED_RESOLUTION_TIMEOUT"
+    )]
+    resolution_timeout: u64,
+
+    /// Answer ANY queries with a single set of records, rather than every
+    /// record for the name (RFC 8482)
+    #[clap(long, action(clap::ArgAction::SetTrue), env = "RESOLVED_MINIMAL_ANY")]
+    minimal_any: bool,
+
+    /// Leave out authority and additional records which aren't needed for the
+    /// answer: the SOA record is only included in negative answers, and
+    /// addresses for MX, NS, and SRV targets are not included
+    #[clap(
+        long,
+        action(clap::ArgAction::SetTrue),
+        env = "RESOLVED_MINIMAL_RESPONSES"
+    )]
+    minimal_responses: bool,
+
+    /// How to order multiple records of the same type for a name in answers,
+    /// so that clients which use the first are spread across all of them: one
+    /// of 'none', 'round-robin' (rotate by one place each time they are
+    /// answered), or 'random'
+    #[clap(long, default_value_t = AnswerRotation::None, value_parser, env = "RESOLVED_ANSWER_ROTATION")]
+    answer_rotation: AnswerRotation,
+
+    /// Answer messages with more than one question, by answering each question
+    /// independently and merging the answers, rather than refusing them
+    #[clap(
+        long,
+        action(clap::ArgAction::SetTrue),
+        env = "RESOLVED_MULTIPLE_QUESTIONS"
+    )]
+    multiple_questions: bool,
+
     /// Act as a forwarding resolver, not a recursive resolver:
     /// forward queries which can't be answered from local state to
-    /// this nameserver (in `ip:port` form) and cache the result
+    /// this nameserver (in `ip:port` form) and cache the result, can
+    /// be specified more than once
     #[clap(short, long, value_parser, env = "RESOLVED_FORWARD_ADDRESS")]
-    forward_address: Option<SocketAddr>,
+    forward_address: Vec<SocketAddr>,
+
+    /// Like `--forward-address`, but forward queries to this DNS-over-HTTPS
+    /// (RFC 8484) nameserver (in `https://host[:port]/path` form), can be
+    /// specified more than once
+    #[clap(long, value_parser, env = "RESOLVED_FORWARD_URLS")]
+    forward_url: Vec<HttpsUrl>,
+
+    /// How to use multiple forwarding nameservers: one of 'failover' (try
+    /// each in turn) or 'race' (query all at once and use the first answer)
+    #[clap(long, default_value_t = ForwardingStrategy::Failover, value_parser, env = "RESOLVED_FORWARD_STRATEGY")]
+    forward_strategy: ForwardingStrategy,
+
+    /// Which transport to use for forwarding nameservers given with
+    /// `--forward-address`: one of 'udp', 'tcp', 'tls' (DNS over TLS), or
+    /// 'quic' (DNS over QUIC), can be specified more than once to try each in
+    /// turn until one works [default: udp, then tcp]
+    #[clap(long, value_parser, env = "RESOLVED_FORWARD_TRANSPORTS")]
+    forward_transport: Vec<TransportKind>,
+
+    /// Forward queries for a domain (and its subdomains) to a specific
+    /// nameserver, overriding `--forward-address` (in `domain=ip:port` form),
+    /// or resolve them recursively (in `domain=recurse` form), can be
+    /// specified more than once
+    #[clap(short = 'F', long, value_parser, env = "RESOLVED_FORWARD_RULES")]
+    forward_rule: Vec<ForwardingRule>,
+
+    /// Whether queries to a forwarding nameserver (by IP address, or by host
+    /// for `--forward-url`) must be encrypted ('strict') or only should be
+    /// ('opportunistic'), and how to authenticate it: by a certificate for
+    /// `name`, or by the base64 SHA-256 hash of its public key (in
+    /// `host=policy[,name=domain][,pin=hash]` form), can be specified more
+    /// than once
+    #[clap(long, value_parser, env = "RESOLVED_UPSTREAM_SECURITY_RULES")]
+    upstream_security: Vec<UpstreamSecurityRule>,
+
+    /// Send the client subnet (RFC 7871) of queries which have one to
+    /// forwarding nameservers, truncated to `--client-subnet-ipv4-prefix` or
+    /// `--client-subnet-ipv6-prefix` bits, rather than stripping it
+    #[clap(
+        long,
+        action(clap::ArgAction::SetTrue),
+        env = "RESOLVED_FORWARD_CLIENT_SUBNET"
+    )]
+    forward_client_subnet: bool,
+
+    /// How many bits of an IPv4 client subnet to forward, if
+    /// `--forward-client-subnet` is given
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u8).range(0..=32),
+        default_value_t = 24,
+        env = "RESOLVED_CLIENT_SUBNET_IPV4_PREFIX"
+    )]
+    client_subnet_ipv4_prefix: u8,
+
+    /// How many bits of an IPv6 client subnet to forward, if
+    /// `--forward-client-subnet` is given
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u8).range(0..=128),
+        default_value_t = 56,
+        env = "RESOLVED_CLIENT_SUBNET_IPV6_PREFIX"
+    )]
+    client_subnet_ipv6_prefix: u8,
 
     /// How many records to hold in the cache
     #[clap(
@@ -475,6 +2235,145 @@ struct Args {
     )]
     cache_size: usize,
 
+    /// How to choose which records to prune when the cache is too big: one of
+    /// 'lru' (least recently used), 'lfu' (least frequently used), or 'ttl'
+    /// (soonest to expire)
+    #[clap(long, default_value_t = CachePolicy::Lru, value_parser, env = "RESOLVED_CACHE_POLICY")]
+    cache_policy: CachePolicy,
+
+    /// Give records from upstream nameservers a TTL of at least this many
+    /// seconds, in the cache and in answers
+    #[clap(long, value_parser, env = "RESOLVED_MIN_TTL")]
+    min_ttl: Option<u32>,
+
+    /// Give records from upstream nameservers a TTL of at most this many
+    /// seconds, in the cache and in answers
+    #[clap(long, value_parser, env = "RESOLVED_MAX_TTL")]
+    max_ttl: Option<u32>,
+
+    /// Also apply --min-ttl and --max-ttl to answers from authoritative zones
+    #[clap(
+        long,
+        action(clap::ArgAction::SetTrue),
+        env = "RESOLVED_CLAMP_AUTHORITATIVE_TTLS"
+    )]
+    clamp_authoritative_ttls: bool,
+
+    /// If resolving a question fails because upstream nameservers can't be
+    /// reached, answer with the last successful answer (with a short TTL) if it
+    /// is no older than this many seconds.  0 disables this
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 0,
+        env = "RESOLVED_LAST_KNOWN_GOOD_MAX_AGE"
+    )]
+    last_known_good_max_age: u64,
+
+    /// How many queries per second to answer from each client /24 (IPv4) or
+    /// /56 (IPv6) network, with bursts of up to one second's worth allowed.  0
+    /// disables this
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 0,
+        env = "RESOLVED_CLIENT_RATE_LIMIT"
+    )]
+    client_rate_limit: u32,
+
+    /// How many queries per second to answer in total, with bursts of up to one
+    /// second's worth allowed.  0 disables this
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 0,
+        env = "RESOLVED_GLOBAL_RATE_LIMIT"
+    )]
+    global_rate_limit: u32,
+
+    /// What to do with queries which go over a rate limit: one of 'refused'
+    /// (answer with REFUSED) or 'drop' (do not answer)
+    #[clap(long, default_value_t = RateLimitAction::Refused, value_parser, env = "RESOLVED_RATE_LIMIT_ACTION")]
+    rate_limit_action: RateLimitAction,
+
+    /// How many identical UDP responses per second to send to each /24 (IPv4)
+    /// or /56 (IPv6) network, to avoid being used to amplify traffic at a
+    /// spoofed address.  0 disables this
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 0,
+        env = "RESOLVED_RESPONSE_RATE_LIMIT"
+    )]
+    response_rate_limit: u32,
+
+    /// When over the response rate limit, send an empty truncated response
+    /// (telling the client to retry over TCP) instead of every this-many
+    /// dropped responses.  0 drops them all
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 2,
+        env = "RESOLVED_RESPONSE_RATE_LIMIT_SLIP"
+    )]
+    response_rate_limit_slip: u32,
+
+    /// Only answer UDP queries which have a valid DNS cookie (RFC 7873), so
+    /// that spoofed queries get nothing useful: queries with no cookie get an
+    /// empty truncated response, and queries with a bad cookie get BADCOOKIE
+    #[clap(
+        long,
+        action(clap::ArgAction::SetTrue),
+        env = "RESOLVED_REQUIRE_COOKIES"
+    )]
+    require_cookies: bool,
+
+    /// How many queries to answer at once.  Further queries are shed, with
+    /// `--overload-action`, rather than waiting.  0 disables this
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 0,
+        env = "RESOLVED_MAX_IN_FLIGHT"
+    )]
+    max_in_flight: usize,
+
+    /// How many seconds to spend answering a query before shedding it, with
+    /// `--overload-action`.  0 disables this
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 0,
+        env = "RESOLVED_REQUEST_TIMEOUT"
+    )]
+    request_timeout: u64,
+
+    /// What to do with queries which are shed because of `--max-in-flight` or
+    /// `--request-timeout`: one of 'servfail' (answer with SERVFAIL) or 'drop'
+    /// (do not answer)
+    #[clap(long, default_value_t = OverloadAction::ServFail, value_parser, env = "RESOLVED_OVERLOAD_ACTION")]
+    overload_action: OverloadAction,
+
+    /// How many TCP connections to have open at once.  Further connections
+    /// are closed straight away.  0 disables this
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 1000,
+        env = "RESOLVED_MAX_TCP_CONNECTIONS"
+    )]
+    max_tcp_connections: usize,
+
+    /// How many seconds to keep a TCP connection open while waiting for the
+    /// next query on it, or for the client to read a response
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 10,
+        env = "RESOLVED_TCP_IDLE_TIMEOUT"
+    )]
+    tcp_idle_timeout: u64,
+
     /// Path to a hosts file, can be specified more than once
     #[clap(short = 'a', long, value_parser, env = "RESOLVED_HOSTS_FILES")]
     hosts_file: Vec<PathBuf>,
@@ -483,6 +2382,35 @@ struct Args {
     #[clap(short = 'A', long, value_parser, env = "RESOLVED_HOSTS_DIRS")]
     hosts_dir: Vec<PathBuf>,
 
+    /// URL to download a hosts file (such as a blocklist) from, over HTTP or
+    /// HTTPS, into `--hosts-url-dir`, can be specified more than once
+    #[clap(long, value_parser, env = "RESOLVED_HOSTS_URLS")]
+    hosts_url: Vec<HostsUrl>,
+
+    /// Path to a directory to keep the hosts files downloaded from
+    /// `--hosts-url` in, required if `--hosts-url` is given
+    #[clap(long, value_parser, env = "RESOLVED_HOSTS_URL_DIR")]
+    hosts_url_dir: Option<PathBuf>,
+
+    /// How often, in seconds, to download the `--hosts-url` files again if
+    /// they have changed, plus up to 10% at random.  The minimum is 60
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u64).range(MIN_REFRESH_INTERVAL..),
+        default_value_t = 86400,
+        env = "RESOLVED_REFRESH_INTERVAL"
+    )]
+    refresh_interval: u64,
+
+    /// Also reload the hosts and zone files every `--refresh-interval`
+    /// seconds, picking up any which have changed
+    #[clap(
+        long,
+        action(clap::ArgAction::SetTrue),
+        env = "RESOLVED_REFRESH_LOCAL_FILES"
+    )]
+    refresh_local_files: bool,
+
     /// Path to a zone file, can be specified more than once
     #[clap(short = 'z', long, value_parser, env = "RESOLVED_ZONE_FILES")]
     zone_file: Vec<PathBuf>,
@@ -490,64 +2418,488 @@ struct Args {
     /// Path to a directory to read zone files from, can be specified more than once
     #[clap(short = 'Z', long, value_parser, env = "RESOLVED_ZONE_FILES")]
     zones_dir: Vec<PathBuf>,
+
+    /// Path to a directory of authoritative zone files named after their apex
+    /// (like 'example.com.zone'), can be specified more than once.  A file
+    /// which cannot be loaded is skipped, rather than stopping the others from
+    /// being served
+    #[clap(long, value_parser, env = "RESOLVED_ZONES_DIRS_AUTO")]
+    zones_dir_auto: Vec<PathBuf>,
+
+    /// Add a PTR record for the address of every A and AAAA record in the hosts
+    /// and zone files, unless there already is one
+    #[clap(
+        long,
+        action(clap::ArgAction::SetTrue),
+        env = "RESOLVED_SYNTHESISE_PTR"
+    )]
+    synthesise_ptr: bool,
+
+    /// Store the A and AAAA records from the hosts files in a more compact
+    /// form, which uses much less memory for very large hosts files (such as
+    /// blocklists)
+    #[clap(long, action(clap::ArgAction::SetTrue), env = "RESOLVED_COMPACT_HOSTS")]
+    compact_hosts: bool,
+
+    /// TTL (in seconds) of the records from the hosts files
+    #[clap(long, value_parser, default_value_t = HOSTS_TTL, env = "RESOLVED_HOSTS_TTL")]
+    hosts_ttl: u32,
+
+    /// Flatten CNAME chains in the zone with this apex, answering with the
+    /// records at the end of the chain renamed to the name which was asked
+    /// about, if the chain can be followed using only the hosts and zone files
+    /// and the cache, can be specified more than once
+    #[clap(long, value_parser, env = "RESOLVED_FLATTEN_CNAMES")]
+    flatten_cnames: Vec<DomainName>,
+
+    /// Skip hosts and zone files (and directories of them) which can't be read
+    /// or parsed, with a warning, rather than refusing to start (or reload)
+    #[clap(
+        long,
+        action(clap::ArgAction::SetTrue),
+        env = "RESOLVED_SKIP_BAD_FILES"
+    )]
+    skip_bad_files: bool,
+
+    /// How to answer A and AAAA queries for domains which the hosts or zone
+    /// files map to 0.0.0.0 or ::, such as those in blocklists: one of
+    /// 'address' (with that address), 'nxdomain', or 'nodata' (with no records)
+    #[clap(long, default_value_t = BlockedResponse::Address, value_parser, env = "RESOLVED_BLOCKED_RESPONSE")]
+    blocked_response: BlockedResponse,
+
+    /// How to combine the records in hosts files, and zone files without an
+    /// SOA record, with records from the cache and upstream nameservers: one
+    /// of 'merge' (records in the files override records of the same type, but
+    /// other types and names are resolved as normal), 'override' (names in the
+    /// files are only answered from them), or 'exclusive' (names which are not
+    /// in the files get a name error too)
+    #[clap(long, default_value_t = ZonePrecedence::Merge, value_parser, env = "RESOLVED_ZONE_PRECEDENCE")]
+    zone_precedence: ZonePrecedence,
+
+    /// Use a different `--zone-precedence` for a domain (and its subdomains)
+    /// (in `domain=precedence` form, eg 'lan.=exclusive'), can be specified
+    /// more than once
+    #[clap(long, value_parser, env = "RESOLVED_ZONE_PRECEDENCE_RULES")]
+    zone_precedence_rule: Vec<ZonePrecedenceRule>,
+
+    /// Answer questions for this domain (and its subdomains) straight from the
+    /// authoritative zone files, without consulting the cache or upstream
+    /// nameservers, can be specified more than once
+    #[clap(long, value_parser, env = "RESOLVED_FAST_PATH_ZONES")]
+    fast_path_zone: Vec<DomainName>,
+
+    /// Never block this domain (or its subdomains), even if the hosts or zone
+    /// files map it to 0.0.0.0 or ::, can be specified more than once
+    #[clap(long, value_parser, env = "RESOLVED_ALLOW_DOMAINS")]
+    allow_domain: Vec<DomainName>,
+
+    /// Path to a file of domains to never block, one per line, can be specified
+    /// more than once
+    #[clap(long, value_parser, env = "RESOLVED_ALLOWLIST_FILES")]
+    allowlist_file: Vec<PathBuf>,
+
+    /// Reload the hosts and zone files automatically when they, or the
+    /// directories they are read from, change
+    #[clap(long, action(clap::ArgAction::SetTrue), env = "RESOLVED_WATCH")]
+    watch: bool,
+
+    /// Path to a root hints file, giving the root nameservers to start
+    /// recursive resolution from, if not given the built-in root hints are used
+    #[clap(long, value_parser, env = "RESOLVED_ROOT_HINTS")]
+    root_hints: Option<PathBuf>,
+
+    /// Path to a file of questions to resolve at startup, and after the cache
+    /// is flushed, so that popular names are already cached: one question per
+    /// line, as a domain name optionally followed by a query type (which
+    /// defaults to A)
+    #[clap(long, value_parser, env = "RESOLVED_PRIME_FILE")]
+    prime_file: Option<PathBuf>,
+
+    /// Tokens for the admin API, which can only be set in the configuration
+    /// file
+    #[clap(skip)]
+    admin_tokens: Vec<AdminToken>,
 }
 
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let cli_args = match Args::from_arg_matches(&matches) {
+        Ok(args) => args,
+        Err(error) => error.exit(),
+    };
+
+    let log_filter = begin_logging();
 
-    begin_logging();
+    let Some(args) = load_args(&cli_args, &matches).await else {
+        tracing::error!("could not load configuration");
+        process::exit(1);
+    };
+
+    if !args.hosts_url.is_empty() {
+        let Some(dir) = &args.hosts_url_dir else {
+            tracing::error!("--hosts-url needs --hosts-url-dir");
+            process::exit(1);
+        };
+        download_missing_hosts_files(&Downloader::new(), &args.hosts_url, dir).await;
+    }
 
-    let zones = match load_zone_configuration(
-        &args.hosts_file,
+    let load_start = Instant::now();
+    let zone_files = ZoneFiles::load(
+        &hosts_files(&args),
         &args.hosts_dir,
         &args.zone_file,
         &args.zones_dir,
+        &args.zones_dir_auto,
+        args.synthesise_ptr,
+        args.compact_hosts,
+        args.hosts_ttl,
+        &args.flatten_cnames,
+        args.skip_bad_files,
     )
-    .await
-    {
-        Some(zs) => zs,
-        None => {
+    .await;
+    ZONE_LOAD_DURATION_SECONDS
+        .with_label_values(&["startup"])
+        .observe(load_start.elapsed().as_secs_f64());
+    let (zone_files, zones) = match zone_files {
+        Ok(zs) => zs,
+        Err(errors) => {
+            for error in errors {
+                tracing::error!(%error, "could not load hosts or zone file");
+            }
             tracing::error!("could not load configuration");
             process::exit(1);
         }
     };
+    SKIPPED_FILES.set(zone_files.skipped().try_into().unwrap_or(i64::MAX));
+    warn_about_cname_loops(&zones);
 
-    tracing::info!(address = %args.address, "binding DNS UDP socket");
-    let udp = match UdpSocket::bind(args.address).await {
-        Ok(s) => s,
-        Err(error) => {
-            tracing::error!(?error, "could not bind DNS UDP socket");
-            process::exit(1);
-        }
+    let Some(root_hints) = load_root_hints(args.root_hints.as_deref()).await else {
+        tracing::error!("could not load configuration");
+        process::exit(1);
     };
 
-    tracing::info!(address = %args.address, "binding DNS TCP socket");
-    let tcp = match TcpListener::bind(args.address).await {
-        Ok(s) => s,
-        Err(error) => {
-            tracing::error!(?error, "could not bind DNS TCP socket");
-            process::exit(1);
+    let Some(allowlist) = load_allowlist(&args.allow_domain, &args.allowlist_file).await else {
+        tracing::error!("could not load configuration");
+        process::exit(1);
+    };
+
+    let mut udp_sockets = Vec::new();
+    let mut tcp_listeners = Vec::new();
+    for address in &args.address {
+        let only_v6 = only_v6(*address, &args.address);
+
+        tracing::info!(%address, count = %args.udp_sockets, "binding DNS UDP sockets");
+        match bind_udp_sockets(*address, args.udp_sockets, only_v6) {
+            Ok(s) => udp_sockets.extend(s),
+            Err(error) => {
+                tracing::error!(%address, ?error, "could not bind DNS UDP socket");
+                process::exit(1);
+            }
+        }
+
+        tracing::info!(%address, "binding DNS TCP socket");
+        match bind_tcp_listener(*address, only_v6) {
+            Ok(s) => tcp_listeners.push(s),
+            Err(error) => {
+                tracing::error!(%address, ?error, "could not bind DNS TCP socket");
+                process::exit(1);
+            }
         }
+    }
+
+    let control_listener = if let Some(path) = &args.control_socket {
+        tracing::info!(?path, "binding control socket");
+        match ControlListener::bind(path) {
+            Ok(listener) => Some(listener),
+            Err(error) => {
+                tracing::error!(?error, "could not bind control socket");
+                process::exit(1);
+            }
+        }
+    } else {
+        None
     };
 
+    let served_zones = ServedZones::new(zones);
+    let admin_tokens = Arc::new(RwLock::new(args.admin_tokens.clone()));
+    let cache = SharedCache::with_policy(std::cmp::max(1, args.cache_size), args.cache_policy);
+    cache.set_ttl_limits(ttl_limits(&args));
+    let nameserver_stats = NameserverStats::new();
     let listen_args = ListenArgs {
-        authoritative_only: args.authoritative_only,
-        protocol_mode: args.protocol_mode,
-        upstream_dns_port: args.upstream_dns_port,
-        forward_address: args.forward_address,
-        zones_lock: Arc::new(RwLock::new(zones)),
-        cache: SharedCache::with_desired_size(std::cmp::max(1, args.cache_size)),
+        settings: Arc::new(RwLock::new(Arc::new(Settings::from_args(
+            &args,
+            root_hints,
+            allowlist,
+            &cache,
+            &nameserver_stats,
+        )))),
+        zones: served_zones.current.clone(),
+        cache,
+        last_known_good: LastKnownGood::new(
+            Duration::from_secs(args.last_known_good_max_age),
+            std::cmp::max(1, args.cache_size),
+        ),
+        nameserver_stats,
+        rate_limiter: RateLimiter::new(args.client_rate_limit, args.global_rate_limit),
+        response_rate_limiter: ResponseRateLimiter::new(
+            args.response_rate_limit,
+            args.response_rate_limit_slip,
+        ),
+        server_cookies: ServerCookies::new(),
+        in_flight: InFlight::new(),
+        tcp_connections: TcpConnections::new(),
+        rotation_counts: RotationCounts::new(),
+        recent_queries: RecentQueries::new(args.recent_queries),
+        top_queries: TopQueries::new(args.top_queries, args.top_queries_metrics),
+        client_stats: ClientStats::new(args.client_stats, args.client_stats_privacy),
     };
 
-    tokio::spawn(listen_tcp_task(listen_args.clone(), tcp));
-    tokio::spawn(listen_udp_task(listen_args.clone(), udp));
-    tokio::spawn(reload_task(listen_args.zones_lock.clone(), args.clone()));
-    tokio::spawn(prune_cache_task(listen_args.cache));
+    if let Err(error) = prometheus::register(Box::new(CacheStatsCollector::new(
+        listen_args.cache.clone(),
+    ))) {
+        tracing::warn!(?error, "could not register cache statistics metrics");
+    }
+    if let Err(error) = prometheus::register(Box::new(TopQueriesCollector::new(
+        listen_args.top_queries.clone(),
+    ))) {
+        tracing::warn!(?error, "could not register top queries metrics");
+    }
+
+    let reload = Arc::new(Notify::new());
+    let prime_cache = Arc::new(Notify::new());
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (in_flight_tx, mut in_flight_rx) = mpsc::channel::<()>(1);
+    let tcp_tasks: Vec<_> = tcp_listeners
+        .into_iter()
+        .map(|tcp| {
+            tokio::spawn(listen_tcp_task(
+                listen_args.clone(),
+                tcp,
+                shutdown_rx.clone(),
+                in_flight_tx.clone(),
+            ))
+        })
+        .collect();
+    drop(in_flight_tx);
+    let udp_tasks: Vec<_> = udp_sockets
+        .into_iter()
+        .map(|udp| {
+            tokio::spawn(listen_udp_task(
+                listen_args.clone(),
+                udp,
+                shutdown_rx.clone(),
+            ))
+        })
+        .collect();
+    tokio::spawn(reload_task(ReloadArgs {
+        cli_args,
+        matches,
+        address: args.address.clone(),
+        udp_sockets: args.udp_sockets,
+        metrics_address: args.metrics_address,
+        control_socket: args.control_socket.clone(),
+        settings: listen_args.settings.clone(),
+        served_zones: served_zones.clone(),
+        cache: listen_args.cache.clone(),
+        nameserver_stats: listen_args.nameserver_stats.clone(),
+        recent_queries: listen_args.recent_queries.clone(),
+        top_queries: listen_args.top_queries.clone(),
+        client_stats: listen_args.client_stats.clone(),
+        reload: reload.clone(),
+        last_known_good: listen_args.last_known_good.clone(),
+        rate_limiter: listen_args.rate_limiter.clone(),
+        response_rate_limiter: listen_args.response_rate_limiter.clone(),
+        admin_tokens: admin_tokens.clone(),
+        zone_files,
+        watcher: update_file_watcher(None, &args),
+    }));
+    tokio::spawn(toggle_debug_logging_task(log_filter.clone()));
+    if let Some(listener) = control_listener {
+        tokio::spawn(control::serve(
+            listener,
+            ControlState {
+                zones: served_zones.clone(),
+                log_filter: log_filter.clone(),
+                cache: listen_args.cache.clone(),
+                reload: reload.clone(),
+                prime_cache: prime_cache.clone(),
+                top_queries: listen_args.top_queries.clone(),
+                recent_queries: listen_args.recent_queries.clone(),
+            },
+        ));
+    }
+    let recurses = (args.forward_address.is_empty() && args.forward_url.is_empty())
+        || args.forward_rule.iter().any(|rule| rule.address.is_none());
+    if !args.authoritative_only && recurses {
+        tokio::spawn(prime_root_hints_task(
+            listen_args.settings.clone(),
+            listen_args.cache.clone(),
+        ));
+    }
+    tokio::spawn(prime_cache_task(listen_args.clone(), prime_cache.clone()));
+    tokio::spawn(refresh_task(listen_args.settings.clone(), reload.clone()));
+    let query_args = listen_args.clone();
+    let cache = listen_args.cache.clone();
+    let recent_queries = listen_args.recent_queries.clone();
+    let top_queries = listen_args.top_queries.clone();
+    let client_stats = listen_args.client_stats.clone();
+    tokio::spawn(prune_cache_task(
+        listen_args.cache,
+        listen_args.last_known_good,
+        listen_args.nameserver_stats,
+        listen_args.rate_limiter,
+        listen_args.response_rate_limiter,
+    ));
 
     tracing::info!(address = %args.metrics_address, "binding HTTP TCP socket");
-    if let Err(error) = serve_prometheus_endpoint_task(args.metrics_address).await {
-        tracing::error!(?error, "could not bind HTTP TCP socket");
-        process::exit(1);
+    let query_handler: QueryHandler = Arc::new(move |query| {
+        let query_args = query_args.clone();
+        Box::pin(async move { resolve_and_build_response(query_args, query).await.0 })
+    });
+    let admin_routes = admin::router(AdminState {
+        tokens: admin_tokens,
+        zones: served_zones,
+        log_filter,
+        resolver: ResolverHandle::spawn(query_handler),
+        cache,
+        recent_queries,
+        top_queries,
+        client_stats,
+        reload,
+        prime_cache,
+    });
+    let span = tokio::select! {
+        result = serve_prometheus_endpoint_task(args.metrics_address, admin_routes) => {
+            if let Err(error) = result {
+                tracing::error!(?error, "could not bind HTTP TCP socket");
+            }
+            process::exit(1);
+        }
+        span = wait_for_shutdown_signal() => span,
+    };
+
+    // stop accepting new queries, and give the ones already being processed
+    // some time to finish
+    span.in_scope(|| tracing::info!("received - shutting down"));
+    let start = Instant::now();
+    _ = shutdown_tx.send(true);
+    let drained = timeout(SHUTDOWN_GRACE_PERIOD, async {
+        for task in tcp_tasks.into_iter().chain(udp_tasks) {
+            _ = task.await;
+        }
+        // resolves once every TCP connection task has dropped its sender
+        in_flight_rx.recv().await;
+    })
+    .await;
+
+    if let Some(path) = &args.control_socket {
+        _ = std::fs::remove_file(path);
+    }
+
+    if drained.is_ok() {
+        span.in_scope(
+            || tracing::info!(duration_seconds = %start.elapsed().as_secs_f64(), "done - success"),
+        );
+    } else {
+        span.in_scope(|| {
+            tracing::warn!(duration_seconds = %start.elapsed().as_secs_f64(), "done - timed out waiting for queries to finish");
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(cli: &[&str], config: &str) -> Args {
+        let matches = Args::command()
+            .try_get_matches_from([&["resolved"], cli].concat())
+            .unwrap();
+        let args = Args::from_arg_matches(&matches).unwrap();
+        merge_config(&args, &matches, Config::deserialise(config).unwrap())
+    }
+
+    #[test]
+    fn merge_config_uses_config_for_default_settings() {
+        let args = merge(&[], "udp-sockets = 4\nauthoritative-only = true");
+
+        assert_eq!(4, args.udp_sockets);
+        assert!(args.authoritative_only);
+    }
+
+    #[test]
+    fn merge_config_prefers_explicit_arguments() {
+        let args = merge(
+            &["--udp-sockets", "2"],
+            "udp-sockets = 4\nauthoritative-only = true",
+        );
+
+        assert_eq!(2, args.udp_sockets);
+        assert!(args.authoritative_only);
+    }
+
+    #[test]
+    fn merge_config_explicit_argument_equal_to_default_wins() {
+        let args = merge(&["--udp-sockets", "1"], "udp-sockets = 4");
+
+        assert_eq!(1, args.udp_sockets);
+    }
+
+    #[test]
+    fn merge_config_concatenates_lists_config_first() {
+        let args = merge(
+            &["-f", "9.9.9.9:53", "-z", "cli.zone"],
+            r#"
+            forward-addresses = ["1.1.1.1:53", "8.8.8.8:53"]
+            zone-files = ["config.zone"]
+            "#,
+        );
+
+        assert_eq!(
+            vec![
+                "1.1.1.1:53".parse::<SocketAddr>().unwrap(),
+                "8.8.8.8:53".parse().unwrap(),
+                "9.9.9.9:53".parse().unwrap(),
+            ],
+            args.forward_address
+        );
+        assert_eq!(
+            vec![PathBuf::from("config.zone"), PathBuf::from("cli.zone")],
+            args.zone_file
+        );
+    }
+
+    #[test]
+    fn merge_config_replaces_default_address() {
+        let args = merge(&[], "addresses = [\"127.0.0.1:5353\"]");
+
+        assert_eq!(
+            vec!["127.0.0.1:5353".parse::<SocketAddr>().unwrap()],
+            args.address
+        );
+    }
+
+    #[test]
+    fn merge_config_prepends_to_explicit_address() {
+        let args = merge(&["-i", "[::1]:53"], "addresses = [\"127.0.0.1:5353\"]");
+
+        assert_eq!(
+            vec![
+                "127.0.0.1:5353".parse::<SocketAddr>().unwrap(),
+                "[::1]:53".parse().unwrap(),
+            ],
+            args.address
+        );
+    }
+
+    #[test]
+    fn merge_config_keeps_default_address_without_config() {
+        let args = merge(&[], "");
+
+        assert_eq!(
+            vec!["0.0.0.0:53".parse::<SocketAddr>().unwrap()],
+            args.address
+        );
     }
 }
@@ -1,11 +1,22 @@
 use axum::{http::StatusCode, routing};
 use lazy_static::lazy_static;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
 use prometheus::{
     opts, register_histogram_vec, register_int_counter, register_int_counter_vec,
-    register_int_gauge, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+    register_int_gauge, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder,
 };
 use std::net::SocketAddr;
 
+use dns_resolver::cache::SharedCache;
+use dns_resolver::metrics::{Metrics, ZoneHitKind};
+use dns_resolver::util::types::{LocalZonePolicy, ResolutionError, UpstreamError};
+use dns_types::protocol::deserialise;
+use dns_types::protocol::types::Question;
+
+use crate::top::TopQueries;
+
 pub const RESPONSE_TIME_BUCKETS: &[f64] = &[
     0.0001, // 0.1 ms
     0.0005, // 0.5 ms
@@ -28,6 +39,16 @@ pub const RESPONSE_TIME_BUCKETS: &[f64] = &[
 // get more granularity on the lower end
 pub const PROCESSING_TIME_BUCKETS: &[f64] = RESPONSE_TIME_BUCKETS;
 
+pub const QUESTION_NAME_LENGTH_BUCKETS: &[f64] =
+    &[8.0, 16.0, 24.0, 32.0, 48.0, 64.0, 96.0, 128.0, 192.0, 255.0];
+
+pub const QUESTION_LABEL_COUNT_BUCKETS: &[f64] = &[
+    0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0, 10.0, 16.0, 32.0, 127.0,
+];
+
+pub const LOAD_DURATION_BUCKETS: &[f64] =
+    &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
 pub const REFUSED_FOR_MULTIPLE_QUESTIONS: &str = "multiple_questions";
 pub const REFUSED_FOR_UNKNOWN_QTYPE_OR_QCLASS: &str = "unknown_qtype_or_qclass";
 
@@ -48,6 +69,95 @@ lazy_static! {
         &["reason"]
     )
     .unwrap();
+    pub static ref DNS_REQUESTS_RATE_LIMITED_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "dns_requests_rate_limited_total",
+            "Total number of DNS requests which went over a rate limit."
+        ),
+        &["limit", "action"]
+    )
+    .unwrap();
+    pub static ref DNS_REQUESTS_COOKIE_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "dns_requests_cookie_total",
+            "Total number of DNS requests by the state of their DNS cookie."
+        ),
+        &["status"]
+    )
+    .unwrap();
+    pub static ref DNS_REQUESTS_OVERLOADED_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "dns_requests_overloaded_total",
+            "Total number of DNS requests which were shed because the server was overloaded."
+        ),
+        &["reason", "action"]
+    )
+    .unwrap();
+    pub static ref DNS_REQUESTS_IN_FLIGHT: IntGauge = register_int_gauge!(opts!(
+        "dns_requests_in_flight",
+        "Number of DNS requests currently being answered."
+    ))
+    .unwrap();
+    pub static ref DNS_TCP_CONNECTIONS: IntGauge = register_int_gauge!(opts!(
+        "dns_tcp_connections",
+        "Number of open DNS TCP connections."
+    ))
+    .unwrap();
+    pub static ref DNS_TCP_CONNECTIONS_CLOSED_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "dns_tcp_connections_closed_total",
+            "Total number of DNS TCP connections closed, by reason."
+        ),
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref RATE_LIMIT_CLIENTS: IntGauge = register_int_gauge!(opts!(
+        "rate_limit_clients",
+        "Number of client networks being tracked by the per-client rate limit."
+    ))
+    .unwrap();
+    pub static ref DNS_RESPONSES_RATE_LIMITED_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "dns_responses_rate_limited_total",
+            "Total number of UDP responses which went over the response rate limit."
+        ),
+        &["action"]
+    )
+    .unwrap();
+    pub static ref RESPONSE_RATE_LIMIT_ENTRIES: IntGauge = register_int_gauge!(opts!(
+        "response_rate_limit_entries",
+        "Number of distinct responses being tracked by the response rate limit."
+    ))
+    .unwrap();
+    pub static ref DNS_REQUESTS_MALFORMED_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "dns_requests_malformed_total",
+            "Total number of DNS requests which could not be parsed."
+        ),
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref DNS_RESOLUTION_ERRORS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "dns_resolution_errors_total",
+            "Total number of DNS questions which could not be answered."
+        ),
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref DNS_LOCAL_ZONE_POLICY_ANSWERS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "dns_local_zone_policy_answers_total",
+            "Total number of DNS questions answered by a local-zone policy."
+        ),
+        &["policy"]
+    )
+    .unwrap();
+    pub static ref DNS_FAST_PATH_ANSWERS_TOTAL: IntCounter = register_int_counter!(opts!(
+        "dns_fast_path_answers_total",
+        "Total number of DNS questions answered from a fast-path zone."
+    ))
+    .unwrap();
     pub static ref DNS_RESPONSES_TOTAL: IntCounterVec = register_int_counter_vec!(
         opts!("dns_responses_total", "Total number of DNS responses sent."),
         &["aa", "tc", "rd", "ra", "rcode"]
@@ -68,6 +178,20 @@ lazy_static! {
         &["rd", "qtype", "qclass"]
     )
     .unwrap();
+    pub static ref DNS_QUESTION_NAME_LENGTH_OCTETS: HistogramVec = register_histogram_vec!(
+        "dns_question_name_length_octets",
+        "Length of the domain name in DNS questions, in its wire format.",
+        &["qtype"],
+        QUESTION_NAME_LENGTH_BUCKETS.to_vec()
+    )
+    .unwrap();
+    pub static ref DNS_QUESTION_LABEL_COUNT: HistogramVec = register_histogram_vec!(
+        "dns_question_label_count",
+        "Number of labels in the domain name in DNS questions, not counting the root.",
+        &["qtype"],
+        QUESTION_LABEL_COUNT_BUCKETS.to_vec()
+    )
+    .unwrap();
     pub static ref DNS_QUESTION_PROCESSING_TIME_SECONDS: HistogramVec = register_histogram_vec!(
         "dns_question_processing_time_seconds",
         "Time spent processing a DNS question (a request may have multiple questions).",
@@ -90,6 +214,11 @@ lazy_static! {
         "Total number of queries which have been blocked."
     ),)
     .unwrap();
+    pub static ref DNS_RESOLVER_ALLOWLISTED_TOTAL: IntCounter = register_int_counter!(opts!(
+        "dns_resolver_allowlisted_total",
+        "Total number of queries which would have been blocked, but are in the allowlist."
+    ),)
+    .unwrap();
     pub static ref DNS_RESOLVER_CACHE_HIT_TOTAL: IntCounter = register_int_counter!(opts!(
         "dns_resolver_cache_hit_total",
         "Total number of cache hits."
@@ -110,8 +239,49 @@ lazy_static! {
         "Total number of misses when calling an upstream nameserver."
     ),)
     .unwrap();
+    pub static ref DNS_RESOLVER_NAMESERVER_LAME_TOTAL: IntCounter = register_int_counter!(opts!(
+        "dns_resolver_nameserver_lame_total",
+        "Total number of upstream nameserver addresses held down for being lame (REFUSED, FORMERR, or not authoritative for the zone)."
+    ),)
+    .unwrap();
+    pub static ref DNS_RESOLVER_NAMESERVER_LAME_SKIPPED_TOTAL: IntCounter = register_int_counter!(opts!(
+        "dns_resolver_nameserver_lame_skipped_total",
+        "Total number of times an upstream nameserver address was not queried because it is held down for being lame."
+    ),)
+    .unwrap();
+    pub static ref DNS_RESOLVER_ZONE_HIT_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "dns_resolver_zone_hit_total",
+            "Total number of answers and CNAMEs from zones, by zone and whether they came from a wildcard."
+        ),
+        &["zone", "wildcard"]
+    )
+    .unwrap();
+    pub static ref DNS_RESOLVER_ZONE_RESULT_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "dns_resolver_zone_result_total",
+            "Total number of results from zones, by zone and result (answer, nodata, blocked, cname, delegation, or nxdomain).  Delegations and nxdomains are only counted for authoritative zones."
+        ),
+        &["zone", "result"]
+    )
+    .unwrap();
     pub static ref CACHE_SIZE: IntGauge =
         register_int_gauge!(opts!("cache_size", "Number of records in the cache.")).unwrap();
+    pub static ref DNS_RESPONSES_LAST_KNOWN_GOOD_TOTAL: IntCounter = register_int_counter!(opts!(
+        "dns_responses_last_known_good_total",
+        "Total number of DNS responses which used a last known good answer because resolution failed."
+    ))
+    .unwrap();
+    pub static ref LAST_KNOWN_GOOD_SIZE: IntGauge = register_int_gauge!(opts!(
+        "last_known_good_size",
+        "Number of questions with a last known good answer."
+    ))
+    .unwrap();
+    pub static ref NAMESERVER_STATS_SIZE: IntGauge = register_int_gauge!(opts!(
+        "nameserver_stats_size",
+        "Number of upstream nameservers with response time statistics."
+    ))
+    .unwrap();
     pub static ref CACHE_OVERFLOW_COUNT: IntCounter = register_int_counter!(opts!(
         "cache_overflow_count",
         "Number of times the cache has overflowed."
@@ -127,6 +297,318 @@ lazy_static! {
         "Number of records which have been pruned from the cache due to overflow."
     ))
     .unwrap();
+    pub static ref HOSTS_URL_REFRESHES_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "hosts_url_refreshes_total",
+            "Total number of times a hosts file has been downloaded from a URL, by whether it had changed."
+        ),
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref ZONE_LOAD_DURATION_SECONDS: HistogramVec = register_histogram_vec!(
+        "zone_load_duration_seconds",
+        "Time taken to load the hosts and zone files, at startup or on a reload.",
+        &["trigger"],
+        LOAD_DURATION_BUCKETS.to_vec()
+    )
+    .unwrap();
+    pub static ref ZONE_FILE_LOAD_DURATION_SECONDS: HistogramVec = register_histogram_vec!(
+        "zone_file_load_duration_seconds",
+        "Time taken to read and parse a single hosts or zone file, by kind of file (hosts, zone, or auto_zone).",
+        &["kind"],
+        LOAD_DURATION_BUCKETS.to_vec()
+    )
+    .unwrap();
+    pub static ref SKIPPED_FILES: IntGauge = register_int_gauge!(opts!(
+        "skipped_files",
+        "Number of hosts and zone files (and directories of them) which could not be loaded and were skipped."
+    ))
+    .unwrap();
+}
+
+/// Exports the cache statistics, by record type, when the metrics are
+/// scraped.
+pub struct CacheStatsCollector {
+    cache: SharedCache,
+    descs: Vec<Desc>,
+}
+
+impl CacheStatsCollector {
+    pub fn new(cache: SharedCache) -> Self {
+        let descs = CacheStatsMetrics::new()
+            .collectors()
+            .iter()
+            .flat_map(|collector| collector.desc().into_iter().cloned())
+            .collect();
+        Self { cache, descs }
+    }
+}
+
+impl Collector for CacheStatsCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // build the metrics afresh each time, so concurrent scrapes don't
+        // interfere with each other
+        let metrics = CacheStatsMetrics::new();
+        for (rtype, stats) in self.cache.stats() {
+            let rtype = rtype.to_string();
+            let labels: &[&str] = &[&rtype];
+            metrics
+                .entries
+                .with_label_values(labels)
+                .set(stats.entries.try_into().unwrap_or(i64::MAX));
+            metrics.hits.with_label_values(labels).inc_by(stats.hits);
+            metrics
+                .misses
+                .with_label_values(labels)
+                .inc_by(stats.misses);
+            metrics
+                .expired
+                .with_label_values(labels)
+                .inc_by(stats.expired);
+            metrics
+                .pruned
+                .with_label_values(labels)
+                .inc_by(stats.pruned);
+        }
+
+        metrics
+            .collectors()
+            .iter()
+            .flat_map(|collector| collector.collect())
+            .collect()
+    }
+}
+
+/// Unregistered metrics for `CacheStatsCollector` to fill in.
+struct CacheStatsMetrics {
+    entries: IntGaugeVec,
+    hits: IntCounterVec,
+    misses: IntCounterVec,
+    expired: IntCounterVec,
+    pruned: IntCounterVec,
+}
+
+impl CacheStatsMetrics {
+    fn new() -> Self {
+        let counter =
+            |name: &str, help: &str| IntCounterVec::new(opts!(name, help), &["rtype"]).unwrap();
+
+        Self {
+            entries: IntGaugeVec::new(
+                opts!(
+                    "cache_records",
+                    "Number of records in the cache, by record type."
+                ),
+                &["rtype"],
+            )
+            .unwrap(),
+            hits: counter(
+                "cache_record_hits_total",
+                "Number of cache lookups which found a record, by record type.",
+            ),
+            misses: counter(
+                "cache_record_misses_total",
+                "Number of cache lookups which did not find a record, by record type.",
+            ),
+            expired: counter(
+                "cache_records_expired_total",
+                "Number of records which have been expired from the cache, by record type.",
+            ),
+            pruned: counter(
+                "cache_records_pruned_total",
+                "Number of records which have been pruned from the cache due to overflow, by record type.",
+            ),
+        }
+    }
+
+    fn collectors(&self) -> [&dyn Collector; 5] {
+        [
+            &self.entries,
+            &self.hits,
+            &self.misses,
+            &self.expired,
+            &self.pruned,
+        ]
+    }
+}
+
+/// Exports the approximate query counts of the most queried names and the
+/// clients making the most queries, when the metrics are scraped.  Only the
+/// top few are exported, so the number of labels stays bounded.
+pub struct TopQueriesCollector {
+    top_queries: TopQueries,
+    descs: Vec<Desc>,
+}
+
+impl TopQueriesCollector {
+    pub fn new(top_queries: TopQueries) -> Self {
+        let descs = TopQueriesMetrics::new()
+            .collectors()
+            .iter()
+            .flat_map(|collector| collector.desc().into_iter().cloned())
+            .collect();
+        Self { top_queries, descs }
+    }
+}
+
+impl Collector for TopQueriesCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let metrics = TopQueriesMetrics::new();
+        let top = self.top_queries.get_for_metrics();
+        for entry in top.names {
+            metrics
+                .names
+                .with_label_values(&[&entry.value])
+                .set(entry.count.try_into().unwrap_or(i64::MAX));
+        }
+        for entry in top.clients {
+            metrics
+                .clients
+                .with_label_values(&[&entry.value.to_string()])
+                .set(entry.count.try_into().unwrap_or(i64::MAX));
+        }
+
+        metrics
+            .collectors()
+            .iter()
+            .flat_map(|collector| collector.collect())
+            .collect()
+    }
+}
+
+/// Unregistered metrics for `TopQueriesCollector` to fill in.
+struct TopQueriesMetrics {
+    names: IntGaugeVec,
+    clients: IntGaugeVec,
+}
+
+impl TopQueriesMetrics {
+    fn new() -> Self {
+        Self {
+            names: IntGaugeVec::new(
+                opts!(
+                    "dns_top_names_queries",
+                    "Approximate number of queries for the most queried names, since counting started."
+                ),
+                &["name"],
+            )
+            .unwrap(),
+            clients: IntGaugeVec::new(
+                opts!(
+                    "dns_top_clients_queries",
+                    "Approximate number of queries from the clients making the most queries, since counting started."
+                ),
+                &["client"],
+            )
+            .unwrap(),
+        }
+    }
+
+    fn collectors(&self) -> [&dyn Collector; 2] {
+        [&self.names, &self.clients]
+    }
+}
+
+/// Add the metrics from a single call to the resolver to the global counters.
+pub fn record_resolver_metrics(metrics: &Metrics) {
+    DNS_RESOLVER_AUTHORITATIVE_HIT_TOTAL.inc_by(metrics.authoritative_hits);
+    DNS_RESOLVER_OVERRIDE_HIT_TOTAL.inc_by(metrics.override_hits);
+    DNS_RESOLVER_BLOCKED_TOTAL.inc_by(metrics.blocked);
+    DNS_RESOLVER_ALLOWLISTED_TOTAL.inc_by(metrics.allowlisted);
+    DNS_RESOLVER_CACHE_HIT_TOTAL.inc_by(metrics.cache_hits);
+    DNS_RESOLVER_CACHE_MISS_TOTAL.inc_by(metrics.cache_misses);
+    DNS_RESOLVER_NAMESERVER_HIT_TOTAL.inc_by(metrics.nameserver_hits);
+    DNS_RESOLVER_NAMESERVER_MISS_TOTAL.inc_by(metrics.nameserver_misses);
+    DNS_RESOLVER_NAMESERVER_LAME_TOTAL.inc_by(metrics.nameserver_lame);
+    DNS_RESOLVER_NAMESERVER_LAME_SKIPPED_TOTAL.inc_by(metrics.nameserver_lame_skipped);
+    for hit in &metrics.zone_hits {
+        let zone = hit.apex.to_dotted_string();
+        DNS_RESOLVER_ZONE_RESULT_TOTAL
+            .with_label_values(&[&zone, hit.kind.as_str()])
+            .inc();
+        if !matches!(hit.kind, ZoneHitKind::Delegation | ZoneHitKind::NameError) {
+            DNS_RESOLVER_ZONE_HIT_TOTAL
+                .with_label_values(&[&zone, &hit.wildcard.to_string()])
+                .inc();
+        }
+    }
+}
+
+/// Record the shape of a question's domain name, to see what real traffic
+/// looks like.
+pub fn record_question_shape(question: &Question) {
+    let qtype = question.qtype.to_string();
+    #[allow(clippy::cast_precision_loss)]
+    {
+        DNS_QUESTION_NAME_LENGTH_OCTETS
+            .with_label_values(&[&qtype])
+            .observe(question.name.len as f64);
+        DNS_QUESTION_LABEL_COUNT
+            .with_label_values(&[&qtype])
+            .observe((question.name.labels.len() - 1) as f64);
+    }
+}
+
+/// The `reason` label for `DNS_REQUESTS_MALFORMED_TOTAL`.
+pub fn malformed_reason(error: deserialise::Error) -> &'static str {
+    match error {
+        deserialise::Error::CompletelyBusted => "completely_busted",
+        deserialise::Error::HeaderTooShort(_) => "header_too_short",
+        deserialise::Error::QuestionTooShort(_) => "question_too_short",
+        deserialise::Error::ResourceRecordTooShort(_) => "resource_record_too_short",
+        deserialise::Error::ResourceRecordInvalid(_) => "resource_record_invalid",
+        deserialise::Error::ResourceRecordRdataOutOfBounds(_) => {
+            "resource_record_rdata_out_of_bounds"
+        }
+        deserialise::Error::DomainTooShort(_) => "domain_too_short",
+        deserialise::Error::DomainTooLong(_) => "domain_too_long",
+        deserialise::Error::DomainPointerInvalid(_) => "domain_pointer_invalid",
+        deserialise::Error::DomainLabelInvalid(_) => "domain_label_invalid",
+        deserialise::Error::DomainPointerChainTooLong(_) => "domain_pointer_chain_too_long",
+        deserialise::Error::TooManyLabels(_) => "too_many_labels",
+    }
+}
+
+/// The `reason` label for `DNS_RESOLUTION_ERRORS_TOTAL`.
+pub fn resolution_error_reason(error: &ResolutionError) -> &'static str {
+    match error {
+        ResolutionError::Timeout => "timeout",
+        ResolutionError::RecursionLimit => "recursion_limit",
+        ResolutionError::DuplicateQuestion { .. } => "loop",
+        ResolutionError::CnameLoop { .. } => "cname_loop",
+        ResolutionError::DeadEnd { .. } => "dead_end",
+        ResolutionError::RecursionNotAllowed { .. } => "recursion_not_allowed",
+        ResolutionError::PolicyRefused { .. } => "policy_refused",
+        ResolutionError::Upstream { error, .. } => match error {
+            UpstreamError::Timeout => "upstream_timeout",
+            UpstreamError::Unreachable => "upstream_unreachable",
+            UpstreamError::Refused => "upstream_refused",
+            UpstreamError::FormatError => "upstream_format_error",
+            UpstreamError::NotAuthoritative => "upstream_not_authoritative",
+            UpstreamError::ServerFailure => "upstream_server_failure",
+            UpstreamError::InvalidResponse => "upstream_invalid_response",
+        },
+        ResolutionError::LocalDelegationMissingNS { .. } => "local_delegation_missing_ns",
+        ResolutionError::CacheTypeMismatch { .. } => "cache_type_mismatch",
+    }
+}
+
+/// The `policy` label for `DNS_LOCAL_ZONE_POLICY_ANSWERS_TOTAL`.
+pub fn local_zone_policy_label(policy: &LocalZonePolicy) -> &'static str {
+    match policy {
+        LocalZonePolicy::NxDomain => "nxdomain",
+        LocalZonePolicy::NoData => "nodata",
+        LocalZonePolicy::Refuse => "refuse",
+        LocalZonePolicy::Static(_) => "static",
+    }
 }
 
 async fn get_metrics() -> (StatusCode, String) {
@@ -136,8 +618,12 @@ async fn get_metrics() -> (StatusCode, String) {
     }
 }
 
-pub async fn serve_prometheus_endpoint_task(address: SocketAddr) -> std::io::Result<()> {
-    let app = axum::Router::new().route("/metrics", routing::get(get_metrics));
+/// Serve the Prometheus metrics at `/metrics`, alongside some other routes.
+pub async fn serve_prometheus_endpoint_task(
+    address: SocketAddr,
+    routes: axum::Router,
+) -> std::io::Result<()> {
+    let app = routes.route("/metrics", routing::get(get_metrics));
     let listener = tokio::net::TcpListener::bind(address).await?;
     axum::serve(listener, app).await?;
 
@@ -0,0 +1,48 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The prefix length used to group IPv4 clients.
+pub const IPV4_PREFIX_LENGTH: u32 = 24;
+
+/// The prefix length used to group IPv6 clients.
+pub const IPV6_PREFIX_LENGTH: u32 = 56;
+
+/// The network an address is grouped into: its /24 (IPv4) or /56 (IPv6).
+///
+/// A single IPv6 client usually has a whole /64 (or more) to pick addresses
+/// from, and a spoofer can pick any address in a victim's network, so limits
+/// and statistics are kept per network rather than per address.
+pub fn network(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX << (32 - IPV4_PREFIX_LENGTH);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX << (128 - IPV6_PREFIX_LENGTH);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_truncates_ipv4_to_24() {
+        assert_eq!(
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 0)),
+            network(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 123)))
+        );
+    }
+
+    #[test]
+    fn network_truncates_ipv6_to_56() {
+        assert_eq!(
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0x1234, 0x5600, 0, 0, 0, 0)),
+            network(IpAddr::V6(Ipv6Addr::new(
+                0x2001, 0xdb8, 0x1234, 0x56ff, 1, 2, 3, 4
+            )))
+        );
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::metrics::{DNS_REQUESTS_IN_FLIGHT, DNS_TCP_CONNECTIONS};
+
+pub const CANNOT_PARSE_OVERLOAD_ACTION: &str = "expected one of 'servfail', 'drop'";
+
+/// What to do with a query which is shed because the server is overloaded.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum OverloadAction {
+    /// Answer with SERVFAIL.
+    #[default]
+    ServFail,
+
+    /// Do not answer at all.
+    Drop,
+}
+
+impl fmt::Display for OverloadAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverloadAction::ServFail => write!(f, "servfail"),
+            OverloadAction::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+impl FromStr for OverloadAction {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "servfail" => Ok(OverloadAction::ServFail),
+            "drop" => Ok(OverloadAction::Drop),
+            _ => Err(CANNOT_PARSE_OVERLOAD_ACTION),
+        }
+    }
+}
+
+/// Why a query was shed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Overload {
+    /// There were already too many queries being answered.
+    InFlight,
+
+    /// The query took too long to answer.
+    Timeout,
+}
+
+impl Overload {
+    /// The reason, used as a metric label.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Overload::InFlight => "in-flight",
+            Overload::Timeout => "timeout",
+        }
+    }
+}
+
+/// Counts how many queries are being answered at once, so that new queries
+/// can be shed when there are too many, rather than piling up (for example,
+/// while an upstream nameserver is not responding).
+///
+/// Invoking `clone` on an `InFlight` gives a new instance which refers to the
+/// same count.
+#[derive(Debug, Clone, Default)]
+pub struct InFlight {
+    count: Arc<AtomicUsize>,
+}
+
+/// A query being answered.  The count is decremented when this is dropped.
+#[derive(Debug)]
+pub struct InFlightGuard {
+    count: Arc<AtomicUsize>,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start answering a query, returning a guard to hold until it has been
+    /// answered, or `None` if there are already `limit` queries being
+    /// answered.  A limit of zero means unlimited.
+    pub fn try_start(&self, limit: usize) -> Option<InFlightGuard> {
+        self.count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (limit == 0 || count < limit).then_some(count + 1)
+            })
+            .ok()?;
+        DNS_REQUESTS_IN_FLIGHT.inc();
+
+        Some(InFlightGuard {
+            count: self.count.clone(),
+        })
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::AcqRel);
+        DNS_REQUESTS_IN_FLIGHT.dec();
+    }
+}
+
+/// Counts how many TCP connections are open, so that new connections can be
+/// refused when there are too many, rather than every client being able to
+/// hold connections open indefinitely.
+///
+/// Invoking `clone` on a `TcpConnections` gives a new instance which refers to
+/// the same count.
+#[derive(Debug, Clone, Default)]
+pub struct TcpConnections {
+    count: Arc<AtomicUsize>,
+}
+
+/// An open TCP connection.  The count is decremented when this is dropped.
+#[derive(Debug)]
+pub struct TcpConnectionGuard {
+    count: Arc<AtomicUsize>,
+}
+
+impl TcpConnections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open a connection, returning a guard to hold until it is closed, or
+    /// `None` if there are already `limit` connections open.  A limit of zero
+    /// means unlimited.
+    pub fn try_open(&self, limit: usize) -> Option<TcpConnectionGuard> {
+        self.count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (limit == 0 || count < limit).then_some(count + 1)
+            })
+            .ok()?;
+        DNS_TCP_CONNECTIONS.inc();
+
+        Some(TcpConnectionGuard {
+            count: self.count.clone(),
+        })
+    }
+}
+
+impl Drop for TcpConnectionGuard {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::AcqRel);
+        DNS_TCP_CONNECTIONS.dec();
+    }
+}
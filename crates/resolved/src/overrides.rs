@@ -0,0 +1,309 @@
+use arc_swap::ArcSwap;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use dns_types::protocol::types::DomainName;
+use dns_types::zones::types::{Zone, Zones};
+
+/// The zones being served: those loaded from hosts and zone files, plus the
+/// runtime-override zone, which holds records added through the admin API.
+///
+/// Override records are served alongside any records from files, and survive
+/// the files being reloaded.
+///
+/// The zones being served are an immutable snapshot, which is swapped out
+/// whole when anything changes, so readers never wait for a reload.
+#[derive(Debug, Clone)]
+pub struct ServedZones {
+    pub current: Arc<ArcSwap<Zones>>,
+    overrides: Arc<Mutex<Overrides>>,
+}
+
+#[derive(Debug, Default)]
+struct Overrides {
+    /// The zones loaded from files, without any overrides applied.  This is
+    /// only kept while there are overrides, as otherwise it's the same as the
+    /// zones being served.  Zones are shared with the zones being served, so
+    /// only those which have override records in them are stored twice.
+    file_zones: Option<Zones>,
+
+    /// The override records, keyed by domain.  Each `Zone` only has records
+    /// for its key.
+    records: BTreeMap<DomainName, Zone>,
+}
+
+impl ServedZones {
+    pub fn new(zones: Zones) -> Self {
+        Self {
+            current: Arc::new(ArcSwap::from_pointee(zones)),
+            overrides: Arc::new(Mutex::new(Overrides::default())),
+        }
+    }
+
+    /// Replace the zones loaded from files, keeping the overrides.
+    pub async fn replace_file_zones(&self, zones: Zones) {
+        let mut overrides = self.overrides.lock().await;
+        if overrides.records.is_empty() {
+            self.current.store(Arc::new(zones));
+        } else {
+            let served = overrides.apply_to(zones.clone());
+            overrides.file_zones = Some(zones);
+            self.current.store(Arc::new(served));
+        }
+    }
+
+    /// Replace or remove some of the zones loaded from files, keyed by apex,
+    /// keeping the overrides.  The other zones are left as they are.
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn update_file_zones(&self, changes: Vec<(DomainName, Option<Zone>)>) {
+        let mut overrides = self.overrides.lock().await;
+        if overrides.records.is_empty() {
+            // writers are serialised by the overrides lock, so nothing else
+            // can store a new snapshot between this load and store
+            let mut zones = Zones::clone(&self.current.load());
+            apply_changes(&mut zones, changes);
+            self.current.store(Arc::new(zones));
+        } else {
+            // safe because `file_zones` is kept while there are overrides
+            let file_zones = overrides.file_zones.as_mut().unwrap();
+            apply_changes(file_zones, changes);
+            let file_zones = file_zones.clone();
+            let served = overrides.apply_to(file_zones);
+            self.current.store(Arc::new(served));
+        }
+    }
+
+    /// Set the override records for a domain, returning the previous ones.
+    /// The zone must only have records for that domain.
+    pub async fn set(&self, name: DomainName, zone: Zone) -> Option<Zone> {
+        let mut overrides = self.overrides.lock().await;
+        if overrides.file_zones.is_none() {
+            overrides.file_zones = Some(Zones::clone(&self.current.load()));
+        }
+        let previous = overrides.records.insert(name, zone);
+
+        // safe because `file_zones` was set above
+        let file_zones = overrides.file_zones.clone().unwrap();
+        self.current.store(Arc::new(overrides.apply_to(file_zones)));
+
+        previous
+    }
+
+    /// Remove the override records for a domain, returning them.
+    pub async fn remove(&self, name: &DomainName) -> Option<Zone> {
+        let mut overrides = self.overrides.lock().await;
+        let previous = overrides.records.remove(name)?;
+
+        if overrides.records.is_empty() {
+            if let Some(file_zones) = overrides.file_zones.take() {
+                self.current.store(Arc::new(file_zones));
+            }
+        } else if let Some(file_zones) = overrides.file_zones.clone() {
+            self.current.store(Arc::new(overrides.apply_to(file_zones)));
+        }
+
+        Some(previous)
+    }
+
+    /// Get all the override records for domains which match the predicate,
+    /// as a single zone.
+    pub async fn get(&self, predicate: impl Fn(&DomainName) -> bool) -> Zone {
+        let overrides = self.overrides.lock().await;
+        let mut combined = Zone::default();
+        for (name, zone) in &overrides.records {
+            if predicate(name) {
+                // safe because both have the root domain as their apex
+                combined.merge(zone.clone()).unwrap();
+            }
+        }
+        combined
+    }
+}
+
+impl Overrides {
+    /// Add the override records to some zones.  Each record goes into the
+    /// most specific zone for its domain, so that it isn't hidden by that
+    /// zone.
+    fn apply_to(&self, mut zones: Zones) -> Zones {
+        for zone in self.records.values() {
+            for (name, zrs) in zone.all_records() {
+                let apex = zones
+                    .get(&name)
+                    .map_or_else(DomainName::root_domain, |z| z.get_apex().clone());
+                let mut override_zone = Zone::new(apex, None);
+                for zr in zrs {
+                    override_zone.insert(&name, zr.rtype_with_data, zr.ttl);
+                }
+                zones.insert_merge(override_zone);
+            }
+        }
+        zones
+    }
+}
+
+fn apply_changes(zones: &mut Zones, changes: Vec<(DomainName, Option<Zone>)>) {
+    for (apex, zone) in changes {
+        match zone {
+            Some(zone) => zones.insert(zone),
+            None => {
+                zones.remove(&apex);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use dns_types::protocol::types::test_util::*;
+    use dns_types::protocol::types::{QueryType, RecordType, ResourceRecord};
+    use dns_types::zones::types::ZoneResult;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn set_serves_records_alongside_file_zones() {
+        let served = ServedZones::new(file_zones("10.0.0.1"));
+
+        let previous = served.set(domain("nas.lan."), nas_zone("10.0.0.2")).await;
+
+        assert_eq!(None, previous);
+        assert_eq!(
+            vec![a_record("www.lan.", Ipv4Addr::new(10, 0, 0, 1))],
+            answer(&served, "www.lan.")
+        );
+        assert_eq!(
+            vec![a_record("nas.lan.", Ipv4Addr::new(10, 0, 0, 2))],
+            answer(&served, "nas.lan.")
+        );
+    }
+
+    #[tokio::test]
+    async fn set_replaces_previous_records() {
+        let served = ServedZones::new(file_zones("10.0.0.1"));
+
+        served.set(domain("nas.lan."), nas_zone("10.0.0.2")).await;
+        let previous = served.set(domain("nas.lan."), nas_zone("10.0.0.3")).await;
+
+        assert_eq!(Some(nas_zone("10.0.0.2")), previous);
+        assert_eq!(
+            vec![a_record("nas.lan.", Ipv4Addr::new(10, 0, 0, 3))],
+            answer(&served, "nas.lan.")
+        );
+    }
+
+    #[tokio::test]
+    async fn remove_restores_file_zones() {
+        let served = ServedZones::new(file_zones("10.0.0.1"));
+        served.set(domain("nas.lan."), nas_zone("10.0.0.2")).await;
+
+        let removed = served.remove(&domain("nas.lan.")).await;
+
+        assert_eq!(Some(nas_zone("10.0.0.2")), removed);
+        assert_eq!(
+            Some(&lan_zone("10.0.0.1")),
+            served.current.load().get(&domain("lan."))
+        );
+        assert_eq!(None, served.remove(&domain("nas.lan.")).await);
+    }
+
+    #[tokio::test]
+    async fn overrides_survive_replacing_file_zones() {
+        let served = ServedZones::new(file_zones("10.0.0.1"));
+        served.set(domain("nas.lan."), nas_zone("10.0.0.2")).await;
+
+        served.replace_file_zones(file_zones("10.0.0.9")).await;
+
+        assert_eq!(
+            vec![a_record("www.lan.", Ipv4Addr::new(10, 0, 0, 9))],
+            answer(&served, "www.lan.")
+        );
+        assert_eq!(
+            vec![a_record("nas.lan.", Ipv4Addr::new(10, 0, 0, 2))],
+            answer(&served, "nas.lan.")
+        );
+
+        // and the new file zones are what's left when the override goes
+        served.remove(&domain("nas.lan.")).await;
+        assert_eq!(
+            Some(&lan_zone("10.0.0.9")),
+            served.current.load().get(&domain("lan."))
+        );
+    }
+
+    #[tokio::test]
+    async fn overrides_survive_updating_file_zones() {
+        let served = ServedZones::new(file_zones("10.0.0.1"));
+        served.set(domain("nas.lan."), nas_zone("10.0.0.2")).await;
+
+        served
+            .update_file_zones(vec![(domain("lan."), Some(lan_zone("10.0.0.9")))])
+            .await;
+
+        assert_eq!(
+            vec![a_record("www.lan.", Ipv4Addr::new(10, 0, 0, 9))],
+            answer(&served, "www.lan.")
+        );
+        assert_eq!(
+            vec![a_record("nas.lan.", Ipv4Addr::new(10, 0, 0, 2))],
+            answer(&served, "nas.lan.")
+        );
+    }
+
+    #[tokio::test]
+    async fn update_file_zones_removes_zones() {
+        let served = ServedZones::new(file_zones("10.0.0.1"));
+
+        served.update_file_zones(vec![(domain("lan."), None)]).await;
+
+        assert!(served.current.load().get(&domain("www.lan.")).is_none());
+    }
+
+    #[tokio::test]
+    async fn get_filters_by_domain() {
+        let served = ServedZones::new(Zones::new());
+        served.set(domain("nas.lan."), nas_zone("10.0.0.2")).await;
+        served
+            .set(
+                domain("example.com."),
+                Zone::deserialise("example.com. 300 IN A 192.0.2.1\n").unwrap(),
+            )
+            .await;
+
+        let zone = served
+            .get(|name| name.is_subdomain_of(&domain("lan.")))
+            .await;
+
+        assert_eq!(nas_zone("10.0.0.2"), zone);
+    }
+
+    fn lan_zone(address: &str) -> Zone {
+        Zone::deserialise(&format!(
+            "$ORIGIN lan.\n@ IN SOA mname rname 1 30 30 30 30\nwww 300 IN A {address}\n"
+        ))
+        .unwrap()
+    }
+
+    fn file_zones(address: &str) -> Zones {
+        let mut zones = Zones::new();
+        zones.insert(lan_zone(address));
+        zones
+    }
+
+    fn nas_zone(address: &str) -> Zone {
+        Zone::deserialise(&format!("nas.lan. 300 IN A {address}\n")).unwrap()
+    }
+
+    fn answer(served: &ServedZones, name: &str) -> Vec<ResourceRecord> {
+        match served
+            .current
+            .load()
+            .resolve(&domain(name), QueryType::Record(RecordType::A))
+        {
+            Some((_, ZoneResult::Answer { rrs, .. })) => rrs,
+            result => panic!("expected answer, got {result:?}"),
+        }
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::net::network;
+
+pub const CANNOT_PARSE_RATE_LIMIT_ACTION: &str = "expected one of 'refused', 'drop'";
+
+const MUTEX_POISON_MESSAGE: &str =
+    "[INTERNAL ERROR] rate limiter mutex poisoned, cannot recover from this - aborting";
+
+/// The most client networks to keep buckets for.  When there are this many,
+/// clients whose buckets have refilled are forgotten, and if there are still
+/// too many an arbitrary client is forgotten to make room.
+pub const MAX_CLIENTS: usize = 65536;
+
+/// How often to look for refilled buckets to forget when there are
+/// `MAX_CLIENTS` clients, so that a flood of new clients doesn't make every
+/// query scan all the buckets.
+pub(crate) const FULL_PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do with a query which goes over a rate limit.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum RateLimitAction {
+    /// Answer with REFUSED.
+    #[default]
+    Refused,
+
+    /// Do not answer at all.
+    Drop,
+}
+
+impl fmt::Display for RateLimitAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitAction::Refused => write!(f, "refused"),
+            RateLimitAction::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+impl FromStr for RateLimitAction {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refused" => Ok(RateLimitAction::Refused),
+            "drop" => Ok(RateLimitAction::Drop),
+            _ => Err(CANNOT_PARSE_RATE_LIMIT_ACTION),
+        }
+    }
+}
+
+/// Which limit a query went over.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Exceeded {
+    /// The limit for the query's source address.
+    Client,
+
+    /// The limit for all queries.
+    Global,
+}
+
+impl Exceeded {
+    /// The name of the limit, used as a metric label.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Exceeded::Client => "client",
+            Exceeded::Global => "global",
+        }
+    }
+}
+
+/// Token-bucket rate limits on queries, for each source network and for all
+/// queries together.
+///
+/// Clients are grouped into /24 (IPv4) or /56 (IPv6) networks, as otherwise an
+/// IPv6 client could get a fresh bucket by using another of its addresses.
+///
+/// Each limit is a number of queries per second, and its bucket holds up to one
+/// second's worth, so short bursts are allowed.  A limit of zero means
+/// unlimited.
+///
+/// Invoking `clone` on a `RateLimiter` gives a new instance which refers to the
+/// same buckets.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    client_limit: u32,
+    global_limit: u32,
+    max_clients: usize,
+    clients: HashMap<IpAddr, Bucket>,
+    global: Bucket,
+    /// When the buckets were last pruned to make room for a new client.
+    full_pruned: Option<Instant>,
+}
+
+/// A token bucket, which fills up at `limit` tokens per second, to at most
+/// `limit` tokens.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    pub(crate) fn full(limit: u32, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit),
+            updated: now,
+        }
+    }
+
+    /// Add the tokens which have accumulated since the bucket was last
+    /// updated, and return whether there is one to take.
+    pub(crate) fn refill(&mut self, limit: u32, now: Instant) -> bool {
+        let limit = f64::from(limit);
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit).min(limit);
+        self.updated = now;
+        self.tokens >= 1.0
+    }
+
+    /// Take a token.  Check there is one with `refill` first.
+    pub(crate) fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    /// Whether the bucket is full, as of the last `refill`.
+    pub(crate) fn is_full(&self, limit: u32) -> bool {
+        self.tokens >= f64::from(limit)
+    }
+}
+
+impl RateLimiter {
+    /// Create a new rate limiter, with every bucket full.
+    pub fn new(client_limit: u32, global_limit: u32) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                client_limit,
+                global_limit,
+                max_clients: MAX_CLIENTS,
+                clients: HashMap::new(),
+                global: Bucket::full(global_limit, Instant::now()),
+                full_pruned: None,
+            })),
+        }
+    }
+
+    /// Take a token for a query from `address`, or say which limit it would go
+    /// over.  A query which goes over a limit takes no tokens.
+    ///
+    /// # Errors
+    ///
+    /// If the query goes over the client or global limit.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn check(&self, address: IpAddr) -> Result<(), Exceeded> {
+        self.check_at(address, Instant::now())
+    }
+
+    fn check_at(&self, address: IpAddr, now: Instant) -> Result<(), Exceeded> {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        let network = network(address);
+        if inner.client_limit != 0 && !inner.clients.contains_key(&network) {
+            inner.make_room(now);
+        }
+
+        let Inner {
+            client_limit,
+            global_limit,
+            clients,
+            global,
+            ..
+        } = &mut *inner;
+
+        let client = if *client_limit == 0 {
+            None
+        } else {
+            let bucket = clients
+                .entry(network)
+                .or_insert_with(|| Bucket::full(*client_limit, now));
+            if !bucket.refill(*client_limit, now) {
+                return Err(Exceeded::Client);
+            }
+            Some(bucket)
+        };
+
+        if *global_limit != 0 {
+            if !global.refill(*global_limit, now) {
+                return Err(Exceeded::Global);
+            }
+            global.take();
+        }
+
+        if let Some(bucket) = client {
+            bucket.take();
+        }
+
+        Ok(())
+    }
+
+    /// Change the limits.  Every bucket is refilled, unless the limits are
+    /// unchanged.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn set_limits(&self, client_limit: u32, global_limit: u32) {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        if inner.client_limit == client_limit && inner.global_limit == global_limit {
+            return;
+        }
+
+        inner.client_limit = client_limit;
+        inner.global_limit = global_limit;
+        inner.clients.clear();
+        inner.global = Bucket::full(global_limit, Instant::now());
+        inner.full_pruned = None;
+    }
+
+    /// Forget about clients whose buckets have refilled, since they are
+    /// indistinguishable from clients which have not been seen at all.
+    ///
+    /// Returns the number of clients still being tracked.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn prune(&self) -> usize {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        inner.prune(Instant::now());
+        inner.clients.len()
+    }
+}
+
+impl Inner {
+    fn prune(&mut self, now: Instant) {
+        let client_limit = self.client_limit;
+        self.clients.retain(|_, bucket| {
+            bucket.refill(client_limit, now);
+            !bucket.is_full(client_limit)
+        });
+    }
+
+    /// Make sure there is room for a bucket for a new client.
+    fn make_room(&mut self, now: Instant) {
+        if self.clients.len() < self.max_clients {
+            return;
+        }
+
+        if self
+            .full_pruned
+            .is_none_or(|then| now.saturating_duration_since(then) >= FULL_PRUNE_INTERVAL)
+        {
+            self.prune(now);
+            self.full_pruned = Some(now);
+        }
+        while self.clients.len() >= self.max_clients {
+            let Some(evicted) = self.clients.keys().next().copied() else {
+                break;
+            };
+            self.clients.remove(&evicted);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn bucket_refills_at_limit_per_second() {
+        let now = Instant::now();
+        let mut bucket = Bucket::full(2, now);
+        assert!(bucket.refill(2, now));
+        bucket.take();
+        assert!(bucket.refill(2, now));
+        bucket.take();
+        assert!(!bucket.refill(2, now));
+
+        assert!(bucket.refill(2, now + Duration::from_millis(500)));
+        bucket.take();
+        assert!(!bucket.refill(2, now + Duration::from_millis(500)));
+
+        assert!(bucket.refill(2, now + Duration::from_secs(10)));
+        assert!(bucket.is_full(2));
+    }
+
+    #[test]
+    fn check_allows_burst_then_refills() {
+        let limiter = RateLimiter::new(2, 0);
+        let now = Instant::now();
+        let client = ipv4(192, 0, 2, 1);
+
+        assert_eq!(Ok(()), limiter.check_at(client, now));
+        assert_eq!(Ok(()), limiter.check_at(client, now));
+        assert_eq!(Err(Exceeded::Client), limiter.check_at(client, now));
+        assert_eq!(
+            Ok(()),
+            limiter.check_at(client, now + Duration::from_millis(500))
+        );
+        assert_eq!(
+            Err(Exceeded::Client),
+            limiter.check_at(client, now + Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn check_enforces_global_limit_across_clients() {
+        let limiter = RateLimiter::new(0, 2);
+        let now = Instant::now();
+
+        assert_eq!(Ok(()), limiter.check_at(ipv4(192, 0, 2, 1), now));
+        assert_eq!(Ok(()), limiter.check_at(ipv4(198, 51, 100, 1), now));
+        assert_eq!(
+            Err(Exceeded::Global),
+            limiter.check_at(ipv4(203, 0, 113, 1), now)
+        );
+    }
+
+    #[test]
+    fn check_over_global_limit_takes_no_client_token() {
+        let limiter = RateLimiter::new(1, 1);
+        let now = Instant::now();
+
+        assert_eq!(Ok(()), limiter.check_at(ipv4(192, 0, 2, 1), now));
+        assert_eq!(
+            Err(Exceeded::Global),
+            limiter.check_at(ipv4(198, 51, 100, 1), now)
+        );
+        assert_eq!(
+            Ok(()),
+            limiter.check_at(ipv4(198, 51, 100, 1), now + Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn check_limits_each_client_network() {
+        let limiter = RateLimiter::new(1, 0);
+        let now = Instant::now();
+
+        assert_eq!(Ok(()), limiter.check_at(ipv4(192, 0, 2, 1), now));
+        assert_eq!(
+            Err(Exceeded::Client),
+            limiter.check_at(ipv4(192, 0, 2, 200), now)
+        );
+        assert_eq!(Ok(()), limiter.check_at(ipv4(192, 0, 3, 1), now));
+    }
+
+    #[test]
+    fn check_groups_ipv6_clients_by_56() {
+        let limiter = RateLimiter::new(1, 0);
+        let now = Instant::now();
+
+        assert_eq!(Ok(()), limiter.check_at(ipv6(0x0000, 1), now));
+        assert_eq!(
+            Err(Exceeded::Client),
+            limiter.check_at(ipv6(0x00ff, 2), now)
+        );
+        assert_eq!(Ok(()), limiter.check_at(ipv6(0x0100, 1), now));
+    }
+
+    #[test]
+    fn check_caps_number_of_clients() {
+        let limiter = RateLimiter::new(1, 0);
+        limiter.inner.lock().unwrap().max_clients = 4;
+        let now = Instant::now();
+
+        for i in 0..100 {
+            assert_eq!(Ok(()), limiter.check_at(ipv4(10, 0, i, 1), now));
+        }
+        assert!(limiter.inner.lock().unwrap().clients.len() <= 4);
+    }
+
+    #[test]
+    fn check_forgets_refilled_clients_before_evicting() {
+        let limiter = RateLimiter::new(1, 0);
+        limiter.inner.lock().unwrap().max_clients = 2;
+        let now = Instant::now();
+
+        assert_eq!(Ok(()), limiter.check_at(ipv4(10, 0, 0, 1), now));
+        let later = now + Duration::from_secs(1);
+        assert_eq!(Ok(()), limiter.check_at(ipv4(10, 0, 1, 1), later));
+        assert_eq!(Ok(()), limiter.check_at(ipv4(10, 0, 2, 1), later));
+
+        // the first client's bucket refilled, so it was forgotten, and the
+        // second is still limited
+        assert_eq!(
+            Err(Exceeded::Client),
+            limiter.check_at(ipv4(10, 0, 1, 1), later)
+        );
+    }
+
+    #[test]
+    fn prune_forgets_refilled_clients() {
+        let limiter = RateLimiter::new(1, 0);
+        assert_eq!(Ok(()), limiter.check(ipv4(192, 0, 2, 1)));
+        assert_eq!(1, limiter.inner.lock().unwrap().clients.len());
+
+        limiter
+            .inner
+            .lock()
+            .unwrap()
+            .prune(Instant::now() + Duration::from_secs(1));
+        assert_eq!(0, limiter.inner.lock().unwrap().clients.len());
+    }
+
+    fn ipv4(a: u8, b: u8, c: u8, d: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+    }
+
+    fn ipv6(subnet: u16, host: u16) -> IpAddr {
+        IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, subnet, 0, 0, 0, host))
+    }
+}
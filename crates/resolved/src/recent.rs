@@ -0,0 +1,518 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+
+use dns_types::protocol::types::{DomainName, Message, QueryType, Question, ResourceRecord};
+use dns_types::zones::types::Zone;
+
+const MUTEX_POISON_MESSAGE: &str =
+    "[INTERNAL ERROR] recent queries mutex poisoned, cannot recover from this - aborting";
+
+/// How many queries can be waiting to be sent to a tail before further
+/// queries are dropped for it.
+pub const TAIL_BUFFER_SIZE: usize = 256;
+
+/// A ring buffer of the most recent queries and the responses to them, so
+/// that the admin API can show exactly what a client asked and what the
+/// answer was.
+///
+/// Queries can also be followed as they happen, with `tail`.
+///
+/// Invoking `clone` on a `RecentQueries` gives a new instance which refers to
+/// the same buffer.
+#[derive(Debug, Clone)]
+pub struct RecentQueries {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    capacity: usize,
+    queries: VecDeque<RecentQuery>,
+    tails: Vec<Tail>,
+}
+
+/// A client following the queries which match a filter.
+#[derive(Debug)]
+struct Tail {
+    filter: QueryFilter,
+    sender: mpsc::Sender<RecentQuery>,
+}
+
+/// Which queries to follow with `RecentQueries::tail`.  Each part of the
+/// filter which is given must match.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct QueryFilter {
+    /// Only queries for this domain or its subdomains.
+    pub name: Option<DomainName>,
+    /// Only queries from this client.
+    pub client: Option<IpAddr>,
+    /// Only queries of this type.
+    pub qtype: Option<QueryType>,
+}
+
+impl QueryFilter {
+    /// Check if a query, by its client and question, matches the filter.  A
+    /// query without a question only matches a filter on the client.
+    pub fn matches(&self, client: IpAddr, question: Option<&Question>) -> bool {
+        if self.client.is_some_and(|c| c != client) {
+            return false;
+        }
+        if self.name.is_none() && self.qtype.is_none() {
+            return true;
+        }
+        let Some(question) = question else {
+            return false;
+        };
+        self.name
+            .as_ref()
+            .is_none_or(|name| question.name.is_subdomain_of(name))
+            && self.qtype.is_none_or(|qtype| qtype == question.qtype)
+    }
+}
+
+// in the same `key=value` form it is parsed from
+impl fmt::Display for QueryFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(name) = &self.name {
+            parts.push(format!("name={name}"));
+        }
+        if let Some(client) = self.client {
+            parts.push(format!("client={client}"));
+        }
+        if let Some(qtype) = self.qtype {
+            parts.push(format!("type={qtype}"));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// An error parsing a query filter.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum QueryFilterFromStrError {
+    /// A part of the filter was not `name=`, `client=`, or `type=`.
+    UnknownField(String),
+    /// A domain name could not be parsed.
+    InvalidDomainName(String),
+    /// A client address could not be parsed.
+    InvalidClient(String),
+    /// A query type could not be parsed.
+    InvalidQueryType(String),
+}
+
+impl fmt::Display for QueryFilterFromStrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryFilterFromStrError::UnknownField(field) => {
+                write!(f, "unknown filter '{field}'")
+            }
+            QueryFilterFromStrError::InvalidDomainName(name) => {
+                write!(f, "invalid domain name '{name}'")
+            }
+            QueryFilterFromStrError::InvalidClient(client) => {
+                write!(f, "invalid client address '{client}'")
+            }
+            QueryFilterFromStrError::InvalidQueryType(qtype) => {
+                write!(f, "invalid type '{qtype}'")
+            }
+        }
+    }
+}
+
+impl std::error::Error for QueryFilterFromStrError {}
+
+/// Parse a filter from whitespace-separated `name=`, `client=`, and `type=`
+/// parts, any of which may be left out.
+impl FromStr for QueryFilter {
+    type Err = QueryFilterFromStrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = QueryFilter::default();
+        for part in s.split_whitespace() {
+            match part.split_once('=') {
+                Some(("name", name)) => {
+                    filter.name = Some(DomainName::from_str(name).map_err(|_| {
+                        QueryFilterFromStrError::InvalidDomainName(name.to_string())
+                    })?);
+                }
+                Some(("client", client)) => {
+                    filter.client =
+                        Some(IpAddr::from_str(client).map_err(|_| {
+                            QueryFilterFromStrError::InvalidClient(client.to_string())
+                        })?);
+                }
+                Some(("type", qtype)) => {
+                    filter.qtype = Some(QueryType::from_str(qtype).map_err(|_| {
+                        QueryFilterFromStrError::InvalidQueryType(qtype.to_string())
+                    })?);
+                }
+                _ => return Err(QueryFilterFromStrError::UnknownField(part.to_string())),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+/// A query, and the response to it.
+#[derive(Debug, Clone, Serialize)]
+pub struct RecentQuery {
+    /// When the query was answered, in seconds since the UNIX epoch.
+    pub time: u64,
+    pub client: IpAddr,
+    /// The question, in `name class type` form, if there was one.
+    pub question: Option<String>,
+    pub rcode: String,
+    /// The answer section of the response, in zone file format.
+    pub answers: Vec<String>,
+    pub duration_seconds: f64,
+}
+
+// on one line, for following queries as they happen
+impl fmt::Display for RecentQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {:.3}s",
+            self.time,
+            self.client,
+            self.question.as_deref().unwrap_or("-"),
+            self.rcode,
+            self.duration_seconds
+        )?;
+        if !self.answers.is_empty() {
+            write!(f, " {}", self.answers.join(" ; "))?;
+        }
+        Ok(())
+    }
+}
+
+impl RecentQueries {
+    /// Create a buffer which holds up to `capacity` queries.  A capacity of
+    /// zero means no queries are kept.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                queries: VecDeque::with_capacity(capacity),
+                tails: Vec::new(),
+            })),
+        }
+    }
+
+    /// Record a query, by its response, dropping the oldest query if the
+    /// buffer is full, and send it to any tails it matches.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn record(&self, client: IpAddr, response: &Message, duration: Duration) {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        if inner.capacity == 0 {
+            return;
+        }
+        inner.tails.retain(|tail| !tail.sender.is_closed());
+
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let question = response
+            .questions
+            .first()
+            .map(|q| format!("{} {} {}", q.name, q.qclass, q.qtype));
+        let answers = response.answers.iter().map(format_rr).collect();
+
+        let query = RecentQuery {
+            time,
+            client,
+            question,
+            rcode: response.header.rcode.to_string(),
+            answers,
+            duration_seconds: duration.as_secs_f64(),
+        };
+
+        for tail in &inner.tails {
+            if tail.filter.matches(client, response.questions.first()) {
+                // a tail which isn't keeping up misses queries, rather than
+                // holding up the server
+                let _ = tail.sender.try_send(query.clone());
+            }
+        }
+
+        if inner.queries.len() >= inner.capacity {
+            inner.queries.pop_front();
+        }
+        inner.queries.push_back(query);
+    }
+
+    /// Follow the queries which match a filter, as they are recorded.  This
+    /// is `None` if queries are not being kept (the capacity is zero), and the
+    /// receiver is closed if the capacity is later changed to zero.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn tail(&self, filter: QueryFilter) -> Option<mpsc::Receiver<RecentQuery>> {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        if inner.capacity == 0 {
+            return None;
+        }
+
+        let (sender, receiver) = mpsc::channel(TAIL_BUFFER_SIZE);
+        inner.tails.push(Tail { filter, sender });
+        Some(receiver)
+    }
+
+    /// Get the queries, oldest first.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn get(&self) -> Vec<RecentQuery> {
+        let inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        inner.queries.iter().cloned().collect()
+    }
+
+    /// Change how many queries are kept, dropping the oldest if there are now
+    /// too many.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        inner.capacity = capacity;
+        while inner.queries.len() > capacity {
+            inner.queries.pop_front();
+        }
+        if capacity == 0 {
+            inner.tails.clear();
+        }
+    }
+}
+
+/// Format a record like a line of a zone file.
+pub fn format_rr(rr: &ResourceRecord) -> String {
+    format!(
+        "{} {} {} {} {}",
+        rr.name,
+        rr.ttl,
+        rr.rclass,
+        rr.rtype_with_data.rtype(),
+        Zone::default().serialise_rdata(&rr.rtype_with_data)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use dns_types::protocol::types::test_util::*;
+    use dns_types::protocol::types::RecordType;
+
+    use super::*;
+
+    #[test]
+    fn query_filter_from_str() {
+        assert_eq!(Ok(QueryFilter::default()), QueryFilter::from_str(""));
+        assert_eq!(
+            Ok(QueryFilter {
+                name: Some(domain("example.com.")),
+                client: Some(client(1)),
+                qtype: Some(QueryType::Record(RecordType::AAAA)),
+            }),
+            QueryFilter::from_str("type=AAAA  client=10.0.0.1 name=example.com.")
+        );
+    }
+
+    #[test]
+    fn query_filter_from_str_roundtrips() {
+        let filter = QueryFilter {
+            name: Some(domain("example.com.")),
+            client: Some(client(1)),
+            qtype: Some(QueryType::Record(RecordType::MX)),
+        };
+
+        assert_eq!(Ok(filter.clone()), filter.to_string().parse());
+    }
+
+    #[test]
+    fn query_filter_from_str_rejects_unknown_fields() {
+        for field in [
+            "class=IN",
+            "name",
+            "example.com.",
+            "=example.com.",
+            "Name=example.com.",
+        ] {
+            assert_eq!(
+                Err(QueryFilterFromStrError::UnknownField(field.to_string())),
+                QueryFilter::from_str(&format!("client=10.0.0.1 {field}")),
+                "{field}"
+            );
+        }
+    }
+
+    #[test]
+    fn query_filter_from_str_rejects_bad_values() {
+        assert_eq!(
+            Err(QueryFilterFromStrError::InvalidDomainName(
+                "a..b".to_string()
+            )),
+            QueryFilter::from_str("name=a..b")
+        );
+        assert_eq!(
+            Err(QueryFilterFromStrError::InvalidClient("10.0.0".to_string())),
+            QueryFilter::from_str("client=10.0.0")
+        );
+        assert_eq!(
+            Err(QueryFilterFromStrError::InvalidQueryType(
+                "NOTATYPE".to_string()
+            )),
+            QueryFilter::from_str("type=NOTATYPE")
+        );
+    }
+
+    #[test]
+    fn query_filter_matches_subdomains() {
+        let filter = QueryFilter {
+            name: Some(domain("example.com.")),
+            ..QueryFilter::default()
+        };
+
+        assert!(filter.matches(client(1), Some(&question("example.com."))));
+        assert!(filter.matches(client(1), Some(&question("www.example.com."))));
+        assert!(filter.matches(client(1), Some(&question("a.b.example.com."))));
+        assert!(!filter.matches(client(1), Some(&question("example.net."))));
+        assert!(!filter.matches(client(1), Some(&question("notexample.com."))));
+        assert!(!filter.matches(client(1), Some(&question("com."))));
+    }
+
+    #[test]
+    fn query_filter_matches_all_parts() {
+        let filter = QueryFilter {
+            name: Some(domain("example.com.")),
+            client: Some(client(1)),
+            qtype: Some(QueryType::Record(RecordType::A)),
+        };
+
+        assert!(filter.matches(client(1), Some(&question("www.example.com."))));
+        assert!(!filter.matches(client(2), Some(&question("www.example.com."))));
+
+        let mut aaaa = question("www.example.com.");
+        aaaa.qtype = QueryType::Record(RecordType::AAAA);
+        assert!(!filter.matches(client(1), Some(&aaaa)));
+    }
+
+    #[test]
+    fn query_filter_matches_questionless_query_on_client_only() {
+        assert!(QueryFilter::default().matches(client(1), None));
+        assert!(QueryFilter {
+            client: Some(client(1)),
+            ..QueryFilter::default()
+        }
+        .matches(client(1), None));
+        assert!(!QueryFilter {
+            name: Some(DomainName::root_domain()),
+            ..QueryFilter::default()
+        }
+        .matches(client(1), None));
+        assert!(!QueryFilter {
+            qtype: Some(QueryType::Record(RecordType::A)),
+            ..QueryFilter::default()
+        }
+        .matches(client(1), None));
+    }
+
+    #[test]
+    fn record_keeps_most_recent() {
+        let recent = RecentQueries::new(2);
+
+        for i in 1..=3 {
+            recent.record(client(i), &a_response("www.example.com."), Duration::ZERO);
+        }
+
+        let clients: Vec<IpAddr> = recent.get().iter().map(|q| q.client).collect();
+        assert_eq!(vec![client(2), client(3)], clients);
+    }
+
+    #[test]
+    fn record_formats_questionless_query() {
+        let recent = RecentQueries::new(1);
+        let mut response = a_response("www.example.com.");
+        response.questions.clear();
+        response.answers.clear();
+
+        recent.record(client(1), &response, Duration::from_millis(1500));
+
+        let query = &recent.get()[0];
+        assert_eq!(None, query.question);
+        assert!(query.to_string().ends_with(" 10.0.0.1 - no-error 1.500s"));
+    }
+
+    #[test]
+    fn tail_receives_matching_queries() {
+        let recent = RecentQueries::new(10);
+        let mut receiver = recent
+            .tail(QueryFilter {
+                client: Some(client(1)),
+                ..QueryFilter::default()
+            })
+            .unwrap();
+
+        recent.record(client(2), &a_response("a.example.com."), Duration::ZERO);
+        recent.record(client(1), &a_response("b.example.com."), Duration::ZERO);
+
+        let query = receiver.try_recv().unwrap();
+        assert_eq!(client(1), query.client);
+        assert_eq!(Some("b.example.com. IN A".to_string()), query.question);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn record_prunes_dropped_tails() {
+        let recent = RecentQueries::new(10);
+        let receiver = recent.tail(QueryFilter::default()).unwrap();
+        let _kept = recent.tail(QueryFilter::default()).unwrap();
+        assert_eq!(2, recent.inner.lock().unwrap().tails.len());
+
+        drop(receiver);
+        recent.record(client(1), &a_response("www.example.com."), Duration::ZERO);
+
+        assert_eq!(1, recent.inner.lock().unwrap().tails.len());
+    }
+
+    #[test]
+    fn tail_needs_capacity() {
+        let recent = RecentQueries::new(0);
+
+        assert!(recent.tail(QueryFilter::default()).is_none());
+    }
+
+    #[test]
+    fn set_capacity_zero_closes_tails() {
+        let recent = RecentQueries::new(10);
+        let mut receiver = recent.tail(QueryFilter::default()).unwrap();
+        recent.record(client(1), &a_response("www.example.com."), Duration::ZERO);
+
+        recent.set_capacity(0);
+
+        // queries already sent can still be received, but then it's closed
+        assert!(receiver.try_recv().is_ok());
+        assert!(matches!(
+            receiver.try_recv(),
+            Err(mpsc::error::TryRecvError::Disconnected)
+        ));
+        assert!(recent.get().is_empty());
+        assert!(recent.tail(QueryFilter::default()).is_none());
+    }
+
+    fn client(i: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, i))
+    }
+}
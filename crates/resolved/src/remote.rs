@@ -0,0 +1,608 @@
+use bytes::Bytes;
+use http::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, LOCATION};
+use http::{HeaderMap, Request, StatusCode, Uri};
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use siphasher::sip::SipHasher24;
+use std::fmt;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::fs::{create_dir_all, read_to_string, rename, write};
+use tokio::time::timeout;
+
+use dns_types::hosts::types::Hosts;
+
+pub const CANNOT_PARSE_HOSTS_URL: &str =
+    "expected an 'http://' or 'https://' URL, eg 'https://example.com/blocklist.txt'";
+
+/// The largest hosts file to download.
+const MAX_HOSTS_SIZE: usize = 256 * 1024 * 1024;
+
+/// How many redirects to follow when downloading a hosts file.
+const MAX_REDIRECTS: usize = 5;
+
+/// How long to spend downloading a hosts file before giving up.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The subdirectory of the download directory which the caching headers and
+/// checksum of each download are kept in.  This is skipped when the directory
+/// is read for hosts files.
+const STATE_DIR: &str = ".state";
+
+type HttpClient = Client<HttpsConnector<HttpConnector>, Empty<Bytes>>;
+
+/// A URL to download a hosts file (such as a blocklist) from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HostsUrl(Uri);
+
+impl HostsUrl {
+    /// Where the downloaded file is kept in `dir`: it is named after a hash
+    /// of the URL, so each URL has its own file.
+    pub fn path_in(&self, dir: &Path) -> PathBuf {
+        dir.join(format!("{}.hosts", self.file_stem()))
+    }
+
+    /// Where the caching headers and checksum of the downloaded file are kept
+    /// in `dir`.
+    fn state_path_in(&self, dir: &Path) -> PathBuf {
+        dir.join(STATE_DIR)
+            .join(format!("{}.toml", self.file_stem()))
+    }
+
+    fn file_stem(&self) -> String {
+        let mut hasher = SipHasher24::new();
+        hasher.write(self.0.to_string().as_bytes());
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+impl fmt::Display for HostsUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for HostsUrl {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let uri = Uri::from_str(s).map_err(|_| CANNOT_PARSE_HOSTS_URL)?;
+        match (uri.scheme_str(), uri.host()) {
+            (Some("http" | "https"), Some(host)) if !host.is_empty() => Ok(Self(uri)),
+            _ => Err(CANNOT_PARSE_HOSTS_URL),
+        }
+    }
+}
+
+/// What happened when a hosts file was refreshed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Refreshed {
+    /// The file has changed, and the new version has been saved.
+    Updated,
+
+    /// The file has not changed since it was last downloaded.
+    Unchanged,
+
+    /// The file could not be downloaded, or did not parse, so the previous
+    /// version (if there is one) has been kept.
+    Failed,
+}
+
+impl Refreshed {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Refreshed::Updated => "updated",
+            Refreshed::Unchanged => "unchanged",
+            Refreshed::Failed => "failed",
+        }
+    }
+}
+
+/// The caching headers and checksum of a downloaded hosts file, so that the
+/// next refresh can ask the server whether it has changed, and tell whether
+/// it has if the server doesn't say.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct DownloadState {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// In hex, as TOML integers can't hold every `u64`.
+    checksum: Option<String>,
+}
+
+/// Downloads hosts files, reusing connections across refreshes.
+///
+/// Invoking `clone` on a `Downloader` gives a new instance which shares the
+/// connections.
+#[derive(Debug, Clone)]
+pub struct Downloader {
+    client: HttpClient,
+}
+
+impl Default for Downloader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Downloader {
+    pub fn new() -> Self {
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_all_versions()
+            .build();
+
+        Self {
+            client: Client::builder(TokioExecutor::new()).build(connector),
+        }
+    }
+
+    /// Download a hosts file into `dir`, if it has changed since it was last
+    /// downloaded.
+    ///
+    /// The server is asked with `If-None-Match` and `If-Modified-Since`
+    /// whether the file has changed, and if it sends it anyway, its checksum
+    /// is compared with the last download's.  A new version is only saved if
+    /// it parses, and it replaces the old file atomically, so a failed
+    /// download or a bad file leaves the previous version in place.
+    pub async fn refresh(&self, url: &HostsUrl, dir: &Path) -> Refreshed {
+        let path = url.path_in(dir);
+        let state_path = url.state_path_in(dir);
+
+        // only send the caching headers if there's a file for them to be
+        // about
+        let state = if path.exists() {
+            read_state(&state_path).await
+        } else {
+            DownloadState::default()
+        };
+
+        let (headers, body) = match timeout(DOWNLOAD_TIMEOUT, self.get(url, &state)).await {
+            Ok(Ok(Some(response))) => response,
+            Ok(Ok(None)) => {
+                tracing::debug!(%url, "hosts file not modified");
+                return Refreshed::Unchanged;
+            }
+            Ok(Err(error)) => {
+                tracing::warn!(%url, %error, "could not download hosts file - keeping previous version");
+                return Refreshed::Failed;
+            }
+            Err(_) => {
+                tracing::warn!(%url, "timed out downloading hosts file - keeping previous version");
+                return Refreshed::Failed;
+            }
+        };
+
+        let mut hasher = SipHasher24::new();
+        hasher.write(&body);
+        let new_state = DownloadState {
+            etag: header_string(&headers, &ETAG),
+            last_modified: header_string(&headers, &LAST_MODIFIED),
+            checksum: Some(format!("{:016x}", hasher.finish())),
+        };
+
+        if new_state.checksum == state.checksum {
+            tracing::debug!(%url, "hosts file unchanged");
+            write_state(&state_path, &new_state).await;
+            return Refreshed::Unchanged;
+        }
+
+        let Ok(data) = String::from_utf8(body.into()) else {
+            tracing::warn!(%url, "hosts file is not UTF-8 - keeping previous version");
+            return Refreshed::Failed;
+        };
+        let data =
+            tokio::task::spawn_blocking(move || Hosts::deserialise(&data).map(|_| data)).await;
+        let data = match data {
+            Ok(Ok(data)) => data,
+            Ok(Err(error)) => {
+                tracing::warn!(%url, %error, "could not parse hosts file - keeping previous version");
+                return Refreshed::Failed;
+            }
+            Err(error) => {
+                tracing::warn!(%url, ?error, "could not parse hosts file - keeping previous version");
+                return Refreshed::Failed;
+            }
+        };
+
+        let tmp_path = dir.join(STATE_DIR).join(format!("{}.tmp", url.file_stem()));
+        if let Err(error) = save(&tmp_path, &path, data.as_bytes()).await {
+            tracing::warn!(%url, ?path, ?error, "could not save hosts file - keeping previous version");
+            return Refreshed::Failed;
+        }
+        write_state(&state_path, &new_state).await;
+        tracing::info!(%url, "downloaded new version of hosts file");
+        Refreshed::Updated
+    }
+
+    /// Send a GET request for a URL, with the caching headers from the last
+    /// download, following up to `MAX_REDIRECTS` redirects.  Returns `None`
+    /// if the server says it hasn't changed.
+    async fn get(
+        &self,
+        url: &HostsUrl,
+        state: &DownloadState,
+    ) -> Result<Option<(HeaderMap, Bytes)>, String> {
+        let mut uri = url.0.clone();
+        let mut redirects = 0;
+        let response = loop {
+            let mut request = Request::get(uri.clone());
+            if let Some(etag) = &state.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &state.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+            let request = request
+                .body(Empty::new())
+                .map_err(|error| error.to_string())?;
+
+            let response = self
+                .client
+                .request(request)
+                .await
+                .map_err(|error| error.to_string())?;
+            match response.status() {
+                StatusCode::NOT_MODIFIED => return Ok(None),
+                StatusCode::OK => break response,
+                status if status.is_redirection() => {
+                    if redirects == MAX_REDIRECTS {
+                        return Err(format!("more than {MAX_REDIRECTS} redirects"));
+                    }
+                    redirects += 1;
+                    let location = header_string(response.headers(), &LOCATION)
+                        .ok_or_else(|| format!("HTTP status {status} without a location"))?;
+                    uri = redirect_target(&uri, &location)
+                        .ok_or_else(|| format!("cannot follow redirect to '{location}'"))?;
+                }
+                status => return Err(format!("unexpected HTTP status {status}")),
+            }
+        };
+
+        let headers = response.headers().clone();
+        let body = Limited::new(response.into_body(), MAX_HOSTS_SIZE)
+            .collect()
+            .await
+            .map_err(|error| error.to_string())?
+            .to_bytes();
+        Ok(Some((headers, body)))
+    }
+}
+
+/// How long to wait before the next refresh: `interval`, plus up to a tenth of
+/// it again at random, so that many servers started at the same time don't
+/// all download their lists at once.
+pub fn jittered(interval: Duration) -> Duration {
+    interval + jitter(interval)
+}
+
+/// A random delay of up to a tenth of `interval`.
+pub fn jitter(interval: Duration) -> Duration {
+    let max = interval.as_millis() / 10;
+    let millis = rand::thread_rng().gen_range(0..=max);
+    Duration::from_millis(millis.try_into().unwrap_or(u64::MAX))
+}
+
+/// Where a redirect from `base` to `location` goes, if it's to an HTTP or
+/// HTTPS URL.  The location may be absolute, or relative to `base`.
+fn redirect_target(base: &Uri, location: &str) -> Option<Uri> {
+    if Uri::from_str(location).is_ok_and(|uri| uri.scheme().is_some()) {
+        return HostsUrl::from_str(location).ok().map(|url| url.0);
+    }
+    if location.starts_with("//") {
+        let url = format!("{}:{location}", base.scheme_str()?);
+        return HostsUrl::from_str(&url).ok().map(|url| url.0);
+    }
+
+    let path_and_query = if location.starts_with('/') {
+        location.to_string()
+    } else {
+        let base_path = base.path();
+        let dir = &base_path[..=base_path.rfind('/')?];
+        format!("{dir}{location}")
+    };
+    Uri::builder()
+        .scheme(base.scheme()?.clone())
+        .authority(base.authority()?.clone())
+        .path_and_query(path_and_query)
+        .build()
+        .ok()
+}
+
+fn header_string(headers: &HeaderMap, name: &http::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(ToString::to_string)
+}
+
+async fn read_state(path: &Path) -> DownloadState {
+    match read_to_string(path).await {
+        Ok(data) => toml::from_str(&data).unwrap_or_default(),
+        Err(_) => DownloadState::default(),
+    }
+}
+
+async fn write_state(path: &Path, state: &DownloadState) {
+    let Ok(data) = toml::to_string(state) else {
+        return;
+    };
+    if let Some(parent) = path.parent() {
+        _ = create_dir_all(parent).await;
+    }
+    if let Err(error) = write(path, data).await {
+        tracing::warn!(?path, ?error, "could not save hosts file download state");
+    }
+}
+
+/// Write a file to a temporary path, and then move it into place.
+async fn save(tmp_path: &Path, path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = tmp_path.parent() {
+        create_dir_all(parent).await?;
+    }
+    write(tmp_path, data).await?;
+    rename(tmp_path, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::test_util::TestDir;
+
+    const HOSTS: &str = "0.0.0.0 ads.example.com\n";
+
+    #[test]
+    fn hosts_url_from_str_accepts_http_and_https() {
+        for url in [
+            "http://example.com/hosts",
+            "https://example.com/hosts",
+            "https://example.com:8443/lists/hosts.txt?v=1",
+        ] {
+            assert_eq!(url, HostsUrl::from_str(url).unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn hosts_url_from_str_rejects_others() {
+        for url in [
+            "",
+            "example.com/hosts",
+            "/hosts",
+            "ftp://example.com/hosts",
+            "file:///etc/hosts",
+            "https://",
+            "not a url",
+        ] {
+            assert_eq!(
+                Err(CANNOT_PARSE_HOSTS_URL),
+                HostsUrl::from_str(url),
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn hosts_url_path_is_per_url() {
+        let dir = Path::new("/var/lib/resolved");
+        let a = HostsUrl::from_str("https://example.com/a").unwrap();
+        let b = HostsUrl::from_str("https://example.com/b").unwrap();
+
+        assert_eq!(a.path_in(dir), a.clone().path_in(dir));
+        assert_ne!(a.path_in(dir), b.path_in(dir));
+        assert_eq!(Some(dir), a.path_in(dir).parent());
+    }
+
+    #[test]
+    fn jitter_is_at_most_a_tenth() {
+        let interval = Duration::from_secs(100);
+        for _ in 0..1000 {
+            let jitter = jitter(interval);
+            assert!(jitter <= Duration::from_secs(10), "{jitter:?}");
+
+            let jittered = jittered(interval);
+            assert!(jittered >= interval, "{jittered:?}");
+            assert!(jittered <= Duration::from_secs(110), "{jittered:?}");
+        }
+    }
+
+    #[test]
+    fn jitter_of_zero_is_zero() {
+        assert_eq!(Duration::ZERO, jitter(Duration::ZERO));
+        assert_eq!(Duration::ZERO, jitter(Duration::from_millis(9)));
+    }
+
+    #[test]
+    fn redirect_target_resolves_locations() {
+        let base = Uri::from_static("https://example.com/lists/hosts.txt?v=1");
+
+        for (location, expected) in [
+            ("http://example.net/hosts", "http://example.net/hosts"),
+            ("//example.net/hosts", "https://example.net/hosts"),
+            ("/other/hosts", "https://example.com/other/hosts"),
+            ("hosts-v2.txt", "https://example.com/lists/hosts-v2.txt"),
+        ] {
+            assert_eq!(
+                Some(Uri::from_static(expected)),
+                redirect_target(&base, location),
+                "{location}"
+            );
+        }
+    }
+
+    #[test]
+    fn redirect_target_rejects_other_schemes() {
+        let base = Uri::from_static("https://example.com/hosts");
+
+        assert_eq!(None, redirect_target(&base, "ftp://example.com/hosts"));
+    }
+
+    #[tokio::test]
+    async fn refresh_saves_new_file() {
+        let dir = TestDir::new();
+        let (url, _) = serve(vec![ok("\"v1\"", HOSTS)]).await;
+
+        assert_eq!(
+            Refreshed::Updated,
+            Downloader::new().refresh(&url, &dir.path).await
+        );
+        assert_eq!(
+            HOSTS,
+            std::fs::read_to_string(url.path_in(&dir.path)).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn refresh_is_unchanged_if_checksum_matches() {
+        let dir = TestDir::new();
+        let (url, _) = serve(vec![ok("\"v1\"", HOSTS), ok("\"v2\"", HOSTS)]).await;
+        let downloader = Downloader::new();
+
+        assert_eq!(
+            Refreshed::Updated,
+            downloader.refresh(&url, &dir.path).await
+        );
+        assert_eq!(
+            Refreshed::Unchanged,
+            downloader.refresh(&url, &dir.path).await
+        );
+
+        // the new caching headers are still saved
+        let state = read_state(&url.state_path_in(&dir.path)).await;
+        assert_eq!(Some("\"v2\"".to_string()), state.etag);
+    }
+
+    #[tokio::test]
+    async fn refresh_is_unchanged_if_not_modified() {
+        let dir = TestDir::new();
+        let not_modified = "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_string();
+        let (url, requests) = serve(vec![ok("\"v1\"", HOSTS), not_modified]).await;
+        let downloader = Downloader::new();
+
+        assert_eq!(
+            Refreshed::Updated,
+            downloader.refresh(&url, &dir.path).await
+        );
+        assert_eq!(
+            Refreshed::Unchanged,
+            downloader.refresh(&url, &dir.path).await
+        );
+
+        let requests = requests.lock().unwrap();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\""));
+    }
+
+    #[tokio::test]
+    async fn refresh_keeps_previous_file_if_new_one_is_bad() {
+        let dir = TestDir::new();
+        let (url, _) = serve(vec![
+            ok("\"v1\"", HOSTS),
+            ok("\"v2\"", "not a hosts file\n"),
+        ])
+        .await;
+        let downloader = Downloader::new();
+
+        assert_eq!(
+            Refreshed::Updated,
+            downloader.refresh(&url, &dir.path).await
+        );
+        assert_eq!(Refreshed::Failed, downloader.refresh(&url, &dir.path).await);
+        assert_eq!(
+            HOSTS,
+            std::fs::read_to_string(url.path_in(&dir.path)).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn refresh_follows_redirects() {
+        let dir = TestDir::new();
+        let (url, requests) = serve(vec![
+            redirect("301 Moved Permanently", "/moved"),
+            redirect("302 Found", "hosts-v2"),
+            ok("\"v1\"", HOSTS),
+        ])
+        .await;
+
+        assert_eq!(
+            Refreshed::Updated,
+            Downloader::new().refresh(&url, &dir.path).await
+        );
+
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].starts_with("get /hosts "));
+        assert!(requests[1].starts_with("get /moved "));
+        assert!(requests[2].starts_with("get /hosts-v2 "));
+    }
+
+    #[tokio::test]
+    async fn refresh_gives_up_after_too_many_redirects() {
+        let dir = TestDir::new();
+        let responses = (0..=MAX_REDIRECTS)
+            .map(|_| redirect("302 Found", "/hosts"))
+            .collect();
+        let (url, requests) = serve(responses).await;
+
+        assert_eq!(
+            Refreshed::Failed,
+            Downloader::new().refresh(&url, &dir.path).await
+        );
+        assert_eq!(MAX_REDIRECTS + 1, requests.lock().unwrap().len());
+        assert!(!url.path_in(&dir.path).exists());
+    }
+
+    fn ok(etag: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nETag: {etag}\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    fn redirect(status: &str, location: &str) -> String {
+        format!("HTTP/1.1 {status}\r\nConnection: close\r\nLocation: {location}\r\nContent-Length: 0\r\n\r\n")
+    }
+
+    /// Serve each response, in order, to one connection, and record the
+    /// requests (lowercased).
+    async fn serve(responses: Vec<String>) -> (HostsUrl, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8_lossy(&request).to_lowercase());
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+
+        let url = HostsUrl::from_str(&format!("http://{address}/hosts")).unwrap();
+        (url, requests)
+    }
+}
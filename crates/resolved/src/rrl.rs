@@ -0,0 +1,546 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use dns_types::protocol::types::*;
+
+use crate::net::network;
+use crate::ratelimit::{Bucket, FULL_PRUNE_INTERVAL};
+
+const MUTEX_POISON_MESSAGE: &str =
+    "[INTERNAL ERROR] response rate limiter mutex poisoned, cannot recover from this - aborting";
+
+/// The most responses to keep buckets for.  When there are this many,
+/// responses whose buckets have refilled are forgotten, and if there are still
+/// too many an arbitrary response is forgotten to make room.
+pub const MAX_ENTRIES: usize = 65536;
+
+/// What to do with a UDP response.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Verdict {
+    /// Send it as normal.
+    Send,
+
+    /// Send an empty truncated response instead, so that a real client can
+    /// retry over TCP.
+    Slip,
+
+    /// Do not send anything.
+    Drop,
+}
+
+impl Verdict {
+    /// The name of the verdict, used as a metric label.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Verdict::Send => "send",
+            Verdict::Slip => "slip",
+            Verdict::Drop => "drop",
+        }
+    }
+}
+
+/// Response rate limiting (RRL), to stop `resolved` from being used to reflect
+/// and amplify traffic at a victim whose address has been spoofed.
+///
+/// Unlike `RateLimiter`, which counts queries from each address, this counts
+/// identical responses sent to each network: a client (or a victim) asking for
+/// many different names is unaffected, but one being sent the same answer
+/// over and over is limited.  Clients are grouped into /24 (IPv4) or /56
+/// (IPv6) networks, since a spoofer can pick any address in the victim's
+/// network.
+///
+/// Once a network goes over the limit for a response, every `slip`th response
+/// is replaced with an empty truncated response (so a real client can retry
+/// over TCP, which cannot be spoofed) and the rest are dropped.  A `slip` of
+/// zero drops them all.
+///
+/// A limit of zero means unlimited.
+///
+/// Invoking `clone` on a `ResponseRateLimiter` gives a new instance which
+/// refers to the same buckets.
+#[derive(Debug, Clone)]
+pub struct ResponseRateLimiter {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    limit: u32,
+    slip: u32,
+    max_entries: usize,
+    entries: HashMap<Key, Entry>,
+    /// When refilled buckets were last forgotten to make room for a new
+    /// response.
+    full_pruned: Option<Instant>,
+}
+
+/// Responses which count against the same limit.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+struct Key {
+    network: IpAddr,
+    kind: ResponseKind,
+}
+
+/// Which responses are considered the same.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+enum ResponseKind {
+    /// A positive (or empty) answer for a name and type.
+    Answer { name: DomainName, qtype: QueryType },
+
+    /// An NXDOMAIN from a zone: these are grouped by zone rather than by name,
+    /// as otherwise random subdomains would get around the limit.
+    NameError { zone: DomainName },
+
+    /// Any other error.
+    Error,
+}
+
+#[derive(Debug)]
+struct Entry {
+    bucket: Bucket,
+    limited: u32,
+}
+
+impl ResponseRateLimiter {
+    /// Create a new response rate limiter.
+    pub fn new(limit: u32, slip: u32) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                limit,
+                slip,
+                max_entries: MAX_ENTRIES,
+                entries: HashMap::new(),
+                full_pruned: None,
+            })),
+        }
+    }
+
+    /// Decide what to do with a response to a UDP query from `peer`.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn check(&self, peer: IpAddr, response: &Message) -> Verdict {
+        self.check_at(peer, response, Instant::now())
+    }
+
+    fn check_at(&self, peer: IpAddr, response: &Message, now: Instant) -> Verdict {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        if inner.limit == 0 {
+            return Verdict::Send;
+        }
+
+        let key = Key {
+            network: network(peer),
+            kind: ResponseKind::of(response),
+        };
+        if !inner.entries.contains_key(&key) {
+            inner.make_room(now);
+        }
+
+        let Inner {
+            limit,
+            slip,
+            entries,
+            ..
+        } = &mut *inner;
+
+        let entry = entries.entry(key).or_insert_with(|| Entry {
+            bucket: Bucket::full(*limit, now),
+            limited: 0,
+        });
+
+        if entry.bucket.refill(*limit, now) {
+            entry.bucket.take();
+            entry.limited = 0;
+            return Verdict::Send;
+        }
+
+        entry.limited = entry.limited.wrapping_add(1);
+        if *slip != 0 && entry.limited % *slip == 0 {
+            Verdict::Slip
+        } else {
+            Verdict::Drop
+        }
+    }
+
+    /// Change the limits.  Every bucket is refilled, unless the limits are
+    /// unchanged.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn set_limits(&self, limit: u32, slip: u32) {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        if inner.limit == limit && inner.slip == slip {
+            return;
+        }
+
+        inner.limit = limit;
+        inner.slip = slip;
+        inner.entries.clear();
+        inner.full_pruned = None;
+    }
+
+    /// Forget about responses whose buckets have refilled.
+    ///
+    /// Returns the number of responses still being tracked.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn prune(&self) -> usize {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        inner.prune(Instant::now());
+        inner.entries.len()
+    }
+}
+
+impl Inner {
+    fn prune(&mut self, now: Instant) {
+        let limit = self.limit;
+        self.entries.retain(|_, entry| {
+            entry.bucket.refill(limit, now);
+            !entry.bucket.is_full(limit)
+        });
+    }
+
+    /// Make sure there is room for a bucket for a new response.
+    fn make_room(&mut self, now: Instant) {
+        if self.entries.len() < self.max_entries {
+            return;
+        }
+
+        if self
+            .full_pruned
+            .is_none_or(|then| now.saturating_duration_since(then) >= FULL_PRUNE_INTERVAL)
+        {
+            self.prune(now);
+            self.full_pruned = Some(now);
+        }
+        while self.entries.len() >= self.max_entries {
+            let Some(evicted) = self.entries.keys().next().cloned() else {
+                break;
+            };
+            self.entries.remove(&evicted);
+        }
+    }
+}
+
+impl ResponseKind {
+    fn of(response: &Message) -> Self {
+        let Some(question) = response.questions.first() else {
+            return ResponseKind::Error;
+        };
+
+        match response.header.rcode {
+            Rcode::NoError => ResponseKind::Answer {
+                name: question.name.clone(),
+                qtype: question.qtype,
+            },
+            Rcode::NameError => ResponseKind::NameError {
+                zone: response
+                    .authority
+                    .iter()
+                    .find(|rr| rr.rtype_with_data.rtype() == RecordType::SOA)
+                    .map_or_else(|| question.name.clone(), |rr| rr.name.clone()),
+            },
+            _ => ResponseKind::Error,
+        }
+    }
+}
+
+/// The empty truncated response sent instead of `response` when it slips.
+pub fn slipped(response: &Message) -> Message {
+    let mut slipped = response.clone();
+    slipped.header.is_truncated = true;
+    slipped.answers.clear();
+    slipped.authority.clear();
+    slipped.additional.clear();
+    slipped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::time::Duration;
+
+    use dns_types::protocol::types::test_util::*;
+
+    use super::*;
+
+    #[test]
+    fn unlimited_always_sends() {
+        let rrl = ResponseRateLimiter::new(0, 2);
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            assert_eq!(
+                Verdict::Send,
+                rrl.check_at(ipv4(10, 0, 0, 1), &a_response("www.example.com."), now)
+            );
+        }
+    }
+
+    #[test]
+    fn slips_every_nth_response_over_limit() {
+        let rrl = ResponseRateLimiter::new(2, 3);
+        let now = Instant::now();
+        let response = a_response("www.example.com.");
+
+        let verdicts: Vec<Verdict> = (0..8)
+            .map(|_| rrl.check_at(ipv4(10, 0, 0, 1), &response, now))
+            .collect();
+
+        assert_eq!(
+            vec![
+                Verdict::Send,
+                Verdict::Send,
+                Verdict::Drop,
+                Verdict::Drop,
+                Verdict::Slip,
+                Verdict::Drop,
+                Verdict::Drop,
+                Verdict::Slip,
+            ],
+            verdicts
+        );
+    }
+
+    #[test]
+    fn slip_of_zero_drops_everything_over_limit() {
+        let rrl = ResponseRateLimiter::new(1, 0);
+        let now = Instant::now();
+        let response = a_response("www.example.com.");
+
+        assert_eq!(
+            Verdict::Send,
+            rrl.check_at(ipv4(10, 0, 0, 1), &response, now)
+        );
+        for _ in 0..10 {
+            assert_eq!(
+                Verdict::Drop,
+                rrl.check_at(ipv4(10, 0, 0, 1), &response, now)
+            );
+        }
+    }
+
+    #[test]
+    fn sends_again_once_refilled() {
+        let rrl = ResponseRateLimiter::new(1, 2);
+        let now = Instant::now();
+        let response = a_response("www.example.com.");
+
+        assert_eq!(
+            Verdict::Send,
+            rrl.check_at(ipv4(10, 0, 0, 1), &response, now)
+        );
+        assert_eq!(
+            Verdict::Drop,
+            rrl.check_at(ipv4(10, 0, 0, 1), &response, now)
+        );
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            Verdict::Send,
+            rrl.check_at(ipv4(10, 0, 0, 1), &response, later)
+        );
+        // the slip count starts again
+        assert_eq!(
+            Verdict::Drop,
+            rrl.check_at(ipv4(10, 0, 0, 1), &response, later)
+        );
+        assert_eq!(
+            Verdict::Slip,
+            rrl.check_at(ipv4(10, 0, 0, 1), &response, later)
+        );
+    }
+
+    #[test]
+    fn groups_ipv4_clients_by_network() {
+        let rrl = ResponseRateLimiter::new(1, 0);
+        let now = Instant::now();
+        let response = a_response("www.example.com.");
+
+        assert_eq!(
+            Verdict::Send,
+            rrl.check_at(ipv4(10, 0, 0, 1), &response, now)
+        );
+        assert_eq!(
+            Verdict::Drop,
+            rrl.check_at(ipv4(10, 0, 0, 200), &response, now)
+        );
+        assert_eq!(
+            Verdict::Send,
+            rrl.check_at(ipv4(10, 0, 1, 1), &response, now)
+        );
+    }
+
+    #[test]
+    fn groups_ipv6_clients_by_network() {
+        let rrl = ResponseRateLimiter::new(1, 0);
+        let now = Instant::now();
+        let response = a_response("www.example.com.");
+
+        assert_eq!(Verdict::Send, rrl.check_at(ipv6(0x0100, 1), &response, now));
+        assert_eq!(Verdict::Drop, rrl.check_at(ipv6(0x01ff, 2), &response, now));
+        assert_eq!(Verdict::Send, rrl.check_at(ipv6(0x0200, 1), &response, now));
+    }
+
+    #[test]
+    fn counts_different_answers_separately() {
+        let rrl = ResponseRateLimiter::new(1, 0);
+        let now = Instant::now();
+
+        assert_eq!(
+            Verdict::Send,
+            rrl.check_at(ipv4(10, 0, 0, 1), &a_response("www.example.com."), now)
+        );
+        assert_eq!(
+            Verdict::Send,
+            rrl.check_at(ipv4(10, 0, 0, 1), &a_response("mail.example.com."), now)
+        );
+        assert_eq!(
+            Verdict::Drop,
+            rrl.check_at(ipv4(10, 0, 0, 1), &a_response("www.example.com."), now)
+        );
+    }
+
+    #[test]
+    fn groups_name_errors_by_zone() {
+        let rrl = ResponseRateLimiter::new(1, 0);
+        let now = Instant::now();
+
+        assert_eq!(
+            Verdict::Send,
+            rrl.check_at(ipv4(10, 0, 0, 1), &name_error("a.example.com."), now)
+        );
+        assert_eq!(
+            Verdict::Drop,
+            rrl.check_at(ipv4(10, 0, 0, 1), &name_error("b.example.com."), now)
+        );
+    }
+
+    #[test]
+    fn check_caps_number_of_entries() {
+        let rrl = ResponseRateLimiter::new(1, 0);
+        rrl.inner.lock().unwrap().max_entries = 4;
+        let now = Instant::now();
+        let response = a_response("www.example.com.");
+
+        for i in 0..100 {
+            assert_eq!(
+                Verdict::Send,
+                rrl.check_at(ipv4(10, 0, i, 1), &response, now)
+            );
+        }
+        assert!(rrl.inner.lock().unwrap().entries.len() <= 4);
+    }
+
+    #[test]
+    fn check_forgets_refilled_entries_before_evicting() {
+        let rrl = ResponseRateLimiter::new(1, 0);
+        rrl.inner.lock().unwrap().max_entries = 2;
+        let now = Instant::now();
+        let response = a_response("www.example.com.");
+
+        assert_eq!(
+            Verdict::Send,
+            rrl.check_at(ipv4(10, 0, 0, 1), &response, now)
+        );
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            Verdict::Send,
+            rrl.check_at(ipv4(10, 0, 1, 1), &response, later)
+        );
+        assert_eq!(
+            Verdict::Send,
+            rrl.check_at(ipv4(10, 0, 2, 1), &response, later)
+        );
+
+        // the first network's bucket refilled, so it was forgotten, and the
+        // second is still limited
+        assert_eq!(
+            Verdict::Drop,
+            rrl.check_at(ipv4(10, 0, 1, 1), &response, later)
+        );
+    }
+
+    #[test]
+    fn prune_forgets_refilled_entries() {
+        let rrl = ResponseRateLimiter::new(1, 0);
+        let response = a_response("www.example.com.");
+        assert_eq!(Verdict::Send, rrl.check(ipv4(10, 0, 0, 1), &response));
+        assert_eq!(1, rrl.inner.lock().unwrap().entries.len());
+
+        rrl.inner
+            .lock()
+            .unwrap()
+            .prune(Instant::now() + Duration::from_secs(1));
+        assert_eq!(0, rrl.inner.lock().unwrap().entries.len());
+    }
+
+    #[test]
+    fn set_limits_refills_only_if_changed() {
+        let rrl = ResponseRateLimiter::new(1, 0);
+        let response = a_response("www.example.com.");
+
+        assert_eq!(Verdict::Send, rrl.check(ipv4(10, 0, 0, 1), &response));
+        rrl.set_limits(1, 0);
+        assert_eq!(Verdict::Drop, rrl.check(ipv4(10, 0, 0, 1), &response));
+        rrl.set_limits(2, 0);
+        assert_eq!(Verdict::Send, rrl.check(ipv4(10, 0, 0, 1), &response));
+    }
+
+    #[test]
+    fn slipped_is_empty_and_truncated() {
+        let mut response = name_error("www.example.com.");
+        response.header.id = 1234;
+        response
+            .answers
+            .push(a_record("www.example.com.", Ipv4Addr::new(1, 1, 1, 1)));
+        response
+            .additional
+            .push(a_record("ns.example.com.", Ipv4Addr::new(2, 2, 2, 2)));
+
+        let slipped = slipped(&response);
+
+        assert!(slipped.header.is_truncated);
+        assert_eq!(1234, slipped.header.id);
+        assert_eq!(Rcode::NameError, slipped.header.rcode);
+        assert_eq!(response.questions, slipped.questions);
+        assert!(slipped.answers.is_empty());
+        assert!(slipped.authority.is_empty());
+        assert!(slipped.additional.is_empty());
+    }
+
+    fn name_error(name: &str) -> Message {
+        let mut response = Message::from_question(0, question(name)).make_response();
+        response.header.rcode = Rcode::NameError;
+        response.authority.push(ResourceRecord {
+            name: domain("example.com."),
+            rtype_with_data: RecordTypeWithData::SOA {
+                mname: domain("ns.example.com."),
+                rname: domain("hostmaster.example.com."),
+                serial: 1,
+                refresh: 30,
+                retry: 30,
+                expire: 30,
+                minimum: 30,
+            },
+            rclass: RecordClass::IN,
+            ttl: 300,
+        });
+        response
+    }
+
+    fn ipv4(a: u8, b: u8, c: u8, d: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+    }
+
+    fn ipv6(subnet: u16, host: u16) -> IpAddr {
+        IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, subnet, 0, 0, 0, host))
+    }
+}
@@ -0,0 +1,185 @@
+//! The platform-specific ways of asking `resolved` to reload, toggle debug
+//! logging, or shut down.
+//!
+//! On unix these are signals: `SIGUSR1` or `SIGHUP` to reload, `SIGUSR2` to
+//! toggle debug logging, and `SIGTERM` or `SIGINT` to shut down.
+//!
+//! On Windows they are console events: `CTRL_BREAK_EVENT` to reload, and
+//! `CTRL_C_EVENT`, `CTRL_CLOSE_EVENT`, or `CTRL_SHUTDOWN_EVENT` to shut down.
+//! There is no console event to toggle debug logging, use the control socket to
+//! change the log filter instead.
+
+#[cfg(unix)]
+pub use unix::*;
+#[cfg(windows)]
+pub use windows::*;
+
+#[cfg(unix)]
+mod unix {
+    use std::io;
+    use tokio::signal::unix::{signal, Signal, SignalKind};
+
+    /// Asks `resolved` to reload its configuration.
+    pub struct ReloadSignals {
+        sigusr1: Signal,
+        sighup: Signal,
+    }
+
+    impl ReloadSignals {
+        /// Subscribe to `SIGUSR1` and `SIGHUP`.
+        ///
+        /// # Errors
+        ///
+        /// If the signal handlers cannot be installed.
+        pub fn new() -> io::Result<Self> {
+            Ok(Self {
+                sigusr1: signal(SignalKind::user_defined1())?,
+                sighup: signal(SignalKind::hangup())?,
+            })
+        }
+
+        /// Wait for the next signal, and return its name.
+        pub async fn recv(&mut self) -> &'static str {
+            tokio::select! {
+                _ = self.sigusr1.recv() => "SIGUSR1",
+                _ = self.sighup.recv() => "SIGHUP",
+            }
+        }
+    }
+
+    /// Asks `resolved` to switch between the normal log filter and debug
+    /// logging.
+    pub struct DebugLoggingSignals {
+        sigusr2: Signal,
+    }
+
+    impl DebugLoggingSignals {
+        /// Subscribe to `SIGUSR2`.
+        ///
+        /// # Errors
+        ///
+        /// If the signal handler cannot be installed.
+        pub fn new() -> io::Result<Self> {
+            Ok(Self {
+                sigusr2: signal(SignalKind::user_defined2())?,
+            })
+        }
+
+        /// Wait for the next signal, and return its name.
+        pub async fn recv(&mut self) -> &'static str {
+            self.sigusr2.recv().await;
+            "SIGUSR2"
+        }
+    }
+
+    /// Asks `resolved` to shut down.
+    pub struct ShutdownSignals {
+        sigterm: Signal,
+        sigint: Signal,
+    }
+
+    impl ShutdownSignals {
+        /// Subscribe to `SIGTERM` and `SIGINT`.
+        ///
+        /// # Errors
+        ///
+        /// If the signal handlers cannot be installed.
+        pub fn new() -> io::Result<Self> {
+            Ok(Self {
+                sigterm: signal(SignalKind::terminate())?,
+                sigint: signal(SignalKind::interrupt())?,
+            })
+        }
+
+        /// Wait for the next signal, and return its name.
+        pub async fn recv(&mut self) -> &'static str {
+            tokio::select! {
+                _ = self.sigterm.recv() => "SIGTERM",
+                _ = self.sigint.recv() => "SIGINT",
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::future;
+    use std::io;
+    use tokio::signal::windows::{
+        ctrl_break, ctrl_c, ctrl_close, ctrl_shutdown, CtrlBreak, CtrlC, CtrlClose, CtrlShutdown,
+    };
+
+    /// Asks `resolved` to reload its configuration.
+    pub struct ReloadSignals {
+        ctrl_break: CtrlBreak,
+    }
+
+    impl ReloadSignals {
+        /// Subscribe to `CTRL_BREAK_EVENT`.
+        ///
+        /// # Errors
+        ///
+        /// If the console event handler cannot be installed.
+        pub fn new() -> io::Result<Self> {
+            Ok(Self {
+                ctrl_break: ctrl_break()?,
+            })
+        }
+
+        /// Wait for the next event, and return its name.
+        pub async fn recv(&mut self) -> &'static str {
+            self.ctrl_break.recv().await;
+            "CTRL_BREAK_EVENT"
+        }
+    }
+
+    /// Asks `resolved` to switch between the normal log filter and debug
+    /// logging: this never happens on Windows.
+    pub struct DebugLoggingSignals;
+
+    impl DebugLoggingSignals {
+        /// # Errors
+        ///
+        /// Never.
+        pub fn new() -> io::Result<Self> {
+            Ok(Self)
+        }
+
+        /// Wait forever.
+        pub async fn recv(&mut self) -> &'static str {
+            future::pending().await
+        }
+    }
+
+    /// Asks `resolved` to shut down.
+    pub struct ShutdownSignals {
+        ctrl_c: CtrlC,
+        ctrl_close: CtrlClose,
+        ctrl_shutdown: CtrlShutdown,
+    }
+
+    impl ShutdownSignals {
+        /// Subscribe to `CTRL_C_EVENT`, `CTRL_CLOSE_EVENT`, and
+        /// `CTRL_SHUTDOWN_EVENT`.
+        ///
+        /// # Errors
+        ///
+        /// If the console event handlers cannot be installed.
+        pub fn new() -> io::Result<Self> {
+            Ok(Self {
+                ctrl_c: ctrl_c()?,
+                ctrl_close: ctrl_close()?,
+                ctrl_shutdown: ctrl_shutdown()?,
+            })
+        }
+
+        /// Wait for the next event, and return its name.
+        pub async fn recv(&mut self) -> &'static str {
+            tokio::select! {
+                _ = self.ctrl_c.recv() => "CTRL_C_EVENT",
+                _ = self.ctrl_close.recv() => "CTRL_CLOSE_EVENT",
+                _ = self.ctrl_shutdown.recv() => "CTRL_SHUTDOWN_EVENT",
+            }
+        }
+    }
+}
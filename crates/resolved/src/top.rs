@@ -0,0 +1,424 @@
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use dns_types::protocol::types::{DomainName, Question};
+
+/// How many names and clients to show, if not told otherwise.
+pub const DEFAULT_TOP_COUNT: usize = 10;
+
+const MUTEX_POISON_MESSAGE: &str =
+    "[INTERNAL ERROR] top queries mutex poisoned, cannot recover from this - aborting";
+
+/// Approximate counts of the most queried names, the clients making the most
+/// queries, and the names each of those clients asks about most, so that the
+/// admin API can show who is making all the queries and what for.
+///
+/// This uses the "Space-Saving" algorithm: only a fixed number of names,
+/// clients, and pairs are counted, and when a new one turns up the one with
+/// the smallest count is replaced, with the new one inheriting its count.  So
+/// memory use is bounded no matter how many different names are queried, the
+/// most frequent entries are always kept, and each count is too high by at
+/// most its `error`.
+///
+/// Invoking `clone` on a `TopQueries` gives a new instance which refers to
+/// the same counts.
+#[derive(Debug, Clone)]
+pub struct TopQueries {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    capacity: usize,
+    metrics: usize,
+    since: SystemTime,
+    names: SpaceSaving<DomainName>,
+    clients: SpaceSaving<IpAddr>,
+    client_names: SpaceSaving<(IpAddr, DomainName)>,
+}
+
+/// The most frequent names and clients.
+#[derive(Debug, Clone, Serialize)]
+pub struct TopSummary {
+    /// When counting started, in seconds since the UNIX epoch.
+    pub since: u64,
+    pub names: Vec<TopEntry<String>>,
+    pub clients: Vec<TopEntry<IpAddr>>,
+    pub client_names: Vec<TopEntry<ClientName>>,
+}
+
+/// A name, client, or pair, and how many queries there have been for it.
+#[derive(Debug, Clone, Serialize)]
+pub struct TopEntry<T> {
+    pub value: T,
+    pub count: u64,
+    /// How much `count` may be over by.
+    pub error: u64,
+}
+
+/// A client and a name it has queried.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientName {
+    pub client: IpAddr,
+    pub name: String,
+}
+
+impl TopQueries {
+    /// Create counters which track up to `capacity` names, clients, and
+    /// pairs, exporting the top `metrics` names and clients as metrics.  A
+    /// capacity of zero means nothing is counted.
+    pub fn new(capacity: usize, metrics: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                metrics,
+                since: SystemTime::now(),
+                names: SpaceSaving::default(),
+                clients: SpaceSaving::default(),
+                client_names: SpaceSaving::default(),
+            })),
+        }
+    }
+
+    /// Count a query.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn record(&self, client: IpAddr, question: Option<&Question>) {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        let capacity = inner.capacity;
+        if capacity == 0 {
+            return;
+        }
+
+        inner.clients.record(client, capacity);
+        if let Some(question) = question {
+            inner.names.record(question.name.clone(), capacity);
+            inner
+                .client_names
+                .record((client, question.name.clone()), capacity);
+        }
+    }
+
+    /// Get the `count` most frequent names, clients, and pairs, most frequent
+    /// first.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn get(&self, count: usize) -> TopSummary {
+        let inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        TopSummary {
+            since: inner
+                .since
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            names: inner.names.top(count, DomainName::to_dotted_string),
+            clients: inner.clients.top(count, |client| *client),
+            client_names: inner.client_names.top(count, |(client, name)| ClientName {
+                client: *client,
+                name: name.to_dotted_string(),
+            }),
+        }
+    }
+
+    /// Get the most frequent names and clients to export as metrics.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn get_for_metrics(&self) -> TopSummary {
+        let count = self.inner.lock().expect(MUTEX_POISON_MESSAGE).metrics;
+        let mut summary = self.get(count);
+        summary.client_names.clear();
+        summary
+    }
+
+    /// Forget every count and start again.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn reset(&self) {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        inner.since = SystemTime::now();
+        inner.names.counts.clear();
+        inner.clients.counts.clear();
+        inner.client_names.counts.clear();
+    }
+
+    /// Change how many names, clients, and pairs are tracked, and how many
+    /// are exported as metrics.  If the capacity shrinks, the least frequent
+    /// are dropped.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn set_limits(&self, capacity: usize, metrics: usize) {
+        let mut inner = self.inner.lock().expect(MUTEX_POISON_MESSAGE);
+        inner.capacity = capacity;
+        inner.metrics = metrics;
+        inner.names.truncate(capacity);
+        inner.clients.truncate(capacity);
+        inner.client_names.truncate(capacity);
+    }
+}
+
+/// Counts for the most frequent values of some type.
+#[derive(Debug)]
+struct SpaceSaving<K> {
+    counts: HashMap<K, Count>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Count {
+    count: u64,
+    error: u64,
+}
+
+impl<K> Default for SpaceSaving<K> {
+    fn default() -> Self {
+        Self {
+            counts: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> SpaceSaving<K> {
+    fn record(&mut self, key: K, capacity: usize) {
+        if let Some(count) = self.counts.get_mut(&key) {
+            count.count += 1;
+            return;
+        }
+
+        if self.counts.len() < capacity {
+            self.counts.insert(key, Count { count: 1, error: 0 });
+            return;
+        }
+
+        if let Some((smallest, min)) = self
+            .counts
+            .iter()
+            .min_by_key(|(_, count)| count.count)
+            .map(|(key, count)| (key.clone(), count.count))
+        {
+            self.counts.remove(&smallest);
+            self.counts.insert(
+                key,
+                Count {
+                    count: min + 1,
+                    error: min,
+                },
+            );
+        }
+    }
+
+    fn top<T>(&self, n: usize, value: impl Fn(&K) -> T) -> Vec<TopEntry<T>> {
+        let mut entries = self.counts.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|(_, count)| Reverse(count.count));
+        entries
+            .into_iter()
+            .take(n)
+            .map(|(key, count)| TopEntry {
+                value: value(key),
+                count: count.count,
+                error: count.error,
+            })
+            .collect()
+    }
+
+    fn truncate(&mut self, capacity: usize) {
+        if self.counts.len() <= capacity {
+            return;
+        }
+
+        let mut entries = self.counts.drain().collect::<Vec<_>>();
+        entries.sort_by_key(|(_, count)| Reverse(count.count));
+        entries.truncate(capacity);
+        self.counts = entries.into_iter().collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use dns_types::protocol::types::test_util::*;
+
+    use super::*;
+
+    #[test]
+    fn counts_names_clients_and_pairs() {
+        let top = TopQueries::new(10, 10);
+        for _ in 0..3 {
+            top.record(client(1), Some(&question("a.example.com.")));
+        }
+        for _ in 0..2 {
+            top.record(client(2), Some(&question("b.example.com.")));
+        }
+        top.record(client(2), Some(&question("a.example.com.")));
+
+        let summary = top.get(10);
+
+        assert_eq!(
+            vec![
+                ("a.example.com.".to_string(), 4),
+                ("b.example.com.".to_string(), 2)
+            ],
+            counts(&summary.names)
+        );
+        assert_eq!(vec![(client(1), 3), (client(2), 3)], {
+            let mut clients = counts(&summary.clients);
+            clients.sort();
+            clients
+        });
+        assert_eq!(
+            vec![
+                ((client(1), "a.example.com.".to_string()), 3),
+                ((client(2), "b.example.com.".to_string()), 2),
+                ((client(2), "a.example.com.".to_string()), 1),
+            ],
+            summary
+                .client_names
+                .iter()
+                .map(|entry| ((entry.value.client, entry.value.name.clone()), entry.count))
+                .collect::<Vec<_>>()
+        );
+        assert!(summary.names.iter().all(|entry| entry.error == 0));
+    }
+
+    #[test]
+    fn counts_questionless_queries_by_client_only() {
+        let top = TopQueries::new(10, 10);
+
+        top.record(client(1), None);
+
+        let summary = top.get(10);
+        assert_eq!(vec![(client(1), 1)], counts(&summary.clients));
+        assert!(summary.names.is_empty());
+        assert!(summary.client_names.is_empty());
+    }
+
+    #[test]
+    fn get_limits_count() {
+        let top = TopQueries::new(10, 10);
+        for (i, name) in ["a.", "b.", "c."].into_iter().enumerate() {
+            for _ in 0..=i {
+                top.record(client(1), Some(&question(name)));
+            }
+        }
+
+        assert_eq!(
+            vec![("c.".to_string(), 3), ("b.".to_string(), 2)],
+            counts(&top.get(2).names)
+        );
+    }
+
+    #[test]
+    fn replaces_smallest_when_full() {
+        let top = TopQueries::new(2, 2);
+        for _ in 0..5 {
+            top.record(client(1), Some(&question("a.")));
+        }
+        for _ in 0..2 {
+            top.record(client(1), Some(&question("b.")));
+        }
+
+        // "c." replaces "b.", inheriting its count as the error
+        top.record(client(1), Some(&question("c.")));
+
+        let names = top.get(10).names;
+        assert_eq!(
+            vec![("a.".to_string(), 5, 0), ("c.".to_string(), 3, 2)],
+            names
+                .iter()
+                .map(|entry| (entry.value.clone(), entry.count, entry.error))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn frequent_names_are_kept() {
+        let top = TopQueries::new(3, 3);
+
+        for i in 0..100 {
+            top.record(client(1), Some(&question("frequent.")));
+            top.record(client(1), Some(&question(&format!("rare{i}."))));
+        }
+
+        assert_eq!("frequent.", top.get(1).names[0].value);
+        assert_eq!(100, top.get(1).names[0].count);
+    }
+
+    #[test]
+    fn capacity_zero_counts_nothing() {
+        let top = TopQueries::new(0, 10);
+
+        top.record(client(1), Some(&question("a.")));
+
+        let summary = top.get(10);
+        assert!(summary.names.is_empty());
+        assert!(summary.clients.is_empty());
+    }
+
+    #[test]
+    fn get_for_metrics_omits_pairs() {
+        let top = TopQueries::new(10, 1);
+        top.record(client(1), Some(&question("a.")));
+        top.record(client(1), Some(&question("a.")));
+        top.record(client(2), Some(&question("b.")));
+
+        let summary = top.get_for_metrics();
+
+        assert_eq!(vec![("a.".to_string(), 2)], counts(&summary.names));
+        assert_eq!(vec![(client(1), 2)], counts(&summary.clients));
+        assert!(summary.client_names.is_empty());
+    }
+
+    #[test]
+    fn set_limits_drops_least_frequent() {
+        let top = TopQueries::new(10, 10);
+        for (i, name) in ["a.", "b.", "c."].into_iter().enumerate() {
+            for _ in 0..=i {
+                top.record(client(1), Some(&question(name)));
+            }
+        }
+
+        top.set_limits(2, 2);
+
+        assert_eq!(
+            vec![("c.".to_string(), 3), ("b.".to_string(), 2)],
+            counts(&top.get(10).names)
+        );
+    }
+
+    #[test]
+    fn reset_forgets_everything() {
+        let top = TopQueries::new(10, 10);
+        top.record(client(1), Some(&question("a.")));
+
+        top.reset();
+
+        let summary = top.get(10);
+        assert!(summary.names.is_empty());
+        assert!(summary.clients.is_empty());
+        assert!(summary.client_names.is_empty());
+    }
+
+    fn counts<T: Clone>(entries: &[TopEntry<T>]) -> Vec<(T, u64)> {
+        entries
+            .iter()
+            .map(|entry| (entry.value.clone(), entry.count))
+            .collect()
+    }
+
+    fn client(i: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, i))
+    }
+}
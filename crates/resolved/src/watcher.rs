@@ -0,0 +1,137 @@
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+/// How long to wait after a change for things to settle down before reporting
+/// it, so that an editor saving a file, or a script rewriting several, only
+/// causes one reload.
+pub const DEBOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+const MUTEX_POISON_MESSAGE: &str =
+    "[INTERNAL ERROR] file watcher mutex poisoned, cannot recover from this - aborting";
+
+/// Watches hosts and zone files, and the directories they are read from, for
+/// changes.
+///
+/// Files are watched through their parent directory, so that a file which is
+/// replaced (as many editors do when saving) or which does not exist yet is
+/// still noticed.
+pub struct FileWatcher {
+    watcher: RecommendedWatcher,
+    watched_dirs: HashSet<PathBuf>,
+    interesting: Arc<Mutex<Interesting>>,
+    changes: mpsc::UnboundedReceiver<()>,
+}
+
+/// The paths which count as a change.
+#[derive(Debug, Default)]
+struct Interesting {
+    files: HashSet<PathBuf>,
+    dirs: HashSet<PathBuf>,
+}
+
+impl Interesting {
+    fn matches(&self, path: &Path) -> bool {
+        self.files.contains(path)
+            || self.dirs.contains(path)
+            || path.parent().is_some_and(|dir| self.dirs.contains(dir))
+    }
+}
+
+impl FileWatcher {
+    /// Create a new watcher, which isn't watching anything yet.
+    ///
+    /// # Errors
+    ///
+    /// If the platform file watching API cannot be used.
+    pub fn new() -> notify::Result<Self> {
+        let interesting = Arc::new(Mutex::new(Interesting::default()));
+        let (tx, changes) = mpsc::unbounded_channel();
+
+        let watcher = {
+            let interesting = interesting.clone();
+            notify::recommended_watcher(move |result: notify::Result<Event>| match result {
+                Ok(event) => {
+                    if matches!(event.kind, EventKind::Access(_)) {
+                        return;
+                    }
+                    let interesting = interesting.lock().expect(MUTEX_POISON_MESSAGE);
+                    if event.paths.iter().any(|path| interesting.matches(path)) {
+                        // the receiver is only dropped when the watcher is
+                        let _ = tx.send(());
+                    }
+                }
+                Err(error) => tracing::warn!(?error, "file watcher error"),
+            })?
+        };
+
+        Ok(Self {
+            watcher,
+            watched_dirs: HashSet::new(),
+            interesting,
+            changes,
+        })
+    }
+
+    /// Watch exactly these files and directories, replacing whatever was
+    /// being watched before.  Paths which cannot be watched (for example,
+    /// because the directory does not exist) are logged and skipped.
+    ///
+    /// # Panics
+    ///
+    /// If the mutex has been poisoned.
+    pub fn watch(&mut self, files: &[PathBuf], dirs: &[PathBuf]) {
+        let files: HashSet<PathBuf> = files.iter().map(|path| absolute(path)).collect();
+        let dirs: HashSet<PathBuf> = dirs.iter().map(|path| absolute(path)).collect();
+
+        let mut to_watch: HashSet<PathBuf> = files
+            .iter()
+            .filter_map(|path| path.parent().map(Path::to_path_buf))
+            .collect();
+        to_watch.extend(dirs.iter().cloned());
+
+        for dir in self.watched_dirs.difference(&to_watch) {
+            if let Err(error) = self.watcher.unwatch(dir) {
+                tracing::debug!(?dir, ?error, "could not stop watching directory");
+            }
+        }
+
+        let mut watched_dirs = HashSet::with_capacity(to_watch.len());
+        for dir in to_watch {
+            if self.watched_dirs.contains(&dir) {
+                watched_dirs.insert(dir);
+            } else {
+                match self.watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                    Ok(()) => {
+                        watched_dirs.insert(dir);
+                    }
+                    Err(error) => tracing::warn!(?dir, ?error, "could not watch directory"),
+                }
+            }
+        }
+        self.watched_dirs = watched_dirs;
+
+        *self.interesting.lock().expect(MUTEX_POISON_MESSAGE) = Interesting { files, dirs };
+    }
+
+    /// Wait until something has changed, and then until nothing more has
+    /// changed for `DEBOUNCE_INTERVAL`.
+    pub async fn changed(&mut self) {
+        if self.changes.recv().await.is_none() {
+            // the sender lives as long as the watcher, so this can't happen,
+            // but don't spin if it does
+            std::future::pending::<()>().await;
+        }
+        while let Ok(Some(())) = timeout(DEBOUNCE_INTERVAL, self.changes.recv()).await {}
+    }
+}
+
+/// Make a path absolute, without resolving symlinks, so that it can be compared
+/// against the paths in events.
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
@@ -0,0 +1,199 @@
+//! A harness for running the real `resolved` binary.
+//!
+//! Each `Server` listens on its own ports, so tests can run in parallel.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dns_types::protocol::types::*;
+
+/// How long to wait for a response before giving up.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A temporary directory for zone and hosts files, deleted when dropped.
+pub struct TestDir {
+    pub path: PathBuf,
+}
+
+impl TestDir {
+    pub fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "resolved-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    /// Write a file, replacing it if it already exists, and return its path
+    /// as a string to pass to `resolved`.
+    pub fn write(&self, name: &str, contents: &str) -> String {
+        let path = self.path.join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A running `resolved`, which is killed when dropped.
+pub struct Server {
+    child: Child,
+    pub address: SocketAddr,
+}
+
+impl Server {
+    /// Start `resolved` with the given arguments, listening on a free port,
+    /// and wait for it to be ready.
+    pub fn start(args: &[&str]) -> Self {
+        let address = free_address();
+        let metrics_address = free_address();
+        let child = Command::new(env!("CARGO_BIN_EXE_resolved"))
+            .args(["-i", &address.to_string()])
+            .args(["--metrics-address", &metrics_address.to_string()])
+            .args(args)
+            .env("RUST_LOG", "warn")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut server = Self { child, address };
+
+        let start = Instant::now();
+        while TcpStream::connect(address).is_err() {
+            assert!(
+                server.child.try_wait().unwrap().is_none(),
+                "resolved exited during startup"
+            );
+            assert!(start.elapsed() < TIMEOUT, "resolved did not start in time");
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        server
+    }
+
+    /// Send a message over UDP and wait for the response, if there is one.
+    pub fn query_udp(&self, message: &Message) -> Option<Message> {
+        let octets = self.query_udp_octets(message)?;
+        Some(Message::from_octets(&octets).unwrap())
+    }
+
+    /// Like `query_udp` but does not parse the response: a truncated
+    /// response is cut off at 512 octets, so may not be a valid message.
+    pub fn query_udp_octets(&self, message: &Message) -> Option<Vec<u8>> {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+        socket
+            .send_to(&message.to_octets().unwrap(), self.address)
+            .unwrap();
+
+        let mut buf = [0; 65535];
+        let (size, _) = socket.recv_from(&mut buf).ok()?;
+        Some(buf[..size].to_vec())
+    }
+
+    /// Send a message over TCP and wait for the response.
+    pub fn query_tcp(&self, message: &Message) -> Message {
+        let mut stream = self.connect_tcp();
+        send_tcp(&mut stream, message);
+        recv_tcp(&mut stream)
+    }
+
+    /// Open a TCP connection, to send several messages over.
+    pub fn connect_tcp(&self) -> TcpStream {
+        let stream = TcpStream::connect(self.address).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        stream
+    }
+
+    /// Tell `resolved` to reload its configuration.
+    #[cfg(unix)]
+    pub fn reload(&self) {
+        let status = Command::new("kill")
+            .args(["-USR1", &self.child.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Send a message over a TCP connection.
+pub fn send_tcp(stream: &mut TcpStream, message: &Message) {
+    let octets = message.to_octets().unwrap();
+    stream
+        .write_all(&u16::try_from(octets.len()).unwrap().to_be_bytes())
+        .unwrap();
+    stream.write_all(&octets).unwrap();
+}
+
+/// Wait for a message on a TCP connection.
+pub fn recv_tcp(stream: &mut TcpStream) -> Message {
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).unwrap();
+    let mut buf = vec![0; usize::from(u16::from_be_bytes(len))];
+    stream.read_exact(&mut buf).unwrap();
+    Message::from_octets(&buf).unwrap()
+}
+
+/// A recursive query for a name and type.
+pub fn query(name: &str, rtype: RecordType) -> Message {
+    let mut message = Message::from_question(
+        rand_id(),
+        Question {
+            name: DomainName::from_dotted_string(name).unwrap(),
+            qtype: QueryType::Record(rtype),
+            qclass: QueryClass::Record(RecordClass::IN),
+        },
+    );
+    message.header.recursion_desired = true;
+    message
+}
+
+/// The `A` addresses in the answer section of a response.
+pub fn a_addresses(response: &Message) -> Vec<std::net::Ipv4Addr> {
+    response
+        .answers
+        .iter()
+        .filter_map(|rr| match rr.rtype_with_data {
+            RecordTypeWithData::A { address } => Some(address),
+            _ => None,
+        })
+        .collect()
+}
+
+/// An address on localhost which is free for both UDP and TCP.
+fn free_address() -> SocketAddr {
+    loop {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = udp.local_addr().unwrap();
+        if TcpListener::bind(address).is_ok() {
+            return address;
+        }
+    }
+}
+
+fn rand_id() -> u16 {
+    static ID: AtomicUsize = AtomicUsize::new(1);
+    (ID.fetch_add(1, Ordering::Relaxed) % usize::from(u16::MAX)) as u16
+}
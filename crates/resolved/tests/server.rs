@@ -0,0 +1,393 @@
+//! End-to-end tests which run the real server.
+
+mod common;
+
+use bytes::Bytes;
+use std::io::{Read, Write};
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dns_resolver::mock::{MockNameserver, MockResponse};
+use dns_types::protocol::types::*;
+
+use common::*;
+
+const ZONE: &str = "$ORIGIN example.com.
+@   IN SOA ns1 hostmaster 1 30 30 30 30
+www IN A 10.0.0.1
+";
+
+#[test]
+fn answers_from_zone_over_udp_and_tcp() {
+    let dir = TestDir::new();
+    let zone = dir.write("example.com.zone", ZONE);
+    let server = Server::start(&["--authoritative-only", "-z", &zone]);
+
+    let udp = server
+        .query_udp(&query("www.example.com.", RecordType::A))
+        .unwrap();
+    let tcp = server.query_tcp(&query("www.example.com.", RecordType::A));
+
+    for response in [udp, tcp] {
+        assert_eq!(Rcode::NoError, response.header.rcode);
+        assert!(response.header.is_authoritative);
+        assert_eq!(vec![Ipv4Addr::new(10, 0, 0, 1)], a_addresses(&response));
+    }
+}
+
+#[test]
+fn truncates_large_udp_responses() {
+    let mut zone = ZONE.to_string();
+    for i in 0..64 {
+        zone.push_str(&format!("big IN A 10.0.1.{i}\n"));
+    }
+    let dir = TestDir::new();
+    let zone = dir.write("example.com.zone", &zone);
+    let server = Server::start(&["--authoritative-only", "-z", &zone]);
+
+    let udp = server
+        .query_udp_octets(&query("big.example.com.", RecordType::A))
+        .unwrap();
+    assert_eq!(512, udp.len());
+    assert_eq!(0b0000_0010, udp[2] & 0b0000_0010);
+
+    let tcp = server.query_tcp(&query("big.example.com.", RecordType::A));
+    assert!(!tcp.header.is_truncated);
+    assert_eq!(64, a_addresses(&tcp).len());
+}
+
+#[test]
+fn fails_cname_loops_with_extended_error() {
+    let dir = TestDir::new();
+    let zone = dir.write(
+        "example.com.zone",
+        &format!("{ZONE}a IN CNAME b\nb IN CNAME a\n"),
+    );
+    let server = Server::start(&["--authoritative-only", "-z", &zone]);
+
+    let mut message = query("a.example.com.", RecordType::A);
+    message.additional.push(ResourceRecord::edns_opt(1232, &[]));
+    let response = server.query_udp(&message).unwrap();
+
+    assert_eq!(Rcode::ServerFailure, response.header.rcode);
+    assert_eq!(
+        Some(vec![EdnsOption::extended_error(
+            ExtendedErrorCode::Other,
+            "CNAME loop"
+        )]),
+        response.edns_opt().and_then(ResourceRecord::edns_options)
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn reloads_zone_files_on_sigusr1() {
+    let dir = TestDir::new();
+    let zone = dir.write("example.com.zone", ZONE);
+    let server = Server::start(&["--authoritative-only", "-z", &zone]);
+
+    dir.write("example.com.zone", &ZONE.replace("10.0.0.1", "10.0.0.2"));
+    server.reload();
+
+    let start = Instant::now();
+    loop {
+        let response = server.query_tcp(&query("www.example.com.", RecordType::A));
+        if a_addresses(&response) == vec![Ipv4Addr::new(10, 0, 0, 2)] {
+            break;
+        }
+        assert!(start.elapsed() < TIMEOUT, "zone was not reloaded in time");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// A mock upstream nameserver which answers every query with an `A` record.
+fn answering(address: Ipv4Addr) -> MockNameserver {
+    MockNameserver::builder()
+        .respond(move |query| {
+            MockResponse::answer(
+                query,
+                vec![ResourceRecord {
+                    name: query.questions[0].name.clone(),
+                    rtype_with_data: RecordTypeWithData::A { address },
+                    rclass: RecordClass::IN,
+                    ttl: 300,
+                }],
+            )
+        })
+        .start()
+        .unwrap()
+}
+
+#[test]
+fn caches_forwarded_answers() {
+    let upstream = answering(Ipv4Addr::new(10, 0, 0, 3));
+    let server = Server::start(&["--forward-address", &upstream.address().to_string()]);
+
+    for _ in 0..2 {
+        let response = server
+            .query_udp(&query("www.example.net.", RecordType::A))
+            .unwrap();
+        assert_eq!(Rcode::NoError, response.header.rcode);
+        assert_eq!(vec![Ipv4Addr::new(10, 0, 0, 3)], a_addresses(&response));
+    }
+
+    assert_eq!(1, upstream.queries().len());
+}
+
+#[test]
+fn retries_truncated_forwarded_answers_over_tcp() {
+    let upstream = MockNameserver::builder()
+        .records(vec![ResourceRecord {
+            name: DomainName::from_dotted_string("www.example.net.").unwrap(),
+            rtype_with_data: RecordTypeWithData::A {
+                address: Ipv4Addr::new(10, 0, 0, 5),
+            },
+            rclass: RecordClass::IN,
+            ttl: 300,
+        }])
+        .truncate_udp(true)
+        .start()
+        .unwrap();
+    let server = Server::start(&["--forward-address", &upstream.address().to_string()]);
+
+    let response = server
+        .query_udp(&query("www.example.net.", RecordType::A))
+        .unwrap();
+    assert_eq!(vec![Ipv4Addr::new(10, 0, 0, 5)], a_addresses(&response));
+    assert_eq!(1, upstream.tcp_queries().len());
+}
+
+#[test]
+fn fails_over_to_next_forwarding_nameserver() {
+    let refusing = MockNameserver::builder().start().unwrap();
+    let answering = answering(Ipv4Addr::new(10, 0, 0, 4));
+    let server = Server::start(&[
+        "--forward-address",
+        &refusing.address().to_string(),
+        "--forward-address",
+        &answering.address().to_string(),
+    ]);
+
+    let response = server
+        .query_udp(&query("www.example.net.", RecordType::A))
+        .unwrap();
+    assert_eq!(Rcode::NoError, response.header.rcode);
+    assert_eq!(vec![Ipv4Addr::new(10, 0, 0, 4)], a_addresses(&response));
+    assert!(!refusing.queries().is_empty());
+    assert_eq!(1, answering.queries().len());
+}
+
+#[test]
+fn does_not_reply_to_responses() {
+    // See #246: answering a response could set up a loop between two servers.
+    let dir = TestDir::new();
+    let zone = dir.write("example.com.zone", ZONE);
+    let server = Server::start(&["--authoritative-only", "-z", &zone]);
+
+    let response = query("www.example.com.", RecordType::A).make_response();
+    assert_eq!(None, server.query_udp(&response));
+
+    assert!(server
+        .query_udp(&query("www.example.com.", RecordType::A))
+        .is_some());
+}
+
+#[test]
+fn requires_valid_cookies_over_udp() {
+    let dir = TestDir::new();
+    let zone = dir.write("example.com.zone", ZONE);
+    let server = Server::start(&["--authoritative-only", "--require-cookies", "-z", &zone]);
+    let client = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    // no cookie: an empty truncated response, to retry over TCP
+    let response = server
+        .query_udp(&query("www.example.com.", RecordType::A))
+        .unwrap();
+    assert!(response.header.is_truncated);
+    assert!(response.answers.is_empty());
+    let response = server.query_tcp(&query("www.example.com.", RecordType::A));
+    assert_eq!(vec![Ipv4Addr::new(10, 0, 0, 1)], a_addresses(&response));
+
+    // client cookie only: BADCOOKIE, with a server cookie to retry with
+    let response = server.query_udp(&with_cookie(client, None)).unwrap();
+    assert_eq!(RCODE_BADCOOKIE, response.extended_rcode());
+    assert!(response.answers.is_empty());
+    let cookie = response.cookie().unwrap();
+    assert_eq!(client, cookie.client);
+    assert!(cookie.server.is_some());
+
+    // wrong server cookie: BADCOOKIE again
+    let response = server
+        .query_udp(&with_cookie(client, Some(Bytes::from_static(&[0; 16]))))
+        .unwrap();
+    assert_eq!(RCODE_BADCOOKIE, response.extended_rcode());
+
+    // the server's own cookie: answered
+    let response = server
+        .query_udp(&with_cookie(client, cookie.server))
+        .unwrap();
+    assert_eq!(Rcode::NoError, response.header.rcode);
+    assert_eq!(vec![Ipv4Addr::new(10, 0, 0, 1)], a_addresses(&response));
+}
+
+fn with_cookie(client: [u8; 8], server: Option<Bytes>) -> Message {
+    let mut message = query("www.example.com.", RecordType::A);
+    message.additional.push(ResourceRecord::edns_opt(
+        4096,
+        &[EdnsOption::cookie(&Cookie { client, server })],
+    ));
+    message
+}
+
+#[test]
+fn answers_pipelined_queries_over_tcp() {
+    let dir = TestDir::new();
+    let zone = dir.write("example.com.zone", ZONE);
+    let server = Server::start(&["--authoritative-only", "-z", &zone]);
+
+    let queries = [
+        query("www.example.com.", RecordType::A),
+        query("www.example.com.", RecordType::AAAA),
+        query("missing.example.com.", RecordType::A),
+    ];
+    let mut stream = server.connect_tcp();
+    for query in &queries {
+        send_tcp(&mut stream, query);
+    }
+
+    // responses may come back in any order
+    let mut ids = (0..queries.len())
+        .map(|_| recv_tcp(&mut stream).header.id)
+        .collect::<Vec<_>>();
+    let mut expected = queries.iter().map(|q| q.header.id).collect::<Vec<_>>();
+    ids.sort_unstable();
+    expected.sort_unstable();
+    assert_eq!(expected, ids);
+}
+
+#[test]
+fn closes_idle_tcp_connections_and_advertises_keepalive() {
+    let dir = TestDir::new();
+    let zone = dir.write("example.com.zone", ZONE);
+    let server = Server::start(&[
+        "--authoritative-only",
+        "-z",
+        &zone,
+        "--tcp-idle-timeout",
+        "1",
+    ]);
+
+    let mut query = query("www.example.com.", RecordType::A);
+    query.additional.push(ResourceRecord::edns_opt(
+        1232,
+        &[EdnsOption {
+            code: EDNS_OPTION_TCP_KEEPALIVE,
+            data: Bytes::new(),
+        }],
+    ));
+    let mut stream = server.connect_tcp();
+    send_tcp(&mut stream, &query);
+    let response = recv_tcp(&mut stream);
+
+    let options = response.edns_opt().unwrap().edns_options().unwrap();
+    assert!(options.contains(&EdnsOption::tcp_keepalive(10)));
+
+    let start = Instant::now();
+    assert_eq!(0, stream.read(&mut [0; 2]).unwrap());
+    assert!(start.elapsed() < Duration::from_secs(3));
+}
+
+#[test]
+fn closes_tcp_connections_which_do_not_read_responses() {
+    // each response is tens of kilobytes, so the socket buffers fill up
+    // quickly if the client doesn't read them
+    let mut zone = String::from(ZONE);
+    for i in 0..200 {
+        zone.push_str(&format!("big IN TXT \"{i:0>200}\"\n"));
+    }
+    let dir = TestDir::new();
+    let zone = dir.write("example.com.zone", &zone);
+    let server = Server::start(&[
+        "--authoritative-only",
+        "-z",
+        &zone,
+        "--tcp-idle-timeout",
+        "1",
+        "--max-tcp-connections",
+        "1",
+    ]);
+
+    let octets = query("big.example.com.", RecordType::TXT)
+        .to_octets()
+        .unwrap();
+    let mut framed = u16::try_from(octets.len()).unwrap().to_be_bytes().to_vec();
+    framed.extend_from_slice(&octets);
+
+    let mut stuck = server.connect_tcp();
+    stuck
+        .set_write_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    for _ in 0..100_000 {
+        if stuck.write_all(&framed).is_err() {
+            break;
+        }
+    }
+
+    // the stuck connection is still open on this end, so a new connection is
+    // only answered once the server has closed it and freed up its slot
+    let octets = query("www.example.com.", RecordType::A)
+        .to_octets()
+        .unwrap();
+    let mut framed = u16::try_from(octets.len()).unwrap().to_be_bytes().to_vec();
+    framed.extend_from_slice(&octets);
+    let start = Instant::now();
+    loop {
+        let mut stream = server.connect_tcp();
+        let mut len = [0; 2];
+        if stream.write_all(&framed).is_ok() && stream.read_exact(&mut len).is_ok() {
+            break;
+        }
+        assert!(start.elapsed() < TIMEOUT, "stuck connection was not closed");
+        thread::sleep(Duration::from_millis(100));
+    }
+    drop(stuck);
+}
+
+#[test]
+#[cfg(unix)]
+fn tails_matching_queries_over_control_socket() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    let dir = TestDir::new();
+    let zone = dir.write("example.com.zone", ZONE);
+    let socket = dir.path.join("control.sock");
+    let server = Server::start(&[
+        "--authoritative-only",
+        "-z",
+        &zone,
+        "--control-socket",
+        socket.to_str().unwrap(),
+    ]);
+
+    let mut stream = UnixStream::connect(&socket).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    writeln!(stream, "tail name=www.example.com. type=A").unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!("ok\n", line);
+
+    server.query_tcp(&query("example.com.", RecordType::SOA));
+    server.query_tcp(&query("www.example.com.", RecordType::AAAA));
+    server.query_tcp(&query("www.example.com.", RecordType::A));
+
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.contains(" www.example.com. IN A no-error "), "{line}");
+    assert!(
+        line.ends_with(" www.example.com. 30 IN A 10.0.0.1\n"),
+        "{line}"
+    );
+}
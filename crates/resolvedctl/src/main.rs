@@ -0,0 +1,204 @@
+use clap::{Parser, Subcommand};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use dns_types::protocol::types::{DomainName, QueryType};
+use resolved::control::Command;
+use resolved::logging::LogFormat;
+use resolved::recent::QueryFilter;
+use resolved::top::DEFAULT_TOP_COUNT;
+
+#[cfg(unix)]
+const DEFAULT_SOCKET: &str = "/run/resolved/control.sock";
+#[cfg(windows)]
+const DEFAULT_SOCKET: &str = r"\\.\pipe\resolved";
+
+// the doc comments for this struct turn into the CLI help text
+#[derive(Parser)]
+/// Control a running resolved through its control socket.
+///
+/// Part of resolved.
+struct Args {
+    /// Path to the control socket (or named pipe, on Windows), as given to
+    /// resolved with `--control-socket`
+    #[clap(
+        short,
+        long,
+        value_parser,
+        default_value = DEFAULT_SOCKET,
+        env = "RESOLVED_CONTROL_SOCKET"
+    )]
+    socket: PathBuf,
+
+    #[clap(subcommand)]
+    command: Action,
+}
+
+#[derive(Subcommand)]
+enum Action {
+    /// Reload the configuration, hosts, and zones, as if resolved had been
+    /// sent SIGHUP
+    Reload,
+
+    /// Remove records from the cache: every record if no domain is given
+    FlushCache {
+        /// Only remove the records for this domain
+        #[clap(value_parser)]
+        domain: Option<DomainName>,
+
+        /// Only remove the records of this type
+        #[clap(value_parser, requires = "domain")]
+        qtype: Option<QueryType>,
+
+        /// Also remove the records for every subdomain
+        #[clap(
+            long,
+            action(clap::ArgAction::SetTrue),
+            requires = "domain",
+            conflicts_with = "qtype"
+        )]
+        subtree: bool,
+    },
+
+    /// Print every record in the cache, in zone file format
+    DumpCache,
+
+    /// Print statistics about the cache and the zones
+    Stats,
+
+    /// Print the most queried names, the clients making the most queries, and
+    /// the names those clients query most, with approximate counts
+    Top {
+        /// How many of each to print
+        #[clap(value_parser, default_value_t = DEFAULT_TOP_COUNT)]
+        count: usize,
+    },
+
+    /// Print the log filter, or replace it if one is given, in `RUST_LOG`
+    /// format
+    LogFilter {
+        #[clap(value_parser)]
+        directives: Option<String>,
+    },
+
+    /// Print the log format, or replace it if one is given, in
+    /// `RUST_LOG_FORMAT` format
+    LogFormat {
+        #[clap(value_parser)]
+        format: Option<LogFormat>,
+    },
+
+    /// Print queries as they are answered, one per line, until interrupted:
+    /// the time, client, question, rcode, duration, and answers
+    Tail {
+        /// Only print queries for this domain or its subdomains
+        #[clap(long, value_parser)]
+        name: Option<DomainName>,
+
+        /// Only print queries from this client address
+        #[clap(long, value_parser)]
+        client: Option<IpAddr>,
+
+        /// Only print queries of this type
+        #[clap(long = "type", value_parser)]
+        qtype: Option<QueryType>,
+    },
+}
+
+impl From<Action> for Command {
+    fn from(action: Action) -> Self {
+        match action {
+            Action::Reload => Command::Reload,
+            Action::FlushCache {
+                domain: None,
+                qtype: _,
+                subtree: _,
+            } => Command::FlushCache,
+            Action::FlushCache {
+                domain: Some(name),
+                subtree: true,
+                qtype: _,
+            } => Command::FlushCacheSubtree(name),
+            Action::FlushCache {
+                domain: Some(name),
+                qtype,
+                subtree: false,
+            } => Command::FlushCacheName(name, qtype.unwrap_or(QueryType::Wildcard)),
+            Action::DumpCache => Command::DumpCache,
+            Action::Stats => Command::Stats,
+            Action::Top { count } => Command::Top(count),
+            Action::LogFilter { directives: None } => Command::GetLogFilter,
+            Action::LogFilter {
+                directives: Some(directives),
+            } => Command::SetLogFilter(directives),
+            Action::LogFormat { format: None } => Command::GetLogFormat,
+            Action::LogFormat {
+                format: Some(format),
+            } => Command::SetLogFormat(format),
+            Action::Tail {
+                name,
+                client,
+                qtype,
+            } => Command::Tail(QueryFilter {
+                name,
+                client,
+                qtype,
+            }),
+        }
+    }
+}
+
+/// Send a command and copy its output to stdout as it arrives.  The response
+/// is either `ok` followed by the output, or `error: ` followed by the reason,
+/// which is returned.
+fn send(socket: &Path, command: &Command) -> io::Result<Result<(), String>> {
+    let mut stream = connect(socket)?;
+    writeln!(stream, "{command}")?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    if let Some(error) = status.trim_end().strip_prefix("error: ") {
+        return Ok(Err(error.to_string()));
+    }
+
+    // stdout being closed (like piping `tail` into `head`) isn't an error
+    match io::copy(&mut reader, &mut io::stdout()) {
+        Err(error) if error.kind() != io::ErrorKind::BrokenPipe => Err(error),
+        _ => Ok(Ok(())),
+    }
+}
+
+/// Connect to the control socket.
+#[cfg(unix)]
+fn connect(socket: &Path) -> io::Result<impl Read + Write> {
+    std::os::unix::net::UnixStream::connect(socket)
+}
+
+/// Connect to the control pipe.
+#[cfg(windows)]
+fn connect(socket: &Path) -> io::Result<impl Read + Write> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(socket)
+}
+
+fn main() {
+    let args = Args::parse();
+    let command = Command::from(args.command);
+
+    match send(&args.socket, &command) {
+        Ok(Ok(())) => (),
+        Ok(Err(error)) => {
+            eprintln!("{error}");
+            process::exit(1);
+        }
+        Err(error) => {
+            eprintln!("could not connect to {}: {error}", args.socket.display());
+            process::exit(1);
+        }
+    }
+}
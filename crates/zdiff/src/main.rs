@@ -0,0 +1,122 @@
+use clap::Parser;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use dns_types::hosts::types::Hosts;
+use dns_types::protocol::types::*;
+use dns_types::zones::types::Zone;
+
+// the doc comments for this struct turn into the CLI help text
+#[derive(Parser)]
+/// Compare two zone files, or two hosts files, and output the records which
+/// have been added, removed, or changed.
+///
+/// Each line is a record in zone file format, prefixed with `+` if it has been
+/// added, `-` if it has been removed, and `~` if only its TTL has changed.
+/// Lines are sorted by domain name, so the output is the same however the
+/// files are structured.
+///
+/// Part of resolved.
+struct Args {
+    /// Read hosts files rather than zone files
+    #[clap(long, action(clap::ArgAction::SetTrue))]
+    hosts: bool,
+
+    /// The old file
+    #[clap(value_parser)]
+    old: PathBuf,
+
+    /// The new file
+    #[clap(value_parser)]
+    new: PathBuf,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let old = records(&load(&args.old, args.hosts));
+    let new = records(&load(&args.new, args.hosts));
+
+    let keys: BTreeSet<&Key> = old.keys().chain(new.keys()).collect();
+    for key in keys {
+        match (old.get(key), new.get(key)) {
+            (Some(old_ttl), Some(new_ttl)) if old_ttl != new_ttl => {
+                println!("~ {} ; TTL was {old_ttl}", serialise(key, *new_ttl));
+            }
+            (Some(old_ttl), None) => println!("- {}", serialise(key, *old_ttl)),
+            (None, Some(new_ttl)) => println!("+ {}", serialise(key, *new_ttl)),
+            _ => (),
+        }
+    }
+}
+
+/// A record, identified by its name, whether it is a wildcard, and its data.
+type Key = (DomainName, bool, RecordTypeWithData);
+
+/// Read a zone or hosts file, and exit with an error if it can't be read.
+fn load(path: &Path, hosts: bool) -> Zone {
+    let source = path.display();
+    if hosts {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("error reading hosts file {source}: {err:?}");
+                process::exit(1);
+            }
+        };
+        match Hosts::deserialise_reader(BufReader::new(file)) {
+            Ok(Ok(hosts)) => Zone::from(hosts),
+            Ok(Err(err)) => {
+                eprintln!("error parsing hosts file {source}: {err}");
+                process::exit(1);
+            }
+            Err(err) => {
+                eprintln!("error reading hosts file {source}: {err:?}");
+                process::exit(1);
+            }
+        }
+    } else {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(err) => {
+                eprintln!("error reading zone file {source}: {err:?}");
+                process::exit(1);
+            }
+        };
+        match Zone::deserialise_with_includes(&data, path, None, |included| {
+            fs::read_to_string(included)
+        }) {
+            Ok(zone) => zone,
+            Err(err) => {
+                eprintln!("error parsing zone file {source}: {err}");
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// All the records in a zone (including the `SOA`), with their TTLs.
+fn records(zone: &Zone) -> BTreeMap<Key, u32> {
+    let mut out = BTreeMap::new();
+    for nzr in zone.iter_records() {
+        out.insert(
+            (nzr.name, nzr.wildcard, nzr.record.rtype_with_data),
+            nzr.record.ttl,
+        );
+    }
+    out
+}
+
+/// Serialise a record as a zone file line, with absolute domain names.
+fn serialise((name, wildcard, rtype_with_data): &Key, ttl: u32) -> String {
+    format!(
+        "{}{name} {ttl} IN {} {}",
+        if *wildcard { "*." } else { "" },
+        rtype_with_data.rtype(),
+        // a non-authoritative zone doesn't make domain names relative
+        Zone::default().serialise_rdata(rtype_with_data)
+    )
+}
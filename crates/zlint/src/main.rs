@@ -0,0 +1,273 @@
+use clap::Parser;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{stdin, Read};
+use std::path::PathBuf;
+use std::process;
+
+use dns_types::protocol::types::*;
+use dns_types::zones::deserialise::{Error, ZoneFile};
+use dns_types::zones::types::{Zone, ZoneResult, Zones, SOA};
+
+// the doc comments for this struct turn into the CLI help text
+#[derive(Parser)]
+/// Check zone files for problems which are not syntax errors, such as
+/// dangling CNAMEs and missing glue.
+///
+/// Reads each file given (or stdin, if there are none) and prints one line
+/// per problem found.  Exits with a non-zero status if there are any problems
+/// or any file cannot be parsed.
+///
+/// Part of resolved.
+struct Args {
+    /// Zone files to check, `$INCLUDE` entries are followed
+    #[clap(value_parser)]
+    files: Vec<PathBuf>,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let mut ok = true;
+    if args.files.is_empty() {
+        let mut buf = String::new();
+        if let Err(err) = stdin().read_to_string(&mut buf) {
+            eprintln!("error reading zone file from stdin: {err:?}");
+            process::exit(1);
+        }
+        ok &= report("stdin", ZoneFile::deserialise_with_origin(&buf, None));
+    } else {
+        for path in &args.files {
+            let source = path.display().to_string();
+            match fs::read_to_string(path) {
+                Ok(data) => {
+                    let records =
+                        ZoneFile::deserialise_with_includes(&data, path, None, |included| {
+                            fs::read_to_string(included)
+                        });
+                    ok &= report(&source, records);
+                }
+                Err(err) => {
+                    eprintln!("error reading zone file {source}: {err:?}");
+                    ok = false;
+                }
+            }
+        }
+    }
+
+    if !ok {
+        process::exit(1);
+    }
+}
+
+/// Print the problems with a zone file, or the error parsing it.  Returns
+/// `true` if there were no problems.
+fn report(source: &str, records: Result<ZoneFile, Error>) -> bool {
+    match records {
+        Ok(records) => {
+            let problems = lint(&records);
+            for problem in &problems {
+                println!("{source}: {problem}");
+            }
+            problems.is_empty()
+        }
+        Err(err) => {
+            eprintln!("error parsing zone file {source}: {err}");
+            false
+        }
+    }
+}
+
+/// Check the records of a zone file, returning a description of each problem.
+///
+/// Checks which need to know where the zone ends are only done if the zone is
+/// authoritative, since a non-authoritative zone can have records for any
+/// domain.
+fn lint(records: &ZoneFile) -> Vec<String> {
+    let rrs: Vec<Record> = records
+        .rrs
+        .iter()
+        .map(|rr| Record {
+            rr,
+            wildcard: false,
+        })
+        .chain(
+            records
+                .wildcard_rrs
+                .iter()
+                .map(|rr| Record { rr, wildcard: true }),
+        )
+        .collect();
+
+    let mut problems = Vec::new();
+    check_duplicates(&rrs, &mut problems);
+    check_cname_and_other_data(&rrs, &mut problems);
+
+    let zone = if let Some((apex, soa)) = &records.apex_and_soa {
+        check_out_of_zone(apex, &rrs, &mut problems);
+        check_ttls(soa, &rrs, &mut problems);
+        check_glue(apex, &rrs, &mut problems);
+
+        // build the zone from only the records inside it, so the checks which
+        // use it still work if there are records outside it.
+        let mut in_zone = records.clone();
+        in_zone.rrs.retain(|rr| rr.name.is_subdomain_of(apex));
+        in_zone
+            .wildcard_rrs
+            .retain(|rr| rr.name.is_subdomain_of(apex));
+        let zone = in_zone.into_zone().ok();
+        if let Some(zone) = &zone {
+            check_dangling_cnames(apex, zone, &rrs, &mut problems);
+        }
+        zone
+    } else {
+        records.clone().into_zone().ok()
+    };
+
+    if let Some(zone) = zone {
+        check_cname_loops(zone, &mut problems);
+    }
+
+    problems
+}
+
+/// A record, and whether it is a wildcard.
+#[derive(Debug, Clone, Copy)]
+struct Record<'a> {
+    rr: &'a ResourceRecord,
+    wildcard: bool,
+}
+
+impl Record<'_> {
+    /// The name as it would be written in the zone file.
+    fn owner(&self) -> String {
+        if self.wildcard {
+            format!("*.{}", self.rr.name)
+        } else {
+            self.rr.name.to_string()
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("{} {}", self.owner(), self.rr.rtype_with_data.rtype())
+    }
+}
+
+/// The same record (ignoring the TTL) appearing more than once.
+fn check_duplicates(rrs: &[Record], problems: &mut Vec<String>) {
+    let mut seen = HashSet::new();
+    for record in rrs {
+        if !seen.insert((record.wildcard, &record.rr.name, &record.rr.rtype_with_data)) {
+            problems.push(format!("{}: duplicate record", record.describe()));
+        }
+    }
+}
+
+/// A `CNAME` record at a name with any other record, including another
+/// `CNAME` (RFC 1034 section 3.6.2).
+fn check_cname_and_other_data(rrs: &[Record], problems: &mut Vec<String>) {
+    let mut by_owner: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+    for record in rrs {
+        let counts = by_owner.entry(record.owner()).or_default();
+        if record.rr.rtype_with_data.rtype() == RecordType::CNAME {
+            counts.0 += 1;
+        } else {
+            counts.1 += 1;
+        }
+    }
+
+    for (owner, (cnames, others)) in by_owner {
+        if cnames > 1 {
+            problems.push(format!("{owner} CNAME: more than one CNAME record"));
+        }
+        if cnames > 0 && others > 0 {
+            problems.push(format!("{owner} CNAME: other records at the same name"));
+        }
+    }
+}
+
+/// A record which is not a subdomain of the apex.
+fn check_out_of_zone(apex: &DomainName, rrs: &[Record], problems: &mut Vec<String>) {
+    for record in rrs {
+        if !record.rr.name.is_subdomain_of(apex) {
+            problems.push(format!("{}: not in the zone {apex}", record.describe()));
+        }
+    }
+}
+
+/// A record with a TTL below the `SOA` minimum, which `resolved` raises to
+/// the minimum.
+fn check_ttls(soa: &SOA, rrs: &[Record], problems: &mut Vec<String>) {
+    for record in rrs {
+        if record.rr.ttl < soa.minimum {
+            problems.push(format!(
+                "{}: TTL {} is below the SOA minimum of {}",
+                record.describe(),
+                record.rr.ttl,
+                soa.minimum
+            ));
+        }
+    }
+}
+
+/// An `NS` record for a nameserver inside the zone with no `A` or `AAAA`
+/// record, so there is no glue to send with a referral to it.
+fn check_glue(apex: &DomainName, rrs: &[Record], problems: &mut Vec<String>) {
+    for record in rrs {
+        let RecordTypeWithData::NS { nsdname } = &record.rr.rtype_with_data else {
+            continue;
+        };
+        if !nsdname.is_subdomain_of(apex) {
+            continue;
+        }
+
+        let has_address = rrs.iter().any(|glue| {
+            !glue.wildcard
+                && glue.rr.name == *nsdname
+                && matches!(
+                    glue.rr.rtype_with_data,
+                    RecordTypeWithData::A { .. } | RecordTypeWithData::AAAA { .. }
+                )
+        });
+        if !has_address {
+            problems.push(format!(
+                "{}: no A or AAAA record for nameserver {nsdname}",
+                record.describe()
+            ));
+        }
+    }
+}
+
+/// A `CNAME` record pointing to a name inside the zone which doesn't exist.
+fn check_dangling_cnames(
+    apex: &DomainName,
+    zone: &Zone,
+    rrs: &[Record],
+    problems: &mut Vec<String>,
+) {
+    for record in rrs {
+        let RecordTypeWithData::CNAME { cname } = &record.rr.rtype_with_data else {
+            continue;
+        };
+        if !cname.is_subdomain_of(apex) {
+            continue;
+        }
+
+        if let Some(ZoneResult::NameError) = zone.resolve(cname, QueryType::Wildcard) {
+            problems.push(format!(
+                "{}: target {cname} does not exist",
+                record.describe()
+            ));
+        }
+    }
+}
+
+/// A `CNAME` record whose chain leads back to its own name, so queries for it
+/// can never be answered.
+fn check_cname_loops(zone: Zone, problems: &mut Vec<String>) {
+    let mut zones = Zones::new();
+    zones.insert(zone);
+    for name in zones.cname_loops() {
+        problems.push(format!("{name} CNAME: part of a CNAME loop"));
+    }
+}
@@ -46,7 +46,7 @@ fn main() {
             }
         }
         Err(err) => {
-            eprintln!("error parsing zone file from stdin: {err:?}");
+            eprintln!("error parsing zone file from stdin: {err}");
             process::exit(1);
         }
     }
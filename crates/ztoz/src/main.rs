@@ -24,7 +24,7 @@ fn main() {
     match Zone::deserialise(&buf) {
         Ok(zone) => print!("{}", zone.serialise()),
         Err(err) => {
-            eprintln!("error parsing zone file from stdin: {err:?}");
+            eprintln!("error parsing zone file from stdin: {err}");
             process::exit(1);
         }
     }
@@ -0,0 +1,30 @@
+#![no_main]
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+
+use dns_types::protocol::types::{ClientSubnet, Cookie, EdnsOption, RecordTypeWithData, ResourceRecord};
+
+fuzz_target!(|data: &[u8]| {
+    let mut opt = ResourceRecord::edns_opt(1232, &[]);
+    if let RecordTypeWithData::Unknown { octets, .. } = &mut opt.rtype_with_data {
+        *octets = Bytes::copy_from_slice(data);
+    }
+
+    if let Some(options) = opt.edns_options() {
+        let rebuilt = ResourceRecord::edns_opt(1232, &options);
+        assert_eq!(opt, rebuilt);
+
+        for option in &options {
+            if let Some(client_subnet) = ClientSubnet::from_option(option) {
+                let serialised = EdnsOption::client_subnet(&client_subnet);
+                assert_eq!(option, &serialised);
+                assert_eq!(Some(client_subnet), ClientSubnet::from_option(&serialised));
+            }
+            if let Some(cookie) = Cookie::from_option(option) {
+                let serialised = EdnsOption::cookie(&cookie);
+                assert_eq!(option, &serialised);
+                assert_eq!(Some(cookie), Cookie::from_option(&serialised));
+            }
+        }
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use dns_types::hosts::types::Hosts;
+
+fuzz_target!(|data: &str| {
+    let from_str = Hosts::deserialise(data);
+    let from_reader = Hosts::deserialise_reader(data.as_bytes()).unwrap();
+    assert_eq!(from_str, from_reader);
+});